@@ -0,0 +1,65 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rhine::bench::{object_schema, sample_text, tool_schemas};
+use rhine::chat::usage::count_tokens;
+use rhine::prompt::assembler::{assemble_output_description, assemble_tools_prompt};
+use rhine::schema::json_schema::JsonSchema;
+use rhine_schema_derive::JsonSchema;
+
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[schema(name = "course_plan", description = "课程安排", strict = true)]
+struct CoursePlan {
+    #[schema(desc = "课程名称")]
+    title: String,
+
+    #[schema(desc = "课时数")]
+    hours: i32,
+
+    #[schema(desc = "是否必修")]
+    required: bool,
+
+    #[schema(desc = "先修课程")]
+    prerequisites: Vec<String>,
+
+    #[schema(desc = "授课教师")]
+    teacher: Option<String>,
+}
+
+fn prompt_assembly(c: &mut Criterion) {
+    let mut group = c.benchmark_group("prompt_assembly");
+    for count in [1, 8, 32] {
+        let tools = tool_schemas(count);
+        group.bench_with_input(BenchmarkId::new("tools_prompt", count), &tools, |b, tools| {
+            b.iter(|| assemble_tools_prompt(black_box(tools.clone())).unwrap())
+        });
+    }
+    for fields in [4, 16, 64] {
+        let schema = object_schema(fields);
+        group.bench_with_input(BenchmarkId::new("output_description", fields), &schema, |b, schema| {
+            b.iter(|| assemble_output_description(black_box(schema)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn schema_generation(c: &mut Criterion) {
+    c.bench_function("schema_generation/derived", |b| b.iter(|| black_box(CoursePlan::json_schema())));
+    c.bench_function("schema_generation/cached", |b| b.iter(|| black_box(CoursePlan::cached_json_schema())));
+}
+
+fn tokenizer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tokenizer");
+    for chars in [256, 4096, 65536] {
+        let text = sample_text(chars);
+        group.throughput(Throughput::Bytes(text.len() as u64));
+        group.bench_with_input(BenchmarkId::new("count_tokens", chars), &text, |b, text| {
+            b.iter(|| count_tokens("gpt-4o", black_box(text)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, prompt_assembly, schema_generation, tokenizer);
+criterion_main!(benches);
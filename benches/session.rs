@@ -0,0 +1,62 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rhine::bench::{deep_session, wide_session};
+use rhine::chat::arena::ArenaSession;
+use rhine::chat::message::Role;
+
+fn context_assembly(c: &mut Criterion) {
+    let mut group = c.benchmark_group("context_assembly");
+    for depth in [16, 128, 1024] {
+        let session = deep_session(depth);
+        group.bench_with_input(BenchmarkId::new("deep", depth), &session, |b, session| {
+            b.iter(|| session.assemble_context(black_box(&session.default_path), &Role::Assistant).unwrap())
+        });
+    }
+    for width in [16, 128, 1024] {
+        let session = wide_session(width, 8);
+        group.bench_with_input(BenchmarkId::new("wide", width), &session, |b, session| {
+            b.iter(|| session.assemble_context(black_box(&session.default_path), &Role::Assistant).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn tree_operations(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree_operations");
+    for depth in [128, 1024] {
+        let session = deep_session(depth);
+        group.bench_with_input(BenchmarkId::new("append_deep", depth), &session, |b, session| {
+            b.iter_batched(
+                || session.clone(),
+                |mut session| session.add_with_default_path(Role::User, "再说一点".to_string()).unwrap(),
+                criterion::BatchSize::SmallInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("nodes_along_path", depth), &session, |b, session| {
+            b.iter(|| session.nodes_along_path(black_box(&session.default_path)).unwrap().len())
+        });
+
+        let arena = ArenaSession::from(session.clone());
+        group.bench_with_input(BenchmarkId::new("arena_append_deep", depth), &arena, |b, arena| {
+            b.iter_batched(
+                || arena.clone(),
+                |mut arena| arena.add_with_default_path(Role::User, "再说一点".to_string()).unwrap(),
+                criterion::BatchSize::SmallInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("arena_move_subtree", depth), &arena, |b, arena| {
+            let node = arena.node_id(&arena.default_path[..depth / 2]).unwrap();
+            let new_parent = arena.roots()[0];
+            b.iter_batched(
+                || arena.clone(),
+                |mut arena| arena.move_subtree(node, new_parent).unwrap(),
+                criterion::BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, context_assembly, tree_operations);
+criterion_main!(benches);
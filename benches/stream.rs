@@ -0,0 +1,31 @@
+use std::hint::black_box;
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::stream;
+use rhine::bench::{sample_text, sse_chunks};
+use rhine::chat::chat_base::BaseChat;
+use tokio::sync::Semaphore;
+
+fn sse_parsing(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let semaphore = Arc::new(Semaphore::new(1));
+    let mut group = c.benchmark_group("sse_parsing");
+    for chars in [1024, 16384] {
+        let chunks = sse_chunks(&sample_text(chars), 4);
+        group.throughput(Throughput::Bytes(chunks.iter().map(|chunk| chunk.len() as u64).sum()));
+        group.bench_with_input(BenchmarkId::new("stream_content", chars), &chunks, |b, chunks| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let permit = semaphore.clone().acquire_owned().await.unwrap();
+                    let chunks = stream::iter(chunks.iter().cloned().map(Ok));
+                    BaseChat::get_content_from_stream_resp(black_box(chunks), permit).await.unwrap()
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sse_parsing);
+criterion_main!(benches);
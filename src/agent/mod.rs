@@ -0,0 +1,25 @@
+use thiserror::Error;
+
+pub mod planner;
+pub mod react;
+
+#[derive(Debug, Error)]
+pub enum AgentError {
+    #[error("Chat request failed")]
+    ChatError,
+
+    #[error("Failed to assemble agent prompt")]
+    PromptError,
+
+    #[error("Failed to build plan")]
+    PlanError,
+
+    #[error("Failed to access memory")]
+    MemoryError,
+
+    #[error("Step budget of {0} exhausted without a final answer")]
+    StepBudgetExceeded(usize),
+
+    #[error("Token budget of {0} exhausted without a final answer")]
+    TokenBudgetExceeded(i32),
+}
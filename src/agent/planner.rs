@@ -0,0 +1,236 @@
+use error_stack::{Report, Result, ResultExt};
+use rhine_schema_derive::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::agent::AgentError;
+use crate::agent::react::ReActAgent;
+use crate::chat::chat_single::SingleChat;
+use crate::chat::chat_tool::add_response_format;
+use crate::chat::message::Role;
+use crate::schema::json_schema::JsonSchema;
+use crate::utils::common::redact::redact;
+
+/// 计划在会话元数据中的键
+/// Key of the plan in session metadata
+pub const PLAN_METADATA_KEY: &str = "plan";
+
+/// 规划模型输出的任务列表
+/// Task list produced by the planning model
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schema(name = "plan", description = "将目标拆解为按顺序执行的子任务", strict = true)]
+pub struct Plan {
+    #[schema(desc = "按执行顺序排列的子任务，每个子任务应可独立完成")]
+    pub tasks: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskStatus {
+    Pending,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanTask {
+    pub description: String,
+
+    pub status: TaskStatus,
+
+    #[serde(default)]
+    pub result: Option<String>,
+}
+
+/// 计划执行状态，保存在规划会话中以便中断后继续
+/// Plan execution state, kept in the planner session so runs can resume
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanState {
+    pub goal: String,
+
+    pub tasks: Vec<PlanTask>,
+
+    #[serde(default)]
+    pub answer: Option<String>,
+}
+
+impl PlanState {
+    pub fn is_finished(&self) -> bool {
+        self.tasks.iter().all(|task| task.status != TaskStatus::Pending)
+    }
+}
+
+/// 规划-执行两层智能体
+/// Two-tier planner-executor agent
+#[derive(Debug, Clone)]
+pub struct PlannerAgent {
+    /// 负责拆解任务与汇总结果的对话
+    /// Chat that decomposes the goal and aggregates results
+    pub planner: SingleChat,
+
+    /// 执行单个子任务的模板，每个子任务使用其副本
+    /// Template executing one task; each task runs on a copy
+    pub executor: ReActAgent,
+
+    /// 子任务失败时是否继续
+    /// Whether to continue when a task fails
+    continue_on_failure: bool,
+}
+
+impl PlannerAgent {
+    pub fn new(planner: SingleChat, executor: ReActAgent) -> Self {
+        Self {
+            planner,
+            executor,
+            continue_on_failure: false,
+        }
+    }
+
+    pub fn continue_on_failure(mut self, continue_on_failure: bool) -> Self {
+        self.continue_on_failure = continue_on_failure;
+        self
+    }
+
+    /// 当前计划状态
+    /// Current plan state
+    pub fn plan_state(&self) -> Option<PlanState> {
+        self.planner
+            .base
+            .session
+            .get_metadata(PLAN_METADATA_KEY)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+
+    /// 拆解目标、逐个执行子任务并汇总答案；若会话中已有同一目标的计划则从中断处继续
+    /// Decompose the goal, run each task and aggregate the answer; resumes an existing plan for the same goal
+    pub async fn run(&mut self, goal: &str) -> Result<(String, PlanState), AgentError> {
+        let mut state = match self.plan_state() {
+            Some(state) if state.goal == goal => state,
+            _ => self.plan(goal).await?,
+        };
+
+        while let Some(index) = state
+            .tasks
+            .iter()
+            .position(|task| task.status == TaskStatus::Pending)
+        {
+            let prompt = Self::task_prompt(&state, index);
+            let mut executor = self.executor.clone();
+            match executor.run(&prompt).await {
+                Ok((result, _)) => {
+                    state.tasks[index].status = TaskStatus::Done;
+                    state.tasks[index].result = Some(result);
+                }
+                Err(report) => {
+                    state.tasks[index].status = TaskStatus::Failed;
+                    state.tasks[index].result = Some(format!("{}", report.current_context()));
+                    self.save(&state)?;
+                    if !self.continue_on_failure {
+                        return Err(report).attach_printable(format!(
+                            "Task failed: {}",
+                            redact(&state.tasks[index].description)
+                        ));
+                    }
+                }
+            }
+            info!("Plan progress: {:?}", state.tasks[index]);
+            self.save(&state)?;
+        }
+
+        let answer = match &state.answer {
+            Some(answer) => answer.clone(),
+            None => self.aggregate(&state).await?,
+        };
+        state.answer = Some(answer.clone());
+        self.save(&state)?;
+        Ok((answer, state))
+    }
+
+    async fn plan(&mut self, goal: &str) -> Result<PlanState, AgentError> {
+        self.planner
+            .base
+            .add_message(
+                Role::System,
+                "将用户给出的目标拆解为若干按顺序执行的子任务，只输出计划",
+            )
+            .change_context(AgentError::ChatError)?;
+
+        let request_body = self
+            .planner
+            .get_req_body(goal)
+            .await
+            .change_context(AgentError::ChatError)?;
+        let request_body = add_response_format(request_body, (*Plan::cached_json_schema()).clone());
+        let answer = self
+            .planner
+            .get_content_from_req_body(request_body)
+            .await
+            .change_context(AgentError::ChatError)?;
+
+        let plan: Plan = serde_json::from_str(&answer)
+            .change_context(AgentError::PlanError)
+            .attach_printable_lazy(|| format!("Invalid plan: {}", redact(&answer)))?;
+        if plan.tasks.is_empty() {
+            return Err(Report::new(AgentError::PlanError)).attach_printable("Empty plan");
+        }
+
+        let state = PlanState {
+            goal: goal.to_string(),
+            tasks: plan
+                .tasks
+                .into_iter()
+                .map(|description| PlanTask {
+                    description,
+                    status: TaskStatus::Pending,
+                    result: None,
+                })
+                .collect(),
+            answer: None,
+        };
+        self.save(&state)?;
+        Ok(state)
+    }
+
+    async fn aggregate(&mut self, state: &PlanState) -> Result<String, AgentError> {
+        let mut results = String::new();
+        for (i, task) in state.tasks.iter().enumerate() {
+            results.push_str(&format!(
+                "{}. {} [{:?}]\n{}\n",
+                i + 1,
+                task.description,
+                task.status,
+                task.result.as_deref().unwrap_or_default()
+            ));
+        }
+
+        let request_body = self
+            .planner
+            .get_req_body(&format!(
+                "子任务已执行完毕，结果如下：\n{results}\n请据此给出目标“{}”的最终答案",
+                state.goal
+            ))
+            .await
+            .change_context(AgentError::ChatError)?;
+        self.planner
+            .get_content_from_req_body(request_body)
+            .await
+            .change_context(AgentError::ChatError)
+    }
+
+    fn task_prompt(state: &PlanState, index: usize) -> String {
+        let mut prompt = format!("总体目标：{}\n", state.goal);
+        for task in state.tasks[..index].iter() {
+            if let Some(result) = &task.result {
+                prompt.push_str(&format!("已完成：{}\n结果：{}\n", task.description, result));
+            }
+        }
+        prompt.push_str(&format!("当前任务：{}", state.tasks[index].description));
+        prompt
+    }
+
+    fn save(&mut self, state: &PlanState) -> Result<(), AgentError> {
+        let value = serde_json::to_value(state).change_context(AgentError::PlanError)?;
+        self.planner.base.session.set_metadata(PLAN_METADATA_KEY, value);
+        Ok(())
+    }
+}
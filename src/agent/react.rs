@@ -0,0 +1,331 @@
+use std::sync::Arc;
+
+use error_stack::{Report, Result, ResultExt};
+use indoc::indoc;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tracing::info;
+
+use crate::agent::AgentError;
+use crate::chat::ask_user::{answer_ask_user, ask_user_schema, UserResponder, ASK_USER_TOOL};
+use crate::chat::chat_single::SingleChat;
+use crate::chat::events::ChatEvent;
+use crate::chat::message::Role;
+use crate::memory::MemoryStore;
+use crate::prompt::assembler::assemble_tool_prompt;
+use crate::schema::tool_schema::{
+    check_tool_arguments, coerce_tool_arguments, find_tool_schema, unknown_tool, ToolCall,
+    ToolError, ToolErrorKind, ToolResult,
+};
+use crate::utils::common::redact::redact;
+
+/// 单步的动作：工具名与参数
+/// Action of one step: tool name and arguments
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReActAction {
+    pub tool: String,
+
+    pub input: serde_json::Value,
+}
+
+/// ReAct 循环中的一步
+/// One step of the ReAct loop
+#[derive(Clone, Debug, Default)]
+pub struct ReActStep {
+    /// 从0开始的步序号
+    /// Zero-based step index
+    pub index: usize,
+
+    pub thought: String,
+
+    pub action: Option<ReActAction>,
+
+    pub observation: Option<String>,
+
+    pub final_answer: Option<String>,
+}
+
+/// 步骤回调
+/// Step callback
+pub type StepCallback = Arc<dyn Fn(&ReActStep) + Send + Sync>;
+
+static THOUGHT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)Thought:\s*(.*?)\s*(?:Action:|Final Answer:|$)").unwrap());
+static ACTION: Lazy<Regex> = Lazy::new(|| Regex::new(r"Action:\s*([^\n]+)").unwrap());
+static ACTION_INPUT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)Action Input:\s*(.*?)\s*(?:Observation:|$)").unwrap());
+static FINAL_ANSWER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)Final Answer:\s*(.*)").unwrap());
+
+/// 推理-行动交替进行的智能体
+/// Agent alternating reasoning and acting steps
+#[derive(Clone)]
+pub struct ReActAgent {
+    pub chat: SingleChat,
+
+    tools_schema: Vec<serde_json::Value>,
+
+    max_steps: usize,
+
+    max_tokens: Option<i32>,
+
+    on_step: Option<StepCallback>,
+
+    memory: Option<Arc<MemoryStore>>,
+}
+
+impl std::fmt::Debug for ReActAgent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReActAgent")
+            .field("chat", &self.chat)
+            .field("tools_schema", &self.tools_schema)
+            .field("max_steps", &self.max_steps)
+            .field("max_tokens", &self.max_tokens)
+            .field("memory", &self.memory)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReActAgent {
+    /// # 参数 (Parameters)
+    /// * `chat` - 用于推理的对话，应为空会话
+    ///   - Chat used for reasoning, expected to have an empty session
+    /// * `tools_schema` - 可用工具的模式，工具需已注册
+    ///   - Schemas of the available tools, which must be registered
+    pub fn new(chat: SingleChat, tools_schema: Vec<serde_json::Value>) -> Self {
+        Self {
+            chat,
+            tools_schema,
+            max_steps: 8,
+            max_tokens: None,
+            on_step: None,
+            memory: None,
+        }
+    }
+
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// 累计用量上限（按 `BaseChat::usage` 计）
+    /// Cumulative usage limit (as counted by `BaseChat::usage`)
+    pub fn with_max_tokens(mut self, max_tokens: i32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// 每完成一步时调用，便于界面展示
+    /// Called after every step, e.g. to drive a UI
+    pub fn on_step(mut self, callback: impl Fn(&ReActStep) + Send + Sync + 'static) -> Self {
+        self.on_step = Some(Arc::new(callback));
+        self
+    }
+
+    /// 接入长期记忆：提供 `remember`/`recall` 工具，并将相关记忆放入系统提示
+    /// Attach long-term memory: adds the `remember`/`recall` tools and surfaces relevant memories in the system prompt
+    pub fn with_memory(mut self, memory: Arc<MemoryStore>) -> Self {
+        self.tools_schema.extend(memory.register_tools());
+        self.memory = Some(memory);
+        self
+    }
+
+    /// 提供 `ask_user` 工具：信息不足时模型可以向用户提问，循环挂起直到宿主给出回答
+    /// Add the `ask_user` tool: when information is missing the model can ask the user, and the loop is suspended
+    /// until the host answers
+    pub fn with_user(mut self, responder: impl UserResponder + 'static) -> Self {
+        self.tools_schema.push(ask_user_schema());
+        self.chat.base.set_user_responder(responder);
+        self
+    }
+
+    /// 运行直到得到最终答案或预算耗尽
+    /// Run until a final answer is produced or the budget runs out
+    pub async fn run(&mut self, task: &str) -> Result<(String, Vec<ReActStep>), AgentError> {
+        let mut system_prompt = self.system_prompt()?;
+        if let Some(memory) = &self.memory {
+            let memories = memory
+                .relevant_prompt(task, 3)
+                .await
+                .change_context(AgentError::MemoryError)?;
+            if !memories.is_empty() {
+                system_prompt.push('\n');
+                system_prompt.push_str(&memories);
+            }
+        }
+        self.chat
+            .base
+            .add_message(Role::System, &system_prompt)
+            .change_context(AgentError::ChatError)?;
+
+        let mut steps = Vec::new();
+        let mut input = format!("Question: {task}");
+
+        for index in 0..self.max_steps {
+            if let Some(max_tokens) = self.max_tokens
+                && self.chat.base.usage_snapshot().total_tokens >= u64::try_from(max_tokens).unwrap_or_default()
+            {
+                return Err(Report::new(AgentError::TokenBudgetExceeded(max_tokens)));
+            }
+
+            let request_body = self
+                .chat
+                .get_req_body(&input)
+                .await
+                .change_context(AgentError::ChatError)?;
+            let answer = self
+                .chat
+                .get_content_from_req_body(request_body)
+                .await
+                .change_context(AgentError::ChatError)?;
+
+            let mut step = Self::parse_step(index, &answer);
+            if step.final_answer.is_none() {
+                let observation = match &step.action {
+                    Some(action) => {
+                        let observation = self.observe(action).await;
+                        self.chat
+                            .base
+                            .screen_tool_output(&observation, Some(&action.tool))
+                            .change_context(AgentError::ChatError)?
+                    }
+                    None => "无法解析你的回答，请严格按照 Thought/Action/Action Input 或 Thought/Final Answer 的格式作答".to_string(),
+                };
+                input = format!("Observation: {observation}");
+                step.observation = Some(observation);
+            }
+
+            if let Some(callback) = &self.on_step {
+                callback(&step);
+            }
+
+            let final_answer = step.final_answer.clone();
+            steps.push(step);
+            if let Some(final_answer) = final_answer {
+                return Ok((final_answer, steps));
+            }
+        }
+
+        Err(Report::new(AgentError::StepBudgetExceeded(self.max_steps)))
+            .attach_printable(format!("Steps taken: {}", steps.len()))
+    }
+
+    /// 解析模型的一次回答
+    /// Parse one answer of the model
+    pub fn parse_step(index: usize, answer: &str) -> ReActStep {
+        let capture = |re: &Regex| {
+            re.captures(answer)
+                .map(|cap| cap[1].trim().to_string())
+                .filter(|text| !text.is_empty())
+        };
+
+        let mut step = ReActStep {
+            index,
+            thought: capture(&THOUGHT).unwrap_or_default(),
+            ..Default::default()
+        };
+
+        if let Some(final_answer) = capture(&FINAL_ANSWER) {
+            step.final_answer = Some(final_answer);
+        } else if let Some(tool) = capture(&ACTION) {
+            let input = capture(&ACTION_INPUT).unwrap_or_else(|| "{}".to_string());
+            let input = serde_json::from_str(&input).unwrap_or(serde_json::Value::String(input));
+            step.action = Some(ReActAction { tool, input });
+        }
+
+        step
+    }
+
+    /// 执行工具并发出调用开始与结束的事件
+    /// Execute a tool, emitting events when the call starts and finishes
+    async fn observe(&self, action: &ReActAction) -> String {
+        let Some(events) = &self.chat.base.events else {
+            return self.execute(action).await;
+        };
+        let call = ToolCall::new(&action.tool, action.input.clone());
+        events.emit(ChatEvent::ToolCallStarted { call: call.clone() });
+        let observation = self.execute(action).await;
+        let result = ToolResult::ok(&call, observation.clone());
+        events.emit(ChatEvent::ToolCallFinished { call, result });
+        observation
+    }
+
+    /// 执行工具，失败信息同样作为观察结果返回给模型
+    /// Execute a tool; failures are also returned to the model as observations
+    async fn execute(&self, action: &ReActAction) -> String {
+        if action.tool == ASK_USER_TOOL
+            && let Some(user) = &self.chat.base.user_responder
+        {
+            info!("Asking the user: {}", redact(&action.input.to_string()));
+            return answer_ask_user(user.as_ref(), &action.input)
+                .await
+                .unwrap_or_else(|error| error.to_string());
+        }
+        let runtime = &self.chat.base.runtime;
+        if let Some(error) = runtime.check_tool_permission(&action.tool) {
+            return error.to_string();
+        }
+        let Some(tool_fn) = runtime.tool(&action.tool) else {
+            return unknown_tool(&action.tool, &self.tools_schema).to_string();
+        };
+        let input = coerce_tool_arguments(&self.tools_schema, &action.tool, &action.input);
+        if let Some(error) = check_tool_arguments(&self.tools_schema, &action.tool, &input) {
+            return error.to_string();
+        }
+
+        info!("Calling function named: {}", action.tool);
+        match tool_fn(input) {
+            Ok(result) => {
+                let observation = match result {
+                    serde_json::Value::String(text) => text,
+                    other => other.to_string(),
+                };
+                info!("Calling function succeeded: {}", redact(&observation));
+                observation
+            }
+            Err(e) => {
+                let error = ToolError::new(
+                    ToolErrorKind::ExecutionFailed,
+                    format!("Calling function '{}' failed: {}", action.tool, e),
+                )
+                .with_tool(&action.tool);
+                match find_tool_schema(&self.tools_schema, &action.tool) {
+                    Some(schema) => error.with_expected_schema(schema),
+                    None => error,
+                }
+                .to_string()
+            }
+        }
+    }
+
+    fn system_prompt(&self) -> Result<String, AgentError> {
+        let mut tools = String::new();
+        for schema in &self.tools_schema {
+            tools.push_str(
+                &assemble_tool_prompt(schema.clone()).change_context(AgentError::PromptError)?,
+            );
+            tools.push('\n');
+        }
+
+        Ok(format!(
+            indoc! {"
+                请通过交替的思考与行动来解决问题。每次回答只能是以下两种格式之一：
+
+                Thought: 你对当前情况的思考
+                Action: 要调用的工具名
+                Action Input: 工具参数（JSON对象）
+
+                或者在得到答案时：
+
+                Thought: 你的最终思考
+                Final Answer: 最终答案
+
+                调用工具后，我会以 `Observation: 工具结果` 的形式告诉你结果。
+
+                你可以使用以下工具：
+
+                {}"},
+            tools
+        ))
+    }
+}
@@ -0,0 +1,142 @@
+use bytes::Bytes;
+use serde_json::json;
+
+use crate::chat::message::{Role, Session};
+
+/// 一条分支上交替的用户与助手消息，共 `depth` 条
+/// One branch of alternating user and assistant messages, `depth` in total
+pub fn deep_session(depth: usize) -> Session {
+    let mut session = Session::new();
+    session
+        .add_with_default_path(Role::System, "你是一个乐于助人的助手".to_string())
+        .unwrap();
+    for turn in 0..depth {
+        session.add_with_default_path(turn_role(turn), message(turn)).unwrap();
+    }
+    session
+}
+
+/// 系统消息下并列 `width` 条分支，每条分支 `depth` 条消息；默认路径为最后一条分支
+/// `width` branches side by side under a system message with `depth` messages each; the default path is the last branch
+pub fn wide_session(width: usize, depth: usize) -> Session {
+    let mut session = Session::new();
+    session
+        .add_with_default_path(Role::System, "你是一个乐于助人的助手".to_string())
+        .unwrap();
+    let root = session.default_path.clone();
+    for branch in 0..width {
+        session
+            .add_with_parent_path(&root, Role::User, format!("第 {branch} 条分支"))
+            .unwrap();
+        for turn in 1..depth {
+            session.add_with_default_path(turn_role(turn), message(turn)).unwrap();
+        }
+    }
+    session
+}
+
+fn turn_role(turn: usize) -> Role {
+    if turn.is_multiple_of(2) { Role::User } else { Role::Assistant }
+}
+
+fn message(turn: usize) -> String {
+    format!("第 {turn} 轮：{}", sample_text(120))
+}
+
+/// 含 `count` 个工具的工具模式，每个工具有字符串、整数、枚举与数组参数
+/// Tool schemas of `count` tools, each with string, integer, enum and array parameters
+pub fn tool_schemas(count: usize) -> Vec<serde_json::Value> {
+    (0..count)
+        .map(|i| {
+            json!({
+                "type": "function",
+                "function": {
+                    "name": format!("tool_{i}"),
+                    "description": format!("第 {i} 个工具"),
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "query": {"type": "string", "description": "查询内容"},
+                            "limit": {"type": "integer", "description": "返回条数", "minimum": 1, "maximum": 50},
+                            "order": {"type": "string", "enum": ["asc", "desc"], "description": "排序方式"},
+                            "tags": {"type": "array", "items": {"type": "string"}, "description": "标签"},
+                        },
+                        "required": ["query"],
+                    },
+                },
+            })
+        })
+        .collect()
+}
+
+/// `response_format` 形式的模式，含 `fields` 个字段，每个字段是带嵌套对象数组的对象
+/// Schema in `response_format` form with `fields` fields, each an object holding an array of nested objects
+pub fn object_schema(fields: usize) -> serde_json::Value {
+    let properties = (0..fields)
+        .map(|i| {
+            let field = json!({
+                "type": "object",
+                "description": format!("第 {i} 个字段"),
+                "properties": {
+                    "title": {"type": "string", "description": "标题"},
+                    "items": {
+                        "type": "array",
+                        "description": "条目",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "name": {"type": "string", "description": "名称"},
+                                "score": {"type": "number", "description": "分数"},
+                            },
+                            "required": ["name", "score"],
+                        },
+                    },
+                },
+                "required": ["title", "items"],
+            });
+            (format!("field_{i}"), field)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": "bench_output",
+            "description": "基准测试输出",
+            "strict": true,
+            "schema": {"type": "object", "properties": properties},
+        },
+    })
+}
+
+/// 流式回答的 SSE 事件，每个事件一块，带 `chunk_chars` 个字符的增量，结尾附用量与 `[DONE]`
+/// SSE events of a streaming answer, one event per chunk with deltas of `chunk_chars` chars, ending with usage and
+/// `[DONE]`
+pub fn sse_chunks(text: &str, chunk_chars: usize) -> Vec<Bytes> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut chunks = chars
+        .chunks(chunk_chars.max(1))
+        .map(|chunk| {
+            let event = json!({
+                "id": "chatcmpl-bench",
+                "object": "chat.completion.chunk",
+                "choices": [{"index": 0, "delta": {"content": chunk.iter().collect::<String>()}}],
+            });
+            Bytes::from(format!("data: {event}\n\n"))
+        })
+        .collect::<Vec<_>>();
+    let usage = json!({
+        "id": "chatcmpl-bench",
+        "object": "chat.completion.chunk",
+        "choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}],
+        "usage": {"prompt_tokens": 100, "completion_tokens": chars.len(), "total_tokens": 100 + chars.len()},
+    });
+    chunks.push(Bytes::from(format!("data: {usage}\n\ndata: [DONE]\n\n")));
+    chunks
+}
+
+/// 中英文混排的文本，约 `chars` 个字符
+/// Mixed Chinese and English text of about `chars` chars
+pub fn sample_text(chars: usize) -> String {
+    const SENTENCE: &str = "Rust 的所有权系统 guarantees memory safety without a garbage collector。";
+    SENTENCE.chars().cycle().take(chars).collect()
+}
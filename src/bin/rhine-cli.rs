@@ -0,0 +1,244 @@
+//! rhine 交互式终端对话
+//! Interactive terminal chat for rhine
+//!
+//! ```text
+//! rhine-cli [--config rhine.toml] [--api NAME] [--no-stream] [--mock]
+//! ```
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use rhine::chat::chat_single::SingleChat;
+use rhine::chat::message::{Messages, Role, Session};
+use rhine::chat::mock::MockReply;
+use rhine::chat::stream::StreamEvent;
+use rhine::config::Config;
+use rhine::schema::tool_schema::extract_tool_uses;
+
+const HELP: &str = "\
+/tree            显示消息树，* 标出当前分支 / show the message tree, * marks the current branch
+/goto 0.1.0      切换到指定路径 / move to a path
+/retry           为上一个问题重新生成回答 / regenerate the answer to the last question
+/system TEXT     添加系统消息 / add a system message
+/save FILE       保存会话 / save the session
+/load FILE       读取会话 / load a session
+/usage           显示令牌用量 / show token usage
+/quit            退出 / quit";
+
+struct Options {
+    config: String,
+    api: Option<String>,
+    stream: bool,
+    mock: bool,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        config: "rhine.toml".to_string(),
+        api: None,
+        stream: true,
+        mock: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => options.config = args.next().ok_or("--config needs a path")?,
+            "--api" => options.api = Some(args.next().ok_or("--api needs a name")?),
+            "--no-stream" => options.stream = false,
+            "--mock" => options.mock = true,
+            "-h" | "--help" => {
+                println!("rhine-cli [--config rhine.toml] [--api NAME] [--no-stream] [--mock]\n\n{HELP}");
+                std::process::exit(0);
+            }
+            other => return Err(format!("unknown argument: {other}")),
+        }
+    }
+    Ok(options)
+}
+
+/// 选出要使用的API：`--mock` 时为回显模拟API，否则从配置文件读取
+/// Pick the API to use: an echoing mock with `--mock`, otherwise one from the configuration file
+fn select_api(options: &Options) -> Result<String, String> {
+    if options.mock {
+        Config::add_mock("mock", |body| {
+            let messages = body["messages"].as_array().cloned().unwrap_or_default();
+            let last = messages.last().and_then(|m| m["content"].as_str()).unwrap_or_default();
+            MockReply::Text(format!("echo: {last}"))
+        });
+        return Ok("mock".to_string());
+    }
+
+    let names = Config::load_file(&options.config).map_err(|e| format!("{e:?}"))?;
+    match &options.api {
+        Some(api) if names.contains(api) => Ok(api.clone()),
+        Some(api) => Err(format!("api {api} is not defined in {}", options.config)),
+        None => names
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("no api defined in {}", options.config)),
+    }
+}
+
+fn format_path(path: &[usize]) -> String {
+    path.iter().map(usize::to_string).collect::<Vec<_>>().join(".")
+}
+
+fn parse_path(text: &str) -> Option<Vec<usize>> {
+    text.split('.').map(|idx| idx.trim().parse().ok()).collect()
+}
+
+fn print_tree(session: &Session) {
+    fn walk(node: &Messages, path: &mut Vec<usize>, current: &[usize]) {
+        let marker = if current.starts_with(path) { "*" } else { " " };
+        let preview: String = node.content.chars().take(60).collect::<String>().replace('\n', " ");
+        println!(
+            "{}{marker} [{}] {}: {preview}",
+            "  ".repeat(path.len() - 1),
+            format_path(path),
+            node.role
+        );
+        for (i, child) in node.child.iter().enumerate() {
+            path.push(i);
+            walk(child, path, current);
+            path.pop();
+        }
+    }
+
+    for (i, root) in session.message_roots.iter().enumerate() {
+        walk(root, &mut vec![i], &session.default_path);
+    }
+}
+
+/// 请求回答并输出；流式时正文经回调逐段输出
+/// Request an answer and print it; when streaming, the callback prints the text as it arrives
+async fn answer(chat: &mut SingleChat, request_body: serde_json::Value, stream: bool) {
+    match chat.get_content_from_req_body(request_body).await {
+        Ok(content) if !stream => {
+            println!("{content}");
+            for call in extract_tool_uses(&content) {
+                println!("[tool call] {call}");
+            }
+        }
+        Ok(_) => println!(),
+        Err(e) => eprintln!("\n[error] {e:?}"),
+    }
+}
+
+async fn run_command(chat: &mut SingleChat, line: &str, stream: bool) -> bool {
+    let (command, arg) = line.split_once(' ').map_or((line, ""), |(c, a)| (c, a.trim()));
+    match command {
+        "/quit" | "/exit" => return false,
+        "/help" => println!("{HELP}"),
+        "/tree" => print_tree(&chat.base.session),
+        "/goto" => match parse_path(arg) {
+            Some(path) if chat.base.session.nodes_along_path(&path).is_ok() => {
+                chat.base.session.default_path = path;
+            }
+            _ => eprintln!("[error] invalid path: {arg}"),
+        },
+        "/retry" => {
+            let path = chat.base.session.default_path.clone();
+            let Some(question) = path
+                .split_last()
+                .map(|(_, parent)| parent.to_vec())
+                .filter(|parent| {
+                    let nodes = chat.base.session.nodes_along_path(parent).unwrap_or_default();
+                    nodes.last().is_some_and(|node| node.role == Role::User)
+                })
+            else {
+                eprintln!("[error] the current message does not answer a question");
+                return true;
+            };
+            chat.base.session.default_path = question.clone();
+            match chat.get_req_body_again(&question).await {
+                Ok(request_body) => answer(chat, request_body, stream).await,
+                Err(e) => eprintln!("[error] {e:?}"),
+            }
+        }
+        "/system" => {
+            if let Err(e) = chat.base.add_message(Role::System, arg) {
+                eprintln!("[error] {e:?}");
+            }
+        }
+        "/save" => match serde_json::to_string_pretty(&chat.base.session)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(arg, json).map_err(|e| e.to_string()))
+        {
+            Ok(()) => println!("saved to {arg}"),
+            Err(e) => eprintln!("[error] {e}"),
+        },
+        "/load" => match std::fs::read_to_string(Path::new(arg))
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_str::<Session>(&json).map_err(|e| e.to_string()))
+        {
+            Ok(session) => chat.base.session = session,
+            Err(e) => eprintln!("[error] {e}"),
+        },
+        "/usage" => {
+            let usage = chat.base.session.usage;
+            println!(
+                "requests: {}, prompt: {}, completion: {}, total: {}",
+                usage.requests,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.total_tokens()
+            );
+        }
+        _ => eprintln!("[error] unknown command: {command}, try /help"),
+    }
+    true
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+    let api = match select_api(&options) {
+        Ok(api) => api,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut chat = SingleChat::new_with_api_name(&api, "", options.stream);
+    chat.base.set_stream_callback(|event| {
+        match event {
+            StreamEvent::Text(text) => print!("{text}"),
+            StreamEvent::ToolCallDetected(call) => print!("\n[tool call] {call}\n"),
+            StreamEvent::PartialJson(_) => {}
+        }
+        let _ = io::stdout().flush();
+    });
+
+    println!("rhine-cli · {api} · /help");
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('/') {
+            if !run_command(&mut chat, line, options.stream).await {
+                break;
+            }
+            continue;
+        }
+        match chat.get_req_body(line).await {
+            Ok(request_body) => answer(&mut chat, request_body, options.stream).await,
+            Err(e) => eprintln!("[error] {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use error_stack::{Report, Result, ResultExt};
+use futures::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::chat::attachment::Attachment;
+use crate::chat::chat_base::{BaseChat, ChatError};
+use crate::chat::chat_multi::MultiChat;
+use crate::chat::chat_single::SingleChat;
+use crate::chat::message::Session;
+use crate::chat::stream::StreamEvent;
+use crate::schema::json_schema::JsonSchema;
+
+/// 流式事件通道的容量，订阅者落后超过此数量时会丢失最早的事件
+/// Capacity of the stream event channel; subscribers lagging further behind lose the oldest events
+pub const STREAM_EVENT_CAPACITY: usize = 256;
+
+type Job<C> = Box<dyn for<'a> FnOnce(&'a mut C) -> BoxFuture<'a, ()> + Send>;
+
+/// 可交给 [`ChatHandle`] 托管的对话
+/// Chat that can be hosted by a [`ChatHandle`]
+pub trait HostedChat: Send + 'static {
+    fn base_mut(&mut self) -> &mut BaseChat;
+}
+
+impl HostedChat for SingleChat {
+    fn base_mut(&mut self) -> &mut BaseChat {
+        &mut self.base
+    }
+}
+
+impl HostedChat for MultiChat {
+    fn base_mut(&mut self) -> &mut BaseChat {
+        &mut self.base
+    }
+}
+
+/// 用户发出的一条消息
+/// Message sent by the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserMessage {
+    pub content: String,
+
+    pub attachments: Vec<Attachment>,
+}
+
+impl UserMessage {
+    pub fn new(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
+            attachments: Vec::new(),
+        }
+    }
+
+    pub fn with_attachment(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+}
+
+impl From<&str> for UserMessage {
+    fn from(content: &str) -> Self {
+        Self::new(content)
+    }
+}
+
+impl From<String> for UserMessage {
+    fn from(content: String) -> Self {
+        Self::new(content)
+    }
+}
+
+/// 一轮问答的回答
+/// Answer of one turn
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answer {
+    pub content: String,
+
+    /// 回答节点在消息树中的路径
+    /// Path of the answer node in the message tree
+    pub path: Vec<usize>,
+}
+
+/// 对话的命令队列句柄：对话由后台任务独占，所有操作按提交顺序逐个执行
+/// Command-queue handle of a chat: a background task owns the chat and runs every operation one at a time,
+/// in the order submitted
+///
+/// 对话的方法在等待网络时会修改消息树与默认路径，多个任务直接操作对话的副本时这些修改可能交错；
+/// 句柄可以任意克隆，经句柄提交的每个操作（如一问一答）都完整执行后才开始下一个
+/// Chat methods modify the message tree and default path while awaiting the network, so tasks working on
+/// copies of a chat can interleave those changes; the handle can be cloned freely and every operation
+/// submitted through it (e.g. a question and its answer) completes before the next one starts
+///
+/// 句柄只包含通道发送端，克隆开销很小且可在线程间共享，适合放进 Web 服务的状态中
+/// The handle only holds channel senders, so it is cheap to clone and can be shared across threads,
+/// which suits web server state
+pub struct ChatHandle<C> {
+    sender: mpsc::UnboundedSender<Job<C>>,
+
+    events: broadcast::Sender<StreamEvent>,
+}
+
+impl<C> Clone for ChatHandle<C> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+impl<C: HostedChat> ChatHandle<C> {
+    /// 在后台任务中接管对话，所有句柄都被丢弃后任务结束
+    /// Hand the chat over to a background task, which ends once every handle is dropped
+    ///
+    /// 对话原有的流式回调仍会被调用，流式事件同时转发给 [`ChatHandle::subscribe_stream`] 的订阅者
+    /// The chat's existing stream callback is still called, and stream events are also forwarded to
+    /// subscribers of [`ChatHandle::subscribe_stream`]
+    pub fn spawn(mut chat: C) -> Self {
+        let (events, _) = broadcast::channel(STREAM_EVENT_CAPACITY);
+        let base = chat.base_mut();
+        let previous = base.stream_callback.take();
+        let forward = events.clone();
+        base.set_stream_callback(move |event| {
+            if let Some(previous) = &previous {
+                previous(event);
+            }
+            let _ = forward.send(event.clone());
+        });
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Job<C>>();
+        tokio::spawn(async move {
+            while let Some(job) = receiver.recv().await {
+                job(&mut chat).await;
+            }
+        });
+        Self { sender, events }
+    }
+
+    /// 排队执行一个操作并等待其结果
+    /// Queue an operation and wait for its result
+    pub async fn run<R, F>(&self, operation: F) -> Result<R, ChatError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a mut C) -> BoxFuture<'a, R> + Send + 'static,
+    {
+        self.queue(operation)?
+            .await
+            .change_context(ChatError::ChatStopped)
+    }
+
+    fn queue<R, F>(&self, operation: F) -> Result<oneshot::Receiver<R>, ChatError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a mut C) -> BoxFuture<'a, R> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let job: Job<C> = Box::new(move |chat: &mut C| -> BoxFuture<'_, ()> {
+            Box::pin(async move {
+                let _ = reply.send(operation(chat).await);
+            })
+        });
+        self.sender
+            .send(job)
+            .map_err(|_| Report::new(ChatError::ChatStopped))?;
+        Ok(result)
+    }
+
+    /// 订阅此后所有流式回答的事件
+    /// Subscribe to the events of every answer streamed from now on
+    pub fn subscribe_stream(&self) -> broadcast::Receiver<StreamEvent> {
+        self.events.subscribe()
+    }
+
+    /// 当前会话的副本，在之前排队的操作都完成后取得
+    /// Copy of the current session, taken once every previously queued operation has finished
+    pub async fn snapshot(&self) -> Result<Session, ChatError> {
+        self.run(|chat| Box::pin(async move { chat.base_mut().session.clone() }))
+            .await
+    }
+}
+
+impl ChatHandle<SingleChat> {
+    /// 排队发送一条消息，立即返回等待回答的接收端
+    /// Queue a message and return right away with a receiver for the answer
+    ///
+    /// 后台任务已停止时接收端直接关闭
+    /// The receiver is closed right away if the background task has stopped
+    pub fn send(&self, message: impl Into<UserMessage>) -> oneshot::Receiver<Result<Answer, ChatError>> {
+        let message = message.into();
+        let (reply, answer) = oneshot::channel();
+        let _ = self.queue(move |chat| {
+            Box::pin(async move {
+                let _ = reply.send(answer_message(chat, message).await);
+            })
+        });
+        answer
+    }
+
+    /// 提问并等待回答，整轮问答不会与其他操作交错
+    /// Ask a question and wait for the answer; the whole turn never interleaves with other operations
+    pub async fn ask(&self, user_input: &str) -> Result<String, ChatError> {
+        let answer = self
+            .send(user_input)
+            .await
+            .change_context(ChatError::ChatStopped)??;
+        Ok(answer.content)
+    }
+
+    pub async fn get_json_answer<T>(&self, user_input: &str) -> Result<T, ChatError>
+    where
+        T: DeserializeOwned + JsonSchema + Send + 'static,
+    {
+        let user_input = user_input.to_string();
+        self.run(move |chat| Box::pin(async move { chat.get_json_answer::<T>(&user_input).await }))
+            .await?
+    }
+}
+
+async fn answer_message(chat: &mut SingleChat, message: UserMessage) -> Result<Answer, ChatError> {
+    let request_body = if message.attachments.is_empty() {
+        chat.get_req_body(&message.content).await?
+    } else {
+        chat.get_req_body_with_attachments(&message.content, message.attachments)
+            .await?
+    };
+    let content = chat.get_content_from_req_body(request_body).await?;
+    Ok(Answer {
+        content,
+        path: chat.base.session.default_path.clone(),
+    })
+}
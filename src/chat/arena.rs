@@ -0,0 +1,664 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use slotmap::{new_key_type, SecondaryMap, SlotMap};
+use tracing::info;
+
+use crate::chat::message::{
+    ConversationTree, MessageError, Messages, Role, Session, SessionUsage, TranscriptStyle,
+};
+use crate::chat::pruning::{now_ms, PruningPolicy};
+
+new_key_type! {
+    /// 竞技场中消息节点的句柄，节点移动后仍然有效，节点被移除后失效
+    /// Handle of a message node in the arena; stays valid when the node moves and expires once it is removed
+    pub struct NodeId;
+}
+
+/// 竞技场中的节点：消息本身的 `child` 恒为空，子节点以句柄记录
+/// Node in the arena: the message's own `child` is always empty and children are kept as handles
+#[derive(Debug, Clone)]
+struct Node {
+    message: Messages,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+}
+
+/// 增量修剪的状态，记录上次检查时各活动路径终点的句柄
+/// State of incremental pruning, keeping the end handles of the active paths at the last check
+#[derive(Debug, Clone, Default)]
+struct PruneState {
+    /// 是否已完整扫描过会话
+    /// Whether the whole session has been scanned
+    scanned: bool,
+
+    /// 最早会有非活动分支过期的时间
+    /// Earliest time at which an inactive branch expires
+    next_expiry_ms: Option<u64>,
+
+    active: Vec<NodeId>,
+}
+
+/// 以竞技场存放消息树的会话，适合数万节点的大会话
+/// Session keeping its message trees in an arena, meant for large sessions with tens of thousands of nodes
+///
+/// 提供与 [`Session`] 对应的路径方法，节点存放在一个 `SlotMap` 中，父子关系以句柄相连，
+/// 添加消息不会重新分配祖先节点，移动子树只需改写两处句柄；
+/// 它是独立的类型，`BaseChat` 仍作用于 [`Session`]，两者序列化格式相同，可随时互相转换
+/// Offers path methods mirroring those of [`Session`], with nodes stored in one `SlotMap` and linked by handles, so
+/// adding a message never reallocates its ancestors and moving a subtree rewrites two handles; it is a standalone
+/// type and `BaseChat` still works on [`Session`], but both serialize alike and convert into each other at any time
+///
+/// 设置修剪策略后，添加消息时按与 [`Session`] 相同的规则修剪
+/// With a pruning policy set, adding messages prunes by the same rules as [`Session`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "Session", into = "Session")]
+pub struct ArenaSession {
+    nodes: SlotMap<NodeId, Node>,
+    roots: Vec<NodeId>,
+    pub default_path: Vec<usize>,
+    pub metadata: HashMap<String, serde_json::Value>,
+    pub trees: Vec<ConversationTree>,
+    pub usage: SessionUsage,
+    pub pruning: Option<PruningPolicy>,
+    prune_state: PruneState,
+}
+
+impl Default for ArenaSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArenaSession {
+    pub fn new() -> Self {
+        Self {
+            nodes: SlotMap::with_key(),
+            roots: Vec::new(),
+            default_path: Vec::new(),
+            metadata: HashMap::new(),
+            trees: Vec::new(),
+            usage: SessionUsage::default(),
+            pruning: None,
+            prune_state: PruneState::default(),
+        }
+    }
+
+    pub fn set_pruning_policy(&mut self, policy: PruningPolicy) {
+        self.pruning = Some(policy);
+        self.prune_state = PruneState::default();
+    }
+
+    /// 会话中的消息总数
+    /// Total number of messages in the session
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&Messages> {
+        self.nodes.get(id).map(|node| &node.message)
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> Option<&mut Messages> {
+        self.nodes.get_mut(id).map(|node| &mut node.message)
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes.get(id)?.parent
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        self.nodes.get(id).map_or(&[], |node| &node.children)
+    }
+
+    /// 路径终点的句柄，路径为空或越界时报错
+    /// Handle of the node at the end of a path; errors on an empty or out-of-range path
+    pub fn node_id(&self, path: &[usize]) -> Result<NodeId, MessageError> {
+        let (&root, rest) = path.split_first().ok_or(MessageError::InvalidPath)?;
+        let mut id = *self.roots.get(root).ok_or(MessageError::InvalidPath)?;
+        for &idx in rest {
+            id = *self.nodes[id].children.get(idx).ok_or(MessageError::InvalidPath)?;
+        }
+        Ok(id)
+    }
+
+    /// 从根到节点的路径，句柄失效时为 `None`
+    /// Path from the root down to a node; `None` once the handle has expired
+    pub fn path_of(&self, id: NodeId) -> Option<Vec<usize>> {
+        let mut path = Vec::new();
+        let mut current = id;
+        loop {
+            let node = self.nodes.get(current)?;
+            let siblings = match node.parent {
+                Some(parent) => &self.nodes[parent].children,
+                None => &self.roots,
+            };
+            path.push(siblings.iter().position(|&sibling| sibling == current)?);
+            match node.parent {
+                Some(parent) => current = parent,
+                None => break,
+            }
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    pub fn get_node_by_path(&mut self, path: &[usize]) -> Result<&mut Messages, MessageError> {
+        let id = self.node_id(path)?;
+        Ok(&mut self.nodes[id].message)
+    }
+
+    /// 在父路径下追加消息并把默认路径指向它，父路径为空时新建一个根，返回新节点的句柄
+    /// Append a message under the parent path and point the default path at it, starting a new root when the parent
+    /// path is empty; returns the handle of the new node
+    pub fn add_with_parent_path(
+        &mut self,
+        path: &[usize],
+        role: Role,
+        content: String,
+    ) -> Result<NodeId, MessageError> {
+        let parent = match path {
+            [] => None,
+            _ => Some(self.node_id(path)?),
+        };
+        let id = self.insert(parent, Messages::new(role, content));
+        self.default_path = match parent {
+            Some(parent) => {
+                let mut new_default_path = path.to_vec();
+                new_default_path.push(self.nodes[parent].children.len() - 1);
+                new_default_path
+            }
+            None => vec![self.roots.len() - 1],
+        };
+        self.prune_after_add()?;
+        Ok(id)
+    }
+
+    pub fn add_with_default_path(&mut self, role: Role, content: String) -> Result<NodeId, MessageError> {
+        self.add_with_parent_path(&self.default_path.clone(), role, content)
+    }
+
+    pub fn set_message_metadata(
+        &mut self,
+        path: &[usize],
+        key: &str,
+        value: serde_json::Value,
+    ) -> Result<(), MessageError> {
+        self.get_node_by_path(path)?
+            .metadata
+            .insert(key.to_string(), value);
+        Ok(())
+    }
+
+    /// 将消息设为某个角色私有，`None` 取消私有
+    /// Make a message private to a character; `None` makes it public again
+    pub fn set_private(&mut self, path: &[usize], character: Option<&str>) -> Result<(), MessageError> {
+        self.get_node_by_path(path)?.private_to = character.map(str::to_string);
+        Ok(())
+    }
+
+    /// 从根到终点依次取出路径上的消息，路径为空或越界时报错
+    /// Collect the messages along a path from the root to its end; errors on an empty or out-of-range path
+    pub fn nodes_along_path(&self, path: &[usize]) -> Result<Vec<&Messages>, MessageError> {
+        let (&root, rest) = path.split_first().ok_or(MessageError::InvalidPath)?;
+        let mut id = *self.roots.get(root).ok_or(MessageError::InvalidPath)?;
+        let mut nodes = Vec::with_capacity(path.len());
+        nodes.push(&self.nodes[id].message);
+        for &idx in rest {
+            id = *self.nodes[id].children.get(idx).ok_or(MessageError::InvalidPath)?;
+            nodes.push(&self.nodes[id].message);
+        }
+        Ok(nodes)
+    }
+
+    /// 会话级系统消息：首个根为系统消息时即为该消息
+    /// Session-wide system message: the first root when it is a system message
+    pub fn root_system_message(&self) -> Option<&Messages> {
+        self.roots
+            .first()
+            .map(|&root| &self.nodes[root].message)
+            .filter(|root| root.role == Role::System)
+    }
+
+    pub fn assemble_context(
+        &self,
+        end_path: &[usize],
+        current_speaker: &Role,
+    ) -> Result<Vec<HashMap<String, String>>, MessageError> {
+        self.assemble_context_with_style(end_path, current_speaker, TranscriptStyle::NamePrefix)
+    }
+
+    /// 按根 → 祖先 → 终点的顺序组装上下文，规则与 [`Session::assemble_context_with_style`] 相同
+    /// Assemble the context in root → ancestors → end order by the same rules as
+    /// [`Session::assemble_context_with_style`]
+    pub fn assemble_context_with_style(
+        &self,
+        end_path: &[usize],
+        current_speaker: &Role,
+        style: TranscriptStyle,
+    ) -> Result<Vec<HashMap<String, String>>, MessageError> {
+        let nodes = self.nodes_along_path(end_path)?;
+        let inherited_system = self
+            .root_system_message()
+            .filter(|_| nodes[0].role != Role::System);
+
+        let mut messages_vec = Vec::with_capacity(nodes.len() + 1);
+        for node in inherited_system.into_iter().chain(nodes) {
+            if node.is_visible_to(current_speaker) {
+                messages_vec.push(node.to_api_format_with_attachments(current_speaker, style)?);
+            }
+        }
+        info!("context: {} messages for path {:?}", messages_vec.len(), end_path);
+
+        Ok(messages_vec)
+    }
+
+    /// 把子树移到新的父节点下，作为其最后一个子节点；根不能移动，也不能移到自己的子树中
+    /// Move a subtree under a new parent as its last child; roots cannot move, nor can a subtree move into itself
+    ///
+    /// 默认路径与各命名树保存的默认路径会随节点一起更新，返回子树的新路径
+    /// The default path and the default paths saved by named trees follow their nodes; returns the new path of the
+    /// subtree
+    pub fn move_subtree(&mut self, id: NodeId, new_parent: NodeId) -> Result<Vec<usize>, MessageError> {
+        let old_parent = self.nodes.get(id).ok_or(MessageError::InvalidPath)?.parent;
+        let Some(old_parent) = old_parent else {
+            return Err(MessageError::UnsupportedOperation("moving a root".to_string()));
+        };
+        if !self.nodes.contains_key(new_parent) {
+            return Err(MessageError::InvalidPath);
+        }
+        if self.ancestors(new_parent).any(|ancestor| ancestor == id) {
+            return Err(MessageError::UnsupportedOperation(
+                "moving a subtree into itself".to_string(),
+            ));
+        }
+
+        let tracked = self.track_paths();
+        self.nodes[old_parent].children.retain(|&child| child != id);
+        self.nodes[new_parent].children.push(id);
+        self.nodes[id].parent = Some(new_parent);
+        self.restore_paths(tracked);
+
+        Ok(self.path_of(id).unwrap_or_default())
+    }
+
+    /// 移除子树，返回移除的消息数；根不能移除
+    /// Remove a subtree and return the number of removed messages; roots cannot be removed
+    ///
+    /// 指向被移除子树的默认路径退回到其父节点
+    /// Default paths that pointed into the removed subtree fall back to its parent
+    pub fn remove_subtree(&mut self, id: NodeId) -> Result<usize, MessageError> {
+        let parent = self.nodes.get(id).ok_or(MessageError::InvalidPath)?.parent;
+        let Some(parent) = parent else {
+            return Err(MessageError::UnsupportedOperation("removing a root".to_string()));
+        };
+
+        let mut tracked = self.track_paths();
+        for tracked_id in tracked.iter_mut() {
+            if self.ancestors(*tracked_id).any(|ancestor| ancestor == id) {
+                *tracked_id = parent;
+            }
+        }
+        self.nodes[parent].children.retain(|&child| child != id);
+
+        let mut removed = 0;
+        let mut pending = vec![id];
+        while let Some(current) = pending.pop() {
+            if let Some(node) = self.nodes.remove(current) {
+                pending.extend(node.children);
+                removed += 1;
+            }
+        }
+        self.restore_paths(tracked);
+
+        Ok(removed)
+    }
+
+    /// 按修剪策略完整扫描会话，归档并移除分支，返回修剪的分支数
+    /// Scan the whole session against the pruning policy, archiving and removing branches; returns the number of
+    /// pruned branches
+    pub fn prune(&mut self) -> Result<usize, MessageError> {
+        let Some(policy) = self.pruning.clone() else {
+            return Ok(0);
+        };
+
+        let mut pruned = 0;
+        while let Some(id) = self.next_prunable(&policy) {
+            self.remove_branch(&policy, id)?;
+            pruned += 1;
+        }
+
+        self.prune_state = PruneState {
+            scanned: true,
+            next_expiry_ms: policy.max_age_ms.and_then(|max_age_ms| {
+                self.inactive_branches()
+                    .into_iter()
+                    .map(|(id, _)| self.newest_ms(id))
+                    .filter(|&newest| newest > 0)
+                    .map(|newest| newest.saturating_add(max_age_ms))
+                    .min()
+            }),
+            active: self.track_paths(),
+        };
+        Ok(pruned)
+    }
+
+    /// 添加一条消息后的增量修剪：只检查自上次检查以来离开活动路径的分支，
+    /// 仅在超出消息总数上限或有分支到期时才扫描整个会话
+    /// Incremental pruning after one added message: only branches that left the active paths since the last check
+    /// are inspected, and the whole session is scanned only when the node limit is exceeded or a branch expires
+    fn prune_after_add(&mut self) -> Result<usize, MessageError> {
+        let Some(policy) = self.pruning.clone() else {
+            return Ok(0);
+        };
+        let now = now_ms();
+        if !self.prune_state.scanned || self.prune_state.next_expiry_ms.is_some_and(|expiry| now > expiry) {
+            return self.prune();
+        }
+
+        let mut pruned = 0;
+        for branch in self.left_branches() {
+            if let Some(max_depth) = policy.max_depth {
+                while let Some(id) = self.too_deep(branch, self.depth(branch), max_depth) {
+                    self.remove_branch(&policy, id)?;
+                    pruned += 1;
+                }
+                if !self.nodes.contains_key(branch) {
+                    continue;
+                }
+            }
+
+            if let Some(max_age_ms) = policy.max_age_ms {
+                let newest = self.newest_ms(branch);
+                if newest > 0 && now.saturating_sub(newest) > max_age_ms {
+                    self.remove_branch(&policy, branch)?;
+                    pruned += 1;
+                } else if newest > 0 {
+                    let expiry = newest.saturating_add(max_age_ms);
+                    let next = self.prune_state.next_expiry_ms.map_or(expiry, |next| next.min(expiry));
+                    self.prune_state.next_expiry_ms = Some(next);
+                }
+            }
+        }
+
+        if let Some(max_nodes) = policy.max_nodes {
+            while self.nodes.len() > max_nodes {
+                let Some(id) = self
+                    .inactive_branches()
+                    .into_iter()
+                    .map(|(id, _)| id)
+                    .min_by_key(|&id| self.newest_ms(id))
+                else {
+                    break;
+                };
+                self.remove_branch(&policy, id)?;
+                pruned += 1;
+            }
+        }
+
+        self.prune_state.active = self.track_paths();
+        Ok(pruned)
+    }
+
+    /// 所有活动路径上的节点
+    /// Every node on an active path
+    fn active_nodes(&self) -> HashSet<NodeId> {
+        self.track_paths()
+            .into_iter()
+            .flat_map(|end| self.ancestors(end))
+            .collect()
+    }
+
+    /// 上次检查时在活动路径上、如今已离开所有活动路径的最大分支
+    /// Maximal branches that were on an active path at the last check and are now off every active path
+    fn left_branches(&self) -> Vec<NodeId> {
+        let active = self.active_nodes();
+        let mut left = Vec::new();
+        for &previous in &self.prune_state.active {
+            let branch = self
+                .ancestors(previous)
+                .filter(|&id| self.nodes.get(id).is_some_and(|node| node.parent.is_some()))
+                .take_while(|id| !active.contains(id))
+                .last();
+            if let Some(branch) = branch
+                && !left.contains(&branch)
+            {
+                left.push(branch);
+            }
+        }
+        left
+    }
+
+    /// 不在任何活动路径上的最大分支及其深度（根为 1）
+    /// Maximal branches off every active path with their depth (roots are depth 1)
+    fn inactive_branches(&self) -> Vec<(NodeId, usize)> {
+        let active = self.active_nodes();
+        let mut branches = Vec::new();
+        let mut stack: Vec<_> = self.roots.iter().map(|&root| (root, 1)).collect();
+        while let Some((id, depth)) = stack.pop() {
+            for &child in &self.nodes[id].children {
+                if active.contains(&child) {
+                    stack.push((child, depth + 1));
+                } else {
+                    branches.push((child, depth + 1));
+                }
+            }
+        }
+        branches
+    }
+
+    /// 找出下一个应被修剪的分支
+    /// Find the next branch to prune
+    fn next_prunable(&self, policy: &PruningPolicy) -> Option<NodeId> {
+        let branches = self.inactive_branches();
+
+        if let Some(max_depth) = policy.max_depth
+            && let Some(id) = branches
+                .iter()
+                .find_map(|&(id, depth)| self.too_deep(id, depth, max_depth))
+        {
+            return Some(id);
+        }
+
+        if let Some(max_age_ms) = policy.max_age_ms {
+            let now = now_ms();
+            let expired = branches.iter().find(|&&(id, _)| {
+                let newest = self.newest_ms(id);
+                newest > 0 && now.saturating_sub(newest) > max_age_ms
+            });
+            if let Some(&(id, _)) = expired {
+                return Some(id);
+            }
+        }
+
+        if let Some(max_nodes) = policy.max_nodes
+            && self.nodes.len() > max_nodes
+        {
+            return branches.iter().map(|&(id, _)| id).min_by_key(|&id| self.newest_ms(id));
+        }
+
+        None
+    }
+
+    /// 归档并移除一个分支
+    /// Archive and remove one branch
+    fn remove_branch(&mut self, policy: &PruningPolicy, id: NodeId) -> Result<(), MessageError> {
+        let parent = self.parent(id).ok_or(MessageError::InvalidPath)?;
+        let parent_path = self.path_of(parent).ok_or(MessageError::InvalidPath)?;
+        policy.archive(&parent_path, &self.to_message(id))?;
+        self.remove_subtree(id)?;
+        Ok(())
+    }
+
+    /// 节点的深度（根为 1）
+    /// Depth of a node (roots are depth 1)
+    fn depth(&self, id: NodeId) -> usize {
+        self.ancestors(id).count()
+    }
+
+    /// 子树中按先序排列的全部节点
+    /// Every node of a subtree in pre-order
+    fn subtree(&self, id: NodeId) -> Vec<NodeId> {
+        let mut order = Vec::new();
+        let mut pending = vec![id];
+        while let Some(current) = pending.pop() {
+            if let Some(node) = self.nodes.get(current) {
+                order.push(current);
+                pending.extend(node.children.iter().rev());
+            }
+        }
+        order
+    }
+
+    /// 子树中最新消息的创建时间
+    /// Creation time of the newest message in the subtree
+    fn newest_ms(&self, id: NodeId) -> u64 {
+        self.subtree(id)
+            .into_iter()
+            .map(|current| self.nodes[current].message.created_ms)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// 子树中第一个深度超过上限的节点，`depth` 为 `id` 自身的深度
+    /// First node of the subtree deeper than the limit, where `depth` is the depth of `id` itself
+    fn too_deep(&self, id: NodeId, depth: usize, max_depth: usize) -> Option<NodeId> {
+        let mut pending = vec![(id, depth)];
+        while let Some((current, depth)) = pending.pop() {
+            let node = self.nodes.get(current)?;
+            if depth > max_depth {
+                return Some(current);
+            }
+            pending.extend(node.children.iter().rev().map(|&child| (child, depth + 1)));
+        }
+        None
+    }
+
+    /// 将子树复制为 [`Messages`] 树，避免深树递归
+    /// Copy a subtree into a [`Messages`] tree, avoiding recursion on deep trees
+    fn to_message(&self, id: NodeId) -> Messages {
+        let mut built: SecondaryMap<NodeId, Messages> = SecondaryMap::new();
+        for current in self.subtree(id).into_iter().rev() {
+            let node = &self.nodes[current];
+            let mut message = node.message.clone();
+            message.child = node
+                .children
+                .iter()
+                .filter_map(|&child| built.remove(child).map(Arc::new))
+                .collect();
+            built.insert(current, message);
+        }
+        built.remove(id).unwrap_or_else(|| self.nodes[id].message.clone())
+    }
+
+    /// 节点自身及其所有祖先，由近及远
+    /// The node itself followed by all of its ancestors, nearest first
+    fn ancestors(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        std::iter::successors(Some(id), |&current| self.nodes.get(current)?.parent)
+    }
+
+    fn insert(&mut self, parent: Option<NodeId>, message: Messages) -> NodeId {
+        let id = self.nodes.insert(Node { message, parent, children: Vec::new() });
+        match parent {
+            Some(parent) => self.nodes[parent].children.push(id),
+            None => self.roots.push(id),
+        }
+        id
+    }
+
+    /// 默认路径与各命名树默认路径的终点句柄，依次排列
+    /// End handles of the default path and of every named tree's default path, in that order
+    fn track_paths(&self) -> Vec<NodeId> {
+        std::iter::once(&self.default_path)
+            .chain(self.trees.iter().map(|tree| &tree.default_path))
+            .map(|path| self.node_id(path).unwrap_or_default())
+            .collect()
+    }
+
+    fn restore_paths(&mut self, tracked: Vec<NodeId>) {
+        let paths: Vec<_> = tracked.into_iter().map(|id| self.path_of(id)).collect();
+        let mut paths = paths.into_iter();
+        if let Some(Some(path)) = paths.next() {
+            self.default_path = path;
+        }
+        for (tree, path) in self.trees.iter_mut().zip(paths) {
+            if let Some(path) = path {
+                tree.default_path = path;
+            }
+        }
+    }
+}
+
+impl From<Session> for ArenaSession {
+    fn from(session: Session) -> Self {
+        let mut arena = Self {
+            nodes: SlotMap::with_capacity_and_key(
+                session.message_roots.iter().map(|root| root.node_count()).sum(),
+            ),
+            roots: Vec::with_capacity(session.message_roots.len()),
+            default_path: session.default_path,
+            metadata: session.metadata,
+            trees: session.trees,
+            usage: session.usage,
+            pruning: session.pruning,
+            prune_state: PruneState::default(),
+        };
+        // 逐个取出子节点而不复制，避免深树递归
+        // Take children out one by one without copying, avoiding recursion on deep trees
+        let mut pending: Vec<_> = session.message_roots.into_iter().map(|root| (None, Arc::unwrap_or_clone(root))).collect();
+        pending.reverse();
+        while let Some((parent, mut message)) = pending.pop() {
+            let children = std::mem::take(&mut message.child);
+            let id = arena.insert(parent, message);
+            pending.extend(
+                children
+                    .into_iter()
+                    .rev()
+                    .map(|child| (Some(id), Arc::unwrap_or_clone(child))),
+            );
+        }
+        arena
+    }
+}
+
+impl From<ArenaSession> for Session {
+    fn from(mut arena: ArenaSession) -> Self {
+        // 先序排列后倒序组装，子节点总是先于父节点完成，避免深树递归
+        // Lay nodes out in pre-order and assemble them in reverse so children finish before their parents, avoiding
+        // recursion on deep trees
+        let mut order = Vec::with_capacity(arena.nodes.len());
+        let mut pending: Vec<_> = arena.roots.iter().rev().copied().collect();
+        while let Some(id) = pending.pop() {
+            order.push(id);
+            pending.extend(arena.nodes[id].children.iter().rev());
+        }
+
+        let mut built = SecondaryMap::with_capacity(order.len());
+        for id in order.into_iter().rev() {
+            let Some(node) = arena.nodes.remove(id) else {
+                continue;
+            };
+            let mut message = node.message;
+            message.child = node
+                .children
+                .iter()
+                .filter_map(|&child| built.remove(child).map(Arc::new))
+                .collect();
+            built.insert(id, message);
+        }
+
+        let mut session = Session::new();
+        session.message_roots = arena.roots.iter().filter_map(|&root| built.remove(root).map(Arc::new)).collect();
+        session.default_path = arena.default_path;
+        session.metadata = arena.metadata;
+        session.trees = arena.trees;
+        session.usage = arena.usage;
+        session.pruning = arena.pruning;
+        session
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt::Debug;
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::schema::tool_schema::{ToolError, ToolErrorKind};
+
+/// 向用户提问的内置工具名称
+/// Name of the built-in tool that asks the user a question
+pub const ASK_USER_TOOL: &str = "ask_user";
+
+/// 用户没有回答时交给模型的结果
+/// Result handed to the model when the user gives no answer
+const NO_ANSWER: &str = "用户没有回答这个问题，请根据已有信息继续，不要臆测用户的意图";
+
+/// 模型向用户提出的问题
+/// Question the model asks the user
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserQuestion {
+    pub question: String,
+
+    /// 供用户选择的候选答案，可以为空
+    /// Candidate answers for the user to pick from; may be empty
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// 回答模型提问的宿主程序，例如命令行提示或网页上的对话框
+/// Host application answering the model's questions, e.g. a command line prompt or a dialog on a web page
+pub trait UserResponder: Debug + Send + Sync {
+    /// 向用户提问并等待回答，工具循环在此期间挂起；用户无法回答时返回 `None`
+    /// Ask the user and wait for the answer while the tool loop is suspended; `None` when the user cannot answer
+    fn ask<'a>(&'a self, question: &'a UserQuestion) -> BoxFuture<'a, Option<String>>;
+}
+
+/// 等待宿主回答的问题；不回答直接丢弃时视为用户无法回答
+/// Question waiting for the host to answer; dropping it unanswered counts as the user being unable to answer
+#[derive(Debug)]
+pub struct PendingQuestion {
+    pub question: UserQuestion,
+
+    reply: oneshot::Sender<String>,
+}
+
+impl PendingQuestion {
+    pub fn answer(self, answer: impl Into<String>) {
+        let _ = self.reply.send(answer.into());
+    }
+}
+
+/// 通过通道把问题转交宿主的 [`UserResponder`]，由 [`user_channel`] 创建
+/// [`UserResponder`] handing questions to the host over a channel, created by [`user_channel`]
+#[derive(Clone, Debug)]
+pub struct UserChannel {
+    sender: mpsc::UnboundedSender<PendingQuestion>,
+}
+
+/// 创建问题通道：把 [`UserChannel`] 交给对话或智能体，宿主从接收端取出问题并逐个回答
+/// Create a question channel: hand the [`UserChannel`] to a chat or agent, and the host takes questions from the
+/// receiver and answers them one by one
+///
+/// 接收端被丢弃后，所有提问都视为用户无法回答
+/// Once the receiver is dropped every question counts as unanswered
+pub fn user_channel() -> (UserChannel, mpsc::UnboundedReceiver<PendingQuestion>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (UserChannel { sender }, receiver)
+}
+
+impl UserResponder for UserChannel {
+    fn ask<'a>(&'a self, question: &'a UserQuestion) -> BoxFuture<'a, Option<String>> {
+        Box::pin(async move {
+            let (reply, answer) = oneshot::channel();
+            self.sender
+                .send(PendingQuestion {
+                    question: question.clone(),
+                    reply,
+                })
+                .ok()?;
+            answer.await.ok()
+        })
+    }
+}
+
+/// [`ASK_USER_TOOL`] 工具的模式；该工具不进入全局注册表，由设置了 [`UserResponder`] 的工具循环直接处理
+/// Schema of the [`ASK_USER_TOOL`] tool; it is not put in the global registry but handled directly by tool loops
+/// that have a [`UserResponder`]
+pub fn ask_user_schema() -> serde_json::Value {
+    json!({
+        "type": "function",
+        "function": {
+            "name": ASK_USER_TOOL,
+            "description": "信息不足或需求不明确时向用户提问并等待回答，不要自行猜测",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "question": {"type": "string", "description": "要问用户的问题"},
+                    "options": {
+                        "type": "array",
+                        "items": {"type": "string"},
+                        "description": "可选的候选答案",
+                    },
+                },
+                "required": ["question"],
+            },
+        },
+    })
+}
+
+/// 按工具参数向用户提问；参数不合法时返回带有参数模式的 [`ToolError`]
+/// Ask the user as described by the tool arguments; invalid arguments give a [`ToolError`] with the parameters schema
+///
+/// 用户没有回答时同样返回成功的结果，提示模型在现有信息下继续
+/// An unanswered question still gives a successful result telling the model to carry on with what it has
+pub async fn answer_ask_user(
+    responder: &dyn UserResponder,
+    arguments: &serde_json::Value,
+) -> std::result::Result<String, ToolError> {
+    let question: UserQuestion = serde_json::from_value(arguments.clone()).map_err(|e| {
+        ToolError::new(ToolErrorKind::InvalidArguments, format!("Invalid arguments for '{ASK_USER_TOOL}': {e}"))
+            .with_tool(ASK_USER_TOOL)
+            .with_expected_schema(&ask_user_schema())
+    })?;
+    Ok(responder.ask(&question).await.unwrap_or_else(|| NO_ANSWER.to_string()))
+}
@@ -0,0 +1,169 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::chat::files::UploadedFile;
+
+/// 附件正文的默认字符上限
+/// Default character limit of attachment text
+pub const DEFAULT_ATTACHMENT_MAX_CHARS: usize = 20_000;
+
+#[derive(Debug, Error)]
+pub enum AttachmentError {
+    #[error("Failed to read attachment: {0}")]
+    ReadError(String),
+
+    #[error("PDF attachments require the `pdf` feature")]
+    PdfUnsupported,
+
+    #[error("Failed to extract PDF text: {0}")]
+    PdfError(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachmentKind {
+    Text,
+    Pdf,
+    Csv,
+}
+
+impl AttachmentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Pdf => "pdf",
+            Self::Csv => "csv",
+        }
+    }
+
+    /// 按扩展名判断类型，未知扩展名按文本处理
+    /// Detect the kind from the extension; unknown extensions are treated as text
+    pub fn from_path(path: &Path) -> Self {
+        match path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("pdf") => Self::Pdf,
+            Some("csv") => Self::Csv,
+            _ => Self::Text,
+        }
+    }
+}
+
+/// 消息附件：在构建请求时读取并提取正文
+/// Message attachment whose text is read and extracted when the request is built
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+
+    pub path: PathBuf,
+
+    pub kind: AttachmentKind,
+
+    /// 正文超过该字符数时使用摘要，没有摘要则截断
+    /// Above this many characters the summary is used, or the text is truncated when there is none
+    pub max_chars: usize,
+
+    /// 正文过长时生成的摘要
+    /// Summary generated when the text is too long
+    #[serde(default)]
+    pub summary: Option<String>,
+
+    /// 已上传到服务商的文件；设置后消息引用文件 ID，不再内联正文
+    /// File uploaded to the provider; when set the message references its ID instead of inlining the text
+    #[serde(default)]
+    pub uploaded: Option<UploadedFile>,
+}
+
+impl Attachment {
+    pub fn from_path(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            name: path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            kind: AttachmentKind::from_path(&path),
+            path,
+            max_chars: DEFAULT_ATTACHMENT_MAX_CHARS,
+            summary: None,
+            uploaded: None,
+        }
+    }
+
+    /// 引用已上传文件的附件
+    /// Attachment referencing an uploaded file
+    pub fn from_uploaded(file: UploadedFile) -> Self {
+        Self {
+            name: file.name.clone(),
+            path: PathBuf::new(),
+            kind: AttachmentKind::from_path(Path::new(&file.name)),
+            max_chars: DEFAULT_ATTACHMENT_MAX_CHARS,
+            summary: None,
+            uploaded: Some(file),
+        }
+    }
+
+    pub fn with_max_chars(mut self, max_chars: usize) -> Self {
+        self.max_chars = max_chars;
+        self
+    }
+
+    /// 提取附件全文
+    /// Extract the full text of the attachment
+    pub fn load(&self) -> Result<String, AttachmentError> {
+        match self.kind {
+            AttachmentKind::Text | AttachmentKind::Csv => fs::read_to_string(&self.path)
+                .map_err(|e| AttachmentError::ReadError(format!("{}: {e}", self.path.display()))),
+            AttachmentKind::Pdf => load_pdf(&self.path),
+        }
+    }
+
+    /// 全文是否超过字符上限
+    /// Whether the full text exceeds the character limit
+    pub fn is_too_long(&self, text: &str) -> bool {
+        text.chars().count() > self.max_chars
+    }
+
+    /// 渲染为放入请求的文本：未超限时为全文，超限时为摘要或截断后的正文
+    /// Render the text sent with the request: the full text within the limit, otherwise the summary or a truncated text
+    pub fn render(&self) -> Result<String, AttachmentError> {
+        let text = self.load()?;
+        let body = match (&self.summary, self.is_too_long(&text)) {
+            (_, false) => text,
+            (Some(summary), true) => format!("[摘要 / summary]\n{summary}"),
+            (None, true) => {
+                let kept: String = text.chars().take(self.max_chars).collect();
+                let omitted = text.chars().count() - self.max_chars;
+                format!("{kept}\n…[truncated {omitted} chars]")
+            }
+        };
+
+        let body = match self.kind {
+            AttachmentKind::Csv => format!("```csv\n{}\n```", body.trim_end()),
+            _ => body,
+        };
+        Ok(format!(
+            "<attachment name=\"{}\" type=\"{}\">\n{}\n</attachment>",
+            self.name,
+            self.kind.as_str(),
+            body
+        ))
+    }
+}
+
+#[cfg(feature = "pdf")]
+fn load_pdf(path: &Path) -> Result<String, AttachmentError> {
+    pdf_extract::extract_text(path)
+        .map_err(|e| AttachmentError::PdfError(format!("{}: {e}", path.display())))
+}
+
+#[cfg(not(feature = "pdf"))]
+fn load_pdf(_path: &Path) -> Result<String, AttachmentError> {
+    Err(AttachmentError::PdfUnsupported)
+}
@@ -4,13 +4,48 @@ use serde_json::json;
 use error_stack::{Report, Result, ResultExt};
 use thiserror::Error;
 
-use futures::{Stream, TryStreamExt};
+use futures::{stream, Stream, TryStreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tokio::sync::OwnedSemaphorePermit;
-use reqwest::{Client, Error, Response};
-use tracing::info;
-use crate::chat::message::{Role, Session};
+use std::time::Instant;
+use tracing::{field, info_span, warn, Instrument, Span};
+use crate::chat::chat_tool::JsonRepair;
+use crate::chat::context::{insert_provided_notes, ContextProvider, ContextStrategy, FullPath};
+use crate::chat::files::expand_file_parts;
+use crate::chat::normalize::normalize_roles;
+use crate::chat::preflight::validate_request_body;
+use crate::chat::transport::{
+    detect_stalls, ChatTransport, ReqwestTransport, StreamChunk, TransportError, TransportRequest,
+};
+use crate::chat::message::{MessageError, Role, Session, SharedSession, TranscriptStyle};
+use crate::chat::recorder::{build_entry, write_entry, Recorder, RecordingStream};
+use crate::chat::repro::{ReproBundle, ReproInfo, REPRO_METADATA_KEY};
+use crate::guard::{GuardChain, GuardOutcome};
+use crate::prompt::assembler::DEFAULT_HIDDEN_FIELDS;
+use crate::prompt::injection::{InjectionFinding, InjectionPolicy};
+use crate::prompt::sanitize::{SanitizePolicy, UntrustedSource};
+use crate::chat::safety::{SafetyAction, SafetyAnnotation, SafetyPolicy, SafetyStage};
+use crate::chat::rate_limit::wait_for_rate_limit;
+use crate::chat::ask_user::UserResponder;
+use crate::chat::events::{ChatEvent, ChatEvents};
+use crate::chat::openrouter::{is_openrouter, ProviderPreferences};
+use crate::storage::blob::{read_blob, BlobOffload, BLOB_METADATA_KEY};
+use crate::chat::scheduler::RequestPriority;
+use crate::chat::stream::{PartialJsonParser, StreamCallback, StreamEvent, ToolUseFilter};
+use crate::chat::variables::PromptVariables;
+use crate::chat::usage::{
+    estimate_usage, AnswerTiming, ResponseMeta, TimedStream, UsageCounters, UsageSnapshot, UsageSource, UsageTracker,
+    TIMING_METADATA_KEY,
+};
 
-use crate::config::{Config, ModelCapability, THREAD_POOL};
+use crate::utils::common::body_log::body_attachment;
+use crate::utils::common::redact::{mask_secret, redact};
+use crate::chat::mock::{MockApi, MockReply};
+use crate::config::{
+    ApiInfo, AuxiliaryTask, Config, ModelCapability,
+};
+use crate::runtime::Runtime;
 
 
 #[derive(Debug, Error)]
@@ -18,6 +53,9 @@ pub enum ChatError {
     #[error("Failed to assemble output description")]
     AssembleOutputDescriptionError,
 
+    #[error("Failed to assemble tools prompt")]
+    AssembleToolsPromptError,
+
     #[error("HTTP error with status code: {0}")]
     HttpError(u16),
 
@@ -45,14 +83,181 @@ pub enum ChatError {
     #[error("Undefined character: {0}")]
     UndefinedCharacter(String),
 
+    #[error("API not found: {0}")]
+    ApiNotFound(String),
+
+    #[error("API source not found: {0}")]
+    ApiSourceNotFound(String),
+
+    #[error("Undefined stage: {0}")]
+    UndefinedStage(String),
+
     #[error("No character selected")]
     NoCharacterSelected,
 
+    #[error("Failed to replay recorded response")]
+    ReplayError,
+
+    #[error("Content filtered: {0}")]
+    ContentFiltered(String),
+
+    #[error("Safety check failed")]
+    SafetyCheckError,
+
+    #[error("Untrusted content rejected")]
+    ContentRejected,
+
+    #[error("Invalid judge scores: {0}")]
+    InvalidScore(String),
+
+    #[error("Output rejected by guard")]
+    GuardViolation,
+
+    #[error("Context strategy failed")]
+    ContextStrategyError,
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Stream stalled")]
+    StreamStalled,
+
+    #[error("Chat actor stopped")]
+    ChatStopped,
+
+    #[error("Blob store error")]
+    BlobStoreError,
+
     #[error("Unknown error")]
     UnknownError,
 }
 
-#[derive(Debug, Clone)]
+pub use crate::chat::transport::ByteStream;
+
+/// 回答结束原因在消息元数据中的键
+/// Key of the finish reason of an answer in message metadata
+pub const FINISH_REASON_METADATA_KEY: &str = "finish_reason";
+
+/// 回答因长度截断时请求续写的提示
+/// Prompt asking to continue an answer cut off by the length limit
+pub const CONTINUE_PROMPT: &str = "继续，从中断处接着写，不要重复已经写过的内容";
+
+/// 回答结束的原因，对应响应中的 `finish_reason`
+/// Why an answer ended, from `finish_reason` of the response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// 自然结束或遇到停止序列
+    /// Natural end or a stop sequence
+    Stop,
+
+    /// 达到 `max_tokens` 或上下文长度上限，回答不完整
+    /// Hit `max_tokens` or the context limit; the answer is incomplete
+    Length,
+
+    ToolCalls,
+
+    ContentFilter,
+
+    Other(String),
+}
+
+impl FinishReason {
+    pub fn parse(reason: &str) -> Self {
+        match reason {
+            "stop" | "end_turn" | "stop_sequence" => Self::Stop,
+            "length" | "max_tokens" => Self::Length,
+            "tool_calls" | "function_call" | "tool_use" => Self::ToolCalls,
+            "content_filter" => Self::ContentFilter,
+            other => Self::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Stop => "stop",
+            Self::Length => "length",
+            Self::ToolCalls => "tool_calls",
+            Self::ContentFilter => "content_filter",
+            Self::Other(reason) => reason,
+        }
+    }
+}
+
+/// 将模拟API的错误应答转换为对应的请求错误，限流应答会暂停该来源
+/// Convert an error reply of a mock API into the matching request error; a rate-limit reply pauses the source
+fn mock_error(
+    reply: MockReply,
+    runtime: &Runtime,
+    base_url: &str,
+    request_body: &serde_json::Value,
+) -> Report<ChatError> {
+    let error = match reply {
+        MockReply::HttpError(status) => ChatError::HttpError(status),
+        MockReply::RateLimited(retry_after) => {
+            runtime.pause_source(base_url, retry_after);
+            ChatError::HttpError(429)
+        }
+        MockReply::Timeout => ChatError::TimeoutError,
+        MockReply::Text(_) | MockReply::Raw(_) => ChatError::ParseResponseError,
+    };
+    Report::new(error).attach_printable(body_attachment("Mock reply to", request_body))
+}
+
+/// 获取API来源的并发许可，开启了优先级调度的来源按优先级排队；来源因限流暂停时带着许可等到暂停结束
+/// Acquire a concurrency permit of an API source; sources with priority scheduling queue by priority.
+/// While the source is paused by rate limiting, the permit is held until the pause is over
+///
+/// 运行时中没有该来源时返回 `ChatError::ApiSourceNotFound`
+/// Returns `ChatError::ApiSourceNotFound` when the runtime has no such source
+pub(crate) async fn acquire_source_permit(
+    runtime: &Runtime,
+    base_url: &str,
+    priority: RequestPriority,
+) -> Result<OwnedSemaphorePermit, ChatError> {
+    let not_found = || Report::new(ChatError::ApiSourceNotFound(base_url.to_string()));
+
+    let permit = match runtime.scheduler(base_url) {
+        Some(scheduler) => scheduler.acquire(priority).await,
+        None => runtime
+            .semaphore(base_url)
+            .ok_or_else(not_found)?
+            .acquire_owned()
+            .await
+            .map_err(|_| not_found())
+            .attach_printable("Semaphore of the source was closed")?,
+    };
+    runtime.wait_for_source(base_url).await;
+    Ok(permit)
+}
+
+/// 将传输层错误转换为对应的请求错误，带 `Retry-After` 的限流会暂停该来源
+/// Convert a transport error into the matching request error; rate limiting with `Retry-After` pauses the source
+fn transport_error(
+    report: Report<TransportError>,
+    runtime: &Runtime,
+    base_url: &str,
+    request_body: &serde_json::Value,
+) -> Report<ChatError> {
+    let error = match report.current_context() {
+        TransportError::Http(status) => ChatError::HttpError(*status),
+        TransportError::RateLimited(retry_after) => {
+            if let Some(retry_after) = retry_after {
+                runtime.pause_source(base_url, *retry_after);
+            }
+            ChatError::HttpError(429)
+        }
+        TransportError::Timeout => ChatError::TimeoutError,
+        TransportError::Network(_) => ChatError::UnknownError,
+        TransportError::Body(_) => ChatError::ParseResponseError,
+        TransportError::Stalled(_) => ChatError::StreamStalled,
+        TransportError::InvalidHeader(name) => ChatError::InvalidRequest(format!("invalid header {name}")),
+    };
+    report
+        .change_context(error)
+        .attach_printable(body_attachment("Request body", request_body))
+}
+
+#[derive(Clone)]
 pub struct BaseChat {
     pub model: String,
 
@@ -60,50 +265,349 @@ pub struct BaseChat {
 
     pub api_key: String,
 
-    pub client: Client,
+    /// 请求的传输层，默认为共享API来源连接池的 reqwest 传输层
+    /// Transport of requests; the reqwest transport on the connection pool of the API source by default
+    pub transport: Arc<dyn ChatTransport>,
+
+    /// 提供并发信号量、工具注册表与辅助任务API的运行时，默认为全局运行时
+    /// Runtime providing concurrency semaphores, the tool registry and auxiliary task APIs; the global one by default
+    pub runtime: Arc<Runtime>,
 
     pub character_prompt: String,
 
     pub session: Session,
 
-    pub usage: i32,
+    /// 累计令牌用量，克隆的对话共享同一组计数器
+    /// Accumulated token usage; cloned chats share the same counters
+    pub usage: Arc<UsageCounters>,
 
     pub need_stream: bool,
+
+    pub priority: RequestPriority,
+
+    pub safety: Option<SafetyPolicy>,
+
+    /// 被安全过滤器标注的内容
+    /// Content annotated by the safety filter
+    pub safety_annotations: Vec<SafetyAnnotation>,
+
+    /// 插入提示前对用户输入与工具结果的清理策略
+    /// Sanitization policy for user input and tool results before they enter the prompt
+    pub sanitize: Option<SanitizePolicy>,
+
+    /// 结构化回答无法在本地解析时的修复方式，默认只在本地修复
+    /// How structured answers are repaired when they can't be parsed locally; local repair only by default
+    pub(crate) json_repair: JsonRepair,
+
+    /// 工具结果交给模型前的提示注入检测策略
+    /// Prompt-injection detection policy for tool results before they go back to the model
+    pub injection: Option<InjectionPolicy>,
+
+    /// 提示注入检测命中的片段
+    /// Segments hit by prompt-injection detection
+    pub injection_findings: Vec<InjectionFinding>,
+
+    /// 回答输出后依次执行的守卫
+    /// Guards run on every answer
+    pub guards: GuardChain,
+
+    /// 最近一次回答的计时，流式回答在流读完后写入
+    /// Timing of the latest answer; streaming answers fill it once the stream is drained
+    last_timing: Arc<Mutex<Option<AnswerTiming>>>,
+
+    /// 当前回答已经重发的次数（停滞重试、守卫重试与续写），写入请求span的 `retry_count`
+    /// Times the current answer has been requested again (stall retries, guard retries and continuations), written to
+    /// `retry_count` of the request span
+    pub(crate) retry_count: u32,
+
+    /// 流式回答的事件回调
+    /// Event callback for streaming answers
+    pub stream_callback: Option<StreamCallback>,
+
+    /// 多角色对话中非发言角色消息的呈现方式
+    /// How messages of non-speaking characters are rendered
+    pub transcript_style: TranscriptStyle,
+
+    shared_session: Option<SharedSession>,
+
+    /// 上下文组装策略，默认发送完整分支
+    /// Context assembly strategy; sends the whole branch by default
+    pub context_strategy: Arc<dyn ContextStrategy>,
+
+    /// 请求的 `seed` 参数，支持的服务商会尽量给出确定的回答
+    /// `seed` request parameter; supporting providers make a best effort to answer deterministically
+    pub seed: Option<u64>,
+
+    /// OpenRouter 的服务商路由偏好，作为请求体的 `provider` 字段发送
+    /// OpenRouter provider routing preferences, sent as the `provider` field of the request body
+    pub provider_preferences: Option<ProviderPreferences>,
+
+    /// 最近一次发出的请求体
+    /// Latest request body sent
+    last_request: Option<serde_json::Value>,
+
+    /// 最近一次回答的指纹与结束原因，流式回答在流读完后写入
+    /// Fingerprint and finish reason of the latest answer; streaming answers fill them once the stream is drained
+    last_meta: Arc<Mutex<ResponseMeta>>,
+
+    /// 回答因长度截断时自动请求续写并拼接
+    /// Automatically ask for a continuation and stitch it on when an answer is cut off by length
+    pub auto_continue: bool,
+
+    /// 单个回答最多续写的次数
+    /// Maximum continuations for one answer
+    pub max_continuations: usize,
+
+    /// 组装结构化输出描述时不呈现的字段，默认为 [`DEFAULT_HIDDEN_FIELDS`]
+    /// Fields left out when the structured output description is assembled; [`DEFAULT_HIDDEN_FIELDS`] by default
+    pub hidden_fields: Vec<String>,
+
+    /// 流式回答是否为结构化输出，是则同时以 [`StreamEvent::PartialJson`] 给出到目前为止的值
+    /// Whether the streaming answer is structured output; if so the value so far is also reported
+    /// as [`StreamEvent::PartialJson`]
+    pub(crate) structured_stream: bool,
+
+    /// 构建请求时填入系统与角色提示占位符的变量
+    /// Variables filled into placeholders of system and character prompts when a request is built
+    pub prompt_variables: PromptVariables,
+
+    /// 每次构建请求时附加系统说明的上下文提供者，默认为空
+    /// Context providers adding system notes every time a request is built; none by default
+    pub context_providers: Vec<Arc<dyn ContextProvider>>,
+
+    /// 大块工具结果的转存策略，未设置时工具结果原样写入会话
+    /// Offload policy for large tool results; results go into the session as is when unset
+    pub blob_offload: Option<BlobOffload>,
+
+    /// 回答 [`ASK_USER_TOOL`](crate::chat::ask_user::ASK_USER_TOOL) 提问的宿主，未设置时该工具不可用
+    /// Host answering [`ASK_USER_TOOL`](crate::chat::ask_user::ASK_USER_TOOL) questions; the tool is unavailable when unset
+    pub user_responder: Option<Arc<dyn UserResponder>>,
+
+    /// 生命周期事件的发送端，未设置时不产生事件
+    /// Sender of lifecycle events; no events are produced when unset
+    pub events: Option<ChatEvents>,
+
+    /// 每个请求附带的HTTP头，创建时取自API信息
+    /// HTTP headers sent with every request; taken from the API info on creation
+    pub headers: HashMap<String, String>,
+
+    /// 只用于下一次回答的HTTP头，见 [`set_request_headers`](Self::set_request_headers)
+    /// HTTP headers for the next answer only, see [`set_request_headers`](Self::set_request_headers)
+    request_headers: HashMap<String, String>,
+}
+
+impl std::fmt::Debug for BaseChat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BaseChat")
+            .field("model", &self.model)
+            .field("base_url", &self.base_url)
+            .field("api_key", &mask_secret(&self.api_key))
+            .field("character_prompt", &self.character_prompt)
+            .field("session", &self.session)
+            .field("usage", &self.usage.snapshot())
+            .field("need_stream", &self.need_stream)
+            .field("priority", &self.priority)
+            .field("safety", &self.safety)
+            .field("guards", &self.guards)
+            .field("transcript_style", &self.transcript_style)
+            .field("context_strategy", &self.context_strategy)
+            .field("seed", &self.seed)
+            .field("provider_preferences", &self.provider_preferences)
+            .field("auto_continue", &self.auto_continue)
+            .field("hidden_fields", &self.hidden_fields)
+            .field("prompt_variables", &self.prompt_variables)
+            .field("context_providers", &self.context_providers)
+            .field("blob_offload", &self.blob_offload)
+            .field("user_responder", &self.user_responder)
+            .field("events", &self.events)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
 }
 
 impl BaseChat {
-    pub fn new_with_api_name(api_name: &str, character_prompt: &str, need_stream: bool) -> Self {
-        let api_info = Config::get_api_info_with_name(api_name.to_string()).unwrap();
+    pub fn new_with_api_info(api_info: ApiInfo, character_prompt: &str, need_stream: bool) -> Self {
+        Self::new_in_runtime(Runtime::global(), api_info, character_prompt, need_stream)
+    }
+
+    /// 在给定运行时中以API信息新建对话，传输层取自该运行时
+    /// New chat from API information within a given runtime, taking the transport from that runtime
+    fn new_in_runtime(runtime: Arc<Runtime>, api_info: ApiInfo, character_prompt: &str, need_stream: bool) -> Self {
+        let transport = runtime
+            .get_transport(&api_info.base_url)
+            .unwrap_or_else(|| Arc::new(ReqwestTransport::new(api_info.client)));
+        let mut chat = Self::new_with_transport(runtime, transport, character_prompt, need_stream);
+        chat.model = api_info.model;
+        chat.base_url = api_info.base_url;
+        chat.api_key = api_info.api_key;
+        chat.headers = api_info.headers;
+        chat
+    }
 
+    /// 以给定传输层新建尚未指定模型与来源的对话
+    /// New chat on a given transport with no model or source set yet
+    fn new_with_transport(
+        runtime: Arc<Runtime>,
+        transport: Arc<dyn ChatTransport>,
+        character_prompt: &str,
+        need_stream: bool,
+    ) -> Self {
         Self {
-            model: api_info.model,
-            base_url: api_info.base_url,
-            api_key: api_info.api_key,
-            client: api_info.client,
+            model: String::new(),
+            base_url: String::new(),
+            api_key: String::new(),
+            transport,
+            runtime,
             character_prompt: character_prompt.to_string(),
             session: Session::new(),
-            usage: 0,
+            usage: Arc::new(UsageCounters::new()),
             need_stream,
+            priority: RequestPriority::default(),
+            safety: None,
+            safety_annotations: Vec::new(),
+            sanitize: None,
+            json_repair: JsonRepair::default(),
+            injection: None,
+            injection_findings: Vec::new(),
+            guards: GuardChain::new(),
+            last_timing: Arc::new(Mutex::new(None)),
+            retry_count: 0,
+            stream_callback: None,
+            transcript_style: TranscriptStyle::default(),
+            shared_session: None,
+            context_strategy: Arc::new(FullPath),
+            seed: None,
+            provider_preferences: None,
+            last_request: None,
+            last_meta: Arc::new(Mutex::new(ResponseMeta::default())),
+            auto_continue: false,
+            max_continuations: 3,
+            hidden_fields: DEFAULT_HIDDEN_FIELDS.iter().map(|field| field.to_string()).collect(),
+            structured_stream: false,
+            prompt_variables: PromptVariables::new(),
+            context_providers: Vec::new(),
+            blob_offload: None,
+            user_responder: None,
+            events: None,
+            headers: HashMap::new(),
+            request_headers: HashMap::new(),
         }
     }
 
+    pub fn new_with_api_name(api_name: &str, character_prompt: &str, need_stream: bool) -> Self {
+        let api_info = Config::get_api_info_with_name(api_name.to_string()).unwrap();
+        Self::new_with_api_info(api_info, character_prompt, need_stream)
+    }
+
     pub fn new_with_model_capability(
         model_capability: ModelCapability,
         character_prompt: &str,
         need_stream: bool,
     ) -> Self {
         let api_info = Config::get_api_info_with_capability(model_capability.clone()).unwrap();
+        Self::new_with_api_info(api_info, character_prompt, need_stream)
+    }
 
-        Self {
-            model: api_info.model,
-            base_url: api_info.base_url,
-            api_key: api_info.api_key,
-            client: api_info.client,
-            character_prompt: character_prompt.to_string(),
-            session: Session::new(),
-            usage: 0,
-            need_stream,
+    /// 为辅助任务新建后台优先级的对话，交互式请求可以先于它获得并发许可
+    /// New background-priority chat for an auxiliary task, so interactive requests get permits ahead of it
+    pub fn new_with_auxiliary_task(
+        task: AuxiliaryTask,
+        character_prompt: &str,
+        need_stream: bool,
+    ) -> Self {
+        let api_info = Config::get_api_info_with_auxiliary_task(&task).unwrap();
+        let mut chat = Self::new_with_api_info(api_info, character_prompt, need_stream);
+        chat.priority = RequestPriority::Background;
+        chat
+    }
+
+    /// 在指定运行时中按名称查找API并创建对话，之后的并发控制、工具调用与辅助任务都使用该运行时
+    /// Create a chat from an API looked up by name in the given runtime; concurrency control, tool calls and
+    /// auxiliary tasks use that runtime from then on
+    ///
+    /// # 参数 (Parameters)
+    /// * `runtime` - 运行时
+    ///   - Runtime
+    /// * `api_name` - API名称
+    ///   - API name
+    /// * `character_prompt` - 角色提示
+    ///   - Character prompt
+    /// * `need_stream` - 是否流式输出
+    ///   - Whether to stream the output
+    ///
+    /// # 返回 (Returns)
+    /// * `Result<Self, ChatError>` - 运行时中没有该名称的API时返回 `ApiNotFound`
+    ///   - Returns `ApiNotFound` when the runtime has no API of that name
+    pub fn new_with_runtime(
+        runtime: Arc<Runtime>,
+        api_name: &str,
+        character_prompt: &str,
+        need_stream: bool,
+    ) -> Result<Self, ChatError> {
+        let api_info = runtime
+            .get_api_info_with_name(api_name)
+            .change_context_lazy(|| ChatError::ApiNotFound(api_name.to_string()))?;
+        Ok(Self::new_in_runtime(runtime, api_info, character_prompt, need_stream))
+    }
+
+    /// 换用另一个运行时；该运行时替换了当前来源的传输层时一并换用
+    /// Switch to another runtime, also taking its transport when it replaced the one of the current source
+    pub fn set_runtime(&mut self, runtime: Arc<Runtime>) {
+        if let Some(transport) = runtime.get_transport(&self.base_url) {
+            self.transport = transport;
         }
+        self.runtime = runtime;
+    }
+
+    /// 在给定运行时中为辅助任务新建后台优先级的对话，运行时中没有可用的API时返回 `ApiNotFound`
+    /// New background-priority chat for an auxiliary task within the given runtime; returns `ApiNotFound` when the
+    /// runtime has no API for it
+    pub fn new_auxiliary_in_runtime(
+        runtime: Arc<Runtime>,
+        task: AuxiliaryTask,
+        character_prompt: &str,
+    ) -> Result<Self, ChatError> {
+        let api_info = runtime
+            .get_api_info_with_auxiliary_task(&task)
+            .change_context_lazy(|| ChatError::ApiNotFound(format!("{task:?}")))?;
+        let mut chat = Self::new_in_runtime(runtime, api_info, character_prompt, false);
+        chat.priority = RequestPriority::Background;
+        Ok(chat)
+    }
+
+    /// 在本对话的运行时中为辅助任务新建后台优先级的对话
+    /// New background-priority chat for an auxiliary task within this chat's runtime
+    pub fn new_auxiliary_chat(&self, task: AuxiliaryTask, character_prompt: &str) -> Result<Self, ChatError> {
+        Self::new_auxiliary_in_runtime(self.runtime.clone(), task, character_prompt)
+    }
+
+    /// 会话为空的后台优先级新对话，沿用本对话的模型、传输层、运行时与请求头，用量计入本对话；用于 JSON 整理等附属请求
+    /// New background-priority chat with an empty session on this chat's model, transport, runtime and headers, with
+    /// usage counted to this chat; used for side requests such as JSON formatting
+    pub fn new_task_chat(&self, character_prompt: &str) -> Self {
+        let mut chat = Self::new_with_transport(self.runtime.clone(), self.transport.clone(), character_prompt, false);
+        chat.model = self.model.clone();
+        chat.base_url = self.base_url.clone();
+        chat.api_key = self.api_key.clone();
+        chat.headers = self.headers.clone();
+        chat.usage = self.usage.clone();
+        chat.priority = RequestPriority::Background;
+        chat.provider_preferences = self.provider_preferences.clone();
+        chat.request_headers = self.request_headers.clone();
+        chat
+    }
+
+    /// 换用另一个API，会话与其余设置不变；传输层随之换成新来源的传输层
+    /// Switch to another API, keeping the session and other settings; the transport is replaced by the new source's
+    pub fn set_api_info(&mut self, api_info: ApiInfo) {
+        self.transport = self
+            .runtime
+            .get_transport(&api_info.base_url)
+            .unwrap_or_else(|| Arc::new(ReqwestTransport::new(api_info.client)));
+        self.model = api_info.model;
+        self.base_url = api_info.base_url;
+        self.api_key = api_info.api_key;
+        self.headers = api_info.headers;
     }
 
     pub fn add_message_with_parent_path(
@@ -112,94 +616,620 @@ impl BaseChat {
         role: Role,
         content: &str,
     ) -> Result<(), ChatError> {
-        self.session
-            .add_with_parent_path(path, role, content.to_string())
-            .change_context(ChatError::SessionError)
+        self.update_session(|session| session.add_with_parent_path(path, role.clone(), content.to_string()))?;
+        self.emit_message_added(role, content);
+        Ok(())
     }
 
     pub fn add_message(&mut self, role: Role, content: &str) -> Result<(), ChatError> {
-        self.session
-            .add_with_default_path(role, content.to_string())
-            .change_context(ChatError::SessionError)
+        self.update_session(|session| session.add_with_default_path(role.clone(), content.to_string()))?;
+        self.emit_message_added(role, content);
+        Ok(())
+    }
+
+    fn emit_message_added(&self, role: Role, content: &str) {
+        self.emit(|| ChatEvent::MessageAdded {
+            path: self.session.default_path.clone(),
+            role,
+            content: content.to_string(),
+        });
     }
 
-    pub fn build_request_body(
+    /// 与其他对话共享同一个会话，各自在不同分支上追加消息
+    /// Share one session with other chats, each appending to its own branch
+    ///
+    /// 共享会话只在追加消息、构建请求体时短暂加锁，流式读取回答期间不持有锁；
+    /// 本对话的默认路径保存在本地，共享会话中的 `default_path` 仅反映最后一次写入
+    /// The shared session is locked only briefly while appending or building a request body and never while an
+    /// answer streams in; this chat's default path lives locally, so `default_path` of the shared session only
+    /// reflects the latest write
+    pub fn share_session(&mut self, shared: SharedSession) -> Result<(), ChatError> {
+        self.shared_session = Some(shared);
+        self.pull_shared_session()
+    }
+
+    /// 修改会话；共享会话时在写锁内以本对话的默认路径执行，并刷新本地副本
+    /// Modify the session; with a shared session this runs under the write lock using this chat's default path and
+    /// refreshes the local copy
+    pub fn update_session<T>(
+        &mut self,
+        update: impl FnOnce(&mut Session) -> std::result::Result<T, MessageError>,
+    ) -> Result<T, ChatError> {
+        let Some(shared) = &self.shared_session else {
+            return update(&mut self.session).change_context(ChatError::SessionError);
+        };
+
+        let mut session = shared
+            .write()
+            .map_err(|_| Report::new(ChatError::SessionError))
+            .attach_printable("Shared session lock poisoned")?;
+        session.default_path = self.session.default_path.clone();
+        // 其他持有者的分支不在本会话的活动路径上，仍被共享时不做结构修剪，以免归档它们或使其路径失效
+        // Branches of other holders are off this session's active paths, so structural pruning waits while the
+        // session is still shared rather than archiving them or leaving their paths stale
+        session.defer_pruning(Arc::strong_count(shared) > 1);
+        let result = update(&mut session).change_context(ChatError::SessionError);
+        session.defer_pruning(false);
+        self.session = session.clone();
+        result
+    }
+
+    /// 从共享会话拉取其他对话追加的消息，保留本对话的默认路径
+    /// Pull messages appended by other chats from the shared session, keeping this chat's default path
+    fn pull_shared_session(&mut self) -> Result<(), ChatError> {
+        let Some(shared) = &self.shared_session else {
+            return Ok(());
+        };
+
+        let session = shared
+            .read()
+            .map_err(|_| Report::new(ChatError::SessionError))
+            .attach_printable("Shared session lock poisoned")?;
+        let default_path = std::mem::take(&mut self.session.default_path);
+        self.session = session.clone();
+        self.session.default_path = default_path;
+        Ok(())
+    }
+
+    /// 添加回答，并把最近一次回答的计时写入该消息的元数据
+    /// Add an answer and store the latest answer timing in its message metadata
+    pub fn add_answer(&mut self, role: Role, content: &str) -> Result<(), ChatError> {
+        self.add_answer_with_parent_path(&self.session.default_path.clone(), role, content)
+    }
+
+    pub fn add_answer_with_parent_path(
+        &mut self,
+        path: &[usize],
+        role: Role,
+        content: &str,
+    ) -> Result<(), ChatError> {
+        self.add_message_with_parent_path(path, role, content)?;
+        if let Some(timing) = self.last_timing() {
+            let timing = serde_json::to_value(timing).change_context(ChatError::SessionError)?;
+            self.update_session(|session| {
+                session.set_message_metadata(&session.default_path.clone(), TIMING_METADATA_KEY, timing)
+            })?;
+        }
+        if let Some(reason) = self.last_finish_reason() {
+            let reason = serde_json::Value::String(reason.as_str().to_string());
+            self.update_session(|session| {
+                session.set_message_metadata(&session.default_path.clone(), FINISH_REASON_METADATA_KEY, reason)
+            })?;
+        }
+        let repro = self.last_repro_info();
+        if !repro.is_empty() {
+            let repro = serde_json::to_value(repro).change_context(ChatError::SessionError)?;
+            self.update_session(|session| {
+                session.set_message_metadata(&session.default_path.clone(), REPRO_METADATA_KEY, repro)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// 最近一次请求的种子与回答的后端指纹
+    /// Seed of the latest request and backend fingerprint of its answer
+    pub fn last_repro_info(&self) -> ReproInfo {
+        ReproInfo {
+            seed: self
+                .last_request
+                .as_ref()
+                .and_then(|request| request["seed"].as_u64()),
+            system_fingerprint: self.last_meta.lock().ok().and_then(|meta| meta.system_fingerprint.clone()),
+        }
+    }
+
+    /// 最近一次请求是否带了原生工具定义
+    /// Whether the latest request carried native tool definitions
+    pub(crate) fn sent_native_tools(&self) -> bool {
+        self.last_request
+            .as_ref()
+            .and_then(|request| request["tools"].as_array())
+            .is_some_and(|tools| !tools.is_empty())
+    }
+
+    /// 最近一次回答的结束原因，服务商未给出时为 `None`
+    /// Finish reason of the latest answer; `None` when the provider did not report one
+    pub fn last_finish_reason(&self) -> Option<FinishReason> {
+        self.last_meta.lock().ok().and_then(|meta| meta.finish_reason.clone())
+    }
+
+    /// 导出最近一次请求的实验包，尚未发出请求时为 `None`
+    /// Export the experiment bundle of the latest request; `None` before any request was sent
+    pub fn repro_bundle(&self) -> Option<ReproBundle> {
+        let request = self.last_request.as_ref()?;
+        Some(ReproBundle::new(&self.model, &self.base_url, request, self.last_repro_info()))
+    }
+
+    /// 最近一次回答的计时
+    /// Timing of the latest answer
+    pub fn last_timing(&self) -> Option<AnswerTiming> {
+        self.last_timing.lock().ok().and_then(|timing| timing.clone())
+    }
+
+    /// 改用自己的用量计数与回答计时，不再与克隆来源的对话共享
+    /// Use usage counters and answer timing of its own instead of sharing them with the chat it was cloned from
+    pub fn detach_counters(&mut self) {
+        self.usage = Arc::new(UsageCounters::new());
+        self.last_timing = Arc::new(Mutex::new(None));
+    }
+
+    /// 当前累计的令牌用量
+    /// Token usage accumulated so far
+    pub fn usage_snapshot(&self) -> UsageSnapshot {
+        self.usage.snapshot()
+    }
+
+    pub async fn build_request_body(
         &mut self,
         end_path: &[usize],
         current_speaker: &Role,
     ) -> Result<serde_json::Value, ChatError> {
-        let messages_json = self
-            .session
-            .assemble_context(end_path, current_speaker)
-            .change_context(ChatError::SessionError)?;
+        // 共享会话时直接在读锁内沿路径组装，不复制整棵会话树
+        // With a shared session, assemble along the path under the read lock instead of copying the whole tree
+        let messages_json = match &self.shared_session {
+            Some(shared) => shared
+                .read()
+                .map_err(|_| Report::new(ChatError::SessionError))
+                .attach_printable("Shared session lock poisoned")?
+                .assemble_context_with_style(end_path, current_speaker, self.transcript_style),
+            None => self
+                .session
+                .assemble_context_with_style(end_path, current_speaker, self.transcript_style),
+        }
+        .change_context(ChatError::SessionError)?;
+        let mut messages_json = self.context_strategy.select(&self.model, messages_json).await?;
 
-        Ok(json!({
+        // 多角色对话中，每轮把当前发言角色的提示作为首条系统消息
+        // In multi-character chats, the current speaker's prompt leads every turn as the first system message
+        if matches!(current_speaker, Role::Character(_)) && !self.character_prompt.is_empty() {
+            messages_json.insert(
+                0,
+                HashMap::from([
+                    ("role".to_string(), "system".to_string()),
+                    ("content".to_string(), self.character_prompt.clone()),
+                ]),
+            );
+        }
+
+        insert_provided_notes(&mut messages_json, &self.context_providers);
+
+        // 在系统与角色提示中填入提示变量
+        // Fill prompt variables into system and character prompts
+        if !self.prompt_variables.is_empty() {
+            for message in messages_json.iter_mut().filter(|m| m.get("role").map(String::as_str) == Some("system")) {
+                if let Some(content) = message.get_mut("content") {
+                    *content = self.prompt_variables.render(content);
+                }
+            }
+        }
+
+        // 按服务商规则合并相邻的同角色消息
+        // Merge adjacent same-role messages according to provider rules
+        let messages_json = normalize_roles(messages_json, self.runtime.get_role_rules(&self.base_url));
+
+        let mut request_body = json!({
             "model": self.model,
-            "messages": messages_json,
+            "messages": expand_file_parts(messages_json),
             "stream": self.need_stream,
-        }))
+        });
+        if self.need_stream {
+            // 让服务商在最后一个分块中返回用量
+            // Ask the provider to send usage in the final chunk
+            request_body["stream_options"] = json!({"include_usage": true});
+        }
+        if let Some(seed) = self.seed {
+            request_body["seed"] = json!(seed);
+        }
+        if let Some(preferences) = &self.provider_preferences {
+            request_body["provider"] = json!(preferences);
+        }
+        if is_openrouter(&self.base_url) {
+            // 让 OpenRouter 在用量中附上费用与缓存、推理令牌数
+            // Have OpenRouter add cost plus cached and reasoning token counts to usage
+            request_body["usage"] = json!({"include": true});
+        }
+
+        Ok(request_body)
     }
 
-    pub async fn send_request(
-        &mut self,
-        request_body: serde_json::Value,
-    ) -> core::result::Result<Response, Error> {
-        self.client
-            .post(&self.base_url)
-            .header("Content-Type", "application/json")
-            .bearer_auth(&self.api_key)
-            .json(&request_body)
-            .send()
+    pub fn set_stream_callback(&mut self, callback: impl Fn(&StreamEvent) + Send + Sync + 'static) {
+        self.stream_callback = Some(Arc::new(callback));
+    }
+
+    pub fn set_context_strategy(&mut self, strategy: impl ContextStrategy + 'static) {
+        self.context_strategy = Arc::new(strategy);
+    }
+
+    pub fn set_auto_continue(&mut self, auto_continue: bool) {
+        self.auto_continue = auto_continue;
+    }
+
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    pub fn set_provider_preferences(&mut self, preferences: ProviderPreferences) {
+        self.provider_preferences = Some(preferences);
+    }
+
+    pub fn set_prompt_variable(&mut self, name: &str, value: impl Into<String>) {
+        self.prompt_variables.set(name, value);
+    }
+
+    /// 设置每次构建请求时求值的提示变量
+    /// Set a prompt variable evaluated every time a request is built
+    pub fn set_dynamic_prompt_variable(&mut self, name: &str, provider: impl Fn() -> String + Send + Sync + 'static) {
+        self.prompt_variables.set_dynamic(name, provider);
+    }
+
+    pub fn add_context_provider(&mut self, provider: impl ContextProvider + 'static) {
+        self.context_providers.push(Arc::new(provider));
+    }
+
+    pub fn set_blob_offload(&mut self, offload: BlobOffload) {
+        self.blob_offload = Some(offload);
+    }
+
+    /// 设置本对话每个请求附带的HTTP头，替换同名的来源或API的头
+    /// Set an HTTP header sent with every request of this chat, replacing a source or API header of the same name
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.insert(name.to_string(), value.to_string());
+    }
+
+    /// 设置只用于下一次回答（包括其续写与重试）的HTTP头，例如追踪头；优先于本对话的头，用过即清除
+    /// Set HTTP headers for the next answer only, including its continuations and retries, e.g. tracing headers;
+    /// they take precedence over the chat's headers and are cleared once used
+    pub fn set_request_headers(&mut self, headers: HashMap<String, String>) {
+        self.request_headers = headers;
+    }
+
+    /// 设置事件发送端，同一个 [`ChatEvents`] 可以交给多个对话，订阅一处即可观察全部
+    /// Set the event sender; one [`ChatEvents`] can be given to several chats so a single subscription observes them all
+    pub fn set_events(&mut self, events: ChatEvents) {
+        self.events = Some(events);
+    }
+
+    pub(crate) fn emit(&self, event: impl FnOnce() -> ChatEvent) {
+        if let Some(events) = &self.events {
+            events.emit(event());
+        }
+    }
+
+    pub fn set_user_responder(&mut self, responder: impl UserResponder + 'static) {
+        self.user_responder = Some(Arc::new(responder));
+    }
+
+    /// 把经过检测与清理的工具结果加入会话，超过转存阈值的结果只保留预览与引用，引用同时记在消息元数据中
+    /// Add a screened and sanitized tool result to the session; results over the offload threshold keep only a
+    /// preview and a reference, which is also recorded in the message metadata
+    pub(crate) fn add_tool_output(&mut self, role: Role, output: &str, tool: Option<&str>) -> Result<(), ChatError> {
+        let output = &self.screen_tool_output(output, tool)?;
+        let offloaded = match &self.blob_offload {
+            Some(offload) => offload.offload(output).change_context(ChatError::BlobStoreError)?,
+            None => None,
+        };
+        let Some((content, blob)) = offloaded else {
+            return self.add_message(role, output);
+        };
+        self.add_message(role, &content)?;
+        self.update_session(|session| {
+            session.set_message_metadata(&session.default_path.clone(), BLOB_METADATA_KEY, json!(blob))
+        })
+    }
+
+    /// 读取被转存的完整工具结果，`hash` 可带 `blob:` 前缀；未设置转存策略或内容不存在时返回 `None`
+    /// Read a full offloaded tool result; `hash` may carry the `blob:` prefix. `None` when no offload policy is set
+    /// or the content does not exist
+    pub fn resolve_blob(&self, hash: &str) -> Result<Option<String>, ChatError> {
+        let Some(offload) = &self.blob_offload else {
+            return Ok(None);
+        };
+        read_blob(offload.store().as_ref(), hash, 0, usize::MAX).change_context(ChatError::BlobStoreError)
+    }
+
+    pub fn set_hidden_fields<I, S>(&mut self, fields: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.hidden_fields = fields.into_iter().map(Into::into).collect();
+    }
+
+    pub fn set_priority(&mut self, priority: RequestPriority) {
+        self.priority = priority;
+    }
+
+    pub fn set_safety_policy(&mut self, policy: SafetyPolicy) {
+        self.safety = Some(policy);
+    }
+
+    /// 设置本地解析失败后的修复方式；交给模型整理（`Caller`、`Auxiliary`）需要在此显式开启
+    /// Set the repair used once local parsing fails; handing it to a model (`Caller`, `Auxiliary`) must be turned
+    /// on explicitly here
+    pub fn set_json_repair(&mut self, repair: JsonRepair) {
+        self.json_repair = repair;
+    }
+
+    pub fn set_sanitize_policy(&mut self, policy: SanitizePolicy) {
+        self.sanitize = Some(policy);
+    }
+
+    pub fn set_injection_policy(&mut self, policy: InjectionPolicy) {
+        self.injection = Some(policy);
+    }
+
+    /// 按注入检测策略处理工具结果并记录命中，再按清理策略处理；`tool` 为产生结果的工具名
+    /// Handle a tool result with the injection policy, recording the hits, then with the sanitization policy;
+    /// `tool` names the tool producing the result
+    pub fn screen_tool_output(&mut self, output: &str, tool: Option<&str>) -> Result<String, ChatError> {
+        let output = match &self.injection {
+            Some(policy) => {
+                let (screened, findings) = policy.screen(output, tool);
+                self.injection_findings.extend(findings);
+                screened
+            }
+            None => output.to_string(),
+        };
+        self.sanitize(&output, UntrustedSource::ToolResult)
+    }
+
+    /// 按清理策略处理来自 `source` 的不可信内容，未设置策略时原样返回
+    /// Sanitize untrusted content from `source` with the sanitization policy; returned unchanged without a policy
+    pub fn sanitize(&self, text: &str, source: UntrustedSource) -> Result<String, ChatError> {
+        let Some(policy) = &self.sanitize else {
+            return Ok(text.to_string());
+        };
+        policy
+            .sanitize_from(text, source)
+            .map(|sanitized| sanitized.into_owned())
+            .change_context(ChatError::ContentRejected)
+            .attach_printable_lazy(|| format!("Rejected {:?}: {}", source, redact(text)))
+    }
+
+    /// 按安全策略检查用户输入或模型输出，返回可继续使用的文本；用户输入先按清理策略处理
+    /// Screen user input or model output with the safety policy, returning the text to continue with; user input
+    /// is sanitized with the sanitization policy first
+    pub async fn screen(&mut self, text: &str, stage: SafetyStage) -> Result<String, ChatError> {
+        let sanitized;
+        let text = match stage {
+            SafetyStage::Input => {
+                sanitized = self.sanitize(text, UntrustedSource::UserInput)?;
+                sanitized.as_str()
+            }
+            SafetyStage::Output => text,
+        };
+        let Some(policy) = self.safety.clone() else {
+            return Ok(text.to_string());
+        };
+
+        let annotation = policy
+            .inspect(text, stage)
             .await
+            .change_context(ChatError::SafetyCheckError)?;
+        let Some(annotation) = annotation else {
+            return Ok(text.to_string());
+        };
+
+        let screened = match annotation.action {
+            SafetyAction::Block => {
+                return Err(Report::new(ChatError::ContentFiltered(
+                    annotation.categories.join(", "),
+                )))
+                .attach_printable(format!("Blocked {:?}: {}", stage, redact(text)));
+            }
+            SafetyAction::Redact => SafetyPolicy::placeholder(&annotation.categories),
+            SafetyAction::Annotate => text.to_string(),
+        };
+        self.safety_annotations.push(annotation);
+        Ok(screened)
+    }
+
+    pub fn set_guards(&mut self, guards: GuardChain) {
+        self.guards = guards;
+    }
+
+    /// 执行守卫链；需要重试时在请求体后追加上次回答与修改要求
+    /// Run the guard chain; on retry, append the rejected answer and the violation to the request body
+    pub fn apply_guards(
+        &self,
+        content: &str,
+        attempt: usize,
+        request_body: &mut serde_json::Value,
+    ) -> Result<Option<String>, ChatError> {
+        match self
+            .guards
+            .apply(content, attempt)
+            .change_context(ChatError::GuardViolation)?
+        {
+            GuardOutcome::Accept(content) => Ok(Some(content)),
+            GuardOutcome::Retry(message) => {
+                if let Some(messages) = request_body["messages"].as_array_mut() {
+                    messages.push(json!({"role": "assistant", "content": content}));
+                    messages.push(json!({
+                        "role": "user",
+                        "content": format!("上面的回答不符合要求（{message}），请重新回答"),
+                    }));
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// 获取并发许可，再等到来源的每分钟限额允许发出这次请求
+    /// Acquire a concurrency permit, then wait until the per-minute quota of the source admits this request
+    async fn acquire_permit(&self, request_body: &serde_json::Value) -> Result<OwnedSemaphorePermit, ChatError> {
+        let permit = acquire_source_permit(&self.runtime, &self.base_url, self.priority).await?;
+        wait_for_rate_limit(&self.runtime, &self.base_url, request_body).await;
+        Ok(permit)
+    }
+
+    /// 替换本对话的传输层
+    /// Replace the transport of this chat
+    pub fn set_transport(&mut self, transport: impl ChatTransport + 'static) {
+        self.transport = Arc::new(transport);
+    }
+
+    fn transport_request(&self, request_body: serde_json::Value) -> TransportRequest {
+        TransportRequest {
+            url: self.base_url.clone(),
+            api_key: self.api_key.clone(),
+            headers: self.headers.clone(),
+            body: request_body,
+        }
+    }
+
+    /// 为单次请求创建追踪span，记录模型、来源、用量、延迟与结果
+    /// Create a tracing span for one request carrying model, provider, usage, latency and outcome
+    fn request_span(&self, stream: bool) -> Span {
+        info_span!(
+            "chat_request",
+            model = %self.model,
+            provider = %self.base_url,
+            stream,
+            priority = ?self.priority,
+            prompt_tokens = field::Empty,
+            completion_tokens = field::Empty,
+            total_tokens = field::Empty,
+            usage_source = field::Empty,
+            latency_ms = field::Empty,
+            retry_count = self.retry_count,
+            outcome = field::Empty,
+        )
+    }
+
+    fn record_outcome<T>(span: &Span, started: Instant, result: &Result<T, ChatError>) {
+        span.record("latency_ms", started.elapsed().as_millis() as u64);
+        match result {
+            Ok(_) => span.record("outcome", "ok"),
+            Err(report) => span.record("outcome", report.current_context().to_string()),
+        };
     }
 
     pub async fn get_response(
         &mut self,
         request_body: serde_json::Value,
     ) -> Result<serde_json::Value, ChatError> {
-        let semaphore_permit = THREAD_POOL
-            .get(&self.base_url)
-            .unwrap()
-            .clone()
-            .acquire_owned()
-            .await
-            .unwrap();
+        let span = self.request_span(false);
+        let started = Instant::now();
+        self.last_timing = Arc::new(Mutex::new(None));
+        self.last_request = Some(request_body.clone());
+        self.last_meta = Arc::new(Mutex::new(ResponseMeta::default()));
+        let result = self
+            .fetch_response(request_body.clone())
+            .instrument(span.clone())
+            .await;
+        Self::record_outcome(&span, started, &result);
+
+        if let Ok(response) = &result {
+            let timing = AnswerTiming::new(
+                started.elapsed().as_millis() as u64,
+                None,
+                &response["usage"],
+            );
+            UsageTracker::record(&self.base_url, &self.model, &timing);
+            self.last_timing = Arc::new(Mutex::new(Some(timing)));
+            let mut meta = ResponseMeta::default();
+            meta.observe(response);
+            self.last_meta = Arc::new(Mutex::new(meta));
+        }
 
-        let response = self.send_request(request_body.clone()).await;
+        if let Some(recorder) = Recorder::current() {
+            let mut entry = build_entry(&self.model, &self.base_url, false, &request_body, started);
+            match &result {
+                Ok(response) => entry.response = Some(response.clone()),
+                Err(report) => entry.error = Some(format!("{:?}", report)),
+            }
+            write_entry(&recorder, &entry);
+        }
+
+        result
+    }
+
+    async fn fetch_response(
+        &mut self,
+        request_body: serde_json::Value,
+    ) -> Result<serde_json::Value, ChatError> {
+        validate_request_body(&self.runtime, &self.base_url, &request_body)?;
+
+        if let Some(store) = self.runtime.replay_store(&self.base_url) {
+            let _semaphore_permit = self.acquire_permit(&request_body).await?;
+            let parsed = store
+                .response(&request_body)
+                .change_context(ChatError::ReplayError)?;
+            return self.account_usage(&request_body, parsed);
+        }
+
+        if let Some(mock) = self.runtime.mock_api(&self.base_url) {
+            let _semaphore_permit = self.acquire_permit(&request_body).await?;
+            let parsed = match mock.reply(&request_body).await {
+                MockReply::Text(text) => MockApi::completion_body(&request_body, &text),
+                MockReply::Raw(body) => body,
+                reply => return Err(mock_error(reply, &self.runtime, &self.base_url, &request_body)),
+            };
+            return self.account_usage(&request_body, parsed);
+        }
+
+        let semaphore_permit = self.acquire_permit(&request_body).await?;
+
+        let request = self.transport_request(request_body.clone());
+        let response = self.transport.send(&request).await;
 
         drop(semaphore_permit);
 
-        match response {
-            Ok(res) => {
-                let res = res.error_for_status().map_err(|e| {
-                    Report::new(ChatError::HttpError(e.status().unwrap().as_u16()))
-                        .attach_printable(format!("HTTP error with request body: {}", request_body))
-                })?;
-
-                let parsed: serde_json::Value = res
-                    .json()
-                    .await
-                    .change_context(ChatError::ParseResponseError)
-                    .attach_printable("Failed to parse response JSON")?;
-
-                self.usage += parsed["usage"]["total_tokens"]
-                    .as_i64()
-                    .ok_or_else(|| Report::new(ChatError::MissingUsageData))
-                    .attach_printable("Missing usage data in response")?
-                    as i32;
-
-                Ok(parsed)
-            }
-            Err(e) => {
-                if e.is_timeout() {
-                    Err(Report::new(ChatError::TimeoutError)
-                        .attach_printable(format!("Request timeout: {}", request_body)))
-                } else {
-                    Err(Report::new(ChatError::UnknownError)
-                        .attach_printable(format!("Network error: {} - {}", e, request_body)))
-                }
+        let parsed = response.map_err(|report| transport_error(report, &self.runtime, &self.base_url, &request_body))?;
+        self.account_usage(&request_body, parsed)
+    }
+
+    /// 累计令牌用量；服务商未返回 `usage` 时用本地分词器估算并写回响应
+    /// Accumulate token usage; when the provider omits `usage` it is estimated locally and written back into the response
+    fn account_usage(
+        &mut self,
+        request_body: &serde_json::Value,
+        mut parsed: serde_json::Value,
+    ) -> Result<serde_json::Value, ChatError> {
+        if parsed["usage"]["total_tokens"].as_u64().is_none() {
+            let content = Self::get_content_from_resp(&parsed).unwrap_or_default();
+            warn!("Response from {} has no usage data, estimating tokens locally", self.model);
+            parsed["usage"] = estimate_usage(&self.model, request_body, &content);
+        }
+
+        let span = Span::current();
+        for field in ["prompt_tokens", "completion_tokens", "total_tokens"] {
+            if let Some(tokens) = parsed["usage"][field].as_u64() {
+                span.record(field, tokens);
             }
         }
+        span.record("usage_source", field::debug(UsageSource::of(&parsed["usage"])));
+
+        self.usage.add_usage(&parsed["usage"]);
+        let prompt_tokens = parsed["usage"]["prompt_tokens"].as_u64().unwrap_or_default();
+        let completion_tokens = parsed["usage"]["completion_tokens"].as_u64().unwrap_or_default();
+        self.update_session(|session| {
+            session.usage.add(prompt_tokens, completion_tokens);
+            Ok(())
+        })?;
+
+        Ok(parsed)
     }
 
     pub fn get_content_from_resp(resp: &serde_json::Value) -> Result<String, ChatError> {
@@ -207,7 +1237,8 @@ impl BaseChat {
             .get("choices")
             .and_then(|c| c.get(0))
             .and_then(|c| c.get("message"))
-            .and_then(|m| m.get("content"));
+            .and_then(|m| m.get("content"))
+            .and_then(|c| c.as_str());
 
         match content {
             Some(content) => Ok(content.to_string()),
@@ -221,61 +1252,277 @@ impl BaseChat {
         request_body: serde_json::Value,
     ) -> Result<
         (
-            impl Stream<Item = reqwest::Result<Bytes>> + Send + Unpin,
+            impl Stream<Item = StreamChunk> + Send + Unpin + use<>,
             OwnedSemaphorePermit,
         ),
         ChatError,
     > {
-        let semaphore_permit = THREAD_POOL
-            .get(&self.base_url)
-            .unwrap()
-            .clone()
-            .acquire_owned()
+        let span = self.request_span(true);
+        let started = Instant::now();
+        self.last_timing = Arc::new(Mutex::new(None));
+        self.last_request = Some(request_body.clone());
+        self.last_meta = Arc::new(Mutex::new(ResponseMeta::default()));
+        let result = self
+            .open_stream(request_body.clone())
+            .instrument(span.clone())
+            .await;
+        // 流打开后由 TimedStream 在读完时记录用量、延迟与结果
+        // Once the stream is open, TimedStream records usage, latency and outcome when it is drained
+        if result.is_err() {
+            Self::record_outcome(&span, started, &result);
+        }
+        let result = result.map(|(stream, semaphore_permit)| match self.runtime.get_stall_policy(&self.base_url) {
+            Some(policy) => (detect_stalls(stream, policy.timeout), semaphore_permit),
+            None => (stream, semaphore_permit),
+        });
+
+        let recording = Recorder::current().map(|recorder| {
+            let entry = build_entry(&self.model, &self.base_url, true, &request_body, started);
+            (recorder, entry)
+        });
+
+        match result {
+            Ok((stream, semaphore_permit)) => Ok((
+                TimedStream::new(
+                    RecordingStream::new(
+                        stream,
+                        recording.map(|(recorder, entry)| (recorder, entry, started)),
+                    ),
+                    &self.base_url,
+                    &self.model,
+                    request_body,
+                    started,
+                    self.last_timing.clone(),
+                    self.last_meta.clone(),
+                )
+                .with_usage_counters(self.usage.clone())
+                .with_span(span),
+                semaphore_permit,
+            )),
+            Err(report) => {
+                if let Some((recorder, mut entry)) = recording {
+                    entry.error = Some(format!("{:?}", report));
+                    write_entry(&recorder, &entry);
+                }
+                Err(report)
+            }
+        }
+    }
+
+    async fn open_stream(
+        &mut self,
+        request_body: serde_json::Value,
+    ) -> Result<(ByteStream, OwnedSemaphorePermit), ChatError> {
+        validate_request_body(&self.runtime, &self.base_url, &request_body)?;
+
+        if let Some(store) = self.runtime.replay_store(&self.base_url) {
+            let semaphore_permit = self.acquire_permit(&request_body).await?;
+            let body = store
+                .stream_body(&request_body)
+                .change_context(ChatError::ReplayError)?;
+            let stream: ByteStream = Box::pin(stream::iter([Ok(Bytes::from(body))]));
+            return Ok((stream, semaphore_permit));
+        }
+
+        if let Some(mock) = self.runtime.mock_api(&self.base_url) {
+            let semaphore_permit = self.acquire_permit(&request_body).await?;
+            return match mock.reply(&request_body).await {
+                MockReply::Text(text) => Ok((mock.sse_stream(&request_body, &text), semaphore_permit)),
+                reply => Err(mock_error(reply, &self.runtime, &self.base_url, &request_body)),
+            };
+        }
+
+        let semaphore_permit = self.acquire_permit(&request_body).await?;
+
+        let request = self.transport_request(request_body.clone());
+        let stream = self
+            .transport
+            .stream(&request)
+            .await
+            .map_err(|report| transport_error(report, &self.runtime, &self.base_url, &request_body))?;
+        Ok((stream, semaphore_permit))
+    }
+
+    /// 请求并提取回答内容，不写入会话；开启 `auto_continue` 时因长度截断的回答会续写拼接
+    /// Request and extract the answer content without touching the session;
+    /// with `auto_continue` an answer cut off by length is continued and stitched together
+    ///
+    /// 续写请求在原请求末尾附上已得到的回答与 [`CONTINUE_PROMPT`]，`last_finish_reason` 反映最后一次请求
+    /// A continuation appends the answer so far and [`CONTINUE_PROMPT`] to the original request; `last_finish_reason` reflects the final request
+    ///
+    /// # 参数 (Parameters)
+    /// * `request_body` - 请求体
+    ///   - Request body
+    /// * `stream` - 是否以流式读取回答
+    ///   - Whether to read the answer as a stream
+    pub async fn fetch_answer(&mut self, request_body: serde_json::Value, stream: bool) -> Result<String, ChatError> {
+        self.emit(|| ChatEvent::AnswerStarted {
+            model: self.model.clone(),
+            stream,
+        });
+        let request_headers = std::mem::take(&mut self.request_headers);
+        let chat_headers = self.headers.clone();
+        self.headers.extend(request_headers);
+        let result = self.fetch_continued_answer(request_body, stream).await;
+        self.headers = chat_headers;
+        match &result {
+            Ok(content) => self.emit(|| ChatEvent::AnswerCompleted {
+                model: self.model.clone(),
+                content: content.clone(),
+            }),
+            Err(report) => self.emit(|| ChatEvent::Error {
+                model: self.model.clone(),
+                message: report.current_context().to_string(),
+            }),
+        }
+        result
+    }
+
+    async fn fetch_continued_answer(&mut self, request_body: serde_json::Value, stream: bool) -> Result<String, ChatError> {
+        let mut content = self.fetch_answer_once(request_body.clone(), stream).await?;
+
+        let mut continuations = 0;
+        let result = loop {
+            if !self.auto_continue
+                || continuations >= self.max_continuations
+                || self.last_finish_reason() != Some(FinishReason::Length)
+            {
+                break Ok(content);
+            }
+            let mut continue_body = request_body.clone();
+            if let Some(messages) = continue_body["messages"].as_array_mut() {
+                messages.push(json!({"role": "assistant", "content": content}));
+                messages.push(json!({"role": "user", "content": CONTINUE_PROMPT}));
+            }
+            continuations += 1;
+            self.retry_count += 1;
+            match self.fetch_answer_once(continue_body, stream).await {
+                Ok(continued) => content.push_str(&continued),
+                Err(report) => break Err(report),
+            }
+        };
+        self.retry_count -= continuations as u32;
+        result
+    }
+
+    async fn fetch_answer_once(&mut self, request_body: serde_json::Value, stream: bool) -> Result<String, ChatError> {
+        if stream {
+            return self.get_stream_content(request_body).await;
+        }
+        let response = self
+            .get_response(request_body)
             .await
-            .unwrap();
+            .attach_printable("Failed to get response")?;
+        Self::get_content_from_resp(&response).attach_printable("Failed to extract content from response")
+    }
 
-        let response = self.send_request(request_body.clone()).await;
+    /// 发起流式请求并读完回答，随后把流末尾的用量计入 `usage`
+    /// Send a streaming request and drain the answer, then add the usage from the end of the stream to `usage`
+    ///
+    /// 设置了流式回调时，正文增量与检测到的 `<ToolUse>` 调用会以事件形式实时给出；
+    /// 结构化回答（`get_json_answer`）还会给出逐步补全的 [`StreamEvent::PartialJson`]
+    /// With a stream callback set, prose deltas and detected `<ToolUse>` calls are reported as events in real time;
+    /// structured answers (`get_json_answer`) also report progressively completed [`StreamEvent::PartialJson`] values
+    ///
+    /// 设置了 [`StallAction::Retry`](crate::chat::transport::StallAction) 的来源在流停滞时从头重新请求，回调会再次收到重新开始的回答
+    /// Sources with [`StallAction::Retry`](crate::chat::transport::StallAction) request again from scratch when the stream stalls;
+    /// the callback then receives the restarted answer again
+    pub async fn get_stream_content(&mut self, request_body: serde_json::Value) -> Result<String, ChatError> {
+        let max_retries = self.runtime.get_stall_policy(&self.base_url).map_or(0, |policy| policy.max_retries());
+        let mut retries = 0;
+        let result = loop {
+            match self.get_stream_content_once(request_body.clone()).await {
+                Err(report) if matches!(report.current_context(), ChatError::StreamStalled) && retries < max_retries => {
+                    retries += 1;
+                    self.retry_count += 1;
+                    warn!("Stream from {} stalled, retrying ({retries}/{max_retries})", self.base_url);
+                }
+                result => break result,
+            }
+        };
+        self.retry_count -= retries;
+        result
+    }
 
-        match response {
-            Ok(res) => {
-                let res = res.error_for_status().map_err(|e| {
-                    Report::new(ChatError::HttpError(e.status().unwrap().as_u16()))
-                        .attach_printable(format!("HTTP error with request body: {}", request_body))
-                })?;
+    async fn get_stream_content_once(&mut self, request_body: serde_json::Value) -> Result<String, ChatError> {
+        let callback = self.stream_callback.clone();
+        let events = self.events.clone();
+        let (stream, semaphore_permit) = self
+            .get_stream_response(request_body)
+            .await
+            .attach_printable("Failed to get stream response")?;
 
-                Ok((res.bytes_stream(), semaphore_permit))
+        let mut filter = ToolUseFilter::new();
+        let mut partial = self.structured_stream.then(PartialJsonParser::new);
+        let content = Self::get_content_from_stream_resp_with(stream, semaphore_permit, |delta| {
+            if let Some(events) = &events {
+                events.emit(ChatEvent::TokenDelta {
+                    delta: delta.to_string(),
+                });
             }
-            Err(e) => {
-                if e.is_timeout() {
-                    Err(Report::new(ChatError::TimeoutError)
-                        .attach_printable(format!("Request timeout: {}", request_body)))
-                } else {
-                    Err(Report::new(ChatError::UnknownError)
-                        .attach_printable(format!("Network error: {} - {}", e, request_body)))
+            if let Some(callback) = &callback {
+                filter.push(delta).iter().for_each(|event| callback(event));
+                if let Some(value) = partial.as_mut().and_then(|parser| parser.push(delta)) {
+                    callback(&StreamEvent::PartialJson(value));
                 }
             }
+        })
+        .await
+        .attach_printable("Failed to extract content from stream response")?;
+        if let Some(callback) = &callback {
+            filter.finish().iter().for_each(|event| callback(event));
+        }
+
+        if let Some(timing) = self.last_timing() {
+            let prompt_tokens = timing.prompt_tokens.unwrap_or_default();
+            let completion_tokens = timing.completion_tokens.unwrap_or_default();
+            self.update_session(|session| {
+                session.usage.add(prompt_tokens, completion_tokens);
+                Ok(())
+            })?;
         }
+
+        Ok(content)
     }
 
     pub async fn get_content_from_stream_resp(
-        stream: impl Stream<Item = reqwest::Result<Bytes>> + Send + Unpin,
+        stream: impl Stream<Item = StreamChunk> + Send + Unpin,
         semaphore_permit: OwnedSemaphorePermit,
     ) -> Result<String, ChatError> {
-        #[derive(Default)]
-        struct StreamResult {
+        Self::get_content_from_stream_resp_with(stream, semaphore_permit, |_| {}).await
+    }
+
+    /// 提取流式回答内容，每段增量内容到达时调用 `on_delta`
+    /// Extract the content of a streaming answer, calling `on_delta` for every delta as it arrives
+    pub async fn get_content_from_stream_resp_with(
+        stream: impl Stream<Item = StreamChunk> + Send + Unpin,
+        semaphore_permit: OwnedSemaphorePermit,
+        on_delta: impl FnMut(&str) + Send,
+    ) -> Result<String, ChatError> {
+        struct StreamResult<F> {
             content: String,
             usage: Option<serde_json::Value>,
+            on_delta: F,
         }
 
         let result = stream
             .map_err(|err| {
-                Report::new(ChatError::HttpError(0))
-                    .attach_printable(format!("Failed to get response: {}", err))
+                let error = match err {
+                    TransportError::Stalled(_) => ChatError::StreamStalled,
+                    _ => ChatError::HttpError(0),
+                };
+                Report::new(error).attach_printable(format!("Failed to get response: {}", redact(&err.to_string())))
             })
-            .try_fold(StreamResult::default(), |mut result, chunk| async move {
+            .try_fold(
+                StreamResult {
+                    content: String::new(),
+                    usage: None,
+                    on_delta,
+                },
+                |mut result, chunk| async move {
                 String::from_utf8_lossy(&chunk)
                     .split('\n')
-                    .filter(|line| !line.is_empty() && *line != "data: [DONE]")
+                    .filter(|line| !line.is_empty() && !line.starts_with(':') && *line != "data: [DONE]")
                     .try_for_each(|line| {
                         let json_str = line.strip_prefix("data: ").unwrap_or(line);
 
@@ -294,7 +1541,10 @@ impl BaseChat {
                                             .filter_map(|delta| {
                                                 delta.get("content").and_then(|c| c.as_str())
                                             })
-                                            .for_each(|content| result.content.push_str(content));
+                                            .for_each(|content| {
+                                                (result.on_delta)(content);
+                                                result.content.push_str(content);
+                                            });
                                     });
 
                                 json.get("usage")
@@ -304,7 +1554,8 @@ impl BaseChat {
                     })?;
 
                 Ok(result)
-            })
+                },
+            )
             .await?;
 
         drop(semaphore_permit);
@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use error_stack::{Report, Result, ResultExt};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tracing::info;
+
+use crate::chat::chat_base::BaseChat;
+use crate::config::{ApiInfo, Config};
+use crate::utils::common::redact::redact;
+
+#[derive(Debug, Error)]
+pub enum BatchError {
+    #[error("Invalid batch: {0}")]
+    InvalidBatch(String),
+
+    #[error("Failed to upload batch input file")]
+    UploadError,
+
+    #[error("Failed to create batch job")]
+    CreateError,
+
+    #[error("Failed to query batch job {0}")]
+    PollError(String),
+
+    #[error("Batch job {0} ended with status {1}")]
+    JobFailed(String, String),
+
+    #[error("Batch job {0} did not finish in time")]
+    Timeout(String),
+
+    #[error("Failed to download batch results")]
+    DownloadError,
+
+    #[error("Failed to parse batch results")]
+    ParseError,
+}
+
+/// 批处理任务状态
+/// Batch job status
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchStatus {
+    pub id: String,
+
+    /// validating / in_progress / finalizing / completed / failed / expired / cancelling / cancelled
+    pub status: String,
+
+    #[serde(default)]
+    pub output_file_id: Option<String>,
+
+    #[serde(default)]
+    pub error_file_id: Option<String>,
+
+    #[serde(default)]
+    pub request_counts: BatchRequestCounts,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BatchRequestCounts {
+    pub total: usize,
+
+    pub completed: usize,
+
+    pub failed: usize,
+}
+
+impl BatchStatus {
+    /// 任务是否已结束（无论成功与否）
+    /// Whether the job has reached a terminal state
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.status.as_str(),
+            "completed" | "failed" | "expired" | "cancelled"
+        )
+    }
+}
+
+/// 批处理中单个请求的结果
+/// Result of one request in a batch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchItem {
+    /// 调用方提供的ID
+    /// Caller-provided ID
+    pub custom_id: String,
+
+    pub status_code: u16,
+
+    #[serde(default)]
+    pub body: Option<serde_json::Value>,
+
+    #[serde(default)]
+    pub error: Option<serde_json::Value>,
+}
+
+impl BatchItem {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && (200..300).contains(&self.status_code)
+    }
+
+    /// 提取助手回复内容
+    /// Extract the assistant content
+    pub fn content(&self) -> Option<String> {
+        self.body
+            .as_ref()
+            .filter(|_| self.is_success())
+            .and_then(|body| BaseChat::get_content_from_resp(body).ok())
+    }
+}
+
+/// OpenAI 风格批处理任务构建器
+/// Builder for OpenAI-style batch jobs
+#[derive(Clone, Debug)]
+pub struct BatchJob {
+    api_info: ApiInfo,
+
+    requests: Vec<(String, serde_json::Value)>,
+
+    completion_window: String,
+
+    poll_interval: Duration,
+
+    max_wait: Option<Duration>,
+}
+
+impl BatchJob {
+    pub fn new_with_api_info(api_info: ApiInfo) -> Self {
+        Self {
+            api_info,
+            requests: Vec::new(),
+            completion_window: "24h".to_string(),
+            poll_interval: Duration::from_secs(30),
+            max_wait: None,
+        }
+    }
+
+    pub fn new_with_api_name(api_name: &str) -> Self {
+        let api_info = Config::get_api_info_with_name(api_name.to_string()).unwrap();
+        Self::new_with_api_info(api_info)
+    }
+
+    /// 添加一个请求体，未指定模型时使用当前API的模型
+    /// Add a request body; the API's model is used when none is given
+    ///
+    /// # 参数 (Parameters)
+    /// * `custom_id` - 用于映射结果的调用方ID
+    ///   - Caller-provided ID used to map results back
+    /// * `request_body` - 聊天补全请求体
+    ///   - Chat completion request body
+    pub fn add_request(mut self, custom_id: &str, mut request_body: serde_json::Value) -> Self {
+        if request_body.get("model").is_none() {
+            request_body["model"] = json!(self.api_info.model);
+        }
+        self.requests.push((custom_id.to_string(), request_body));
+        self
+    }
+
+    /// 以单条用户消息添加请求
+    /// Add a request made of a single user message
+    pub fn add_question(self, custom_id: &str, system_prompt: &str, question: &str) -> Self {
+        let mut messages = Vec::new();
+        if !system_prompt.is_empty() {
+            messages.push(json!({"role": "system", "content": system_prompt}));
+        }
+        messages.push(json!({"role": "user", "content": question}));
+        self.add_request(custom_id, json!({"messages": messages}))
+    }
+
+    pub fn with_completion_window(mut self, completion_window: &str) -> Self {
+        self.completion_window = completion_window.to_string();
+        self
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// 等待结果的最长时间，默认不限
+    /// Maximum time to wait for results, unlimited by default
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// 生成批处理输入文件（JSONL）
+    /// Build the batch input file (JSONL)
+    pub fn input_jsonl(&self) -> Result<String, BatchError> {
+        let endpoint = BatchEndpoint::parse(&self.api_info.base_url)?;
+        let mut seen = std::collections::HashSet::new();
+        let mut lines = String::new();
+        for (custom_id, body) in &self.requests {
+            if !seen.insert(custom_id) {
+                return Err(Report::new(BatchError::InvalidBatch(format!(
+                    "duplicate custom_id: {custom_id}"
+                ))));
+            }
+            let line = json!({
+                "custom_id": custom_id,
+                "method": "POST",
+                "url": endpoint.path,
+                "body": body,
+            });
+            lines.push_str(&line.to_string());
+            lines.push('\n');
+        }
+        Ok(lines)
+    }
+
+    /// 上传请求并创建批处理任务
+    /// Upload the requests and create the batch job
+    pub async fn submit(self) -> Result<BatchHandle, BatchError> {
+        if self.requests.is_empty() {
+            return Err(Report::new(BatchError::InvalidBatch(
+                "no requests".to_string(),
+            )));
+        }
+
+        let input = self.input_jsonl()?;
+        let endpoint = BatchEndpoint::parse(&self.api_info.base_url)?;
+        let client = &self.api_info.client;
+
+        let form = Form::new().text("purpose", "batch").part(
+            "file",
+            Part::text(input)
+                .file_name("batch.jsonl")
+                .mime_str("application/jsonl")
+                .change_context(BatchError::UploadError)?,
+        );
+        let file: serde_json::Value = client
+            .post(endpoint.url("files"))
+            .bearer_auth(&self.api_info.api_key)
+            .multipart(form)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Report::new(BatchError::UploadError).attach_printable(redact(&e.to_string())))?
+            .json()
+            .await
+            .change_context(BatchError::UploadError)?;
+        let input_file_id = file["id"]
+            .as_str()
+            .ok_or_else(|| Report::new(BatchError::UploadError))
+            .attach_printable_lazy(|| format!("Missing file id: {file}"))?;
+
+        let status: BatchStatus = client
+            .post(endpoint.url("batches"))
+            .bearer_auth(&self.api_info.api_key)
+            .json(&json!({
+                "input_file_id": input_file_id,
+                "endpoint": endpoint.path,
+                "completion_window": self.completion_window,
+            }))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Report::new(BatchError::CreateError).attach_printable(redact(&e.to_string())))?
+            .json()
+            .await
+            .change_context(BatchError::CreateError)?;
+
+        info!("Batch job {} created with {} requests", status.id, self.requests.len());
+
+        Ok(BatchHandle {
+            id: status.id,
+            endpoint,
+            client: client.clone(),
+            api_key: self.api_info.api_key,
+            poll_interval: self.poll_interval,
+            max_wait: self.max_wait,
+        })
+    }
+
+    /// 提交并等待全部结果
+    /// Submit and wait for all results
+    pub async fn run(self) -> Result<HashMap<String, BatchItem>, BatchError> {
+        self.submit().await?.wait().await
+    }
+}
+
+/// 已提交的批处理任务
+/// A submitted batch job
+#[derive(Clone, Debug)]
+pub struct BatchHandle {
+    pub id: String,
+
+    endpoint: BatchEndpoint,
+
+    client: Client,
+
+    api_key: String,
+
+    poll_interval: Duration,
+
+    max_wait: Option<Duration>,
+}
+
+impl BatchHandle {
+    /// 查询任务状态
+    /// Query the job status
+    pub async fn status(&self) -> Result<BatchStatus, BatchError> {
+        self.client
+            .get(self.endpoint.url(&format!("batches/{}", self.id)))
+            .bearer_auth(&self.api_key)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| {
+                Report::new(BatchError::PollError(self.id.clone()))
+                    .attach_printable(redact(&e.to_string()))
+            })?
+            .json()
+            .await
+            .change_context_lazy(|| BatchError::PollError(self.id.clone()))
+    }
+
+    /// 取消任务
+    /// Cancel the job
+    pub async fn cancel(&self) -> Result<BatchStatus, BatchError> {
+        self.client
+            .post(self.endpoint.url(&format!("batches/{}/cancel", self.id)))
+            .bearer_auth(&self.api_key)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| {
+                Report::new(BatchError::PollError(self.id.clone()))
+                    .attach_printable(redact(&e.to_string()))
+            })?
+            .json()
+            .await
+            .change_context_lazy(|| BatchError::PollError(self.id.clone()))
+    }
+
+    /// 轮询直到任务结束，并按调用方ID返回结果
+    /// Poll until the job finishes and return results keyed by caller-provided ID
+    pub async fn wait(&self) -> Result<HashMap<String, BatchItem>, BatchError> {
+        let started = Instant::now();
+        let status = loop {
+            let status = self.status().await?;
+            if status.is_finished() {
+                break status;
+            }
+            if self.max_wait.is_some_and(|max_wait| started.elapsed() >= max_wait) {
+                return Err(Report::new(BatchError::Timeout(self.id.clone())));
+            }
+            info!(
+                "Batch job {} is {} ({}/{})",
+                self.id, status.status, status.request_counts.completed, status.request_counts.total
+            );
+            tokio::time::sleep(self.poll_interval).await;
+        };
+
+        if status.output_file_id.is_none() && status.error_file_id.is_none() {
+            return Err(Report::new(BatchError::JobFailed(
+                self.id.clone(),
+                status.status,
+            )));
+        }
+
+        let mut items = HashMap::new();
+        for file_id in [&status.output_file_id, &status.error_file_id]
+            .into_iter()
+            .flatten()
+        {
+            let content = self.download(file_id).await?;
+            items.extend(parse_results(&content)?);
+        }
+        Ok(items)
+    }
+
+    async fn download(&self, file_id: &str) -> Result<String, BatchError> {
+        self.client
+            .get(self.endpoint.url(&format!("files/{file_id}/content")))
+            .bearer_auth(&self.api_key)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Report::new(BatchError::DownloadError).attach_printable(redact(&e.to_string())))?
+            .text()
+            .await
+            .change_context(BatchError::DownloadError)
+    }
+}
+
+/// 解析批处理输出文件（JSONL），按调用方ID映射结果
+/// Parse a batch output file (JSONL) into results keyed by caller-provided ID
+pub fn parse_results(content: &str) -> Result<HashMap<String, BatchItem>, BatchError> {
+    let mut items = HashMap::new();
+    for (line_no, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: serde_json::Value = serde_json::from_str(line)
+            .change_context(BatchError::ParseError)
+            .attach_printable_lazy(|| format!("Invalid result at line {}", line_no + 1))?;
+        let custom_id = record["custom_id"]
+            .as_str()
+            .ok_or_else(|| Report::new(BatchError::ParseError))
+            .attach_printable_lazy(|| format!("Missing custom_id at line {}", line_no + 1))?
+            .to_string();
+
+        let response = &record["response"];
+        let error = match &record["error"] {
+            serde_json::Value::Null => response["body"].get("error").cloned(),
+            error => Some(error.clone()),
+        };
+        items.insert(
+            custom_id.clone(),
+            BatchItem {
+                custom_id,
+                status_code: response["status_code"].as_u64().unwrap_or(0) as u16,
+                body: response.get("body").cloned(),
+                error,
+            },
+        );
+    }
+    Ok(items)
+}
+
+/// 由聊天补全URL推导出的接口根地址与路径
+/// API root and endpoint path derived from the chat completion URL
+#[derive(Clone, Debug)]
+struct BatchEndpoint {
+    root: String,
+
+    path: String,
+}
+
+impl BatchEndpoint {
+    fn parse(base_url: &str) -> Result<Self, BatchError> {
+        let url = Url::parse(base_url)
+            .change_context_lazy(|| BatchError::InvalidBatch(base_url.to_string()))?;
+        let path = url.path().to_string();
+        let root = base_url
+            .strip_suffix("/chat/completions")
+            .ok_or_else(|| Report::new(BatchError::InvalidBatch(base_url.to_string())))
+            .attach_printable("Batch requires a `/chat/completions` base URL")?
+            .to_string();
+        Ok(Self { root, path })
+    }
+
+    fn url(&self, route: &str) -> String {
+        format!("{}/{}", self.root, route)
+    }
+}
@@ -9,11 +9,19 @@ use thiserror::Error;
 use tracing::info;
 
 use crate::chat::chat_base::{BaseChat, ChatError};
-use crate::chat::chat_tool::ChatTool;
-use crate::chat::message::Role;
-use crate::config::ModelCapability;
-use crate::prompt::assembler::assemble_output_description;
+use crate::chat::chat_single::{run_tool_calls, ToolCallError};
+use crate::chat::chat_tool::{add_response_format, finish_structured_answer};
+use crate::chat::extract::extract_json;
+use crate::chat::message::{Role, TranscriptStyle};
+use crate::chat::safety::SafetyStage;
+use crate::config::{ApiInfo, AuxiliaryTask, Config, ModelCapability};
+use crate::prompt::assembler::{assemble_output_description_with_hidden, assemble_tools_prompt};
+use crate::schema::coerce::coerce;
 use crate::schema::json_schema::JsonSchema;
+use crate::schema::tool_schema::{ToolCall, ToolResult};
+use crate::utils::common::redact::redact;
+
+const SPEAKER_PROMPT: &str = "你在主持一场多人对话，请根据各角色的设定与对话记录选出最自然的下一位发言者，只输出符合格式的JSON";
 
 #[derive(Debug, Clone)]
 pub struct MultiChat {
@@ -24,11 +32,23 @@ pub struct MultiChat {
     pub current_character: String,
 
     need_stream: bool,
+
+    /// 各角色可用的工具模式及组装好的工具提示
+    /// Tool schemas available to each character with their assembled tools prompt
+    character_tools: HashMap<String, (Vec<serde_json::Value>, String)>,
+
+    /// 创建时的API，未绑定API的角色发言时使用
+    /// API the chat was created with, used when a character without a bound API speaks
+    default_api: ApiInfo,
+
+    /// 各角色绑定的API，切换角色时随之切换模型
+    /// API bound to each character; the model switches along with the speaker
+    character_apis: HashMap<String, ApiInfo>,
 }
 
 impl MultiChat {
-    pub fn new_with_api_name(
-        api_name: &str,
+    pub fn new_with_api_info(
+        api_info: ApiInfo,
         character_prompts: HashMap<String, String>,
         need_stream: bool,
     ) -> Result<Self, ChatError> {
@@ -37,41 +57,174 @@ impl MultiChat {
         }
 
         Ok(Self {
-            base: BaseChat::new_with_api_name(api_name, "", need_stream),
+            base: BaseChat::new_with_api_info(api_info.clone(), "", need_stream),
             character_prompts,
             current_character: String::new(),
             need_stream,
+            character_tools: HashMap::new(),
+            default_api: api_info,
+            character_apis: HashMap::new(),
         })
     }
 
+    pub fn new_with_api_name(
+        api_name: &str,
+        character_prompts: HashMap<String, String>,
+        need_stream: bool,
+    ) -> Result<Self, ChatError> {
+        let api_info = Config::get_api_info_with_name(api_name.to_string()).unwrap();
+        Self::new_with_api_info(api_info, character_prompts, need_stream)
+    }
+
     pub fn new_with_model_capability(
         model_capability: ModelCapability,
         character_prompts: HashMap<String, String>,
         need_stream: bool,
     ) -> Result<Self, ChatError> {
-        if character_prompts.is_empty() {
-            return Err(Report::new(ChatError::NoCharacterPrompts));
-        }
+        let api_info = Config::get_api_info_with_capability(model_capability).unwrap();
+        Self::new_with_api_info(api_info, character_prompts, need_stream)
+    }
 
-        Ok(Self {
-            base: BaseChat::new_with_model_capability(model_capability, "", need_stream),
-            character_prompts,
-            current_character: String::new(),
-            need_stream,
-        })
+    pub fn with_transcript_style(mut self, style: TranscriptStyle) -> Self {
+        self.base.transcript_style = style;
+        self
     }
 
-    pub fn set_character(&mut self, character: &str) -> Result<(), ChatError> {
+    fn check_character(&self, character: &str) -> Result<(), ChatError> {
         if !self.character_prompts.contains_key(character) {
             return Err(Report::new(ChatError::UndefinedCharacter(
                 character.to_owned(),
             )));
         }
+        Ok(())
+    }
+
+    /// 将默认路径末端的消息设为 `character` 私有
+    /// Make the message at the end of the default path private to `character`
+    fn mark_private(&mut self, character: &str) -> Result<(), ChatError> {
+        self.base.update_session(|session| {
+            session.set_private(&session.default_path.clone(), Some(character))
+        })
+    }
+
+    /// 切换发言角色；角色绑定的API与当前不同时一并切换模型
+    /// Switch the speaking character; the model switches too when the character's API differs from the current one
+    pub fn set_character(&mut self, character: &str) -> Result<(), ChatError> {
+        self.check_character(character)?;
         self.current_character = character.to_owned();
         self.base.character_prompt = self.character_prompts[&self.current_character].clone();
+
+        let api_info = self.character_apis.get(character).unwrap_or(&self.default_api);
+        if (&api_info.model, &api_info.base_url, &api_info.api_key)
+            != (&self.base.model, &self.base.base_url, &self.base.api_key)
+        {
+            info!("Character {character} speaks through model {}", api_info.model);
+            self.base.set_api_info(api_info.clone());
+        }
+        Ok(())
+    }
+
+    /// 为角色绑定API，该角色发言时使用它的模型
+    /// Bind an API to a character; its model is used whenever the character speaks
+    ///
+    /// # 参数 (Parameters)
+    /// * `character` - 角色名
+    ///   - Character name
+    /// * `api_info` - API信息
+    ///   - API information
+    pub fn set_character_api_info(&mut self, character: &str, api_info: ApiInfo) -> Result<(), ChatError> {
+        self.check_character(character)?;
+        self.character_apis.insert(character.to_owned(), api_info);
+        if self.current_character == character {
+            self.set_character(character)?;
+        }
         Ok(())
     }
 
+    /// 按名称在对话的运行时中查找API并绑定到角色，见 [`MultiChat::set_character_api_info`]
+    /// Bind an API looked up by name in the chat's runtime to a character, see [`MultiChat::set_character_api_info`]
+    pub fn set_character_api_name(&mut self, character: &str, api_name: &str) -> Result<(), ChatError> {
+        let api_info = self
+            .base
+            .runtime
+            .get_api_info_with_name(api_name)
+            .change_context_lazy(|| ChatError::ApiNotFound(api_name.to_string()))?;
+        self.set_character_api_info(character, api_info)
+    }
+
+    /// 按模型能力在对话的运行时中查找API并绑定到角色，见 [`MultiChat::set_character_api_info`]
+    /// Bind an API looked up by capability in the chat's runtime to a character, see
+    /// [`MultiChat::set_character_api_info`]
+    pub fn set_character_model_capability(
+        &mut self,
+        character: &str,
+        capability: ModelCapability,
+    ) -> Result<(), ChatError> {
+        let api_info = self
+            .base
+            .runtime
+            .get_api_info_with_capability(capability.clone())
+            .change_context_lazy(|| ChatError::ApiNotFound(format!("{capability:?}")))?;
+        self.set_character_api_info(character, api_info)
+    }
+
+    /// 解除角色绑定的API，之后该角色使用创建对话时的API
+    /// Unbind a character's API; the character then uses the API the chat was created with
+    pub fn clear_character_api(&mut self, character: &str) -> Result<(), ChatError> {
+        self.check_character(character)?;
+        self.character_apis.remove(character);
+        if self.current_character == character {
+            self.set_character(character)?;
+        }
+        Ok(())
+    }
+
+    /// 为角色设置可用工具，空列表收回该角色的全部工具
+    /// Set the tools available to a character; an empty list takes all its tools away
+    ///
+    /// 工具提示不写入会话，只在该角色发言的请求中作为系统消息附上，其他角色看不到
+    /// The tools prompt is not stored in the session; it is attached as a system message only to requests where this
+    /// character speaks, so other characters never see it
+    ///
+    /// # 参数 (Parameters)
+    /// * `character` - 角色名
+    ///   - Character name
+    /// * `tools_schema` - 工具模式
+    ///   - Tool schemas
+    pub fn set_tools(&mut self, character: &str, tools_schema: Vec<serde_json::Value>) -> Result<(), ChatError> {
+        self.check_character(character)?;
+        if tools_schema.is_empty() {
+            self.character_tools.remove(character);
+            return Ok(());
+        }
+        let tools_prompt =
+            assemble_tools_prompt(tools_schema.clone()).change_context(ChatError::AssembleToolsPromptError)?;
+        self.character_tools.insert(character.to_owned(), (tools_schema, tools_prompt));
+        Ok(())
+    }
+
+    /// 角色可用的工具模式
+    /// Tool schemas available to a character
+    pub fn tools(&self, character: &str) -> &[serde_json::Value] {
+        self.character_tools
+            .get(character)
+            .map(|(tools_schema, _)| tools_schema.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// 在开头的系统消息之后附上当前角色的工具提示
+    /// Attach the tools prompt of the current character after the leading system messages
+    fn attach_tools_prompt(&self, mut request_body: serde_json::Value) -> serde_json::Value {
+        let Some((_, tools_prompt)) = self.character_tools.get(&self.current_character) else {
+            return request_body;
+        };
+        if let Some(messages) = request_body["messages"].as_array_mut() {
+            let position = messages.iter().take_while(|m| m["role"] == "system").count();
+            messages.insert(position, json!({"role": "system", "content": tools_prompt}));
+        }
+        request_body
+    }
+
     pub fn add_user_message(&mut self, content: &str) -> Result<(), ChatError> {
         self.base.add_message(Role::User, content)
     }
@@ -80,6 +233,19 @@ impl MultiChat {
         self.base.add_message(Role::System, content)
     }
 
+    /// 在默认路径下添加仅对 `character` 可见的消息
+    /// Add a message under the default path that only `character` can see
+    pub fn add_private_message(
+        &mut self,
+        character: &str,
+        role: Role,
+        content: &str,
+    ) -> Result<(), ChatError> {
+        self.check_character(character)?;
+        self.base.add_message(role, content)?;
+        self.mark_private(character)
+    }
+
     pub fn add_message_with_parent_path(
         &mut self,
         path: &[usize],
@@ -98,14 +264,17 @@ impl MultiChat {
             return Err(Report::new(ChatError::NoCharacterSelected));
         }
 
+        let user_input = self.base.screen(user_input, SafetyStage::Input).await?;
         self.base
-            .add_message_with_parent_path(parent_path, Role::User, user_input)?;
+            .add_message_with_parent_path(parent_path, Role::User, &user_input)?;
 
         let character_role = Role::Character(self.current_character.clone());
 
-        Ok(self
+        let request_body = self
             .base
-            .build_request_body(&self.base.session.default_path.clone(), &character_role)?)
+            .build_request_body(&self.base.session.default_path.clone(), &character_role)
+            .await?;
+        Ok(self.attach_tools_prompt(request_body))
     }
 
     pub async fn get_req_body_again(
@@ -118,7 +287,8 @@ impl MultiChat {
 
         let character_role = Role::Character(self.current_character.clone());
 
-        Ok(self.base.build_request_body(end_path, &character_role)?)
+        let request_body = self.base.build_request_body(end_path, &character_role).await?;
+        Ok(self.attach_tools_prompt(request_body))
     }
 
     pub async fn get_req_body(&mut self, user_input: &str) -> Result<serde_json::Value, ChatError> {
@@ -129,37 +299,33 @@ impl MultiChat {
 
     async fn get_content_from_req_body(
         &mut self,
-        request_body: serde_json::Value,
+        mut request_body: serde_json::Value,
     ) -> Result<String, ChatError> {
-        let content = if self.need_stream {
-            let (stream, semaphore_permit) = self
-                .base
-                .get_stream_response(request_body.clone())
-                .await
-                .attach_printable("Failed to get stream response")?;
-
-            BaseChat::get_content_from_stream_resp(stream, semaphore_permit)
-                .await
-                .attach_printable("Failed to extract content from stream response")?
-        } else {
-            let response = self
-                .base
-                .get_response(request_body.clone())
-                .await
-                .attach_printable("Failed to get response")?;
-
-            BaseChat::get_content_from_resp(&response)
-                .attach_printable("Failed to extract content from response")?
+        let mut attempt = 0;
+        let content = loop {
+            let content = self.fetch_content(request_body.clone()).await?;
+            if let Some(content) = self.base.apply_guards(&content, attempt, &mut request_body)? {
+                break content;
+            }
+            attempt += 1;
         };
 
+        let character_role = Role::Character(self.current_character.clone());
+        self.base.add_answer(character_role, &content)?;
+
+        Ok(content)
+    }
+
+    async fn fetch_content(&mut self, request_body: serde_json::Value) -> Result<String, ChatError> {
+        let content = self.base.fetch_answer(request_body, self.need_stream).await?;
+        let content = self.base.screen(&content, SafetyStage::Output).await?;
+
         info!(
             "GetLLMAPIAnswer from {}: {}",
-            self.current_character, content
+            self.current_character,
+            redact(&content)
         );
 
-        let character_role = Role::Character(self.current_character.clone());
-        self.base.add_message(character_role, &content)?;
-
         Ok(content)
     }
 
@@ -173,28 +339,157 @@ impl MultiChat {
         self.get_content_from_req_body(request_body).await
     }
 
+    /// 以当前角色提问并执行回答中的工具调用，只允许调用该角色被授予的工具
+    /// Ask as the current character and execute the tool calls in the answer; only tools granted to this character may be called
+    ///
+    /// 返回去掉调用标签的回答与按出现顺序排列的（调用，结果）对
+    /// Returns the answer without call tags and (call, result) pairs in the order the calls appeared
+    pub async fn get_tool_answer(
+        &mut self,
+        user_input: &str,
+    ) -> Result<(String, Vec<(ToolCall, ToolResult)>), ToolCallError> {
+        let answer = self.get_answer(user_input).await.map_err(|e| {
+            Report::new(ToolCallError::ExtractFunctionCall(format!(
+                "Failed to get answer for tool call: {:?}",
+                e
+            )))
+            .attach_printable(format!("Character: {}", self.current_character))
+        })?;
+        let tools_schema = self.tools(&self.current_character).to_vec();
+        let user = self.base.user_responder.clone();
+        let events = self.base.events.clone();
+        let runtime = self.base.runtime.clone();
+        Ok(run_tool_calls(&answer, &tools_schema, true, user, events, runtime).await)
+    }
+
+    /// 切换到该角色并以可用工具回答
+    /// Switch to the character and answer with its tools
+    pub async fn tool_dialogue(
+        &mut self,
+        character: &str,
+        user_input: &str,
+    ) -> Result<(String, Vec<(ToolCall, ToolResult)>), ToolCallError> {
+        self.set_character(character)
+            .change_context_lazy(|| ToolCallError::ExtractFunctionCall(format!("Undefined character: {character}")))?;
+        self.get_tool_answer(user_input).await
+    }
+
     pub async fn get_json_answer<T: DeserializeOwned + 'static + JsonSchema>(
         &mut self,
         user_input: &str,
     ) -> Result<T, ChatError> {
-        let schema = T::json_schema();
+        let schema = T::cached_json_schema();
 
-        let output_description = assemble_output_description(schema.clone())
+        let output_description = assemble_output_description_with_hidden(&schema, &self.base.hidden_fields)
             .change_context(ChatError::AssembleOutputDescriptionError)
             .attach_printable(format!(
                 "Failed to assemble output description for schema: {:?}",
-                serde_json::to_string(&schema)
+                serde_json::to_string(&*schema)
                     .unwrap_or_else(|_| "Schema serialization failed".to_string())
             ))?;
 
         self.base
             .add_message(Role::System, output_description.as_str())?;
 
-        let answer = self.get_answer(user_input).await?;
+        if self.current_character.is_empty() {
+            return Err(Report::new(ChatError::NoCharacterSelected));
+        }
 
-        ChatTool::get_json::<T>(&answer, schema)
+        // 已知会忽略 response_format 的来源直接走提示注入加修复
+        // Sources known to ignore response_format go straight to prompt injection plus repair
+        let response_format = !self.base.runtime.is_response_format_ignored(&self.base.base_url);
+        let mut request_body = self
+            .get_req_body(user_input)
             .await
-            .attach_printable(format!("Failed to parse answer as JSON: {}", answer))
+            .attach_printable("Failed to get answer for JSON request")?;
+        if response_format {
+            request_body = add_response_format(request_body, (*schema).clone());
+        }
+
+        self.base.structured_stream = true;
+        let answer = self.get_content_from_req_body(request_body).await;
+        self.base.structured_stream = false;
+        let answer = answer?;
+
+        finish_structured_answer::<T>(&mut self.base, &answer, &schema, response_format).await
+    }
+
+    /// 使用 `AuxiliaryTask::SpeakerSelection`（默认 Cheap 档位）的模型根据对话记录选出下一位发言者
+    /// Pick the next speaker from the transcript with the model of `AuxiliaryTask::SpeakerSelection` (Cheap tier by
+    /// default)
+    ///
+    /// 只返回角色名，不切换当前角色；私有消息不会交给选择模型
+    /// Only returns the character name without switching to it; private messages are not shown to the selecting model
+    pub async fn pick_next_speaker(&self) -> Result<String, ChatError> {
+        let chat = self.base.new_auxiliary_chat(AuxiliaryTask::SpeakerSelection, "")?;
+        self.pick_next_speaker_with(chat).await
+    }
+
+    /// 使用指定的对话选出下一位发言者，见 [`MultiChat::pick_next_speaker`]
+    /// Pick the next speaker with the given chat, see [`MultiChat::pick_next_speaker`]
+    pub async fn pick_next_speaker_with(&self, mut chat: BaseChat) -> Result<String, ChatError> {
+        let mut characters = self.character_prompts.keys().collect::<Vec<_>>();
+        characters.sort();
+        let schema = json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "next_speaker",
+                "strict": true,
+                "schema": {
+                    "type": "object",
+                    "properties": {
+                        "speaker": {"type": "string", "enum": characters, "description": "下一位发言者"},
+                        "reason": {"type": "string", "description": "选择理由"},
+                    },
+                    "required": ["speaker", "reason"],
+                    "additionalProperties": false,
+                },
+            },
+        });
+
+        let roster = characters
+            .iter()
+            .map(|character| format!("- {character}: {}", self.character_prompts[*character]))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let path = &self.base.session.default_path;
+        let transcript = if path.is_empty() {
+            "（对话尚未开始）".to_string()
+        } else {
+            self.base
+                .session
+                .nodes_along_path(path)
+                .change_context(ChatError::SessionError)?
+                .iter()
+                .filter(|message| message.private_to.is_none() && message.role != Role::System)
+                .map(|message| format!("{}: {}", message.role, message.content))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let mut prompt = format!("角色：\n{roster}\n\n对话记录：\n{transcript}");
+        if !self.current_character.is_empty() {
+            prompt.push_str(&format!("\n\n上一位发言者：{}", self.current_character));
+        }
+
+        chat.add_message(Role::System, SPEAKER_PROMPT)?;
+        chat.add_message(Role::User, &prompt)?;
+        let mut request_body = chat
+            .build_request_body(&chat.session.default_path.clone(), &Role::User)
+            .await?;
+        if !chat.runtime.is_response_format_ignored(&chat.base_url) {
+            request_body = add_response_format(request_body, schema.clone());
+        }
+        let response = chat.get_response(request_body).await?;
+        let content = BaseChat::get_content_from_resp(&response)?;
+
+        let mut choice = extract_json(&content)
+            .ok_or_else(|| Report::new(ChatError::GetJsonError))
+            .attach_printable_lazy(|| format!("Speaker selection: {}", redact(&content)))?;
+        coerce(&schema, &mut choice);
+        let speaker = choice["speaker"].as_str().unwrap_or_default().to_string();
+        self.check_character(&speaker)?;
+        info!("Next speaker: {speaker} ({})", redact(choice["reason"].as_str().unwrap_or_default()));
+        Ok(speaker)
     }
 
     pub async fn dialogue(
@@ -207,6 +502,21 @@ impl MultiChat {
         self.get_answer(user_input).await
     }
 
+    /// 私下对话：提问与回答都只对该角色可见，其他角色发言时看不到
+    /// Private dialogue: the question and the answer are visible only to this character and hidden from the others
+    pub async fn private_dialogue(
+        &mut self,
+        character: &str,
+        user_input: &str,
+    ) -> Result<String, ChatError> {
+        self.set_character(character)?;
+        let request_body = self.get_req_body(user_input).await?;
+        self.mark_private(character)?;
+        let answer = self.get_content_from_req_body(request_body).await?;
+        self.mark_private(character)?;
+        Ok(answer)
+    }
+
     pub async fn structured_dialogue<T: DeserializeOwned + 'static + JsonSchema>(
         &mut self,
         character: &str,
@@ -8,13 +8,24 @@ use tokio::task;
 
 use tracing::log::info;
 
+use std::sync::Arc;
+
+use crate::chat::ask_user::{answer_ask_user, UserResponder, ASK_USER_TOOL};
+use crate::chat::attachment::Attachment;
+use crate::chat::events::{ChatEvent, ChatEvents};
 use crate::chat::chat_base::{BaseChat, ChatError};
-use crate::chat::chat_tool::ChatTool;
+use crate::chat::chat_tool::{add_response_format, finish_structured_answer, ChatTool};
 use crate::chat::message::Role;
+use crate::chat::safety::SafetyStage;
 use crate::config::ModelCapability;
-use crate::prompt::assembler::{assemble_output_description, assemble_tools_prompt};
+use crate::prompt::assembler::{assemble_output_description_with_hidden, assemble_tools_prompt};
+use crate::prompt::model::Prompt;
+use crate::runtime::Runtime;
 use crate::schema::json_schema::JsonSchema;
-use crate::schema::tool_schema::extract_tool_uses;
+use crate::schema::tool_schema::{
+    extract_tool_uses, strip_tool_uses, unknown_tool, ToolCall, ToolError, ToolErrorKind, ToolResult,
+};
+use crate::utils::common::redact::{redact, redact_json};
 
 #[derive(Debug, Error)]
 pub enum ToolCallError {
@@ -41,6 +52,30 @@ pub enum ToolCallError {
 
     #[error("Missing field: {0}")]
     MissingField(String),
+
+    #[error("Failed to assemble tools prompt")]
+    AssembleToolsPrompt,
+}
+
+/// 标记工具提示系统消息的消息元数据键
+/// Message metadata key marking the system message that holds the tools prompt
+pub const TOOLS_METADATA_KEY: &str = "tools";
+
+/// 记录当前阶段名称的会话元数据键
+/// Session metadata key recording the name of the current stage
+pub const STAGE_METADATA_KEY: &str = "stage";
+
+const CRITIQUE_PROMPT: &str = "请作为严格的评审，指出上面回答中的错误、遗漏和可以改进之处，只给出评审意见";
+
+const REVISE_PROMPT: &str = "请根据评审意见修改你最初的回答，只输出修改后的完整回答";
+
+/// 一轮自我改进：草稿及其评审意见
+/// One refinement round: a draft and its critique
+#[derive(Debug, Clone)]
+pub struct RefinementRound {
+    pub draft: String,
+
+    pub critique: String,
 }
 
 #[derive(Debug, Clone)]
@@ -50,6 +85,14 @@ pub struct SingleChat {
     need_stream: bool,
 
     tools_schema: Vec<serde_json::Value>,
+
+    /// 工具集变更后尚未写入会话
+    /// The tool set changed and has not been written to the session yet
+    tools_changed: bool,
+
+    /// 绑定的提示，提供各阶段的内容
+    /// Bound prompt providing the content of each stage
+    prompt: Option<Prompt>,
 }
 
 impl SingleChat {
@@ -59,6 +102,8 @@ impl SingleChat {
             base,
             need_stream,
             tools_schema: Vec::new(),
+            tools_changed: false,
+            prompt: None,
         }
     }
 
@@ -73,26 +118,124 @@ impl SingleChat {
             base,
             need_stream,
             tools_schema: Vec::new(),
+            tools_changed: false,
+            prompt: None,
         }
     }
 
+    /// 在指定运行时中创建对话，见 [`BaseChat::new_with_runtime`]
+    /// Create a chat within the given runtime, see [`BaseChat::new_with_runtime`]
+    pub fn new_with_runtime(
+        runtime: Arc<Runtime>,
+        api_name: &str,
+        character_prompt: &str,
+        need_stream: bool,
+    ) -> Result<Self, ChatError> {
+        let base = BaseChat::new_with_runtime(runtime, api_name, character_prompt, need_stream)?;
+        Ok(Self {
+            base,
+            need_stream,
+            tools_schema: Vec::new(),
+            tools_changed: false,
+            prompt: None,
+        })
+    }
+
+    /// 切换是否以流式请求回答，流式回调只在流式请求中被调用
+    /// Switch whether answers are requested as streams; the stream callback is only called for streaming requests
+    pub fn set_need_stream(&mut self, need_stream: bool) {
+        self.need_stream = need_stream;
+        self.base.need_stream = need_stream;
+    }
+
+    /// 绑定提示：把默认角色（assistant）的提示作为系统消息写入会话，之后可用 `enter_stage` 进入各阶段
+    /// Bind a prompt: the default character (assistant) prompt is written to the session as a system message, and its
+    /// stages can then be entered with `enter_stage`
+    pub fn with_prompt(mut self, prompt: &Prompt) -> Result<Self, ChatError> {
+        let character_prompt = prompt
+            .default()
+            .change_context(ChatError::UndefinedCharacter("assistant".to_string()))?;
+        self.base.character_prompt = character_prompt.clone();
+        self.base.add_message(Role::System, &character_prompt)?;
+        self.prompt = Some(prompt.clone());
+        Ok(self)
+    }
+
+    /// 进入绑定提示中的阶段：阶段内容作为系统消息追加，阶段名称记入会话元数据
+    /// Enter a stage of the bound prompt: its content is appended as a system message and its name is recorded in the
+    /// session metadata
+    pub fn enter_stage(&mut self, stage_name: &str) -> Result<(), ChatError> {
+        let content = self
+            .prompt
+            .as_ref()
+            .ok_or_else(|| Report::new(ChatError::UndefinedStage(stage_name.to_string())))
+            .attach_printable("No prompt bound")?
+            .stage(stage_name)
+            .change_context(ChatError::UndefinedStage(stage_name.to_string()))?;
+        self.base.add_message(Role::System, &content)?;
+        self.base.update_session(|session| {
+            session.set_metadata(STAGE_METADATA_KEY, json!(stage_name));
+            Ok(())
+        })?;
+        info!("Entered stage: {}", stage_name);
+        Ok(())
+    }
+
+    /// 当前所处的阶段，未进入任何阶段时为 `None`
+    /// The current stage, `None` before any stage is entered
+    pub fn current_stage(&self) -> Option<String> {
+        self.base
+            .session
+            .get_metadata(STAGE_METADATA_KEY)
+            .and_then(|stage| stage.as_str())
+            .map(str::to_string)
+    }
+
     pub async fn get_req_body_with_new_question(
         &mut self,
         parent_path: &[usize],
         user_input: &str,
     ) -> Result<serde_json::Value, ChatError> {
+        let user_input = self.base.screen(user_input, SafetyStage::Input).await?;
+        let default_path = self.base.session.default_path.clone();
+        self.sync_tools_prompt()?;
+        let parent_path = if parent_path == default_path {
+            self.base.session.default_path.clone()
+        } else {
+            parent_path.to_vec()
+        };
+        self.base
+            .add_message_with_parent_path(&parent_path, Role::User, &user_input)?;
+        self.base
+            .build_request_body(&self.base.session.default_path.clone(), &Role::User)
+            .await
+    }
+
+    /// 提问并附上文件，附件正文在构建请求时提取
+    /// Ask a question with files attached; attachment text is extracted when the request is built
+    pub async fn get_req_body_with_attachments(
+        &mut self,
+        user_input: &str,
+        attachments: Vec<Attachment>,
+    ) -> Result<serde_json::Value, ChatError> {
+        let user_input = self.base.screen(user_input, SafetyStage::Input).await?;
+        self.sync_tools_prompt()?;
+        self.base.add_message(Role::User, &user_input)?;
+        for attachment in attachments {
+            self.base.update_session(|session| {
+                session.add_attachment(&session.default_path.clone(), attachment)
+            })?;
+        }
         self.base
-            .add_message_with_parent_path(parent_path, Role::User, user_input)?;
-        Ok(self
-            .base
-            .build_request_body(&self.base.session.default_path.clone(), &Role::User)?)
+            .build_request_body(&self.base.session.default_path.clone(), &Role::User)
+            .await
     }
 
     pub async fn get_req_body_again(
         &mut self,
         end_path: &[usize],
     ) -> Result<serde_json::Value, ChatError> {
-        Ok(self.base.build_request_body(end_path, &Role::User)?)
+        self.base.build_request_body(end_path, &Role::User).await
     }
 
     pub async fn get_req_body(&mut self, user_input: &str) -> Result<serde_json::Value, ChatError> {
@@ -105,157 +248,302 @@ impl SingleChat {
         &mut self,
         request_body: serde_json::Value,
     ) -> Result<String, ChatError> {
-        let content = if self.need_stream {
-            let (stream, semaphore_permit) = self
+        let content = self.fetch_guarded_content(request_body).await?;
+        self.base.add_answer(Role::Assistant, &content)?;
+        Ok(content)
+    }
+
+    /// 请求回答并执行守卫链，按守卫要求重试
+    /// Request an answer and run the guard chain, retrying as the guards require
+    async fn fetch_guarded_content(
+        &mut self,
+        mut request_body: serde_json::Value,
+    ) -> Result<String, ChatError> {
+        let mut attempt = 0;
+        let result = loop {
+            let content = match self.fetch_content(request_body.clone()).await {
+                Ok(content) => content,
+                Err(report) => break Err(report),
+            };
+            match self.base.apply_guards(&content, attempt, &mut request_body) {
+                Ok(Some(content)) => break Ok(content),
+                Ok(None) => {
+                    attempt += 1;
+                    self.base.retry_count += 1;
+                }
+                Err(report) => break Err(report),
+            }
+        };
+        self.base.retry_count -= attempt as u32;
+        result
+    }
+
+    /// 请求并提取回答内容，不写入会话
+    /// Request and extract the answer content without touching the session
+    async fn fetch_content(&mut self, request_body: serde_json::Value) -> Result<String, ChatError> {
+        let content = self.base.fetch_answer(request_body, self.need_stream).await?;
+        let content = self.base.screen(&content, SafetyStage::Output).await?;
+
+        info!("GetLLMAPIAnswer: {}", redact(&content));
+        Ok(content)
+    }
+
+    /// 先起草回答，再多轮“评审 → 修改”，返回最终回答与每轮的草稿和评审意见
+    /// Draft an answer, then run rounds of critique and revision; returns the final answer plus each round's draft and critique
+    ///
+    /// 每轮的评审以草稿的子分支保存，修改稿作为草稿的兄弟分支保存，默认路径指向最终回答
+    /// Each critique is stored as a child branch of its draft and each revision as a sibling of the draft;
+    /// the default path ends at the final answer
+    ///
+    /// # 参数 (Parameters)
+    /// * `user_input` - 用户输入
+    ///   - User input
+    /// * `n_rounds` - 评审与修改的轮数
+    ///   - Number of critique and revision rounds
+    pub async fn get_refined_answer(
+        &mut self,
+        user_input: &str,
+        n_rounds: usize,
+    ) -> Result<(String, Vec<RefinementRound>), ChatError> {
+        let request_body = self.get_req_body(user_input).await?;
+        let mut answer = self.get_content_from_req_body(request_body).await?;
+        let mut draft_path = self.base.session.default_path.clone();
+        let question_path = draft_path[..draft_path.len() - 1].to_vec();
+
+        let mut rounds = Vec::with_capacity(n_rounds);
+        for _ in 0..n_rounds {
+            self.base
+                .add_message_with_parent_path(&draft_path, Role::User, CRITIQUE_PROMPT)?;
+            let request_body = self
                 .base
-                .get_stream_response(request_body.clone())
-                .await
-                .attach_printable("Failed to get stream response")?;
+                .build_request_body(&self.base.session.default_path.clone(), &Role::User)
+                .await?;
+            let critique = self.fetch_content(request_body).await?;
+            self.base.add_answer(Role::Assistant, &critique)?;
 
-            BaseChat::get_content_from_stream_resp(stream, semaphore_permit)
-                .await
-                .attach_printable("Failed to extract content from stream response")?
-        } else {
-            let response = self
+            let mut request_body = self
                 .base
-                .get_response(request_body.clone())
-                .await
-                .attach_printable("Failed to get response")?;
+                .build_request_body(&self.base.session.default_path.clone(), &Role::User)
+                .await?;
+            if let Some(messages) = request_body["messages"].as_array_mut() {
+                messages.push(json!({"role": "user", "content": REVISE_PROMPT}));
+            }
+            let revised = self.fetch_guarded_content(request_body).await?;
 
-            BaseChat::get_content_from_resp(&response)
-                .attach_printable("Failed to extract content from response")?
-        };
+            self.base
+                .add_answer_with_parent_path(&question_path, Role::Assistant, &revised)?;
+            draft_path = self.base.session.default_path.clone();
 
-        info!("GetLLMAPIAnswer: {}", content);
+            rounds.push(RefinementRound {
+                draft: std::mem::replace(&mut answer, revised),
+                critique,
+            });
+        }
 
-        self.base.add_message(Role::Assistant, &content)?;
-        Ok(content)
+        Ok((answer, rounds))
     }
 
     pub async fn get_json_answer<T: DeserializeOwned + 'static + JsonSchema>(
         &mut self,
         user_input: &str,
     ) -> Result<T, ChatError> {
-        let schema = T::json_schema();
+        let schema = T::cached_json_schema();
 
-        let output_description = assemble_output_description(schema.clone())
+        let output_description = assemble_output_description_with_hidden(&schema, &self.base.hidden_fields)
             .change_context(ChatError::AssembleOutputDescriptionError)
             .attach_printable(format!(
                 "Failed to assemble output description for schema: {:?}",
-                serde_json::to_string(&schema)
+                serde_json::to_string(&*schema)
                     .unwrap_or_else(|_| "Schema serialization failed".to_string())
             ))?;
 
         self.base
             .add_message(Role::System, output_description.as_str())?;
 
-        let resp = self
+        // 已知会忽略 response_format 的来源直接走提示注入加修复
+        // Sources known to ignore response_format go straight to prompt injection plus repair
+        let response_format = !self.base.runtime.is_response_format_ignored(&self.base.base_url);
+        let mut request_body = self
             .get_req_body(user_input)
             .await
             .attach_printable("Failed to get answer for JSON request")?;
+        if response_format {
+            request_body = add_response_format(request_body, (*schema).clone());
+        }
 
-        let answer = self.get_content_from_req_body(resp).await?;
+        self.base.structured_stream = true;
+        let answer = self.get_content_from_req_body(request_body).await;
+        self.base.structured_stream = false;
+        let answer = answer?;
 
-        ChatTool::get_json::<T>(&answer, schema)
-            .await
-            .attach_printable(format!("Failed to parse answer as JSON: {}", answer))
+        finish_structured_answer::<T>(&mut self.base, &answer, &schema, response_format).await
     }
 
+    /// 设置可用工具；工具提示在下一次提问时才生成，工具集未变化时不做任何事
+    /// Set the available tools; the tools prompt is generated on the next question and nothing happens when the tool set is unchanged
+    ///
+    /// 会话中已有工具提示时原地替换该系统消息，不会再追加新的系统消息
+    /// When the session already holds a tools prompt, that system message is replaced in place rather than appended again
     pub fn set_tools(&mut self, tools_schema: Vec<serde_json::Value>) -> Result<(), ChatError> {
-        self.tools_schema = tools_schema.clone();
+        if self.tools_schema != tools_schema {
+            self.tools_schema = tools_schema;
+            self.tools_changed = true;
+        }
+        Ok(())
+    }
 
-        let tools_prompt = assemble_tools_prompt(tools_schema).unwrap();
+    /// 把变更后的工具提示写入会话：替换默认路径上已标记的工具系统消息，没有时在默认路径末尾追加
+    /// Write a changed tools prompt to the session: replace the marked tools system message on the default path,
+    /// or append one at the end of the default path when there is none
+    fn sync_tools_prompt(&mut self) -> Result<(), ChatError> {
+        if !self.tools_changed {
+            return Ok(());
+        }
 
-        self.base.add_message(Role::System, &tools_prompt)
+        let default_path = self.base.session.default_path.clone();
+        let tools_path = match self.base.session.nodes_along_path(&default_path) {
+            Ok(nodes) => nodes
+                .iter()
+                .position(|node| node.metadata.contains_key(TOOLS_METADATA_KEY))
+                .map(|i| default_path[..=i].to_vec()),
+            Err(_) => None,
+        };
+        if tools_path.is_none() && self.tools_schema.is_empty() {
+            self.tools_changed = false;
+            return Ok(());
+        }
+
+        let tools_prompt = assemble_tools_prompt(self.tools_schema.clone())
+            .change_context(ChatError::AssembleToolsPromptError)?;
+        match tools_path {
+            Some(path) => self.base.update_session(|session| {
+                session.get_node_by_path(&path)?.content = tools_prompt;
+                Ok(())
+            })?,
+            None => {
+                self.base.add_message(Role::System, &tools_prompt)?;
+                self.base.update_session(|session| {
+                    session.set_message_metadata(&session.default_path.clone(), TOOLS_METADATA_KEY, json!(true))
+                })?;
+            }
+        }
+        self.tools_changed = false;
+        Ok(())
     }
 
-    async fn process_tool_call(
-        text_call: String,
-        tools_schema: Vec<serde_json::Value>,
-    ) -> error_stack::Result<String, ToolCallError> {
-        let function_call: serde_json::Value =
-            ChatTool::get_function(&text_call, json!({"tools": tools_schema}))
-                .await
-                .change_context(ToolCallError::ParseFunctionCall)
-                .attach_printable(format!(
-                    "Failed to parse function call from text: {}",
-                    text_call
-                ))?;
-
-        info!(
-            "function_call: {}",
-            serde_json::to_string_pretty(&function_call).unwrap_or_default()
-        );
-
-        let function_name = function_call["name"].as_str().ok_or_else(|| {
-            Report::new(ToolCallError::MissingField("name".to_string())).attach_printable(format!(
-                "Function call missing 'name' field: {}",
-                serde_json::to_string(&function_call).unwrap_or_default()
-            ))
-        })?;
+    /// 把工具调用结果加入会话，设置了转存策略时过长的结果只保留预览与引用；上一次请求带了原生工具时以 `tool` 角色记录，
+    /// 否则（提示词工具模式）记为用户消息
+    /// Add a tool call result to the session; with an offload policy set, long results keep only a preview and a
+    /// reference. Recorded with the `tool` role when the previous request sent native tools, otherwise (prompt-based
+    /// tool mode) as a user message
+    pub fn add_tool_result(&mut self, call_id: &str, result: &str) -> Result<(), ChatError> {
+        let role = self.tool_result_role(call_id);
+        self.base.add_tool_output(role, result, None)
+    }
 
-        let arg_str = function_call["arguments"].as_str().ok_or_else(|| {
-            Report::new(ToolCallError::MissingField("arguments".to_string())).attach_printable(
-                format!(
-                    "Function call missing 'arguments' field for function: {}",
-                    function_name
-                ),
-            )
-        })?;
+    /// 把一组工具调用结果加入会话，原生工具模式下 `tool_call_id` 取自各结果的调用编号
+    /// Add tool call results to the session; in native tool mode each `tool_call_id` is taken from its call id
+    pub fn add_tool_results(&mut self, results: &[ToolResult]) -> Result<(), ChatError> {
+        results
+            .iter()
+            .try_for_each(|result| self.add_tool_result(&result.id, &result.output))
+    }
 
-        let arg_json: serde_json::Value = serde_json::from_str(arg_str).map_err(|e| {
-            Report::new(ToolCallError::DeserializeArguments(e.to_string())).attach_printable(
-                format!(
-                    "Failed to deserialize arguments for function '{}': {}",
-                    function_name, arg_str
-                ),
-            )
-        })?;
+    /// 同 `add_tool_results`，但带上工具名，使注入检测策略可以只检测指定工具的结果
+    /// Same as `add_tool_results` but with the tool names, so the injection policy can check only chosen tools
+    pub fn add_tool_call_results(&mut self, pairs: &[(ToolCall, ToolResult)]) -> Result<(), ChatError> {
+        pairs.iter().try_for_each(|(call, result)| {
+            let role = self.tool_result_role(&result.id);
+            self.base.add_tool_output(role, &result.output, Some(&call.name))
+        })
+    }
 
-        use crate::schema::tool_schema::get_tool_registry;
-        let registry = get_tool_registry();
-
-        match registry.get(function_name) {
-            Some(tool_fn) => {
-                info!("Calling function named: {}", function_name);
-                match tool_fn(arg_json.clone()) {
-                    Ok(result) => {
-                        let serialized = serde_json::to_string_pretty(&result).map_err(|e| {
-                            Report::new(ToolCallError::SerializeResult).attach_printable(format!(
-                                "Failed to serialize result for function '{}': {:?}",
-                                function_name, e
-                            ))
-                        })?;
-
-                        info!("Calling function succeeded: {}", serialized);
-                        Ok(serialized)
-                    }
-                    Err(e) => {
-                        let err_msg = format!("Calling function '{}' failed: {}", function_name, e);
-                        info!("{}", err_msg);
-                        Ok(err_msg)
-                    }
-                }
-            }
-            None => {
-                let err_msg = format!("Cannot find function named '{}'", function_name);
-                info!("{}", err_msg);
-                Ok(err_msg)
+    /// 没有带 `tool_calls` 的助手消息在前时，OpenAI 兼容后端会拒绝 `tool` 角色消息，因此只在发送了原生工具后使用
+    /// OpenAI-compatible backends reject a `tool` message without a preceding assistant message carrying
+    /// `tool_calls`, so it is used only after native tools were sent
+    fn tool_result_role(&self, call_id: &str) -> Role {
+        if self.base.sent_native_tools() {
+            Role::Tool {
+                call_id: call_id.to_string(),
             }
+        } else {
+            Role::User
         }
     }
 
+    /// 提问并执行回答中的全部工具调用，返回去掉调用标签的回答与按出现顺序排列的（调用，结果）对
+    /// Ask and execute every tool call in the answer; returns the answer without call tags and
+    /// (call, result) pairs in the order the calls appeared
     pub async fn get_tool_answer(
         &mut self,
         user_input: &str,
-    ) -> Result<(String, Vec<String>), ToolCallError> {
-        let resp_with_text_calls = self.get_req_body(user_input).await.map_err(|e| {
+    ) -> Result<(String, Vec<(ToolCall, ToolResult)>), ToolCallError> {
+        let tools_schema = self.tools_schema.clone();
+        self.tool_answer(user_input, tools_schema, false).await
+    }
+
+    /// 只向本次调用提供给定名称的工具，提示与工具调用解析都只包含这些工具，不改变会话中保存的工具提示
+    /// Offer only the named tools for this call; both the prompt and the tool call parsing see only these tools,
+    /// and the tools prompt stored in the session is left unchanged
+    ///
+    /// # 参数 (Parameters)
+    /// * `tools` - 本次可用的工具名称，须为 `set_tools` 设置过的工具
+    ///   - Names of the tools offered for this call; must be among the tools given to `set_tools`
+    /// * `user_input` - 用户输入
+    ///   - User input
+    pub async fn get_tool_answer_with(
+        &mut self,
+        tools: &[&str],
+        user_input: &str,
+    ) -> Result<(String, Vec<(ToolCall, ToolResult)>), ToolCallError> {
+        let offered = tools
+            .iter()
+            .map(|&name| {
+                self.tools_schema
+                    .iter()
+                    .find(|schema| schema["function"]["name"] == name)
+                    .cloned()
+                    .ok_or_else(|| Report::new(ToolCallError::FunctionNotFound(name.to_string())))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.tool_answer(user_input, offered, true).await
+    }
+
+    /// 把请求体中完整的工具提示替换为只含 `offered` 的工具提示
+    /// Replace the full tools prompt in a request body with one covering only `offered`
+    fn offer_tools(
+        &self,
+        request_body: &mut serde_json::Value,
+        offered: &[serde_json::Value],
+    ) -> Result<(), ToolCallError> {
+        let full_prompt = assemble_tools_prompt(self.tools_schema.clone())
+            .change_context(ToolCallError::AssembleToolsPrompt)?;
+        let offered_prompt =
+            assemble_tools_prompt(offered.to_vec()).change_context(ToolCallError::AssembleToolsPrompt)?;
+        for message in request_body["messages"].as_array_mut().into_iter().flatten() {
+            if message["role"] == "system" && message["content"] == full_prompt.as_str() {
+                message["content"] = json!(offered_prompt);
+            }
+        }
+        Ok(())
+    }
+
+    async fn tool_answer(
+        &mut self,
+        user_input: &str,
+        tools_schema: Vec<serde_json::Value>,
+        offered_only: bool,
+    ) -> Result<(String, Vec<(ToolCall, ToolResult)>), ToolCallError> {
+        let mut resp_with_text_calls = self.get_req_body(user_input).await.map_err(|e| {
             Report::new(ToolCallError::ExtractFunctionCall(format!(
                 "Failed to get answer for tool call: {:?}",
                 e
             )))
-            .attach_printable(format!("User input: {}", user_input))
+            .attach_printable(format!("User input: {}", redact(user_input)))
         })?;
+        if offered_only {
+            self.offer_tools(&mut resp_with_text_calls, &tools_schema)?;
+        }
         let answer_with_text_calls = self
             .get_content_from_req_body(resp_with_text_calls)
             .await
@@ -264,69 +552,198 @@ impl SingleChat {
                     "Failed to get answer for tool call: {:?}",
                     e
                 )))
-                .attach_printable(format!("User input: {}", user_input))
+                .attach_printable(format!("User input: {}", redact(user_input)))
             })?;
 
-        let text_calls = extract_tool_uses(&answer_with_text_calls);
-        info!("text_calls: {:?}", text_calls);
+        let user = self.base.user_responder.clone();
+        let events = self.base.events.clone();
+        let runtime = self.base.runtime.clone();
+        Ok(run_tool_calls(&answer_with_text_calls, &tools_schema, offered_only, user, events, runtime).await)
+    }
+}
 
-        let mut results = Vec::with_capacity(text_calls.len());
+/// 在给定运行时中解析 `<ToolUse>` 标签内的文本为结构化工具调用
+/// Parse the text inside a `<ToolUse>` tag into a structured tool call within the given runtime
+async fn parse_tool_call(
+    text_call: &str,
+    tools_schema: Vec<serde_json::Value>,
+    runtime: Arc<Runtime>,
+) -> error_stack::Result<ToolCall, ToolCallError> {
+    let function_call: serde_json::Value =
+        ChatTool::get_function_in_runtime(runtime, text_call, json!({"tools": tools_schema}))
+            .await
+            .change_context(ToolCallError::ParseFunctionCall)
+            .attach_printable(format!(
+                "Failed to parse function call from text: {}",
+                redact(text_call)
+            ))?;
+
+    info!(
+        "function_call: {}",
+        serde_json::to_string_pretty(&redact_json(&function_call)).unwrap_or_default()
+    );
+
+    let function_name = function_call["name"].as_str().ok_or_else(|| {
+        Report::new(ToolCallError::MissingField("name".to_string())).attach_printable(format!(
+            "Function call missing 'name' field: {}",
+            serde_json::to_string(&function_call).unwrap_or_default()
+        ))
+    })?;
+
+    let arg_str = function_call["arguments"].as_str().ok_or_else(|| {
+        Report::new(ToolCallError::MissingField("arguments".to_string())).attach_printable(
+            format!(
+                "Function call missing 'arguments' field for function: {}",
+                function_name
+            ),
+        )
+    })?;
+
+    let arg_json: serde_json::Value = serde_json::from_str(arg_str).map_err(|e| {
+        Report::new(ToolCallError::DeserializeArguments(e.to_string())).attach_printable(
+            format!(
+                "Failed to deserialize arguments for function '{}': {}",
+                function_name,
+                redact(arg_str)
+            ),
+        )
+    })?;
+
+    Ok(ToolCall::new(function_name, arg_json))
+}
 
-        if text_calls.is_empty() {
-            info!("No function calls found, returning original answer");
-            return Ok((answer_with_text_calls, results));
+/// 解析并执行一次工具调用；解析失败时调用名为空、参数为原始文本，结果标记为出错
+/// Parse and execute one tool call; when parsing fails the call has an empty name with the raw text as arguments
+/// and the result is marked as an error
+///
+/// # 参数 (Parameters)
+/// * `offered_only` - 只允许调用 `tools_schema` 中的工具
+///   - Only allow calls to tools in `tools_schema`
+/// * `user` - 回答 [`ASK_USER_TOOL`] 提问的宿主，设置时该工具的调用会等到宿主回答
+///   - Host answering [`ASK_USER_TOOL`] questions; when set, calls to that tool wait for the host's answer
+async fn process_tool_call(
+    text_call: String,
+    tools_schema: Vec<serde_json::Value>,
+    offered_only: bool,
+    user: Option<Arc<dyn UserResponder>>,
+    events: Option<ChatEvents>,
+    runtime: Arc<Runtime>,
+) -> (ToolCall, ToolResult) {
+    let call = match parse_tool_call(&text_call, tools_schema.clone(), runtime.clone()).await {
+        Ok(call) => call,
+        Err(err) => {
+            let call = ToolCall::new("", serde_json::Value::String(text_call));
+            let error = ToolError::new(ToolErrorKind::ParseError, format!("Tool call failed with error: {err}"));
+            let result = ToolResult::failure(&call, &error);
+            return (call, result);
         }
+    };
 
-        let clean_answer = text_calls
-            .iter()
-            .fold(answer_with_text_calls.clone(), |acc, call| {
-                acc.replace(&format!("<ToolUse>{}</ToolUse>", call), "")
-            });
-        info!("clean_answer: {}", clean_answer);
+    if let Some(events) = &events {
+        events.emit(ChatEvent::ToolCallStarted { call: call.clone() });
+    }
+    let result = invoke_tool_call(&call, &tools_schema, offered_only, user, &runtime).await;
+    if let Some(events) = &events {
+        events.emit(ChatEvent::ToolCallFinished {
+            call: call.clone(),
+            result: result.clone(),
+        });
+    }
+    (call, result)
+}
 
-        let tools_schema = self.tools_schema.clone();
+async fn invoke_tool_call(
+    call: &ToolCall,
+    tools_schema: &[serde_json::Value],
+    offered_only: bool,
+    user: Option<Arc<dyn UserResponder>>,
+    runtime: &Runtime,
+) -> ToolResult {
+    if offered_only && !tools_schema.iter().any(|schema| schema["function"]["name"] == call.name.as_str()) {
+        return ToolResult::failure(call, &unknown_tool(&call.name, tools_schema));
+    }
+
+    if call.name == ASK_USER_TOOL
+        && let Some(user) = user
+    {
+        info!("Asking the user: {}", redact(&call.arguments.to_string()));
+        return match answer_ask_user(user.as_ref(), &call.arguments).await {
+            Ok(answer) => ToolResult::ok(call, answer),
+            Err(error) => ToolResult::failure(call, &error),
+        };
+    }
+
+    info!("Calling function named: {}", call.name);
+    let result = runtime.execute_tool(call, tools_schema);
+    info!("Calling function '{}' returned: {}", call.name, redact(&result.output));
+    result
+}
+
+/// 执行回答中的全部工具调用，返回去掉调用标签的回答与按出现顺序排列的（调用，结果）对
+/// Execute every tool call in an answer; returns the answer without call tags and (call, result) pairs in the order the calls appeared
+///
+/// # 参数 (Parameters)
+/// * `answer` - 含 `<ToolUse>` 标签的回答
+///   - Answer containing `<ToolUse>` tags
+/// * `tools_schema` - 可用工具的模式
+///   - Schemas of the available tools
+/// * `offered_only` - 只允许调用 `tools_schema` 中的工具
+///   - Only allow calls to tools in `tools_schema`
+/// * `user` - 回答 [`ASK_USER_TOOL`] 提问的宿主
+///   - Host answering [`ASK_USER_TOOL`] questions
+/// * `events` - 每次调用开始与结束时发出事件
+///   - Receives an event when each call starts and finishes
+/// * `runtime` - 查找并执行工具的运行时
+///   - Runtime whose tool registry runs the calls
+pub(crate) async fn run_tool_calls(
+    answer: &str,
+    tools_schema: &[serde_json::Value],
+    offered_only: bool,
+    user: Option<Arc<dyn UserResponder>>,
+    events: Option<ChatEvents>,
+    runtime: Arc<Runtime>,
+) -> (String, Vec<(ToolCall, ToolResult)>) {
+    let text_calls = extract_tool_uses(answer);
+    info!("text_calls: {}", redact(&format!("{:?}", text_calls)));
+
+    let mut results = Vec::with_capacity(text_calls.len());
+
+    if text_calls.is_empty() {
+        info!("No function calls found, returning original answer");
+        return (answer.to_string(), results);
+    }
 
-        let tasks = text_calls
-            .into_iter()
-            .map(|text_call| {
-                let tools_schema_clone = tools_schema.clone();
-                task::spawn(
-                    async move { Self::process_tool_call(text_call, tools_schema_clone).await },
-                )
+    let clean_answer = strip_tool_uses(answer);
+    info!("clean_answer: {}", redact(&clean_answer));
+
+    let tasks = text_calls
+        .into_iter()
+        .map(|text_call| {
+            let tools_schema_clone = tools_schema.to_vec();
+            let user = user.clone();
+            let events = events.clone();
+            let runtime = runtime.clone();
+            task::spawn(async move {
+                process_tool_call(text_call, tools_schema_clone, offered_only, user, events, runtime).await
             })
-            .collect::<Vec<_>>();
-
-        let mut errors = Vec::new();
-
-        for (i, task) in tasks.into_iter().enumerate() {
-            match task.await {
-                Ok(result) => match result {
-                    Ok(success_result) => results.push(success_result),
-                    Err(err) => {
-                        errors.push(format!("Tool call #{} failed: {}", i, err));
-
-                        results.push(format!(
-                            "{{\"error\": \"Tool call failed with error: {}\"}}",
-                            err
-                        ));
-                    }
-                },
-                Err(e) => {
-                    let error_msg = format!("Task join error for call #{}: {:?}", i, e);
-                    errors.push(error_msg.clone());
-
-                    results.push(format!(
-                        "{{\"error\": \"Task execution failed: {}\"}}",
-                        error_msg
-                    ));
-                }
+        })
+        .collect::<Vec<_>>();
+
+    for task in tasks {
+        match task.await {
+            Ok(pair) => results.push(pair),
+            Err(e) => {
+                let call = ToolCall::new("", serde_json::Value::Null);
+                let error = ToolError::new(ToolErrorKind::Internal, format!("Task execution failed: {e:?}"));
+                results.push((call.clone(), ToolResult::failure(&call, &error)));
             }
         }
+    }
 
-        if !errors.is_empty() {
-            info!("Tool call errors occurred: {:?}", errors);
-        }
-
-        Ok((clean_answer, results))
+    let errors = results.iter().filter(|(_, result)| result.is_error).count();
+    if errors > 0 {
+        info!("{} of {} tool calls failed", errors, results.len());
     }
+
+    (clean_answer, results)
 }
@@ -1,15 +1,72 @@
+use std::sync::Arc;
+
 // 错误处理和结果类型
 use error_stack::{Report, Result, ResultExt};
 // 序列化相关
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 // 日志功能
-use tracing::log::info;
+use tracing::log::{info, warn};
 
 // 项目内部模块
 use crate::chat::chat_base::{BaseChat, ChatError};
 use crate::chat::message::Role;
-use crate::config::ModelCapability::ToolUse;
+use crate::chat::extract::{extract_json, json_candidates};
+use crate::config::AuxiliaryTask;
+use crate::guard::strip_code_fence;
+use crate::runtime::Runtime;
 use crate::schema::json_schema::JsonSchema;
+use crate::schema::validator::validate;
+use crate::utils::common::body_log::text_attachment;
+use crate::utils::common::redact::redact;
+
+/// 结构化输出方式在回答消息元数据中的键
+/// Key of the structured output mode in the metadata of the answer message
+pub const STRUCTURED_OUTPUT_METADATA_KEY: &str = "structured_output";
+
+/// JSON 整理任务的系统提示
+/// System prompt of the JSON formatting task
+const JSON_FORMAT_PROMPT: &str = "将输入内容整理为指定的json形式输出";
+
+/// 函数调用解析任务的提示
+/// Prompt of the function call parsing task
+const FUNCTION_CALL_PROMPT: &str = "根据输入的内容调用指定的函数"; // Call specified function based on input content
+
+/// 本地无法解析出符合模式的 JSON 时的修复方式
+/// How to repair when no JSON matching the schema can be parsed locally
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JsonRepair {
+    /// 只在本地解析与修复，失败即返回错误
+    /// Parse and repair locally only, failing with an error otherwise
+    #[default]
+    Local,
+
+    /// 再用调用方的模型整理一次，不需要额外配置模型
+    /// Then have the caller's own model reformat it, without any extra configured model
+    Caller,
+
+    /// 再交给 `AuxiliaryTask::JsonFormat` 配置的模型整理
+    /// Then hand it to the model configured for `AuxiliaryTask::JsonFormat`
+    Auxiliary,
+}
+
+/// 结构化回答实际采用的方式
+/// How a structured answer was actually obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StructuredOutputMode {
+    /// 服务商按 `response_format` 直接返回了符合模式的 JSON
+    /// The provider honoured `response_format` and returned JSON matching the schema
+    ResponseFormat,
+
+    /// 按提示中的输出描述回答，再由模型整理修复
+    /// Answered from the output description in the prompt, then repaired by a reformatting model
+    PromptRepair,
+
+    /// 按提示中的输出描述回答，在本地解析与修复
+    /// Answered from the output description in the prompt, then parsed and repaired locally
+    LocalRepair,
+}
 
 /// ChatTool结构体：提供与语言模型交互的工具功能
 /// ChatTool struct: Provides utility functions for interacting with language models
@@ -32,22 +89,82 @@ impl ChatTool {
         text_answer: &str,
         json_schema: serde_json::Value,
     ) -> Result<T, ChatError> {
-        // 创建支持工具使用能力的基础聊天实例
-        // Create a base chat instance with tool use capability
-        let mut base = BaseChat::new_with_model_capability(
-            ToolUse,
-            "将输入内容整理为指定的json形式输出", // Format input content into specified JSON output
-            false,
-        );
+        // 创建用于JSON整理任务的基础聊天实例
+        // Create a base chat instance for the JSON formatting task
+        let base = BaseChat::new_with_auxiliary_task(AuxiliaryTask::JsonFormat, JSON_FORMAT_PROMPT, false);
+        Self::reformat_json(base, text_answer, json_schema).await
+    }
 
-        // 添加用户消息
-        // Add user message
+    /// 先在本地解析与修复，失败时按 `repair` 决定是否交给模型整理
+    /// Parse and repair locally first, and on failure let `repair` decide whether a model reformats it
+    ///
+    /// # 参数 (Parameters)
+    /// * `base` - 调用方的对话，`JsonRepair::Caller` 时使用其模型
+    ///   - Caller's chat, whose model is used with `JsonRepair::Caller`
+    /// * `text_answer` - 需要转换为JSON的文本输入
+    ///   - Text input to be converted to JSON
+    /// * `json_schema` - 定义输出JSON格式的模式
+    ///   - Schema defining the output JSON format
+    /// * `repair` - 本地失败后的修复方式
+    ///   - Repair used once local parsing fails
+    pub async fn get_json_with_repair<T: DeserializeOwned + 'static + JsonSchema>(
+        base: &BaseChat,
+        text_answer: &str,
+        json_schema: serde_json::Value,
+        repair: JsonRepair,
+    ) -> Result<T, ChatError> {
+        match Self::parse_json(text_answer, &json_schema) {
+            Ok(output) => Ok(output),
+            Err(err) => match repair {
+                JsonRepair::Local => Err(err),
+                JsonRepair::Caller => {
+                    Self::reformat_json(base.new_task_chat(JSON_FORMAT_PROMPT), text_answer, json_schema).await
+                }
+                JsonRepair::Auxiliary => {
+                    let aux = base.new_auxiliary_chat(AuxiliaryTask::JsonFormat, JSON_FORMAT_PROMPT)?;
+                    Self::reformat_json(aux, text_answer, json_schema).await
+                }
+            },
+        }
+    }
+
+    /// 不请求模型，在本地提取 JSON 并取第一个符合模式的候选，提取规则见 [`json_candidates`]
+    /// Extract JSON locally without any request and take the first candidate matching the schema; see
+    /// [`json_candidates`] for the extraction rules
+    pub fn parse_json<T: DeserializeOwned>(text_answer: &str, json_schema: &serde_json::Value) -> Result<T, ChatError> {
+        let candidates = json_candidates(text_answer);
+        let Some(first) = candidates.first() else {
+            return Err(Report::new(ChatError::GetJsonError))
+                .attach_printable(text_attachment("No JSON found in", text_answer));
+        };
+
+        let Some(value) = candidates.iter().find(|value| validate(json_schema, value).is_empty()) else {
+            return Err(Report::new(ChatError::GetJsonError)).attach_printable(format!(
+                "JSON does not match the schema: {:?}",
+                validate(json_schema, first)
+            ));
+        };
+        serde_json::from_value(value.clone())
+            .change_context(ChatError::GetJsonError)
+            .attach_printable_lazy(|| text_attachment("Failed to deserialize JSON", text_answer))
+    }
+
+    /// 请求模型把文本整理为符合模式的 JSON
+    /// Have a model reformat text into JSON matching the schema
+    async fn reformat_json<T: DeserializeOwned>(
+        mut base: BaseChat,
+        text_answer: &str,
+        json_schema: serde_json::Value,
+    ) -> Result<T, ChatError> {
+        // 添加整理要求与用户消息
+        // Add the formatting instruction and the user message
+        base.add_message(Role::System, JSON_FORMAT_PROMPT)?;
         base.add_message(Role::User, text_answer)?;
 
         // 构建包含响应格式的请求体
         // Build request body with response format
         let request_body = add_response_format(
-            base.build_request_body(&base.session.default_path.clone(), &Role::User)?,
+            base.build_request_body(&base.session.default_path.clone(), &Role::User).await?,
             json_schema
         );
 
@@ -67,17 +184,20 @@ impl ChatTool {
 
         // 记录LLM返回的答案
         // Log the answer from LLM
-        info!("Get LLM API Answer: {}", json_answer);
+        info!("Get LLM API Answer: {}", redact(json_answer));
 
         // 添加助手回复
         // Add assistant reply
         base.add_message(Role::Assistant, json_answer)?;
 
-        // 将JSON字符串反序列化为目标类型
-        // Deserialize JSON string to target type
-        serde_json::from_str(json_answer)
+        // 提取JSON并反序列化为目标类型
+        // Extract the JSON and deserialize it to the target type
+        let value = extract_json(json_answer)
+            .ok_or_else(|| Report::new(ChatError::GetJsonError))
+            .attach_printable_lazy(|| text_attachment("No JSON found in", json_answer))?;
+        serde_json::from_value(value)
             .change_context(ChatError::GetJsonError)
-            .attach_printable_lazy(|| format!("Failed to deserialize JSON: {}", json_answer))
+            .attach_printable_lazy(|| text_attachment("Failed to deserialize JSON", json_answer))
     }
 
     /// 基于输入文本调用函数
@@ -96,13 +216,28 @@ impl ChatTool {
         text_answer: &str,
         tools_schema: serde_json::Value,
     ) -> Result<serde_json::Value, ChatError> {
-        // 创建支持工具使用能力的基础聊天实例
-        // Create a base chat instance with tool use capability
-        let mut base = BaseChat::new_with_model_capability(
-            ToolUse,
-            "根据输入的内容调用指定的函数", // Call specified function based on input content
-            false,
-        );
+        Self::get_function_in_runtime(Runtime::global(), text_answer, tools_schema).await
+    }
+
+    /// 在给定运行时中基于输入文本调用函数，解析请求使用该运行时中 `AuxiliaryTask::FunctionCall` 的模型
+    /// Call a function based on text input within the given runtime, parsing with the model of
+    /// `AuxiliaryTask::FunctionCall` in that runtime
+    ///
+    /// # 参数 (Parameters)
+    /// * `runtime` - 发起解析请求的运行时
+    ///   - Runtime issuing the parsing request
+    /// * `text_answer` - 用户输入的文本
+    ///   - Text input from user
+    /// * `tools_schema` - 可用工具的模式定义
+    ///   - Schema defining available tools
+    pub async fn get_function_in_runtime(
+        runtime: Arc<Runtime>,
+        text_answer: &str,
+        tools_schema: serde_json::Value,
+    ) -> Result<serde_json::Value, ChatError> {
+        // 创建用于函数调用解析任务的基础聊天实例
+        // Create a base chat instance for the function call parsing task
+        let mut base = BaseChat::new_auxiliary_in_runtime(runtime, AuxiliaryTask::FunctionCall, FUNCTION_CALL_PROMPT)?;
 
         // 添加用户消息
         // Add user message
@@ -113,7 +248,7 @@ impl ChatTool {
         let request_body = add_tools(base.build_request_body(
             &base.session.default_path.clone(),
             &Role::User,
-        )?, tools_schema);
+        ).await?, tools_schema);
 
         // 发送请求并处理可能的错误
         // Send request and handle potential errors
@@ -142,7 +277,7 @@ impl ChatTool {
 /// # 返回 (Returns)
 /// * `serde_json::Value` - 添加了响应格式后的请求体
 ///                       - Request body with response format added
-fn add_response_format(
+pub(crate) fn add_response_format(
     mut request_body: serde_json::Value,
     schema: serde_json::Value,
 ) -> serde_json::Value {
@@ -186,4 +321,78 @@ fn add_tools(
         }
     }
     request_body
-}
\ No newline at end of file
+}
+
+/// 回答可直接解析为 JSON 且符合模式时返回该值
+/// The answer as JSON when it parses directly and matches the schema
+pub(crate) fn parse_structured_answer(answer: &str, schema: &serde_json::Value) -> Option<serde_json::Value> {
+    let value: serde_json::Value = serde_json::from_str(strip_code_fence(answer)).ok()?;
+    validate(schema, &value).is_empty().then_some(value)
+}
+
+/// 把回答转换为结构化输出，并在回答消息的元数据中记录采用的方式
+/// Turn an answer into structured output and record the mode used in the metadata of the answer message
+///
+/// 请求带有 `response_format` 而回答根本不是 JSON 时，视为服务商静默忽略了它：
+/// 标记该API来源，之后的请求不再附带 `response_format`，本次回答走修复流程
+/// When the request carried `response_format` but the answer is not JSON at all, the provider is taken to
+/// ignore it silently: the API source is marked so later requests leave `response_format` out,
+/// and this answer goes through repair
+///
+/// 修复先在本地进行，本地失败时才按 `base.json_repair` 交给模型整理
+/// Repair happens locally first; only when that fails does `base.json_repair` hand it to a model
+///
+/// # 参数 (Parameters)
+/// * `base` - 回答所在的对话，回答应为默认路径的最后一条消息
+///   - Chat holding the answer, which should be the last message of the default path
+/// * `response_format` - 请求是否附带了 `response_format`
+///   - Whether the request carried `response_format`
+pub(crate) async fn finish_structured_answer<T: DeserializeOwned + 'static + JsonSchema>(
+    base: &mut BaseChat,
+    answer: &str,
+    schema: &serde_json::Value,
+    response_format: bool,
+) -> Result<T, ChatError> {
+    if response_format {
+        if let Some(value) = parse_structured_answer(answer, schema)
+            && let Ok(output) = serde_json::from_value(value)
+        {
+            record_structured_output_mode(base, StructuredOutputMode::ResponseFormat)?;
+            return Ok(output);
+        }
+        if serde_json::from_str::<serde_json::Value>(strip_code_fence(answer)).is_err() {
+            warn!(
+                "API source {} ignored response_format, falling back to prompt injection and repair",
+                base.base_url
+            );
+            base.runtime.set_response_format_ignored(&base.base_url, true);
+        }
+    }
+
+    let repair = match ChatTool::parse_json::<T>(answer, schema) {
+        Ok(output) => {
+            record_structured_output_mode(base, StructuredOutputMode::LocalRepair)?;
+            return Ok(output);
+        }
+        Err(err) => match base.json_repair {
+            JsonRepair::Local => Err(err),
+            JsonRepair::Caller => {
+                ChatTool::reformat_json::<T>(base.new_task_chat(JSON_FORMAT_PROMPT), answer, schema.clone()).await
+            }
+            JsonRepair::Auxiliary => {
+                let aux = base.new_auxiliary_chat(AuxiliaryTask::JsonFormat, JSON_FORMAT_PROMPT)?;
+                ChatTool::reformat_json::<T>(aux, answer, schema.clone()).await
+            }
+        },
+    };
+    let output = repair.attach_printable(text_attachment("Failed to parse answer as JSON", answer))?;
+    record_structured_output_mode(base, StructuredOutputMode::PromptRepair)?;
+    Ok(output)
+}
+
+fn record_structured_output_mode(base: &mut BaseChat, mode: StructuredOutputMode) -> Result<(), ChatError> {
+    let mode = serde_json::to_value(mode).change_context(ChatError::SessionError)?;
+    base.update_session(|session| {
+        session.set_message_metadata(&session.default_path.clone(), STRUCTURED_OUTPUT_METADATA_KEY, mode)
+    })
+}
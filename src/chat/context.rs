@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use error_stack::{Report, Result, ResultExt};
+use futures::future::BoxFuture;
+
+use crate::chat::chat_base::{BaseChat, ChatError};
+use crate::chat::message::{Role, Session};
+use crate::chat::usage::count_tokens;
+use crate::config::{AuxiliaryTask, Config};
+use crate::memory::{cosine_similarity, Embedder};
+
+const MAP_PROMPT: &str = "用一小段话概括下面的对话片段，保留关键事实、约定与结论，只输出摘要本身";
+
+const REDUCE_PROMPT: &str = "把下面几段按时间顺序排列的对话摘要合并为一段摘要，只输出摘要本身";
+
+/// 上下文中的一条 API 格式消息
+/// One API-format message of the context
+pub type ContextMessage = HashMap<String, String>;
+
+/// 上下文组装策略：从完整分支中选出实际发送的消息
+/// Context assembly strategy: picks the messages actually sent out of the full branch
+///
+/// 开头连续的系统消息与最后一条消息总会保留
+/// The leading system messages and the last message are always kept
+pub trait ContextStrategy: Debug + Send + Sync {
+    /// # 参数 (Parameters)
+    /// * `model` - 模型名，用于计算令牌数
+    ///   - Model name, used to count tokens
+    /// * `messages` - 根到终点的完整上下文
+    ///   - Full context from the root to the end
+    fn select<'a>(
+        &'a self,
+        model: &'a str,
+        messages: Vec<ContextMessage>,
+    ) -> BoxFuture<'a, Result<Vec<ContextMessage>, ChatError>>;
+}
+
+/// 上下文提供者：每次构建请求时给出一条附加的系统说明，不写入会话
+/// Context provider: gives an extra system note every time a request is built, without touching the session
+///
+/// 说明插在开头连续的系统消息之后；返回 `None` 时不插入
+/// The note goes right after the leading system messages; nothing is inserted for `None`
+pub trait ContextProvider: Debug + Send + Sync {
+    fn provide(&self) -> Option<String>;
+}
+
+/// 在开头连续的系统消息之后插入提供者给出的说明
+/// Insert the notes of the providers after the leading system messages
+pub(crate) fn insert_provided_notes(messages: &mut Vec<ContextMessage>, providers: &[Arc<dyn ContextProvider>]) {
+    let position = messages
+        .iter()
+        .take_while(|m| m.get("role").map(String::as_str) == Some("system"))
+        .count();
+    let notes = providers.iter().filter_map(|provider| provider.provide()).map(|note| {
+        HashMap::from([
+            ("role".to_string(), "system".to_string()),
+            ("content".to_string(), note),
+        ])
+    });
+    messages.splice(position..position, notes);
+}
+
+/// 把上下文拆成 (开头的系统消息, 其余消息, 最后一条消息)
+/// Split the context into (leading system messages, the rest, the last message)
+fn split_pinned(
+    mut messages: Vec<ContextMessage>,
+) -> (Vec<ContextMessage>, Vec<ContextMessage>, Option<ContextMessage>) {
+    let system_len = messages
+        .iter()
+        .take_while(|m| m.get("role").map(String::as_str) == Some("system"))
+        .count();
+    let mut rest = messages.split_off(system_len);
+    let last = rest.pop();
+    (messages, rest, last)
+}
+
+fn join_pinned(
+    mut system: Vec<ContextMessage>,
+    selected: Vec<ContextMessage>,
+    last: Option<ContextMessage>,
+) -> Vec<ContextMessage> {
+    system.extend(selected);
+    system.extend(last);
+    system
+}
+
+fn content(message: &ContextMessage) -> &str {
+    message.get("content").map(String::as_str).unwrap_or_default()
+}
+
+/// 发送完整分支（默认）
+/// Send the whole branch (default)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FullPath;
+
+impl ContextStrategy for FullPath {
+    fn select<'a>(
+        &'a self,
+        _model: &'a str,
+        messages: Vec<ContextMessage>,
+    ) -> BoxFuture<'a, Result<Vec<ContextMessage>, ChatError>> {
+        Box::pin(async move { Ok(messages) })
+    }
+}
+
+/// 只保留最近若干轮，每轮从一条用户消息开始
+/// Keep only the latest turns, each starting at a user message
+#[derive(Debug, Clone, Copy)]
+pub struct LastTurns {
+    pub turns: usize,
+}
+
+impl LastTurns {
+    pub fn new(turns: usize) -> Self {
+        Self { turns }
+    }
+}
+
+impl ContextStrategy for LastTurns {
+    fn select<'a>(
+        &'a self,
+        _model: &'a str,
+        messages: Vec<ContextMessage>,
+    ) -> BoxFuture<'a, Result<Vec<ContextMessage>, ChatError>> {
+        Box::pin(async move {
+            let (system, mut rest, last) = split_pinned(messages);
+            rest.extend(last);
+            let start = match self.turns {
+                0 => rest.len().saturating_sub(1),
+                turns => rest
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .filter(|(_, m)| m.get("role").map(String::as_str) == Some("user"))
+                    .nth(turns - 1)
+                    .map_or(0, |(i, _)| i),
+            };
+            Ok(join_pinned(system, rest.split_off(start), None))
+        })
+    }
+}
+
+/// 在令牌预算内从最新的消息往前保留
+/// Keep messages from the newest backwards within a token budget
+///
+/// 每条消息另计 3 个格式令牌；开头的系统消息与最后一条消息即使超出预算也会保留
+/// Each message costs 3 extra framing tokens; the leading system messages and the last message are kept even over budget
+#[derive(Debug, Clone, Copy)]
+pub struct TokenWindow {
+    pub max_tokens: u64,
+}
+
+impl TokenWindow {
+    pub fn new(max_tokens: u64) -> Self {
+        Self { max_tokens }
+    }
+}
+
+impl ContextStrategy for TokenWindow {
+    fn select<'a>(
+        &'a self,
+        model: &'a str,
+        messages: Vec<ContextMessage>,
+    ) -> BoxFuture<'a, Result<Vec<ContextMessage>, ChatError>> {
+        Box::pin(async move {
+            let cost = |m: &ContextMessage| count_tokens(model, content(m)) + 3;
+            let (system, mut rest, last) = split_pinned(messages);
+
+            let mut budget = self
+                .max_tokens
+                .saturating_sub(system.iter().chain(&last).map(cost).sum());
+            let mut start = rest.len();
+            while start > 0 {
+                let tokens = cost(&rest[start - 1]);
+                if tokens > budget {
+                    break;
+                }
+                budget -= tokens;
+                start -= 1;
+            }
+            Ok(join_pinned(system, rest.split_off(start), last))
+        })
+    }
+}
+
+/// 按模型的上下文长度裁剪：预算为上下文长度减去回答上限，取自默认运行时的 [`Config::get_model_limits`]
+/// Trim to the model's context window: the budget is the window minus the output cap, from the default runtime's
+/// [`Config::get_model_limits`]
+///
+/// 上限未知的模型发送完整分支
+/// Models with unknown limits get the whole branch
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModelWindow;
+
+impl ContextStrategy for ModelWindow {
+    fn select<'a>(
+        &'a self,
+        model: &'a str,
+        messages: Vec<ContextMessage>,
+    ) -> BoxFuture<'a, Result<Vec<ContextMessage>, ChatError>> {
+        Box::pin(async move {
+            match Config::get_model_limits(model).prompt_budget() {
+                Some(budget) => TokenWindow::new(budget).select(model, messages).await,
+                None => Ok(messages),
+            }
+        })
+    }
+}
+
+/// 按与最后一条消息的向量相似度挑选较早的消息，并保留最近的若干条
+/// Pick earlier messages by embedding similarity to the last message, keeping the most recent ones as well
+#[derive(Debug, Clone)]
+pub struct Salience {
+    embedder: Arc<dyn Embedder>,
+
+    /// 按相似度挑选的较早消息数
+    /// Number of earlier messages picked by similarity
+    pub top_k: usize,
+
+    /// 最后一条消息之前无条件保留的消息数
+    /// Number of messages before the last one that are always kept
+    pub keep_recent: usize,
+}
+
+impl Salience {
+    pub fn new(embedder: impl Embedder + 'static, top_k: usize) -> Self {
+        Self {
+            embedder: Arc::new(embedder),
+            top_k,
+            keep_recent: 2,
+        }
+    }
+
+    pub fn with_keep_recent(mut self, keep_recent: usize) -> Self {
+        self.keep_recent = keep_recent;
+        self
+    }
+}
+
+impl ContextStrategy for Salience {
+    fn select<'a>(
+        &'a self,
+        _model: &'a str,
+        messages: Vec<ContextMessage>,
+    ) -> BoxFuture<'a, Result<Vec<ContextMessage>, ChatError>> {
+        Box::pin(async move {
+            let (system, mut earlier, last) = split_pinned(messages);
+            let recent = earlier.split_off(earlier.len().saturating_sub(self.keep_recent));
+            let Some(query) = last.as_ref().filter(|_| earlier.len() > self.top_k) else {
+                earlier.extend(recent);
+                return Ok(join_pinned(system, earlier, last));
+            };
+
+            let mut texts: Vec<String> = earlier.iter().map(|m| content(m).to_string()).collect();
+            texts.push(content(query).to_string());
+            let mut embeddings = self
+                .embedder
+                .embed(&texts)
+                .await
+                .change_context(ChatError::ContextStrategyError)?;
+            let query_embedding = embeddings
+                .pop()
+                .ok_or_else(|| Report::new(ChatError::ContextStrategyError))
+                .attach_printable("Missing query embedding")?;
+
+            let mut ranked: Vec<(usize, f32)> = embeddings
+                .iter()
+                .map(|embedding| cosine_similarity(&query_embedding, embedding))
+                .enumerate()
+                .collect();
+            ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+            let mut picked: Vec<usize> = ranked.into_iter().take(self.top_k).map(|(i, _)| i).collect();
+            picked.sort_unstable();
+
+            let mut selected: Vec<ContextMessage> = picked.into_iter().map(|i| earlier[i].clone()).collect();
+            selected.extend(recent);
+            Ok(join_pinned(system, selected, last))
+        })
+    }
+}
+
+/// 将较早的消息分块摘要（map），再合并为一条摘要（reduce），以系统消息放在最近消息之前
+/// Summarize earlier messages chunk by chunk (map) and merge the summaries (reduce) into one system message ahead of the recent ones
+#[derive(Debug, Clone)]
+pub struct MapReduce {
+    chat: BaseChat,
+
+    /// 每块的消息数
+    /// Messages per chunk
+    pub chunk_size: usize,
+
+    /// 最后一条消息之前原样保留的消息数
+    /// Number of messages before the last one kept verbatim
+    pub keep_recent: usize,
+}
+
+impl MapReduce {
+    /// 使用 `AuxiliaryTask::Summarize`（默认 Cheap 档位）生成摘要
+    /// Summarize with `AuxiliaryTask::Summarize` (Cheap tier by default)
+    pub fn new() -> Self {
+        Self::new_with_chat(BaseChat::new_with_auxiliary_task(AuxiliaryTask::Summarize, "", false))
+    }
+
+    pub fn new_with_chat(chat: BaseChat) -> Self {
+        Self {
+            chat,
+            chunk_size: 8,
+            keep_recent: 4,
+        }
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn with_keep_recent(mut self, keep_recent: usize) -> Self {
+        self.keep_recent = keep_recent;
+        self
+    }
+
+    async fn summarize(&self, instruction: &str, text: &str) -> Result<String, ChatError> {
+        let mut chat = self.chat.clone();
+        chat.session = Session::new();
+        chat.add_message(Role::System, instruction)?;
+        chat.add_message(Role::User, text)?;
+        let request_body = chat
+            .build_request_body(&chat.session.default_path.clone(), &Role::User)
+            .await?;
+        let response = chat.get_response(request_body).await?;
+        Ok(BaseChat::get_content_from_resp(&response)?.trim().to_string())
+    }
+}
+
+impl Default for MapReduce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContextStrategy for MapReduce {
+    fn select<'a>(
+        &'a self,
+        _model: &'a str,
+        messages: Vec<ContextMessage>,
+    ) -> BoxFuture<'a, Result<Vec<ContextMessage>, ChatError>> {
+        Box::pin(async move {
+            let (system, mut earlier, last) = split_pinned(messages);
+            let recent = earlier.split_off(earlier.len().saturating_sub(self.keep_recent));
+            if earlier.is_empty() {
+                return Ok(join_pinned(system, recent, last));
+            }
+
+            let mut summaries = Vec::new();
+            for chunk in earlier.chunks(self.chunk_size) {
+                let transcript = chunk
+                    .iter()
+                    .map(|m| format!("{}: {}", m.get("role").map(String::as_str).unwrap_or_default(), content(m)))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                summaries.push(self.summarize(MAP_PROMPT, &transcript).await?);
+            }
+            let summary = match summaries.len() {
+                1 => summaries.remove(0),
+                _ => self.summarize(REDUCE_PROMPT, &summaries.join("\n\n")).await?,
+            };
+
+            let mut selected = vec![HashMap::from([
+                ("role".to_string(), "system".to_string()),
+                ("content".to_string(), format!("此前对话的摘要：\n{summary}")),
+            ])];
+            selected.extend(recent);
+            Ok(join_pinned(system, selected, last))
+        })
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::chat::context::ContextProvider;
+
+const WEEKDAYS: [&str; 7] = ["星期一", "星期二", "星期三", "星期四", "星期五", "星期六", "星期日"];
+
+/// 注入当前日期时间、时区与语言区域的上下文提供者（需通过 `BaseChat::add_context_provider` 启用）
+/// Context provider injecting the current date and time, timezone and locale
+/// (opt in with `BaseChat::add_context_provider`)
+///
+/// 设置了刷新间隔时，说明在间隔内保持不变，请求前缀更稳定，便于服务商缓存提示
+/// With a refresh interval the note stays the same within the interval, keeping request prefixes stable
+/// for provider-side prompt caching
+#[derive(Clone)]
+pub struct DateTimeContext {
+    /// 相对 UTC 的偏移（分钟）
+    /// Offset from UTC in minutes
+    utc_offset_minutes: i32,
+
+    /// 时区名称，如 `Asia/Shanghai`
+    /// Timezone name, e.g. `Asia/Shanghai`
+    timezone_name: Option<String>,
+
+    locale: Option<String>,
+
+    refresh: Option<Duration>,
+
+    clock: Arc<dyn Fn() -> SystemTime + Send + Sync>,
+
+    cached: Arc<Mutex<Option<(SystemTime, String)>>>,
+}
+
+impl Debug for DateTimeContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DateTimeContext")
+            .field("utc_offset_minutes", &self.utc_offset_minutes)
+            .field("timezone_name", &self.timezone_name)
+            .field("locale", &self.locale)
+            .field("refresh", &self.refresh)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for DateTimeContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DateTimeContext {
+    /// UTC 时间，语言区域取自 `LC_ALL` / `LANG` 环境变量
+    /// UTC time, with the locale taken from the `LC_ALL` / `LANG` environment variables
+    pub fn new() -> Self {
+        Self {
+            utc_offset_minutes: 0,
+            timezone_name: None,
+            locale: locale_from_env(),
+            refresh: None,
+            clock: Arc::new(SystemTime::now),
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn with_utc_offset(mut self, minutes: i32, timezone_name: Option<&str>) -> Self {
+        self.utc_offset_minutes = minutes;
+        self.timezone_name = timezone_name.map(str::to_string);
+        self
+    }
+
+    pub fn with_locale(mut self, locale: Option<&str>) -> Self {
+        self.locale = locale.map(str::to_string);
+        self
+    }
+
+    /// 说明过期前复用上一次的内容
+    /// Reuse the previous note until it is older than `refresh`
+    pub fn with_refresh(mut self, refresh: Duration) -> Self {
+        self.refresh = Some(refresh);
+        self
+    }
+
+    /// 替换时间来源，用于测试或回放
+    /// Replace the time source, for tests or replays
+    pub fn with_clock(mut self, clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// 给定时刻的说明
+    /// The note at a given instant
+    pub fn render_at(&self, time: SystemTime) -> String {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        };
+        let local = seconds + i64::from(self.utc_offset_minutes) * 60;
+        let days = local.div_euclid(86400);
+        let second_of_day = local.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        // 1970-01-01 是星期四
+        // 1970-01-01 was a Thursday
+        let weekday = WEEKDAYS[(days + 3).rem_euclid(7) as usize];
+
+        let offset = self.utc_offset_minutes.abs();
+        let sign = if self.utc_offset_minutes < 0 { '-' } else { '+' };
+        let mut note = format!(
+            "当前时间：{year:04}-{month:02}-{day:02} {:02}:{:02}（{weekday}），时区：UTC{sign}{:02}:{:02}",
+            second_of_day / 3600,
+            second_of_day % 3600 / 60,
+            offset / 60,
+            offset % 60,
+        );
+        if let Some(name) = &self.timezone_name {
+            note.push_str(&format!(" ({name})"));
+        }
+        if let Some(locale) = &self.locale {
+            note.push_str(&format!("，语言区域：{locale}"));
+        }
+        note
+    }
+}
+
+impl ContextProvider for DateTimeContext {
+    fn provide(&self) -> Option<String> {
+        let now = (self.clock)();
+        let mut cached = self.cached.lock().ok()?;
+        if let (Some(refresh), Some((rendered_at, note))) = (self.refresh, cached.as_ref())
+            && now.duration_since(*rendered_at).is_ok_and(|age| age < refresh)
+        {
+            return Some(note.clone());
+        }
+        let note = self.render_at(now);
+        *cached = Some((now, note.clone()));
+        Some(note)
+    }
+}
+
+/// `LC_ALL` 或 `LANG` 中的语言区域，去掉编码部分并把 `_` 换成 `-`，如 `zh_CN.UTF-8` → `zh-CN`
+/// Locale from `LC_ALL` or `LANG` without the encoding and with `_` as `-`, e.g. `zh_CN.UTF-8` → `zh-CN`
+fn locale_from_env() -> Option<String> {
+    ["LC_ALL", "LANG"]
+        .iter()
+        .filter_map(|key| std::env::var(key).ok())
+        .map(|value| value.split(['.', '@']).next().unwrap_or_default().replace('_', "-"))
+        .find(|locale| !locale.is_empty() && locale != "C" && locale != "POSIX")
+}
+
+/// 距 1970-01-01 的天数对应的公历日期
+/// Civil date of a number of days from 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = (if month_index < 10 { month_index + 3 } else { month_index - 9 }) as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
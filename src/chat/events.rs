@@ -0,0 +1,116 @@
+use reqwest::Client;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::chat::message::Role;
+use crate::schema::tool_schema::{ToolCall, ToolResult};
+
+/// 事件通道的容量，订阅者落后超过这么多事件时会丢失最早的事件
+/// Capacity of the event channel; subscribers lagging further behind lose the oldest events
+const EVENT_CAPACITY: usize = 1024;
+
+/// 对话生命周期中的事件，序列化后以 `type` 字段区分
+/// Event in the lifecycle of a chat; serialized with a `type` field telling them apart
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatEvent {
+    /// 会话中加入了一条消息，`path` 为它在会话中的路径
+    /// A message was added to the session; `path` is where it sits in the session
+    MessageAdded {
+        path: Vec<usize>,
+        role: Role,
+        content: String,
+    },
+
+    /// 开始向模型请求回答
+    /// Started requesting an answer from the model
+    AnswerStarted { model: String, stream: bool },
+
+    /// 流式回答的一段增量
+    /// One delta of a streaming answer
+    TokenDelta { delta: String },
+
+    ToolCallStarted { call: ToolCall },
+
+    ToolCallFinished { call: ToolCall, result: ToolResult },
+
+    /// 得到完整回答，自动续写时为拼接后的回答
+    /// A full answer arrived; the stitched answer when auto-continue kicked in
+    AnswerCompleted { model: String, content: String },
+
+    /// 请求回答失败
+    /// Requesting an answer failed
+    Error { model: String, message: String },
+}
+
+/// 对话事件的广播发送端，可以在多个对话与智能体间共用
+/// Broadcasting sender of chat events; can be shared by several chats and agents
+///
+/// 没有订阅者时事件直接丢弃，不会阻塞对话
+/// Events are dropped when nobody subscribes, so chats are never blocked
+#[derive(Clone, Debug)]
+pub struct ChatEvents {
+    sender: broadcast::Sender<ChatEvent>,
+}
+
+impl Default for ChatEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChatEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChatEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn emit(&self, event: ChatEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// 把之后的每个事件以 JSON 形式 POST 到 `url`，须在 Tokio 运行时中调用
+    /// POST every later event as JSON to `url`; must be called within a Tokio runtime
+    ///
+    /// 发送失败只记录警告，不影响对话；丢弃返回的句柄不会停止发送，需要停止时调用 `abort`
+    /// Failed deliveries only log a warning and never affect the chat; dropping the returned handle does not stop
+    /// delivery, call `abort` for that
+    pub fn add_webhook(&self, url: &str) -> JoinHandle<()> {
+        self.add_webhook_with_client(url, Client::new())
+    }
+
+    /// 同 [`add_webhook`](Self::add_webhook)，使用给定的客户端，例如带认证头或超时设置的客户端
+    /// Same as [`add_webhook`](Self::add_webhook) using the given client, e.g. one with auth headers or timeouts
+    pub fn add_webhook_with_client(&self, url: &str, client: Client) -> JoinHandle<()> {
+        let mut receiver = self.subscribe();
+        let url = url.to_string();
+        tokio::spawn(async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Webhook {url} fell behind and skipped {skipped} events");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let delivered = client
+                    .post(&url)
+                    .json(&event)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(error) = delivered {
+                    warn!("Failed to deliver chat event to webhook {url}: {error}");
+                }
+            }
+        })
+    }
+}
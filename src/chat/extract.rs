@@ -0,0 +1,195 @@
+use std::borrow::Cow;
+
+use crate::chat::stream::parse_partial_json;
+
+const FENCE: &str = "```";
+
+/// 从回答中提取 JSON，取第一个能解析的候选
+/// Extract JSON from an answer, taking the first candidate that parses
+pub fn extract_json(text: &str) -> Option<serde_json::Value> {
+    json_candidates(text).into_iter().next()
+}
+
+/// 按优先顺序列出回答中能解析为 JSON 的候选
+/// List the candidates of an answer that parse as JSON, in order of preference
+///
+/// 依次尝试代码块围栏内的文本与整个回答；每段文本先整体解析，再找出其中括号配对的对象或数组，
+/// 解析时容忍多余的逗号与注释。都不成功时把被截断的结尾补全后再试
+/// Text inside code fences is tried first, then the whole answer; each text is parsed as a whole, then the
+/// bracket-balanced objects and arrays inside it are, tolerating trailing commas and comments. When nothing
+/// parses, a truncated end is completed and tried last
+pub fn json_candidates(text: &str) -> Vec<serde_json::Value> {
+    let mut sources = fenced_blocks(text);
+    sources.push(text.trim());
+
+    let mut candidates = Vec::new();
+    let mut push = |value: serde_json::Value| {
+        if !candidates.contains(&value) {
+            candidates.push(value);
+        }
+    };
+    for source in &sources {
+        if let Some(value) = parse_lenient(source) {
+            push(value);
+            continue;
+        }
+        let mut start = 0;
+        while let Some(offset) = source[start..].find(['{', '[']) {
+            let open = start + offset;
+            match balanced_span(&source[open..]) {
+                Some(span) => {
+                    if let Some(value) = parse_lenient(span) {
+                        push(value);
+                        start = open + span.len();
+                        continue;
+                    }
+                    start = open + 1;
+                }
+                None => start = open + 1,
+            }
+        }
+    }
+
+    if candidates.is_empty()
+        && let Some(value) = sources.iter().find_map(|source| parse_partial_json(&lenient(source)))
+    {
+        candidates.push(value);
+    }
+    candidates
+}
+
+/// 回答中各个代码块围栏内的文本，忽略语言标记；未闭合的围栏取到结尾
+/// Text inside each code fence of an answer, ignoring the language tag; an unclosed fence runs to the end
+fn fenced_blocks(text: &str) -> Vec<&str> {
+    let mut blocks = Vec::new();
+    let mut rest = text;
+    while let Some(open) = rest.find(FENCE) {
+        let after = &rest[open + FENCE.len()..];
+        let body = after.split_once('\n').map_or("", |(_, body)| body);
+        match body.find(FENCE) {
+            Some(close) => {
+                blocks.push(body[..close].trim());
+                rest = &body[close + FENCE.len()..];
+            }
+            None => {
+                blocks.push(body.trim());
+                break;
+            }
+        }
+    }
+    blocks
+}
+
+/// 从开头的 `{` 或 `[` 到与之配对的闭合括号，字符串内的括号不计；没有配对时为 `None`
+/// From the leading `{` or `[` to its matching bracket, ignoring brackets inside strings; `None` when unmatched
+fn balanced_span(text: &str) -> Option<&str> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, ch) in text.char_indices() {
+        if in_string {
+            match ch {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match ch {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(&text[..=index]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_lenient(text: &str) -> Option<serde_json::Value> {
+    serde_json::from_str(text)
+        .ok()
+        .or_else(|| serde_json::from_str(&lenient(text)).ok())
+}
+
+/// 去掉字符串之外的注释与对象、数组末尾多余的逗号
+/// Drop comments and trailing commas of objects and arrays outside strings
+fn lenient(text: &str) -> Cow<'_, str> {
+    if !text.contains([',', '/']) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+    let mut escaped = false;
+    // 尚未确定是否多余的逗号及其后的空白
+    // Comma not yet known to be trailing, with the whitespace after it
+    let mut pending = String::new();
+    while let Some(ch) = chars.next() {
+        if in_string {
+            result.push(ch);
+            match ch {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match ch {
+            '/' if chars.peek() == Some(&'/') => {
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        push_whitespace(&mut result, &mut pending, '\n');
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = '\0';
+                for next in chars.by_ref() {
+                    if last == '*' && next == '/' {
+                        break;
+                    }
+                    last = next;
+                }
+            }
+            ',' => {
+                result.push_str(&pending);
+                pending.clear();
+                pending.push(',');
+            }
+            c if c.is_whitespace() => push_whitespace(&mut result, &mut pending, c),
+            '}' | ']' => {
+                // 闭合括号前的逗号是多余的，只保留其后的空白
+                // A comma right before a closing bracket is trailing; only the whitespace after it is kept
+                result.push_str(pending.trim_start_matches(','));
+                pending.clear();
+                result.push(ch);
+            }
+            _ => {
+                result.push_str(&pending);
+                pending.clear();
+                result.push(ch);
+                in_string = ch == '"';
+            }
+        }
+    }
+    result.push_str(&pending);
+    Cow::Owned(result)
+}
+
+fn push_whitespace(result: &mut String, pending: &mut String, ch: char) {
+    if pending.is_empty() {
+        result.push(ch);
+    } else {
+        pending.push(ch);
+    }
+}
@@ -0,0 +1,395 @@
+use std::path::{Path, PathBuf};
+
+use error_stack::{Report, Result, ResultExt};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tracing::info;
+
+use crate::chat::chat_base::acquire_source_permit;
+use crate::runtime::Runtime;
+use crate::chat::context::ContextMessage;
+use crate::chat::mock::MockReply;
+use crate::chat::scheduler::RequestPriority;
+use crate::config::{ApiInfo, Config};
+use crate::utils::common::body_log::body_attachment;
+use crate::utils::common::redact::{mask_secret, redact};
+
+/// 上下文消息中携带已上传文件引用的保留键，构建请求时展开为内容片段，不会原样发送
+/// Reserved context message key carrying uploaded file references; expanded into content parts when the request is built and never sent as is
+pub const FILE_PARTS_KEY: &str = "rhine_file_parts";
+
+const GEMINI_HOST: &str = "generativelanguage.googleapis.com";
+
+#[derive(Debug, Error)]
+pub enum FileError {
+    #[error("Cannot derive files endpoint from: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("Failed to read file: {0}")]
+    ReadError(String),
+
+    #[error("Failed to upload file: {0}")]
+    UploadError(String),
+
+    #[error("Failed to parse files response")]
+    ParseResponseError,
+
+    #[error("Failed to delete file: {0}")]
+    DeleteError(String),
+}
+
+/// 文件接口的服务商风格
+/// Provider style of the files API
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileProvider {
+    /// OpenAI `/files`（多部分表单上传）
+    /// OpenAI `/files` (multipart upload)
+    #[default]
+    OpenAi,
+
+    /// Gemini Files API（可续传上传）
+    /// Gemini Files API (resumable upload)
+    Gemini,
+}
+
+/// 已上传到服务商的文件
+/// File uploaded to a provider
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadedFile {
+    /// 服务商的文件 ID，Gemini 为 `files/…` 资源名
+    /// Provider file ID; the `files/…` resource name for Gemini
+    pub id: String,
+
+    pub name: String,
+
+    pub provider: FileProvider,
+
+    pub mime_type: String,
+
+    pub bytes: u64,
+
+    /// Gemini 文件的 URI，消息中以它引用文件
+    /// URI of a Gemini file, used to reference it in messages
+    #[serde(default)]
+    pub uri: Option<String>,
+}
+
+impl UploadedFile {
+    /// 在消息中引用该文件的内容片段
+    /// Content part referencing this file in a message
+    pub fn content_part(&self) -> serde_json::Value {
+        match (self.provider, &self.uri) {
+            (FileProvider::Gemini, Some(uri)) => json!({
+                "type": "file",
+                "file": {"file_uri": uri, "mime_type": self.mime_type},
+            }),
+            _ => json!({"type": "file", "file": {"file_id": self.id}}),
+        }
+    }
+}
+
+/// 按扩展名推断 MIME 类型
+/// Guess the MIME type from the extension
+pub fn mime_type_of(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("pdf") => "application/pdf",
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("csv") => "text/csv",
+        Some("json") => "application/json",
+        Some("html" | "htm") => "text/html",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// 服务商文件接口客户端，与对话共用API来源的连接池与并发许可
+/// Client of provider files APIs, sharing the connection pool and concurrency permits of its API source
+///
+/// 上传过的文件按 (API来源, 本地路径) 记录在配置中，重复上传同一文件直接返回已有的 ID
+/// Uploaded files are tracked in the config by (API source, local path), so uploading the same file again returns the existing ID
+#[derive(Clone)]
+pub struct FileClient {
+    provider: FileProvider,
+
+    /// OpenAI 为 `…/files`，Gemini 为 `https://host`
+    /// `…/files` for OpenAI, `https://host` for Gemini
+    url: String,
+
+    base_url: String,
+
+    api_key: String,
+
+    client: Client,
+
+    /// 上传时使用的 `purpose`（仅 OpenAI）
+    /// `purpose` used for uploads (OpenAI only)
+    pub purpose: String,
+
+    pub priority: RequestPriority,
+}
+
+impl std::fmt::Debug for FileClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileClient")
+            .field("provider", &self.provider)
+            .field("url", &self.url)
+            .field("api_key", &mask_secret(&self.api_key))
+            .field("purpose", &self.purpose)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileClient {
+    /// 由API信息推导文件接口：Gemini 地址使用 Gemini Files API，其余把 `/chat/completions` 替换为 `/files`
+    /// Derive the files endpoint from API info: Gemini hosts use the Gemini Files API, others turn `/chat/completions` into `/files`
+    pub fn new_with_api_info(api_info: ApiInfo) -> Result<Self, FileError> {
+        let invalid = || Report::new(FileError::InvalidEndpoint(api_info.base_url.clone()));
+        let (provider, url) = if Runtime::default_ref().mocks.contains_key(&api_info.base_url) {
+            (FileProvider::OpenAi, api_info.base_url.clone())
+        } else {
+            let parsed = Url::parse(&api_info.base_url).map_err(|_| invalid())?;
+            match parsed.host_str() {
+                Some(host) if host == GEMINI_HOST => {
+                    (FileProvider::Gemini, format!("{}://{host}", parsed.scheme()))
+                }
+                _ => {
+                    let root = api_info
+                        .base_url
+                        .strip_suffix("/chat/completions")
+                        .ok_or_else(invalid)?;
+                    (FileProvider::OpenAi, format!("{root}/files"))
+                }
+            }
+        };
+        Ok(Self {
+            provider,
+            url,
+            base_url: api_info.base_url,
+            api_key: api_info.api_key,
+            client: api_info.client,
+            purpose: "user_data".to_string(),
+            priority: RequestPriority::default(),
+        })
+    }
+
+    pub fn new_with_api_name(api_name: &str) -> Result<Self, FileError> {
+        let api_info = Config::get_api_info_with_name(api_name.to_string())
+            .change_context_lazy(|| FileError::InvalidEndpoint(api_name.to_string()))?;
+        Self::new_with_api_info(api_info)
+    }
+
+    pub fn with_purpose(mut self, purpose: &str) -> Self {
+        self.purpose = purpose.to_string();
+        self
+    }
+
+    pub fn provider(&self) -> FileProvider {
+        self.provider
+    }
+
+    /// 上传本地文件，已上传过的文件直接返回记录
+    /// Upload a local file; files uploaded before return their record
+    pub async fn upload(&self, path: impl AsRef<Path>) -> Result<UploadedFile, FileError> {
+        let path = path.as_ref();
+        if let Some(file) = Config::get_uploaded_file(&self.base_url, path) {
+            return Ok(file);
+        }
+
+        let bytes = tokio::fs::read(path)
+            .await
+            .change_context_lazy(|| FileError::ReadError(path.display().to_string()))?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let file = self.upload_bytes(&name, mime_type_of(path), bytes).await?;
+        Config::record_uploaded_file(&self.base_url, path, file.clone());
+        Ok(file)
+    }
+
+    /// 上传内存中的内容，不记录在配置中
+    /// Upload in-memory content; not tracked in the config
+    pub async fn upload_bytes(&self, name: &str, mime_type: &str, bytes: Vec<u8>) -> Result<UploadedFile, FileError> {
+        let _semaphore_permit = acquire_source_permit(Runtime::default_ref(), &self.base_url, self.priority)
+            .await
+            .change_context_lazy(|| FileError::UploadError(name.to_string()))?;
+        let size = bytes.len() as u64;
+
+        let response = match Runtime::default_ref().mock_api(&self.base_url) {
+            Some(mock) => {
+                let request = json!({"purpose": self.purpose, "filename": name, "bytes": size});
+                match mock.reply(&request).await {
+                    MockReply::Raw(response) => response,
+                    reply => {
+                        return Err(Report::new(FileError::UploadError(name.to_string())))
+                            .attach_printable(format!("Mock reply: {reply:?}"));
+                    }
+                }
+            }
+            None => match self.provider {
+                FileProvider::OpenAi => self.upload_openai(name, mime_type, bytes).await?,
+                FileProvider::Gemini => self.upload_gemini(name, mime_type, bytes).await?,
+            },
+        };
+
+        let file = parse_uploaded(self.provider, &response, name, mime_type, size)?;
+        info!("Uploaded {} as {}", name, file.id);
+        Ok(file)
+    }
+
+    async fn upload_openai(&self, name: &str, mime_type: &str, bytes: Vec<u8>) -> Result<serde_json::Value, FileError> {
+        let upload_error = || FileError::UploadError(name.to_string());
+        let part = Part::bytes(bytes)
+            .file_name(name.to_string())
+            .mime_str(mime_type)
+            .change_context_lazy(upload_error)?;
+        let form = Form::new().text("purpose", self.purpose.clone()).part("file", part);
+        self.client
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .multipart(form)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Report::new(upload_error()).attach_printable(redact(&e.to_string())))?
+            .json()
+            .await
+            .change_context(FileError::ParseResponseError)
+    }
+
+    /// Gemini 可续传上传：先申请上传地址，再一次性上传并结束
+    /// Gemini resumable upload: request an upload URL, then upload and finalize in one go
+    async fn upload_gemini(&self, name: &str, mime_type: &str, bytes: Vec<u8>) -> Result<serde_json::Value, FileError> {
+        let upload_error = || FileError::UploadError(name.to_string());
+        let start = self
+            .client
+            .post(format!("{}/upload/v1beta/files", self.url))
+            .header("x-goog-api-key", &self.api_key)
+            .header("X-Goog-Upload-Protocol", "resumable")
+            .header("X-Goog-Upload-Command", "start")
+            .header("X-Goog-Upload-Header-Content-Length", bytes.len())
+            .header("X-Goog-Upload-Header-Content-Type", mime_type)
+            .json(&json!({"file": {"display_name": name}}))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Report::new(upload_error()).attach_printable(redact(&e.to_string())))?;
+        let upload_url = start
+            .headers()
+            .get("x-goog-upload-url")
+            .and_then(|url| url.to_str().ok())
+            .ok_or_else(|| Report::new(upload_error()))
+            .attach_printable("Missing x-goog-upload-url header")?
+            .to_string();
+
+        self.client
+            .post(upload_url)
+            .header("X-Goog-Upload-Offset", 0)
+            .header("X-Goog-Upload-Command", "upload, finalize")
+            .body(bytes)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Report::new(upload_error()).attach_printable(redact(&e.to_string())))?
+            .json()
+            .await
+            .change_context(FileError::ParseResponseError)
+    }
+
+    /// 删除服务商上的文件，并从配置中移除记录
+    /// Delete a file on the provider and drop its record from the config
+    pub async fn delete(&self, file: &UploadedFile) -> Result<(), FileError> {
+        Config::forget_uploaded_file(&self.base_url, &file.id);
+        if Runtime::default_ref().mocks.contains_key(&self.base_url) {
+            return Ok(());
+        }
+
+        let _semaphore_permit = acquire_source_permit(Runtime::default_ref(), &self.base_url, self.priority)
+            .await
+            .change_context_lazy(|| FileError::DeleteError(file.id.clone()))?;
+        let request = match self.provider {
+            FileProvider::OpenAi => self
+                .client
+                .delete(format!("{}/{}", self.url, file.id))
+                .bearer_auth(&self.api_key),
+            FileProvider::Gemini => self
+                .client
+                .delete(format!("{}/v1beta/{}", self.url, file.id))
+                .header("x-goog-api-key", &self.api_key),
+        };
+        request
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Report::new(FileError::DeleteError(file.id.clone())).attach_printable(redact(&e.to_string())))?;
+        Ok(())
+    }
+}
+
+fn parse_uploaded(
+    provider: FileProvider,
+    response: &serde_json::Value,
+    name: &str,
+    mime_type: &str,
+    bytes: u64,
+) -> Result<UploadedFile, FileError> {
+    let (object, uri) = match provider {
+        FileProvider::OpenAi => (response, None),
+        FileProvider::Gemini => (&response["file"], response["file"]["uri"].as_str().map(str::to_string)),
+    };
+    let id_key = match provider {
+        FileProvider::OpenAi => "id",
+        FileProvider::Gemini => "name",
+    };
+    let id = object[id_key]
+        .as_str()
+        .ok_or_else(|| Report::new(FileError::ParseResponseError))
+        .attach_printable_lazy(|| body_attachment(&format!("Missing file {id_key}"), response))?;
+    Ok(UploadedFile {
+        id: id.to_string(),
+        name: name.to_string(),
+        provider,
+        mime_type: object["mimeType"].as_str().unwrap_or(mime_type).to_string(),
+        bytes: object["bytes"].as_u64().unwrap_or(bytes),
+        uri,
+    })
+}
+
+/// 把上下文消息转为请求中的 JSON 消息，带有文件引用的消息展开为 `[文本, 文件…]` 内容片段数组
+/// Turn context messages into request JSON, expanding messages with file references into `[text, files…]` content part arrays
+pub fn expand_file_parts(messages: Vec<ContextMessage>) -> Vec<serde_json::Value> {
+    messages
+        .into_iter()
+        .map(|mut message| {
+            let parts = message
+                .remove(FILE_PARTS_KEY)
+                .and_then(|parts| serde_json::from_str::<Vec<serde_json::Value>>(&parts).ok());
+            let mut json = json!(message);
+            if let Some(parts) = parts {
+                let text = message.get("content").cloned().unwrap_or_default();
+                let mut content = vec![json!({"type": "text", "text": text})];
+                content.extend(parts);
+                json["content"] = serde_json::Value::Array(content);
+            }
+            json
+        })
+        .collect()
+}
+
+/// 本地路径在配置中的键
+/// Config key of a local path
+pub(crate) fn path_key(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chat::message::{Role, TranscriptStyle};
+
+/// 导出微调数据时选取的分支
+/// Branches picked when exporting fine-tuning data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinetuneBranch {
+    /// 会话的默认路径
+    /// The session default path
+    Default,
+
+    /// 从根到指定消息的路径
+    /// The path from the root to the given message
+    Path(Vec<usize>),
+
+    /// 命名树中每一条从根到叶的分支
+    /// Every root-to-leaf branch of a named tree
+    Tree(String),
+
+    /// 会话中每一条从根到叶的分支
+    /// Every root-to-leaf branch of the session
+    All,
+}
+
+/// 微调数据导出选项
+/// Options of the fine-tuning data export
+///
+/// 每条分支导出为一条 `{"messages": [...]}` 记录；`speaker` 的消息作为 `assistant`，
+/// 其他角色按 `style` 呈现，与组装上下文时一致
+/// Every branch becomes one `{"messages": [...]}` record; messages of `speaker` become `assistant`
+/// and other characters are rendered with `style`, the same as when the context is assembled
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FinetuneOptions {
+    pub speaker: Role,
+
+    pub style: TranscriptStyle,
+
+    /// 映射后再改写的角色名（如 `system` → `developer`）
+    /// Role names rewritten after the mapping (e.g. `system` → `developer`)
+    pub role_names: HashMap<String, String>,
+
+    pub include_system: bool,
+
+    /// 是否保留对 `speaker` 不可见的私有消息
+    /// Whether messages private to characters other than `speaker` are kept
+    pub include_private: bool,
+
+    /// 是否保留工具与函数调用结果
+    /// Whether tool and function call results are kept
+    pub include_tool_results: bool,
+
+    /// 去掉最后一条 `assistant` 消息之后的消息，使每条记录以回答结尾
+    /// Drop messages after the last `assistant` message so every record ends with an answer
+    pub trim_trailing: bool,
+
+    /// 少于该数量 `assistant` 消息的记录会被跳过
+    /// Records with fewer `assistant` messages are skipped
+    pub min_assistant_messages: usize,
+}
+
+impl Default for FinetuneOptions {
+    fn default() -> Self {
+        Self {
+            speaker: Role::Assistant,
+            style: TranscriptStyle::default(),
+            role_names: HashMap::new(),
+            include_system: true,
+            include_private: false,
+            include_tool_results: true,
+            trim_trailing: true,
+            min_assistant_messages: 1,
+        }
+    }
+}
+
+impl FinetuneOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_speaker(mut self, speaker: Role) -> Self {
+        self.speaker = speaker;
+        self
+    }
+
+    pub fn with_style(mut self, style: TranscriptStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    pub fn with_role_name(mut self, from: &str, to: &str) -> Self {
+        self.role_names.insert(from.to_string(), to.to_string());
+        self
+    }
+
+    pub fn with_system(mut self, include_system: bool) -> Self {
+        self.include_system = include_system;
+        self
+    }
+
+    pub fn with_private(mut self, include_private: bool) -> Self {
+        self.include_private = include_private;
+        self
+    }
+
+    pub fn with_tool_results(mut self, include_tool_results: bool) -> Self {
+        self.include_tool_results = include_tool_results;
+        self
+    }
+
+    pub fn with_trim_trailing(mut self, trim_trailing: bool) -> Self {
+        self.trim_trailing = trim_trailing;
+        self
+    }
+
+    pub fn with_min_assistant_messages(mut self, min_assistant_messages: usize) -> Self {
+        self.min_assistant_messages = min_assistant_messages;
+        self
+    }
+}
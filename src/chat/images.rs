@@ -0,0 +1,314 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use error_stack::{Report, Result, ResultExt};
+use reqwest::Client;
+use serde_json::json;
+use thiserror::Error;
+use tracing::info;
+
+use crate::chat::chat_base::acquire_source_permit;
+use crate::runtime::Runtime;
+use crate::chat::mock::MockReply;
+use crate::chat::scheduler::{pause_source, RequestPriority};
+use crate::config::{ApiInfo, Config, ModelCapability};
+use crate::utils::common::body_log::body_attachment;
+use crate::utils::common::redact::{mask_secret, redact};
+
+#[derive(Debug, Error)]
+pub enum ImageError {
+    #[error("Cannot derive image endpoint from: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("HTTP error with status code: {0}")]
+    HttpError(u16),
+
+    #[error("Timeout error")]
+    TimeoutError,
+
+    #[error("Image request failed")]
+    RequestError,
+
+    #[error("Failed to parse image response")]
+    ParseResponseError,
+
+    #[error("Failed to decode image data")]
+    DecodeError,
+}
+
+/// 服务商返回图像的方式
+/// How the provider returns images
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageResponseFormat {
+    /// 返回临时链接（默认）
+    /// Temporary URLs (default)
+    #[default]
+    Url,
+
+    /// 以 Base64 返回图像字节
+    /// Image bytes encoded as Base64
+    Bytes,
+}
+
+/// 图像生成选项，未设置的字段不会发送，由服务商决定默认值
+/// Image generation options; unset fields are not sent and left to provider defaults
+#[derive(Debug, Clone, Default)]
+pub struct ImageOptions {
+    /// 生成数量
+    /// Number of images
+    pub n: Option<u32>,
+
+    /// 尺寸，例如 `1024x1024`
+    /// Size, e.g. `1024x1024`
+    pub size: Option<String>,
+
+    pub quality: Option<String>,
+
+    pub style: Option<String>,
+
+    pub response_format: ImageResponseFormat,
+
+    pub priority: RequestPriority,
+}
+
+impl ImageOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_n(mut self, n: u32) -> Self {
+        self.n = Some(n);
+        self
+    }
+
+    pub fn with_size(mut self, size: &str) -> Self {
+        self.size = Some(size.to_string());
+        self
+    }
+
+    pub fn with_quality(mut self, quality: &str) -> Self {
+        self.quality = Some(quality.to_string());
+        self
+    }
+
+    pub fn with_style(mut self, style: &str) -> Self {
+        self.style = Some(style.to_string());
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: ImageResponseFormat) -> Self {
+        self.response_format = response_format;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: RequestPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// 生成的图像内容
+/// Content of a generated image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageData {
+    Url(String),
+
+    Bytes(Vec<u8>),
+}
+
+/// 一张生成的图像
+/// One generated image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedImage {
+    pub data: ImageData,
+
+    /// 服务商改写后实际使用的提示
+    /// Prompt as rewritten and actually used by the provider
+    pub revised_prompt: Option<String>,
+}
+
+impl GeneratedImage {
+    pub fn url(&self) -> Option<&str> {
+        match &self.data {
+            ImageData::Url(url) => Some(url),
+            ImageData::Bytes(_) => None,
+        }
+    }
+
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match &self.data {
+            ImageData::Bytes(bytes) => Some(bytes),
+            ImageData::Url(_) => None,
+        }
+    }
+}
+
+/// 基于 OpenAI 风格 `/images/generations` 接口的图像生成，与对话共用API来源的连接池与并发许可
+/// Image generation backed by an OpenAI-style `/images/generations` endpoint, sharing the connection pool and concurrency permits of its API source
+#[derive(Clone)]
+pub struct ImageGenerator {
+    url: String,
+
+    base_url: String,
+
+    api_key: String,
+
+    model: String,
+
+    client: Client,
+}
+
+impl std::fmt::Debug for ImageGenerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageGenerator")
+            .field("url", &self.url)
+            .field("model", &self.model)
+            .field("api_key", &mask_secret(&self.api_key))
+            .finish_non_exhaustive()
+    }
+}
+
+impl ImageGenerator {
+    /// 由API信息推导图像接口（将 `/chat/completions` 替换为 `/images/generations`）
+    /// Derive the image endpoint from API info (`/chat/completions` becomes `/images/generations`)
+    pub fn new_with_api_info(api_info: ApiInfo) -> Result<Self, ImageError> {
+        let url = if Runtime::default_ref().mocks.contains_key(&api_info.base_url) {
+            api_info.base_url.clone()
+        } else {
+            let root = api_info
+                .base_url
+                .strip_suffix("/chat/completions")
+                .ok_or_else(|| Report::new(ImageError::InvalidEndpoint(api_info.base_url.clone())))?;
+            format!("{root}/images/generations")
+        };
+        Ok(Self {
+            url,
+            base_url: api_info.base_url,
+            api_key: api_info.api_key,
+            model: api_info.model,
+            client: api_info.client,
+        })
+    }
+
+    pub fn new_with_api_name(api_name: &str) -> Result<Self, ImageError> {
+        let api_info = Config::get_api_info_with_name(api_name.to_string())
+            .change_context_lazy(|| ImageError::InvalidEndpoint(api_name.to_string()))?;
+        Self::new_with_api_info(api_info)
+    }
+
+    pub fn new_with_model_capability() -> Result<Self, ImageError> {
+        let api_info = Config::get_api_info_with_capability(ModelCapability::ImageGeneration)
+            .change_context(ImageError::InvalidEndpoint("image_generation".to_string()))?;
+        Self::new_with_api_info(api_info)
+    }
+
+    /// 替换图像接口地址，用于路径不同的服务商
+    /// Replace the image endpoint URL, for providers with a different path
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.url = url.to_string();
+        self
+    }
+
+    fn request_body(&self, prompt: &str, options: &ImageOptions) -> serde_json::Value {
+        let mut body = json!({"model": self.model, "prompt": prompt});
+        if let Some(n) = options.n {
+            body["n"] = json!(n);
+        }
+        for (key, value) in [("size", &options.size), ("quality", &options.quality), ("style", &options.style)] {
+            if let Some(value) = value {
+                body[key] = json!(value);
+            }
+        }
+        if options.response_format == ImageResponseFormat::Bytes {
+            body["response_format"] = json!("b64_json");
+        }
+        body
+    }
+
+    /// 生成图像
+    /// Generate images
+    ///
+    /// # 参数 (Parameters)
+    /// * `prompt` - 图像描述
+    ///   - Image description
+    /// * `options` - 生成选项
+    ///   - Generation options
+    pub async fn generate(&self, prompt: &str, options: &ImageOptions) -> Result<Vec<GeneratedImage>, ImageError> {
+        let body = self.request_body(prompt, options);
+        let _semaphore_permit = acquire_source_permit(Runtime::default_ref(), &self.base_url, options.priority)
+            .await
+            .change_context(ImageError::RequestError)?;
+
+        let response = match Runtime::default_ref().mock_api(&self.base_url) {
+            Some(mock) => match mock.reply(&body).await {
+                MockReply::Raw(response) => response,
+                MockReply::HttpError(status) => return Err(Report::new(ImageError::HttpError(status))),
+                MockReply::RateLimited(retry_after) => {
+                    pause_source(&self.base_url, retry_after);
+                    return Err(Report::new(ImageError::HttpError(429)));
+                }
+                MockReply::Timeout => return Err(Report::new(ImageError::TimeoutError)),
+                MockReply::Text(_) => return Err(Report::new(ImageError::ParseResponseError)),
+            },
+            None => self.send(&body).await?,
+        };
+
+        let images = parse_images(&response)?;
+        info!("Generated {} images with {}", images.len(), self.model);
+        Ok(images)
+    }
+
+    async fn send(&self, body: &serde_json::Value) -> Result<serde_json::Value, ImageError> {
+        self.client
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .json(body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| {
+                let error = match e.status() {
+                    Some(status) => ImageError::HttpError(status.as_u16()),
+                    None if e.is_timeout() => ImageError::TimeoutError,
+                    None => ImageError::RequestError,
+                };
+                Report::new(error)
+                    .attach_printable(redact(&e.to_string()))
+                    .attach_printable(body_attachment("Request body", body))
+            })?
+            .json()
+            .await
+            .change_context(ImageError::ParseResponseError)
+    }
+}
+
+/// 解析 `data` 数组，每项为 `url` 或 `b64_json`
+/// Parse the `data` array, each item holding either `url` or `b64_json`
+fn parse_images(response: &serde_json::Value) -> Result<Vec<GeneratedImage>, ImageError> {
+    let data = response["data"]
+        .as_array()
+        .ok_or_else(|| Report::new(ImageError::ParseResponseError))
+        .attach_printable("Missing data in image response")?;
+
+    data.iter()
+        .map(|item| {
+            let data = if let Some(url) = item["url"].as_str() {
+                ImageData::Url(url.to_string())
+            } else if let Some(encoded) = item["b64_json"].as_str() {
+                ImageData::Bytes(STANDARD.decode(encoded).change_context(ImageError::DecodeError)?)
+            } else {
+                return Err(Report::new(ImageError::ParseResponseError))
+                    .attach_printable("Image item has neither url nor b64_json");
+            };
+            Ok(GeneratedImage {
+                data,
+                revised_prompt: item["revised_prompt"].as_str().map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// 用 `ModelCapability::ImageGeneration` 对应的API生成图像
+/// Generate images with the API registered for `ModelCapability::ImageGeneration`
+pub async fn generate(prompt: &str, options: &ImageOptions) -> Result<Vec<GeneratedImage>, ImageError> {
+    ImageGenerator::new_with_model_capability()?.generate(prompt, options).await
+}
@@ -0,0 +1,169 @@
+use error_stack::{Report, Result, ResultExt};
+use serde::de::DeserializeOwned;
+use tracing::info;
+
+use crate::chat::chat_base::{BaseChat, ChatError};
+use crate::chat::chat_tool::add_response_format;
+use crate::chat::message::{Role, Session};
+use crate::config::{AuxiliaryTask, ModelCapability};
+use crate::prompt::assembler::{extract_properties_with_defs, schema_definitions, DEFAULT_HIDDEN_FIELDS};
+use crate::schema::json_schema::JsonSchema;
+use crate::schema::validator::{inner_schema, validate};
+use crate::utils::common::redact::redact;
+
+const JUDGE_PROMPT: &str = "你是一名严格、公正的评审，请依据评分标准对回答逐项打分，只输出符合格式的JSON";
+
+/// 评审：按评分标准对回答给出结构化分数
+/// Judge: scores an answer against a rubric and returns structured scores
+#[derive(Debug, Clone)]
+pub struct Judge {
+    pub base: BaseChat,
+
+    /// 额外的评分说明
+    /// Extra scoring instructions
+    criteria: Option<String>,
+
+    /// 未声明范围的数值字段使用的默认范围
+    /// Default range for numeric fields without declared bounds
+    score_range: Option<(f64, f64)>,
+}
+
+impl Judge {
+    /// 使用 `AuxiliaryTask::Judge` 对应的能力档位
+    /// Use the capability tier configured for `AuxiliaryTask::Judge`
+    pub fn new() -> Self {
+        Self::with_base(BaseChat::new_with_auxiliary_task(AuxiliaryTask::Judge, JUDGE_PROMPT, false))
+    }
+
+    pub fn new_with_model_capability(model_capability: ModelCapability) -> Self {
+        Self::with_base(BaseChat::new_with_model_capability(model_capability, JUDGE_PROMPT, false))
+    }
+
+    pub fn new_with_api_name(api_name: &str) -> Self {
+        Self::with_base(BaseChat::new_with_api_name(api_name, JUDGE_PROMPT, false))
+    }
+
+    fn with_base(base: BaseChat) -> Self {
+        Self {
+            base,
+            criteria: None,
+            score_range: None,
+        }
+    }
+
+    pub fn with_criteria(mut self, criteria: &str) -> Self {
+        self.criteria = Some(criteria.to_string());
+        self
+    }
+
+    /// 为未声明 `minimum`/`maximum` 的数值字段设置范围
+    /// Bound numeric fields that declare no `minimum`/`maximum`
+    pub fn with_score_range(mut self, min: f64, max: f64) -> Self {
+        self.score_range = Some((min, max));
+        self
+    }
+
+    /// 按评分标准类型打分
+    /// Score with a rubric type
+    ///
+    /// # 参数 (Parameters)
+    /// * `question` - 原始问题
+    ///   - Original question
+    /// * `answer` - 待评审的回答
+    ///   - Answer under review
+    pub async fn score<T: DeserializeOwned + JsonSchema + 'static>(
+        &mut self,
+        question: &str,
+        answer: &str,
+    ) -> Result<T, ChatError> {
+        let scores = self.score_with_schema(question, answer, (*T::cached_json_schema()).clone()).await?;
+        serde_json::from_value(scores.clone())
+            .change_context(ChatError::InvalidScore(scores.to_string()))
+    }
+
+    /// 按 `response_format` 形式的评分标准模式打分，分数会按模式中的数值约束校验
+    /// Score with a rubric schema in `response_format` form; scores are checked against its numeric constraints
+    pub async fn score_with_schema(
+        &mut self,
+        question: &str,
+        answer: &str,
+        mut schema: serde_json::Value,
+    ) -> Result<serde_json::Value, ChatError> {
+        if let Some((min, max)) = self.score_range {
+            apply_score_range(&mut schema, min, max);
+        }
+
+        self.base.session = Session::new();
+        self.base.add_message(Role::System, JUDGE_PROMPT)?;
+
+        let mut prompt = format!(
+            "评分标准：\n{}",
+            extract_properties_with_defs(
+                &inner_schema(&schema)["properties"],
+                &schema_definitions(inner_schema(&schema)),
+                DEFAULT_HIDDEN_FIELDS,
+                1
+            )
+        );
+        if let Some(criteria) = &self.criteria {
+            prompt.push_str(&format!("\n补充说明：{criteria}\n"));
+        }
+        prompt.push_str(&format!("\n问题：\n{question}\n\n回答：\n{answer}"));
+        self.base.add_message(Role::User, &prompt)?;
+
+        let request_body = add_response_format(
+            self.base
+                .build_request_body(&self.base.session.default_path.clone(), &Role::User)
+                .await?,
+            schema.clone(),
+        );
+        let response = self.base.get_response(request_body).await?;
+        let content = BaseChat::get_content_from_resp(&response)?;
+        info!("Judge scores: {}", redact(&content));
+
+        let scores: serde_json::Value = serde_json::from_str(&content)
+            .change_context_lazy(|| ChatError::InvalidScore(content.clone()))?;
+        let violations = validate(&schema, &scores);
+        if !violations.is_empty() {
+            let detail = violations
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join("; ");
+            return Err(Report::new(ChatError::InvalidScore(detail)))
+                .attach_printable(format!("Scores: {}", redact(&content)));
+        }
+
+        Ok(scores)
+    }
+}
+
+impl Default for Judge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 为未声明范围的数值字段加上范围
+/// Add bounds to numeric fields that declare none
+fn apply_score_range(schema: &mut serde_json::Value, min: f64, max: f64) {
+    let properties = if schema.get("json_schema").is_some() {
+        schema.pointer_mut("/json_schema/schema/properties")
+    } else {
+        schema.pointer_mut("/properties")
+    };
+    let Some(properties) = properties.and_then(|p| p.as_object_mut()) else {
+        return;
+    };
+
+    for field in properties.values_mut() {
+        let numeric = matches!(
+            field.get("type").and_then(|t| t.as_str()),
+            Some("integer" | "number")
+        );
+        if let (true, Some(field)) = (numeric, field.as_object_mut()) {
+            field.entry("minimum").or_insert(min.into());
+            field.entry("maximum").or_insert(max.into());
+        }
+    }
+}
@@ -0,0 +1,69 @@
+/// 模型的上下文长度与回答长度上限（令牌数），未知的项为 `None`
+/// Context window and output cap of a model in tokens; unknown items are `None`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModelLimits {
+    /// 提示与回答合计的令牌数上限
+    /// Token limit for the prompt and the answer together
+    pub context_window: Option<u64>,
+
+    /// 单次回答的令牌数上限
+    /// Token limit of a single answer
+    pub max_output_tokens: Option<u64>,
+}
+
+impl ModelLimits {
+    /// 上下文长度减去回答上限，即留给提示的令牌数；上下文长度未知时为 `None`
+    /// Context window minus the output cap, i.e. the tokens left for the prompt; `None` when the window is unknown
+    pub fn prompt_budget(&self) -> Option<u64> {
+        self.context_window
+            .map(|window| window.saturating_sub(self.max_output_tokens.unwrap_or_default()))
+    }
+}
+
+/// 常见模型的上下文长度与回答上限，按模型名前缀匹配，最长的前缀优先
+/// Context windows and output caps of common models, matched by model name prefix with the longest prefix winning
+const BUILTIN_LIMITS: &[(&str, u64, u64)] = &[
+    ("gpt-3.5-turbo", 16_385, 4_096),
+    ("gpt-4", 8_192, 8_192),
+    ("gpt-4-turbo", 128_000, 4_096),
+    ("gpt-4o", 128_000, 16_384),
+    ("gpt-4.1", 1_047_576, 32_768),
+    ("gpt-5", 400_000, 128_000),
+    ("o1", 200_000, 100_000),
+    ("o1-mini", 128_000, 65_536),
+    ("o3", 200_000, 100_000),
+    ("o4-mini", 200_000, 100_000),
+    ("claude-3-haiku", 200_000, 4_096),
+    ("claude-3-opus", 200_000, 4_096),
+    ("claude-3-5-haiku", 200_000, 8_192),
+    ("claude-3-5-sonnet", 200_000, 8_192),
+    ("claude-3-7-sonnet", 200_000, 64_000),
+    ("claude-sonnet-4", 200_000, 64_000),
+    ("claude-opus-4", 200_000, 32_000),
+    ("gemini-1.5-flash", 1_048_576, 8_192),
+    ("gemini-1.5-pro", 2_097_152, 8_192),
+    ("gemini-2.0-flash", 1_048_576, 8_192),
+    ("gemini-2.5-flash", 1_048_576, 65_536),
+    ("gemini-2.5-pro", 1_048_576, 65_536),
+    ("deepseek-chat", 65_536, 8_192),
+    ("deepseek-reasoner", 65_536, 32_768),
+    ("qwen-turbo", 1_000_000, 8_192),
+    ("qwen-plus", 131_072, 8_192),
+    ("qwen-max", 32_768, 8_192),
+    ("glm-4", 128_000, 4_096),
+];
+
+/// 内置表中模型的上限；带服务商前缀的名称（如 `openai/gpt-4o`）按去掉前缀后的名称查找
+/// Limits of a model from the built-in table; provider-prefixed names such as `openai/gpt-4o` are looked up without
+/// the prefix
+pub fn builtin_limits(model: &str) -> Option<ModelLimits> {
+    let name = model.rsplit('/').next().unwrap_or(model).to_ascii_lowercase();
+    BUILTIN_LIMITS
+        .iter()
+        .filter(|(prefix, _, _)| name.starts_with(prefix))
+        .max_by_key(|(prefix, _, _)| prefix.len())
+        .map(|&(_, context_window, max_output_tokens)| ModelLimits {
+            context_window: Some(context_window),
+            max_output_tokens: Some(max_output_tokens),
+        })
+}
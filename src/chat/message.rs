@@ -1,9 +1,47 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use error_stack::ResultExt;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use thiserror::Error;
 use tracing::info;
 
+use crate::chat::attachment::Attachment;
+use crate::chat::finetune::{FinetuneBranch, FinetuneOptions};
+use crate::chat::files::{UploadedFile, FILE_PARTS_KEY};
+use crate::chat::chat_base::{BaseChat, ChatError};
+use crate::chat::pruning::{now_ms, PruneState, PruningPolicy};
+use crate::config::AuxiliaryTask;
+
+/// 标题在会话元数据中的键
+/// Key of the title in session metadata
+pub const TITLE_METADATA_KEY: &str = "title";
+
+/// 摘要在会话元数据中的键
+/// Key of the summary in session metadata
+pub const SUMMARY_METADATA_KEY: &str = "summary";
+
+/// 重放得到的回答在消息元数据中记录所用模型的键
+/// Key under which replayed answers record their model in message metadata
+pub const MODEL_METADATA_KEY: &str = "model";
+
+/// 角色输出中只给特定角色看的片段：`<secret>…</secret>` 只有发言者自己能看到，
+/// `<secret to="bob,carol">…</secret>` 另外对列出的角色可见
+/// Spans of character output meant for particular characters only: `<secret>…</secret>` is seen by the speaker alone,
+/// and `<secret to="bob,carol">…</secret>` is also visible to the listed characters
+static SECRET_SPAN: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?s)<secret(?:\s+to\s*=\s*"([^"]*)")?\s*>.*?</secret>"#).unwrap());
+
+const TITLE_PROMPT: &str = "为下面的对话起一个不超过15个字的标题，只输出标题本身";
+
+const ATTACHMENT_PROMPT: &str = "概括下面的文档，保留关键事实、数据与结论，只输出摘要本身";
+
+const SUMMARY_PROMPT: &str = "用一小段话概括下面对话的主要内容和结论，只输出摘要本身";
+
 #[derive(Debug, Error)]
 pub enum MessageError {
     #[error("Invalid path")]
@@ -14,6 +52,21 @@ pub enum MessageError {
 
     #[error("Unsupported operation: {0}")]
     UnsupportedOperation(String),
+
+    #[error("Conversation tree already exists: {0}")]
+    DuplicateTree(String),
+
+    #[error("Undefined conversation tree: {0}")]
+    UndefinedTree(String),
+
+    #[error("Failed to archive pruned branch: {0}")]
+    ArchiveError(String),
+
+    #[error("Failed to load attachment: {0}")]
+    AttachmentError(String),
+
+    #[error("Failed to export fine-tuning data: {0}")]
+    ExportError(String),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -22,6 +75,15 @@ pub enum Role {
     System,
     User,
     Assistant,
+
+    /// 工具调用结果，对应 OpenAI 的 `tool` 角色
+    /// Tool call result, maps to the OpenAI `tool` role
+    Tool { call_id: String },
+
+    /// 旧版函数调用结果，对应 OpenAI 的 `function` 角色
+    /// Legacy function call result, maps to the OpenAI `function` role
+    Function { name: String },
+
     #[serde(untagged)]
     Character(String),
 }
@@ -43,17 +105,77 @@ impl Display for Role {
             Self::System => "system".to_string(),
             Self::User => "user".to_string(),
             Self::Assistant => "assistant".to_string(),
+            Self::Tool { .. } => "tool".to_string(),
+            Self::Function { .. } => "function".to_string(),
             Self::Character(name) => name.clone(),
         };
         write!(f, "{}", str)
     }
 }
 
+/// 去掉 `viewer` 无权看到的 `<secret>` 片段，`viewer` 为 `None` 时去掉全部片段
+/// Remove the `<secret>` spans `viewer` may not see; every span is removed when `viewer` is `None`
+///
+/// 宿主向玩家展示对话时也可以用它，例如主持人的回答只把对该玩家公开的部分给他看
+/// Hosts can use it when showing the conversation to players too, e.g. so a player only sees the parts of the game
+/// master's answer addressed to them
+pub fn strip_secrets(content: &str, viewer: Option<&str>) -> String {
+    SECRET_SPAN
+        .replace_all(content, |caps: &regex::Captures| {
+            let allowed = viewer.zip(caps.get(1)).is_some_and(|(viewer, to)| {
+                to.as_str().split(',').any(|name| name.trim() == viewer)
+            });
+            if allowed { caps[0].to_string() } else { String::new() }
+        })
+        .into_owned()
+}
+
+/// 多角色对话中非发言角色消息的呈现方式
+/// How messages of non-speaking characters are rendered in multi-character chats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TranscriptStyle {
+    /// `alice said: …`
+    #[default]
+    NamePrefix,
+
+    /// `<speaker name="alice">…</speaker>`
+    XmlTags,
+
+    /// OpenAI 的 `name` 字段，角色名需符合 `^[a-zA-Z0-9_-]+$`
+    /// OpenAI `name` field; character names must match `^[a-zA-Z0-9_-]+$`
+    NameField,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Messages {
     pub role: Role,
     pub content: String,
-    pub child: Vec<Messages>,
+
+    /// 子消息以 `Arc` 共享：克隆会话时不复制未改动的子树，修改时只复制路径上仍被共享的节点
+    /// Child messages are shared through `Arc`: cloning a session never copies untouched subtrees, and a
+    /// modification copies only the still-shared nodes along its path
+    pub child: Vec<Arc<Messages>>,
+
+    /// 消息级附加数据（如回答计时）
+    /// Per-message extra data (e.g. answer timing)
+    #[serde(default)]
+    pub metadata: HashMap<String, serde_json::Value>,
+
+    /// 仅对该角色可见的私有消息，其他角色发言时不会进入上下文
+    /// Private to this character; left out of the context when another character speaks
+    #[serde(default)]
+    pub private_to: Option<String>,
+
+    /// 创建时间（毫秒时间戳），旧数据为 0
+    /// Creation time (unix millis); 0 for older data
+    #[serde(default)]
+    pub created_ms: u64,
+
+    /// 附件，构建请求时提取正文并附在消息内容之后
+    /// Attachments whose text is extracted when the request is built and appended to the content
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
 }
 
 impl Messages {
@@ -62,9 +184,65 @@ impl Messages {
             role,
             content,
             child: Vec::new(),
+            metadata: HashMap::new(),
+            private_to: None,
+            created_ms: now_ms(),
+            attachments: Vec::new(),
+        }
+    }
+
+    /// 消息对当前发言者是否可见
+    /// Whether the message is visible to the current speaker
+    pub fn is_visible_to(&self, current_speaker: &Role) -> bool {
+        match &self.private_to {
+            Some(owner) => matches!(current_speaker, Role::Character(c) if c == owner),
+            None => true,
         }
     }
 
+    /// 转为 API 格式，并把附件正文附在内容之后
+    /// Convert to API format with the attachment text appended to the content
+    pub(crate) fn to_api_format_with_attachments(
+        &self,
+        current_speaker: &Role,
+        style: TranscriptStyle,
+    ) -> Result<HashMap<String, String>, MessageError> {
+        let mut message = self.to_api_format_with_style(current_speaker, style);
+        let file_parts: Vec<_> = self
+            .attachments
+            .iter()
+            .filter_map(|attachment| attachment.uploaded.as_ref().map(UploadedFile::content_part))
+            .collect();
+        if !file_parts.is_empty() {
+            message.insert(FILE_PARTS_KEY.to_string(), serde_json::Value::from(file_parts).to_string());
+        }
+        if let Some(content) = message.get_mut("content") {
+            for attachment in self.attachments.iter().filter(|a| a.uploaded.is_none()) {
+                let rendered = attachment
+                    .render()
+                    .map_err(|e| MessageError::AttachmentError(e.to_string()))?;
+                content.push_str("\n\n");
+                content.push_str(&rendered);
+            }
+        }
+        Ok(message)
+    }
+
+    /// 子树中的消息数
+    /// Number of messages in the subtree
+    pub fn node_count(&self) -> usize {
+        1 + self.child.iter().map(|child| child.node_count()).sum::<usize>()
+    }
+
+    /// 子树中最新消息的创建时间
+    /// Creation time of the newest message in the subtree
+    pub fn newest_ms(&self) -> u64 {
+        self.child
+            .iter()
+            .map(|child| child.newest_ms())
+            .fold(self.created_ms, u64::max)
+    }
+
     pub fn get_node_by_path(&mut self, path: &[usize]) -> Result<&mut Messages, MessageError> {
         if path.is_empty() {
             return Ok(self);
@@ -74,7 +252,7 @@ impl Messages {
             return Err(MessageError::InvalidPath);
         }
 
-        self.child[path[0]].get_node_by_path(&path[1..])
+        Arc::make_mut(&mut self.child[path[0]]).get_node_by_path(&path[1..])
     }
 
     pub fn add_with_parent_path(
@@ -85,19 +263,29 @@ impl Messages {
     ) -> Result<Vec<usize>, MessageError> {
         let parent = self.get_node_by_path(parent_path)?;
         let new_message = Self::new(role, content);
-        parent.child.push(new_message);
+        parent.child.push(Arc::new(new_message));
         let mut new_default_path = parent_path.to_vec();
         new_default_path.push(parent.child.len() - 1);
         Ok(new_default_path)
     }
 
     pub fn to_api_format(&self, current_speaker: &Role) -> HashMap<String, String> {
+        self.to_api_format_with_style(current_speaker, TranscriptStyle::NamePrefix)
+    }
+
+    pub fn to_api_format_with_style(
+        &self,
+        current_speaker: &Role,
+        style: TranscriptStyle,
+    ) -> HashMap<String, String> {
         // 根据角色和当前发言者确定 API 格式
         // Determine API format based on role and current speaker
         let (role_str, content) = match &self.role {
             Role::System => ("system", self.content.clone()),
             Role::User => ("user", self.content.clone()),
             Role::Assistant => ("assistant", self.content.clone()),
+            Role::Tool { .. } => ("tool", self.content.clone()),
+            Role::Function { .. } => ("function", self.content.clone()),
             Role::Character(c) => {
                 // 判断是否是当前发言者
                 // Check if it's the current speaker
@@ -106,27 +294,116 @@ impl Messages {
                     // Is the speaker: output as assistant
                     ("assistant", self.content.clone())
                 } else {
-                    // 非发言者：添加前缀并作为 user 输出
-                    // Not the speaker: add prefix and output as user
-                    let prefixed_content = format!("{} said: {}", c, self.content);
-                    ("user", prefixed_content)
+                    // 非发言者：去掉对当前发言者保密的片段，按呈现方式标明说话人并作为 user 输出
+                    // Not the speaker: drop spans kept secret from the current speaker, mark the speaker according to
+                    // the style and output as user
+                    let viewer = match current_speaker {
+                        Role::Character(name) => Some(name.as_str()),
+                        _ => None,
+                    };
+                    let visible = strip_secrets(&self.content, viewer);
+                    let content = match style {
+                        TranscriptStyle::NamePrefix => format!("{} said: {}", c, visible),
+                        TranscriptStyle::XmlTags => {
+                            format!("<speaker name=\"{}\">{}</speaker>", c, visible)
+                        }
+                        TranscriptStyle::NameField => visible,
+                    };
+                    ("user", content)
                 }
             }
         };
 
         // 创建并返回 API 格式的消息
         // Create and return message in API format
-        HashMap::from([
+        let mut message = HashMap::from([
             ("role".to_string(), role_str.to_string()),
             ("content".to_string(), content),
-        ])
+        ]);
+        match (&self.role, style) {
+            (Role::Tool { call_id }, _) => {
+                message.insert("tool_call_id".to_string(), call_id.clone());
+            }
+            (Role::Function { name }, _) | (Role::Character(name), TranscriptStyle::NameField) => {
+                message.insert("name".to_string(), name.clone());
+            }
+            _ => {}
+        }
+        message
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Session {
-    pub message_roots: Vec<Messages>,
+    pub message_roots: Vec<Arc<Messages>>,
+    pub default_path: Vec<usize>,
+
+    /// 会话级附加状态（如智能体计划），随会话一起序列化
+    /// Session-level extra state (e.g. agent plans), serialized with the session
+    #[serde(default)]
+    pub metadata: HashMap<String, serde_json::Value>,
+
+    /// 命名的对话树，每棵树对应 `message_roots` 中的一个根
+    /// Named conversation trees, each backed by one root in `message_roots`
+    #[serde(default)]
+    pub trees: Vec<ConversationTree>,
+
+    #[serde(default)]
+    pub usage: SessionUsage,
+
+    /// 每次添加消息后执行的修剪策略；经对话写入仍被多处持有的共享会话时暂不执行
+    /// Pruning policy enforced after every added message; skipped while chats write to a shared session that is
+    /// still held elsewhere
+    #[serde(default)]
+    pub pruning: Option<PruningPolicy>,
+
+    #[serde(skip)]
+    prune_state: PruneState,
+}
+
+/// 可在多个对话间共享的会话
+/// Session that can be shared between chats
+pub type SharedSession = Arc<RwLock<Session>>;
+
+/// 命名对话树：记录根的位置、在该树内的默认路径与树级元数据
+/// Named conversation tree: where its root is, its own default path and tree-level metadata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationTree {
+    pub name: String,
+
+    /// 在 `message_roots` 中的下标
+    /// Index in `message_roots`
+    pub root: usize,
+
+    /// 切换离开时保存的默认路径
+    /// Default path saved when switching away
     pub default_path: Vec<usize>,
+
+    #[serde(default)]
+    pub metadata: HashMap<String, serde_json::Value>,
+}
+
+/// 会话内累计的令牌用量
+/// Token usage accumulated in a session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionUsage {
+    pub requests: u64,
+
+    pub prompt_tokens: u64,
+
+    pub completion_tokens: u64,
+}
+
+impl SessionUsage {
+    pub fn add(&mut self, prompt_tokens: u64, completion_tokens: u64) {
+        self.requests += 1;
+        self.prompt_tokens += prompt_tokens;
+        self.completion_tokens += completion_tokens;
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
 }
 
 impl Session {
@@ -134,18 +411,538 @@ impl Session {
         Self {
             message_roots: Vec::new(),
             default_path: Vec::new(),
+            metadata: HashMap::new(),
+            trees: Vec::new(),
+            usage: SessionUsage::default(),
+            pruning: None,
+            prune_state: PruneState::default(),
         }
     }
 
-    pub fn get_node_by_path(&mut self, path: &[usize]) -> Result<&mut Messages, MessageError> {
-        if path.is_empty() {
-            return Err(MessageError::InvalidPath);
+    pub fn set_pruning_policy(&mut self, policy: PruningPolicy) {
+        self.pruning = Some(policy);
+        self.prune_state = PruneState::default();
+    }
+
+    /// 按修剪策略完整扫描会话，归档并移除分支，返回修剪的分支数
+    /// Scan the whole session against the pruning policy, archiving and removing branches; returns the number of
+    /// pruned branches
+    ///
+    /// 添加消息时只做增量检查，直接修改 `message_roots` 后应调用本方法重新统计
+    /// Adding messages only checks incrementally; call this after editing `message_roots` directly to recount
+    pub fn prune(&mut self) -> Result<usize, MessageError> {
+        let Some(policy) = self.pruning.clone() else {
+            return Ok(0);
+        };
+
+        let mut pruned = 0;
+        while let Some(path) = self.next_prunable(&policy) {
+            self.remove_branch(&policy, &path)?;
+            pruned += 1;
         }
-        if path.len() == 1 {
-            Ok(&mut self.message_roots[path[0]])
-        } else {
-            Ok(self.message_roots[path[0]].get_node_by_path(&path[1..])?)
+
+        let branches = self.inactive_branches();
+        self.prune_state = PruneState {
+            node_count: Some(self.message_roots.iter().map(|root| root.node_count()).sum()),
+            next_expiry_ms: policy.max_age_ms.and_then(|max_age_ms| {
+                branches
+                    .iter()
+                    .map(|(_, node)| node.newest_ms())
+                    .filter(|&newest| newest > 0)
+                    .map(|newest| newest.saturating_add(max_age_ms))
+                    .min()
+            }),
+            active: self.active_paths(),
+            deferred: self.prune_state.deferred,
+        };
+        Ok(pruned)
+    }
+
+    /// 暂停或恢复添加消息时的修剪；暂停期间添加的消息只标记需要重新统计，恢复后的下一次添加会完整扫描
+    /// Pause or resume pruning on add; messages added while paused only mark the state for a recount, so the next
+    /// add after resuming scans the whole session
+    pub(crate) fn defer_pruning(&mut self, deferred: bool) {
+        self.prune_state.deferred = deferred;
+    }
+
+    /// 添加一条消息后的增量修剪：只检查自上次检查以来离开活动路径的分支，
+    /// 仅在超出消息总数上限或有分支到期时才扫描整个会话
+    /// Incremental pruning after one added message: only branches that left the active paths since the last check
+    /// are inspected, and the whole session is scanned only when the node limit is exceeded or a branch expires
+    fn prune_after_add(&mut self) -> Result<usize, MessageError> {
+        let Some(policy) = self.pruning.clone() else {
+            return Ok(0);
+        };
+        if self.prune_state.deferred {
+            self.prune_state.node_count = None;
+            return Ok(0);
+        }
+        let now = now_ms();
+        let Some(count) = self.prune_state.node_count else {
+            return self.prune();
+        };
+        if self.prune_state.next_expiry_ms.is_some_and(|expiry| now > expiry) {
+            return self.prune();
+        }
+        self.prune_state.node_count = Some(count + 1);
+
+        // 从后往前处理，移除分支只会移动已处理过的兄弟
+        // Work backwards so that removing a branch only shifts siblings already handled
+        let mut left = self.left_branches();
+        left.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut pruned = 0;
+        for branch in left {
+            if let Some(max_depth) = policy.max_depth {
+                if branch.len() > max_depth {
+                    self.remove_branch(&policy, &branch)?;
+                    pruned += 1;
+                    continue;
+                }
+                while let Some(path) = self
+                    .nodes_along_path(&branch)?
+                    .last()
+                    .and_then(|node| too_deep(node, branch.clone(), max_depth))
+                {
+                    self.remove_branch(&policy, &path)?;
+                    pruned += 1;
+                }
+            }
+
+            if let Some(max_age_ms) = policy.max_age_ms {
+                let newest = self.nodes_along_path(&branch)?.last().map_or(0, |node| node.newest_ms());
+                if newest > 0 && now.saturating_sub(newest) > max_age_ms {
+                    self.remove_branch(&policy, &branch)?;
+                    pruned += 1;
+                } else if newest > 0 {
+                    let expiry = newest.saturating_add(max_age_ms);
+                    let next = self.prune_state.next_expiry_ms.map_or(expiry, |next| next.min(expiry));
+                    self.prune_state.next_expiry_ms = Some(next);
+                }
+            }
+        }
+
+        if let Some(max_nodes) = policy.max_nodes {
+            while self.prune_state.node_count.is_some_and(|count| count > max_nodes) {
+                let Some(path) = self
+                    .inactive_branches()
+                    .into_iter()
+                    .min_by_key(|(_, node)| node.newest_ms())
+                    .map(|(path, _)| path)
+                else {
+                    break;
+                };
+                self.remove_branch(&policy, &path)?;
+                pruned += 1;
+            }
+        }
+
+        self.prune_state.active = self.active_paths();
+        Ok(pruned)
+    }
+
+    /// 会话默认路径与各命名树保存的默认路径
+    /// The session default path and the saved default path of each named tree
+    fn active_paths(&self) -> Vec<Vec<usize>> {
+        std::iter::once(self.default_path.clone())
+            .chain(self.trees.iter().map(|tree| tree.default_path.clone()))
+            .collect()
+    }
+
+    /// 上次检查时在活动路径上、如今已离开所有活动路径的最大分支
+    /// Maximal branches that were on an active path at the last check and are now off every active path
+    fn left_branches(&self) -> Vec<Vec<usize>> {
+        let active = self.active_paths();
+        let mut left: Vec<Vec<usize>> = Vec::new();
+        for previous in &self.prune_state.active {
+            let branch = (2..=previous.len())
+                .map(|len| &previous[..len])
+                .find(|prefix| !active.iter().any(|a| a.starts_with(prefix)));
+            if let Some(branch) = branch
+                && self.nodes_along_path(branch).is_ok()
+                && !left.iter().any(|l| branch.starts_with(l))
+            {
+                left.retain(|l| !l.starts_with(branch));
+                left.push(branch.to_vec());
+            }
+        }
+        left
+    }
+
+    /// 不在任何活动路径上的最大分支
+    /// Maximal branches off every active path
+    fn inactive_branches(&self) -> Vec<(Vec<usize>, &Messages)> {
+        let active = self.active_paths();
+        let mut branches = Vec::new();
+        let mut stack: Vec<(Vec<usize>, &Messages)> = self
+            .message_roots
+            .iter()
+            .enumerate()
+            .map(|(i, root)| (vec![i], root.as_ref()))
+            .collect();
+        while let Some((path, node)) = stack.pop() {
+            for (i, child) in node.child.iter().enumerate() {
+                let mut child_path = path.clone();
+                child_path.push(i);
+                if active.iter().any(|a| a.starts_with(&child_path)) {
+                    stack.push((child_path, child.as_ref()));
+                } else {
+                    branches.push((child_path, child.as_ref()));
+                }
+            }
+        }
+        branches
+    }
+
+    /// 找出下一个应被修剪的分支
+    /// Find the next branch to prune
+    fn next_prunable(&self, policy: &PruningPolicy) -> Option<Vec<usize>> {
+        let branches = self.inactive_branches();
+
+        if let Some(max_depth) = policy.max_depth
+            && let Some(path) = branches
+                .iter()
+                .find_map(|(path, node)| too_deep(node, path.clone(), max_depth))
+        {
+            return Some(path);
+        }
+
+        if let Some(max_age_ms) = policy.max_age_ms {
+            let now = now_ms();
+            let expired = branches.iter().find(|(_, node)| {
+                let newest = node.newest_ms();
+                newest > 0 && now.saturating_sub(newest) > max_age_ms
+            });
+            if let Some((path, _)) = expired {
+                return Some(path.clone());
+            }
+        }
+
+        if let Some(max_nodes) = policy.max_nodes {
+            let total: usize = self.message_roots.iter().map(|root| root.node_count()).sum();
+            if total > max_nodes {
+                return branches
+                    .iter()
+                    .min_by_key(|(_, node)| node.newest_ms())
+                    .map(|(path, _)| path.clone());
+            }
+        }
+
+        None
+    }
+
+    /// 归档并移除一个分支，同时更新消息计数与活动路径
+    /// Archive and remove one branch, updating the message count and the active paths
+    fn remove_branch(&mut self, policy: &PruningPolicy, path: &[usize]) -> Result<(), MessageError> {
+        let (parent_path, index) = path.split_at(path.len() - 1);
+        let parent = self.get_node_by_path(parent_path)?;
+        let removed = parent.child.get(index[0]).ok_or(MessageError::InvalidPath)?;
+        policy.archive(parent_path, removed)?;
+        let removed = removed.node_count();
+        parent.child.remove(index[0]);
+        self.shift_active_paths(parent_path, index[0]);
+        if let Some(count) = self.prune_state.node_count.as_mut() {
+            *count = count.saturating_sub(removed);
+        }
+        Ok(())
+    }
+
+    /// 移除 `parent` 下第 `removed` 个子消息后，修正各活动路径中排在其后的兄弟下标
+    /// After removing child `removed` of `parent`, fix the sibling indices after it in every active path
+    fn shift_active_paths(&mut self, parent: &[usize], removed: usize) {
+        let shift = |path: &mut Vec<usize>| {
+            if path.len() > parent.len() && path.starts_with(parent) && path[parent.len()] > removed {
+                path[parent.len()] -= 1;
+            }
+        };
+        shift(&mut self.default_path);
+        self.trees.iter_mut().for_each(|tree| shift(&mut tree.default_path));
+    }
+
+    pub fn add_attachment(&mut self, path: &[usize], attachment: Attachment) -> Result<(), MessageError> {
+        self.get_node_by_path(path)?.attachments.push(attachment);
+        Ok(())
+    }
+
+    /// 使用 `AuxiliaryTask::Summarize` 为默认路径上过长的附件生成摘要
+    /// Summarize over-long attachments on the default path with `AuxiliaryTask::Summarize`
+    pub async fn summarize_attachments(&mut self) -> error_stack::Result<usize, ChatError> {
+        let chat = BaseChat::new_with_auxiliary_task(AuxiliaryTask::Summarize, ATTACHMENT_PROMPT, false);
+        self.summarize_attachments_with(chat).await
+    }
+
+    /// 使用指定的对话为默认路径上过长的附件生成摘要，返回新生成的摘要数
+    /// Summarize over-long attachments on the default path with the given chat; returns the number of new summaries
+    pub async fn summarize_attachments_with(
+        &mut self,
+        chat: BaseChat,
+    ) -> error_stack::Result<usize, ChatError> {
+        let mut pending = Vec::new();
+        for depth in 1..=self.default_path.len() {
+            let path = self.default_path[..depth].to_vec();
+            let node = self.get_node_by_path(&path).change_context(ChatError::SessionError)?;
+            for (i, attachment) in node.attachments.iter().enumerate() {
+                if attachment.summary.is_some() || attachment.uploaded.is_some() {
+                    continue;
+                }
+                let text = attachment
+                    .load()
+                    .change_context(ChatError::SessionError)
+                    .attach_printable_lazy(|| format!("Attachment: {}", attachment.name))?;
+                if attachment.is_too_long(&text) {
+                    pending.push((path.clone(), i, text));
+                }
+            }
+        }
+
+        let summarized = pending.len();
+        for (path, i, text) in pending {
+            let mut chat = chat.clone();
+            chat.session = Session::new();
+            chat.add_message(Role::System, ATTACHMENT_PROMPT)?;
+            chat.add_message(Role::User, &text)?;
+            let request_body =
+                chat.build_request_body(&chat.session.default_path.clone(), &Role::User).await?;
+            let response = chat.get_response(request_body).await?;
+            let summary = BaseChat::get_content_from_resp(&response)?.trim().to_string();
+
+            let node = self.get_node_by_path(&path).change_context(ChatError::SessionError)?;
+            node.attachments[i].summary = Some(summary);
+        }
+        Ok(summarized)
+    }
+
+    pub fn into_shared(self) -> SharedSession {
+        Arc::new(RwLock::new(self))
+    }
+
+    /// 在路径处分叉出独立的会话副本，副本的默认路径指向该处
+    /// Fork an independent copy of the session whose default path points at the given path
+    ///
+    /// 副本与原会话共享全部子树，之后任一方修改时只复制路径上仍被共享的节点，
+    /// 因此分叉的代价与历史长度无关，适合投机分支与 A/B 重新生成
+    /// The copy shares every subtree with the original and a later modification on either side copies only the
+    /// still-shared nodes along its path, so forking costs the same however long the history is; suited to
+    /// speculative branches and A/B regeneration
+    pub fn fork_at(&self, path: &[usize]) -> Result<Session, MessageError> {
+        self.walk_path(path, |_| Ok(()))?;
+        let mut fork = self.clone();
+        fork.default_path = path.to_vec();
+        Ok(fork)
+    }
+
+    /// 以一条消息为根新建命名对话树，并把默认路径切换到该树
+    /// Start a named conversation tree rooted at one message and switch the default path to it
+    pub fn new_tree(&mut self, name: &str, role: Role, content: String) -> Result<(), MessageError> {
+        if self.tree(name).is_some() {
+            return Err(MessageError::DuplicateTree(name.to_string()));
+        }
+
+        self.save_current_tree();
+        self.add_with_parent_path(&[], role, content)?;
+        self.trees.push(ConversationTree {
+            name: name.to_string(),
+            root: self.message_roots.len() - 1,
+            default_path: self.default_path.clone(),
+            metadata: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    /// 切换到命名对话树，恢复它上次的默认路径
+    /// Switch to a named conversation tree, restoring its last default path
+    pub fn switch_tree(&mut self, name: &str) -> Result<(), MessageError> {
+        let path = self
+            .tree(name)
+            .map(|tree| tree.default_path.clone())
+            .ok_or_else(|| MessageError::UndefinedTree(name.to_string()))?;
+
+        self.save_current_tree();
+        self.default_path = path;
+        Ok(())
+    }
+
+    pub fn tree(&self, name: &str) -> Option<&ConversationTree> {
+        self.trees.iter().find(|tree| tree.name == name)
+    }
+
+    /// 默认路径所在的命名对话树
+    /// Named tree the default path is in
+    pub fn current_tree(&self) -> Option<&ConversationTree> {
+        let root = *self.default_path.first()?;
+        self.trees.iter().find(|tree| tree.root == root)
+    }
+
+    pub fn set_tree_metadata(
+        &mut self,
+        name: &str,
+        key: &str,
+        value: serde_json::Value,
+    ) -> Result<(), MessageError> {
+        self.trees
+            .iter_mut()
+            .find(|tree| tree.name == name)
+            .ok_or_else(|| MessageError::UndefinedTree(name.to_string()))?
+            .metadata
+            .insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn save_current_tree(&mut self) {
+        let Some(&root) = self.default_path.first() else {
+            return;
+        };
+        if let Some(tree) = self.trees.iter_mut().find(|tree| tree.root == root) {
+            tree.default_path = self.default_path.clone();
+        }
+    }
+
+    pub fn set_metadata(&mut self, key: &str, value: serde_json::Value) {
+        self.metadata.insert(key.to_string(), value);
+    }
+
+    pub fn get_metadata(&self, key: &str) -> Option<&serde_json::Value> {
+        self.metadata.get(key)
+    }
+
+    /// 使用 `AuxiliaryTask::Summarize`（默认 Cheap 档位）为当前分支生成标题并存入元数据
+    /// Generate a title for the current branch with `AuxiliaryTask::Summarize` (Cheap tier by default) and store it in metadata
+    pub async fn generate_title(&mut self) -> error_stack::Result<String, ChatError> {
+        let chat = BaseChat::new_with_auxiliary_task(AuxiliaryTask::Summarize, TITLE_PROMPT, false);
+        self.generate_title_with(chat).await
+    }
+
+    /// 使用 `AuxiliaryTask::Summarize`（默认 Cheap 档位）为当前分支生成摘要并存入元数据
+    /// Generate a summary of the current branch with `AuxiliaryTask::Summarize` (Cheap tier by default) and store it in metadata
+    pub async fn generate_summary(&mut self) -> error_stack::Result<String, ChatError> {
+        let chat = BaseChat::new_with_auxiliary_task(AuxiliaryTask::Summarize, SUMMARY_PROMPT, false);
+        self.generate_summary_with(chat).await
+    }
+
+    /// 使用指定的对话生成标题
+    /// Generate a title with the given chat
+    pub async fn generate_title_with(&mut self, chat: BaseChat) -> error_stack::Result<String, ChatError> {
+        self.describe_branch(chat, TITLE_PROMPT, TITLE_METADATA_KEY).await
+    }
+
+    /// 使用指定的对话生成摘要
+    /// Generate a summary with the given chat
+    pub async fn generate_summary_with(&mut self, chat: BaseChat) -> error_stack::Result<String, ChatError> {
+        self.describe_branch(chat, SUMMARY_PROMPT, SUMMARY_METADATA_KEY).await
+    }
+
+    async fn describe_branch(
+        &mut self,
+        mut chat: BaseChat,
+        instruction: &str,
+        key: &str,
+    ) -> error_stack::Result<String, ChatError> {
+        if self.default_path.is_empty() {
+            return Err(error_stack::Report::new(ChatError::SessionError))
+                .attach_printable("Cannot describe an empty session");
+        }
+
+        let transcript = self
+            .assemble_context(&self.default_path.clone(), &Role::Assistant)
+            .change_context(ChatError::SessionError)?
+            .iter()
+            .map(|message| format!("{}: {}", message["role"], message["content"]))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        chat.add_message(Role::System, instruction)?;
+        chat.add_message(Role::User, &transcript)?;
+        let request_body =
+            chat.build_request_body(&chat.session.default_path.clone(), &Role::User).await?;
+        let response = chat.get_response(request_body).await?;
+        let text = BaseChat::get_content_from_resp(&response)?.trim().to_string();
+
+        self.set_metadata(key, serde_json::Value::String(text.clone()));
+        Ok(text)
+    }
+
+    /// 用另一个API的模型重放默认路径：依次重发其中的用户轮次，把新模型的回答存为与原回答并列的分支
+    /// Replay the default path on another API's model: its user turns are sent again one by one and the new model's
+    /// answers are stored as a branch beside the original answers
+    ///
+    /// 新分支从第一个助手回答处分出，其余消息按原样复制；开头的助手消息没有可供重新生成的上下文，作为共同前缀保留。
+    /// 默认路径保持不变，返回新分支的终点路径
+    /// The new branch forks at the first assistant answer and the other messages are copied as they are; a leading
+    /// assistant message has no context to regenerate from and is kept as part of the shared prefix. The default path
+    /// is kept and the end path of the new branch is returned
+    ///
+    /// # 参数 (Parameters)
+    /// * `api_name` - 用于重放的API名称
+    ///   - Name of the API to replay on
+    pub async fn replay_on(&mut self, api_name: &str) -> error_stack::Result<Vec<usize>, ChatError> {
+        let chat = BaseChat::new_with_api_name(api_name, "", false);
+        self.replay_on_with(chat).await
+    }
+
+    /// 使用指定的对话重放默认路径，见 [`Session::replay_on`]；中途出错时会话保持不变
+    /// Replay the default path with the given chat, see [`Session::replay_on`]; the session is left unchanged when
+    /// it fails midway
+    pub async fn replay_on_with(&mut self, mut chat: BaseChat) -> error_stack::Result<Vec<usize>, ChatError> {
+        let original = self.default_path.clone();
+        // 只取消息本身，不带子树
+        // Take the messages themselves without their subtrees
+        let nodes = self
+            .nodes_along_path(&original)
+            .change_context(ChatError::SessionError)?
+            .into_iter()
+            .map(|node| Messages {
+                role: node.role.clone(),
+                content: node.content.clone(),
+                child: Vec::new(),
+                metadata: node.metadata.clone(),
+                private_to: node.private_to.clone(),
+                created_ms: node.created_ms,
+                attachments: node.attachments.clone(),
+            })
+            .collect::<Vec<_>>();
+        let Some(fork) = nodes
+            .iter()
+            .skip(1)
+            .position(|node| node.role == Role::Assistant)
+            .map(|i| i + 1)
+        else {
+            return Err(error_stack::Report::new(ChatError::SessionError))
+                .attach_printable("No assistant answer to replay on the default path");
+        };
+
+        chat.session = self.fork_at(&original).change_context(ChatError::SessionError)?;
+        let model = serde_json::Value::String(chat.model.clone());
+        let mut path = original[..fork].to_vec();
+        for node in &nodes[fork..] {
+            if node.role == Role::Assistant {
+                let request_body = chat.build_request_body(&path, &Role::Assistant).await?;
+                let answer = chat.fetch_answer(request_body, false).await?;
+                chat.add_answer_with_parent_path(&path, Role::Assistant, &answer)?;
+                path = chat.session.default_path.clone();
+                chat.session
+                    .set_message_metadata(&path, MODEL_METADATA_KEY, model.clone())
+                    .change_context(ChatError::SessionError)?;
+            } else {
+                chat.session
+                    .add_with_parent_path(&path, node.role.clone(), node.content.clone())
+                    .change_context(ChatError::SessionError)?;
+                path = chat.session.default_path.clone();
+                let copy = chat.session.get_node_by_path(&path).change_context(ChatError::SessionError)?;
+                copy.metadata = node.metadata.clone();
+                copy.private_to = node.private_to.clone();
+                copy.attachments = node.attachments.clone();
+            }
         }
+        info!("Replayed the default path on {}", chat.model);
+
+        chat.session.default_path = original;
+        *self = chat.session;
+        Ok(path)
+    }
+
+    pub fn get_node_by_path(&mut self, path: &[usize]) -> Result<&mut Messages, MessageError> {
+        let (&root, rest) = path.split_first().ok_or(MessageError::InvalidPath)?;
+        Arc::make_mut(self.message_roots.get_mut(root).ok_or(MessageError::InvalidPath)?).get_node_by_path(rest)
     }
 
     pub fn add_with_parent_path(
@@ -155,13 +952,34 @@ impl Session {
         content: String,
     ) -> Result<(), MessageError> {
         if path.is_empty() {
-            self.message_roots.push(Messages::new(role, content));
+            self.message_roots.push(Arc::new(Messages::new(role, content)));
             self.default_path = vec![self.message_roots.len() - 1];
         } else {
+            let root = Arc::make_mut(self.message_roots.get_mut(path[0]).ok_or(MessageError::InvalidPath)?);
             let mut new_default_path = vec![path[0]];
-            new_default_path.append(&mut self.message_roots[path[0]].add_with_parent_path(&path[1..], role, content)?);
+            new_default_path.append(&mut root.add_with_parent_path(&path[1..], role, content)?);
             self.default_path = new_default_path;
         }
+        self.prune_after_add()?;
+        Ok(())
+    }
+
+    pub fn set_message_metadata(
+        &mut self,
+        path: &[usize],
+        key: &str,
+        value: serde_json::Value,
+    ) -> Result<(), MessageError> {
+        self.get_node_by_path(path)?
+            .metadata
+            .insert(key.to_string(), value);
+        Ok(())
+    }
+
+    /// 将消息设为某个角色私有，`None` 取消私有
+    /// Make a message private to a character; `None` makes it public again
+    pub fn set_private(&mut self, path: &[usize], character: Option<&str>) -> Result<(), MessageError> {
+        self.get_node_by_path(path)?.private_to = character.map(str::to_string);
         Ok(())
     }
 
@@ -173,21 +991,233 @@ impl Session {
         self.add_with_parent_path(&self.default_path.clone(), role, content)
     }
 
+    /// 从根到终点依次取出路径上的消息，路径为空或越界时报错
+    /// Collect the messages along a path from the root to its end; errors on an empty or out-of-range path
+    pub fn nodes_along_path(&self, path: &[usize]) -> Result<Vec<&Messages>, MessageError> {
+        let mut nodes = Vec::with_capacity(path.len());
+        self.walk_path(path, |node| {
+            nodes.push(node);
+            Ok(())
+        })?;
+        Ok(nodes)
+    }
+
+    /// 从根到终点依次以引用访问路径上的消息，路径为空或越界时报错
+    /// Visit the messages along a path by reference from the root to its end; errors on an empty or out-of-range path
+    fn walk_path<'a>(
+        &'a self,
+        path: &[usize],
+        mut visit: impl FnMut(&'a Messages) -> Result<(), MessageError>,
+    ) -> Result<(), MessageError> {
+        let (&root, rest) = path.split_first().ok_or(MessageError::InvalidPath)?;
+        let mut node = self.message_roots.get(root).ok_or(MessageError::InvalidPath)?;
+        visit(node)?;
+        for &idx in rest {
+            node = node.child.get(idx).ok_or(MessageError::InvalidPath)?;
+            visit(node)?;
+        }
+        Ok(())
+    }
+
+    /// 会话级系统消息：首个根为系统消息时即为该消息
+    /// Session-wide system message: the first root when it is a system message
+    pub fn root_system_message(&self) -> Option<&Messages> {
+        self.message_roots.first().map(Arc::as_ref).filter(|root| root.role == Role::System)
+    }
+
     pub fn assemble_context(
-        &mut self,
+        &self,
+        end_path: &[usize],
+        current_speaker: &Role,
+    ) -> Result<Vec<HashMap<String, String>>, MessageError> {
+        self.assemble_context_with_style(end_path, current_speaker, TranscriptStyle::NamePrefix)
+    }
+
+    /// 按根 → 祖先 → 终点的顺序组装上下文
+    /// Assemble the context in root → ancestors → end order
+    ///
+    /// 路径所在的树不以系统消息开头时，会话级系统消息放在最前；
+    /// 系统消息只出现一次，对当前发言者不可见的私有消息会被跳过
+    /// When the tree of the path does not start with a system message, the session-wide system message leads;
+    /// the system message appears exactly once and messages private to others are skipped
+    pub fn assemble_context_with_style(
+        &self,
         end_path: &[usize],
         current_speaker: &Role,
+        style: TranscriptStyle,
     ) -> Result<Vec<HashMap<String, String>>, MessageError> {
-        let mut node = self.get_node_by_path([end_path[0]].as_ref())?;
-        let mut messages_vec = vec![node.to_api_format(current_speaker)];
-        info!("node: {:?}", node);
+        let first = end_path
+            .first()
+            .and_then(|&root| self.message_roots.get(root))
+            .ok_or(MessageError::InvalidPath)?;
+        let inherited_system = self
+            .root_system_message()
+            .filter(|_| first.role != Role::System);
 
-        // 将for_each改为传统for循环
-        for &idx in end_path[1..].iter() {
-            node = &mut node.child[idx];
-            messages_vec.push(node.to_api_format(current_speaker));
+        // 沿路径边走边转换，不复制节点及其子树
+        // Convert while walking the path without copying nodes or their subtrees
+        let mut messages_vec = Vec::with_capacity(end_path.len() + 1);
+        let mut push = |node: &Messages| {
+            if node.is_visible_to(current_speaker) {
+                messages_vec.push(node.to_api_format_with_attachments(current_speaker, style)?);
+            }
+            Ok(())
+        };
+        if let Some(system) = inherited_system {
+            push(system)?;
         }
+        self.walk_path(end_path, push)?;
+        info!("context: {} messages for path {:?}", messages_vec.len(), end_path);
 
         Ok(messages_vec)
     }
+
+    /// 以默认选项将分支导出为 OpenAI 微调 JSONL，返回写入的记录数
+    /// Export branches as OpenAI fine-tuning JSONL with the default options; returns the number of records written
+    pub fn export_finetune_jsonl(
+        &self,
+        path: impl AsRef<Path>,
+        branch: &FinetuneBranch,
+    ) -> Result<usize, MessageError> {
+        self.export_finetune_jsonl_with(path, branch, &FinetuneOptions::default())
+    }
+
+    /// 按选项将分支导出为 OpenAI 微调 JSONL，已存在的文件会被覆盖
+    /// Export branches as OpenAI fine-tuning JSONL with the given options; an existing file is overwritten
+    pub fn export_finetune_jsonl_with(
+        &self,
+        path: impl AsRef<Path>,
+        branch: &FinetuneBranch,
+        options: &FinetuneOptions,
+    ) -> Result<usize, MessageError> {
+        let path = path.as_ref();
+        let export_error = |e: std::io::Error| MessageError::ExportError(e.to_string());
+
+        let records = self.finetune_records(branch, options)?;
+        let mut jsonl = String::new();
+        for record in &records {
+            jsonl.push_str(&record.to_string());
+            jsonl.push('\n');
+        }
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(export_error)?;
+        }
+        fs::write(path, jsonl).map_err(export_error)?;
+        info!("finetune: {} records exported to {}", records.len(), path.display());
+
+        Ok(records.len())
+    }
+
+    /// 将分支转为 `{"messages": [...]}` 微调记录，空记录与回答过少的记录会被跳过
+    /// Convert branches into `{"messages": [...]}` fine-tuning records; empty records and those with too few answers are skipped
+    pub fn finetune_records(
+        &self,
+        branch: &FinetuneBranch,
+        options: &FinetuneOptions,
+    ) -> Result<Vec<serde_json::Value>, MessageError> {
+        let paths = match branch {
+            FinetuneBranch::Default => vec![self.default_path.clone()],
+            FinetuneBranch::Path(path) => vec![path.clone()],
+            FinetuneBranch::Tree(name) => {
+                let tree = self.tree(name).ok_or_else(|| MessageError::UndefinedTree(name.clone()))?;
+                let root = self.message_roots.get(tree.root).ok_or(MessageError::InvalidPath)?;
+                leaf_paths(root, vec![tree.root])
+            }
+            FinetuneBranch::All => self
+                .message_roots
+                .iter()
+                .enumerate()
+                .flat_map(|(i, root)| leaf_paths(root, vec![i]))
+                .collect(),
+        };
+
+        paths
+            .iter()
+            .filter_map(|path| self.finetune_record(path, options).transpose())
+            .collect()
+    }
+
+    fn finetune_record(
+        &self,
+        path: &[usize],
+        options: &FinetuneOptions,
+    ) -> Result<Option<serde_json::Value>, MessageError> {
+        let nodes = self.nodes_along_path(path)?;
+        let inherited_system = self
+            .root_system_message()
+            .filter(|_| nodes[0].role != Role::System);
+
+        let mut messages = Vec::with_capacity(nodes.len() + 1);
+        for node in inherited_system.into_iter().chain(nodes) {
+            let kept = match node.role {
+                Role::System => options.include_system,
+                Role::Tool { .. } | Role::Function { .. } => options.include_tool_results,
+                _ => true,
+            };
+            if !kept || !(options.include_private || node.is_visible_to(&options.speaker)) {
+                continue;
+            }
+            let mut message = node.to_api_format_with_attachments(&options.speaker, options.style)?;
+            // 已上传文件的引用在微调数据中无效
+            // References to uploaded files are meaningless in fine-tuning data
+            message.remove(FILE_PARTS_KEY);
+            messages.push(message);
+        }
+
+        let is_answer = |message: &HashMap<String, String>| message["role"] == "assistant";
+        if options.trim_trailing {
+            let end = messages.iter().rposition(is_answer).map_or(0, |last| last + 1);
+            messages.truncate(end);
+        }
+        if messages.is_empty() || messages.iter().filter(|m| is_answer(m)).count() < options.min_assistant_messages {
+            return Ok(None);
+        }
+
+        let messages: Vec<serde_json::Value> = messages
+            .into_iter()
+            .map(|mut message| {
+                if let Some(name) = options.role_names.get(&message["role"]) {
+                    message.insert("role".to_string(), name.clone());
+                }
+                serde_json::Value::Object(
+                    message
+                        .into_iter()
+                        .map(|(key, value)| (key, serde_json::Value::String(value)))
+                        .collect(),
+                )
+            })
+            .collect();
+        Ok(Some(serde_json::json!({ "messages": messages })))
+    }
+}
+
+/// 子树中每一条从该节点到叶的路径
+/// Every path from this node down to a leaf of its subtree
+fn leaf_paths(node: &Messages, path: Vec<usize>) -> Vec<Vec<usize>> {
+    if node.child.is_empty() {
+        return vec![path];
+    }
+    node.child
+        .iter()
+        .enumerate()
+        .flat_map(|(i, child)| {
+            let mut child_path = path.clone();
+            child_path.push(i);
+            leaf_paths(child, child_path)
+        })
+        .collect()
+}
+
+/// 分支中第一个超过最大深度的消息路径
+/// Path of the first message in the branch deeper than the maximum depth
+fn too_deep(node: &Messages, path: Vec<usize>, max_depth: usize) -> Option<Vec<usize>> {
+    if path.len() > max_depth {
+        return Some(path);
+    }
+    node.child.iter().enumerate().find_map(|(i, child)| {
+        let mut child_path = path.clone();
+        child_path.push(i);
+        too_deep(child, child_path, max_depth)
+    })
 }
@@ -0,0 +1,209 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use serde_json::json;
+
+use crate::chat::transport::ByteStream;
+
+/// 模拟响应中的 `system_fingerprint`
+/// `system_fingerprint` of mock responses
+pub const MOCK_FINGERPRINT: &str = "fp_mock";
+
+/// 模拟响应
+/// Mock reply
+#[derive(Clone, Debug)]
+pub enum MockReply {
+    /// 以助手文本作答
+    /// Answer with assistant text
+    Text(String),
+
+    /// 直接返回完整的响应体（仅非流式）
+    /// Return a complete response body as-is (non-streaming only)
+    Raw(serde_json::Value),
+
+    /// 返回HTTP错误状态码
+    /// Fail with an HTTP status code
+    HttpError(u16),
+
+    /// 返回 429 并附带 `Retry-After`
+    /// Fail with 429 and a `Retry-After` delay
+    RateLimited(Duration),
+
+    /// 模拟超时
+    /// Simulate a timeout
+    Timeout,
+}
+
+impl From<&str> for MockReply {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl From<String> for MockReply {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+/// 根据请求体生成响应的函数
+/// Function producing a reply from the request body
+pub type MockResponder = Arc<dyn Fn(&serde_json::Value) -> MockReply + Send + Sync>;
+
+/// 模拟API提供方，用于无网络环境下测试
+/// Mock API provider for testing without network
+#[derive(Clone)]
+pub struct MockApi {
+    responder: MockResponder,
+
+    latency: Option<Duration>,
+
+    chunk_chars: usize,
+
+    fail_first: usize,
+
+    fail_status: u16,
+
+    calls: Arc<AtomicUsize>,
+}
+
+impl std::fmt::Debug for MockApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockApi")
+            .field("latency", &self.latency)
+            .field("chunk_chars", &self.chunk_chars)
+            .field("fail_first", &self.fail_first)
+            .field("calls", &self.calls())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MockApi {
+    pub fn new(
+        responder: impl Fn(&serde_json::Value) -> MockReply + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            responder: Arc::new(responder),
+            latency: None,
+            chunk_chars: 8,
+            fail_first: 0,
+            fail_status: 500,
+            calls: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// 每次应答（流式时为每个分块）前的人为延迟
+    /// Artificial delay before each reply (each chunk when streaming)
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// 流式应答时每个分块的字符数
+    /// Characters per chunk when streaming
+    pub fn with_chunk_chars(mut self, chunk_chars: usize) -> Self {
+        self.chunk_chars = chunk_chars.max(1);
+        self
+    }
+
+    /// 前 `count` 次调用返回给定的HTTP错误
+    /// Fail the first `count` calls with the given HTTP status
+    pub fn fail_first(mut self, count: usize, status: u16) -> Self {
+        self.fail_first = count;
+        self.fail_status = status;
+        self
+    }
+
+    /// 已收到的调用次数
+    /// Number of calls received so far
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    /// 生成本次调用的响应
+    /// Produce the reply for one call
+    pub async fn reply(&self, request_body: &serde_json::Value) -> MockReply {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst);
+        if let Some(latency) = self.latency {
+            tokio::time::sleep(latency).await;
+        }
+        if call < self.fail_first {
+            return MockReply::HttpError(self.fail_status);
+        }
+        (self.responder)(request_body)
+    }
+
+    /// 将文本包装为非流式响应体
+    /// Wrap text into a non-streaming response body
+    pub fn completion_body(request_body: &serde_json::Value, text: &str) -> serde_json::Value {
+        let model = request_body["model"].as_str().unwrap_or("mock");
+        json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion",
+            "model": model,
+            "system_fingerprint": MOCK_FINGERPRINT,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": text},
+                "finish_reason": "stop",
+            }],
+            "usage": Self::usage(request_body, text),
+        })
+    }
+
+    /// 将文本切分为 SSE 分块流
+    /// Split text into a stream of SSE chunks
+    pub fn sse_stream(&self, request_body: &serde_json::Value, text: &str) -> ByteStream {
+        let model = request_body["model"].as_str().unwrap_or("mock");
+        let chars = text.chars().collect::<Vec<_>>();
+        let mut events = chars
+            .chunks(self.chunk_chars)
+            .map(|chunk| {
+                json!({
+                    "id": "chatcmpl-mock",
+                    "object": "chat.completion.chunk",
+                    "model": model,
+                    "system_fingerprint": MOCK_FINGERPRINT,
+                    "choices": [{"index": 0, "delta": {"content": chunk.iter().collect::<String>()}}],
+                })
+            })
+            .collect::<Vec<_>>();
+        events.push(json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion.chunk",
+            "model": model,
+            "system_fingerprint": MOCK_FINGERPRINT,
+            "choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}],
+            "usage": Self::usage(request_body, text),
+        }));
+
+        let mut lines = events
+            .into_iter()
+            .map(|event| format!("data: {}\n\n", event))
+            .collect::<Vec<_>>();
+        lines.push("data: [DONE]\n\n".to_string());
+
+        let latency = self.latency;
+        Box::pin(stream::iter(lines).then(move |line| async move {
+            if let Some(latency) = latency {
+                tokio::time::sleep(latency).await;
+            }
+            Ok(Bytes::from(line))
+        }))
+    }
+
+    /// 粗略估算用量（约4个字符一个token）
+    /// Rough usage estimate (about 4 characters per token)
+    fn usage(request_body: &serde_json::Value, text: &str) -> serde_json::Value {
+        let prompt_tokens = request_body["messages"].to_string().chars().count().div_ceil(4);
+        let completion_tokens = text.chars().count().div_ceil(4);
+        json!({
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        })
+    }
+}
@@ -1,5 +1,34 @@
 pub mod message;
+pub mod arena;
 pub mod chat_base;
 pub mod chat_single;
 pub mod chat_multi;
 pub mod chat_tool;
+pub mod scheduler;
+pub mod recorder;
+pub mod replay;
+pub mod mock;
+pub mod chat_batch;
+pub mod safety;
+pub mod judge;
+pub mod usage;
+pub mod stream;
+pub mod pruning;
+pub mod attachment;
+pub mod context;
+pub mod normalize;
+pub mod transport;
+pub mod repro;
+pub mod preflight;
+pub mod images;
+pub mod files;
+pub mod finetune;
+pub mod variables;
+pub mod datetime;
+pub mod actor;
+pub mod rate_limit;
+pub mod ask_user;
+pub mod events;
+pub mod openrouter;
+pub mod limits;
+pub mod extract;
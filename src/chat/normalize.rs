@@ -0,0 +1,75 @@
+use crate::chat::context::ContextMessage;
+
+/// 服务商对消息角色顺序的要求
+/// Provider rules on the order of message roles
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoleRules {
+    /// 原样发送（默认）
+    /// Send as is (default)
+    #[default]
+    Permissive,
+
+    /// 合并相邻的同角色消息，工具与函数结果除外
+    /// Merge adjacent messages of the same role, except tool and function results
+    MergeConsecutive,
+
+    /// 严格交替：系统消息只能在开头，其后的系统消息转为用户消息；
+    /// 相邻的用户或助手消息即使说话人不同也合并，`name` 字段改为内容前缀
+    /// Strict alternation: system messages may only lead and later ones become user messages;
+    /// adjacent user or assistant messages merge even across speakers, with `name` folded into the content
+    StrictAlternation,
+}
+
+fn role(message: &ContextMessage) -> &str {
+    message.get("role").map(String::as_str).unwrap_or_default()
+}
+
+/// 除内容外的字段都相同、且不是工具或函数结果时可以合并
+/// Mergeable when every field but the content matches and neither is a tool or function result
+fn mergeable(previous: &ContextMessage, message: &ContextMessage) -> bool {
+    !matches!(role(message), "tool" | "function")
+        && previous.len() == message.len()
+        && message
+            .iter()
+            .all(|(key, value)| key == "content" || previous.get(key) == Some(value))
+}
+
+/// 按服务商规则整理消息角色
+/// Normalize message roles according to provider rules
+///
+/// # 参数 (Parameters)
+/// * `messages` - 请求中的消息
+///   - Messages of the request
+/// * `rules` - 服务商规则
+///   - Provider rules
+pub fn normalize_roles(messages: Vec<ContextMessage>, rules: RoleRules) -> Vec<ContextMessage> {
+    if rules == RoleRules::Permissive {
+        return messages;
+    }
+
+    let strict = rules == RoleRules::StrictAlternation;
+    let mut normalized: Vec<ContextMessage> = Vec::with_capacity(messages.len());
+    for mut message in messages {
+        if strict {
+            if role(&message) == "system" && normalized.iter().any(|m| role(m) != "system") {
+                message.insert("role".to_string(), "user".to_string());
+            }
+            if matches!(role(&message), "user" | "assistant")
+                && let Some(name) = message.remove("name")
+            {
+                let content = message.entry("content".to_string()).or_default();
+                *content = format!("{name} said: {content}");
+            }
+        }
+
+        match normalized.last_mut() {
+            Some(previous) if mergeable(previous, &message) => {
+                let content = previous.entry("content".to_string()).or_default();
+                content.push_str("\n\n");
+                content.push_str(message.get("content").map(String::as_str).unwrap_or_default());
+            }
+            _ => normalized.push(message),
+        }
+    }
+    normalized
+}
@@ -0,0 +1,254 @@
+use error_stack::{Report, Result, ResultExt};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+
+use crate::config::Config;
+
+/// OpenRouter 的对话补全接口，可直接作为 API 来源的基础 URL
+/// OpenRouter chat completions endpoint, usable directly as the base URL of an API source
+pub const OPENROUTER_BASE_URL: &str = "https://openrouter.ai/api/v1/chat/completions";
+
+/// OpenRouter 的模型列表接口
+/// OpenRouter model list endpoint
+pub const OPENROUTER_MODELS_URL: &str = "https://openrouter.ai/api/v1/models";
+
+#[derive(Debug, Error)]
+pub enum OpenRouterError {
+    #[error("HTTP error with status code: {0}")]
+    HttpError(u16),
+
+    #[error("Model list request failed")]
+    RequestError,
+
+    #[error("Failed to parse model list")]
+    ParseResponseError,
+}
+
+/// 基础 URL 是否指向 OpenRouter
+/// Whether a base URL points at OpenRouter
+pub fn is_openrouter(base_url: &str) -> bool {
+    reqwest::Url::parse(base_url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host == "openrouter.ai" || host.ends_with(".openrouter.ai")))
+        .unwrap_or(false)
+}
+
+/// 是否允许服务商保存请求数据
+/// Whether providers may store request data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataCollection {
+    Allow,
+
+    Deny,
+}
+
+/// 选择服务商时优先考虑的指标
+/// Metric favoured when picking a provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderSort {
+    Price,
+
+    Throughput,
+
+    Latency,
+}
+
+/// OpenRouter 的服务商路由偏好，作为请求体的 `provider` 字段发送；未设置的项不发送
+/// OpenRouter provider routing preferences, sent as the `provider` field of the request body; unset items are left out
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProviderPreferences {
+    /// 依次尝试的服务商
+    /// Providers tried in this order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub order: Vec<String>,
+
+    /// 列出的服务商都不可用时是否改用其他服务商
+    /// Whether other providers may be used when the listed ones are unavailable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_fallbacks: Option<bool>,
+
+    /// 只使用支持请求中全部参数的服务商
+    /// Only use providers supporting every parameter of the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub require_parameters: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_collection: Option<DataCollection>,
+
+    /// 只允许这些服务商
+    /// Only these providers are allowed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub only: Vec<String>,
+
+    /// 不使用这些服务商
+    /// These providers are never used
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+
+    /// 允许的量化精度，例如 `fp8`
+    /// Allowed quantizations, e.g. `fp8`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quantizations: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<ProviderSort>,
+}
+
+impl ProviderPreferences {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_order<I, S>(mut self, providers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.order = providers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_allow_fallbacks(mut self, allow_fallbacks: bool) -> Self {
+        self.allow_fallbacks = Some(allow_fallbacks);
+        self
+    }
+
+    pub fn with_require_parameters(mut self, require_parameters: bool) -> Self {
+        self.require_parameters = Some(require_parameters);
+        self
+    }
+
+    pub fn with_data_collection(mut self, data_collection: DataCollection) -> Self {
+        self.data_collection = Some(data_collection);
+        self
+    }
+
+    pub fn with_only<I, S>(mut self, providers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.only = providers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_ignore<I, S>(mut self, providers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.ignore = providers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_quantizations<I, S>(mut self, quantizations: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.quantizations = quantizations.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_sort(mut self, sort: ProviderSort) -> Self {
+        self.sort = Some(sort);
+        self
+    }
+}
+
+/// 模型的单价（美元每令牌），OpenRouter 以字符串给出
+/// Prices of a model in USD per token, given as strings by OpenRouter
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct ModelPricing {
+    #[serde(default)]
+    pub prompt: String,
+
+    #[serde(default)]
+    pub completion: String,
+}
+
+/// OpenRouter 模型列表中的一个模型
+/// One model of the OpenRouter model list
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct OpenRouterModel {
+    /// 请求中使用的模型名，例如 `openai/gpt-4o`
+    /// Model name used in requests, e.g. `openai/gpt-4o`
+    pub id: String,
+
+    #[serde(default)]
+    pub name: String,
+
+    #[serde(default)]
+    pub context_length: Option<u64>,
+
+    #[serde(default)]
+    pub pricing: ModelPricing,
+
+    /// 模型支持的请求参数，例如 `tools`、`response_format`
+    /// Request parameters the model supports, e.g. `tools` and `response_format`
+    #[serde(default)]
+    pub supported_parameters: Vec<String>,
+}
+
+impl OpenRouterModel {
+    pub fn prompt_price(&self) -> Option<f64> {
+        self.pricing.prompt.parse().ok()
+    }
+
+    pub fn completion_price(&self) -> Option<f64> {
+        self.pricing.completion.parse().ok()
+    }
+
+    pub fn supports(&self, parameter: &str) -> bool {
+        self.supported_parameters.iter().any(|supported| supported == parameter)
+    }
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<OpenRouterModel>,
+}
+
+/// 获取 OpenRouter 上可用的模型
+/// Fetch the models available on OpenRouter
+pub async fn list_models(client: &Client, api_key: Option<&str>) -> Result<Vec<OpenRouterModel>, OpenRouterError> {
+    list_models_from(OPENROUTER_MODELS_URL, client, api_key).await
+}
+
+/// 从给定的模型列表接口获取模型，用于代理或自建的兼容网关
+/// Fetch models from the given model list endpoint, for proxies or self-hosted compatible gateways
+pub async fn list_models_from(
+    url: &str,
+    client: &Client,
+    api_key: Option<&str>,
+) -> Result<Vec<OpenRouterModel>, OpenRouterError> {
+    let mut request = client.get(url);
+    if let Some(api_key) = api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let response = request.send().await.change_context(OpenRouterError::RequestError)?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Report::new(OpenRouterError::HttpError(status.as_u16())));
+    }
+    let list: ModelList = response
+        .json()
+        .await
+        .change_context(OpenRouterError::ParseResponseError)?;
+    info!("{} models listed by {}", list.data.len(), url);
+    Ok(list.data)
+}
+
+/// 把模型列表中的上下文长度登记为各模型的上下文上限
+/// Register the context lengths of a model list as the context limits of those models
+pub fn register_context_limits(models: &[OpenRouterModel]) {
+    for model in models {
+        if let Some(context_length) = model.context_length {
+            Config::set_context_limit(&model.id, context_length);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use error_stack::{Report, Result};
+
+use crate::chat::chat_base::ChatError;
+use crate::chat::normalize::RoleRules;
+use crate::chat::usage::count_tokens;
+use crate::runtime::Runtime;
+
+/// 服务商接受的消息角色
+/// Message roles accepted by providers
+const VALID_ROLES: &[&str] = &["system", "developer", "user", "assistant", "tool", "function"];
+
+/// 每条消息另计的格式令牌数
+/// Extra framing tokens per message
+const FRAMING_TOKENS: u64 = 3;
+
+fn invalid(reason: String) -> Report<ChatError> {
+    Report::new(ChatError::InvalidRequest(reason))
+}
+
+/// 发送前校验请求体，用清楚的 `ChatError::InvalidRequest` 代替服务商含糊的 400
+/// Validate a request body before sending, returning a descriptive `ChatError::InvalidRequest` instead of an opaque 400 from the provider
+///
+/// 检查消息非空、角色合法且符合服务商的角色规则，`max_tokens` 不超过模型的回答上限，以及提示令牌数与 `max_tokens`
+/// 之和不超过模型的上下文长度上限；角色规则与上限取自给定运行时（[`Runtime::get_model_limits`]）
+/// Checks that messages are present, roles are valid and follow the provider's role rules, that `max_tokens` fits
+/// the model's output cap and that the prompt tokens plus `max_tokens` fit the model's context limit; role rules and
+/// limits come from the given runtime ([`Runtime::get_model_limits`])
+///
+/// # 参数 (Parameters)
+/// * `runtime` - 对话所在的运行时
+///   - Runtime of the chat
+/// * `base_url` - API基础URL，用于查找角色规则
+///   - API base URL, used to look up role rules
+/// * `request_body` - 组装好的请求体
+///   - Assembled request body
+pub fn validate_request_body(
+    runtime: &Runtime,
+    base_url: &str,
+    request_body: &serde_json::Value,
+) -> Result<(), ChatError> {
+    let model = request_body["model"].as_str().unwrap_or_default();
+    let messages = match request_body["messages"].as_array() {
+        Some(messages) if !messages.is_empty() => messages,
+        Some(_) => return Err(invalid("`messages` is empty".to_string())),
+        None => return Err(invalid("`messages` is missing or not an array".to_string())),
+    };
+
+    let strict = runtime.get_role_rules(base_url) == RoleRules::StrictAlternation;
+    let mut previous_role = None;
+    let mut prompt_tokens = 0;
+    for (i, message) in messages.iter().enumerate() {
+        let role = message["role"]
+            .as_str()
+            .ok_or_else(|| invalid(format!("message {i} has no role")))?;
+        if !VALID_ROLES.contains(&role) {
+            return Err(invalid(format!("message {i} has unsupported role `{role}`")));
+        }
+        if message.get("content").is_none_or(|content| content.is_null()) && role != "assistant" {
+            return Err(invalid(format!("message {i} ({role}) has no content")));
+        }
+        if role == "tool" && message["tool_call_id"].as_str().is_none_or(str::is_empty) {
+            return Err(invalid(format!("tool message {i} has no `tool_call_id`")));
+        }
+        if strict {
+            if role == "system" && previous_role.is_some_and(|previous| previous != "system") {
+                return Err(invalid(format!("system message {i} follows a non-system message")));
+            }
+            if matches!(role, "user" | "assistant") && previous_role == Some(role) {
+                return Err(invalid(format!("message {i} repeats role `{role}` without alternation")));
+            }
+        }
+        previous_role = Some(role);
+
+        let content = match &message["content"] {
+            serde_json::Value::String(text) => text.clone(),
+            serde_json::Value::Null => String::new(),
+            other => other.to_string(),
+        };
+        prompt_tokens += count_tokens(model, &content) + FRAMING_TOKENS;
+    }
+
+    let limits = runtime.get_model_limits(model);
+    let max_tokens = ["max_completion_tokens", "max_tokens"]
+        .iter()
+        .find_map(|key| request_body[*key].as_u64())
+        .unwrap_or_default();
+    if let Some(max_output_tokens) = limits.max_output_tokens
+        && max_tokens > max_output_tokens
+    {
+        return Err(invalid(format!(
+            "{max_tokens} tokens reserved for the answer exceed the {max_output_tokens} token output limit of `{model}`"
+        )));
+    }
+    if let Some(context_limit) = limits.context_window
+        && prompt_tokens + max_tokens > context_limit
+    {
+        return Err(invalid(format!(
+            "about {prompt_tokens} prompt tokens plus {max_tokens} reserved for the answer exceed the {context_limit} token context limit of `{model}`"
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chat::message::{MessageError, Messages};
+
+/// 会话树的修剪策略
+/// Pruning policy of a session tree
+///
+/// 只修剪不在任何活动路径上的分支（会话默认路径与各命名树保存的默认路径），根消息不会被修剪；
+/// 被修剪的分支以 JSONL 形式追加到归档文件
+/// Only branches off every active path (the session default path and the saved default path of each named tree)
+/// are pruned and roots are never pruned; pruned branches are appended to the archive file as JSONL
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PruningPolicy {
+    /// 会话中消息总数的上限，超出时先修剪最久未活动的分支
+    /// Maximum number of messages in the session; the least recently active branch goes first
+    pub max_nodes: Option<usize>,
+
+    /// 非活动分支的最大深度（根为 1）
+    /// Maximum depth of inactive branches (roots are depth 1)
+    pub max_depth: Option<usize>,
+
+    /// 非活动分支最后一条消息的最长保留时间
+    /// Maximum age of the newest message of an inactive branch
+    pub max_age_ms: Option<u64>,
+
+    pub archive_path: PathBuf,
+}
+
+/// 归档文件中的一条记录
+/// One record of the archive file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedBranch {
+    pub pruned_ms: u64,
+
+    /// 修剪时父消息的路径
+    /// Path of the parent message at pruning time
+    pub parent_path: Vec<usize>,
+
+    pub branch: Messages,
+}
+
+impl PruningPolicy {
+    pub fn new(archive_path: impl Into<PathBuf>) -> Self {
+        Self {
+            max_nodes: None,
+            max_depth: None,
+            max_age_ms: None,
+            archive_path: archive_path.into(),
+        }
+    }
+
+    pub fn with_max_nodes(mut self, max_nodes: usize) -> Self {
+        self.max_nodes = Some(max_nodes);
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age_ms = Some(max_age.as_millis() as u64);
+        self
+    }
+
+    /// 将分支追加到归档文件
+    /// Append a branch to the archive file
+    pub(crate) fn archive(&self, parent_path: &[usize], branch: &Messages) -> Result<(), MessageError> {
+        let archive_error = |e: std::io::Error| MessageError::ArchiveError(e.to_string());
+
+        if let Some(dir) = self.archive_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(archive_error)?;
+        }
+        let record = ArchivedBranch {
+            pruned_ms: now_ms(),
+            parent_path: parent_path.to_vec(),
+            branch: branch.clone(),
+        };
+        let line = serde_json::to_string(&record).map_err(|e| MessageError::ArchiveError(e.to_string()))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.archive_path)
+            .map_err(archive_error)?;
+        writeln!(file, "{line}").map_err(archive_error)
+    }
+}
+
+/// 增量修剪的状态，不参与序列化与比较
+/// State of incremental pruning; neither serialized nor compared
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PruneState {
+    /// 会话中的消息总数，`None` 表示需要完整扫描后重新统计
+    /// Number of messages in the session; `None` means a full scan is needed to recount
+    pub(crate) node_count: Option<usize>,
+
+    /// 最早会有非活动分支过期的时间
+    /// Earliest time at which an inactive branch expires
+    pub(crate) next_expiry_ms: Option<u64>,
+
+    /// 上次检查时的活动路径
+    /// Active paths at the last check
+    pub(crate) active: Vec<Vec<usize>>,
+
+    /// 添加消息时暂不修剪，见 [`Session::defer_pruning`](crate::chat::message::Session::defer_pruning)
+    /// Skip pruning on add for now, see [`Session::defer_pruning`](crate::chat::message::Session::defer_pruning)
+    pub(crate) deferred: bool,
+}
+
+impl PartialEq for PruneState {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for PruneState {}
+
+/// 读取归档文件中的全部分支
+/// Read every branch in an archive file
+pub fn load_archive(path: impl AsRef<Path>) -> Result<Vec<ArchivedBranch>, MessageError> {
+    fs::read_to_string(path)
+        .map_err(|e| MessageError::ArchiveError(e.to_string()))?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| MessageError::ArchiveError(e.to_string())))
+        .collect()
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,185 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use error_stack::Result;
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::chat::usage::estimate_usage;
+use crate::runtime::Runtime;
+
+/// 限额计数窗口的长度
+/// Length of the window quotas are counted in
+pub const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum RateLimitError {
+    #[error("Rate limit backend unavailable: {0}")]
+    Backend(String),
+}
+
+/// API来源每分钟的请求数与令牌数限额，未设置的项不限制
+/// Per-minute request and token quota of an API source; unset items are unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub requests_per_minute: Option<u64>,
+
+    #[serde(default)]
+    pub tokens_per_minute: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_requests_per_minute(mut self, requests: u64) -> Self {
+        self.requests_per_minute = Some(requests);
+        self
+    }
+
+    pub fn with_tokens_per_minute(mut self, tokens: u64) -> Self {
+        self.tokens_per_minute = Some(tokens);
+        self
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.requests_per_minute.is_none() && self.tokens_per_minute.is_none()
+    }
+
+    /// 在已用 `requests` 次请求、`used_tokens` 个令牌的窗口里能否再发出一次用 `tokens` 个令牌的请求
+    /// Whether a request of `tokens` tokens still fits a window that already used `requests` requests and `used_tokens` tokens
+    ///
+    /// 单次请求超过令牌限额时，只要窗口里还没有令牌用量就放行，避免永远等待
+    /// A request larger than the token quota is let through into a window without token usage so it never waits forever
+    pub fn admits(&self, requests: u64, used_tokens: u64, tokens: u64) -> bool {
+        let requests_fit = self.requests_per_minute.is_none_or(|limit| requests < limit);
+        let tokens_fit = self
+            .tokens_per_minute
+            .is_none_or(|limit| used_tokens == 0 || used_tokens + tokens <= limit);
+        requests_fit && tokens_fit
+    }
+}
+
+/// 限额计数的存放处：默认为进程内计数，多副本部署时可换成共享的后端（如 Redis）
+/// Where quota counters live: in-process by default, replaceable with a shared backend (e.g. Redis) for multi-replica deployments
+pub trait RateLimitBackend: Debug + Send + Sync {
+    /// 在当前窗口内为一次请求登记用量并返回零；超出限额时不登记，返回距窗口结束的时间
+    /// Record one request in the current window and return zero; when over quota nothing is recorded and the
+    /// time until the window ends is returned
+    fn reserve<'a>(&'a self, key: &'a str, limit: RateLimit, tokens: u64) -> BoxFuture<'a, Result<Duration, RateLimitError>>;
+}
+
+/// 进程内的固定窗口计数
+/// In-process fixed-window counters
+#[derive(Debug, Default)]
+pub struct LocalRateLimiter {
+    windows: DashMap<String, UsageWindow>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct UsageWindow {
+    index: u64,
+    requests: u64,
+    tokens: u64,
+}
+
+impl LocalRateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 以给定的当前时间（距 Unix 纪元）登记用量
+    /// Record usage as of the given current time (since the Unix epoch)
+    pub(crate) fn reserve_at(&self, key: &str, limit: RateLimit, tokens: u64, now: Duration) -> Duration {
+        let window_ms = RATE_LIMIT_WINDOW.as_millis() as u64;
+        let now_ms = now.as_millis() as u64;
+        let index = now_ms / window_ms;
+        let mut window = self.windows.entry(key.to_string()).or_default();
+        if window.index != index {
+            *window = UsageWindow {
+                index,
+                ..UsageWindow::default()
+            };
+        }
+        if !limit.admits(window.requests, window.tokens, tokens) {
+            return Duration::from_millis((index + 1) * window_ms - now_ms);
+        }
+        window.requests += 1;
+        window.tokens += tokens;
+        Duration::ZERO
+    }
+}
+
+impl LocalRateLimiter {
+    /// 丢弃键以 `prefix` 开头的计数窗口
+    /// Drop the counter windows whose keys start with `prefix`
+    pub(crate) fn forget(&self, prefix: &str) {
+        self.windows.retain(|key, _| !key.starts_with(prefix));
+    }
+}
+
+impl RateLimitBackend for LocalRateLimiter {
+    fn reserve<'a>(&'a self, key: &'a str, limit: RateLimit, tokens: u64) -> BoxFuture<'a, Result<Duration, RateLimitError>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Box::pin(async move { Ok(self.reserve_at(key, limit, tokens, now)) })
+    }
+}
+
+/// 未单独设置后端的来源共用的进程内计数
+/// In-process counters shared by sources without a backend of their own
+pub(crate) static LOCAL_RATE_LIMITER: Lazy<Arc<LocalRateLimiter>> = Lazy::new(Default::default);
+
+/// 估算请求会用掉的令牌数：提示令牌加上请求的回答上限
+/// Estimate the tokens a request will use: its prompt tokens plus the requested completion cap
+pub fn estimate_request_tokens(request_body: &serde_json::Value) -> u64 {
+    let model = request_body["model"].as_str().unwrap_or_default();
+    let prompt_tokens = estimate_usage(model, request_body, "")["prompt_tokens"]
+        .as_u64()
+        .unwrap_or_default();
+    let completion_cap = request_body["max_completion_tokens"]
+        .as_u64()
+        .or_else(|| request_body["max_tokens"].as_u64())
+        .unwrap_or_default();
+    prompt_tokens + completion_cap
+}
+
+/// 等到API来源的全局限额与运行时自己的限额都允许发出这次请求；后端不可用时记录警告并直接放行
+/// Wait until both the source-wide quota of an API source and the runtime's own quota admit this request; when
+/// the backend is unavailable a warning is logged and the request goes ahead
+pub(crate) async fn wait_for_rate_limit(runtime: &Runtime, base_url: &str, request_body: &serde_json::Value) {
+    if let Some(limit) = runtime.get_source_rate_limit(base_url) {
+        reserve(runtime, base_url, base_url, limit, request_body).await;
+    }
+    if let Some(limit) = runtime.get_rate_limit(base_url) {
+        reserve(runtime, base_url, &runtime.quota_key(base_url), limit, request_body).await;
+    }
+}
+
+async fn reserve(runtime: &Runtime, base_url: &str, key: &str, limit: RateLimit, request_body: &serde_json::Value) {
+    let tokens = if limit.tokens_per_minute.is_some() {
+        estimate_request_tokens(request_body)
+    } else {
+        0
+    };
+    let backend = runtime.get_rate_limit_backend(base_url);
+    loop {
+        match backend.reserve(key, limit, tokens).await {
+            Ok(delay) if delay.is_zero() => return,
+            Ok(delay) => {
+                info!("{key} is over its quota, waiting {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+            Err(report) => {
+                warn!("Rate limit backend of {base_url} failed, sending without quota: {report:?}");
+                return;
+            }
+        }
+    }
+}
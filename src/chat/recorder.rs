@@ -0,0 +1,313 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use error_stack::{Result, ResultExt};
+use futures::Stream;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::chat::replay::ReplayStore;
+use crate::chat::transport::StreamChunk;
+use crate::utils::common::redact::redact_json;
+
+/// 流在读完前被丢弃时写入记录的错误信息
+/// Error recorded for a stream dropped before it finished
+pub const STREAM_DROPPED: &str = "Stream dropped before completion";
+
+#[derive(Debug, Error)]
+pub enum RecorderError {
+    #[error("Failed to open record file: {0}")]
+    OpenError(String),
+
+    #[error("Failed to write record")]
+    WriteError,
+
+    #[error("Failed to rotate record files")]
+    RotateError,
+}
+
+/// 一次请求的记录
+/// Record of one request
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordEntry {
+    /// 记录时间（毫秒时间戳）
+    /// Record time (unix millis)
+    pub timestamp_ms: u64,
+
+    pub model: String,
+
+    pub base_url: String,
+
+    pub stream: bool,
+
+    /// 请求体（已遮盖敏感字段）
+    /// Request body (sensitive fields redacted)
+    pub request: serde_json::Value,
+
+    /// 未遮盖请求体的哈希，回放时按此匹配；旧记录没有该字段时由 `request` 计算
+    /// Hash of the unredacted request body used for replay; computed from `request` for older records
+    #[serde(default)]
+    pub request_hash: Option<String>,
+
+    /// 非流式响应体
+    /// Non-streaming response body
+    #[serde(default)]
+    pub response: Option<serde_json::Value>,
+
+    /// 流式响应的原始 SSE 文本
+    /// Raw SSE text of a streaming response
+    #[serde(default)]
+    pub stream_body: Option<String>,
+
+    #[serde(default)]
+    pub error: Option<String>,
+
+    pub latency_ms: u64,
+}
+
+/// 记录器配置
+/// Recorder options
+#[derive(Clone, Debug)]
+pub struct RecorderOptions {
+    /// 文件名前缀，文件名为 `{prefix}.jsonl`，轮转后为 `{prefix}.{n}.jsonl`
+    /// File name prefix: `{prefix}.jsonl`, rotated files are `{prefix}.{n}.jsonl`
+    pub file_prefix: String,
+
+    /// 单个文件的最大字节数，超过后轮转
+    /// Maximum bytes per file before rotation
+    pub max_file_bytes: u64,
+
+    /// 保留的轮转文件数量
+    /// Number of rotated files to keep
+    pub max_files: usize,
+}
+
+impl Default for RecorderOptions {
+    fn default() -> Self {
+        Self {
+            file_prefix: "requests".to_string(),
+            max_file_bytes: 64 * 1024 * 1024,
+            max_files: 8,
+        }
+    }
+}
+
+struct RecorderState {
+    file: File,
+    size: u64,
+}
+
+/// 将请求与响应以 JSONL 形式追加写入文件的记录器
+/// Recorder appending requests and responses to JSONL files
+pub struct Recorder {
+    dir: PathBuf,
+    options: RecorderOptions,
+    state: Mutex<RecorderState>,
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder")
+            .field("dir", &self.dir)
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+static RECORDER: Lazy<RwLock<Option<Arc<Recorder>>>> = Lazy::new(|| RwLock::new(None));
+
+impl Recorder {
+    pub fn new(dir: impl AsRef<Path>, options: RecorderOptions) -> Result<Self, RecorderError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .change_context_lazy(|| RecorderError::OpenError(dir.display().to_string()))?;
+
+        let path = dir.join(format!("{}.jsonl", options.file_prefix));
+        let file = Self::open(&path)?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+        Ok(Self {
+            dir,
+            options,
+            state: Mutex::new(RecorderState { file, size }),
+        })
+    }
+
+    /// 安装为全局记录器，之后的所有请求都会被记录
+    /// Install as the global recorder; every following request is recorded
+    pub fn install(self) -> Arc<Recorder> {
+        let recorder = Arc::new(self);
+        *RECORDER.write().unwrap() = Some(recorder.clone());
+        recorder
+    }
+
+    /// 卸载全局记录器
+    /// Uninstall the global recorder
+    pub fn uninstall() {
+        *RECORDER.write().unwrap() = None;
+    }
+
+    pub fn current() -> Option<Arc<Recorder>> {
+        RECORDER.read().unwrap().clone()
+    }
+
+    /// 当前写入的文件路径
+    /// Path of the file currently written
+    pub fn current_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", self.options.file_prefix))
+    }
+
+    /// 追加一条记录，必要时轮转文件
+    /// Append one record, rotating files when needed
+    pub fn record(&self, entry: &RecordEntry) -> Result<(), RecorderError> {
+        let mut line = serde_json::to_string(entry).change_context(RecorderError::WriteError)?;
+        line.push('\n');
+
+        let mut state = self.state.lock().unwrap();
+        if state.size > 0 && state.size + line.len() as u64 > self.options.max_file_bytes {
+            self.rotate(&mut state)?;
+        }
+
+        state
+            .file
+            .write_all(line.as_bytes())
+            .change_context(RecorderError::WriteError)?;
+        state.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self, state: &mut RecorderState) -> Result<(), RecorderError> {
+        let rotated = |n: usize| {
+            self.dir
+                .join(format!("{}.{}.jsonl", self.options.file_prefix, n))
+        };
+
+        if self.options.max_files == 0 {
+            fs::remove_file(self.current_path()).change_context(RecorderError::RotateError)?;
+        } else {
+            let oldest = rotated(self.options.max_files);
+            if oldest.exists() {
+                fs::remove_file(&oldest).change_context(RecorderError::RotateError)?;
+            }
+            for n in (1..self.options.max_files).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))
+                        .change_context(RecorderError::RotateError)?;
+                }
+            }
+            fs::rename(self.current_path(), rotated(1)).change_context(RecorderError::RotateError)?;
+        }
+
+        state.file = Self::open(&self.current_path())?;
+        state.size = 0;
+        Ok(())
+    }
+
+    fn open(path: &Path) -> Result<File, RecorderError> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .change_context_lazy(|| RecorderError::OpenError(path.display().to_string()))
+    }
+}
+
+/// 构造记录条目
+/// Build a record entry
+pub(crate) fn build_entry(
+    model: &str,
+    base_url: &str,
+    stream: bool,
+    request: &serde_json::Value,
+    started: Instant,
+) -> RecordEntry {
+    RecordEntry {
+        timestamp_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0),
+        model: model.to_string(),
+        base_url: base_url.to_string(),
+        stream,
+        request: redact_json(request),
+        request_hash: Some(ReplayStore::request_hash(request)),
+        response: None,
+        stream_body: None,
+        error: None,
+        latency_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// 写入记录，失败只记日志不影响请求
+/// Write a record; failures are logged and never affect the request
+pub(crate) fn write_entry(recorder: &Recorder, entry: &RecordEntry) {
+    if let Err(err) = recorder.record(entry) {
+        warn!("Failed to record request: {:?}", err);
+    }
+}
+
+/// 在流结束或被提前丢弃时写入记录的流包装
+/// Stream wrapper writing the record once the stream finishes or is dropped early
+pub struct RecordingStream<S> {
+    inner: S,
+    recording: Option<(Arc<Recorder>, RecordEntry, Vec<u8>, Instant)>,
+}
+
+impl<S> RecordingStream<S> {
+    pub(crate) fn new(inner: S, recording: Option<(Arc<Recorder>, RecordEntry, Instant)>) -> Self {
+        Self {
+            inner,
+            recording: recording.map(|(recorder, entry, started)| (recorder, entry, Vec::new(), started)),
+        }
+    }
+
+    /// 写入已收到的部分并结束记录，只会写入一次
+    /// Write what has been received so far and end the recording; writes at most once
+    fn finish(&mut self, error: Option<String>) {
+        if let Some((recorder, mut entry, body, started)) = self.recording.take() {
+            entry.stream_body = Some(String::from_utf8_lossy(&body).into_owned());
+            entry.error = error;
+            entry.latency_ms = started.elapsed().as_millis() as u64;
+            write_entry(&recorder, &entry);
+        }
+    }
+}
+
+impl<S> Stream for RecordingStream<S>
+where
+    S: Stream<Item = StreamChunk> + Unpin,
+{
+    type Item = StreamChunk;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.inner).poll_next(cx);
+
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some((_, _, body, _)) = self.recording.as_mut() {
+                    body.extend_from_slice(chunk);
+                }
+            }
+            Poll::Ready(Some(Err(err))) => self.finish(Some(err.to_string())),
+            Poll::Ready(None) => self.finish(None),
+            Poll::Pending => {}
+        }
+
+        polled
+    }
+}
+
+impl<S> Drop for RecordingStream<S> {
+    fn drop(&mut self) {
+        // 调用方取消或遇到停止序列时流不会读完，仍记录已收到的部分
+        // The caller may stop early (cancellation, stop sequences); still record the partial body
+        self.finish(Some(STREAM_DROPPED.to_string()));
+    }
+}
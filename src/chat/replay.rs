@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use error_stack::{Report, Result, ResultExt};
+use thiserror::Error;
+
+use crate::chat::recorder::RecordEntry;
+use crate::utils::common::body_log::body_attachment;
+use crate::utils::common::redact::is_credential_field;
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("Failed to read record file: {0}")]
+    ReadError(String),
+
+    #[error("Invalid record at {0}:{1}")]
+    InvalidRecord(String, usize),
+
+    #[error("No recorded response for request {0}")]
+    Miss(String),
+}
+
+/// 回放存储：按请求哈希提供已记录的响应
+/// Replay store: serves recorded responses keyed by request hash
+#[derive(Clone, Debug, Default)]
+pub struct ReplayStore {
+    entries: HashMap<String, RecordEntry>,
+}
+
+impl ReplayStore {
+    /// 从记录器生成的 JSONL 文件或包含此类文件的目录加载
+    /// Load from a recorder JSONL file or a directory of such files
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        let path = path.as_ref();
+        let mut files = if path.is_dir() {
+            fs::read_dir(path)
+                .change_context_lazy(|| ReplayError::ReadError(path.display().to_string()))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "jsonl"))
+                .collect::<Vec<_>>()
+        } else {
+            vec![path.to_path_buf()]
+        };
+        files.sort();
+
+        let mut entries = Vec::new();
+        for file in files {
+            let content = fs::read_to_string(&file)
+                .change_context_lazy(|| ReplayError::ReadError(file.display().to_string()))?;
+
+            for (line_no, line) in content.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry: RecordEntry = serde_json::from_str(line).change_context_lazy(|| {
+                    ReplayError::InvalidRecord(file.display().to_string(), line_no + 1)
+                })?;
+                entries.push(entry);
+            }
+        }
+
+        Ok(Self::from_entries(entries))
+    }
+
+    /// 从记录条目构建，同一请求以最后一条成功记录为准
+    /// Build from record entries; the last successful record of a request wins
+    pub fn from_entries(entries: impl IntoIterator<Item = RecordEntry>) -> Self {
+        let entries = entries
+            .into_iter()
+            .filter(|entry| entry.error.is_none())
+            .map(|entry| {
+                let hash = entry
+                    .request_hash
+                    .clone()
+                    .unwrap_or_else(|| Self::request_hash(&entry.request));
+                (hash, entry)
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// 计算请求的稳定哈希（FNV-1a），只去掉内置凭据字段，其余内容原样参与计算
+    /// Stable request hash (FNV-1a); only built-in credential fields are stripped, everything else is hashed as is
+    pub fn request_hash(request: &serde_json::Value) -> String {
+        let canonical = serde_json::to_string(&strip_credentials(request)).unwrap_or_default();
+        let hash = canonical
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        format!("{hash:016x}")
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, request: &serde_json::Value) -> Option<&RecordEntry> {
+        self.entries.get(&Self::request_hash(request))
+    }
+
+    /// 获取非流式响应
+    /// Get a non-streaming response
+    pub fn response(&self, request: &serde_json::Value) -> Result<serde_json::Value, ReplayError> {
+        self.get(request)
+            .and_then(|entry| entry.response.clone())
+            .ok_or_else(|| Self::miss(request))
+    }
+
+    /// 获取流式响应的原始 SSE 文本
+    /// Get the raw SSE text of a streaming response
+    pub fn stream_body(&self, request: &serde_json::Value) -> Result<String, ReplayError> {
+        self.get(request)
+            .and_then(|entry| entry.stream_body.clone())
+            .ok_or_else(|| Self::miss(request))
+    }
+
+    fn miss(request: &serde_json::Value) -> Report<ReplayError> {
+        Report::new(ReplayError::Miss(Self::request_hash(request)))
+            .attach_printable(body_attachment("Unrecorded request", request))
+    }
+}
+
+/// 去掉请求体中的凭据字段，不改动其它文本
+/// Remove credential fields from a request body, leaving all other text untouched
+fn strip_credentials(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.iter()
+                .filter(|(key, _)| !is_credential_field(key))
+                .map(|(key, value)| (key.clone(), strip_credentials(value)))
+                .collect(),
+        ),
+        serde_json::Value::Array(items) => {
+            serde_json::Value::Array(items.iter().map(strip_credentials).collect())
+        }
+        other => other.clone(),
+    }
+}
@@ -0,0 +1,109 @@
+use std::fs;
+use std::path::Path;
+
+use error_stack::{Report, Result, ResultExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::chat::chat_base::BaseChat;
+use crate::chat::pruning::now_ms;
+use crate::utils::common::redact::redact_json;
+
+/// 回答的复现信息在消息元数据中的键
+/// Key of the reproducibility info of an answer in message metadata
+pub const REPRO_METADATA_KEY: &str = "repro";
+
+#[derive(Debug, Error)]
+pub enum ReproError {
+    #[error("Failed to write bundle: {0}")]
+    WriteError(String),
+
+    #[error("Failed to read bundle: {0}")]
+    ReadError(String),
+
+    #[error("Failed to re-run bundle")]
+    RerunError,
+}
+
+/// 单次回答的复现信息：请求使用的种子与服务商返回的后端指纹
+/// Reproducibility info of one answer: the seed of the request and the backend fingerprint returned by the provider
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReproInfo {
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// 指纹不同说明服务商的后端配置变了，相同种子也可能得到不同回答
+    /// A different fingerprint means the provider backend changed, so the same seed may still give a different answer
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+}
+
+impl ReproInfo {
+    pub fn is_empty(&self) -> bool {
+        self.seed.is_none() && self.system_fingerprint.is_none()
+    }
+}
+
+/// 可重新运行的实验包：实际发出的请求体（含全部参数、提示与种子）及其复现信息
+/// Re-runnable experiment bundle: the request body actually sent (all parameters, prompts and the seed) with its reproducibility info
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReproBundle {
+    pub rhine_version: String,
+
+    /// 导出时间（毫秒时间戳）
+    /// Export time (unix millis)
+    pub created_ms: u64,
+
+    pub model: String,
+
+    pub base_url: String,
+
+    /// 请求体（已遮盖敏感字段）
+    /// Request body (sensitive fields redacted)
+    pub request: serde_json::Value,
+
+    #[serde(flatten)]
+    pub info: ReproInfo,
+}
+
+impl ReproBundle {
+    pub fn new(model: &str, base_url: &str, request: &serde_json::Value, info: ReproInfo) -> Self {
+        Self {
+            rhine_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_ms: now_ms(),
+            model: model.to_string(),
+            base_url: base_url.to_string(),
+            request: redact_json(request),
+            info,
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ReproError> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self)
+            .change_context_lazy(|| ReproError::WriteError(path.display().to_string()))?;
+        fs::write(path, json).change_context_lazy(|| ReproError::WriteError(path.display().to_string()))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ReproError> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).change_context_lazy(|| ReproError::ReadError(path.display().to_string()))?;
+        serde_json::from_str(&json).change_context_lazy(|| ReproError::ReadError(path.display().to_string()))
+    }
+
+    /// 用给定对话的模型与API重新发送请求（非流式），返回新的回答；之后可用 `chat.last_repro_info()` 比较指纹
+    /// Re-send the request (non-streaming) with the model and API of the given chat and return the new answer;
+    /// compare fingerprints through `chat.last_repro_info()` afterwards
+    pub async fn rerun(&self, chat: &mut BaseChat) -> Result<String, ReproError> {
+        let mut request = self.request.clone();
+        let Some(body) = request.as_object_mut() else {
+            return Err(Report::new(ReproError::RerunError)).attach_printable("Request body is not an object");
+        };
+        body.insert("model".to_string(), serde_json::Value::String(chat.model.clone()));
+        body.insert("stream".to_string(), serde_json::Value::Bool(false));
+        body.remove("stream_options");
+
+        let response = chat.get_response(request).await.change_context(ReproError::RerunError)?;
+        BaseChat::get_content_from_resp(&response).change_context(ReproError::RerunError)
+    }
+}
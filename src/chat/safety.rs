@@ -0,0 +1,291 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use error_stack::{Report, Result, ResultExt};
+use futures::future::BoxFuture;
+use reqwest::Client;
+use serde_json::json;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::config::{ApiInfo, Config};
+use crate::utils::common::body_log::body_attachment;
+use crate::utils::common::redact::redact;
+
+#[derive(Debug, Error)]
+pub enum SafetyError {
+    #[error("Invalid moderation endpoint: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("Moderation request failed")]
+    RequestError,
+
+    #[error("Failed to parse moderation response")]
+    ParseError,
+}
+
+/// 检查发生的阶段
+/// Stage at which a check happens
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SafetyStage {
+    /// 用户输入
+    /// User input
+    Input,
+
+    /// 模型输出
+    /// Model output
+    Output,
+}
+
+/// 命中后的处理方式
+/// Action taken on flagged content
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SafetyAction {
+    /// 拒绝并返回 `ChatError::ContentFiltered`
+    /// Reject with `ChatError::ContentFiltered`
+    #[default]
+    Block,
+
+    /// 以占位文本替换内容
+    /// Replace the content with a placeholder
+    Redact,
+
+    /// 保留内容，仅记录标注
+    /// Keep the content and only record an annotation
+    Annotate,
+}
+
+/// 一次检查的结果
+/// Result of one check
+#[derive(Clone, Debug, Default)]
+pub struct SafetyVerdict {
+    pub flagged: bool,
+
+    /// 命中的类别
+    /// Flagged categories
+    pub categories: Vec<String>,
+}
+
+impl SafetyVerdict {
+    pub fn pass() -> Self {
+        Self::default()
+    }
+
+    pub fn flagged(categories: Vec<String>) -> Self {
+        Self {
+            flagged: true,
+            categories,
+        }
+    }
+}
+
+/// 内容安全过滤器
+/// Content-safety filter
+pub trait SafetyFilter: Debug + Send + Sync {
+    fn check<'a>(
+        &'a self,
+        text: &'a str,
+        stage: SafetyStage,
+    ) -> BoxFuture<'a, Result<SafetyVerdict, SafetyError>>;
+}
+
+/// 基于 OpenAI 风格 `/moderations` 接口的过滤器
+/// Filter backed by an OpenAI-style `/moderations` endpoint
+#[derive(Clone)]
+pub struct ModerationFilter {
+    url: String,
+
+    api_key: String,
+
+    model: String,
+
+    client: Client,
+}
+
+impl Debug for ModerationFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModerationFilter")
+            .field("url", &self.url)
+            .field("model", &self.model)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ModerationFilter {
+    /// 由聊天API信息推导审核接口（将 `/chat/completions` 替换为 `/moderations`）
+    /// Derive the moderation endpoint from chat API info (`/chat/completions` becomes `/moderations`)
+    pub fn new_with_api_info(api_info: ApiInfo, model: &str) -> Result<Self, SafetyError> {
+        let root = api_info
+            .base_url
+            .strip_suffix("/chat/completions")
+            .ok_or_else(|| Report::new(SafetyError::InvalidEndpoint(api_info.base_url.clone())))?;
+        Ok(Self {
+            url: format!("{root}/moderations"),
+            api_key: api_info.api_key,
+            model: model.to_string(),
+            client: api_info.client,
+        })
+    }
+
+    pub fn new_with_api_name(api_name: &str, model: &str) -> Result<Self, SafetyError> {
+        let api_info = Config::get_api_info_with_name(api_name.to_string())
+            .change_context_lazy(|| SafetyError::InvalidEndpoint(api_name.to_string()))?;
+        Self::new_with_api_info(api_info, model)
+    }
+
+    async fn moderate(&self, text: &str) -> Result<SafetyVerdict, SafetyError> {
+        let resp: serde_json::Value = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .json(&json!({"model": self.model, "input": text}))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Report::new(SafetyError::RequestError).attach_printable(redact(&e.to_string())))?
+            .json()
+            .await
+            .change_context(SafetyError::ParseError)?;
+
+        let result = &resp["results"][0];
+        let flagged = result["flagged"]
+            .as_bool()
+            .ok_or_else(|| Report::new(SafetyError::ParseError))
+            .attach_printable_lazy(|| body_attachment("Unexpected moderation response", &resp))?;
+        if !flagged {
+            return Ok(SafetyVerdict::pass());
+        }
+
+        let categories = result["categories"]
+            .as_object()
+            .map(|categories| {
+                categories
+                    .iter()
+                    .filter(|(_, hit)| hit.as_bool() == Some(true))
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(SafetyVerdict::flagged(categories))
+    }
+}
+
+impl SafetyFilter for ModerationFilter {
+    fn check<'a>(
+        &'a self,
+        text: &'a str,
+        _stage: SafetyStage,
+    ) -> BoxFuture<'a, Result<SafetyVerdict, SafetyError>> {
+        Box::pin(self.moderate(text))
+    }
+}
+
+/// 基于关键词的本地过滤器
+/// Local keyword-based filter
+#[derive(Clone, Debug)]
+pub struct KeywordFilter {
+    category: String,
+
+    keywords: Vec<String>,
+}
+
+impl KeywordFilter {
+    pub fn new(category: &str, keywords: &[&str]) -> Self {
+        Self {
+            category: category.to_string(),
+            keywords: keywords.iter().map(|k| k.to_lowercase()).collect(),
+        }
+    }
+}
+
+impl SafetyFilter for KeywordFilter {
+    fn check<'a>(
+        &'a self,
+        text: &'a str,
+        _stage: SafetyStage,
+    ) -> BoxFuture<'a, Result<SafetyVerdict, SafetyError>> {
+        let text = text.to_lowercase();
+        let verdict = if self.keywords.iter().any(|k| text.contains(k)) {
+            SafetyVerdict::flagged(vec![self.category.clone()])
+        } else {
+            SafetyVerdict::pass()
+        };
+        Box::pin(async move { Ok(verdict) })
+    }
+}
+
+/// 安全策略：过滤器与输入、输出阶段各自的处理方式
+/// Safety policy: a filter plus the action for inputs and for outputs
+#[derive(Clone, Debug)]
+pub struct SafetyPolicy {
+    pub filter: Arc<dyn SafetyFilter>,
+
+    pub input_action: SafetyAction,
+
+    pub output_action: SafetyAction,
+}
+
+/// 被命中的内容的标注
+/// Annotation of flagged content
+#[derive(Clone, Debug)]
+pub struct SafetyAnnotation {
+    pub stage: SafetyStage,
+
+    pub action: SafetyAction,
+
+    pub categories: Vec<String>,
+}
+
+impl SafetyPolicy {
+    pub fn new(filter: impl SafetyFilter + 'static) -> Self {
+        Self {
+            filter: Arc::new(filter),
+            input_action: SafetyAction::default(),
+            output_action: SafetyAction::default(),
+        }
+    }
+
+    pub fn with_input_action(mut self, action: SafetyAction) -> Self {
+        self.input_action = action;
+        self
+    }
+
+    pub fn with_output_action(mut self, action: SafetyAction) -> Self {
+        self.output_action = action;
+        self
+    }
+
+    pub fn action(&self, stage: SafetyStage) -> SafetyAction {
+        match stage {
+            SafetyStage::Input => self.input_action,
+            SafetyStage::Output => self.output_action,
+        }
+    }
+
+    /// 替换被命中内容的占位文本
+    /// Placeholder replacing flagged content
+    pub fn placeholder(categories: &[String]) -> String {
+        format!("[content filtered: {}]", categories.join(", "))
+    }
+
+    /// 检查文本，命中时返回标注
+    /// Check text and return an annotation when flagged
+    pub async fn inspect(
+        &self,
+        text: &str,
+        stage: SafetyStage,
+    ) -> Result<Option<SafetyAnnotation>, SafetyError> {
+        let verdict = self.filter.check(text, stage).await?;
+        if !verdict.flagged {
+            return Ok(None);
+        }
+
+        let annotation = SafetyAnnotation {
+            stage,
+            action: self.action(stage),
+            categories: verdict.categories,
+        };
+        warn!("Content flagged: {:?}", annotation);
+        Ok(Some(annotation))
+    }
+}
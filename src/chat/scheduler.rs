@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::runtime::Runtime;
+
+/// 请求优先级
+/// Request priority
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum RequestPriority {
+    /// 交互式请求，优先获得并发许可
+    /// Interactive requests, served first
+    High,
+
+    /// 普通请求
+    /// Normal requests
+    #[default]
+    Normal,
+
+    /// 后台任务（批处理、摘要等）
+    /// Background jobs (batch, summarization, ...)
+    Background,
+}
+
+impl RequestPriority {
+    fn index(self) -> usize {
+        match self {
+            Self::High => 0,
+            Self::Normal => 1,
+            Self::Background => 2,
+        }
+    }
+}
+
+/// 优先级调度器：在信号量饱和时按优先级分配许可
+/// Priority scheduler: hands out semaphore permits by priority once the pool is saturated
+#[derive(Debug)]
+pub struct PriorityScheduler {
+    semaphore: Arc<Semaphore>,
+    queues: Mutex<[VecDeque<u64>; 3]>,
+    next_ticket: AtomicU64,
+    notify: Notify,
+}
+
+impl PriorityScheduler {
+    pub fn new(semaphore: Arc<Semaphore>) -> Self {
+        Self {
+            semaphore,
+            queues: Mutex::new([VecDeque::new(), VecDeque::new(), VecDeque::new()]),
+            next_ticket: AtomicU64::new(0),
+            notify: Notify::new(),
+        }
+    }
+
+    /// 按优先级获取许可，只有最高优先级队列的队首才会等待信号量
+    /// Acquire a permit by priority; only the head of the highest non-empty queue waits on the semaphore
+    pub async fn acquire(&self, priority: RequestPriority) -> OwnedSemaphorePermit {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        self.queues.lock().unwrap()[priority.index()].push_back(ticket);
+        let _guard = TicketGuard {
+            scheduler: self,
+            priority,
+            ticket,
+        };
+
+        // 新请求入队可能改变队首，唤醒等待者重新检查
+        // A new arrival may change the head, wake waiters to re-check
+        self.notify.notify_waiters();
+
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.is_head(ticket) {
+                tokio::select! {
+                    permit = self.semaphore.clone().acquire_owned() => {
+                        return permit.expect("scheduler semaphore closed");
+                    }
+                    _ = &mut notified => continue,
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    /// 等待中的请求数量
+    /// Number of queued requests
+    pub fn queued(&self) -> usize {
+        self.queues.lock().unwrap().iter().map(VecDeque::len).sum()
+    }
+
+    fn is_head(&self, ticket: u64) -> bool {
+        self.queues
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|queue| queue.front())
+            .is_some_and(|&head| head == ticket)
+    }
+
+    fn remove(&self, priority: RequestPriority, ticket: u64) {
+        self.queues.lock().unwrap()[priority.index()].retain(|&t| t != ticket);
+        self.notify.notify_waiters();
+    }
+}
+
+/// 离开队列（获得许可或被取消）时移除票据
+/// Removes the ticket when leaving the queue (permit granted or cancelled)
+struct TicketGuard<'a> {
+    scheduler: &'a PriorityScheduler,
+    priority: RequestPriority,
+    ticket: u64,
+}
+
+impl Drop for TicketGuard<'_> {
+    fn drop(&mut self) {
+        self.scheduler.remove(self.priority, self.ticket);
+    }
+}
+
+/// 暂停默认运行时中的某个API来源，见 [`Runtime::pause_source`]
+/// Pause an API source of the default runtime, see [`Runtime::pause_source`]
+pub fn pause_source(base_url: &str, delay: Duration) {
+    Runtime::default_ref().pause_source(base_url, delay);
+}
+
+/// 默认运行时中来源暂停的结束时间，见 [`Runtime::source_paused_until`]
+/// When the pause of a source in the default runtime ends, see [`Runtime::source_paused_until`]
+pub fn source_paused_until(base_url: &str) -> Option<Instant> {
+    Runtime::default_ref().source_paused_until(base_url)
+}
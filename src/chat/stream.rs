@@ -0,0 +1,236 @@
+use std::sync::Arc;
+
+use tracing::warn;
+
+const TOOL_USE_OPEN: &str = "<ToolUse>";
+const TOOL_USE_CLOSE: &str = "</ToolUse>";
+
+/// 流式回答中交给界面的事件
+/// Event handed to the UI while an answer streams in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// 可直接展示的正文
+    /// Prose that can be shown as is
+    Text(String),
+
+    /// 检测到完整的 `<ToolUse>` 调用，内容为标签内文本
+    /// A complete `<ToolUse>` call was detected; holds the text inside the tags
+    ToolCallDetected(String),
+
+    /// 结构化回答到目前为止的值，见 [`PartialJsonParser`]
+    /// Value of a structured answer so far, see [`PartialJsonParser`]
+    PartialJson(serde_json::Value),
+}
+
+pub type StreamCallback = Arc<dyn Fn(&StreamEvent) + Send + Sync>;
+
+/// 增量识别 `<ToolUse>` 标签的过滤器：正文照常转发，工具调用文本被拦下并以事件形式给出
+/// Incremental `<ToolUse>` tag filter: prose is forwarded, tool-call text is held back and reported as an event
+///
+/// 末尾可能是半个开始标签的文本会暂存，直到能确定它是否为标签
+/// Trailing text that may be half of an opening tag is held until it can be told apart
+#[derive(Debug, Clone, Default)]
+pub struct ToolUseFilter {
+    buffer: String,
+
+    in_tool_use: bool,
+}
+
+impl ToolUseFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 输入一段增量文本，返回此时可以确定的事件
+    /// Feed a delta and return the events that can be decided so far
+    pub fn push(&mut self, delta: &str) -> Vec<StreamEvent> {
+        self.buffer.push_str(delta);
+        let mut events = Vec::new();
+
+        loop {
+            if self.in_tool_use {
+                let Some(end) = self.buffer.find(TOOL_USE_CLOSE) else {
+                    break;
+                };
+                events.push(StreamEvent::ToolCallDetected(self.buffer[..end].trim().to_string()));
+                self.buffer.drain(..end + TOOL_USE_CLOSE.len());
+                self.in_tool_use = false;
+            } else if let Some(start) = self.buffer.find(TOOL_USE_OPEN) {
+                push_text(&mut events, &self.buffer[..start]);
+                self.buffer.drain(..start + TOOL_USE_OPEN.len());
+                self.in_tool_use = true;
+            } else {
+                let keep = partial_tag_len(&self.buffer);
+                let emit = self.buffer.len() - keep;
+                push_text(&mut events, &self.buffer[..emit]);
+                self.buffer.drain(..emit);
+                break;
+            }
+        }
+
+        events
+    }
+
+    /// 流结束时调用，给出暂存的正文；未闭合的工具调用会被丢弃
+    /// Call at the end of the stream to flush held prose; an unclosed tool call is dropped
+    pub fn finish(&mut self) -> Vec<StreamEvent> {
+        let rest = std::mem::take(&mut self.buffer);
+        if std::mem::take(&mut self.in_tool_use) {
+            warn!("Stream ended inside an unclosed <ToolUse> tag");
+            return Vec::new();
+        }
+
+        let mut events = Vec::new();
+        push_text(&mut events, &rest);
+        events
+    }
+}
+
+fn push_text(events: &mut Vec<StreamEvent>, text: &str) {
+    if !text.is_empty() {
+        events.push(StreamEvent::Text(text.to_string()));
+    }
+}
+
+/// 文本末尾与开始标签前缀重合的最长长度
+/// Length of the longest suffix of the text that is a prefix of the opening tag
+fn partial_tag_len(text: &str) -> usize {
+    (1..TOOL_USE_OPEN.len())
+        .rev()
+        .find(|&len| text.ends_with(&TOOL_USE_OPEN[..len]))
+        .unwrap_or(0)
+}
+
+/// 结构化输出流的增量 JSON 解析器：每段增量到达后给出当前已生成部分补全后的值
+/// Incremental JSON parser for streamed structured output: after every delta it yields the value
+/// generated so far, completed to valid JSON
+///
+/// 未写完的字符串值按已到达的部分给出，未写完的键、字面量与末尾的逗号会被略去
+/// Unfinished string values are given as far as they arrived; unfinished keys, literals and trailing commas are left out
+#[derive(Debug, Clone, Default)]
+pub struct PartialJsonParser {
+    buffer: String,
+
+    last: Option<serde_json::Value>,
+}
+
+impl PartialJsonParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 输入一段增量文本，值与上次相比有变化时返回新的值
+    /// Feed a delta and return the new value when it changed since the last one
+    pub fn push(&mut self, delta: &str) -> Option<serde_json::Value> {
+        self.buffer.push_str(delta);
+        let value = parse_partial_json(&self.buffer)?;
+        if self.last.as_ref() == Some(&value) {
+            return None;
+        }
+        self.last = Some(value.clone());
+        Some(value)
+    }
+
+    /// 最近一次解析出的值
+    /// Latest value parsed
+    pub fn value(&self) -> Option<&serde_json::Value> {
+        self.last.as_ref()
+    }
+}
+
+/// 把不完整的 JSON 文本补全后解析，从第一个 `{` 或 `[` 开始，忽略代码块围栏等前后文字
+/// Complete and parse unfinished JSON text, starting at the first `{` or `[` and ignoring surrounding
+/// text such as code fences
+pub fn parse_partial_json(text: &str) -> Option<serde_json::Value> {
+    let start = text.find(['{', '['])?;
+    let text = &text[start..];
+
+    // 容器栈：闭合字符，以及对象中下一个字符串是否为键
+    // Container stack: closing character, and for objects whether the next string is a key
+    let mut stack: Vec<(char, bool)> = Vec::new();
+    // 最近一个可截断的位置与此时需要补上的闭合字符
+    // Latest position the text can be cut at, with the closing characters it needs then
+    let mut safe_cut = (0, String::new());
+    let mut in_string = false;
+    let mut string_is_key = false;
+    let mut escaped = false;
+    let mut token_start: Option<usize> = None;
+
+    let closers = |stack: &[(char, bool)]| stack.iter().rev().map(|(close, _)| *close).collect::<String>();
+
+    for (index, ch) in text.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if ch == '\\' {
+                escaped = true;
+            } else if ch == '"' {
+                in_string = false;
+                if !string_is_key {
+                    safe_cut = (index + 1, closers(&stack));
+                }
+            }
+            continue;
+        }
+
+        if token_start.is_some() && matches!(ch, ',' | '}' | ']' | ' ' | '\n' | '\r' | '\t') {
+            token_start = None;
+            safe_cut = (index, closers(&stack));
+        }
+
+        match ch {
+            '{' | '[' => {
+                stack.push(if ch == '{' { ('}', true) } else { (']', false) });
+                safe_cut = (index + 1, closers(&stack));
+            }
+            '}' | ']' => {
+                stack.pop();
+                safe_cut = (index + 1, closers(&stack));
+                if stack.is_empty() {
+                    break;
+                }
+            }
+            '"' => {
+                in_string = true;
+                string_is_key = stack.last().is_some_and(|(close, expect_key)| *close == '}' && *expect_key);
+            }
+            ':' => {
+                if let Some(top) = stack.last_mut() {
+                    top.1 = false;
+                }
+            }
+            ',' => {
+                if let Some(top) = stack.last_mut().filter(|(close, _)| *close == '}') {
+                    top.1 = true;
+                }
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                token_start.get_or_insert(index);
+            }
+        }
+    }
+
+    let mut candidates = Vec::with_capacity(2);
+    if in_string && !string_is_key {
+        // 去掉末尾未写完的转义序列
+        // Drop an unfinished escape sequence at the end
+        let mut end = text.len();
+        if let Some(backslash) = text.rfind('\\').filter(|&pos| pos + 6 > end) {
+            let escape = &text[backslash..];
+            let needed = if escape[1..].starts_with('u') { 6 } else { 2 };
+            let starts_escape = text[..backslash].chars().rev().take_while(|c| *c == '\\').count() % 2 == 0;
+            if starts_escape && escape.len() < needed {
+                end = backslash;
+            }
+        }
+        candidates.push(format!("{}\"{}", &text[..end], closers(&stack)));
+    } else if token_start.is_some() && !stack.is_empty() {
+        candidates.push(format!("{}{}", text, closers(&stack)));
+    }
+    candidates.push(format!("{}{}", &text[..safe_cut.0], safe_cut.1));
+
+    candidates
+        .iter()
+        .find_map(|candidate| serde_json::from_str(candidate).ok())
+}
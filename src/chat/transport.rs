@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use error_stack::{Report, Result};
+use futures::future::BoxFuture;
+use futures::{Stream, StreamExt, TryStreamExt};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use reqwest::{Client, StatusCode};
+use thiserror::Error;
+
+use crate::utils::common::redact::redact;
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum TransportError {
+    #[error("HTTP error with status code: {0}")]
+    Http(u16),
+
+    #[error("Request timed out")]
+    Timeout,
+
+    #[error("Network error: {0}")]
+    Network(String),
+
+    #[error("Invalid response body: {0}")]
+    Body(String),
+
+    #[error("Stream stalled: no data for {0:?}")]
+    Stalled(Duration),
+
+    #[error("Invalid HTTP header: {0}")]
+    InvalidHeader(String),
+
+    /// HTTP 429，附带从 `Retry-After` 解析出的等待时间
+    /// HTTP 429 with the delay parsed from `Retry-After`
+    #[error("Rate limited, retry after {0:?}")]
+    RateLimited(Option<Duration>),
+}
+
+/// 流式响应中的一块数据
+/// One chunk of a streaming response
+pub type StreamChunk = std::result::Result<Bytes, TransportError>;
+
+/// 响应字节流
+/// Response byte stream
+pub type ByteStream = Pin<Box<dyn Stream<Item = StreamChunk> + Send>>;
+
+/// 发往服务商的一次请求
+/// One request to a provider
+#[derive(Debug, Clone)]
+pub struct TransportRequest {
+    pub url: String,
+
+    pub api_key: String,
+
+    /// 附加的HTTP头，与默认的 `Content-Type`、`Authorization` 同名时替换它们
+    /// Extra HTTP headers, replacing the default `Content-Type` and `Authorization` on equal names
+    pub headers: HashMap<String, String>,
+
+    pub body: serde_json::Value,
+}
+
+/// 请求的传输层，默认基于 reqwest，可替换为 hyper、Unix 套接字或测试替身
+/// Transport of requests; reqwest by default, replaceable with hyper, unix sockets or test doubles
+pub trait ChatTransport: Debug + Send + Sync {
+    /// 发送请求并解析 JSON 响应
+    /// Send a request and parse the JSON response
+    fn send<'a>(&'a self, request: &'a TransportRequest) -> BoxFuture<'a, Result<serde_json::Value, TransportError>>;
+
+    /// 发送流式请求，返回 SSE 字节流
+    /// Send a streaming request and return the SSE byte stream
+    fn stream<'a>(&'a self, request: &'a TransportRequest) -> BoxFuture<'a, Result<ByteStream, TransportError>>;
+}
+
+/// 基于 reqwest 的默认传输层
+/// Default transport backed by reqwest
+#[derive(Debug, Clone, Default)]
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    async fn post(&self, request: &TransportRequest) -> Result<reqwest::Response, TransportError> {
+        let mut headers = HeaderMap::with_capacity(request.headers.len());
+        for (name, value) in &request.headers {
+            let name = HeaderName::try_from(name.as_str())
+                .map_err(|_| Report::new(TransportError::InvalidHeader(name.clone())))?;
+            let value = HeaderValue::try_from(value.as_str())
+                .map_err(|_| Report::new(TransportError::InvalidHeader(name.to_string())))?;
+            headers.insert(name, value);
+        }
+        let response = self
+            .client
+            .post(&request.url)
+            .header("Content-Type", "application/json")
+            .bearer_auth(&request.api_key)
+            .headers(headers)
+            .json(&request.body)
+            .send()
+            .await
+            .map_err(|e| Report::new(transport_error(&e)))?;
+
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, SystemTime::now()));
+            return Err(Report::new(TransportError::RateLimited(retry_after)));
+        }
+        response.error_for_status().map_err(|e| Report::new(transport_error(&e)))
+    }
+}
+
+/// 解析 `Retry-After`：秒数或 IMF-fixdate 格式的时间（如 `Sun, 06 Nov 1994 08:49:37 GMT`），已过去的时间为零
+/// Parse `Retry-After`: delay seconds or an IMF-fixdate (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`); a past date is zero
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = parts[..] else {
+        return None;
+    };
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|&m| m == month)? as u32 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut clock = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let target = u64::try_from(days).ok()? * 86400 + hour * 3600 + minute * 60 + second;
+    let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(Duration::from_secs(target.saturating_sub(now)))
+}
+
+/// 公历日期距 1970-01-01 的天数
+/// Days from 1970-01-01 to a civil date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn transport_error(e: &reqwest::Error) -> TransportError {
+    if let Some(status) = e.status() {
+        TransportError::Http(status.as_u16())
+    } else if e.is_timeout() {
+        TransportError::Timeout
+    } else if e.is_decode() || e.is_body() {
+        TransportError::Body(redact(&e.to_string()))
+    } else {
+        TransportError::Network(redact(&e.to_string()))
+    }
+}
+
+impl ChatTransport for ReqwestTransport {
+    fn send<'a>(&'a self, request: &'a TransportRequest) -> BoxFuture<'a, Result<serde_json::Value, TransportError>> {
+        Box::pin(async move {
+            self.post(request)
+                .await?
+                .json()
+                .await
+                .map_err(|e| Report::new(TransportError::Body(redact(&e.to_string()))))
+        })
+    }
+
+    fn stream<'a>(&'a self, request: &'a TransportRequest) -> BoxFuture<'a, Result<ByteStream, TransportError>> {
+        Box::pin(async move {
+            let response = self.post(request).await?;
+            let stream: ByteStream = Box::pin(response.bytes_stream().map_err(|e| transport_error(&e)));
+            Ok(stream)
+        })
+    }
+}
+
+/// 流停滞时的处理方式
+/// What to do when a stream stalls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallAction {
+    /// 以 `ChatError::StreamStalled` 结束
+    /// Fail with `ChatError::StreamStalled`
+    Abort,
+
+    /// 丢弃已收到的部分，从头重新请求，最多 `max_retries` 次
+    /// Drop what was received and request again from scratch, at most `max_retries` times
+    Retry { max_retries: u32 },
+}
+
+/// API来源的流停滞检测策略：超过 `timeout` 未收到任何字节即视为停滞
+/// Stall detection policy of an API source: a stream that yields no bytes for `timeout` is considered stalled
+///
+/// SSE 心跳注释（如 `: keep-alive`）同样算作收到数据
+/// SSE heartbeat comments (such as `: keep-alive`) count as received data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StallPolicy {
+    pub timeout: Duration,
+
+    pub action: StallAction,
+}
+
+impl StallPolicy {
+    pub fn abort(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            action: StallAction::Abort,
+        }
+    }
+
+    pub fn retry(timeout: Duration, max_retries: u32) -> Self {
+        Self {
+            timeout,
+            action: StallAction::Retry { max_retries },
+        }
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        match self.action {
+            StallAction::Abort => 0,
+            StallAction::Retry { max_retries } => max_retries,
+        }
+    }
+}
+
+/// 包装字节流：两块数据之间超过 `timeout` 时产出 `TransportError::Stalled` 并结束
+/// Wrap a byte stream so that a gap longer than `timeout` between chunks yields `TransportError::Stalled` and ends it
+pub fn detect_stalls(stream: ByteStream, timeout: Duration) -> ByteStream {
+    Box::pin(futures::stream::unfold(Some(stream), move |stream| async move {
+        let mut stream = stream?;
+        match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(chunk)) => Some((chunk, Some(stream))),
+            Ok(None) => None,
+            Err(_) => Some((Err(TransportError::Stalled(timeout)), None)),
+        }
+    }))
+}
@@ -0,0 +1,479 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::Stream;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tiktoken_rs::{bpe_for_model, o200k_base_singleton};
+use tracing::{field, Span};
+use crate::chat::chat_base::FinishReason;
+use crate::chat::transport::StreamChunk;
+
+/// 回答计时在消息元数据中的键
+/// Key of the answer timing in message metadata
+pub const TIMING_METADATA_KEY: &str = "timing";
+
+static USAGE_STATS: Lazy<DashMap<(String, String), ProviderStats>> = Lazy::new(DashMap::new);
+
+/// 令牌数的来源
+/// Where token counts come from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageSource {
+    /// 服务商返回的 `usage`
+    /// `usage` returned by the provider
+    #[default]
+    Reported,
+
+    /// 服务商未返回 `usage`，由本地分词器估算
+    /// The provider omitted `usage`; estimated with the local tokenizer
+    Estimated,
+}
+
+impl UsageSource {
+    /// 判断 `usage` 对象的来源
+    /// Tell where a `usage` object comes from
+    pub fn of(usage: &serde_json::Value) -> Self {
+        if usage["estimated"].as_bool() == Some(true) {
+            Self::Estimated
+        } else {
+            Self::Reported
+        }
+    }
+}
+
+/// 用本地分词器计算令牌数，未知模型使用 o200k_base
+/// Count tokens with the local tokenizer, falling back to o200k_base for unknown models
+pub fn count_tokens(model: &str, text: &str) -> u64 {
+    let bpe = bpe_for_model(model).unwrap_or_else(|_| o200k_base_singleton());
+    bpe.encode_with_special_tokens(text).len() as u64
+}
+
+/// 估算一次请求的 `usage`，结果带有 `"estimated": true` 标记
+/// Estimate the `usage` of one request; the result is marked with `"estimated": true`
+///
+/// # 参数 (Parameters)
+/// * `model` - 模型名，用于选择分词器
+///   - Model name, used to pick the tokenizer
+/// * `request_body` - 请求体，按其中的消息估算提示令牌数
+///   - Request body whose messages are counted as prompt tokens
+/// * `completion` - 回答内容
+///   - Answer content
+pub fn estimate_usage(model: &str, request_body: &serde_json::Value, completion: &str) -> serde_json::Value {
+    // 每条消息约 3 个格式令牌，回答前另有 3 个
+    // About 3 framing tokens per message plus 3 priming the reply
+    let prompt_tokens = request_body["messages"]
+        .as_array()
+        .map(|messages| {
+            messages
+                .iter()
+                .map(|m| count_tokens(model, m["content"].as_str().unwrap_or_default()) + 3)
+                .sum::<u64>()
+                + 3
+        })
+        .unwrap_or(0);
+    let completion_tokens = count_tokens(model, completion);
+
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+        "estimated": true,
+    })
+}
+
+/// 对话累计的令牌用量计数器，可在对话的克隆与派生任务之间共享
+/// Token usage counters of a chat, shareable between its clones and spawned tasks
+#[derive(Debug, Default)]
+pub struct UsageCounters {
+    prompt_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+    total_tokens: AtomicU64,
+}
+
+/// 某一时刻的累计用量
+/// Accumulated usage at one point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageSnapshot {
+    pub prompt_tokens: u64,
+
+    pub completion_tokens: u64,
+
+    pub total_tokens: u64,
+}
+
+impl UsageCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 计入一次回答的用量，按响应 `usage` 对象中的字段累加，缺少 `total_tokens` 时取前两者之和
+    /// Add the usage of one answer from a response `usage` object; a missing `total_tokens` is the sum of the other two
+    pub fn add_usage(&self, usage: &serde_json::Value) {
+        let prompt_tokens = usage["prompt_tokens"].as_u64().unwrap_or_default();
+        let completion_tokens = usage["completion_tokens"].as_u64().unwrap_or_default();
+        let total_tokens = usage["total_tokens"].as_u64().unwrap_or(prompt_tokens + completion_tokens);
+        self.add(prompt_tokens, completion_tokens, total_tokens);
+    }
+
+    pub fn add(&self, prompt_tokens: u64, completion_tokens: u64, total_tokens: u64) {
+        self.prompt_tokens.fetch_add(prompt_tokens, Ordering::Relaxed);
+        self.completion_tokens.fetch_add(completion_tokens, Ordering::Relaxed);
+        self.total_tokens.fetch_add(total_tokens, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> UsageSnapshot {
+        UsageSnapshot {
+            prompt_tokens: self.prompt_tokens.load(Ordering::Relaxed),
+            completion_tokens: self.completion_tokens.load(Ordering::Relaxed),
+            total_tokens: self.total_tokens.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 单次回答的计时数据
+/// Timing data of one answer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnswerTiming {
+    /// 从发出请求到回答结束的总耗时
+    /// Total time from sending the request to the end of the answer
+    pub latency_ms: u64,
+
+    /// 首个内容块的到达耗时，仅流式请求有值
+    /// Time until the first content chunk arrived; streaming requests only
+    pub time_to_first_token_ms: Option<u64>,
+
+    #[serde(default)]
+    pub prompt_tokens: Option<u64>,
+
+    pub completion_tokens: Option<u64>,
+
+    /// 生成速度，流式请求从首个内容块开始计时
+    /// Generation speed; streaming requests are timed from the first content chunk
+    pub tokens_per_second: Option<f64>,
+
+    #[serde(default)]
+    pub usage_source: UsageSource,
+
+    /// 服务商报告的费用（美元），例如 OpenRouter 的 `usage.cost`
+    /// Cost in USD reported by the provider, e.g. OpenRouter's `usage.cost`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+
+    /// 命中提示缓存的令牌数（`prompt_tokens_details.cached_tokens`）
+    /// Prompt tokens served from cache (`prompt_tokens_details.cached_tokens`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_tokens: Option<u64>,
+
+    /// 推理令牌数（`completion_tokens_details.reasoning_tokens`）
+    /// Reasoning tokens (`completion_tokens_details.reasoning_tokens`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_tokens: Option<u64>,
+}
+
+impl AnswerTiming {
+    /// # 参数 (Parameters)
+    /// * `latency_ms` - 总耗时
+    ///   - Total latency
+    /// * `time_to_first_token_ms` - 首个内容块的到达耗时
+    ///   - Time to the first content chunk
+    /// * `usage` - 响应中的 `usage` 对象
+    ///   - `usage` object of the response
+    pub fn new(latency_ms: u64, time_to_first_token_ms: Option<u64>, usage: &serde_json::Value) -> Self {
+        let completion_tokens = usage["completion_tokens"].as_u64();
+        let generation_ms = latency_ms.saturating_sub(time_to_first_token_ms.unwrap_or(0));
+        let tokens_per_second = completion_tokens
+            .filter(|_| generation_ms > 0)
+            .map(|tokens| tokens as f64 * 1000.0 / generation_ms as f64);
+
+        Self {
+            latency_ms,
+            time_to_first_token_ms,
+            prompt_tokens: usage["prompt_tokens"].as_u64(),
+            completion_tokens,
+            tokens_per_second,
+            usage_source: UsageSource::of(usage),
+            cost: usage["cost"].as_f64(),
+            cached_tokens: usage["prompt_tokens_details"]["cached_tokens"].as_u64(),
+            reasoning_tokens: usage["completion_tokens_details"]["reasoning_tokens"].as_u64(),
+        }
+    }
+}
+
+/// 某个服务商与模型的累计统计
+/// Accumulated statistics of one provider and model
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProviderStats {
+    pub base_url: String,
+
+    pub model: String,
+
+    pub requests: u64,
+
+    pub prompt_tokens: u64,
+
+    pub completion_tokens: u64,
+
+    pub total_latency_ms: u64,
+
+    /// 令牌数为本地估算的请求数
+    /// Requests whose token counts were estimated locally
+    pub estimated_requests: u64,
+
+    /// 服务商报告的累计费用（美元），未报告费用的请求不计入
+    /// Accumulated cost in USD reported by the provider; requests without a reported cost add nothing
+    #[serde(default)]
+    pub total_cost: f64,
+
+    #[serde(default)]
+    pub cached_tokens: u64,
+
+    #[serde(default)]
+    pub reasoning_tokens: u64,
+
+    total_time_to_first_token_ms: u64,
+
+    time_to_first_token_samples: u64,
+
+    total_tokens_per_second: f64,
+
+    tokens_per_second_samples: u64,
+}
+
+impl ProviderStats {
+    pub fn mean_latency_ms(&self) -> Option<f64> {
+        (self.requests > 0).then(|| self.total_latency_ms as f64 / self.requests as f64)
+    }
+
+    pub fn mean_time_to_first_token_ms(&self) -> Option<f64> {
+        (self.time_to_first_token_samples > 0).then(|| {
+            self.total_time_to_first_token_ms as f64 / self.time_to_first_token_samples as f64
+        })
+    }
+
+    pub fn mean_tokens_per_second(&self) -> Option<f64> {
+        (self.tokens_per_second_samples > 0)
+            .then(|| self.total_tokens_per_second / self.tokens_per_second_samples as f64)
+    }
+
+    fn add(&mut self, timing: &AnswerTiming) {
+        self.requests += 1;
+        self.prompt_tokens += timing.prompt_tokens.unwrap_or(0);
+        self.completion_tokens += timing.completion_tokens.unwrap_or(0);
+        self.total_latency_ms += timing.latency_ms;
+        if timing.usage_source == UsageSource::Estimated {
+            self.estimated_requests += 1;
+        }
+        self.total_cost += timing.cost.unwrap_or(0.0);
+        self.cached_tokens += timing.cached_tokens.unwrap_or(0);
+        self.reasoning_tokens += timing.reasoning_tokens.unwrap_or(0);
+        if let Some(ttft) = timing.time_to_first_token_ms {
+            self.total_time_to_first_token_ms += ttft;
+            self.time_to_first_token_samples += 1;
+        }
+        if let Some(speed) = timing.tokens_per_second {
+            self.total_tokens_per_second += speed;
+            self.tokens_per_second_samples += 1;
+        }
+    }
+}
+
+/// 全局用量统计，按 (base_url, model) 汇总每次回答的令牌与计时
+/// Global usage tracker aggregating tokens and timing of every answer per (base_url, model)
+pub struct UsageTracker;
+
+impl UsageTracker {
+    pub fn record(base_url: &str, model: &str, timing: &AnswerTiming) {
+        USAGE_STATS
+            .entry((base_url.to_string(), model.to_string()))
+            .or_insert_with(|| ProviderStats {
+                base_url: base_url.to_string(),
+                model: model.to_string(),
+                ..Default::default()
+            })
+            .add(timing);
+    }
+
+    pub fn stats(base_url: &str, model: &str) -> Option<ProviderStats> {
+        USAGE_STATS
+            .get(&(base_url.to_string(), model.to_string()))
+            .map(|stats| stats.clone())
+    }
+
+    /// 所有服务商的统计，按 base_url 与模型排序
+    /// Statistics of every provider, sorted by base_url and model
+    pub fn snapshot() -> Vec<ProviderStats> {
+        let mut stats: Vec<_> = USAGE_STATS.iter().map(|entry| entry.value().clone()).collect();
+        stats.sort_by(|a, b| (&a.base_url, &a.model).cmp(&(&b.base_url, &b.model)));
+        stats
+    }
+
+    pub fn reset() {
+        USAGE_STATS.clear();
+    }
+}
+
+/// 从响应中读出的回答信息，流式回答取各分块中的最新值
+/// Answer info read from a response; streaming answers keep the latest value seen across chunks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ResponseMeta {
+    pub(crate) system_fingerprint: Option<String>,
+
+    pub(crate) finish_reason: Option<FinishReason>,
+}
+
+impl ResponseMeta {
+    /// 读取完整响应或单个分块
+    /// Read a full response or a single chunk
+    pub(crate) fn observe(&mut self, json: &serde_json::Value) {
+        if let Some(fingerprint) = json["system_fingerprint"].as_str() {
+            self.system_fingerprint = Some(fingerprint.to_string());
+        }
+        if let Some(reason) = json["choices"][0]["finish_reason"].as_str() {
+            self.finish_reason = Some(FinishReason::parse(reason));
+        }
+    }
+}
+
+/// 为流式回答计时的包装流，结束时写入计时、计入用量统计与对话的用量计数器
+/// Stream wrapper timing a streaming answer; on completion it stores the timing and feeds the usage tracker and the usage counters of the chat
+pub(crate) struct TimedStream<S> {
+    inner: S,
+    base_url: String,
+    model: String,
+    started: Instant,
+    time_to_first_token_ms: Option<u64>,
+    request_body: serde_json::Value,
+    content: String,
+    usage: Option<serde_json::Value>,
+    timing: Option<Arc<Mutex<Option<AnswerTiming>>>>,
+    meta: ResponseMeta,
+    meta_slot: Arc<Mutex<ResponseMeta>>,
+    counters: Option<Arc<UsageCounters>>,
+    span: Option<Span>,
+}
+
+impl<S> TimedStream<S> {
+    pub(crate) fn new(
+        inner: S,
+        base_url: &str,
+        model: &str,
+        request_body: serde_json::Value,
+        started: Instant,
+        timing: Arc<Mutex<Option<AnswerTiming>>>,
+        meta_slot: Arc<Mutex<ResponseMeta>>,
+    ) -> Self {
+        Self {
+            inner,
+            base_url: base_url.to_string(),
+            model: model.to_string(),
+            started,
+            time_to_first_token_ms: None,
+            request_body,
+            content: String::new(),
+            usage: None,
+            timing: Some(timing),
+            meta: ResponseMeta::default(),
+            meta_slot,
+            counters: None,
+            span: None,
+        }
+    }
+
+    pub(crate) fn with_usage_counters(mut self, counters: Arc<UsageCounters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// 流读完或出错时在请求span上记录用量、延迟与结果
+    /// Record usage, latency and outcome on the request span once the stream is drained or fails
+    pub(crate) fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    fn inspect_chunk(&mut self, chunk: &Bytes) {
+        if self.time_to_first_token_ms.is_none() && !chunk.is_empty() {
+            self.time_to_first_token_ms = Some(self.started.elapsed().as_millis() as u64);
+        }
+
+        for line in String::from_utf8_lossy(chunk).lines() {
+            let Ok(json) =
+                serde_json::from_str::<serde_json::Value>(line.strip_prefix("data: ").unwrap_or(line))
+            else {
+                continue;
+            };
+            if let Some(content) = json["choices"][0]["delta"]["content"].as_str() {
+                self.content.push_str(content);
+            }
+            if let Some(usage) = json.get("usage").filter(|u| !u.is_null()) {
+                self.usage = Some(usage.clone());
+            }
+            self.meta.observe(&json);
+        }
+    }
+
+    fn finish(&mut self) {
+        let Some(slot) = self.timing.take() else {
+            return;
+        };
+        let latency_ms = self.started.elapsed().as_millis() as u64;
+        let usage = self
+            .usage
+            .take()
+            .unwrap_or_else(|| estimate_usage(&self.model, &self.request_body, &self.content));
+        let timing = AnswerTiming::new(latency_ms, self.time_to_first_token_ms, &usage);
+        if let Some(span) = self.span.take() {
+            for field in ["prompt_tokens", "completion_tokens", "total_tokens"] {
+                if let Some(tokens) = usage[field].as_u64() {
+                    span.record(field, tokens);
+                }
+            }
+            span.record("usage_source", field::debug(UsageSource::of(&usage)));
+            span.record("latency_ms", latency_ms);
+            span.record("outcome", "ok");
+        }
+        if let Some(counters) = &self.counters {
+            counters.add_usage(&usage);
+        }
+
+        UsageTracker::record(&self.base_url, &self.model, &timing);
+        if let Ok(mut slot) = slot.lock() {
+            *slot = Some(timing);
+        }
+        if let Ok(mut slot) = self.meta_slot.lock() {
+            *slot = std::mem::take(&mut self.meta);
+        }
+    }
+}
+
+impl<S> Stream for TimedStream<S>
+where
+    S: Stream<Item = StreamChunk> + Unpin,
+{
+    type Item = StreamChunk;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.inner).poll_next(cx);
+
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => self.inspect_chunk(chunk),
+            Poll::Ready(Some(Err(err))) => {
+                if let Some(span) = self.span.take() {
+                    span.record("latency_ms", self.started.elapsed().as_millis() as u64);
+                    span.record("outcome", err.to_string());
+                }
+            }
+            Poll::Ready(None) => self.finish(),
+            _ => {}
+        }
+
+        polled
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// 占位符的开始与结束标记，如 `{{user_name}}`
+/// Opening and closing markers of a placeholder, e.g. `{{user_name}}`
+pub const PLACEHOLDER_OPEN: &str = "{{";
+pub const PLACEHOLDER_CLOSE: &str = "}}";
+
+/// 提示变量的值
+/// Value of a prompt variable
+#[derive(Clone)]
+pub enum PromptVariable {
+    Value(String),
+
+    /// 每次构建请求时求值，如当前日期
+    /// Evaluated every time a request is built, e.g. the current date
+    Dynamic(Arc<dyn Fn() -> String + Send + Sync>),
+}
+
+impl PromptVariable {
+    pub fn resolve(&self) -> String {
+        match self {
+            Self::Value(value) => value.clone(),
+            Self::Dynamic(provider) => provider(),
+        }
+    }
+}
+
+impl Debug for PromptVariable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Value(value) => f.debug_tuple("Value").field(value).finish(),
+            Self::Dynamic(_) => f.write_str("Dynamic"),
+        }
+    }
+}
+
+/// 对话级的提示变量表，构建请求时替换系统与角色提示中的 `{{name}}` 占位符
+/// Chat-level prompt variables; `{{name}}` placeholders in system and character prompts are replaced
+/// when a request is built
+///
+/// 会话中保存的仍是模板，修改变量后下一次请求即使用新值；未定义的占位符保持原样
+/// The session keeps the template, so a changed variable is used from the next request on;
+/// undefined placeholders are left as they are
+#[derive(Debug, Clone, Default)]
+pub struct PromptVariables {
+    variables: HashMap<String, PromptVariable>,
+}
+
+impl PromptVariables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, name: &str, value: impl Into<String>) {
+        self.variables.insert(name.to_string(), PromptVariable::Value(value.into()));
+    }
+
+    pub fn set_dynamic(&mut self, name: &str, provider: impl Fn() -> String + Send + Sync + 'static) {
+        self.variables.insert(name.to_string(), PromptVariable::Dynamic(Arc::new(provider)));
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<PromptVariable> {
+        self.variables.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.variables.get(name).map(PromptVariable::resolve)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty()
+    }
+
+    /// 替换文本中已定义的占位符，动态变量在同一次渲染中只求值一次
+    /// Replace the defined placeholders in a text; a dynamic variable is evaluated once per render
+    pub fn render(&self, text: &str) -> String {
+        let mut resolved: HashMap<&str, String> = HashMap::new();
+        render_template(text, |name| {
+            let (key, variable) = self.variables.get_key_value(name)?;
+            Some(resolved.entry(key.as_str()).or_insert_with(|| variable.resolve()).clone())
+        })
+    }
+}
+
+/// 用 `lookup` 替换文本中的 `{{name}}` 占位符，名称两侧的空白会被忽略，`lookup` 返回 `None` 时保持原样
+/// Replace `{{name}}` placeholders in a text with `lookup`; whitespace around the name is ignored and
+/// placeholders are kept as they are when `lookup` returns `None`
+pub fn render_template(text: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(PLACEHOLDER_OPEN) {
+        let after_open = &rest[start + PLACEHOLDER_OPEN.len()..];
+        let Some(end) = after_open.find(PLACEHOLDER_CLOSE) else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        match lookup(after_open[..end].trim()) {
+            Some(value) => result.push_str(&value),
+            None => result.push_str(&rest[start..start + PLACEHOLDER_OPEN.len() + end + PLACEHOLDER_CLOSE.len()]),
+        }
+        rest = &after_open[end + PLACEHOLDER_CLOSE.len()..];
+    }
+    result.push_str(rest);
+    result
+}
@@ -1,5 +1,8 @@
 // 标准库
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 // 并发和同步原语
 use dashmap::DashMap;
@@ -9,8 +12,22 @@ use tokio::sync::Semaphore;
 // HTTP客户端
 use reqwest::Client;
 
+// 序列化
+use serde::Deserialize;
+
+// 项目内部模块
+use crate::chat::files::UploadedFile;
+use crate::chat::limits::ModelLimits;
+use crate::chat::mock::{MockApi, MockReply};
+use crate::chat::normalize::RoleRules;
+use crate::chat::rate_limit::{RateLimit, RateLimitBackend};
+use crate::chat::transport::{ChatTransport, StallPolicy};
+use crate::runtime::Runtime;
+use crate::utils::common::load_toml::load_toml;
+use crate::utils::common::redact::mask_secret;
+
 // 错误处理
-use error_stack::Result;
+use error_stack::{Report, Result, ResultExt};
 use thiserror::Error;
 
 /// 配置相关错误枚举
@@ -31,11 +48,42 @@ pub enum ConfigError {
     /// API information not found
     #[error("API info not found")]
     ApiInfoNotFound,
+
+    /// API来源未找到
+    /// API source not found
+    #[error("API source not found: {0}")]
+    ApiSourceNotFound(String),
+
+    /// HTTP客户端构建失败
+    /// Failed to build HTTP client
+    #[error("Failed to build HTTP client")]
+    ClientBuildError,
+
+    /// 回放记录加载失败
+    /// Failed to load replay records
+    #[error("Failed to load replay records")]
+    ReplayLoadError,
+
+    /// 配置文件无效
+    /// Invalid configuration file
+    #[error("Invalid config file: {0}")]
+    ConfigFileError(String),
+
+    /// 模型列表获取失败
+    /// Failed to fetch the model list
+    #[error("Failed to discover models of {0}")]
+    ModelDiscoveryError(String),
+
+    /// 配置的模型不在API来源的模型列表中
+    /// The configured model is not in the model list of its API source
+    #[error("Model {model} is not listed by {base_url}")]
+    ModelNotFound { model: String, base_url: String },
 }
 
 /// 模型能力枚举
 /// Model capability enum
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ModelCapability {
     /// 思考能力
     /// Thinking capability
@@ -48,6 +96,63 @@ pub enum ModelCapability {
     /// 长上下文处理能力
     /// Long context processing capability
     LongContext,
+
+    /// 视觉理解能力
+    /// Vision capability
+    Vision,
+
+    /// 文本嵌入能力
+    /// Embedding capability
+    Embedding,
+
+    /// 快速响应档位
+    /// Fast response tier
+    Fast,
+
+    /// 低成本档位
+    /// Cheap tier
+    Cheap,
+
+    /// 图像生成能力
+    /// Image generation capability
+    ImageGeneration,
+}
+
+/// 辅助任务枚举 - 库内部发起的模型调用
+/// Auxiliary task enum - model calls issued internally by the library
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum AuxiliaryTask {
+    /// 将文本整理为JSON
+    /// Format text into JSON
+    JsonFormat,
+
+    /// 从文本中解析函数调用
+    /// Parse function calls from text
+    FunctionCall,
+
+    /// 生成摘要
+    /// Summarization
+    Summarize,
+
+    /// 评判打分
+    /// Judging and scoring
+    Judge,
+
+    /// 多角色对话中选出下一位发言者
+    /// Picking the next speaker in a multi-character chat
+    SpeakerSelection,
+}
+
+impl AuxiliaryTask {
+    /// 未配置映射时使用的默认能力
+    /// Default capability used when no mapping is configured
+    pub fn default_capability(&self) -> ModelCapability {
+        match self {
+            Self::JsonFormat | Self::FunctionCall => ModelCapability::ToolUse,
+            Self::Summarize | Self::SpeakerSelection => ModelCapability::Cheap,
+            Self::Judge => ModelCapability::Think,
+        }
+    }
 }
 
 /// API来源结构体
@@ -61,11 +166,90 @@ pub struct ApiSource {
     /// 并行请求数量限制
     /// Parallel request limit
     pub parallelism: usize,
+
+    /// 该来源下所有API信息共享的HTTP客户端
+    /// HTTP client shared by all API infos of this source
+    pub client: Client,
+
+    /// 该来源的每个请求都附带的HTTP头，例如 OpenRouter 的 `HTTP-Referer`
+    /// HTTP headers sent with every request to this source, e.g. OpenRouter's `HTTP-Referer`
+    pub headers: HashMap<String, String>,
+}
+
+impl std::fmt::Debug for ApiInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 调试输出中遮盖API密钥
+        // Mask the API key in debug output
+        f.debug_struct("ApiInfo")
+            .field("model", &self.model)
+            .field("base_url", &self.base_url)
+            .field("api_key", &mask_secret(&self.api_key))
+            .field("client", &self.client)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// HTTP客户端连接池配置
+/// HTTP client connection pool options
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    /// 每个主机保留的最大空闲连接数
+    /// Maximum idle connections kept per host
+    pub pool_max_idle_per_host: usize,
+
+    /// 空闲连接的超时时间
+    /// Timeout for idle pooled connections
+    pub pool_idle_timeout: Option<Duration>,
+
+    /// TCP keep-alive 间隔
+    /// TCP keep-alive interval
+    pub tcp_keepalive: Option<Duration>,
+
+    /// 是否直接使用HTTP/2
+    /// Whether to use HTTP/2 with prior knowledge
+    pub http2_prior_knowledge: bool,
+
+    /// 单个请求的总超时时间
+    /// Total timeout of a single request
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: usize::MAX,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            http2_prior_knowledge: false,
+            timeout: None,
+        }
+    }
+}
+
+impl ClientOptions {
+    /// 根据配置构建HTTP客户端
+    /// Build an HTTP client from the options
+    pub fn build_client(&self) -> Result<Client, ConfigError> {
+        let mut builder = Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive);
+
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        builder.build().change_context(ConfigError::ClientBuildError)
+    }
 }
 
 /// API信息结构体
 /// API information structure
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct ApiInfo {
     /// 模型名称
     /// Model name
@@ -79,14 +263,108 @@ pub struct ApiInfo {
     /// API key
     pub api_key: String,
     
-    /// HTTP客户端实例
-    /// HTTP client instance
+    /// HTTP客户端实例（与API来源共享连接池）
+    /// HTTP client instance (shares the connection pool of its API source)
     pub client: Client,
+
+    /// 请求附带的HTTP头：来源的头加上该API自己的头，同名时以API的为准
+    /// HTTP headers sent with requests: the source's headers plus this API's own, the API winning on equal names
+    pub headers: HashMap<String, String>,
+}
+
+/// 配置文件中的API来源
+/// API source in a configuration file
+#[derive(Clone, Debug, Deserialize)]
+pub struct SourceEntry {
+    pub name: String,
+
+    pub base_url: String,
+
+    #[serde(default = "default_parallelism")]
+    pub parallelism: usize,
+
+    /// 每分钟限额，字段为 `requests_per_minute` 与 `tokens_per_minute`
+    /// Per-minute quota, given as `requests_per_minute` and `tokens_per_minute`
+    #[serde(flatten)]
+    pub rate_limit: RateLimit,
+
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+fn default_parallelism() -> usize {
+    8
+}
+
+/// 配置文件中的API信息，密钥可直接给出或从环境变量读取
+/// API info in a configuration file; the key is given inline or read from an environment variable
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiEntry {
+    pub name: String,
+
+    pub model: String,
+
+    pub capability: ModelCapability,
+
+    pub source: String,
+
+    #[serde(default)]
+    pub api_key: Option<String>,
+
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+
+    /// 模型的上下文长度上限（令牌数）
+    /// Context limit of the model in tokens
+    #[serde(default)]
+    pub context_limit: Option<u64>,
+
+    /// 模型单次回答的令牌数上限
+    /// Output token cap of the model
+    #[serde(default)]
+    pub max_output_tokens: Option<u64>,
+
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// TOML 配置文件
+/// TOML configuration file
+///
+/// ```toml
+/// [[source]]
+/// name = "openai"
+/// base_url = "https://api.openai.com/v1/chat/completions"
+/// parallelism = 8
+/// requests_per_minute = 500
+/// tokens_per_minute = 200000
+/// headers = { "OpenAI-Organization" = "org-123" }
+///
+/// [[api]]
+/// name = "gpt-4o"
+/// model = "gpt-4o"
+/// capability = "tool_use"
+/// source = "openai"
+/// api_key_env = "OPENAI_API_KEY"
+/// context_limit = 128000
+/// max_output_tokens = 16384
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ConfigFile {
+    #[serde(default, rename = "source")]
+    pub sources: Vec<SourceEntry>,
+
+    #[serde(default, rename = "api")]
+    pub apis: Vec<ApiEntry>,
 }
 
 /// 配置管理结构体
 /// Configuration management structure
-#[derive(Clone, Debug)]
+///
+/// 关联函数操作默认运行时的配置，其他运行时的配置通过 [`Runtime`] 的同名方法操作
+/// The associated functions work on the default runtime's configuration; other runtimes' configurations are handled
+/// through the methods of the same name on [`Runtime`]
+#[derive(Clone, Debug, Default)]
 pub struct Config {
     /// API来源映射表 - 存储名称到API来源的映射
     /// API source map - stores mappings from name to API source
@@ -95,6 +373,10 @@ pub struct Config {
     /// API信息映射表 - 存储(名称,能力)到API信息的映射
     /// API info map - stores mappings from (name, capability) to API info
     pub api_info: DashMap<(String, ModelCapability), ApiInfo>,
+
+    /// 辅助任务能力映射表 - 存储辅助任务到模型能力的映射
+    /// Auxiliary capability map - stores mappings from auxiliary task to model capability
+    pub auxiliary_capability: DashMap<AuxiliaryTask, ModelCapability>,
 }
 
 impl Config {
@@ -109,19 +391,329 @@ impl Config {
     /// * `parallelism` - 并行度（允许的并发请求数）
     ///                 - Parallelism (allowed concurrent requests)
     pub fn add_api_source(name: &str, base_url: &str, parallelism: usize) {
-        // 向配置中添加API来源
-        // Add API source to configuration
-        CFG.api_source.insert(
-            name.to_string(),
-            ApiSource {
-                base_url: base_url.to_string(),
-                parallelism,
-            },
-        );
+        Self::insert_api_source(name, base_url, parallelism, Client::new());
+    }
+
+    /// 添加API来源并指定HTTP客户端连接池配置
+    /// Add API source with HTTP client connection pool options
+    ///
+    /// # 参数 (Parameters)
+    /// * `name` - API来源名称
+    ///   - API source name
+    /// * `base_url` - API基础URL
+    ///   - API base URL
+    /// * `parallelism` - 并行度（允许的并发请求数）
+    ///   - Parallelism (allowed concurrent requests)
+    /// * `options` - HTTP客户端配置
+    ///   - HTTP client options
+    pub fn add_api_source_with_client_options(
+        name: &str,
+        base_url: &str,
+        parallelism: usize,
+        options: &ClientOptions,
+    ) -> Result<(), ConfigError> {
+        let client = options.build_client()?;
+        Self::insert_api_source(name, base_url, parallelism, client);
+        Ok(())
+    }
+
+    /// 添加回放来源，请求不会发出而是由记录文件应答
+    /// Add a replay source; requests are answered from recordings instead of the network
+    ///
+    /// # 参数 (Parameters)
+    /// * `name` - API来源名称
+    ///   - API source name
+    /// * `records_path` - 记录器生成的 JSONL 文件或目录
+    ///   - JSONL file or directory produced by the recorder
+    /// * `parallelism` - 并行度
+    ///   - Parallelism
+    pub fn add_replay_source(
+        name: &str,
+        records_path: &str,
+        parallelism: usize,
+    ) -> Result<(), ConfigError> {
+        Runtime::default_ref().add_replay_source(name, records_path, parallelism)
+    }
+
+    /// 添加模拟API，名称同时作为来源名与API名，并服务于所有模型能力
+    /// Add a mock API; the name is used as both source and API name and serves every capability
+    ///
+    /// # 参数 (Parameters)
+    /// * `name` - 模拟API名称
+    ///   - Mock API name
+    /// * `responder` - 根据请求体生成响应的函数
+    ///   - Function producing a reply from the request body
+    pub fn add_mock(
+        name: &str,
+        responder: impl Fn(&serde_json::Value) -> MockReply + Send + Sync + 'static,
+    ) -> MockApi {
+        Runtime::default_ref().add_mock(name, responder)
+    }
+
+    /// 以指定能力添加配置好的模拟API
+    /// Add a configured mock API under a given capability
+    ///
+    /// # 参数 (Parameters)
+    /// * `name` - 模拟API名称
+    ///   - Mock API name
+    /// * `capability` - 模型能力
+    ///   - Model capability
+    /// * `mock` - 模拟API（延迟、分块、错误注入等）
+    ///   - Mock API (latency, chunking, error injection, ...)
+    pub fn add_mock_api(name: &str, capability: ModelCapability, mock: MockApi) {
+        Runtime::default_ref().add_mock_api(name, capability, mock);
+    }
+
+    fn insert_api_source(name: &str, base_url: &str, parallelism: usize, client: Client) {
+        Runtime::default_ref().insert_api_source(name, base_url, parallelism, client);
+    }
+
+    /// 为API来源启用优先级调度
+    /// Enable priority scheduling for an API source
+    ///
+    /// 启用后，并发许可饱和时高优先级请求会先于后台请求获得许可
+    /// Once enabled, high priority requests are served before background ones when permits run out
+    ///
+    /// # 参数 (Parameters)
+    /// * `source_name` - API来源名称
+    ///   - API source name
+    pub fn enable_priority_scheduling(source_name: &str) -> Result<(), ConfigError> {
+        Runtime::default_ref().enable_priority_scheduling(source_name)
+    }
+
+    /// 设置API来源对消息角色顺序的要求，未设置时原样发送
+    /// Set the role rules of an API source; messages are sent as is when unset
+    ///
+    /// # 参数 (Parameters)
+    /// * `source_name` - API来源名称
+    ///   - API source name
+    /// * `rules` - 角色规则
+    ///   - Role rules
+    pub fn set_role_rules(source_name: &str, rules: RoleRules) -> Result<(), ConfigError> {
+        Runtime::default_ref().set_role_rules(source_name, rules)
+    }
+
+    /// 获取API来源的角色规则
+    /// Get the role rules of an API source
+    ///
+    /// # 参数 (Parameters)
+    /// * `base_url` - API基础URL
+    ///   - API base URL
+    pub fn get_role_rules(base_url: &str) -> RoleRules {
+        Runtime::default_ref().get_role_rules(base_url)
+    }
+
+    /// 标记API来源是否忽略 `response_format`，被标记的来源改用提示注入加修复的方式获取结构化输出
+    /// Mark whether an API source ignores `response_format`; marked sources get structured output
+    /// through prompt injection plus repair instead
+    ///
+    /// # 参数 (Parameters)
+    /// * `base_url` - API基础URL
+    ///   - API base URL
+    /// * `ignored` - 是否忽略
+    ///   - Whether it is ignored
+    pub fn set_response_format_ignored(base_url: &str, ignored: bool) {
+        Runtime::default_ref().set_response_format_ignored(base_url, ignored);
+    }
+
+    /// API来源是否已知会忽略 `response_format`
+    /// Whether an API source is known to ignore `response_format`
+    pub fn response_format_ignored(base_url: &str) -> bool {
+        Runtime::default_ref().is_response_format_ignored(base_url)
+    }
+
+    /// 替换API来源的传输层，未设置时使用共享连接池的 reqwest 传输层
+    /// Replace the transport of an API source; the reqwest transport on its shared connection pool is used when unset
+    ///
+    /// # 参数 (Parameters)
+    /// * `source_name` - API来源名称
+    ///   - API source name
+    /// * `transport` - 传输层，例如 hyper、Unix 套接字或测试替身
+    ///   - Transport, e.g. hyper, a unix socket or a test double
+    pub fn set_transport(source_name: &str, transport: impl ChatTransport + 'static) -> Result<(), ConfigError> {
+        Runtime::default_ref().set_transport(source_name, transport)
+    }
+
+    /// 获取API来源替换后的传输层
+    /// Get the replaced transport of an API source
+    ///
+    /// # 参数 (Parameters)
+    /// * `base_url` - API基础URL
+    ///   - API base URL
+    pub fn get_transport(base_url: &str) -> Option<Arc<dyn ChatTransport>> {
+        Runtime::default_ref().get_transport(base_url)
+    }
+
+    /// 设置API来源的流停滞检测策略，未设置时流式请求不做停滞检测
+    /// Set the stream stall policy of an API source; streams are not watched for stalls when unset
+    ///
+    /// # 参数 (Parameters)
+    /// * `source_name` - API来源名称
+    ///   - API source name
+    /// * `policy` - 停滞超时与处理方式
+    ///   - Stall timeout and action
+    pub fn set_stall_policy(source_name: &str, policy: StallPolicy) -> Result<(), ConfigError> {
+        Runtime::default_ref().set_stall_policy(source_name, policy)
+    }
+
+    /// 获取API来源的流停滞检测策略
+    /// Get the stream stall policy of an API source
+    ///
+    /// # 参数 (Parameters)
+    /// * `base_url` - API基础URL
+    ///   - API base URL
+    pub fn get_stall_policy(base_url: &str) -> Option<StallPolicy> {
+        Runtime::default_ref().get_stall_policy(base_url)
+    }
+
+    /// 设置API来源的每分钟请求数与令牌数限额，超出时请求等到下一个窗口再发出
+    /// Set the per-minute request and token quota of an API source; requests over quota wait for the next window
+    ///
+    /// # 参数 (Parameters)
+    /// * `source_name` - API来源名称
+    ///   - API source name
+    /// * `limit` - 每分钟限额
+    ///   - Per-minute quota
+    pub fn set_rate_limit(source_name: &str, limit: RateLimit) -> Result<(), ConfigError> {
+        Runtime::default_ref().set_source_rate_limit(source_name, limit)
+    }
+
+    /// 获取API来源的每分钟限额，未设置时为 `None`
+    /// Get the per-minute quota of an API source; `None` when unset
+    ///
+    /// # 参数 (Parameters)
+    /// * `base_url` - API基础URL
+    ///   - API base URL
+    pub fn get_rate_limit(base_url: &str) -> Option<RateLimit> {
+        Runtime::default_ref().get_source_rate_limit(base_url)
+    }
+
+    /// 替换API来源的限额计数后端，多个服务副本使用同一个共享后端即可协调限额
+    /// Replace the quota counter backend of an API source; replicas sharing one backend coordinate their quota
+    ///
+    /// # 参数 (Parameters)
+    /// * `source_name` - API来源名称
+    ///   - API source name
+    /// * `backend` - 计数后端，例如 Redis
+    ///   - Counter backend, e.g. Redis
+    pub fn set_rate_limit_backend(
+        source_name: &str,
+        backend: impl RateLimitBackend + 'static,
+    ) -> Result<(), ConfigError> {
+        Runtime::default_ref().set_rate_limit_backend(source_name, backend)
+    }
+
+    /// 获取API来源的限额计数后端，未替换时为进程内计数
+    /// Get the quota counter backend of an API source; the in-process counters when not replaced
+    pub fn get_rate_limit_backend(base_url: &str) -> Arc<dyn RateLimitBackend> {
+        Runtime::default_ref().get_rate_limit_backend(base_url)
+    }
+
+    /// 设置模型的上下文长度上限，覆盖内置表；发送前校验请求的令牌数不超过该上限
+    /// Set the context limit of a model, overriding the built-in table; requests are checked against it before sending
+    ///
+    /// # 参数 (Parameters)
+    /// * `model` - 模型名称
+    ///   - Model name
+    /// * `context_limit` - 提示与回答合计的令牌数上限
+    ///   - Token limit for the prompt and the answer together
+    pub fn set_context_limit(model: &str, context_limit: u64) {
+        Runtime::default_ref().set_context_limit(model, context_limit);
+    }
+
+    /// 获取模型的上下文长度上限，手动设置的优先于内置表，都没有时为 `None`
+    /// Get the context limit of a model, a manual setting winning over the built-in table; `None` when neither knows it
+    pub fn get_context_limit(model: &str) -> Option<u64> {
+        Self::get_model_limits(model).context_window
+    }
+
+    /// 设置模型单次回答的令牌数上限，覆盖内置表
+    /// Set the output token cap of a model, overriding the built-in table
+    ///
+    /// # 参数 (Parameters)
+    /// * `model` - 模型名称
+    ///   - Model name
+    /// * `max_output_tokens` - 单次回答的令牌数上限
+    ///   - Token limit of a single answer
+    pub fn set_max_output_tokens(model: &str, max_output_tokens: u64) {
+        Runtime::default_ref().set_max_output_tokens(model, max_output_tokens);
+    }
+
+    /// 获取模型的上下文长度与回答上限：手动设置的项优先，其余取自内置表
+    /// Get the context window and output cap of a model: manually set items win, the rest come from the built-in table
+    pub fn get_model_limits(model: &str) -> ModelLimits {
+        Runtime::default_ref().get_model_limits(model)
+    }
+
+    /// 记录上传到API来源的本地文件
+    /// Record a local file uploaded to an API source
+    ///
+    /// # 参数 (Parameters)
+    /// * `base_url` - API基础URL
+    ///   - API base URL
+    /// * `path` - 本地文件路径
+    ///   - Local file path
+    /// * `file` - 服务商返回的文件信息
+    ///   - File info returned by the provider
+    pub fn record_uploaded_file(base_url: &str, path: &Path, file: UploadedFile) {
+        Runtime::default_ref().record_uploaded_file(base_url, path, file);
+    }
+
+    /// 获取本地文件在API来源上的上传记录
+    /// Get the upload record of a local file on an API source
+    pub fn get_uploaded_file(base_url: &str, path: &Path) -> Option<UploadedFile> {
+        Runtime::default_ref().get_uploaded_file(base_url, path)
+    }
+
+    /// 移除API来源上某个文件 ID 的上传记录
+    /// Drop the upload record of a file ID on an API source
+    pub fn forget_uploaded_file(base_url: &str, file_id: &str) {
+        Runtime::default_ref().forget_uploaded_file(base_url, file_id);
+    }
+
+    /// 从 TOML 配置文件添加API来源与API信息，返回按文件顺序排列的API名称
+    /// Add the API sources and infos of a TOML configuration file; returns the API names in file order
+    ///
+    /// # 参数 (Parameters)
+    /// * `path` - 配置文件路径，格式见 [`ConfigFile`]
+    ///   - Path of the configuration file, see [`ConfigFile`] for the format
+    pub fn load_file(path: &str) -> Result<Vec<String>, ConfigError> {
+        let file: ConfigFile =
+            load_toml(path).change_context_lazy(|| ConfigError::ConfigFileError(path.to_string()))?;
+
+        for source in &file.sources {
+            Self::add_api_source(&source.name, &source.base_url, source.parallelism);
+            if !source.rate_limit.is_unlimited() {
+                Self::set_rate_limit(&source.name, source.rate_limit)?;
+            }
+            if !source.headers.is_empty() {
+                Self::set_source_headers(&source.name, source.headers.clone())?;
+            }
+        }
+        for api in &file.apis {
+            if !CFG.api_source.contains_key(&api.source) {
+                return Err(ConfigError::ApiSourceNotFound(api.source.clone()).into());
+            }
+            let api_key = match (&api.api_key, &api.api_key_env) {
+                (Some(api_key), _) => api_key.clone(),
+                (None, Some(var)) => std::env::var(var).map_err(|_| {
+                    ConfigError::ConfigFileError(format!("environment variable {var} is not set"))
+                })?,
+                (None, None) => String::new(),
+            };
+            Self::add_api_info(&api.name, &api.model, api.capability.clone(), &api.source, &api_key);
+            if let Some(context_limit) = api.context_limit {
+                Self::set_context_limit(&api.model, context_limit);
+            }
+            if let Some(max_output_tokens) = api.max_output_tokens {
+                Self::set_max_output_tokens(&api.model, max_output_tokens);
+            }
+            if !api.headers.is_empty() {
+                Self::set_api_headers(&api.name, api.headers.clone())?;
+            }
+        }
 
-        // 为该API来源创建信号量用于控制并发
-        // Create semaphore for this API source to control concurrency
-        THREAD_POOL.insert(base_url.to_string(), Arc::new(Semaphore::new(parallelism)));
+        Ok(file.apis.into_iter().map(|api| api.name).collect())
     }
 
     /// 添加API信息
@@ -145,26 +737,48 @@ impl Config {
         source_name: &str,
         api_key: &str,
     ) {
-        // 获取API来源的基础URL
-        // Get the base URL of API source
-        let base_url = CFG
-            .api_source
-            .get(source_name)
-            .unwrap()
-            .base_url
-            .clone();
-        
-        // 向配置中添加API信息
-        // Add API information to configuration
-        CFG.api_info.insert(
-            (name.to_string(), capability),
-            ApiInfo {
-                model: model.to_string(),
-                base_url,
-                api_key: api_key.to_string(),
-                client: Client::new(),
-            },
-        );
+        Runtime::default_ref()
+            .add_api_info(name, model, capability, source_name, api_key)
+            .unwrap();
+    }
+
+    /// 设置API来源每个请求附带的HTTP头，同名的头被替换；已添加的该来源API也会带上这些头
+    /// Set HTTP headers sent with every request to an API source, replacing headers of the same name; APIs already
+    /// added from this source get them too
+    ///
+    /// # 参数 (Parameters)
+    /// * `source_name` - API来源名称
+    ///   - API source name
+    /// * `headers` - 头名称到值的映射
+    ///   - Map from header name to value
+    pub fn set_source_headers(source_name: &str, headers: HashMap<String, String>) -> Result<(), ConfigError> {
+        let base_url = {
+            let mut source = CFG
+                .api_source
+                .get_mut(source_name)
+                .ok_or(ConfigError::ApiSourceNotFound(source_name.to_string()))?;
+            source.headers.extend(headers.clone());
+            source.base_url.clone()
+        };
+        for mut entry in CFG.api_info.iter_mut().filter(|entry| entry.base_url == base_url) {
+            entry.headers.extend(headers.clone());
+        }
+        Ok(())
+    }
+
+    /// 设置某个API（所有能力下同名的条目）附带的HTTP头，同名的头被替换
+    /// Set HTTP headers sent by an API (every entry of that name across capabilities), replacing headers of the same name
+    pub fn set_api_headers(name: &str, headers: HashMap<String, String>) -> Result<(), ConfigError> {
+        let mut found = false;
+        for mut entry in CFG.api_info.iter_mut().filter(|entry| entry.key().0 == name) {
+            entry.headers.extend(headers.clone());
+            found = true;
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(ConfigError::ApiInfoNotFound.into())
+        }
     }
 
     /// 根据名称获取API信息
@@ -178,14 +792,7 @@ impl Config {
     /// * `Result<ApiInfo, ConfigError>` - 成功返回API信息，失败返回配置错误
     ///                                  - Returns API info on success, config error on failure
     pub fn get_api_info_with_name(name: String) -> Result<ApiInfo, ConfigError> {
-        // 在API信息映射表中查找匹配的条目
-        // Find matching entry in API info map
-        CFG.api_info
-            .iter()
-            .find_map(|entry| {
-                (entry.key().0 == name).then(|| entry.value().clone())
-            })
-            .ok_or(ConfigError::ApiInfoNotFound.into())
+        Runtime::default_ref().get_api_info_with_name(&name)
     }
 
     /// 根据模型能力获取API信息
@@ -201,26 +808,72 @@ impl Config {
     pub fn get_api_info_with_capability(
         capability: ModelCapability,
     ) -> Result<ApiInfo, ConfigError> {
-        // 在API信息映射表中查找匹配的条目
-        // Find matching entry in API info map
-        CFG.api_info
-            .iter()
-            .find_map(|entry| {
-                (entry.key().1 == capability).then(|| entry.value().clone())
-            })
-            .ok_or(ConfigError::ApiInfoNotFound.into())
+        Runtime::default_ref().get_api_info_with_capability(capability)
+    }
+
+    /// 查询API来源的模型列表接口（将 `/chat/completions` 替换为 `/models`）并缓存到默认运行时，返回模型名称
+    /// Query the model list endpoint of an API source (`/chat/completions` becomes `/models`) and cache the result in
+    /// the default runtime; returns the model names
+    ///
+    /// 缓存之后，[`get_api_info_with_capability`](Self::get_api_info_with_capability) 会校验配置的模型确实存在
+    /// Once cached, [`get_api_info_with_capability`](Self::get_api_info_with_capability) verifies that the configured
+    /// model actually exists
+    ///
+    /// # 参数 (Parameters)
+    /// * `source_name` - API来源名称
+    ///   - API source name
+    pub async fn discover_models(source_name: &str) -> Result<Vec<String>, ConfigError> {
+        Runtime::default_ref().discover_models(source_name).await
     }
-}
 
-/// 全局配置实例
-/// Global configuration instance
-pub static CFG: Lazy<Config> = Lazy::new(|| {
-    Config {
-        api_source: DashMap::new(),
-        api_info: DashMap::new(),
+    /// 获取API来源已发现的模型名称，尚未发现时为 `None`
+    /// Get the discovered model names of an API source; `None` before discovery
+    ///
+    /// # 参数 (Parameters)
+    /// * `base_url` - API基础URL
+    ///   - API base URL
+    pub fn get_discovered_models(base_url: &str) -> Option<Vec<String>> {
+        Runtime::default_ref().get_discovered_models(base_url)
     }
-});
 
-/// 全局线程池（信号量池）- 用于控制对不同API来源的并发请求
-/// Global thread pool (semaphore pool) - used to control concurrent requests to different API sources
-pub static THREAD_POOL: Lazy<DashMap<String, Arc<Semaphore>>> = Lazy::new(|| DashMap::new());
\ No newline at end of file
+    /// 设置辅助任务使用的模型能力
+    /// Set the model capability used by an auxiliary task
+    ///
+    /// # 参数 (Parameters)
+    /// * `task` - 辅助任务
+    ///   - Auxiliary task
+    /// * `capability` - 模型能力
+    ///   - Model capability
+    pub fn set_auxiliary_capability(task: AuxiliaryTask, capability: ModelCapability) {
+        Runtime::default_ref().set_auxiliary_capability(task, capability);
+    }
+
+    /// 获取辅助任务使用的模型能力，未配置时返回默认能力
+    /// Get the model capability used by an auxiliary task, falling back to its default
+    pub fn get_auxiliary_capability(task: &AuxiliaryTask) -> ModelCapability {
+        Runtime::default_ref().get_auxiliary_capability(task)
+    }
+
+    /// 根据辅助任务获取API信息
+    /// Get API information by auxiliary task
+    ///
+    /// 先查找映射的能力，找不到时回退到工具使用能力
+    /// Looks up the mapped capability first, falling back to the tool-use capability
+    ///
+    /// # 返回 (Returns)
+    /// * `Result<ApiInfo, ConfigError>` - 成功返回API信息，失败返回配置错误
+    ///   - Returns API info on success, config error on failure
+    pub fn get_api_info_with_auxiliary_task(task: &AuxiliaryTask) -> Result<ApiInfo, ConfigError> {
+        Runtime::default_ref().get_api_info_with_auxiliary_task(task)
+    }
+}
+
+/// 全局配置实例，即默认运行时的配置
+/// Global configuration instance, i.e. the default runtime's configuration
+pub static CFG: Lazy<&'static Config> = Lazy::new(|| &Runtime::default_ref().config);
+
+/// 全局线程池（信号量池）- 用于控制对不同API来源的并发请求，即默认运行时的信号量
+/// Global thread pool (semaphore pool) - used to control concurrent requests to different API sources, i.e. the
+/// default runtime's semaphores
+pub static THREAD_POOL: Lazy<&'static DashMap<String, Arc<Semaphore>>> =
+    Lazy::new(|| &Runtime::default_ref().semaphores);
@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Instant;
+
+use error_stack::Result;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::chat::chat_base::ChatError;
+use crate::chat::chat_single::SingleChat;
+use crate::chat::judge::Judge;
+use crate::chat::usage::UsageSnapshot;
+use crate::pipeline::INPUT_PLACEHOLDER;
+
+/// 参与比较的一组配置：模板对话（模型与系统提示）加提示模板
+/// One configuration under comparison: a template chat (model and system prompt) plus a prompt template
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub name: String,
+
+    chat: SingleChat,
+
+    prompt: String,
+}
+
+impl Variant {
+    /// # 参数 (Parameters)
+    /// * `name` - 报告中显示的名称
+    ///   - Name shown in the report
+    /// * `chat` - 模板对话，每个输入使用它的一个副本
+    ///   - Template chat; every input uses a copy of it
+    /// * `prompt` - 提示模板，`{input}` 处替换为输入；为空时直接发送输入
+    ///   - Prompt template; `{input}` is replaced with the input, and the input is sent as is when empty
+    pub fn new(name: &str, chat: SingleChat, prompt: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            chat,
+            prompt: prompt.to_string(),
+        }
+    }
+
+    fn render_prompt(&self, input: &str) -> String {
+        if self.prompt.is_empty() {
+            input.to_string()
+        } else if self.prompt.contains(INPUT_PLACEHOLDER) {
+            self.prompt.replace(INPUT_PLACEHOLDER, input)
+        } else {
+            format!("{}\n\n{}", self.prompt, input)
+        }
+    }
+
+    async fn run(&self, input: &str) -> VariantOutput {
+        let mut chat = self.chat.clone();
+        chat.base.detach_counters();
+        let started = Instant::now();
+        let answer = self.answer(&mut chat, input).await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        let cost = chat.base.last_timing().and_then(|timing| timing.cost);
+        let usage = chat.base.usage_snapshot();
+        match answer {
+            Ok(answer) => VariantOutput {
+                answer: Some(answer),
+                error: None,
+                scores: None,
+                latency_ms,
+                usage,
+                cost,
+            },
+            Err(report) => {
+                warn!("eval: {} failed: {report:?}", self.name);
+                VariantOutput {
+                    answer: None,
+                    error: Some(report.current_context().to_string()),
+                    scores: None,
+                    latency_ms,
+                    usage,
+                    cost,
+                }
+            }
+        }
+    }
+
+    async fn answer(&self, chat: &mut SingleChat, input: &str) -> Result<String, ChatError> {
+        let request_body = chat.get_req_body(&self.render_prompt(input)).await?;
+        chat.get_content_from_req_body(request_body).await
+    }
+}
+
+/// 一组配置对一个输入的输出
+/// Output of one configuration for one input
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VariantOutput {
+    pub answer: Option<String>,
+
+    /// 生成或评审失败时的错误说明
+    /// Error description when generating or judging failed
+    pub error: Option<String>,
+
+    /// 评审给出的分数，未设置评审或评审失败时为 `None`
+    /// Scores given by the judge; `None` without a judge or when judging failed
+    pub scores: Option<serde_json::Value>,
+
+    pub latency_ms: u64,
+
+    pub usage: UsageSnapshot,
+
+    /// 服务商报告的费用（美元）
+    /// Cost in USD reported by the provider
+    pub cost: Option<f64>,
+}
+
+/// 一个输入上两组配置的比较结果
+/// Outcome of comparing the two configurations on one input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    A,
+    B,
+    Tie,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaseResult {
+    pub input: String,
+
+    pub a: VariantOutput,
+
+    pub b: VariantOutput,
+
+    /// 两边都有分数时按各项分数之和判定，否则为 `None`
+    /// Decided by the sum of the scores when both sides were scored, `None` otherwise
+    pub verdict: Option<Verdict>,
+}
+
+/// 一组配置在整个数据集上的汇总
+/// Aggregate of one configuration over the whole dataset
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VariantSummary {
+    pub name: String,
+
+    pub runs: usize,
+
+    pub failures: usize,
+
+    pub wins: usize,
+
+    /// 胜率，平局各计半场；没有判定结果时为 `None`
+    /// Win rate with ties counted as half a win; `None` when no case was decided
+    pub win_rate: Option<f64>,
+
+    /// 各项分数的平均值
+    /// Mean of each score
+    pub mean_scores: BTreeMap<String, f64>,
+
+    pub prompt_tokens: u64,
+
+    pub completion_tokens: u64,
+
+    /// 服务商报告的累计费用（美元），未报告费用的请求不计入
+    /// Accumulated cost in USD reported by the provider; requests without a reported cost add nothing
+    pub cost: f64,
+
+    pub mean_latency_ms: Option<f64>,
+}
+
+impl VariantSummary {
+    fn new(name: &str, outputs: &[&VariantOutput], wins: usize, ties: usize, decided: usize) -> Self {
+        let mut totals: BTreeMap<String, (f64, usize)> = BTreeMap::new();
+        for scores in outputs.iter().filter_map(|output| output.scores.as_ref()) {
+            for (field, score) in numeric_scores(scores) {
+                let total = totals.entry(field).or_default();
+                total.0 += score;
+                total.1 += 1;
+            }
+        }
+
+        Self {
+            name: name.to_string(),
+            runs: outputs.len(),
+            failures: outputs.iter().filter(|output| output.answer.is_none()).count(),
+            wins,
+            win_rate: (decided > 0).then(|| (wins as f64 + ties as f64 / 2.0) / decided as f64),
+            mean_scores: totals
+                .into_iter()
+                .map(|(field, (total, count))| (field, total / count as f64))
+                .collect(),
+            prompt_tokens: outputs.iter().map(|output| output.usage.prompt_tokens).sum(),
+            completion_tokens: outputs.iter().map(|output| output.usage.completion_tokens).sum(),
+            cost: outputs.iter().filter_map(|output| output.cost).sum(),
+            mean_latency_ms: (!outputs.is_empty()).then(|| {
+                outputs.iter().map(|output| output.latency_ms as f64).sum::<f64>() / outputs.len() as f64
+            }),
+        }
+    }
+}
+
+/// 实验报告
+/// Experiment report
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExperimentReport {
+    pub a: VariantSummary,
+
+    pub b: VariantSummary,
+
+    pub ties: usize,
+
+    pub cases: Vec<CaseResult>,
+}
+
+impl ExperimentReport {
+    fn new(a: &str, b: &str, cases: Vec<CaseResult>) -> Self {
+        let count = |verdict| cases.iter().filter(|case| case.verdict == Some(verdict)).count();
+        let (wins_a, wins_b, ties) = (count(Verdict::A), count(Verdict::B), count(Verdict::Tie));
+        let decided = wins_a + wins_b + ties;
+        let outputs_a = cases.iter().map(|case| &case.a).collect::<Vec<_>>();
+        let outputs_b = cases.iter().map(|case| &case.b).collect::<Vec<_>>();
+
+        Self {
+            a: VariantSummary::new(a, &outputs_a, wins_a, ties, decided),
+            b: VariantSummary::new(b, &outputs_b, wins_b, ties, decided),
+            ties,
+            cases,
+        }
+    }
+
+    /// 以 Markdown 表格呈现汇总
+    /// Render the aggregate as a Markdown table
+    pub fn to_markdown(&self) -> String {
+        let mut lines = vec![
+            format!("| Metric | {} | {} |", self.a.name, self.b.name),
+            "|---|---|---|".to_string(),
+        ];
+        let mut row = |metric: &str, value: &dyn Fn(&VariantSummary) -> String| {
+            lines.push(format!("| {metric} | {} | {} |", value(&self.a), value(&self.b)));
+        };
+
+        row("Wins", &|summary| summary.wins.to_string());
+        row("Win rate", &|summary| match summary.win_rate {
+            Some(rate) => format!("{:.1}%", rate * 100.0),
+            None => "-".to_string(),
+        });
+        let fields = self.a.mean_scores.keys().chain(self.b.mean_scores.keys()).collect::<BTreeSet<_>>();
+        for field in fields {
+            row(&format!("Mean {field}"), &|summary| match summary.mean_scores.get(field) {
+                Some(score) => format!("{score:.2}"),
+                None => "-".to_string(),
+            });
+        }
+        row("Failures", &|summary| format!("{}/{}", summary.failures, summary.runs));
+        row("Prompt tokens", &|summary| summary.prompt_tokens.to_string());
+        row("Completion tokens", &|summary| summary.completion_tokens.to_string());
+        row("Cost (USD)", &|summary| format!("{:.4}", summary.cost));
+        row("Mean latency (ms)", &|summary| match summary.mean_latency_ms {
+            Some(latency) => format!("{latency:.0}"),
+            None => "-".to_string(),
+        });
+
+        format!("Inputs: {}, ties: {}\n\n{}\n", self.cases.len(), self.ties, lines.join("\n"))
+    }
+}
+
+/// A/B 实验：把同一批输入交给两组配置（不同提示或模型），可选地由评审打分，汇总胜率、平均分与成本
+/// A/B experiment: runs a batch of inputs through two configurations (prompt variants or models), optionally scores
+/// the outputs with a judge, and aggregates win rate, mean scores and cost
+#[derive(Debug, Clone)]
+pub struct Experiment {
+    a: Variant,
+
+    b: Variant,
+
+    /// 评审与 `response_format` 形式的评分标准模式
+    /// Judge with a rubric schema in `response_format` form
+    judge: Option<(Judge, serde_json::Value)>,
+
+    concurrency: usize,
+}
+
+impl Experiment {
+    pub fn new(a: Variant, b: Variant) -> Self {
+        Self {
+            a,
+            b,
+            judge: None,
+            concurrency: 4,
+        }
+    }
+
+    /// 用评审按评分标准给每个输出打分，见 [`Judge::score_with_schema`]
+    /// Score every output with a judge against a rubric, see [`Judge::score_with_schema`]
+    pub fn with_judge(mut self, judge: Judge, rubric: serde_json::Value) -> Self {
+        self.judge = Some((judge, rubric));
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// 运行实验；单个输入的失败记入报告而不中断实验，结果按输入顺序排列
+    /// Run the experiment; failures of single inputs are recorded in the report without stopping it, and cases
+    /// keep the order of the inputs
+    pub async fn run<I>(&self, inputs: I) -> ExperimentReport
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let cases = futures::stream::iter(inputs.into_iter().map(Into::into))
+            .map(|input: String| async move { self.run_case(input).await })
+            .buffered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        let report = ExperimentReport::new(&self.a.name, &self.b.name, cases);
+        info!(
+            "eval: {} cases, {} {} wins, {} {} wins, {} ties",
+            report.cases.len(),
+            report.a.name,
+            report.a.wins,
+            report.b.name,
+            report.b.wins,
+            report.ties
+        );
+        report
+    }
+
+    async fn run_case(&self, input: String) -> CaseResult {
+        let (mut a, mut b) = futures::join!(self.a.run(&input), self.b.run(&input));
+        if let Some((judge, rubric)) = &self.judge {
+            futures::join!(score(judge, rubric, &input, &mut a), score(judge, rubric, &input, &mut b));
+        }
+
+        let verdict = match (&a.scores, &b.scores) {
+            (Some(scores_a), Some(scores_b)) => {
+                let total = |scores| numeric_scores(scores).into_iter().map(|(_, score)| score).sum::<f64>();
+                let (total_a, total_b) = (total(scores_a), total(scores_b));
+                Some(if total_a > total_b {
+                    Verdict::A
+                } else if total_b > total_a {
+                    Verdict::B
+                } else {
+                    Verdict::Tie
+                })
+            }
+            _ => None,
+        };
+        CaseResult { input, a, b, verdict }
+    }
+}
+
+async fn score(judge: &Judge, rubric: &serde_json::Value, input: &str, output: &mut VariantOutput) {
+    let Some(answer) = &output.answer else {
+        return;
+    };
+    match judge.clone().score_with_schema(input, answer, rubric.clone()).await {
+        Ok(scores) => output.scores = Some(scores),
+        Err(report) => {
+            warn!("eval: judging failed: {report:?}");
+            output.error = Some(format!("Judging failed: {}", report.current_context()));
+        }
+    }
+}
+
+/// 分数对象中的数值字段
+/// Numeric fields of a score object
+fn numeric_scores(scores: &serde_json::Value) -> Vec<(String, f64)> {
+    scores
+        .as_object()
+        .map(|fields| {
+            fields
+                .iter()
+                .filter_map(|(field, score)| score.as_f64().map(|score| (field.clone(), score)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
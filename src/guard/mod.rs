@@ -0,0 +1,289 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use error_stack::{Report, Result};
+use regex::Regex;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::schema::validator::validate;
+
+#[derive(Debug, Error)]
+pub enum GuardError {
+    #[error("Guard '{0}' rejected output: {1}")]
+    Violation(String, String),
+}
+
+/// 单个守卫的检查结果
+/// Result of one guard check
+#[derive(Debug, Clone, PartialEq)]
+pub enum GuardVerdict {
+    Pass,
+
+    /// 以新文本替换输出（转换器）
+    /// Replace the output with new text (transformer)
+    Replace(String),
+
+    /// 违规，`valid_prefix` 为可保留的前缀字节长度（用于截断）
+    /// Violation; `valid_prefix` is the byte length of the prefix that may be kept (for truncation)
+    Violation {
+        message: String,
+        valid_prefix: Option<usize>,
+    },
+}
+
+/// 输出守卫：校验或转换模型输出
+/// Output guard: validates or transforms model output
+pub trait Guard: Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    fn check(&self, text: &str) -> GuardVerdict;
+}
+
+/// 违规时的处理方式
+/// Behavior on violation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnViolation {
+    /// 重新请求，最多重试给定次数后报错
+    /// Request again, failing after the given number of retries
+    Retry(usize),
+
+    /// 截断到合法前缀，无法截断时报错
+    /// Truncate to the valid prefix, failing when that is not possible
+    Truncate,
+
+    Error,
+}
+
+/// 守卫链的处理结果
+/// Outcome of running the guard chain
+#[derive(Debug, Clone, PartialEq)]
+pub enum GuardOutcome {
+    Accept(String),
+
+    Retry(String),
+}
+
+/// 正则黑名单
+/// Regex deny-list
+#[derive(Debug, Clone)]
+pub struct DenyList {
+    patterns: Vec<Regex>,
+}
+
+impl DenyList {
+    pub fn new(patterns: &[&str]) -> std::result::Result<Self, regex::Error> {
+        Ok(Self {
+            patterns: patterns.iter().map(|p| Regex::new(p)).collect::<std::result::Result<_, _>>()?,
+        })
+    }
+}
+
+impl Guard for DenyList {
+    fn name(&self) -> &str {
+        "deny_list"
+    }
+
+    fn check(&self, text: &str) -> GuardVerdict {
+        let first = self
+            .patterns
+            .iter()
+            .filter_map(|p| p.find(text).map(|m| (m.start(), p.as_str())))
+            .min_by_key(|(start, _)| *start);
+
+        match first {
+            Some((start, pattern)) => GuardVerdict::Violation {
+                message: format!("matches denied pattern `{pattern}`"),
+                valid_prefix: Some(start),
+            },
+            None => GuardVerdict::Pass,
+        }
+    }
+}
+
+/// 最大字符数
+/// Maximum number of characters
+#[derive(Debug, Clone)]
+pub struct MaxLength {
+    max_chars: usize,
+}
+
+impl MaxLength {
+    pub fn new(max_chars: usize) -> Self {
+        Self { max_chars }
+    }
+}
+
+impl Guard for MaxLength {
+    fn name(&self) -> &str {
+        "max_length"
+    }
+
+    fn check(&self, text: &str) -> GuardVerdict {
+        match text.char_indices().nth(self.max_chars) {
+            Some((end, _)) => GuardVerdict::Violation {
+                message: format!("longer than {} characters", self.max_chars),
+                valid_prefix: Some(end),
+            },
+            None => GuardVerdict::Pass,
+        }
+    }
+}
+
+/// 按 JSON 模式校验输出，会先去除 Markdown 代码块围栏
+/// Validate output against a JSON schema, stripping Markdown code fences first
+#[derive(Debug, Clone)]
+pub struct JsonSchemaGuard {
+    schema: serde_json::Value,
+}
+
+impl JsonSchemaGuard {
+    pub fn new(schema: serde_json::Value) -> Self {
+        Self { schema }
+    }
+}
+
+impl Guard for JsonSchemaGuard {
+    fn name(&self) -> &str {
+        "json_schema"
+    }
+
+    fn check(&self, text: &str) -> GuardVerdict {
+        let json_text = strip_code_fence(text);
+        let value: serde_json::Value = match serde_json::from_str(json_text) {
+            Ok(value) => value,
+            Err(e) => {
+                return GuardVerdict::Violation {
+                    message: format!("invalid JSON: {e}"),
+                    valid_prefix: None,
+                };
+            }
+        };
+
+        let violations = validate(&self.schema, &value);
+        if !violations.is_empty() {
+            return GuardVerdict::Violation {
+                message: violations
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+                valid_prefix: None,
+            };
+        }
+
+        if json_text.len() == text.len() {
+            GuardVerdict::Pass
+        } else {
+            GuardVerdict::Replace(json_text.to_string())
+        }
+    }
+}
+
+/// 自定义闭包守卫
+/// Custom closure guard
+pub struct FnGuard {
+    name: String,
+
+    check: Box<dyn Fn(&str) -> GuardVerdict + Send + Sync>,
+}
+
+impl FnGuard {
+    pub fn new(name: &str, check: impl Fn(&str) -> GuardVerdict + Send + Sync + 'static) -> Self {
+        Self {
+            name: name.to_string(),
+            check: Box::new(check),
+        }
+    }
+}
+
+impl Debug for FnGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FnGuard").field("name", &self.name).finish_non_exhaustive()
+    }
+}
+
+impl Guard for FnGuard {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&self, text: &str) -> GuardVerdict {
+        (self.check)(text)
+    }
+}
+
+/// 按注册顺序依次执行的守卫链
+/// Chain of guards run in registration order
+#[derive(Debug, Clone, Default)]
+pub struct GuardChain {
+    guards: Vec<(Arc<dyn Guard>, OnViolation)>,
+}
+
+impl GuardChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(mut self, guard: impl Guard + 'static, on_violation: OnViolation) -> Self {
+        self.guards.push((Arc::new(guard), on_violation));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.guards.is_empty()
+    }
+
+    /// 执行守卫链
+    /// Run the chain
+    ///
+    /// # 参数 (Parameters)
+    /// * `text` - 模型输出
+    ///   - Model output
+    /// * `attempt` - 已重试的次数
+    ///   - Number of retries already made
+    pub fn apply(&self, text: &str, attempt: usize) -> Result<GuardOutcome, GuardError> {
+        let mut text = text.to_string();
+
+        for (guard, on_violation) in &self.guards {
+            let (message, valid_prefix) = match guard.check(&text) {
+                GuardVerdict::Pass => continue,
+                GuardVerdict::Replace(replaced) => {
+                    text = replaced;
+                    continue;
+                }
+                GuardVerdict::Violation {
+                    message,
+                    valid_prefix,
+                } => (message, valid_prefix),
+            };
+            warn!("Guard '{}' violated: {}", guard.name(), message);
+
+            match (on_violation, valid_prefix) {
+                (OnViolation::Retry(max_retries), _) if attempt < *max_retries => {
+                    return Ok(GuardOutcome::Retry(message));
+                }
+                (OnViolation::Truncate, Some(end)) => text.truncate(end),
+                _ => {
+                    return Err(Report::new(GuardError::Violation(
+                        guard.name().to_string(),
+                        message,
+                    )));
+                }
+            }
+        }
+
+        Ok(GuardOutcome::Accept(text))
+    }
+}
+
+/// 去除包裹内容的 Markdown 代码块围栏
+/// Strip a Markdown code fence wrapping the content
+pub fn strip_code_fence(text: &str) -> &str {
+    let trimmed = text.trim();
+    trimmed
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map(|inner| inner.split_once('\n').map_or(inner, |(_, body)| body).trim())
+        .unwrap_or(trimmed)
+}
@@ -1,7 +1,19 @@
+pub mod agent;
+#[cfg(feature = "bench")]
+pub mod bench;
 pub mod chat;
 pub mod prompt;
 pub mod schema;
 pub mod utils;
 pub mod config;
+pub mod eval;
+pub mod guard;
+pub mod memory;
+pub mod pipeline;
+pub mod runtime;
+pub mod storage;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(test)]
 mod tests;
 mod tool_use;
\ No newline at end of file
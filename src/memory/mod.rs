@@ -0,0 +1,449 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use error_stack::{Report, Result, ResultExt};
+use futures::future::BoxFuture;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::config::{ApiInfo, Config, ModelCapability};
+use crate::schema::tool_schema::{ChatToolSchemaError, create_tool, get_tool_registry};
+use crate::utils::common::redact::redact;
+
+#[derive(Debug, Error)]
+pub enum MemoryError {
+    #[error("Failed to read memory file: {0}")]
+    ReadError(String),
+
+    #[error("Failed to write memory file: {0}")]
+    WriteError(String),
+
+    #[error("Invalid embedding endpoint: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("Embedding request failed")]
+    EmbeddingError,
+}
+
+/// 一条记忆笔记
+/// One memory note
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemoryNote {
+    pub id: u64,
+
+    pub text: String,
+
+    pub created_ms: u64,
+
+    /// 向量，尚未计算时为空
+    /// Embedding, empty until computed
+    #[serde(default)]
+    pub embedding: Option<Vec<f32>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct MemoryData {
+    #[serde(default)]
+    kv: BTreeMap<String, String>,
+
+    #[serde(default)]
+    notes: Vec<MemoryNote>,
+
+    #[serde(default)]
+    next_id: u64,
+}
+
+/// 文本向量化
+/// Text embedding
+pub trait Embedder: Debug + Send + Sync {
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, Result<Vec<Vec<f32>>, MemoryError>>;
+}
+
+/// 基于 OpenAI 风格 `/embeddings` 接口的向量化
+/// Embedding backed by an OpenAI-style `/embeddings` endpoint
+#[derive(Clone)]
+pub struct ApiEmbedder {
+    url: String,
+
+    api_key: String,
+
+    model: String,
+
+    client: Client,
+}
+
+impl Debug for ApiEmbedder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiEmbedder")
+            .field("url", &self.url)
+            .field("model", &self.model)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ApiEmbedder {
+    /// 由API信息推导向量接口（将 `/chat/completions` 替换为 `/embeddings`）
+    /// Derive the embedding endpoint from API info (`/chat/completions` becomes `/embeddings`)
+    pub fn new_with_api_info(api_info: ApiInfo) -> Result<Self, MemoryError> {
+        let root = api_info
+            .base_url
+            .strip_suffix("/chat/completions")
+            .ok_or_else(|| Report::new(MemoryError::InvalidEndpoint(api_info.base_url.clone())))?;
+        Ok(Self {
+            url: format!("{root}/embeddings"),
+            api_key: api_info.api_key,
+            model: api_info.model,
+            client: api_info.client,
+        })
+    }
+
+    pub fn new_with_model_capability() -> Result<Self, MemoryError> {
+        let api_info = Config::get_api_info_with_capability(ModelCapability::Embedding)
+            .change_context(MemoryError::InvalidEndpoint("embedding".to_string()))?;
+        Self::new_with_api_info(api_info)
+    }
+
+    async fn request(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, MemoryError> {
+        let resp: serde_json::Value = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .json(&json!({"model": self.model, "input": texts}))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| Report::new(MemoryError::EmbeddingError).attach_printable(redact(&e.to_string())))?
+            .json()
+            .await
+            .change_context(MemoryError::EmbeddingError)?;
+
+        let data = resp["data"]
+            .as_array()
+            .ok_or_else(|| Report::new(MemoryError::EmbeddingError))
+            .attach_printable("Missing data in embedding response")?;
+        data.iter()
+            .map(|item| {
+                serde_json::from_value(item["embedding"].clone())
+                    .change_context(MemoryError::EmbeddingError)
+            })
+            .collect()
+    }
+}
+
+impl Embedder for ApiEmbedder {
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, Result<Vec<Vec<f32>>, MemoryError>> {
+        Box::pin(self.request(texts))
+    }
+}
+
+/// 智能体长期记忆：键值与可检索笔记，持久化到磁盘
+/// Long-term agent memory: key-value pairs and searchable notes, persisted to disk
+#[derive(Debug)]
+pub struct MemoryStore {
+    path: PathBuf,
+
+    data: RwLock<MemoryData>,
+
+    embedder: Option<Arc<dyn Embedder>>,
+
+    /// 视为相关的最低分数
+    /// Minimum score considered relevant
+    min_score: f32,
+}
+
+impl MemoryStore {
+    /// 打开记忆文件，不存在时创建空记忆
+    /// Open a memory file, starting empty when it does not exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, MemoryError> {
+        let path = path.as_ref().to_path_buf();
+        let data = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .change_context_lazy(|| MemoryError::ReadError(path.display().to_string()))?;
+            serde_json::from_str(&content)
+                .change_context_lazy(|| MemoryError::ReadError(path.display().to_string()))?
+        } else {
+            MemoryData::default()
+        };
+
+        Ok(Self {
+            path,
+            data: RwLock::new(data),
+            embedder: None,
+            min_score: 0.3,
+        })
+    }
+
+    /// 启用向量检索，未启用时按关键词重合度检索
+    /// Enable embedding search; keyword overlap is used otherwise
+    pub fn with_embedder(mut self, embedder: impl Embedder + 'static) -> Self {
+        self.embedder = Some(Arc::new(embedder));
+        self
+    }
+
+    pub fn with_min_score(mut self, min_score: f32) -> Self {
+        self.min_score = min_score;
+        self
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<(), MemoryError> {
+        let mut data = self.data.write().unwrap();
+        data.kv.insert(key.to_string(), value.to_string());
+        self.save(&data)
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.data.read().unwrap().kv.get(key).cloned()
+    }
+
+    pub fn remove(&self, key: &str) -> Result<Option<String>, MemoryError> {
+        let mut data = self.data.write().unwrap();
+        let removed = data.kv.remove(key);
+        self.save(&data)?;
+        Ok(removed)
+    }
+
+    /// 添加笔记，向量在下次检索时计算
+    /// Add a note; its embedding is computed on the next search
+    pub fn add_note(&self, text: &str) -> Result<u64, MemoryError> {
+        let mut data = self.data.write().unwrap();
+        let id = data.next_id;
+        data.next_id += 1;
+        data.notes.push(MemoryNote {
+            id,
+            text: text.to_string(),
+            created_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            embedding: None,
+        });
+        self.save(&data)?;
+        Ok(id)
+    }
+
+    pub fn notes(&self) -> Vec<MemoryNote> {
+        self.data.read().unwrap().notes.clone()
+    }
+
+    /// 检索与查询最相关的笔记，返回 (分数, 笔记)，已按分数降序
+    /// Find the notes most relevant to a query as (score, note), sorted by descending score
+    pub async fn search(&self, query: &str, top_k: usize) -> Result<Vec<(f32, MemoryNote)>, MemoryError> {
+        let Some(embedder) = self.embedder.clone() else {
+            return Ok(self.keyword_search(query, top_k));
+        };
+
+        self.embed_pending(embedder.as_ref()).await?;
+        let query_embedding = embedder
+            .embed(&[query.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| Report::new(MemoryError::EmbeddingError))?;
+
+        let data = self.data.read().unwrap();
+        let scored = data
+            .notes
+            .iter()
+            .filter_map(|note| {
+                let embedding = note.embedding.as_ref()?;
+                Some((cosine_similarity(&query_embedding, embedding), note.clone()))
+            })
+            .collect();
+        Ok(self.top(scored, top_k))
+    }
+
+    /// 按关键词重合度检索（不需要网络）
+    /// Search by keyword overlap (no network needed)
+    pub fn keyword_search(&self, query: &str, top_k: usize) -> Vec<(f32, MemoryNote)> {
+        let query_tokens = tokenize(query);
+        if query_tokens.is_empty() {
+            return Vec::new();
+        }
+
+        let data = self.data.read().unwrap();
+        let scored = data
+            .notes
+            .iter()
+            .map(|note| {
+                let note_tokens = tokenize(&note.text);
+                let hits = query_tokens.iter().filter(|t| note_tokens.contains(t)).count();
+                (hits as f32 / query_tokens.len() as f32, note.clone())
+            })
+            .collect();
+        self.top(scored, top_k)
+    }
+
+    /// 生成可放入系统提示的相关记忆段落，没有相关内容时为空
+    /// Build a system-prompt section of relevant memories, empty when nothing is relevant
+    pub async fn relevant_prompt(&self, query: &str, top_k: usize) -> Result<String, MemoryError> {
+        let notes = self.search(query, top_k).await?;
+        if notes.is_empty() {
+            return Ok(String::new());
+        }
+
+        let mut prompt = "以下是可能与当前任务相关的长期记忆：\n".to_string();
+        for (_, note) in notes {
+            prompt.push_str(&format!("- {}\n", note.text));
+        }
+        Ok(prompt)
+    }
+
+    /// 注册 `remember` 与 `recall` 工具，返回其工具模式
+    /// Register the `remember` and `recall` tools and return their schemas
+    pub fn register_tools(self: &Arc<Self>) -> Vec<serde_json::Value> {
+        let store = self.clone();
+        let (name, remember) = create_tool("remember", move |args| {
+            let content = args["content"].as_str().ok_or_else(|| {
+                Report::new(ChatToolSchemaError::ParamsParseError(
+                    "remember".to_string(),
+                    args.to_string(),
+                ))
+            })?;
+            let stored = match args["key"].as_str().filter(|key| !key.is_empty()) {
+                Some(key) => store.set(key, content).map(|_| format!("已记住 {key}")),
+                None => store.add_note(content).map(|id| format!("已记住笔记 #{id}")),
+            };
+            stored
+                .map(serde_json::Value::String)
+                .change_context(ChatToolSchemaError::FunctionCallError)
+        });
+        get_tool_registry().insert(name, remember);
+
+        let store = self.clone();
+        let (name, recall) = create_tool("recall", move |args| {
+            let query = args["query"].as_str().ok_or_else(|| {
+                Report::new(ChatToolSchemaError::ParamsParseError(
+                    "recall".to_string(),
+                    args.to_string(),
+                ))
+            })?;
+            let mut found = Vec::new();
+            if let Some(value) = store.get(query) {
+                found.push(format!("{query}: {value}"));
+            }
+            found.extend(store.keyword_search(query, 5).into_iter().map(|(_, note)| note.text));
+            Ok(json!(found))
+        });
+        get_tool_registry().insert(name, recall);
+
+        vec![
+            json!({
+                "type": "function",
+                "function": {
+                    "name": "remember",
+                    "description": "将信息写入长期记忆，给出 key 时按键保存，否则保存为笔记",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "key": {"type": "string", "description": "可选的键名"},
+                            "content": {"type": "string", "description": "要记住的内容"},
+                        },
+                        "required": ["content"],
+                    },
+                },
+            }),
+            json!({
+                "type": "function",
+                "function": {
+                    "name": "recall",
+                    "description": "按键名或关键词从长期记忆中检索信息",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "query": {"type": "string", "description": "键名或检索关键词"},
+                        },
+                        "required": ["query"],
+                    },
+                },
+            }),
+        ]
+    }
+
+    async fn embed_pending(&self, embedder: &dyn Embedder) -> Result<(), MemoryError> {
+        let pending = {
+            let data = self.data.read().unwrap();
+            data.notes
+                .iter()
+                .filter(|note| note.embedding.is_none())
+                .map(|note| (note.id, note.text.clone()))
+                .collect::<Vec<_>>()
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let texts = pending.iter().map(|(_, text)| text.clone()).collect::<Vec<_>>();
+        let embeddings = embedder.embed(&texts).await?;
+
+        let mut data = self.data.write().unwrap();
+        for ((id, _), embedding) in pending.into_iter().zip(embeddings) {
+            if let Some(note) = data.notes.iter_mut().find(|note| note.id == id) {
+                note.embedding = Some(embedding);
+            }
+        }
+        self.save(&data)
+    }
+
+    fn top(&self, mut scored: Vec<(f32, MemoryNote)>, top_k: usize) -> Vec<(f32, MemoryNote)> {
+        scored.retain(|(score, _)| *score >= self.min_score);
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.truncate(top_k);
+        scored
+    }
+
+    fn save(&self, data: &MemoryData) -> Result<(), MemoryError> {
+        let write_error = || MemoryError::WriteError(self.path.display().to_string());
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).change_context_lazy(write_error)?;
+        }
+
+        let content = serde_json::to_string_pretty(data).change_context_lazy(write_error)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content).change_context_lazy(write_error)?;
+        fs::rename(&tmp, &self.path).change_context_lazy(write_error)?;
+        Ok(())
+    }
+}
+
+/// 余弦相似度
+/// Cosine similarity
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        warn!("Embedding dimensions differ: {} vs {}", a.len(), b.len());
+        return 0.0;
+    }
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|y| y * y).sum::<f32>().sqrt();
+    if norm == 0.0 { 0.0 } else { dot / norm }
+}
+
+/// 切分为小写单词，中日韩字符逐字切分
+/// Split into lowercase words, with CJK characters as single tokens
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() && c.is_ascii() {
+            word.extend(c.to_lowercase());
+            continue;
+        }
+        if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+        if c.is_alphanumeric() {
+            tokens.push(c.to_string());
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens.sort();
+    tokens.dedup();
+    tokens
+}
@@ -0,0 +1,322 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use error_stack::{Report, Result, ResultExt};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::chat::chat_base::ChatError;
+use crate::chat::chat_single::SingleChat;
+use crate::chat::pruning::now_ms;
+use crate::chat::usage::UsageSnapshot;
+use crate::pipeline::{DatasetRecord, PipelineError};
+
+/// 状态文件名在输出文件名之后追加的后缀
+/// Suffix appended to the output file name to get the state file name
+pub const STATE_FILE_SUFFIX: &str = ".state.json";
+
+/// 某一项已用的尝试次数与最后一次的错误
+/// Attempts an item has used and its last error
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemFailure {
+    pub attempts: u32,
+
+    pub error: String,
+}
+
+/// 批处理进度，每完成若干项写入状态文件，崩溃后据此续跑
+/// Batch progress, written to the state file every few items so a crashed run can resume from it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchState {
+    pub completed: BTreeSet<String>,
+
+    /// 尚未成功的项，跨运行累计尝试次数
+    /// Items that have not succeeded yet; attempts accumulate across runs
+    pub failures: BTreeMap<String, ItemFailure>,
+
+    pub usage: UsageSnapshot,
+
+    pub started_ms: u64,
+
+    pub updated_ms: u64,
+}
+
+/// 一次运行结束时的报告，计数与用量均包含之前的运行
+/// Report at the end of a run; counts and usage include earlier runs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchReport {
+    pub completed: usize,
+
+    pub completed_this_run: usize,
+
+    /// 重试预算已用完的项
+    /// Items whose retry budget is used up
+    pub exhausted: BTreeMap<String, ItemFailure>,
+
+    /// 失败但仍有预算、下次运行会再试的项
+    /// Failed items with budget left, tried again on the next run
+    pub pending: BTreeMap<String, ItemFailure>,
+
+    pub usage: UsageSnapshot,
+}
+
+/// 可断点续跑的批处理：逐项提问并把回答追加到 JSONL 输出，进度保存在旁边的状态文件中
+/// Checkpointable batch runner: asks every item and appends the answers to a JSONL output,
+/// keeping progress in a state file next to it
+///
+/// 状态文件存在时自动续跑；两次保存之间崩溃时，已写入输出的项仍视为完成，但其用量会丢失
+/// An existing state file resumes the batch automatically; after a crash between two checkpoints,
+/// items already in the output still count as completed but their usage is lost
+#[derive(Debug, Clone)]
+pub struct BatchRunner {
+    chat: SingleChat,
+
+    output_path: PathBuf,
+
+    state_path: PathBuf,
+
+    concurrency: usize,
+
+    /// 每一项跨运行的最多尝试次数
+    /// Maximum attempts of every item across runs
+    retry_budget: u32,
+
+    retry_delay: Duration,
+
+    /// 每完成多少项保存一次状态
+    /// Save the state every this many finished items
+    checkpoint_every: usize,
+}
+
+impl BatchRunner {
+    /// # 参数 (Parameters)
+    /// * `chat` - 模板对话，每一项使用它的一个副本
+    ///   - Template chat; every item uses a copy of it
+    /// * `output_path` - 回答输出文件，状态文件为同名加 `.state.json`
+    ///   - Answer output file; the state file has the same name plus `.state.json`
+    pub fn new(chat: SingleChat, output_path: impl Into<PathBuf>) -> Self {
+        let output_path = output_path.into();
+        let mut state_path = output_path.clone().into_os_string();
+        state_path.push(STATE_FILE_SUFFIX);
+        Self {
+            chat,
+            output_path,
+            state_path: state_path.into(),
+            concurrency: 4,
+            retry_budget: 3,
+            retry_delay: Duration::from_secs(1),
+            checkpoint_every: 10,
+        }
+    }
+
+    pub fn with_state_path(mut self, state_path: impl Into<PathBuf>) -> Self {
+        self.state_path = state_path.into();
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_retry_budget(mut self, retry_budget: u32) -> Self {
+        self.retry_budget = retry_budget.max(1);
+        self
+    }
+
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn with_checkpoint_every(mut self, checkpoint_every: usize) -> Self {
+        self.checkpoint_every = checkpoint_every.max(1);
+        self
+    }
+
+    pub fn state_path(&self) -> &Path {
+        &self.state_path
+    }
+
+    /// 读取已保存的进度，没有状态文件时为空
+    /// Read the saved progress; empty without a state file
+    pub fn load_state(&self) -> Result<BatchState, PipelineError> {
+        match fs::read_to_string(&self.state_path) {
+            Ok(content) => serde_json::from_str(&content)
+                .change_context_lazy(|| PipelineError::ResumeError(self.state_path.display().to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BatchState {
+                started_ms: now_ms(),
+                ..BatchState::default()
+            }),
+            Err(e) => Err(Report::new(PipelineError::ResumeError(e.to_string()))),
+        }
+    }
+
+    /// 运行批处理，跳过已完成与预算耗尽的项
+    /// Run the batch, skipping completed items and items whose budget is used up
+    ///
+    /// # 参数 (Parameters)
+    /// * `items` - `(id, 提问)` 对，重复的 id 只处理第一次出现
+    ///   - `(id, prompt)` pairs; only the first occurrence of a repeated id is processed
+    pub async fn run<I, K, P>(&self, items: I) -> Result<BatchReport, PipelineError>
+    where
+        I: IntoIterator<Item = (K, P)>,
+        K: Into<String>,
+        P: Into<String>,
+    {
+        let mut state = self.load_state()?;
+        let existing = fs::read_to_string(&self.output_path).unwrap_or_default();
+        state.completed.extend(output_ids(&existing));
+        for id in &state.completed {
+            state.failures.remove(id);
+        }
+
+        let mut seen = HashSet::new();
+        let pending: Vec<(String, String, u32)> = items
+            .into_iter()
+            .map(|(id, prompt)| (id.into(), prompt.into()))
+            .filter(|(id, _)| seen.insert(id.clone()) && !state.completed.contains(id))
+            .filter_map(|(id, prompt)| {
+                let used = state.failures.get(&id).map_or(0, |failure| failure.attempts);
+                (used < self.retry_budget).then_some((id, prompt, used))
+            })
+            .collect();
+        info!(
+            "batch: {} items pending, {} already completed",
+            pending.len(),
+            state.completed.len()
+        );
+
+        if let Some(dir) = self.output_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .change_context_lazy(|| PipelineError::OpenError(dir.display().to_string()))?;
+        }
+        let mut output = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.output_path)
+            .change_context_lazy(|| PipelineError::OpenError(self.output_path.display().to_string()))?;
+        // 崩溃时可能留下未写完的行
+        // A crash may have left an unfinished line
+        if !existing.is_empty() && !existing.ends_with('\n') {
+            writeln!(output).change_context(PipelineError::WriteError)?;
+        }
+
+        let usage_before = state.usage;
+        let usage_start = self.chat.base.usage_snapshot();
+        let mut completed_this_run = 0;
+        let mut unsaved = 0;
+
+        let mut results = futures::stream::iter(pending)
+            .map(|(id, prompt, used)| async move {
+                let outcome = self.answer(&prompt, used).await;
+                (id, prompt, outcome)
+            })
+            .buffer_unordered(self.concurrency);
+
+        while let Some((id, prompt, outcome)) = results.next().await {
+            match outcome {
+                Ok(answer) => {
+                    let record = DatasetRecord { id: id.clone(), input: prompt, output: answer };
+                    let line = serde_json::to_string(&record).change_context(PipelineError::WriteError)?;
+                    writeln!(output, "{line}").change_context(PipelineError::WriteError)?;
+                    output.flush().change_context(PipelineError::WriteError)?;
+                    state.failures.remove(&id);
+                    state.completed.insert(id);
+                    completed_this_run += 1;
+                }
+                Err(failure) => {
+                    warn!("batch: item {id} failed after {} attempts: {}", failure.attempts, failure.error);
+                    state.failures.insert(id, failure);
+                }
+            }
+
+            unsaved += 1;
+            if unsaved >= self.checkpoint_every {
+                state.usage = accumulate(usage_before, usage_start, self.chat.base.usage_snapshot());
+                self.save_state(&mut state)?;
+                unsaved = 0;
+            }
+        }
+
+        state.usage = accumulate(usage_before, usage_start, self.chat.base.usage_snapshot());
+        self.save_state(&mut state)?;
+
+        let (exhausted, pending) = state
+            .failures
+            .iter()
+            .map(|(id, failure)| (id.clone(), failure.clone()))
+            .partition(|(_, failure)| failure.attempts >= self.retry_budget);
+        Ok(BatchReport {
+            completed: state.completed.len(),
+            completed_this_run,
+            exhausted,
+            pending,
+            usage: state.usage,
+        })
+    }
+
+    /// 在剩余预算内反复提问，失败时返回累计尝试次数与最后一次的错误
+    /// Ask within the remaining budget; on failure returns the accumulated attempts and the last error
+    async fn answer(&self, prompt: &str, used: u32) -> std::result::Result<String, ItemFailure> {
+        let mut attempts = used;
+        loop {
+            attempts += 1;
+            match self.answer_once(prompt).await {
+                Ok(answer) => return Ok(answer),
+                Err(report) if attempts >= self.retry_budget => {
+                    return Err(ItemFailure {
+                        attempts,
+                        error: report.current_context().to_string(),
+                    });
+                }
+                Err(report) => {
+                    warn!("batch: attempt {attempts} failed, retrying: {}", report.current_context());
+                    tokio::time::sleep(self.retry_delay).await;
+                }
+            }
+        }
+    }
+
+    async fn answer_once(&self, prompt: &str) -> Result<String, ChatError> {
+        let mut chat = self.chat.clone();
+        let request_body = chat.get_req_body(prompt).await?;
+        chat.get_content_from_req_body(request_body).await
+    }
+
+    /// 先写临时文件再改名，崩溃时不会留下写了一半的状态
+    /// Write a temporary file and rename it, so a crash never leaves a half-written state
+    fn save_state(&self, state: &mut BatchState) -> Result<(), PipelineError> {
+        state.updated_ms = now_ms();
+        let json = serde_json::to_string_pretty(state).change_context(PipelineError::WriteError)?;
+
+        let mut temp_path = self.state_path.clone().into_os_string();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, json).change_context(PipelineError::WriteError)?;
+        fs::rename(&temp_path, &self.state_path).change_context(PipelineError::WriteError)
+    }
+}
+
+/// 输出文件中已写入的项，覆盖两次保存之间崩溃的情况
+/// Items already in the output file, covering a crash between two checkpoints
+fn output_ids(content: &str) -> impl Iterator<Item = String> + '_ {
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter_map(|record| record["id"].as_str().map(str::to_string))
+}
+
+/// 之前运行的用量加上本次运行至今的用量
+/// Usage of earlier runs plus the usage of this run so far
+fn accumulate(before: UsageSnapshot, start: UsageSnapshot, now: UsageSnapshot) -> UsageSnapshot {
+    UsageSnapshot {
+        prompt_tokens: before.prompt_tokens + now.prompt_tokens.saturating_sub(start.prompt_tokens),
+        completion_tokens: before.completion_tokens
+            + now.completion_tokens.saturating_sub(start.completion_tokens),
+        total_tokens: before.total_tokens + now.total_tokens.saturating_sub(start.total_tokens),
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::time::Duration;
+
+use error_stack::{Report, Result, ResultExt};
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::chat::chat_base::ChatError;
+use crate::chat::chat_single::SingleChat;
+use crate::chat::chat_tool::add_response_format;
+use crate::chat::message::Role;
+use crate::guard::strip_code_fence;
+use crate::prompt::assembler::assemble_output_description;
+use crate::schema::json_schema::JsonSchema;
+use crate::schema::validator::{inner_schema, validate};
+use crate::utils::common::redact::redact;
+
+pub mod batch;
+
+/// 提示模板中输入的占位符，模板中没有占位符时输入附在提示之后
+/// Placeholder of the input in the prompt template; without it the input is appended to the prompt
+pub const INPUT_PLACEHOLDER: &str = "{input}";
+
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    #[error("Failed to open dataset file: {0}")]
+    OpenError(String),
+
+    #[error("Failed to read existing dataset file: {0}")]
+    ResumeError(String),
+
+    #[error("Failed to write dataset record")]
+    WriteError,
+
+    #[error("Failed to assemble output description")]
+    AssembleOutputDescriptionError,
+}
+
+/// 数据集文件格式
+/// Dataset file format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatasetFormat {
+    /// 每行一条 `{"id", "input", "output"}` 记录
+    /// One `{"id", "input", "output"}` record per line
+    #[default]
+    Jsonl,
+
+    /// `id,input` 后接输出的各个顶层字段，非字符串字段写为 JSON 文本
+    /// `id,input` followed by the top-level output fields; non-string fields are written as JSON text
+    Csv,
+}
+
+/// 数据集中的一条记录，`id` 由输入内容计算，用于续跑时跳过已完成的输入
+/// One dataset record; `id` is derived from the input and lets a resumed run skip finished inputs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DatasetRecord<T> {
+    pub id: String,
+
+    pub input: String,
+
+    pub output: T,
+}
+
+/// 重试后仍失败的输入，不会写入数据集，下次续跑时会再次尝试
+/// An input that still failed after its retries; it is not written and is tried again on the next resumed run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatasetFailure {
+    pub id: String,
+
+    pub input: String,
+
+    pub attempts: u32,
+
+    pub error: String,
+}
+
+/// 一次生成的结果统计
+/// Outcome of one generation run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatasetSummary {
+    pub written: usize,
+
+    /// 数据集中已有或与之前输入重复而跳过的输入数
+    /// Inputs skipped because the dataset already holds them or they repeat an earlier input
+    pub skipped: usize,
+
+    pub failed: Vec<DatasetFailure>,
+}
+
+/// 数据集构建器：将一批输入逐个交给模型，按目标类型生成结构化记录并增量写入文件
+/// Dataset builder: runs a batch of inputs through a model into structured records of the target type,
+/// written to the file as they complete
+///
+/// 每个输入使用模板对话的独立副本；文件中已有的记录在续跑时会被跳过
+/// Every input gets its own copy of the template chat; records already in the file are skipped when resuming
+#[derive(Debug, Clone)]
+pub struct DatasetBuilder<T> {
+    chat: SingleChat,
+
+    prompt: String,
+
+    format: DatasetFormat,
+
+    concurrency: usize,
+
+    /// 首次尝试之外的重试次数
+    /// Retries on top of the first attempt
+    max_retries: u32,
+
+    retry_delay: Duration,
+
+    resume: bool,
+
+    _output: PhantomData<fn() -> T>,
+}
+
+impl<T> DatasetBuilder<T>
+where
+    T: DeserializeOwned + Serialize + JsonSchema + 'static,
+{
+    /// # 参数 (Parameters)
+    /// * `chat` - 模板对话，每个输入使用它的一个副本
+    ///   - Template chat; every input uses a copy of it
+    /// * `prompt` - 提示模板，`{input}` 处替换为输入
+    ///   - Prompt template; `{input}` is replaced with the input
+    pub fn new(chat: SingleChat, prompt: &str) -> Self {
+        Self {
+            chat,
+            prompt: prompt.to_string(),
+            format: DatasetFormat::default(),
+            concurrency: 4,
+            max_retries: 2,
+            retry_delay: Duration::from_secs(1),
+            resume: true,
+            _output: PhantomData,
+        }
+    }
+
+    pub fn with_format(mut self, format: DatasetFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// 关闭续跑时覆盖已有文件
+    /// With resuming off, an existing file is overwritten
+    pub fn with_resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// 生成数据集并写入 `path`，每条记录完成后立即追加
+    /// Generate the dataset into `path`, appending every record as soon as it completes
+    pub async fn run<I>(&self, inputs: I, path: impl AsRef<Path>) -> Result<DatasetSummary, PipelineError>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let path = path.as_ref();
+        let schema = T::cached_json_schema();
+        let output_description = assemble_output_description(&schema)
+            .change_context(PipelineError::AssembleOutputDescriptionError)?;
+
+        let mut seen = if self.resume { load_ids(path, self.format)? } else { HashSet::new() };
+        let mut summary = DatasetSummary::default();
+        let pending: Vec<(String, String)> = inputs
+            .into_iter()
+            .map(Into::into)
+            .filter_map(|input| {
+                let id = input_id(&input);
+                if seen.insert(id.clone()) {
+                    Some((id, input))
+                } else {
+                    summary.skipped += 1;
+                    None
+                }
+            })
+            .collect();
+        info!("dataset: {} inputs pending, {} skipped", pending.len(), summary.skipped);
+
+        let mut writer = DatasetWriter::open(path, self.format, &schema, self.resume)?;
+        let mut results = futures::stream::iter(pending)
+            .map(|(id, input)| {
+                let schema = &schema;
+                let output_description = &output_description;
+                async move {
+                    let outcome = self.generate(&input, schema, output_description).await;
+                    (id, input, outcome)
+                }
+            })
+            .buffer_unordered(self.concurrency);
+
+        while let Some((id, input, outcome)) = results.next().await {
+            match outcome {
+                Ok(output) => {
+                    writer.write(&DatasetRecord { id, input, output })?;
+                    summary.written += 1;
+                }
+                Err((attempts, report)) => {
+                    warn!("dataset: input {id} failed after {attempts} attempts: {report:?}");
+                    summary.failed.push(DatasetFailure {
+                        id,
+                        input,
+                        attempts,
+                        error: report.current_context().to_string(),
+                    });
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// 为一个输入生成输出，失败时按设置重试，返回尝试次数与最后一次的错误
+    /// Generate the output for one input, retrying as configured; returns the attempt count and the last error on failure
+    async fn generate(
+        &self,
+        input: &str,
+        schema: &serde_json::Value,
+        output_description: &str,
+    ) -> std::result::Result<T, (u32, Report<ChatError>)> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.generate_once(input, schema, output_description).await {
+                Ok(output) => return Ok(output),
+                Err(report) if attempt > self.max_retries => return Err((attempt, report)),
+                Err(report) => {
+                    warn!("dataset: attempt {attempt} failed, retrying: {}", report.current_context());
+                    tokio::time::sleep(self.retry_delay).await;
+                }
+            }
+        }
+    }
+
+    async fn generate_once(
+        &self,
+        input: &str,
+        schema: &serde_json::Value,
+        output_description: &str,
+    ) -> Result<T, ChatError> {
+        let mut chat = self.chat.clone();
+        chat.base.add_message(Role::System, output_description)?;
+
+        let request_body = add_response_format(chat.get_req_body(&self.render_prompt(input)).await?, schema.clone());
+        let answer = chat.get_content_from_req_body(request_body).await?;
+
+        let output: serde_json::Value = serde_json::from_str(strip_code_fence(&answer))
+            .change_context(ChatError::GetJsonError)
+            .attach_printable_lazy(|| format!("Answer is not JSON: {}", redact(&answer)))?;
+        let violations = validate(schema, &output);
+        if !violations.is_empty() {
+            let detail = violations.iter().map(|v| v.to_string()).collect::<Vec<_>>().join("; ");
+            return Err(Report::new(ChatError::GetJsonError)).attach_printable(detail);
+        }
+        serde_json::from_value(output).change_context(ChatError::GetJsonError)
+    }
+
+    fn render_prompt(&self, input: &str) -> String {
+        if self.prompt.contains(INPUT_PLACEHOLDER) {
+            self.prompt.replace(INPUT_PLACEHOLDER, input)
+        } else {
+            format!("{}\n\n{}", self.prompt, input)
+        }
+    }
+}
+
+/// 输入的稳定标识（FNV-1a）
+/// Stable identifier of an input (FNV-1a)
+pub fn input_id(input: &str) -> String {
+    let hash = input.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// 读取数据集文件中已完成记录的标识，文件不存在时为空
+/// Read the identifiers of the records already in a dataset file; empty when the file does not exist
+fn load_ids(path: &Path, format: DatasetFormat) -> Result<HashSet<String>, PipelineError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(Report::new(PipelineError::ResumeError(e.to_string()))),
+    };
+
+    let ids = match format {
+        DatasetFormat::Jsonl => content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str::<serde_json::Value>(line) {
+                Ok(record) => record["id"].as_str().map(str::to_string),
+                Err(e) => {
+                    warn!("dataset: skipping unreadable line in {}: {e}", path.display());
+                    None
+                }
+            })
+            .collect(),
+        DatasetFormat::Csv => parse_csv(&content)
+            .into_iter()
+            .skip(1)
+            .filter_map(|row| row.into_iter().next())
+            .filter(|id| !id.is_empty())
+            .collect(),
+    };
+    Ok(ids)
+}
+
+/// 增量写入数据集文件
+/// Incremental writer of a dataset file
+struct DatasetWriter {
+    file: File,
+
+    format: DatasetFormat,
+
+    /// CSV 的输出字段列，为空时整个输出写在 `output` 列
+    /// Output field columns of CSV; when empty the whole output goes in an `output` column
+    columns: Vec<String>,
+}
+
+impl DatasetWriter {
+    fn open(
+        path: &Path,
+        format: DatasetFormat,
+        schema: &serde_json::Value,
+        append: bool,
+    ) -> Result<Self, PipelineError> {
+        let open_error = || PipelineError::OpenError(path.display().to_string());
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).change_context_lazy(open_error)?;
+        }
+        let existing = if append { fs::read_to_string(path).unwrap_or_default() } else { String::new() };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .change_context_lazy(open_error)?;
+
+        let columns: Vec<String> = inner_schema(schema)["properties"]
+            .as_object()
+            .map(|properties| properties.keys().cloned().collect())
+            .unwrap_or_default();
+
+        // 上次中断时可能留下未写完的行
+        // An interrupted run may have left an unfinished line
+        if !existing.is_empty() && !existing.ends_with('\n') {
+            writeln!(file).change_context(PipelineError::WriteError)?;
+        }
+        if format == DatasetFormat::Csv && existing.trim().is_empty() {
+            let mut header = vec!["id".to_string(), "input".to_string()];
+            if columns.is_empty() {
+                header.push("output".to_string());
+            } else {
+                header.extend(columns.iter().cloned());
+            }
+            writeln!(file, "{}", csv_row(&header)).change_context(PipelineError::WriteError)?;
+        }
+        Ok(Self { file, format, columns })
+    }
+
+    fn write<T: Serialize>(&mut self, record: &DatasetRecord<T>) -> Result<(), PipelineError> {
+        let line = match self.format {
+            DatasetFormat::Jsonl => serde_json::to_string(record).change_context(PipelineError::WriteError)?,
+            DatasetFormat::Csv => {
+                let output = serde_json::to_value(&record.output).change_context(PipelineError::WriteError)?;
+                let mut row = vec![record.id.clone(), record.input.clone()];
+                if self.columns.is_empty() {
+                    row.push(csv_cell(&output));
+                } else {
+                    row.extend(self.columns.iter().map(|column| csv_cell(&output[column])));
+                }
+                csv_row(&row)
+            }
+        };
+        writeln!(self.file, "{line}").change_context(PipelineError::WriteError)?;
+        self.file.flush().change_context(PipelineError::WriteError)
+    }
+}
+
+fn csv_cell(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// 按 RFC 4180 拼接一行，含逗号、引号或换行的字段加引号
+/// Join one row per RFC 4180, quoting fields with commas, quotes or line breaks
+fn csv_row(fields: &[String]) -> String {
+    fields
+        .iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 按 RFC 4180 解析 CSV，引号内可包含换行
+/// Parse CSV per RFC 4180; quoted fields may span lines
+fn parse_csv(content: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => row.push(std::mem::take(&mut field)),
+            ('\n', false) => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            ('\r', false) => {}
+            (c, _) => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+/// 读取 JSONL 数据集中的全部记录
+/// Read every record of a JSONL dataset
+pub fn load_dataset<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<Vec<DatasetRecord<T>>, PipelineError> {
+    let path = path.as_ref();
+    fs::read_to_string(path)
+        .change_context_lazy(|| PipelineError::ResumeError(path.display().to_string()))?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .change_context_lazy(|| PipelineError::ResumeError(path.display().to_string()))
+        })
+        .collect()
+}
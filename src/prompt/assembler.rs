@@ -1,4 +1,5 @@
 // 标准库
+use std::borrow::Cow;
 use std::collections::HashMap;
 
 // 错误处理
@@ -7,11 +8,35 @@ use thiserror::Error;
 
 // 辅助工具
 use indoc::indoc;
+use tracing::warn;
 
 // 项目内部模块
-use crate::prompt::model::{Content, Info, Prompt, Template};
+use crate::prompt::model::{CharacterPrompts, Content, Info, Prompt, PromptFormat, Template, TemplateElement};
 use crate::schema::tool_schema::ChatToolSchemaError;
 
+/// 模板未给出顺序时的元素顺序，模板的额外元素插在自定义元素之前
+/// Element order when the template gives none; extra template elements go right before the custom elements
+pub const DEFAULT_SECTION_ORDER: &[&str] = &[
+    "task_description",
+    "input_description",
+    "output_description",
+    "principle",
+    "how_to_think",
+    "examples",
+    CUSTOM_ELEMENTS_SECTION,
+    STAGE_SECTION,
+];
+
+/// 元素顺序中代表内容文件自定义元素的键
+/// Key standing for the custom elements of content files in the element order
+pub const CUSTOM_ELEMENTS_SECTION: &str = "custom_elements";
+
+const STAGE_SECTION: &str = "stage_description";
+
+/// 默认不在提示中呈现的字段，如思维链
+/// Fields not rendered in prompts by default, such as the chain of thought
+pub const DEFAULT_HIDDEN_FIELDS: &[&str] = &["cot"];
+
 /// 输出描述错误枚举
 /// Output description error enum
 #[derive(Debug, Error)]
@@ -88,92 +113,230 @@ fn assemble_character_prompt(template: &Template, content: &Content) -> HashMap<
     let num_chars = content.character_prompts.character_names.len();
     let mut result = HashMap::with_capacity(num_chars);
 
-    for character_name in &content.character_prompts.character_names {
-        let mut character_prompt_parts = Vec::with_capacity(7); // 预分配空间为可能的元素数量
-                                                              // Pre-allocate space for possible elements
-
-        // 处理各个字段
-        // Process each field
-        let field_pairs = [
-            (&tcp.task_description,     &ccp.task_description),
-            // (&tcp.input_description,    &ccp.input_description),
-            // (&tcp.output_description,   &ccp.output_description),
-            (&tcp.principle,            &ccp.principle),
-            (&tcp.how_to_think,         &ccp.how_to_think),
-            (&tcp.examples,             &ccp.examples),
-        ];
-        
-        for (template_field, content_field) in field_pairs.iter() {
-            if let Some(value) = content_field.get(character_name)
-                .filter(|value| !value.is_empty())
-                .or_else(|| content_field.get("assistant"))
-                .filter(|value| !value.is_empty())
-            {
-                character_prompt_parts.push(build_element(
-                    &template_field.element_name,
-                    &template_field.description,
-                    value,
-                ));
+    // 基础模板合并内容文件的覆盖
+    // Merge the overrides of the content file into the base template
+    let overrides = &content.template_overrides;
+    let is_section = |key: &str| {
+        template_element(template, key).is_some() || tcp.extra_sections.iter().any(|extra| extra.key == key)
+    };
+    for key in overrides.keys().filter(|key| !is_section(key)) {
+        warn!("Ignoring override of unknown template element `{key}`");
+    }
+
+    // 按模板给出的顺序解析各个元素
+    // Resolve the elements in the order given by the template
+    let order: Vec<&str> = if tcp.section_order.is_empty() {
+        let mut order = Vec::with_capacity(DEFAULT_SECTION_ORDER.len() + tcp.extra_sections.len());
+        for &key in DEFAULT_SECTION_ORDER {
+            if key == CUSTOM_ELEMENTS_SECTION {
+                order.extend(tcp.extra_sections.iter().map(|extra| extra.key.as_str()));
             }
+            order.push(key);
         }
-        
-        // 处理阶段描述
-        // Process stage description
-        let mut stage_content = String::with_capacity(content.stage_prompt.len() * 50);
-        for stage_prompt in &content.stage_prompt {
-            stage_content.push_str(&format!("{}: {}\n", stage_prompt.name, stage_prompt.description));
+        order
+    } else {
+        let mut order: Vec<&str> = tcp.section_order.iter().map(String::as_str).collect();
+        if !order.contains(&CUSTOM_ELEMENTS_SECTION) {
+            order.push(CUSTOM_ELEMENTS_SECTION);
+        }
+        order
+    };
+    let sections: Vec<Section> = order
+        .into_iter()
+        .filter_map(|key| {
+            let element = |base: &TemplateElement| base.with_override(overrides.get(key));
+            match key {
+                CUSTOM_ELEMENTS_SECTION => Some(Section::Custom),
+                STAGE_SECTION => Some(Section::Stages(element(&tcp.stage_description))),
+                _ => {
+                    let section = match template_element(template, key) {
+                        Some(base) => Section::Element(element(base), content_field(ccp, key)),
+                        None => {
+                            let Some(extra) = tcp.extra_sections.iter().find(|extra| extra.key == key) else {
+                                warn!("Ignoring unknown template element `{key}` in the section order");
+                                return None;
+                            };
+                            Section::Element(element(&extra.element()), ccp.sections.get(key))
+                        }
+                    };
+                    Some(section)
+                }
+            }
+        })
+        .collect();
+
+    // 阶段描述对所有角色相同
+    // The stage description is the same for every character
+    let mut stage_content = String::with_capacity(content.stage_prompt.len() * 50);
+    for stage_prompt in &content.stage_prompt {
+        stage_content.push_str(&format!("{}: {}\n", stage_prompt.name, stage_prompt.description));
+    }
+
+    for character_name in &content.character_prompts.character_names {
+        // 预分配空间为可能的元素数量
+        // Pre-allocate space for possible elements
+        let mut character_prompt_parts = Vec::with_capacity(sections.len() + ccp.custom_elements.len());
+
+        for section in &sections {
+            match section {
+                Section::Element(template_field, content_field) => {
+                    if let Some(value) = content_field.and_then(|field| character_value(field, character_name)) {
+                        character_prompt_parts.push(build_element(
+                            tcp.format,
+                            &template_field.element_name,
+                            &template_field.description,
+                            value,
+                        ));
+                    }
+                }
+                Section::Custom => {
+                    for custom in &ccp.custom_elements {
+                        if let Some(value) = character_value(&custom.content, character_name) {
+                            character_prompt_parts.push(build_element(
+                                tcp.format,
+                                &custom.element_name,
+                                &custom.description,
+                                value,
+                            ));
+                        }
+                    }
+                }
+                Section::Stages(template_field) => {
+                    character_prompt_parts.push(build_element(
+                        tcp.format,
+                        &template_field.element_name,
+                        &template_field.description,
+                        &stage_content,
+                    ));
+                }
+            }
         }
         
-        character_prompt_parts.push(build_element(
-            &template.character_prompts.stage_description.element_name,
-            &template.character_prompts.stage_description.description,
-            &stage_content,
-        ));
-        
-        // 合并所有部分
-        // Combine all parts
-        result.insert(character_name.clone(), character_prompt_parts.join(""));
+        // 合并所有部分，非 XML 格式以空行分隔元素
+        // Combine all parts; formats other than XML separate the elements with a blank line
+        let separator = if tcp.format == PromptFormat::Xml { "" } else { "\n" };
+        character_prompt_parts.retain(|part| !part.is_empty());
+        result.insert(character_name.clone(), character_prompt_parts.join(separator));
     }
     
     result
 }
 
-/// 构建XML元素
-/// Build XML element
+/// 解析后的提示元素
+/// Resolved prompt element
+enum Section<'a> {
+    /// 模板元素及其在内容文件中的角色内容
+    /// Template element with its per-character content from the content file
+    Element(TemplateElement, Option<&'a HashMap<String, String>>),
+
+    /// 内容文件的自定义元素
+    /// Custom elements of the content file
+    Custom,
+
+    /// 阶段描述
+    /// Stage description
+    Stages(TemplateElement),
+}
+
+/// 模板中以字段名给出的内置元素
+/// Built-in template element by field name
+fn template_element<'a>(template: &'a Template, key: &str) -> Option<&'a TemplateElement> {
+    let tcp = &template.character_prompts;
+    match key {
+        "task_description" => Some(&tcp.task_description),
+        "input_description" => Some(&tcp.input_description),
+        "output_description" => Some(&tcp.output_description),
+        "principle" => Some(&tcp.principle),
+        "how_to_think" => Some(&tcp.how_to_think),
+        "examples" => Some(&tcp.examples),
+        STAGE_SECTION => Some(&tcp.stage_description),
+        _ => None,
+    }
+}
+
+/// 内容文件中内置元素的角色内容
+/// Per-character content of a built-in element in a content file
+fn content_field<'a>(ccp: &'a CharacterPrompts, key: &str) -> Option<&'a HashMap<String, String>> {
+    match key {
+        "task_description" => Some(&ccp.task_description),
+        "input_description" => Some(&ccp.input_description),
+        "output_description" => Some(&ccp.output_description),
+        "principle" => Some(&ccp.principle),
+        "how_to_think" => Some(&ccp.how_to_think),
+        "examples" => Some(&ccp.examples),
+        _ => None,
+    }
+}
+
+/// 角色在某个字段中的非空内容，没有时回退到 `assistant` 的内容
+/// Non-empty content of a character in a field, falling back to the content of `assistant`
+fn character_value<'a>(field: &'a HashMap<String, String>, character_name: &str) -> Option<&'a String> {
+    field
+        .get(character_name)
+        .filter(|value| !value.is_empty())
+        .or_else(|| field.get("assistant"))
+        .filter(|value| !value.is_empty())
+}
+
+/// 按呈现格式构建元素
+/// Build an element in the rendering format
 ///
 /// # 参数 (Parameters)
+/// * `format` - 呈现格式
+///   - Rendering format
 /// * `element_name` - 元素名称
-///                   - Element name
+///   - Element name
 /// * `element_description` - 元素描述
-///                         - Element description
+///   - Element description
 /// * `content` - 元素内容
-///             - Element content
+///   - Element content
 ///
 /// # 返回 (Returns)
-/// * `String` - 格式化的XML元素字符串
-///            - Formatted XML element string
+/// * `String` - 格式化的元素字符串，内容为空时为空字符串
+///   - Formatted element string, empty when the content is empty
 #[inline]
-fn build_element(element_name: &str, element_description: &str, content: &str) -> String {
+fn build_element(format: PromptFormat, element_name: &str, element_description: &str, content: &str) -> String {
     if content.is_empty() {
-        String::new()
-    } else {
-        // 预分配适当的容量
-        // Pre-allocate appropriate capacity
-        let capacity = element_name.len() * 2 + element_description.len() + content.len() + 20;
-        let mut result = String::with_capacity(capacity);
-        
-        result.push_str("<");
-        result.push_str(element_name);
-        result.push_str(">\n    <!-- ");
-        result.push_str(element_description);
-        result.push_str(" -->\n");
-        result.push_str(content);
-        result.push_str("</");
-        result.push_str(element_name);
-        result.push_str(">\n");
-        
-        result
+        return String::new();
+    }
+
+    // 预分配适当的容量
+    // Pre-allocate appropriate capacity
+    let capacity = element_name.len() * 2 + element_description.len() + content.len() + 20;
+    let mut result = String::with_capacity(capacity);
+    match format {
+        PromptFormat::Xml => {
+            result.push('<');
+            result.push_str(element_name);
+            result.push_str(">\n    <!-- ");
+            result.push_str(element_description);
+            result.push_str(" -->\n");
+            result.push_str(content);
+            result.push_str("</");
+            result.push_str(element_name);
+            result.push_str(">\n");
+        }
+        PromptFormat::Markdown => {
+            result.push_str("## ");
+            result.push_str(element_name);
+            result.push('\n');
+            if !element_description.is_empty() {
+                result.push_str("<!-- ");
+                result.push_str(element_description);
+                result.push_str(" -->\n");
+            }
+            result.push('\n');
+            result.push_str(content.trim_end());
+            result.push('\n');
+        }
+        PromptFormat::Plain => {
+            result.push_str(element_name);
+            result.push_str(":\n");
+            result.push_str(content.trim_end());
+            result.push('\n');
+        }
     }
+    result
 }
 
 /// 组装阶段提示
@@ -208,7 +371,22 @@ fn assemble_stage_prompt(content: &Content) -> HashMap<String, String>{
 /// * `error_stack::Result<String, OutputDescriptionError>` - 成功返回组装后的描述，失败返回错误
 ///                                                         - Returns assembled description on success, error on failure
 pub fn assemble_output_description(
-    json_schema: serde_json::Value,
+    json_schema: &serde_json::Value,
+) -> error_stack::Result<String, OutputDescriptionError> {
+    assemble_output_description_with_hidden(json_schema, DEFAULT_HIDDEN_FIELDS)
+}
+
+/// 组装输出描述，省略指定的字段
+/// Assemble output description, omitting the given fields
+///
+/// # 参数 (Parameters)
+/// * `json_schema` - JSON模式对象
+///   - JSON schema object
+/// * `hidden` - 不在描述中呈现的字段名，作用于所有嵌套层级
+///   - Field names left out of the description, at every nesting level
+pub fn assemble_output_description_with_hidden<S: AsRef<str>>(
+    json_schema: &serde_json::Value,
+    hidden: &[S],
 ) -> error_stack::Result<String, OutputDescriptionError> {
     // 获取json_schema字段
     // Get json_schema field
@@ -235,9 +413,14 @@ pub fn assemble_output_description(
     let schema = json_schema
         .get("schema")
         .ok_or(Report::new(OutputDescriptionError::MissingSchemaField))?;
-    let properties = schema
-        .get("properties")
-        .ok_or(Report::new(OutputDescriptionError::MissingPropertiesField))?;
+    // 顶层为联合类型时可以没有属性
+    // Properties may be absent when the top level is a union
+    let properties = schema.get("properties");
+    if properties.is_none() && union_of(schema).is_none() {
+        return Err(Report::new(OutputDescriptionError::MissingPropertiesField));
+    }
+    let defs = schema_definitions(schema);
+    let hidden: Vec<&str> = hidden.iter().map(AsRef::as_ref).collect();
 
     // 构造结果字符串，预先分配容量
     // Construct result string with pre-allocated capacity
@@ -247,7 +430,10 @@ pub fn assemble_output_description(
     result.push_str(": ");
     result.push_str(description);
     result.push_str("\n");
-    result.push_str(&extract_properties(properties, 1));
+    if let Some(properties) = properties {
+        result.push_str(&extract_properties_with_defs(properties, &defs, &hidden, 1));
+    }
+    result.push_str(&extract_alternatives(schema, &defs, &hidden, 1, &mut Vec::new()));
 
     Ok(result)
 }
@@ -317,7 +503,7 @@ pub fn assemble_tools_prompt(json_schema_vec: Vec<serde_json::Value>) -> error_s
 /// # 返回 (Returns)
 /// * `error_stack::Result<String, ChatToolSchemaError>` - 成功返回组装后的工具提示，失败返回错误
 ///                                                      - Returns assembled tool prompt on success, error on failure
-fn assemble_tool_prompt(json_schema: serde_json::Value) -> error_stack::Result<String, ChatToolSchemaError> {
+pub fn assemble_tool_prompt(json_schema: serde_json::Value) -> error_stack::Result<String, ChatToolSchemaError> {
     // 提取function对象
     // Extract function object
     let function = json_schema.get("function")
@@ -353,7 +539,7 @@ fn assemble_tool_prompt(json_schema: serde_json::Value) -> error_stack::Result<S
 
     // 提取和格式化属性信息
     // Extract and format property information
-    result.push_str(&extract_properties(properties, 1));
+    result.push_str(&extract_properties_with_defs(properties, &schema_definitions(parameters), DEFAULT_HIDDEN_FIELDS, 1));
 
     Ok(result)
 }
@@ -371,6 +557,79 @@ fn assemble_tool_prompt(json_schema: serde_json::Value) -> error_stack::Result<S
 /// * `String` - 格式化的属性信息字符串
 ///            - Formatted property information string
 pub fn extract_properties(properties: &serde_json::Value, indent: usize) -> String {
+    extract_properties_with_defs(properties, &serde_json::Map::new(), DEFAULT_HIDDEN_FIELDS, indent)
+}
+
+/// 提取属性信息，并从定义表中解析 `$ref`
+/// Extract property information, resolving `$ref` from a definitions map
+///
+/// # 参数 (Parameters)
+/// * `properties` - 属性对象
+///   - Properties object
+/// * `defs` - 定义表，见 [`schema_definitions`]
+///   - Definitions map, see [`schema_definitions`]
+/// * `hidden` - 跳过的字段名，如 [`DEFAULT_HIDDEN_FIELDS`]
+///   - Field names to skip, such as [`DEFAULT_HIDDEN_FIELDS`]
+/// * `indent` - 缩进级别
+///   - Indentation level
+///
+/// # 返回 (Returns)
+/// * `String` - 格式化的属性信息字符串
+///   - Formatted property information string
+pub fn extract_properties_with_defs<S: AsRef<str>>(
+    properties: &serde_json::Value,
+    defs: &serde_json::Map<String, serde_json::Value>,
+    hidden: &[S],
+    indent: usize,
+) -> String {
+    let hidden: Vec<&str> = hidden.iter().map(AsRef::as_ref).collect();
+    let mut visiting = Vec::new();
+    extract_properties_inner(properties, defs, &hidden, indent, &mut visiting)
+}
+
+/// 合并模式中的 `$defs` 与 `definitions`
+/// Merge `$defs` and `definitions` of a schema
+pub fn schema_definitions(schema: &serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+    let mut defs = serde_json::Map::new();
+    for key in ["definitions", "$defs"] {
+        if let Some(map) = schema.get(key).and_then(|d| d.as_object()) {
+            defs.extend(map.iter().map(|(name, def)| (name.clone(), def.clone())));
+        }
+    }
+    defs
+}
+
+/// 解析 `$ref`，返回定义名与解析后的模式；引用处的描述优先于定义中的描述
+/// Resolve `$ref`, returning the definition name and the resolved schema; a description at the reference wins over the definition's
+fn resolve_ref<'a>(
+    value: &'a serde_json::Value,
+    defs: &serde_json::Map<String, serde_json::Value>,
+) -> (Option<String>, Cow<'a, serde_json::Value>) {
+    let Some(reference) = value.get("$ref").and_then(|r| r.as_str()) else {
+        return (None, Cow::Borrowed(value));
+    };
+    let name = reference
+        .strip_prefix("#/$defs/")
+        .or_else(|| reference.strip_prefix("#/definitions/"))
+        .unwrap_or(reference);
+    let Some(mut resolved) = defs.get(name).cloned() else {
+        return (None, Cow::Borrowed(value));
+    };
+    if let (Some(resolved), Some(site)) = (resolved.as_object_mut(), value.as_object()) {
+        for (key, field) in site.iter().filter(|(key, _)| *key != "$ref") {
+            resolved.insert(key.clone(), field.clone());
+        }
+    }
+    (Some(name.to_string()), Cow::Owned(resolved))
+}
+
+fn extract_properties_inner(
+    properties: &serde_json::Value,
+    defs: &serde_json::Map<String, serde_json::Value>,
+    hidden: &[&str],
+    indent: usize,
+    visiting: &mut Vec<String>,
+) -> String {
     // 预估属性数量，为结果字符串分配合理容量
     // Estimate number of properties and allocate reasonable capacity
     let props_len = properties.as_object().map_or(0, |obj| obj.len());
@@ -379,49 +638,39 @@ pub fn extract_properties(properties: &serde_json::Value, indent: usize) -> Stri
 
     if let Some(props) = properties.as_object() {
         for (prop_name, prop_value) in props {
-            // 跳过"cot"属性
-            // Skip "cot" property
-            if prop_name == "cot" {
+            // 跳过隐藏的属性
+            // Skip hidden properties
+            if hidden.contains(&prop_name.as_str()) {
                 continue;
             }
-            
+
+            let (prop_ref, prop_value) = resolve_ref(prop_value, defs);
+            let prop_value = prop_value.as_ref();
+
             // 创建基本属性行，预先分配容量
             // Create basic property line with pre-allocated capacity
             let mut line = String::with_capacity(prop_name.len() + 100);
             line.push_str(&indent_str);
             line.push_str(prop_name);
 
-            // 提取常用字段为局部变量
-            // Extract commonly used fields as local variables
+            // 提取常用字段为局部变量，数组元素同样解析引用
+            // Extract commonly used fields as local variables, resolving references of array items as well
             let prop_type = prop_value.get("type");
             let prop_desc = prop_value.get("description").and_then(|d| d.as_str());
-            let prop_enum = prop_value.get("enum");
+            let (items_ref, items) = match prop_value.get("items") {
+                Some(items) => {
+                    let (items_ref, items) = resolve_ref(items, defs);
+                    (items_ref, Some(items))
+                }
+                None => (None, None),
+            };
+            let prop_enum = prop_value
+                .get("enum")
+                .or_else(|| items.as_deref().and_then(|items| items.get("enum")));
 
             // 添加类型信息
             // Add type information
-            if let Some(type_val) = prop_type {
-                match type_val {
-                    serde_json::Value::String(type_str) => {
-                        line.push_str(" (");
-                        line.push_str(type_str);
-                        line.push_str(")");
-                    }
-                    serde_json::Value::Array(type_array) => {
-                        let mut types = Vec::with_capacity(type_array.len());
-                        for v in type_array {
-                            if let Some(s) = v.as_str() {
-                                types.push(s.to_string());
-                            }
-                        }
-                        if !types.is_empty() {
-                            line.push_str(" ([");
-                            line.push_str(&types.join(", "));
-                            line.push_str("])");
-                        }
-                    }
-                    _ => {}
-                }
-            }
+            push_type_info(&mut line, prop_type);
 
             // 添加描述信息
             // Add description information
@@ -430,38 +679,199 @@ pub fn extract_properties(properties: &serde_json::Value, indent: usize) -> Stri
                 line.push_str(desc);
             }
 
-            // 添加枚举信息
-            // Add enum information
-            if let Some(enum_val) = prop_enum {
-                if let Some(enum_values) = enum_val.as_array() {
-                    let mut enum_strings = Vec::with_capacity(enum_values.len());
-                    for v in enum_values {
-                        if let Some(s) = v.as_str() {
-                            enum_strings.push(s.to_string());
-                        }
-                    }
-                    if !enum_strings.is_empty() {
-                        line.push_str(" (Enum: [");
-                        line.push_str(&enum_strings.join(", "));
-                        line.push_str("])");
-                    }
-                }
-            }
+            // 添加枚举与常量信息
+            // Add enum and constant information
+            push_enum_info(&mut line, prop_enum);
+            push_const_info(&mut line, prop_value.get("const"));
 
             // 添加属性行到结果
             // Add property line to result
             line.push('\n');
             result.push_str(&line);
 
-            // 递归处理嵌套对象
-            // Recursively process nested objects
-            if prop_type == Some(&serde_json::Value::String("object".to_string())) {
-                if let Some(sub_properties) = prop_value.get("properties") {
-                    result.push_str(&extract_properties(sub_properties, indent + 1));
-                }
+            // 递归处理嵌套对象与对象数组的元素
+            // Recursively process nested objects and items of object arrays
+            let nested = match items.as_deref() {
+                Some(items) => items.get("properties").map(|sub| (sub, items_ref)),
+                None => prop_value.get("properties").map(|sub| (sub, prop_ref)),
+            };
+            if let Some((sub_properties, name)) = nested {
+                result.push_str(&extract_nested(sub_properties, name, defs, hidden, indent + 1, visiting));
+            }
+
+            // 展开联合类型（自身或数组元素）的各个形式
+            // Expand every alternative of a union, on the property itself or on its array items
+            let union_holder = items
+                .as_deref()
+                .filter(|items| union_of(items).is_some())
+                .unwrap_or(prop_value);
+            result.push_str(&extract_alternatives(union_holder, defs, hidden, indent + 1, visiting));
+        }
+    }
+
+    result
+}
+
+/// 展开嵌套属性，已在展开中的定义不再展开以避免循环
+/// Expand nested properties; definitions already being expanded are skipped to avoid cycles
+fn extract_nested(
+    properties: &serde_json::Value,
+    name: Option<String>,
+    defs: &serde_json::Map<String, serde_json::Value>,
+    hidden: &[&str],
+    indent: usize,
+    visiting: &mut Vec<String>,
+) -> String {
+    let Some(name) = name else {
+        return extract_properties_inner(properties, defs, hidden, indent, visiting);
+    };
+    if visiting.contains(&name) {
+        return String::new();
+    }
+    visiting.push(name);
+    let result = extract_properties_inner(properties, defs, hidden, indent, visiting);
+    visiting.pop();
+    result
+}
+
+/// 模式中的联合类型，返回是否为 `oneOf` 与各个形式
+/// Union of a schema, returning whether it is `oneOf` and its alternatives
+fn union_of(schema: &serde_json::Value) -> Option<(bool, &Vec<serde_json::Value>)> {
+    if let Some(alternatives) = schema.get("oneOf").and_then(|a| a.as_array()) {
+        Some((true, alternatives))
+    } else {
+        schema.get("anyOf").and_then(|a| a.as_array()).map(|alternatives| (false, alternatives))
+    }
+}
+
+/// 形式中判别字段的固定取值（`const` 或只有一个值的 `enum`）
+/// Fixed value of the discriminator field in an alternative (`const` or a single-value `enum`)
+fn discriminator_value<'a>(alternative: &'a serde_json::Value, field: &str) -> Option<&'a serde_json::Value> {
+    let property = alternative.get("properties")?.get(field)?;
+    property.get("const").or_else(|| {
+        property
+            .get("enum")
+            .and_then(|e| e.as_array())
+            .filter(|values| values.len() == 1)
+            .map(|values| &values[0])
+    })
+}
+
+/// 展开联合类型的各个形式，并说明用于区分它们的判别字段
+/// Expand every alternative of a union and explain the discriminator field telling them apart
+///
+/// 判别字段取自 `discriminator.propertyName`，否则取每个形式中都有固定取值的第一个字段
+/// The discriminator comes from `discriminator.propertyName`, otherwise it is the first field
+/// with a fixed value in every alternative
+fn extract_alternatives(
+    schema: &serde_json::Value,
+    defs: &serde_json::Map<String, serde_json::Value>,
+    hidden: &[&str],
+    indent: usize,
+    visiting: &mut Vec<String>,
+) -> String {
+    let Some((one_of, alternatives)) = union_of(schema) else {
+        return String::new();
+    };
+    let resolved: Vec<_> = alternatives.iter().map(|alternative| resolve_ref(alternative, defs)).collect();
+    let discriminator = schema
+        .pointer("/discriminator/propertyName")
+        .and_then(|d| d.as_str())
+        .map(str::to_string)
+        .or_else(|| {
+            let (_, first) = resolved.first().filter(|_| resolved.len() > 1)?;
+            let fields = first.get("properties")?.as_object()?;
+            fields
+                .keys()
+                .find(|field| resolved.iter().all(|(_, alternative)| discriminator_value(alternative, field).is_some()))
+                .cloned()
+        });
+
+    let indent_str = "  ".repeat(indent);
+    let mut result = String::with_capacity(resolved.len() * 128);
+    result.push_str(&indent_str);
+    result.push_str(if one_of { "必须且只能符合以下其中一种形式" } else { "需符合以下至少一种形式" });
+    if let Some(field) = &discriminator {
+        result.push_str("，由 ");
+        result.push_str(field);
+        result.push_str(" 字段的取值区分");
+    }
+    result.push('\n');
+
+    for (index, (name, alternative)) in resolved.iter().enumerate() {
+        let alternative = alternative.as_ref();
+        let mut line = format!("{indent_str}- 形式 {}", index + 1);
+        if let Some(title) = alternative.get("title").and_then(|t| t.as_str()).or(name.as_deref()) {
+            line.push(' ');
+            line.push_str(title);
+        }
+        match discriminator.as_ref().and_then(|field| Some((field, discriminator_value(alternative, field)?))) {
+            Some((field, value)) => {
+                line.push_str(&format!(" ({field} = {value})"));
+            }
+            None => {
+                push_type_info(&mut line, alternative.get("type"));
+                push_const_info(&mut line, alternative.get("const"));
             }
         }
+        if let Some(desc) = alternative.get("description").and_then(|d| d.as_str()) {
+            line.push_str(": ");
+            line.push_str(desc);
+        }
+        push_enum_info(&mut line, alternative.get("enum"));
+        line.push('\n');
+        result.push_str(&line);
+
+        if let Some(properties) = alternative.get("properties") {
+            result.push_str(&extract_nested(properties, name.clone(), defs, hidden, indent + 1, visiting));
+        }
+        result.push_str(&extract_alternatives(alternative, defs, hidden, indent + 1, visiting));
     }
 
     result
-}
\ No newline at end of file
+}
+
+/// 添加类型信息，如 ` (string)` 或 ` ([string, null])`
+/// Add type information, e.g. ` (string)` or ` ([string, null])`
+fn push_type_info(line: &mut String, type_val: Option<&serde_json::Value>) {
+    match type_val {
+        Some(serde_json::Value::String(type_str)) => {
+            line.push_str(" (");
+            line.push_str(type_str);
+            line.push(')');
+        }
+        Some(serde_json::Value::Array(type_array)) => {
+            let types: Vec<&str> = type_array.iter().filter_map(|v| v.as_str()).collect();
+            if !types.is_empty() {
+                line.push_str(" ([");
+                line.push_str(&types.join(", "));
+                line.push_str("])");
+            }
+        }
+        _ => {}
+    }
+}
+
+/// 添加字符串枚举信息
+/// Add string enum information
+fn push_enum_info(line: &mut String, enum_val: Option<&serde_json::Value>) {
+    let Some(enum_values) = enum_val.and_then(|e| e.as_array()) else {
+        return;
+    };
+    let enum_strings: Vec<&str> = enum_values.iter().filter_map(|v| v.as_str()).collect();
+    if !enum_strings.is_empty() {
+        line.push_str(" (Enum: [");
+        line.push_str(&enum_strings.join(", "));
+        line.push_str("])");
+    }
+}
+
+/// 添加常量信息
+/// Add constant information
+fn push_const_info(line: &mut String, const_val: Option<&serde_json::Value>) {
+    if let Some(value) = const_val {
+        line.push_str(" (Const: ");
+        line.push_str(&value.to_string());
+        line.push(')');
+    }
+}
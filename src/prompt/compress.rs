@@ -0,0 +1,242 @@
+use std::collections::HashSet;
+
+use error_stack::{Result, ResultExt};
+use thiserror::Error;
+
+use crate::chat::chat_base::BaseChat;
+use crate::chat::context::ContextMessage;
+use crate::chat::message::{Role, Session};
+use crate::chat::usage::count_tokens;
+
+/// 剪除时忽略的英文停用词
+/// English stopwords dropped when pruning
+const STOPWORDS: &[&str] = &[
+    "a", "an", "the", "of", "to", "in", "on", "at", "by", "for", "with", "from", "and", "or", "but", "so", "as",
+    "is", "are", "was", "were", "be", "been", "being", "am", "do", "does", "did", "that", "this", "these",
+    "those", "it", "its", "there", "here", "very", "really", "just", "quite", "rather", "then", "than", "also",
+    "which", "who", "whom", "would", "could", "should", "shall", "will", "can", "may", "might", "must", "please",
+];
+
+/// 省略重复内容时留下的标记
+/// Marker left where repeated content was elided
+pub const ELISION_MARKER: &str = "[…]";
+
+/// 参与重复省略的最短行长度（字符），更短的行（如代码中的括号）总会保留
+/// Shortest line (in characters) considered for elision; shorter lines, such as braces in code, are always kept
+const MIN_ELIDED_CHARS: usize = 16;
+
+const MODEL_PASS_PROMPT: &str = "在不丢失关键事实、数字、名称与指令的前提下压缩下面的文本，可以删去冗余词句、改用简短表达，只输出压缩后的文本";
+
+#[derive(Debug, Error)]
+pub enum CompressError {
+    #[error("Model pass failed")]
+    ModelPassError,
+}
+
+/// 压缩结果
+/// Compression result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressedPrompt {
+    pub text: String,
+
+    pub original_tokens: u64,
+
+    pub tokens: u64,
+}
+
+/// 提示压缩器：依次省略重复内容、剪除停用词，仍超出目标时可再交由模型压缩
+/// Prompt compressor: elides repeated content, then prunes stopwords, then optionally lets a model compress what is still over the target
+///
+/// 每一步只在超出目标令牌数时执行；代码块内的内容不会被剪除
+/// Each step only runs while the text is over the target token count; content inside code fences is never pruned
+#[derive(Debug, Clone)]
+pub struct PromptCompressor {
+    /// 模型名，用于计算令牌数
+    /// Model name, used to count tokens
+    pub model: String,
+
+    pub target_tokens: u64,
+
+    pub elide_repeats: bool,
+
+    pub prune_stopwords: bool,
+
+    model_pass: Option<BaseChat>,
+}
+
+impl PromptCompressor {
+    pub fn new(model: &str, target_tokens: u64) -> Self {
+        Self {
+            model: model.to_string(),
+            target_tokens,
+            elide_repeats: true,
+            prune_stopwords: true,
+            model_pass: None,
+        }
+    }
+
+    pub fn with_repeat_elision(mut self, elide_repeats: bool) -> Self {
+        self.elide_repeats = elide_repeats;
+        self
+    }
+
+    pub fn with_stopword_pruning(mut self, prune_stopwords: bool) -> Self {
+        self.prune_stopwords = prune_stopwords;
+        self
+    }
+
+    /// 规则压缩后仍超出目标时，用该对话（通常为 Cheap 档位模型）做一次 LLMLingua 式压缩
+    /// When rule-based steps leave the text over the target, run an LLMLingua-style pass with this chat (usually a Cheap tier model)
+    pub fn with_model_pass(mut self, chat: BaseChat) -> Self {
+        self.model_pass = Some(chat);
+        self
+    }
+
+    fn tokens(&self, text: &str) -> u64 {
+        count_tokens(&self.model, text)
+    }
+
+    fn total_tokens(&self, messages: &[ContextMessage]) -> u64 {
+        messages.iter().map(|m| self.tokens(content(m))).sum()
+    }
+
+    /// 压缩一段提示
+    /// Compress one prompt
+    pub async fn compress(&self, text: &str) -> Result<CompressedPrompt, CompressError> {
+        let message = ContextMessage::from([("content".to_string(), text.to_string())]);
+        let original_tokens = self.tokens(text);
+        let mut messages = self.compress_messages(vec![message]).await?;
+        let text = messages.pop().and_then(|mut m| m.remove("content")).unwrap_or_default();
+        Ok(CompressedPrompt {
+            tokens: self.tokens(&text),
+            text,
+            original_tokens,
+        })
+    }
+
+    /// 压缩一组消息，目标令牌数针对全部内容；较早消息中出现过的行在后续消息里被省略
+    /// Compress a list of messages against one target for all content; lines seen in earlier messages are elided from later ones
+    ///
+    /// 可在 `build_request_body` 之前对要发送的消息使用
+    /// Meant for the messages to be sent, before `build_request_body`
+    pub async fn compress_messages(
+        &self,
+        mut messages: Vec<ContextMessage>,
+    ) -> Result<Vec<ContextMessage>, CompressError> {
+        if self.elide_repeats && self.total_tokens(&messages) > self.target_tokens {
+            let mut seen = HashSet::new();
+            for message in &mut messages {
+                let elided = elide_repeats(content(message), &mut seen);
+                message.insert("content".to_string(), elided);
+            }
+        }
+
+        if self.prune_stopwords && self.total_tokens(&messages) > self.target_tokens {
+            for message in &mut messages {
+                let pruned = prune_stopwords(content(message));
+                message.insert("content".to_string(), pruned);
+            }
+        }
+
+        let Some(chat) = &self.model_pass else {
+            return Ok(messages);
+        };
+        let total = self.total_tokens(&messages);
+        if total <= self.target_tokens {
+            return Ok(messages);
+        }
+
+        // 从最长的消息开始按比例压缩，直到满足目标
+        // Compress proportionally starting from the longest message until the target is met
+        let ratio = self.target_tokens as f64 / total as f64;
+        let mut order: Vec<(usize, u64)> = messages.iter().map(|m| self.tokens(content(m))).enumerate().collect();
+        order.sort_by_key(|&(_, tokens)| std::cmp::Reverse(tokens));
+        for (i, tokens) in order {
+            if self.total_tokens(&messages) <= self.target_tokens {
+                break;
+            }
+            let target = ((tokens as f64 * ratio) as u64).max(1);
+            let compressed = model_pass(chat, content(&messages[i]), target).await?;
+            if self.tokens(&compressed) < tokens {
+                messages[i].insert("content".to_string(), compressed);
+            }
+        }
+        Ok(messages)
+    }
+}
+
+fn content(message: &ContextMessage) -> &str {
+    message.get("content").map(String::as_str).unwrap_or_default()
+}
+
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with("```")
+}
+
+/// 省略已出现过的较长行，连续被省略的行只留一个标记
+/// Elide long lines seen before; a run of elided lines leaves a single marker
+fn elide_repeats(text: &str, seen: &mut HashSet<String>) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    for line in text.lines() {
+        let key = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if key.chars().count() < MIN_ELIDED_CHARS || seen.insert(key) {
+            lines.push(line);
+        } else if lines.last() != Some(&ELISION_MARKER) {
+            lines.push(ELISION_MARKER);
+        }
+    }
+    lines.join("\n")
+}
+
+/// 剪除代码块外的英文停用词，保留词后的标点
+/// Prune English stopwords outside code fences, keeping punctuation that followed them
+fn prune_stopwords(text: &str) -> String {
+    let mut in_code = false;
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        if is_fence(line) {
+            in_code = !in_code;
+            lines.push(line.to_string());
+            continue;
+        }
+        if in_code {
+            lines.push(line.to_string());
+            continue;
+        }
+
+        let mut words: Vec<String> = Vec::new();
+        for word in line.split_whitespace() {
+            let core = word.trim_end_matches(|c: char| c.is_ascii_punctuation());
+            if !STOPWORDS.contains(&core.to_ascii_lowercase().as_str()) {
+                words.push(word.to_string());
+            } else if core.len() < word.len()
+                && let Some(previous) = words.last_mut()
+            {
+                previous.push_str(&word[core.len()..]);
+            }
+        }
+        let indent = &line[..line.len() - line.trim_start().len()];
+        lines.push(format!("{indent}{}", words.join(" ")));
+    }
+    lines.join("\n")
+}
+
+async fn model_pass(chat: &BaseChat, text: &str, target_tokens: u64) -> Result<String, CompressError> {
+    let mut chat = chat.clone();
+    chat.session = Session::new();
+    chat.add_message(Role::System, &format!("{MODEL_PASS_PROMPT}，目标约 {target_tokens} 个令牌"))
+        .change_context(CompressError::ModelPassError)?;
+    chat.add_message(Role::User, text).change_context(CompressError::ModelPassError)?;
+    let request_body = chat
+        .build_request_body(&chat.session.default_path.clone(), &Role::User)
+        .await
+        .change_context(CompressError::ModelPassError)?;
+    let response = chat
+        .get_response(request_body)
+        .await
+        .change_context(CompressError::ModelPassError)?;
+    Ok(BaseChat::get_content_from_resp(&response)
+        .change_context(CompressError::ModelPassError)?
+        .trim()
+        .to_string())
+}
@@ -1,8 +1,15 @@
 use once_cell::sync::Lazy;
 use crate::prompt::model::Prompts;
+use crate::runtime::Runtime;
 
 pub mod model;
 pub mod assembler;
 pub mod loader;
+pub mod compress;
+pub mod injection;
+pub mod sanitize;
+pub mod testing;
 
-pub static PROMPTS: Lazy<Prompts> = Lazy::new(Prompts::init_unchecked);
\ No newline at end of file
+/// 默认运行时的提示
+/// Prompts of the default runtime
+pub static PROMPTS: Lazy<&'static Prompts> = Lazy::new(|| Runtime::default_ref().prompts());
\ No newline at end of file
@@ -117,11 +117,83 @@ pub struct CharacterPromptsTemplate {
     /// 示例模板元素
     /// Examples template element
     pub examples: TemplateElement,
+
+    /// 元素的呈现顺序，写元素的字段名或额外元素的键；`custom_elements` 表示内容文件的自定义元素
+    /// Rendering order of the elements, by field name or extra element key; `custom_elements` stands for the custom
+    /// elements of content files
+    ///
+    /// 为空时使用默认顺序；不为空时未列出的元素不呈现，但未列出的自定义元素仍放在最后
+    /// The default order applies when empty; otherwise unlisted elements are left out, except custom elements which
+    /// still go last when unlisted
+    #[serde(default)]
+    pub section_order: Vec<String>,
+
+    /// 模板定义的额外元素，内容写在内容文件的 `character_prompts.sections.<key>` 中
+    /// Extra elements defined by the template, with content under `character_prompts.sections.<key>` of content files
+    #[serde(default)]
+    pub extra_sections: Vec<SectionTemplate>,
+
+    /// 元素的呈现格式，默认为 XML
+    /// Rendering format of the elements, XML by default
+    #[serde(default)]
+    pub format: PromptFormat,
+}
+
+/// 提示元素的呈现格式，可按模型系列偏好的风格选择
+/// Rendering format of prompt elements, chosen to match the style a model family responds to best
+///
+/// ```toml
+/// [character_prompts]
+/// format = "markdown"
+/// ```
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptFormat {
+    /// `<Name>` 标签包裹内容，描述写作注释
+    /// Content wrapped in `<Name>` tags, with the description as a comment
+    #[default]
+    Xml,
+
+    /// `## Name` 标题后接内容，描述写作注释
+    /// `## Name` heading followed by the content, with the description as a comment
+    Markdown,
+
+    /// `Name:` 后接内容，不带描述
+    /// `Name:` followed by the content, without the description
+    Plain,
+}
+
+/// 模板定义的额外元素
+/// Extra element defined by a template
+///
+/// ```toml
+/// [[character_prompts.extra_sections]]
+/// key = "background"
+/// element_name = "Background"
+/// description = "背景资料"
+/// ```
+#[derive(Clone, Debug, Deserialize, Default, PartialEq, Eq)]
+pub struct SectionTemplate {
+    pub key: String,
+
+    pub element_name: String,
+
+    #[serde(default)]
+    pub description: String,
+}
+
+impl SectionTemplate {
+    pub fn element(&self) -> TemplateElement {
+        TemplateElement {
+            element_name: self.element_name.clone(),
+            description: self.description.clone(),
+        }
+    }
 }
 
 /// 模板元素结构体，包含元素名称和描述
 /// Template element struct containing element name and description
-#[derive(Debug, Deserialize, Default)]
+#[derive(Clone, Debug, Deserialize, Default, PartialEq, Eq)]
 pub struct TemplateElement {
     /// 元素名称
     /// Element name
@@ -132,6 +204,37 @@ pub struct TemplateElement {
     pub description: String,
 }
 
+impl TemplateElement {
+    /// 应用内容文件的覆盖，未给出的项沿用模板
+    /// Apply an override from a content file; items left out keep the template's
+    pub fn with_override(&self, element_override: Option<&ElementOverride>) -> Self {
+        let Some(element_override) = element_override else {
+            return self.clone();
+        };
+        Self {
+            element_name: element_override
+                .element_name
+                .clone()
+                .unwrap_or_else(|| self.element_name.clone()),
+            description: element_override
+                .description
+                .clone()
+                .unwrap_or_else(|| self.description.clone()),
+        }
+    }
+}
+
+/// 内容文件对单个模板元素的覆盖
+/// Override of one template element in a content file
+#[derive(Clone, Debug, Deserialize, Default, PartialEq, Eq)]
+pub struct ElementOverride {
+    #[serde(default)]
+    pub element_name: Option<String>,
+
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
 //======================================================================
 // TOML内容结构定义
 // TOML content structure definitions
@@ -148,7 +251,17 @@ pub struct Content {
     /// 阶段提示列表，默认为空
     /// Stage prompt list, defaults to empty
     #[serde(default)]
-    pub stage_prompt: Vec<StagePrompt>
+    pub stage_prompt: Vec<StagePrompt>,
+
+    /// 对基础模板元素的覆盖，以模板中的字段名或额外元素的键为键，如 `principle`
+    /// Overrides of base template elements, keyed by the template field name or extra element key such as `principle`
+    ///
+    /// ```toml
+    /// [template_overrides.principle]
+    /// element_name = "Rules"
+    /// ```
+    #[serde(default)]
+    pub template_overrides: HashMap<String, ElementOverride>,
 }
 
 /// 返回默认角色名称列表
@@ -171,12 +284,15 @@ pub struct CharacterPrompts {
     #[serde(default)]
     pub task_description: HashMap<String, String>,
     
-    // 暂时注释掉的字段
-    // Temporarily commented fields
-    // #[serde(default)]
-    // pub input_description: HashMap<String, String>,
-    // #[serde(default)]
-    // pub output_description: HashMap<String, String>,
+    /// 输入描述映射，默认为空
+    /// Input description mapping, defaults to empty
+    #[serde(default)]
+    pub input_description: HashMap<String, String>,
+
+    /// 输出描述映射，默认为空
+    /// Output description mapping, defaults to empty
+    #[serde(default)]
+    pub output_description: HashMap<String, String>,
     
     /// 原则映射，默认为空
     /// Principle mapping, defaults to empty
@@ -192,6 +308,38 @@ pub struct CharacterPrompts {
     /// Examples mapping, defaults to empty
     #[serde(default)]
     pub examples: HashMap<String, String>,
+
+    /// 模板额外元素的内容，以元素的键为键
+    /// Content of the template's extra elements, keyed by element key
+    #[serde(default)]
+    pub sections: HashMap<String, HashMap<String, String>>,
+
+    /// 模板之外的自定义元素，按文件顺序放在模板元素之后
+    /// Custom elements beyond the template, placed after the template elements in file order
+    #[serde(default)]
+    pub custom_elements: Vec<CustomElement>,
+}
+
+/// 内容文件自带的提示元素
+/// Prompt element defined by a content file itself
+///
+/// ```toml
+/// [[character_prompts.custom_elements]]
+/// element_name = "Glossary"
+/// description = "术语表"
+/// content = { assistant = "..." }
+/// ```
+#[derive(Clone, Debug, Deserialize, Default)]
+pub struct CustomElement {
+    pub element_name: String,
+
+    #[serde(default)]
+    pub description: String,
+
+    /// 角色名称到元素内容的映射，缺少的角色使用 `assistant` 的内容
+    /// Mapping from character name to element content; missing characters use the content of `assistant`
+    #[serde(default)]
+    pub content: HashMap<String, String>,
 }
 
 /// 阶段提示结构体，包含名称、描述和内容
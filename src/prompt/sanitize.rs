@@ -0,0 +1,145 @@
+use std::borrow::Cow;
+
+use error_stack::{Report, Result};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use thiserror::Error;
+
+/// 类似 XML 标签的片段：开始、结束与自闭合标签，以及注释与 CDATA 的边界
+/// Tag-like fragments: opening, closing and self-closing tags, plus comment and CDATA delimiters
+static TAG_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"</?\s*([A-Za-z_][\w.:-]*)(?:\s[^<>]*)?/?>|<!--|-->|<!\[CDATA\[|\]\]>").expect("valid tag pattern")
+});
+
+#[derive(Debug, Error)]
+pub enum SanitizeError {
+    #[error("Untrusted content contains tag: {0}")]
+    TagRejected(String),
+}
+
+/// 不可信内容的来源
+/// Source of untrusted content
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum UntrustedSource {
+    /// 用户输入
+    /// User input
+    UserInput,
+
+    /// 工具调用结果
+    /// Tool call result
+    ToolResult,
+}
+
+/// 发现标签后的处理方式
+/// Action taken on tags found in untrusted content
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SanitizeAction {
+    /// 把 `<` 与 `>` 转义为 `&lt;` 与 `&gt;`
+    /// Escape `<` and `>` as `&lt;` and `&gt;`
+    #[default]
+    Escape,
+
+    /// 删除标签，保留标签之间的文本
+    /// Remove the tags, keeping the text between them
+    Strip,
+
+    /// 拒绝并返回 `SanitizeError::TagRejected`
+    /// Reject with `SanitizeError::TagRejected`
+    Reject,
+}
+
+/// 插入提示前对不可信内容的处理策略，防止伪造的 `</ToolUse>` 或元素标签注入提示
+/// Policy for untrusted content before it is interpolated into prompts, so fake `</ToolUse>` or element tags can't
+/// inject into the prompt
+///
+/// 默认转义用户输入与工具结果中的全部标签；`a < b` 这类不构成标签的文本保持原样
+/// By default every tag in user input and tool results is escaped; text such as `a < b` that forms no tag is kept as is
+#[derive(Clone, Debug)]
+pub struct SanitizePolicy {
+    pub action: SanitizeAction,
+
+    /// 只处理这些名称的标签（大小写不敏感），为空时处理全部标签与注释、CDATA 边界
+    /// Only tags with these names are handled (case-insensitive); when empty every tag, comment and CDATA delimiter is
+    pub tags: Vec<String>,
+
+    /// 需要处理的来源
+    /// Sources that are handled
+    pub sources: Vec<UntrustedSource>,
+}
+
+impl Default for SanitizePolicy {
+    fn default() -> Self {
+        Self {
+            action: SanitizeAction::default(),
+            tags: Vec::new(),
+            sources: vec![UntrustedSource::UserInput, UntrustedSource::ToolResult],
+        }
+    }
+}
+
+impl SanitizePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_action(mut self, action: SanitizeAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn with_tags<I, S>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.tags = tags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_sources(mut self, sources: impl IntoIterator<Item = UntrustedSource>) -> Self {
+        self.sources = sources.into_iter().collect();
+        self
+    }
+
+    /// 按策略处理来自 `source` 的内容，不处理的来源原样返回
+    /// Sanitize content from `source` under the policy; sources not handled are returned unchanged
+    pub fn sanitize_from<'a>(&self, text: &'a str, source: UntrustedSource) -> Result<Cow<'a, str>, SanitizeError> {
+        if !self.sources.contains(&source) {
+            return Ok(Cow::Borrowed(text));
+        }
+        self.sanitize(text)
+    }
+
+    /// 按策略处理内容，没有需要处理的标签时不复制
+    /// Sanitize content under the policy without copying when no tag needs handling
+    pub fn sanitize<'a>(&self, text: &'a str) -> Result<Cow<'a, str>, SanitizeError> {
+        let matches = |caps: &Captures| match caps.get(1) {
+            Some(name) => self.tags.is_empty() || self.tags.iter().any(|tag| tag.eq_ignore_ascii_case(name.as_str())),
+            None => self.tags.is_empty(),
+        };
+
+        if self.action == SanitizeAction::Reject {
+            return match TAG_PATTERN.captures_iter(text).find(|caps| matches(caps)) {
+                Some(caps) => Err(Report::new(SanitizeError::TagRejected(caps[0].to_string()))),
+                None => Ok(Cow::Borrowed(text)),
+            };
+        }
+
+        Ok(TAG_PATTERN.replace_all(text, |caps: &Captures| {
+            let tag = &caps[0];
+            if !matches(caps) {
+                return tag.to_string();
+            }
+            match self.action {
+                SanitizeAction::Strip => String::new(),
+                _ => escape(tag),
+            }
+        }))
+    }
+}
+
+/// 转义文本中的 `&`、`<` 与 `>`
+/// Escape `&`, `<` and `>` in text
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
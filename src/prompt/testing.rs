@@ -0,0 +1,151 @@
+use std::path::{Path, PathBuf};
+
+use error_stack::{Report, Result, ResultExt};
+use thiserror::Error;
+
+use crate::prompt::model::Prompt;
+
+/// 指定快照目录的环境变量，未设置时使用 `DEFAULT_SNAPSHOT_DIR`
+/// Environment variable naming the snapshot directory; `DEFAULT_SNAPSHOT_DIR` is used when it is unset
+pub const SNAPSHOT_DIR_ENV: &str = "RHINE_PROMPT_SNAPSHOTS";
+
+/// 设为 `1` 时以当前渲染结果覆盖快照文件
+/// When set to `1`, snapshot files are overwritten with the current rendering
+pub const UPDATE_SNAPSHOTS_ENV: &str = "RHINE_UPDATE_SNAPSHOTS";
+
+/// 相对于当前工作目录（`cargo test` 下为包根目录）的默认快照目录
+/// Default snapshot directory, relative to the working directory (the package root under `cargo test`)
+pub const DEFAULT_SNAPSHOT_DIR: &str = "tests/snapshots/prompts";
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("Failed to render prompt for character: {0}")]
+    RenderError(String),
+
+    #[error("Snapshot not found: {0}")]
+    SnapshotNotFound(String),
+
+    #[error("Failed to read snapshot: {0}")]
+    ReadError(String),
+
+    #[error("Failed to write snapshot: {0}")]
+    WriteError(String),
+
+    #[error("Prompt differs from snapshot {path} at line {line}")]
+    Mismatch {
+        path: String,
+        line: usize,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// 提示快照：把渲染出的角色提示与快照文件逐字比较，防止组装逻辑的改动悄悄改变模型收到的内容
+/// Prompt snapshots: rendered character prompts are compared verbatim against snapshot files, so changes to the
+/// assembler can't silently alter what models receive
+#[derive(Debug, Clone)]
+pub struct PromptSnapshots {
+    dir: PathBuf,
+
+    update: bool,
+}
+
+impl PromptSnapshots {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            update: false,
+        }
+    }
+
+    /// 从 `SNAPSHOT_DIR_ENV` 与 `UPDATE_SNAPSHOTS_ENV` 读取目录与更新模式
+    /// Read the directory and update mode from `SNAPSHOT_DIR_ENV` and `UPDATE_SNAPSHOTS_ENV`
+    pub fn from_env() -> Self {
+        let dir = std::env::var(SNAPSHOT_DIR_ENV).unwrap_or_else(|_| DEFAULT_SNAPSHOT_DIR.to_string());
+        let update = std::env::var(UPDATE_SNAPSHOTS_ENV).is_ok_and(|value| value == "1");
+        Self::new(dir).with_update(update)
+    }
+
+    /// 更新模式下不做比较，直接写入快照文件
+    /// In update mode nothing is compared and the snapshot file is written instead
+    pub fn with_update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+
+    /// 快照文件路径：`<目录>/<名称>.<角色>.txt`
+    /// Snapshot file path: `<dir>/<name>.<character>.txt`
+    pub fn path(&self, name: &str, character: &str) -> PathBuf {
+        self.dir.join(format!("{name}.{character}.txt"))
+    }
+
+    /// 渲染角色提示并与快照比较，更新模式下写入快照
+    /// Render the character prompt and compare it with the snapshot, or write the snapshot in update mode
+    pub fn check(&self, name: &str, prompt: &Prompt, character: &str) -> Result<(), SnapshotError> {
+        let rendered = prompt
+            .character(character)
+            .change_context(SnapshotError::RenderError(character.to_string()))?;
+        let path = self.path(name, character);
+        if self.update {
+            return write_snapshot(&path, &rendered);
+        }
+
+        let display = path.display().to_string();
+        if !path.exists() {
+            return Err(Report::new(SnapshotError::SnapshotNotFound(display)))
+                .attach_printable(format!("Run with {UPDATE_SNAPSHOTS_ENV}=1 to create it"));
+        }
+        let expected = std::fs::read_to_string(&path).change_context(SnapshotError::ReadError(display.clone()))?;
+        match first_difference(&expected, &rendered) {
+            None => Ok(()),
+            Some((line, expected, actual)) => Err(Report::new(SnapshotError::Mismatch {
+                path: display,
+                line,
+                expected,
+                actual,
+            }))
+            .attach_printable(format!("Run with {UPDATE_SNAPSHOTS_ENV}=1 to accept the new prompt")),
+        }
+    }
+}
+
+fn write_snapshot(path: &Path, rendered: &str) -> Result<(), SnapshotError> {
+    let display = path.display().to_string();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).change_context(SnapshotError::WriteError(display.clone()))?;
+    }
+    std::fs::write(path, rendered).change_context(SnapshotError::WriteError(display))
+}
+
+/// 第一处不同的行号（从 1 开始）及两边的内容，缺少的行记为空
+/// First differing line number (1-based) with both sides; a missing line is recorded as empty
+fn first_difference(expected: &str, actual: &str) -> Option<(usize, String, String)> {
+    if expected == actual {
+        return None;
+    }
+    let mut expected_lines = expected.split('\n');
+    let mut actual_lines = actual.split('\n');
+    let mut line = 1;
+    loop {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(left), Some(right)) if left == right => line += 1,
+            (left, right) => {
+                return Some((line, left.unwrap_or_default().to_string(), right.unwrap_or_default().to_string()));
+            }
+        }
+    }
+}
+
+/// 断言角色提示与快照一致，快照目录与更新模式取自环境变量，不一致时 panic 并给出第一处差异
+/// Assert a character prompt matches its snapshot, with the snapshot directory and update mode taken from the
+/// environment; panics with the first difference on mismatch
+pub fn assert_prompt_snapshot(name: &str, prompt: &Prompt, character: &str) {
+    if let Err(report) = PromptSnapshots::from_env().check(name, prompt, character) {
+        match report.current_context() {
+            SnapshotError::Mismatch { expected, actual, .. } => {
+                panic!("{report:?}\n  expected: {expected:?}\n    actual: {actual:?}")
+            }
+            _ => panic!("{report:?}"),
+        }
+    }
+}
@@ -0,0 +1,724 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::{DashMap, DashSet};
+use error_stack::{Report, Result, ResultExt};
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::Client;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::chat::files::{path_key, UploadedFile};
+use crate::chat::limits::{builtin_limits, ModelLimits};
+use crate::chat::mock::{MockApi, MockReply};
+use crate::chat::normalize::RoleRules;
+use crate::chat::rate_limit::{RateLimit, RateLimitBackend, LOCAL_RATE_LIMITER};
+use crate::chat::replay::ReplayStore;
+use crate::chat::scheduler::PriorityScheduler;
+use crate::chat::transport::{ChatTransport, StallPolicy};
+use crate::config::{ApiInfo, ApiSource, AuxiliaryTask, Config, ConfigError, ModelCapability};
+use crate::prompt::model::Prompts;
+use crate::schema::tool_schema::{
+    execute_tool_call_with, ToolCall, ToolError, ToolErrorKind, ToolFunction, ToolPermissions, ToolRegistry,
+    ToolResult,
+};
+use crate::tool_use::Environment;
+use crate::utils::common::redact::register_secret;
+
+/// 默认运行时，`CFG`、`THREAD_POOL`、`PROMPTS`、全局工具注册表与工具环境都指向它
+/// Default runtime; `CFG`, `THREAD_POOL`, `PROMPTS`, the global tool registry and tool environments all point into it
+static DEFAULT_RUNTIME: Lazy<Arc<Runtime>> = Lazy::new(|| Arc::new(Runtime::new()));
+
+/// 租户运行时，以租户 ID 为键
+/// Tenant runtimes keyed by tenant ID
+static TENANT_POOL: Lazy<DashMap<String, Arc<Runtime>>> = Lazy::new(DashMap::new);
+
+#[derive(Debug, Error)]
+pub enum TenantError {
+    #[error("Tenant '{0}' already exists")]
+    Exists(String),
+
+    #[error("Tenant '{0}' not found")]
+    NotFound(String),
+}
+
+/// 运行时：API 配置、来源并发信号量与按来源登记的设置、提示、工具注册表与工具环境的集合
+/// Runtime: bundle of the API configuration, source concurrency semaphores and per-source settings, prompts, tool
+/// registry and tool environments
+///
+/// 全局状态由默认运行时（[`Runtime::global`]）承载；测试或多租户服务可各建一个运行时，
+/// 以 [`BaseChat::new_with_runtime`](crate::chat::chat_base::BaseChat::new_with_runtime) 创建的对话只在其中查找
+/// API、工具与按基础 URL 登记的来源设置（调度器、传输层、限流、角色规则、回放与模拟应答等），
+/// 两个运行时即使使用同一基础 URL 也互不影响
+/// Global state lives in the default runtime ([`Runtime::global`]); tests or multi-tenant servers can build a
+/// runtime each, and chats created with
+/// [`BaseChat::new_with_runtime`](crate::chat::chat_base::BaseChat::new_with_runtime) only look up APIs, tools and
+/// source settings keyed by base URL (schedulers, transport, rate limits, role rules, replay and mock replies, ...)
+/// in it, so two runtimes never affect each other even when they use the same base URL
+#[derive(Default)]
+pub struct Runtime {
+    pub config: Config,
+
+    /// 各 API 来源的并发信号量，以基础 URL 为键
+    /// Concurrency semaphore of each API source, keyed by base URL
+    pub semaphores: DashMap<String, Arc<Semaphore>>,
+
+    pub tools: ToolRegistry,
+
+    pub envs: DashMap<String, Environment>,
+
+    /// 本运行时自己的每分钟限额，以基础 URL 为键；与来源的限额叠加生效
+    /// This runtime's own per-minute quotas keyed by base URL; applied on top of the source quota
+    pub rate_limits: DashMap<String, RateLimit>,
+
+    /// 启用了优先级调度的来源的调度器，以基础 URL 为键
+    /// Schedulers of sources with priority scheduling enabled, keyed by base URL
+    pub schedulers: DashMap<String, Arc<PriorityScheduler>>,
+
+    /// 因限流暂停的来源，值为暂停结束时间，以基础 URL 为键
+    /// Sources paused by rate limiting, valued by when the pause ends, keyed by base URL
+    pub pauses: DashMap<String, Instant>,
+
+    /// 来源的每分钟限额，计数以基础 URL 为键，设置了同一限额的运行时共用计数
+    /// Per-minute quotas of sources; counted by base URL, so runtimes setting a quota on the same source share it
+    pub source_rate_limits: DashMap<String, RateLimit>,
+
+    /// 替换了计数后端的来源，以基础 URL 为键
+    /// Sources with a replaced quota counter backend, keyed by base URL
+    pub rate_limit_backends: DashMap<String, Arc<dyn RateLimitBackend>>,
+
+    /// 被检测到忽略 `response_format` 的来源的基础 URL
+    /// Base URLs of sources detected to ignore `response_format`
+    pub response_format_ignored: DashSet<String>,
+
+    /// 回放来源的记录，以基础 URL 为键
+    /// Recordings of replay sources, keyed by base URL
+    pub replays: DashMap<String, Arc<ReplayStore>>,
+
+    /// 模拟来源的应答，以基础 URL 为键
+    /// Replies of mock sources, keyed by base URL
+    pub mocks: DashMap<String, MockApi>,
+
+    /// 来源的角色规则，以基础 URL 为键
+    /// Role rules of sources, keyed by base URL
+    pub role_rules: DashMap<String, RoleRules>,
+
+    /// 替换了传输层的来源，以基础 URL 为键
+    /// Sources with a replaced transport, keyed by base URL
+    pub transports: DashMap<String, Arc<dyn ChatTransport>>,
+
+    /// 设置了流停滞策略的来源，以基础 URL 为键
+    /// Sources with a stream stall policy, keyed by base URL
+    pub stall_policies: DashMap<String, StallPolicy>,
+
+    /// 手动设置的模型上下文长度上限，以模型名称为键
+    /// Manually set context limits, keyed by model name
+    pub context_limits: DashMap<String, u64>,
+
+    /// 手动设置的模型回答上限，以模型名称为键
+    /// Manually set output caps, keyed by model name
+    pub max_output_tokens: DashMap<String, u64>,
+
+    /// 已发现模型列表的来源，以基础 URL 为键
+    /// Sources whose model list was discovered, keyed by base URL
+    pub model_catalogs: DashMap<String, Vec<String>>,
+
+    /// 已上传的文件，以 (基础 URL, 本地路径) 为键
+    /// Uploaded files, keyed by (base URL, local path)
+    pub uploaded_files: DashMap<(String, PathBuf), UploadedFile>,
+
+    pub tool_permissions: ToolPermissions,
+
+    /// 经 [`Runtime::create_tenant`] 登记时的租户 ID
+    /// Tenant ID when registered through [`Runtime::create_tenant`]
+    tenant: Option<String>,
+
+    /// 首次使用时从提示目录加载
+    /// Loaded from the prompt directory on first use
+    prompts: OnceCell<Prompts>,
+}
+
+impl Runtime {
+    /// 空的运行时，与默认运行时不共享 API、工具与提示
+    /// Empty runtime sharing no APIs, tools or prompts with the default runtime
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 默认运行时
+    /// Default runtime
+    pub fn global() -> Arc<Self> {
+        DEFAULT_RUNTIME.clone()
+    }
+
+    pub(crate) fn default_ref() -> &'static Self {
+        &DEFAULT_RUNTIME
+    }
+
+    /// 使用给定的提示而不从提示目录加载
+    /// Use the given prompts instead of loading them from the prompt directory
+    pub fn with_prompts(self, prompts: Prompts) -> Self {
+        let _ = self.prompts.set(prompts);
+        self
+    }
+
+    pub fn with_tool_permissions(mut self, permissions: ToolPermissions) -> Self {
+        self.tool_permissions = permissions;
+        self
+    }
+
+    /// 把运行时登记为租户，之后可在进程内任意处以 [`Runtime::get_tenant`] 取回
+    /// Register a runtime as a tenant, retrievable anywhere in the process through [`Runtime::get_tenant`]
+    ///
+    /// # 参数 (Parameters)
+    /// * `id` - 租户 ID，已存在时返回 `TenantError::Exists`
+    ///   - Tenant ID; returns `TenantError::Exists` when taken
+    /// * `runtime` - 租户自己的 API 密钥、限额与工具权限
+    ///   - The tenant's own API keys, quotas and tool permissions
+    pub fn create_tenant(id: &str, mut runtime: Runtime) -> Result<Arc<Self>, TenantError> {
+        match TENANT_POOL.entry(id.to_string()) {
+            dashmap::Entry::Occupied(_) => Err(Report::new(TenantError::Exists(id.to_string()))),
+            dashmap::Entry::Vacant(entry) => {
+                runtime.tenant = Some(id.to_string());
+                let runtime = Arc::new(runtime);
+                entry.insert(runtime.clone());
+                Ok(runtime)
+            }
+        }
+    }
+
+    pub fn get_tenant(id: &str) -> Result<Arc<Self>, TenantError> {
+        TENANT_POOL
+            .get(id)
+            .map(|entry| entry.value().clone())
+            .ok_or_else(|| Report::new(TenantError::NotFound(id.to_string())))
+    }
+
+    /// 注销租户并清掉其限额计数；仍持有该运行时的对话照常使用到结束
+    /// Unregister a tenant and drop its quota counters; chats still holding the runtime keep using it until they end
+    pub fn remove_tenant(id: &str) -> Result<Arc<Self>, TenantError> {
+        let (_, runtime) = TENANT_POOL
+            .remove(id)
+            .ok_or_else(|| Report::new(TenantError::NotFound(id.to_string())))?;
+        LOCAL_RATE_LIMITER.forget(&format!("{id}@"));
+        Ok(runtime)
+    }
+
+    pub fn list_tenants() -> Vec<String> {
+        let mut tenants = TENANT_POOL.iter().map(|entry| entry.key().clone()).collect::<Vec<_>>();
+        tenants.sort();
+        tenants
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    #[allow(deprecated)]
+    pub fn prompts(&self) -> &Prompts {
+        self.prompts.get_or_init(Prompts::init_unchecked)
+    }
+
+    /// 添加API来源，见 [`Config::add_api_source`]
+    /// Add an API source, see [`Config::add_api_source`]
+    pub fn add_api_source(&self, name: &str, base_url: &str, parallelism: usize) {
+        self.insert_api_source(name, base_url, parallelism, Client::new());
+    }
+
+    pub(crate) fn insert_api_source(&self, name: &str, base_url: &str, parallelism: usize, client: Client) {
+        self.config.api_source.insert(
+            name.to_string(),
+            ApiSource {
+                base_url: base_url.to_string(),
+                parallelism,
+                client,
+                headers: Default::default(),
+            },
+        );
+        self.semaphores
+            .insert(base_url.to_string(), Arc::new(Semaphore::new(parallelism)));
+    }
+
+    /// 添加API信息，见 [`Config::add_api_info`]；来源不存在时返回 `ConfigError::ApiSourceNotFound`
+    /// Add API information, see [`Config::add_api_info`]; returns `ConfigError::ApiSourceNotFound` for an unknown source
+    pub fn add_api_info(
+        &self,
+        name: &str,
+        model: &str,
+        capability: ModelCapability,
+        source_name: &str,
+        api_key: &str,
+    ) -> Result<(), ConfigError> {
+        let (base_url, client, headers) = {
+            let source = self
+                .config
+                .api_source
+                .get(source_name)
+                .ok_or_else(|| Report::new(ConfigError::ApiSourceNotFound(source_name.to_string())))?;
+            (source.base_url.clone(), source.client.clone(), source.headers.clone())
+        };
+
+        register_secret(api_key);
+        self.config.api_info.insert(
+            (name.to_string(), capability),
+            ApiInfo {
+                model: model.to_string(),
+                base_url,
+                api_key: api_key.to_string(),
+                client,
+                headers,
+            },
+        );
+        Ok(())
+    }
+
+    /// 添加模拟API，见 [`Config::add_mock`]
+    /// Add a mock API, see [`Config::add_mock`]
+    pub fn add_mock(
+        &self,
+        name: &str,
+        responder: impl Fn(&serde_json::Value) -> MockReply + Send + Sync + 'static,
+    ) -> MockApi {
+        let mock = MockApi::new(responder);
+        for capability in [
+            ModelCapability::Think,
+            ModelCapability::ToolUse,
+            ModelCapability::LongContext,
+            ModelCapability::Vision,
+            ModelCapability::Embedding,
+            ModelCapability::Fast,
+            ModelCapability::Cheap,
+            ModelCapability::ImageGeneration,
+        ] {
+            self.add_mock_api(name, capability, mock.clone());
+        }
+        mock
+    }
+
+    /// 以指定能力添加配置好的模拟API，见 [`Config::add_mock_api`]
+    /// Add a configured mock API under a given capability, see [`Config::add_mock_api`]
+    pub fn add_mock_api(&self, name: &str, capability: ModelCapability, mock: MockApi) {
+        let base_url = format!("mock://{name}");
+        if !self.config.api_source.contains_key(name) {
+            self.insert_api_source(name, &base_url, usize::MAX >> 4, Client::new());
+        }
+        self.mocks.insert(base_url, mock);
+        self.add_api_info(name, name, capability, name, "")
+            .expect("mock source was just added");
+    }
+
+    /// 根据名称获取API信息
+    /// Get API information by name
+    pub fn get_api_info_with_name(&self, name: &str) -> Result<ApiInfo, ConfigError> {
+        self.config
+            .api_info
+            .iter()
+            .find_map(|entry| (entry.key().0 == name).then(|| entry.value().clone()))
+            .ok_or(ConfigError::ApiInfoNotFound.into())
+    }
+
+    /// 根据模型能力获取API信息，跳过不在已发现模型列表中的模型
+    /// Get API information by model capability, skipping models missing from a discovered model list
+    pub fn get_api_info_with_capability(&self, capability: ModelCapability) -> Result<ApiInfo, ConfigError> {
+        let mut missing = None;
+        for entry in self.config.api_info.iter().filter(|entry| entry.key().1 == capability) {
+            match self.check_model_listed(entry.value()) {
+                Ok(()) => return Ok(entry.value().clone()),
+                Err(err) => {
+                    missing.get_or_insert(err);
+                }
+            }
+        }
+        Err(missing.unwrap_or_else(|| ConfigError::ApiInfoNotFound.into()))
+    }
+
+    /// 设置辅助任务使用的模型能力
+    /// Set the model capability used by an auxiliary task
+    pub fn set_auxiliary_capability(&self, task: AuxiliaryTask, capability: ModelCapability) {
+        self.config.auxiliary_capability.insert(task, capability);
+    }
+
+    /// 获取辅助任务使用的模型能力，未配置时返回默认能力
+    /// Get the model capability used by an auxiliary task, falling back to its default
+    pub fn get_auxiliary_capability(&self, task: &AuxiliaryTask) -> ModelCapability {
+        self.config
+            .auxiliary_capability
+            .get(task)
+            .map(|entry| entry.value().clone())
+            .unwrap_or_else(|| task.default_capability())
+    }
+
+    /// 根据辅助任务获取API信息，找不到映射的能力时回退到工具使用能力
+    /// Get API information by auxiliary task, falling back to the tool-use capability
+    pub fn get_api_info_with_auxiliary_task(&self, task: &AuxiliaryTask) -> Result<ApiInfo, ConfigError> {
+        let capability = self.get_auxiliary_capability(task);
+
+        self.get_api_info_with_capability(capability.clone()).or_else(|err| {
+            if capability == ModelCapability::ToolUse {
+                Err(err)
+            } else {
+                self.get_api_info_with_capability(ModelCapability::ToolUse)
+            }
+        })
+    }
+
+    /// 设置本运行时在API来源上的每分钟限额，见 [`Config::set_rate_limit`]
+    /// Set this runtime's per-minute quota on an API source, see [`Config::set_rate_limit`]
+    pub fn set_rate_limit(&self, source_name: &str, limit: RateLimit) -> Result<(), ConfigError> {
+        let base_url = self
+            .config
+            .api_source
+            .get(source_name)
+            .ok_or_else(|| Report::new(ConfigError::ApiSourceNotFound(source_name.to_string())))?
+            .base_url
+            .clone();
+        self.rate_limits.insert(base_url, limit);
+        Ok(())
+    }
+
+    pub fn get_rate_limit(&self, base_url: &str) -> Option<RateLimit> {
+        self.rate_limits.get(base_url).map(|entry| *entry.value())
+    }
+
+    /// 本运行时的限额计数键；未登记为租户时以运行时地址区分
+    /// Quota counter key of this runtime; told apart by address when not registered as a tenant
+    pub(crate) fn quota_key(&self, base_url: &str) -> String {
+        match &self.tenant {
+            Some(tenant) => format!("{tenant}@{base_url}"),
+            None => format!("{:p}@{base_url}", self),
+        }
+    }
+
+    /// 工具权限不允许时返回 `permission_denied` 错误
+    /// Returns a `permission_denied` error when the tool permissions don't allow the tool
+    pub fn check_tool_permission(&self, name: &str) -> Option<ToolError> {
+        let registered = self.tools.resolve(name).ok().flatten();
+        let names = match &registered {
+            Some(registered) => vec![name, registered.as_str()],
+            None => vec![name],
+        };
+        if self.tool_permissions.permits(&names) {
+            return None;
+        }
+        let error = ToolError::new(
+            ToolErrorKind::PermissionDenied,
+            format!("Tool '{name}' is not permitted in this runtime"),
+        );
+        Some(error.with_tool(name))
+    }
+
+    /// 按名称取本运行时可用的工具函数，不检查工具权限；自身注册表中没有且允许继承时使用默认运行时的工具
+    /// Get a tool function available to this runtime by name without checking tool permissions; falls back to the
+    /// default runtime's tools when the own registry lacks it and inheriting is allowed
+    pub fn tool(&self, name: &str) -> Option<ToolFunction> {
+        self.tools.get(name).or_else(|| {
+            let default = Self::default_ref();
+            (self.tool_permissions.inherit_global && !std::ptr::eq(self, default))
+                .then(|| default.tools.get(name))
+                .flatten()
+        })
+    }
+
+    /// 在工具权限内执行工具调用，见 [`execute_tool_call`](crate::schema::tool_schema::execute_tool_call)
+    /// Execute a tool call within the tool permissions, see
+    /// [`execute_tool_call`](crate::schema::tool_schema::execute_tool_call)
+    pub fn execute_tool(&self, call: &ToolCall, tools_schema: &[serde_json::Value]) -> ToolResult {
+        if let Some(error) = self.check_tool_permission(&call.name) {
+            return ToolResult::failure(call, &error);
+        }
+        execute_tool_call_with(call, tools_schema, |name| self.tool(name))
+    }
+
+    /// 添加工具环境，已存在时替换为空环境
+    /// Add a tool environment, replacing an existing one with an empty environment
+    pub fn add_env(&self, key: &str) {
+        self.envs.insert(key.to_string(), Environment::default());
+    }
+
+    /// 移除工具环境，返回被移除的环境
+    /// Remove a tool environment, returning the removed environment
+    pub fn remove_env(&self, key: &str) -> Option<Environment> {
+        self.envs.remove(key).map(|(_, env)| env)
+    }
+
+    /// API来源的并发信号量
+    /// Concurrency semaphore of an API source
+    pub(crate) fn semaphore(&self, base_url: &str) -> Option<Arc<Semaphore>> {
+        self.semaphores.get(base_url).map(|entry| entry.value().clone())
+    }
+
+    /// API来源的基础 URL，来源不存在时返回 `ConfigError::ApiSourceNotFound`
+    /// Base URL of an API source; returns `ConfigError::ApiSourceNotFound` for an unknown source
+    fn source_base_url(&self, source_name: &str) -> Result<String, ConfigError> {
+        self.config
+            .api_source
+            .get(source_name)
+            .map(|source| source.base_url.clone())
+            .ok_or_else(|| Report::new(ConfigError::ApiSourceNotFound(source_name.to_string())))
+    }
+
+    /// 添加回放来源，见 [`Config::add_replay_source`]
+    /// Add a replay source, see [`Config::add_replay_source`]
+    pub fn add_replay_source(&self, name: &str, records_path: &str, parallelism: usize) -> Result<(), ConfigError> {
+        let store = ReplayStore::load(records_path).change_context(ConfigError::ReplayLoadError)?;
+        let base_url = format!("replay://{name}");
+
+        self.insert_api_source(name, &base_url, parallelism, Client::new());
+        self.replays.insert(base_url, Arc::new(store));
+        Ok(())
+    }
+
+    pub(crate) fn replay_store(&self, base_url: &str) -> Option<Arc<ReplayStore>> {
+        self.replays.get(base_url).map(|entry| entry.value().clone())
+    }
+
+    pub(crate) fn mock_api(&self, base_url: &str) -> Option<MockApi> {
+        self.mocks.get(base_url).map(|entry| entry.value().clone())
+    }
+
+    /// 为API来源启用优先级调度，见 [`Config::enable_priority_scheduling`]
+    /// Enable priority scheduling for an API source, see [`Config::enable_priority_scheduling`]
+    pub fn enable_priority_scheduling(&self, source_name: &str) -> Result<(), ConfigError> {
+        let base_url = self.source_base_url(source_name)?;
+        let semaphore = self
+            .semaphore(&base_url)
+            .ok_or_else(|| Report::new(ConfigError::ApiSourceNotFound(source_name.to_string())))?;
+
+        self.schedulers.insert(base_url, Arc::new(PriorityScheduler::new(semaphore)));
+        Ok(())
+    }
+
+    pub(crate) fn scheduler(&self, base_url: &str) -> Option<Arc<PriorityScheduler>> {
+        self.schedulers.get(base_url).map(|entry| entry.value().clone())
+    }
+
+    /// 暂停某个API来源：暂停结束前获得许可的请求会带着许可等待，已有更晚的暂停时保持不变
+    /// Pause an API source: requests granted a permit before the pause ends wait while holding it;
+    /// a later pause already in place is kept
+    pub fn pause_source(&self, base_url: &str, delay: Duration) {
+        let until = Instant::now() + delay;
+        self.pauses
+            .entry(base_url.to_string())
+            .and_modify(|current| *current = (*current).max(until))
+            .or_insert(until);
+        warn!("{base_url} is rate limited, pausing requests for {delay:?}");
+    }
+
+    /// 来源暂停的结束时间，未暂停或暂停已结束时为 `None`
+    /// When the pause of a source ends; `None` when it is not paused or the pause is over
+    pub fn source_paused_until(&self, base_url: &str) -> Option<Instant> {
+        self.pauses
+            .get(base_url)
+            .map(|entry| *entry.value())
+            .filter(|until| *until > Instant::now())
+    }
+
+    /// 等待来源的暂停结束，等待期间暂停被延长时继续等待
+    /// Wait until the pause of a source is over, waiting on if it gets extended meanwhile
+    pub(crate) async fn wait_for_source(&self, base_url: &str) {
+        while let Some(until) = self.source_paused_until(base_url) {
+            tokio::time::sleep_until(until.into()).await;
+        }
+    }
+
+    /// 设置API来源的角色规则，见 [`Config::set_role_rules`]
+    /// Set the role rules of an API source, see [`Config::set_role_rules`]
+    pub fn set_role_rules(&self, source_name: &str, rules: RoleRules) -> Result<(), ConfigError> {
+        let base_url = self.source_base_url(source_name)?;
+        self.role_rules.insert(base_url, rules);
+        Ok(())
+    }
+
+    pub fn get_role_rules(&self, base_url: &str) -> RoleRules {
+        self.role_rules
+            .get(base_url)
+            .map(|entry| *entry.value())
+            .unwrap_or_default()
+    }
+
+    /// 标记API来源是否忽略 `response_format`，见 [`Config::set_response_format_ignored`]
+    /// Mark whether an API source ignores `response_format`, see [`Config::set_response_format_ignored`]
+    pub fn set_response_format_ignored(&self, base_url: &str, ignored: bool) {
+        if ignored {
+            self.response_format_ignored.insert(base_url.to_string());
+        } else {
+            self.response_format_ignored.remove(base_url);
+        }
+    }
+
+    pub fn is_response_format_ignored(&self, base_url: &str) -> bool {
+        self.response_format_ignored.contains(base_url)
+    }
+
+    /// 替换API来源的传输层，见 [`Config::set_transport`]
+    /// Replace the transport of an API source, see [`Config::set_transport`]
+    pub fn set_transport(&self, source_name: &str, transport: impl ChatTransport + 'static) -> Result<(), ConfigError> {
+        let base_url = self.source_base_url(source_name)?;
+        self.transports.insert(base_url, Arc::new(transport));
+        Ok(())
+    }
+
+    pub fn get_transport(&self, base_url: &str) -> Option<Arc<dyn ChatTransport>> {
+        self.transports.get(base_url).map(|entry| entry.value().clone())
+    }
+
+    /// 设置API来源的流停滞检测策略，见 [`Config::set_stall_policy`]
+    /// Set the stream stall policy of an API source, see [`Config::set_stall_policy`]
+    pub fn set_stall_policy(&self, source_name: &str, policy: StallPolicy) -> Result<(), ConfigError> {
+        let base_url = self.source_base_url(source_name)?;
+        self.stall_policies.insert(base_url, policy);
+        Ok(())
+    }
+
+    pub fn get_stall_policy(&self, base_url: &str) -> Option<StallPolicy> {
+        self.stall_policies.get(base_url).map(|entry| *entry.value())
+    }
+
+    /// 设置API来源的每分钟限额，见 [`Config::set_rate_limit`]
+    /// Set the per-minute quota of an API source, see [`Config::set_rate_limit`]
+    pub fn set_source_rate_limit(&self, source_name: &str, limit: RateLimit) -> Result<(), ConfigError> {
+        let base_url = self.source_base_url(source_name)?;
+        self.source_rate_limits.insert(base_url, limit);
+        Ok(())
+    }
+
+    pub fn get_source_rate_limit(&self, base_url: &str) -> Option<RateLimit> {
+        self.source_rate_limits.get(base_url).map(|entry| *entry.value())
+    }
+
+    /// 替换API来源的限额计数后端，见 [`Config::set_rate_limit_backend`]
+    /// Replace the quota counter backend of an API source, see [`Config::set_rate_limit_backend`]
+    pub fn set_rate_limit_backend(
+        &self,
+        source_name: &str,
+        backend: impl RateLimitBackend + 'static,
+    ) -> Result<(), ConfigError> {
+        let base_url = self.source_base_url(source_name)?;
+        self.rate_limit_backends.insert(base_url, Arc::new(backend));
+        Ok(())
+    }
+
+    /// 获取API来源的限额计数后端，未替换时为进程内计数
+    /// Get the quota counter backend of an API source; the in-process counters when not replaced
+    pub fn get_rate_limit_backend(&self, base_url: &str) -> Arc<dyn RateLimitBackend> {
+        self.rate_limit_backends
+            .get(base_url)
+            .map(|entry| entry.value().clone())
+            .unwrap_or_else(|| LOCAL_RATE_LIMITER.clone())
+    }
+
+    pub fn set_context_limit(&self, model: &str, context_limit: u64) {
+        self.context_limits.insert(model.to_string(), context_limit);
+    }
+
+    pub fn set_max_output_tokens(&self, model: &str, max_output_tokens: u64) {
+        self.max_output_tokens.insert(model.to_string(), max_output_tokens);
+    }
+
+    /// 获取模型的上下文长度与回答上限，见 [`Config::get_model_limits`]
+    /// Get the context window and output cap of a model, see [`Config::get_model_limits`]
+    pub fn get_model_limits(&self, model: &str) -> ModelLimits {
+        let builtin = builtin_limits(model).unwrap_or_default();
+        ModelLimits {
+            context_window: self
+                .context_limits
+                .get(model)
+                .map(|entry| *entry.value())
+                .or(builtin.context_window),
+            max_output_tokens: self
+                .max_output_tokens
+                .get(model)
+                .map(|entry| *entry.value())
+                .or(builtin.max_output_tokens),
+        }
+    }
+
+    /// 查询本运行时中API来源的模型列表接口并缓存到本运行时，返回模型名称，见 [`Config::discover_models`]
+    /// Query the model list endpoint of an API source in this runtime and cache it in this runtime; returns the
+    /// model names, see [`Config::discover_models`]
+    ///
+    /// 缓存之后，[`get_api_info_with_capability`](Self::get_api_info_with_capability) 会校验配置的模型确实存在
+    /// Once cached, [`get_api_info_with_capability`](Self::get_api_info_with_capability) verifies that the configured
+    /// model actually exists
+    pub async fn discover_models(&self, source_name: &str) -> Result<Vec<String>, ConfigError> {
+        let (base_url, client, headers) = {
+            let source = self
+                .config
+                .api_source
+                .get(source_name)
+                .ok_or(ConfigError::ApiSourceNotFound(source_name.to_string()))?;
+            (source.base_url.clone(), source.client.clone(), source.headers.clone())
+        };
+        let root = base_url
+            .strip_suffix("/chat/completions")
+            .ok_or_else(|| Report::new(ConfigError::ModelDiscoveryError(base_url.clone())))
+            .attach_printable("Model discovery requires a `/chat/completions` base URL")?;
+        // 来源本身不保存密钥，借用该来源下任一API的密钥
+        // Sources hold no key themselves, so borrow the key of any API on this source
+        let api_key = self
+            .config
+            .api_info
+            .iter()
+            .find(|entry| entry.base_url == base_url && !entry.api_key.is_empty())
+            .map(|entry| entry.api_key.clone());
+
+        let mut request = client.get(format!("{root}/models"));
+        for (name, value) in &headers {
+            request = request.header(name, value);
+        }
+        if let Some(api_key) = api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response: serde_json::Value = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .change_context_lazy(|| ConfigError::ModelDiscoveryError(base_url.clone()))?
+            .json()
+            .await
+            .change_context_lazy(|| ConfigError::ModelDiscoveryError(base_url.clone()))?;
+        let models: Vec<String> = response["data"]
+            .as_array()
+            .ok_or_else(|| Report::new(ConfigError::ModelDiscoveryError(base_url.clone())))
+            .attach_printable("Model list has no `data` array")?
+            .iter()
+            .filter_map(|model| model["id"].as_str().map(str::to_string))
+            .collect();
+
+        self.model_catalogs.insert(base_url, models.clone());
+        Ok(models)
+    }
+
+    pub fn get_discovered_models(&self, base_url: &str) -> Option<Vec<String>> {
+        self.model_catalogs.get(base_url).map(|entry| entry.value().clone())
+    }
+
+    /// 模型在其来源已发现的模型列表中，或来源尚未发现模型
+    /// The model is in the discovered model list of its source, or the source has not been discovered
+    pub(crate) fn check_model_listed(&self, api_info: &ApiInfo) -> Result<(), ConfigError> {
+        match self.model_catalogs.get(&api_info.base_url) {
+            Some(models) if !models.contains(&api_info.model) => Err(Report::new(ConfigError::ModelNotFound {
+                model: api_info.model.clone(),
+                base_url: api_info.base_url.clone(),
+            })),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_uploaded_file(&self, base_url: &str, path: &Path, file: UploadedFile) {
+        self.uploaded_files.insert((base_url.to_string(), path_key(path)), file);
+    }
+
+    pub fn get_uploaded_file(&self, base_url: &str, path: &Path) -> Option<UploadedFile> {
+        self.uploaded_files
+            .get(&(base_url.to_string(), path_key(path)))
+            .map(|entry| entry.value().clone())
+    }
+
+    pub fn forget_uploaded_file(&self, base_url: &str, file_id: &str) {
+        self.uploaded_files
+            .retain(|(url, _), file| url != base_url || file.id != file_id);
+    }
+}
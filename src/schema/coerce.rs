@@ -0,0 +1,130 @@
+use crate::schema::validator::{inner_schema, matches_type};
+
+/// 按模式修正参数中常见的类型偏差，返回被修正的位置
+/// Fix common type drift in arguments according to a schema, returning the paths that were changed
+///
+/// 只在值不符合模式的类型或枚举时改写，且只做无歧义的转换：
+/// 数字与布尔字符串转为数字与布尔，整数值的浮点数转为整数，JSON 字符串转为对象或数组，
+/// 枚举值忽略大小写与首尾空白匹配到模式中的写法。无法转换的值保持原样，交由校验报错
+/// Values are only rewritten when they miss the schema's type or enum, and only by unambiguous conversions:
+/// numeric and boolean strings become numbers and booleans, integral floats become integers, JSON strings become
+/// objects or arrays, and enum values are matched to the schema's spelling ignoring case and surrounding
+/// whitespace. Values that can't be converted are kept for validation to report
+pub fn coerce(schema: &serde_json::Value, value: &mut serde_json::Value) -> Vec<String> {
+    let mut changed = Vec::new();
+    coerce_at(inner_schema(schema), value, "", &mut changed);
+    changed
+}
+
+fn coerce_at(schema: &serde_json::Value, value: &mut serde_json::Value, path: &str, changed: &mut Vec<String>) {
+    let types = match schema.get("type") {
+        Some(serde_json::Value::Array(types)) => types.iter().filter_map(|t| t.as_str()).collect(),
+        Some(other) => other.as_str().into_iter().collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+    if !types.is_empty()
+        && !types.iter().any(|t| matches_type(t, value))
+        && let Some(coerced) = types.iter().find_map(|t| convert(t, value))
+    {
+        *value = coerced;
+        changed.push(path.to_string());
+    }
+
+    if let Some(options) = schema.get("enum").and_then(|e| e.as_array())
+        && !options.contains(value)
+        && let Some(option) = match_option(options, value)
+    {
+        *value = option.clone();
+        if changed.last().map(String::as_str) != Some(path) {
+            changed.push(path.to_string());
+        }
+    }
+
+    match value {
+        serde_json::Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter_mut().enumerate() {
+                    coerce_at(item_schema, item, &format!("{path}/{i}"), changed);
+                }
+            }
+        }
+        serde_json::Value::Object(object) => {
+            if let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) {
+                for (name, field) in object.iter_mut() {
+                    if let Some(field_schema) = properties.get(name) {
+                        coerce_at(field_schema, field, &format!("{path}/{name}"), changed);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// 把值转换为指定类型，不能无歧义转换时为 `None`
+/// Convert a value to the given type, `None` when there's no unambiguous conversion
+fn convert(expected: &str, value: &serde_json::Value) -> Option<serde_json::Value> {
+    match (expected, value) {
+        ("integer", serde_json::Value::String(text)) => parse_integer(text.trim()),
+        ("integer", serde_json::Value::Number(number)) => number
+            .as_f64()
+            .filter(|n| n.fract() == 0.0 && n.abs() < i64::MAX as f64)
+            .map(|n| serde_json::Value::from(n as i64)),
+        ("number", serde_json::Value::String(text)) => parse_integer(text.trim()).or_else(|| {
+            text.trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(serde_json::Value::Number)
+        }),
+        ("boolean", serde_json::Value::String(text)) => match text.trim().to_ascii_lowercase().as_str() {
+            "true" | "yes" | "y" | "on" | "1" => Some(serde_json::Value::Bool(true)),
+            "false" | "no" | "n" | "off" | "0" => Some(serde_json::Value::Bool(false)),
+            _ => None,
+        },
+        ("boolean", serde_json::Value::Number(number)) => match number.as_f64() {
+            Some(1.0) => Some(serde_json::Value::Bool(true)),
+            Some(0.0) => Some(serde_json::Value::Bool(false)),
+            _ => None,
+        },
+        ("array", serde_json::Value::String(text)) => {
+            serde_json::from_str::<serde_json::Value>(text.trim()).ok().filter(|v| v.is_array())
+        }
+        ("object", serde_json::Value::String(text)) => {
+            serde_json::from_str::<serde_json::Value>(text.trim()).ok().filter(|v| v.is_object())
+        }
+        _ => None,
+    }
+}
+
+fn parse_integer(text: &str) -> Option<serde_json::Value> {
+    if let Ok(integer) = text.parse::<i64>() {
+        return Some(serde_json::Value::from(integer));
+    }
+    if let Ok(integer) = text.parse::<u64>() {
+        return Some(serde_json::Value::from(integer));
+    }
+    text.parse::<f64>()
+        .ok()
+        .filter(|n| n.fract() == 0.0 && n.abs() < i64::MAX as f64)
+        .map(|n| serde_json::Value::from(n as i64))
+}
+
+/// 忽略大小写与首尾空白匹配枚举项；多个枚举项同时匹配时视为有歧义
+/// Match an enum option ignoring case and surrounding whitespace; several matching options are ambiguous
+fn match_option<'a>(options: &'a [serde_json::Value], value: &serde_json::Value) -> Option<&'a serde_json::Value> {
+    let text = match value {
+        serde_json::Value::String(text) => text.trim().to_lowercase(),
+        serde_json::Value::Number(_) | serde_json::Value::Bool(_) => value.to_string(),
+        _ => return None,
+    };
+    let mut matches = options.iter().filter(|option| match option {
+        serde_json::Value::String(option) => option.trim().to_lowercase() == text,
+        serde_json::Value::Number(_) | serde_json::Value::Bool(_) => option.to_string().eq_ignore_ascii_case(&text),
+        _ => false,
+    });
+    match (matches.next(), matches.next()) {
+        (Some(option), None) => Some(option),
+        _ => None,
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::schema::validator::inner_schema;
+
+/// 两个模式之间的一处变化
+/// One change between two schemas
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChangeKind {
+    FieldAdded { required: bool },
+
+    FieldRemoved { required: bool },
+
+    /// 必填字段变为可选，解析方可能拿不到该字段
+    /// A required field became optional; parsers may no longer get it
+    NoLongerRequired,
+
+    NowRequired,
+
+    TypeChanged { old: Vec<String>, new: Vec<String> },
+
+    /// 新增了枚举约束
+    /// An enum constraint was added
+    EnumAdded { values: Vec<serde_json::Value> },
+
+    EnumRemoved,
+
+    EnumNarrowed { removed: Vec<serde_json::Value> },
+
+    EnumWidened { added: Vec<serde_json::Value> },
+}
+
+impl Display for ChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FieldAdded { required } => write!(f, "{} field added", if *required { "required" } else { "optional" }),
+            Self::FieldRemoved { required } => {
+                write!(f, "{} field removed", if *required { "required" } else { "optional" })
+            }
+            Self::NoLongerRequired => write!(f, "no longer required"),
+            Self::NowRequired => write!(f, "now required"),
+            Self::TypeChanged { old, new } => write!(f, "type changed from {} to {}", old.join(" or "), new.join(" or ")),
+            Self::EnumAdded { values } => write!(f, "enum added: {}", serde_json::Value::from(values.clone())),
+            Self::EnumRemoved => write!(f, "enum removed"),
+            Self::EnumNarrowed { removed } => write!(f, "enum values removed: {}", serde_json::Value::from(removed.clone())),
+            Self::EnumWidened { added } => write!(f, "enum values added: {}", serde_json::Value::from(added.clone())),
+        }
+    }
+}
+
+/// 模式中某一位置的变化
+/// Change at one location of a schema
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchemaChange {
+    /// 字段路径，数组元素记为 `*`，如 `/tasks/*/title`
+    /// Field path with array items written as `*`, e.g. `/tasks/*/title`
+    pub path: String,
+
+    pub kind: ChangeKind,
+
+    /// 是否会破坏按旧模式编写的解析方
+    /// Whether parsers written against the old schema may break
+    pub breaking: bool,
+}
+
+impl Display for SchemaChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = if self.path.is_empty() { "/" } else { &self.path };
+        let marker = if self.breaking { "BREAKING" } else { "compatible" };
+        write!(f, "[{marker}] {path}: {}", self.kind)
+    }
+}
+
+/// 两个模式之间的全部变化
+/// Every change between two schemas
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SchemaDiff {
+    pub changes: Vec<SchemaChange>,
+}
+
+impl SchemaDiff {
+    pub fn is_breaking(&self) -> bool {
+        self.changes.iter().any(|change| change.breaking)
+    }
+
+    pub fn breaking(&self) -> impl Iterator<Item = &SchemaChange> {
+        self.changes.iter().filter(|change| change.breaking)
+    }
+}
+
+impl Display for SchemaDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "{change}")?;
+        }
+        Ok(())
+    }
+}
+
+/// 比较两个生成的模式，从结构化输出解析方的角度判断哪些变化是破坏性的
+/// Compare two generated schemas, judging which changes break parsers of the structured output
+///
+/// 破坏性变化：删除或不再必填的必填字段、出现旧模式不允许的类型、新增枚举约束或删除枚举值；
+/// 新增字段、收窄类型与新增枚举值视为兼容
+/// Breaking: removed or no longer required required fields, types the old schema did not allow,
+/// added enum constraints and removed enum values; added fields, narrowed types and added enum values are compatible
+pub fn diff(old: &serde_json::Value, new: &serde_json::Value) -> SchemaDiff {
+    let mut changes = Vec::new();
+    diff_at(inner_schema(old), inner_schema(new), "", &mut changes);
+    SchemaDiff { changes }
+}
+
+fn diff_at(old: &serde_json::Value, new: &serde_json::Value, path: &str, changes: &mut Vec<SchemaChange>) {
+    let mut change = |kind: ChangeKind, breaking: bool| {
+        changes.push(SchemaChange {
+            path: path.to_string(),
+            kind,
+            breaking,
+        })
+    };
+
+    let (old_types, new_types) = (types_of(old), types_of(new));
+    if old_types != new_types && !old_types.is_empty() {
+        let breaking = new_types.is_empty() || new_types.iter().any(|t| !type_allowed(t, &old_types));
+        change(
+            ChangeKind::TypeChanged {
+                old: old_types,
+                new: new_types,
+            },
+            breaking,
+        );
+    }
+
+    match (enum_of(old), enum_of(new)) {
+        (None, Some(values)) => change(ChangeKind::EnumAdded { values: values.clone() }, true),
+        (Some(_), None) => change(ChangeKind::EnumRemoved, false),
+        (Some(old_values), Some(new_values)) => {
+            let removed: Vec<_> = old_values.iter().filter(|v| !new_values.contains(v)).cloned().collect();
+            let added: Vec<_> = new_values.iter().filter(|v| !old_values.contains(v)).cloned().collect();
+            if !removed.is_empty() {
+                change(ChangeKind::EnumNarrowed { removed }, true);
+            }
+            if !added.is_empty() {
+                change(ChangeKind::EnumWidened { added }, false);
+            }
+        }
+        (None, None) => {}
+    }
+
+    if let (Some(old_items), Some(new_items)) = (old.get("items"), new.get("items")) {
+        diff_at(old_items, new_items, &format!("{path}/*"), changes);
+    }
+
+    let empty = serde_json::Map::new();
+    let old_properties = old.get("properties").and_then(|p| p.as_object()).unwrap_or(&empty);
+    let new_properties = new.get("properties").and_then(|p| p.as_object()).unwrap_or(&empty);
+    let (old_required, new_required) = (required_of(old), required_of(new));
+
+    for (name, old_property) in old_properties {
+        let field_path = format!("{path}/{name}");
+        let was_required = old_required.contains(&name.as_str());
+        let field_change = |kind, breaking| SchemaChange {
+            path: field_path.clone(),
+            kind,
+            breaking,
+        };
+        match new_properties.get(name) {
+            None => changes.push(field_change(ChangeKind::FieldRemoved { required: was_required }, was_required)),
+            Some(new_property) => {
+                let is_required = new_required.contains(&name.as_str());
+                if was_required && !is_required {
+                    changes.push(field_change(ChangeKind::NoLongerRequired, true));
+                } else if !was_required && is_required {
+                    changes.push(field_change(ChangeKind::NowRequired, false));
+                }
+                diff_at(old_property, new_property, &field_path, changes);
+            }
+        }
+    }
+    for name in new_properties.keys().filter(|name| !old_properties.contains_key(*name)) {
+        changes.push(SchemaChange {
+            path: format!("{path}/{name}"),
+            kind: ChangeKind::FieldAdded {
+                required: new_required.contains(&name.as_str()),
+            },
+            breaking: false,
+        });
+    }
+}
+
+fn types_of(schema: &serde_json::Value) -> Vec<String> {
+    let mut types: Vec<String> = match schema.get("type") {
+        Some(serde_json::Value::Array(types)) => types.iter().filter_map(|t| t.as_str()).map(str::to_string).collect(),
+        Some(serde_json::Value::String(t)) => vec![t.clone()],
+        _ => Vec::new(),
+    };
+    types.sort();
+    types
+}
+
+/// 旧模式的类型是否已允许该类型（`number` 包含 `integer`）
+/// Whether the old types already allow this type (`number` covers `integer`)
+fn type_allowed(new_type: &str, old_types: &[String]) -> bool {
+    old_types.iter().any(|t| t == new_type || (t == "number" && new_type == "integer"))
+}
+
+fn enum_of(schema: &serde_json::Value) -> Option<&Vec<serde_json::Value>> {
+    schema.get("enum").and_then(|e| e.as_array())
+}
+
+fn required_of(schema: &serde_json::Value) -> Vec<&str> {
+    schema
+        .get("required")
+        .and_then(|r| r.as_array())
+        .map(|required| required.iter().filter_map(|r| r.as_str()).collect())
+        .unwrap_or_default()
+}
@@ -1,3 +1,87 @@
+use std::any::TypeId;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// 已生成的模式，以类型为键
+/// Generated schemas, keyed by type
+static SCHEMA_CACHE: Lazy<DashMap<TypeId, Arc<serde_json::Value>>> = Lazy::new(DashMap::new);
+
 pub trait JsonSchema {
     fn json_schema() -> serde_json::Value;
+
+    /// 缓存的模式：每个类型只生成一次，之后返回同一份共享的值
+    /// Cached schema: generated once per type, the same shared value is returned afterwards
+    fn cached_json_schema() -> Arc<serde_json::Value>
+    where
+        Self: Sized + 'static,
+    {
+        if let Some(schema) = SCHEMA_CACHE.get(&TypeId::of::<Self>()) {
+            return schema.value().clone();
+        }
+        SCHEMA_CACHE
+            .entry(TypeId::of::<Self>())
+            .or_insert_with(|| Arc::new(Self::json_schema()))
+            .value()
+            .clone()
+    }
+}
+
+/// 可在模式中作为字符串枚举使用的 Rust 枚举，取值与 serde 序列化结果一致（遵循 `rename`/`rename_all`）
+/// Rust enum usable as a string enum in schemas; values match its serde serialization (honouring `rename`/`rename_all`)
+///
+/// 派生宏的 `#[schema(enum = "...")]` 需要手写取值，用 [`apply_enum_type`] 可从枚举类型填入，使两者保持同步
+/// `#[schema(enum = "...")]` of the derive macro takes hand-written values; [`apply_enum_type`] fills them in
+/// from the enum type instead so the two stay in sync
+pub trait SchemaEnum: Serialize + Sized {
+    fn variants() -> Vec<Self>;
+
+    /// 各变体序列化后的名称，序列化结果不是字符串的变体会被跳过
+    /// Serialized name of every variant; variants that do not serialize to a string are skipped
+    fn variant_names() -> Vec<String> {
+        Self::variants()
+            .iter()
+            .filter_map(|variant| match serde_json::to_value(variant) {
+                Ok(serde_json::Value::String(name)) => Some(name),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// 枚举类型的字符串枚举模式，可用于实现其 [`JsonSchema`]
+/// String enum schema of an enum type, usable to implement its [`JsonSchema`]
+pub fn enum_schema<T: SchemaEnum>() -> serde_json::Value {
+    serde_json::json!({
+        "type": "string",
+        "enum": T::variant_names(),
+    })
+}
+
+/// 用枚举类型的取值设置字段的 `enum`，数组字段设置在 `items` 上；字段不存在时返回 `false`
+/// Set the `enum` of a field from an enum type, on `items` for array fields; returns `false` when the field does not exist
+///
+/// # 参数 (Parameters)
+/// * `schema` - 派生宏生成的模式，或其中的 `json_schema.schema`
+///   - Schema generated by the derive macro, or its `json_schema.schema`
+/// * `field` - 字段名
+///   - Field name
+pub fn apply_enum_type<T: SchemaEnum>(schema: &mut serde_json::Value, field: &str) -> bool {
+    let pointer = if schema.get("json_schema").is_some() {
+        format!("/json_schema/schema/properties/{field}")
+    } else {
+        format!("/properties/{field}")
+    };
+    let Some(property) = schema.pointer_mut(&pointer) else {
+        return false;
+    };
+
+    let is_array = property["type"] == "array";
+    let target = if is_array { &mut property["items"] } else { property };
+    if let Some(target) = target.as_object_mut() {
+        target.insert("enum".to_string(), T::variant_names().into());
+    }
+    true
 }
@@ -1,2 +1,7 @@
+pub mod coerce;
+pub mod diff;
 pub mod json_schema;
-pub mod tool_schema;
\ No newline at end of file
+pub mod tool_schema;
+pub mod validator;
+
+pub use diff::diff;
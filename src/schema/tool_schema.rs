@@ -1,10 +1,14 @@
-use error_stack::{Result, ResultExt};  // 引入 error-stack
+use error_stack::{Report, Result, ResultExt};  // 引入 error-stack
 use dashmap::DashMap;
-use once_cell::sync::OnceCell;
-use regex::Regex;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tracing::debug;
 use crate::chat::chat_tool::ChatTool;
+use crate::runtime::Runtime;
+use crate::schema::coerce::coerce;
+use crate::schema::validator::{inner_schema, validate, Violation};
 // 引入 thiserror
 
 // 定义错误类型
@@ -31,10 +35,7 @@ pub enum ChatToolSchemaError {
 }
 
 // 修改 ToolFunction 类型定义，使用 error_stack::Result
-type ToolFunction = Arc<dyn Fn(serde_json::Value) -> Result<serde_json::Value, ChatToolSchemaError> + Send + Sync>;
-
-static REGISTRY: OnceCell<DashMap<String, ToolFunction>> = OnceCell::new();
-
+pub type ToolFunction = Arc<dyn Fn(serde_json::Value) -> Result<serde_json::Value, ChatToolSchemaError> + Send + Sync>;
 
 pub fn create_tool(
     name: &str,
@@ -43,28 +44,687 @@ pub fn create_tool(
     (name.to_string(), Arc::new(func))
 }
 
+/// 命名空间与工具名之间的分隔符
+/// Separator between a namespace and a tool name
+pub const NAMESPACE_SEPARATOR: &str = "::";
+
+#[derive(Debug, Error)]
+pub enum ToolRegistryError {
+    #[error("Tool '{0}' is already registered")]
+    Collision(String),
+
+    #[error("Tool name '{0}' matches several namespaced tools")]
+    Ambiguous(String),
+}
+
+/// 已注册工具的名称与模式
+/// Name and schema of a registered tool
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolInfo {
+    pub name: String,
+
+    /// 经 `register_tool` 注册时给出的模式
+    /// Schema given through `register_tool`
+    pub schema: Option<serde_json::Value>,
+}
+
+/// 运行时可调用哪些工具：允许列表为 `None` 时不限制，禁止列表优先于允许列表
+/// Which tools a runtime may call: no restriction when the allow list is `None`; the deny list wins over the allow list
+///
+/// 名称可为完整注册名或不带命名空间的短名，任一名称被禁止即不可调用
+/// Names can be full registered names or short names without the namespace; a tool is blocked when either is denied
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ToolPermissions {
+    pub allowed: Option<Vec<String>>,
+
+    pub denied: Vec<String>,
+
+    /// 自身注册表中找不到时是否使用默认运行时的工具
+    /// Whether tools of the default runtime are used when the runtime's own registry lacks them
+    pub inherit_global: bool,
+}
+
+impl ToolPermissions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_allowed(mut self, allowed: &[&str]) -> Self {
+        self.allowed = Some(allowed.iter().map(|name| name.to_string()).collect());
+        self
+    }
+
+    pub fn with_denied(mut self, denied: &[&str]) -> Self {
+        self.denied = denied.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    pub fn with_inherit_global(mut self, inherit_global: bool) -> Self {
+        self.inherit_global = inherit_global;
+        self
+    }
+
+    /// 是否允许调用以 `names` 中任一名称指代的工具
+    /// Whether the tool referred to by any of `names` may be called
+    pub fn permits(&self, names: &[&str]) -> bool {
+        let listed = |list: &[String]| names.iter().any(|name| list.iter().any(|entry| entry == name));
+        !listed(&self.denied) && self.allowed.as_deref().is_none_or(listed)
+    }
+}
+
+/// 工具注册表，每个 [`Runtime`] 各有一份；本模块的同名函数操作默认运行时的注册表
+/// Tool registry, one per [`Runtime`]; the functions of the same name in this module work on the default runtime's
+#[derive(Default)]
+pub struct ToolRegistry {
+    functions: DashMap<String, ToolFunction>,
+
+    /// 工具模式，以注册名为键；派生宏注册的工具不在其中
+    /// Tool schemas keyed by registered name; tools registered by the derive macro are not included
+    schemas: DashMap<String, serde_json::Value>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 工具函数表，以注册名为键
+    /// Tool functions keyed by registered name
+    pub fn functions(&self) -> &DashMap<String, ToolFunction> {
+        &self.functions
+    }
+
+    /// 见 [`register_tool`]
+    /// See [`register_tool`]
+    pub fn register(
+        &self,
+        name: &str,
+        mut schema: serde_json::Value,
+        func: impl Fn(serde_json::Value) -> Result<serde_json::Value, ChatToolSchemaError> + Send + Sync + 'static,
+    ) -> Result<serde_json::Value, ToolRegistryError> {
+        match self.functions.entry(name.to_string()) {
+            dashmap::Entry::Occupied(_) => Err(Report::new(ToolRegistryError::Collision(name.to_string()))),
+            dashmap::Entry::Vacant(entry) => {
+                if let Some(function) = schema.get_mut("function") {
+                    function["name"] = serde_json::Value::String(name.to_string());
+                }
+                self.schemas.insert(name.to_string(), schema.clone());
+                entry.insert(Arc::new(func));
+                Ok(schema)
+            }
+        }
+    }
+
+    /// 见 [`unregister_tool`]
+    /// See [`unregister_tool`]
+    pub fn unregister(&self, name: &str) -> bool {
+        self.schemas.remove(name);
+        self.functions.remove(name).is_some()
+    }
+
+    /// 见 [`list_tools`]
+    /// See [`list_tools`]
+    pub fn list(&self) -> Vec<ToolInfo> {
+        let mut tools = self
+            .functions
+            .iter()
+            .map(|entry| ToolInfo {
+                name: entry.key().clone(),
+                schema: self.schemas.get(entry.key()).map(|schema| schema.value().clone()),
+            })
+            .collect::<Vec<_>>();
+        tools.sort_by(|a, b| a.name.cmp(&b.name));
+        tools
+    }
+
+    /// 见 [`resolve_tool_name`]
+    /// See [`resolve_tool_name`]
+    pub fn resolve(&self, name: &str) -> Result<Option<String>, ToolRegistryError> {
+        if self.functions.contains_key(name) {
+            return Ok(Some(name.to_string()));
+        }
+
+        let suffix = format!("{NAMESPACE_SEPARATOR}{name}");
+        let mut matches = self
+            .functions
+            .iter()
+            .filter(|entry| entry.key().ends_with(&suffix))
+            .map(|entry| entry.key().clone());
+        match (matches.next(), matches.next()) {
+            (Some(_), Some(_)) => Err(Report::new(ToolRegistryError::Ambiguous(name.to_string()))),
+            (found, _) => Ok(found),
+        }
+    }
+
+    /// 见 [`get_tool_function`]
+    /// See [`get_tool_function`]
+    pub fn get(&self, name: &str) -> Option<ToolFunction> {
+        let name = self.resolve(name).ok().flatten()?;
+        self.functions.get(&name).map(|entry| entry.value().clone())
+    }
+
+    /// 见 [`execute_tool_call`]
+    /// See [`execute_tool_call`]
+    pub fn execute(&self, call: &ToolCall, tools_schema: &[serde_json::Value]) -> ToolResult {
+        execute_tool_call_with(call, tools_schema, |name| self.get(name))
+    }
+}
+
+/// 默认运行时的工具函数表
+/// Tool functions of the default runtime
 pub fn get_tool_registry() -> &'static DashMap<String, ToolFunction> {
-    REGISTRY.get_or_init(|| DashMap::new())
+    Runtime::default_ref().tools.functions()
+}
+
+/// 注册工具，同名工具已存在时返回 `ToolRegistryError::Collision` 而不覆盖
+/// Register a tool; returns `ToolRegistryError::Collision` instead of overwriting when the name is taken
+///
+/// 模式中的 `function.name` 会改写为注册名，返回改写后的模式
+/// `function.name` in the schema is rewritten to the registered name; the rewritten schema is returned
+///
+/// # 参数 (Parameters)
+/// * `name` - 工具名，可带命名空间，例如 `crate::memory::recall`
+///   - Tool name, optionally namespaced, e.g. `crate::memory::recall`
+/// * `schema` - 工具模式
+///   - Tool schema
+/// * `func` - 工具函数
+///   - Tool function
+pub fn register_tool(
+    name: &str,
+    schema: serde_json::Value,
+    func: impl Fn(serde_json::Value) -> Result<serde_json::Value, ChatToolSchemaError> + Send + Sync + 'static,
+) -> Result<serde_json::Value, ToolRegistryError> {
+    Runtime::default_ref().tools.register(name, schema, func)
+}
+
+/// 以 `命名空间::工具名` 注册工具，不同模块的同名工具互不冲突，返回改写名称后的模式
+/// Register a tool as `namespace::name` so equally named tools of different modules do not collide;
+/// returns the schema with the qualified name
+///
+/// # 参数 (Parameters)
+/// * `namespace` - 命名空间，通常为 `module_path!()`
+///   - Namespace, usually `module_path!()`
+/// * `name` - 工具名
+///   - Tool name
+/// * `schema` - 工具模式
+///   - Tool schema
+/// * `func` - 工具函数
+///   - Tool function
+pub fn register_namespaced_tool(
+    namespace: &str,
+    name: &str,
+    schema: serde_json::Value,
+    func: impl Fn(serde_json::Value) -> Result<serde_json::Value, ChatToolSchemaError> + Send + Sync + 'static,
+) -> Result<serde_json::Value, ToolRegistryError> {
+    register_tool(&format!("{namespace}{NAMESPACE_SEPARATOR}{name}"), schema, func)
+}
+
+/// 注销工具，返回是否存在
+/// Unregister a tool; returns whether it existed
+pub fn unregister_tool(name: &str) -> bool {
+    Runtime::default_ref().tools.unregister(name)
 }
 
+/// 列出全部已注册工具，按名称排序
+/// List all registered tools, sorted by name
+pub fn list_tools() -> Vec<ToolInfo> {
+    Runtime::default_ref().tools.list()
+}
+
+/// 把工具名解析为注册名：优先完全匹配，否则按不带命名空间的短名查找唯一的命名空间工具
+/// Resolve a tool name to its registered name: an exact match first, otherwise the unique namespaced tool with that short name
+pub fn resolve_tool_name(name: &str) -> Result<Option<String>, ToolRegistryError> {
+    Runtime::default_ref().tools.resolve(name)
+}
+
+/// 按名称取工具函数，名称可为完整注册名或唯一的短名
+/// Get a tool function by its full registered name or a unique short name
 pub fn get_tool_function(name: &str) -> Option<ToolFunction> {
-    get_tool_registry().get(name).map(|entry| entry.value().clone())
+    Runtime::default_ref().tools.get(name)
+}
+
+/// 按名称在工具模式中查找
+/// Find a tool's schema by name
+pub fn find_tool_schema<'a>(tools_schema: &'a [serde_json::Value], name: &str) -> Option<&'a serde_json::Value> {
+    tools_schema
+        .iter()
+        .find(|schema| schema["function"]["name"].as_str() == Some(name))
+}
+
+/// 按工具的参数模式修正参数中常见的类型偏差，例如字符串形式的数字与布尔值、大小写不符的枚举值
+/// Fix common type drift in arguments against the tool's parameters schema, such as numbers and booleans sent
+/// as strings or enum values in the wrong case
+///
+/// # 参数 (Parameters)
+/// * `tools_schema` - 可用工具的模式，找不到同名工具时原样返回参数
+///   - Schemas of the available tools; arguments are returned unchanged when the tool is not listed
+/// * `name` - 工具名
+///   - Tool name
+/// * `arguments` - 解析后的参数
+///   - Parsed arguments
+pub fn coerce_tool_arguments(
+    tools_schema: &[serde_json::Value],
+    name: &str,
+    arguments: &serde_json::Value,
+) -> serde_json::Value {
+    let mut arguments = arguments.clone();
+    if let Some(schema) = find_tool_schema(tools_schema, name) {
+        let changed = coerce(schema, &mut arguments);
+        if !changed.is_empty() {
+            debug!("Coerced arguments of '{}' at {:?}", name, changed);
+        }
+    }
+    arguments
+}
+
+/// 执行前按工具的参数模式校验参数，不匹配时返回可交给模型的 [`ToolError`]，其中带有违规项与参数模式
+/// Validate arguments against the tool's parameters schema before execution,
+/// returning a [`ToolError`] for the model with the violations and the parameters schema on mismatch
+///
+/// # 参数 (Parameters)
+/// * `tools_schema` - 可用工具的模式，找不到同名工具时不做校验
+///   - Schemas of the available tools; no validation when the tool is not listed
+/// * `name` - 工具名
+///   - Tool name
+/// * `arguments` - 解析后的参数
+///   - Parsed arguments
+pub fn check_tool_arguments(
+    tools_schema: &[serde_json::Value],
+    name: &str,
+    arguments: &serde_json::Value,
+) -> Option<ToolError> {
+    let schema = find_tool_schema(tools_schema, name)?;
+
+    let violations = validate(schema, arguments);
+    if violations.is_empty() {
+        return None;
+    }
+
+    Some(
+        ToolError::new(
+            ToolErrorKind::InvalidArguments,
+            format!("Arguments of '{name}' do not match its parameters schema; fix the violations and call it again"),
+        )
+        .with_tool(name)
+        .with_violations(violations)
+        .with_expected_schema(schema),
+    )
 }
 
 pub async fn tool_use(text_answer: &str, tools_schema: serde_json::Value) -> Result<(), ChatToolSchemaError> {
+    tool_use_in_runtime(Runtime::global(), text_answer, tools_schema).await
+}
+
+/// 在给定运行时中解析回答里的全部工具调用，解析请求使用该运行时中的模型
+/// Parse every tool call in an answer within the given runtime, whose models handle the parsing requests
+pub async fn tool_use_in_runtime(
+    runtime: Arc<Runtime>,
+    text_answer: &str,
+    tools_schema: serde_json::Value,
+) -> Result<(), ChatToolSchemaError> {
     let functions_calling = extract_tool_uses(text_answer);
     for function_calling in functions_calling {
-        ChatTool::get_function(function_calling.as_str(), tools_schema.clone()).await
+        ChatTool::get_function_in_runtime(runtime.clone(), function_calling.as_str(), tools_schema.clone()).await
             .change_context(ChatToolSchemaError::FunctionCallError)?; // 使用 change_context 转换错误
     }
     Ok(())
 }
 
+/// 本进程内工具调用编号的计数器
+/// Counter numbering tool calls within this process
+static NEXT_CALL_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 一次结构化的工具调用
+/// One structured tool call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// 调用编号，对应结果的 `id` 与 `tool` 消息的 `tool_call_id`
+    /// Call id, matching the `id` of its result and the `tool_call_id` of the `tool` message
+    pub id: String,
+
+    pub name: String,
+
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    /// 创建工具调用并分配进程内唯一的编号
+    /// Create a tool call with an id unique within this process
+    pub fn new(name: &str, arguments: serde_json::Value) -> Self {
+        Self {
+            id: format!("call_{}", NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed)),
+            name: name.to_string(),
+            arguments,
+        }
+    }
+}
+
+/// 一次工具调用的结果
+/// Result of one tool call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolResult {
+    /// 所属调用的编号
+    /// Id of the call it belongs to
+    pub id: String,
+
+    /// 交给模型的输出；出错时为错误说明
+    /// Output handed to the model; the error description on failure
+    pub output: String,
+
+    pub is_error: bool,
+}
+
+impl ToolResult {
+    pub fn ok(call: &ToolCall, output: String) -> Self {
+        Self {
+            id: call.id.clone(),
+            output,
+            is_error: false,
+        }
+    }
+
+    pub fn error(call: &ToolCall, output: String) -> Self {
+        Self {
+            id: call.id.clone(),
+            output,
+            is_error: true,
+        }
+    }
+
+    /// 以 [`ToolError`] 载荷为输出的出错结果
+    /// Failed result with a [`ToolError`] payload as output
+    pub fn failure(call: &ToolCall, error: &ToolError) -> Self {
+        Self::error(call, error.to_string())
+    }
+}
+
+/// 工具调用失败的类别
+/// Category of a failed tool call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolErrorKind {
+    /// 调用文本无法解析为工具调用
+    /// Call text could not be parsed into a tool call
+    ParseError,
+
+    /// 工具不存在或本次未提供
+    /// Tool does not exist or is not offered for this call
+    UnknownTool,
+
+    /// 参数不符合工具的参数模式
+    /// Arguments do not match the tool's parameters schema
+    InvalidArguments,
+
+    /// 工具执行失败或结果无法序列化
+    /// Tool failed or its result could not be serialized
+    ExecutionFailed,
+
+    /// 运行时不允许调用该工具
+    /// The runtime does not permit calling the tool
+    PermissionDenied,
+
+    /// 调用任务本身失败
+    /// The task running the call failed
+    Internal,
+}
+
+/// 作为工具结果交给模型的结构化错误，序列化为 `{"error": {"type", "message", ...}}`，便于模型修正参数后重试
+/// Structured error handed to the model as a tool result, serialized as `{"error": {"type", "message", ...}}`
+/// so the model can retry with corrected arguments
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolError {
+    #[serde(rename = "type")]
+    pub kind: ToolErrorKind,
+
+    pub message: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool: Option<String>,
+
+    /// 参数校验的违规项
+    /// Violations found by argument validation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>,
+
+    /// 工具的参数模式，已知时给出
+    /// Parameters schema of the tool, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_schema: Option<serde_json::Value>,
+}
+
+impl ToolError {
+    pub fn new(kind: ToolErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            tool: None,
+            violations: Vec::new(),
+            expected_schema: None,
+        }
+    }
+
+    pub fn with_tool(mut self, tool: &str) -> Self {
+        self.tool = Some(tool.to_string());
+        self
+    }
+
+    pub fn with_violations(mut self, violations: Vec<Violation>) -> Self {
+        self.violations = violations;
+        self
+    }
+
+    /// 给出期望的参数模式，工具模式会取出其中的 `function.parameters`
+    /// Give the expected parameters schema; `function.parameters` is taken out of a tool schema
+    pub fn with_expected_schema(mut self, schema: &serde_json::Value) -> Self {
+        self.expected_schema = Some(inner_schema(schema).clone());
+        self
+    }
+
+    /// 交给模型的载荷
+    /// Payload handed to the model
+    pub fn to_value(&self) -> serde_json::Value {
+        serde_json::json!({ "error": self })
+    }
+
+    /// 从工具结果的输出中解析结构化错误，输出不是该格式时为 `None`
+    /// Parse a structured error back from a tool result's output; `None` when the output has another shape
+    pub fn from_output(output: &str) -> Option<Self> {
+        let mut value = serde_json::from_str::<serde_json::Value>(output).ok()?;
+        serde_json::from_value(value.get_mut("error")?.take()).ok()
+    }
+}
+
+impl std::fmt::Display for ToolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_value())
+    }
+}
+
+/// 执行工具调用：先按 `tools_schema` 修正并校验参数，找不到工具、参数不合法或执行失败时返回 `is_error` 为真的结果，
+/// 其输出为 [`ToolError`] 载荷
+/// Execute a tool call, coercing and validating arguments against `tools_schema` first; a missing tool, invalid
+/// arguments or a failed execution give a result with `is_error` set and a [`ToolError`] payload as output
+pub fn execute_tool_call(call: &ToolCall, tools_schema: &[serde_json::Value]) -> ToolResult {
+    Runtime::default_ref().tools.execute(call, tools_schema)
+}
+
+/// 以 `lookup` 查找工具函数并执行工具调用，其余同 [`execute_tool_call`]
+/// Execute a tool call with tool functions found by `lookup`; otherwise the same as [`execute_tool_call`]
+pub(crate) fn execute_tool_call_with(
+    call: &ToolCall,
+    tools_schema: &[serde_json::Value],
+    lookup: impl Fn(&str) -> Option<ToolFunction>,
+) -> ToolResult {
+    let arguments = coerce_tool_arguments(tools_schema, &call.name, &call.arguments);
+    if let Some(error) = check_tool_arguments(tools_schema, &call.name, &arguments) {
+        return ToolResult::failure(call, &error);
+    }
+    let Some(tool_fn) = lookup(&call.name) else {
+        return ToolResult::failure(call, &unknown_tool(&call.name, tools_schema));
+    };
+
+    let failed = |message: String| {
+        let error = ToolError::new(ToolErrorKind::ExecutionFailed, message).with_tool(&call.name);
+        match find_tool_schema(tools_schema, &call.name) {
+            Some(schema) => error.with_expected_schema(schema),
+            None => error,
+        }
+    };
+    match tool_fn(arguments) {
+        Ok(result) => match serde_json::to_string_pretty(&result) {
+            Ok(output) => ToolResult::ok(call, output),
+            Err(e) => ToolResult::failure(call, &failed(format!("Failed to serialize result of '{}': {}", call.name, e))),
+        },
+        Err(e) => ToolResult::failure(call, &failed(format!("Calling function '{}' failed: {}", call.name, e))),
+    }
+}
+
+/// 工具不存在或本次未提供时的错误，列出可用的工具名
+/// Error for a tool that doesn't exist or isn't offered for this call, listing the available tool names
+pub fn unknown_tool(name: &str, tools_schema: &[serde_json::Value]) -> ToolError {
+    let available = tools_schema
+        .iter()
+        .filter_map(|schema| schema["function"]["name"].as_str())
+        .collect::<Vec<_>>();
+    let message = if available.is_empty() {
+        format!("Tool '{name}' is not available")
+    } else {
+        format!("Tool '{name}' is not available; available tools: {}", available.join(", "))
+    };
+    ToolError::new(ToolErrorKind::UnknownTool, message).with_tool(name)
+}
+
+const TOOL_USE_OPEN: &str = "<ToolUse>";
+const TOOL_USE_CLOSE: &str = "</ToolUse>";
+const CDATA_OPEN: &str = "<![CDATA[";
+const CDATA_CLOSE: &str = "]]>";
+
+/// 回答中的一个 `<ToolUse>` 调用：整个标签在原文中的字节范围及标签内的文本
+/// One `<ToolUse>` call in an answer: the byte range of the whole tag in the input and the text inside it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolUseSpan {
+    pub range: std::ops::Range<usize>,
+
+    /// 去掉首尾空白与 CDATA 包裹后的文本
+    /// Text with surrounding whitespace and CDATA wrappers removed
+    pub content: String,
+}
+
+/// 扫描标签内文本直到与之配对的 `</ToolUse>`，返回结束标签的位置与标签内文本
+/// Scan the text inside a tag up to its matching `</ToolUse>`; returns the position of the closing tag and the text inside
+///
+/// 严格模式下嵌套的 `<ToolUse>` 需要各自闭合，JSON 对象或数组内字符串中的标签不计入；
+/// 宽松模式与旧的正则一致，遇到第一个 `</ToolUse>` 即结束。两种模式都保留 CDATA 内的原文
+/// In strict mode nested `<ToolUse>` tags must be closed in turn and tags inside strings of a JSON object or array do not count;
+/// lenient mode matches the former regex and ends at the first `</ToolUse>`. Both modes keep CDATA content verbatim
+fn scan_tool_use(input: &str, from: usize, strict: bool) -> Option<(usize, String)> {
+    let mut content = String::new();
+    let mut depth = 1;
+    let mut brackets = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut i = from;
+
+    while i < input.len() {
+        let rest = &input[i..];
+        if in_string {
+            let c = rest.chars().next()?;
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            content.push(c);
+            i += c.len_utf8();
+            continue;
+        }
+
+        if let Some(cdata) = rest.strip_prefix(CDATA_OPEN) {
+            let end = cdata.find(CDATA_CLOSE)?;
+            content.push_str(&cdata[..end]);
+            i += CDATA_OPEN.len() + end + CDATA_CLOSE.len();
+            continue;
+        }
+        if rest.starts_with(TOOL_USE_CLOSE) {
+            depth -= 1;
+            if depth == 0 || !strict {
+                return Some((i, content.trim().to_string()));
+            }
+            content.push_str(TOOL_USE_CLOSE);
+            i += TOOL_USE_CLOSE.len();
+            continue;
+        }
+        if strict && rest.starts_with(TOOL_USE_OPEN) {
+            depth += 1;
+            content.push_str(TOOL_USE_OPEN);
+            i += TOOL_USE_OPEN.len();
+            continue;
+        }
+
+        let c = rest.chars().next()?;
+        if strict {
+            match c {
+                '{' | '[' => brackets += 1,
+                '}' | ']' => brackets = brackets.saturating_sub(1),
+                '"' if brackets > 0 => in_string = true,
+                _ => {}
+            }
+        }
+        content.push(c);
+        i += c.len_utf8();
+    }
+    None
+}
+
+/// 找出回答中的全部 `<ToolUse>` 调用
+/// Find every `<ToolUse>` call in an answer
+///
+/// 支持嵌套标签、JSON 字符串中出现的 `</ToolUse>` 与 CDATA 段；按严格规则无法闭合的开始标签被跳过，
+/// 其后没有其他开始标签时退回到遇到第一个 `</ToolUse>` 即结束，仍未闭合则忽略
+/// Handles nested tags, `</ToolUse>` inside JSON strings and CDATA sections; an opening tag that cannot be closed under
+/// the strict rules is skipped, or, when no other opening tag follows, ends at the first `</ToolUse>`; if it still does not
+/// close it is ignored
+pub fn find_tool_uses(input: &str) -> Vec<ToolUseSpan> {
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = input[pos..].find(TOOL_USE_OPEN) {
+        let start = pos + offset;
+        let body = start + TOOL_USE_OPEN.len();
+        let scanned = match scan_tool_use(input, body, true) {
+            Some(scanned) => Some(scanned),
+            None if input[body..].contains(TOOL_USE_OPEN) => {
+                pos = body;
+                continue;
+            }
+            None => scan_tool_use(input, body, false),
+        };
+        let Some((end, content)) = scanned else {
+            break;
+        };
+        let end = end + TOOL_USE_CLOSE.len();
+        spans.push(ToolUseSpan { range: start..end, content });
+        pos = end;
+    }
+    spans
+}
+
+/// 提取回答中全部 `<ToolUse>` 标签内的文本
+/// Extract the text inside every `<ToolUse>` tag of an answer
 pub fn extract_tool_uses(input: &str) -> Vec<String> {
-    // 定义正则表达式，匹配 <ToolUse> 标签包裹的内容，支持多行
-    let re = Regex::new(r"(?s)<ToolUse>(.*?)</ToolUse>").unwrap();
+    find_tool_uses(input).into_iter().map(|span| span.content).collect()
+}
 
-    re.captures_iter(input)
-        .map(|cap| cap[1].trim().to_string())
-        .collect()
-}
\ No newline at end of file
+/// 去掉回答中全部 `<ToolUse>` 标签，保留其余正文
+/// Remove every `<ToolUse>` tag from an answer, keeping the rest of the text
+pub fn strip_tool_uses(input: &str) -> String {
+    let mut text = String::with_capacity(input.len());
+    let mut pos = 0;
+    for span in find_tool_uses(input) {
+        text.push_str(&input[pos..span.range.start]);
+        pos = span.range.end;
+    }
+    text.push_str(&input[pos..]);
+    text
+}
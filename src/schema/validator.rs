@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+
+/// 校验失败的位置与原因
+/// Location and reason of a validation failure
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violation {
+    /// JSON 指针风格的路径，如 `/tasks/0`
+    /// JSON-pointer style path such as `/tasks/0`
+    pub path: String,
+
+    pub message: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = if self.path.is_empty() { "/" } else { &self.path };
+        write!(f, "{}: {}", path, self.message)
+    }
+}
+
+/// 取出实际用于校验的模式：兼容 `response_format` 外层与工具模式
+/// Get the schema to validate against, unwrapping `response_format` and tool schemas
+pub fn inner_schema(schema: &serde_json::Value) -> &serde_json::Value {
+    if let Some(inner) = schema.get("json_schema").and_then(|s| s.get("schema")) {
+        inner
+    } else if let Some(parameters) = schema.get("function").and_then(|f| f.get("parameters")) {
+        parameters
+    } else {
+        schema
+    }
+}
+
+/// 按 JSON Schema 的常用子集校验值，返回全部违规项
+/// Validate a value against the common subset of JSON Schema, returning every violation
+///
+/// 支持 type、enum、properties、required、additionalProperties、items、
+/// minimum/maximum、exclusiveMinimum/exclusiveMaximum、minLength/maxLength、minItems/maxItems
+/// Supports type, enum, properties, required, additionalProperties, items,
+/// minimum/maximum, exclusiveMinimum/exclusiveMaximum, minLength/maxLength, minItems/maxItems
+pub fn validate(schema: &serde_json::Value, value: &serde_json::Value) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    validate_at(inner_schema(schema), value, "", &mut violations);
+    violations
+}
+
+fn validate_at(
+    schema: &serde_json::Value,
+    value: &serde_json::Value,
+    path: &str,
+    violations: &mut Vec<Violation>,
+) {
+    let mut violate = |message: String| {
+        violations.push(Violation {
+            path: path.to_string(),
+            message,
+        })
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types = match expected {
+            serde_json::Value::Array(types) => types.iter().filter_map(|t| t.as_str()).collect(),
+            other => other.as_str().into_iter().collect::<Vec<_>>(),
+        };
+        if !types.is_empty() && !types.iter().any(|t| matches_type(t, value)) {
+            violate(format!("expected {}, got {}", types.join(" or "), type_name(value)));
+            return;
+        }
+    }
+
+    if let Some(options) = schema.get("enum").and_then(|e| e.as_array())
+        && !options.contains(value)
+    {
+        violate(format!("must be one of {}", serde_json::Value::Array(options.clone())));
+    }
+
+    if let Some(number) = value.as_f64() {
+        let bound = |key: &str| schema.get(key).and_then(|b| b.as_f64());
+        if let Some(min) = bound("minimum")
+            && number < min
+        {
+            violate(format!("must be >= {min}"));
+        }
+        if let Some(max) = bound("maximum")
+            && number > max
+        {
+            violate(format!("must be <= {max}"));
+        }
+        if let Some(min) = bound("exclusiveMinimum")
+            && number <= min
+        {
+            violate(format!("must be > {min}"));
+        }
+        if let Some(max) = bound("exclusiveMaximum")
+            && number >= max
+        {
+            violate(format!("must be < {max}"));
+        }
+    }
+
+    if let Some(text) = value.as_str() {
+        let length = text.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(|m| m.as_u64())
+            && length < min
+        {
+            violate(format!("length must be >= {min}"));
+        }
+        if let Some(max) = schema.get("maxLength").and_then(|m| m.as_u64())
+            && length > max
+        {
+            violate(format!("length must be <= {max}"));
+        }
+    }
+
+    if let Some(items) = value.as_array() {
+        let count = items.len() as u64;
+        if let Some(min) = schema.get("minItems").and_then(|m| m.as_u64())
+            && count < min
+        {
+            violate(format!("must have at least {min} items"));
+        }
+        if let Some(max) = schema.get("maxItems").and_then(|m| m.as_u64())
+            && count > max
+        {
+            violate(format!("must have at most {max} items"));
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                validate_at(item_schema, item, &format!("{path}/{i}"), violations);
+            }
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(|p| p.as_object());
+
+        if let Some(required) = schema.get("required").and_then(|r| r.as_array()) {
+            for name in required.iter().filter_map(|r| r.as_str()) {
+                if !object.contains_key(name) {
+                    violations.push(Violation {
+                        path: format!("{path}/{name}"),
+                        message: "is required".to_string(),
+                    });
+                }
+            }
+        }
+
+        for (name, field) in object {
+            let field_path = format!("{path}/{name}");
+            match properties.and_then(|p| p.get(name)) {
+                Some(field_schema) => validate_at(field_schema, field, &field_path, violations),
+                None if schema.get("additionalProperties") == Some(&serde_json::Value::Bool(false)) => {
+                    violations.push(Violation {
+                        path: field_path,
+                        message: "is not allowed".to_string(),
+                    });
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+pub(crate) fn matches_type(expected: &str, value: &serde_json::Value) -> bool {
+    match expected {
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Number(n) if n.is_f64() => "number",
+        serde_json::Value::Number(_) => "integer",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use error_stack::{Report, Result, ResultExt};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{info, warn};
+
+use crate::agent::react::ReActAgent;
+use crate::chat::chat_single::SingleChat;
+use crate::chat::message::{Role, SessionUsage};
+use crate::chat::pruning::now_ms;
+use crate::chat::stream::StreamEvent;
+use crate::utils::common::redact::redact;
+
+static COMPLETION_ID: AtomicU64 = AtomicU64::new(0);
+
+type TextCallback = Arc<dyn Fn(&str) + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("Failed to bind address: {0}")]
+    BindError(String),
+
+    #[error("Server stopped unexpectedly")]
+    ServeError,
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Unknown model: {0}")]
+    UnknownModel(String),
+
+    #[error("Failed to answer")]
+    AnswerError,
+}
+
+impl ServerError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Self::UnknownModel(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// 对外提供的模型：普通对话或带工具的 ReAct 智能体
+/// Model served to clients: a plain chat or a ReAct agent with tools
+#[derive(Debug, Clone)]
+pub enum ServedModel {
+    /// 每个请求克隆该对话，保留其提示、守卫与安全策略
+    /// Cloned for every request, keeping its prompts, guards and safety policy
+    Chat(SingleChat),
+
+    /// 以最后一条用户消息为任务运行，此前的对话作为背景附在任务前
+    /// Runs the last user message as its task, with the earlier conversation prepended as background
+    Agent(ReActAgent),
+}
+
+/// 请求中的一条消息
+/// One message of a request
+#[derive(Debug, Clone, Deserialize)]
+pub struct IncomingMessage {
+    pub role: String,
+
+    /// 字符串或 `{"type": "text", "text": …}` 片段数组
+    /// A string or an array of `{"type": "text", "text": …}` parts
+    #[serde(default)]
+    pub content: serde_json::Value,
+
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub tool_call_id: Option<String>,
+}
+
+impl IncomingMessage {
+    /// 拼接出纯文本内容，非文本片段被忽略
+    /// Join the text content; non-text parts are ignored
+    pub fn text(&self) -> String {
+        match &self.content {
+            serde_json::Value::String(text) => text.clone(),
+            serde_json::Value::Array(parts) => parts
+                .iter()
+                .filter_map(|part| part["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        }
+    }
+
+    fn to_role(&self) -> Result<Role, ServerError> {
+        match self.role.as_str() {
+            "system" | "developer" => Ok(Role::System),
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            "tool" => Ok(Role::Tool {
+                call_id: self.tool_call_id.clone().unwrap_or_default(),
+            }),
+            "function" => Ok(Role::Function {
+                name: self.name.clone().unwrap_or_default(),
+            }),
+            other => Err(Report::new(ServerError::InvalidRequest(format!("unknown role `{other}`")))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CompletionRequest {
+    model: String,
+
+    messages: Vec<IncomingMessage>,
+
+    #[serde(default)]
+    stream: bool,
+}
+
+/// 拆出作为任务的最后一条用户消息与此前的对话，并检查每条消息的角色
+/// Split off the last user message as the task from the earlier conversation, checking every message's role
+fn split_messages(messages: &[IncomingMessage]) -> Result<(&IncomingMessage, &[IncomingMessage]), ServerError> {
+    let (last, history) = messages
+        .split_last()
+        .filter(|(last, _)| last.role == "user")
+        .ok_or_else(|| Report::new(ServerError::InvalidRequest("the last message must come from the user".to_string())))?;
+    history.iter().try_for_each(|message| message.to_role().map(|_| ()))?;
+    Ok((last, history))
+}
+
+impl ServedModel {
+    /// 回答一组消息，返回回答与本次请求的用量
+    /// Answer a list of messages; returns the answer and the usage of this request
+    pub async fn answer(&self, messages: &[IncomingMessage]) -> Result<(String, SessionUsage), ServerError> {
+        self.answer_with(messages, None).await
+    }
+
+    /// 同 `answer`，但回答正文按到达顺序交给 `on_text`
+    /// Same as `answer`, but the answer text is handed to `on_text` as it arrives
+    ///
+    /// 没有守卫与安全策略的对话逐段转发模型的增量；其余情况（包括智能体）要等回答定稿，
+    /// 只在结束时整体交出一次，以免发出随后被改写或拦下的内容
+    /// Chats without guards or a safety policy forward the model's deltas one by one; otherwise (agents included)
+    /// the answer is handed over once at the end, so no text that is later rewritten or blocked gets sent
+    pub async fn answer_streaming(
+        &self,
+        messages: &[IncomingMessage],
+        on_text: impl Fn(&str) + Send + Sync + 'static,
+    ) -> Result<(String, SessionUsage), ServerError> {
+        self.answer_with(messages, Some(Arc::new(on_text))).await
+    }
+
+    async fn answer_with(
+        &self,
+        messages: &[IncomingMessage],
+        on_text: Option<TextCallback>,
+    ) -> Result<(String, SessionUsage), ServerError> {
+        let (last, history) = split_messages(messages)?;
+
+        match self {
+            Self::Chat(template) => {
+                let mut chat = template.clone();
+                let live = on_text
+                    .clone()
+                    .filter(|_| chat.base.guards.is_empty() && chat.base.safety.is_none());
+                if let Some(on_text) = &live {
+                    let on_text = on_text.clone();
+                    chat.set_need_stream(true);
+                    chat.base.set_stream_callback(move |event| {
+                        if let StreamEvent::Text(text) = event {
+                            on_text(text);
+                        }
+                    });
+                }
+
+                let before = chat.base.session.usage;
+                for message in history {
+                    chat.base
+                        .add_message(message.to_role()?, &message.text())
+                        .change_context(ServerError::AnswerError)?;
+                }
+                let request_body = chat
+                    .get_req_body(&last.text())
+                    .await
+                    .change_context(ServerError::AnswerError)?;
+                let answer = chat
+                    .get_content_from_req_body(request_body)
+                    .await
+                    .change_context(ServerError::AnswerError)?;
+                if let (None, Some(on_text)) = (&live, &on_text) {
+                    on_text(&answer);
+                }
+                Ok((answer, usage_since(before, chat.base.session.usage)))
+            }
+            Self::Agent(template) => {
+                let mut agent = template.clone();
+                let before = agent.chat.base.session.usage;
+                let mut task = String::new();
+                if !history.is_empty() {
+                    task.push_str("此前的对话：\n");
+                    for message in history {
+                        task.push_str(&format!("{}: {}\n", message.role, message.text()));
+                    }
+                    task.push('\n');
+                }
+                task.push_str(&last.text());
+
+                let (answer, steps) = agent.run(&task).await.change_context(ServerError::AnswerError)?;
+                info!("Agent answered in {} steps", steps.len());
+                if let Some(on_text) = &on_text {
+                    on_text(&answer);
+                }
+                Ok((answer, usage_since(before, agent.chat.base.session.usage)))
+            }
+        }
+    }
+}
+
+fn usage_since(before: SessionUsage, after: SessionUsage) -> SessionUsage {
+    SessionUsage {
+        requests: after.requests - before.requests,
+        prompt_tokens: after.prompt_tokens - before.prompt_tokens,
+        completion_tokens: after.completion_tokens - before.completion_tokens,
+    }
+}
+
+/// OpenAI 兼容的 HTTP 服务，让现有前端（LibreChat、OpenWebUI 等）把 rhine 对话或智能体当作模型使用
+/// OpenAI-compatible HTTP server letting existing front-ends (LibreChat, OpenWebUI, ...) use rhine chats or agents as models
+///
+/// 提供 `POST /v1/chat/completions`（支持 `stream`）与 `GET /v1/models`
+/// Serves `POST /v1/chat/completions` (with `stream` support) and `GET /v1/models`
+#[derive(Debug, Clone, Default)]
+pub struct ChatServer {
+    models: BTreeMap<String, ServedModel>,
+
+    api_key: Option<String>,
+}
+
+impl ChatServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_chat(mut self, name: &str, chat: SingleChat) -> Self {
+        self.models.insert(name.to_string(), ServedModel::Chat(chat));
+        self
+    }
+
+    pub fn with_agent(mut self, name: &str, agent: ReActAgent) -> Self {
+        self.models.insert(name.to_string(), ServedModel::Agent(agent));
+        self
+    }
+
+    /// 要求请求携带 `Authorization: Bearer <api_key>`
+    /// Require `Authorization: Bearer <api_key>` on every request
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/v1/chat/completions", post(chat_completions))
+            .route("/v1/models", get(list_models))
+            .with_state(Arc::new(self))
+    }
+
+    /// 监听地址并持续提供服务
+    /// Listen on an address and serve until the server stops
+    ///
+    /// # 参数 (Parameters)
+    /// * `addr` - 监听地址，例如 `127.0.0.1:8080`
+    ///   - Address to listen on, e.g. `127.0.0.1:8080`
+    pub async fn serve(self, addr: &str) -> Result<(), ServerError> {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .change_context_lazy(|| ServerError::BindError(addr.to_string()))?;
+        info!("Serving OpenAI-compatible API on {addr}");
+        axum::serve(listener, self.router())
+            .await
+            .change_context(ServerError::ServeError)
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(api_key) = &self.api_key else {
+            return true;
+        };
+        // 常量时间比较，避免按响应时间逐字节猜出密钥
+        // Constant-time comparison, so the key cannot be guessed byte by byte from response times
+        headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| bool::from(given.as_bytes().ct_eq(api_key.as_bytes())))
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    let error_type = if status.is_client_error() {
+        "invalid_request_error"
+    } else {
+        "server_error"
+    };
+    (status, Json(json!({"error": {"message": message, "type": error_type}}))).into_response()
+}
+
+async fn list_models(State(server): State<Arc<ChatServer>>, headers: HeaderMap) -> Response {
+    if !server.authorized(&headers) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key");
+    }
+    let data: Vec<_> = server
+        .models
+        .keys()
+        .map(|name| json!({"id": name, "object": "model", "owned_by": "rhine"}))
+        .collect();
+    Json(json!({"object": "list", "data": data})).into_response()
+}
+
+async fn chat_completions(
+    State(server): State<Arc<ChatServer>>,
+    headers: HeaderMap,
+    Json(request): Json<CompletionRequest>,
+) -> Response {
+    if !server.authorized(&headers) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key");
+    }
+    let Some(model) = server.models.get(&request.model) else {
+        let error = ServerError::UnknownModel(request.model.clone());
+        return error_response(error.status(), &error.to_string());
+    };
+
+    let header = ChunkHeader {
+        id: format!("chatcmpl-{}", COMPLETION_ID.fetch_add(1, Ordering::Relaxed)),
+        created: now_ms() / 1000,
+        model: request.model.clone(),
+    };
+    if request.stream {
+        return stream_completion(model.clone(), request.messages, header);
+    }
+
+    let (answer, usage) = match model.answer(&request.messages).await {
+        Ok(answered) => answered,
+        Err(report) => {
+            warn!("Failed to answer request for {}: {}", request.model, redact(&format!("{report:?}")));
+            return error_response(report.current_context().status(), &report.current_context().to_string());
+        }
+    };
+    Json(json!({
+        "id": header.id,
+        "object": "chat.completion",
+        "created": header.created,
+        "model": header.model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": answer},
+            "finish_reason": "stop",
+        }],
+        "usage": usage_json(usage),
+    }))
+    .into_response()
+}
+
+fn usage_json(usage: SessionUsage) -> serde_json::Value {
+    json!({
+        "prompt_tokens": usage.prompt_tokens,
+        "completion_tokens": usage.completion_tokens,
+        "total_tokens": usage.total_tokens(),
+    })
+}
+
+/// 一次回答中每个 SSE 分块共有的字段
+/// Fields shared by every SSE chunk of one answer
+#[derive(Debug, Clone)]
+struct ChunkHeader {
+    id: String,
+
+    created: u64,
+
+    model: String,
+}
+
+impl ChunkHeader {
+    fn chunk(&self, delta: serde_json::Value, finish_reason: Option<&str>) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        })
+    }
+}
+
+/// 在后台回答并以 SSE 转发：角色、随生成到达的内容增量、结束原因与用量，出错时为错误对象
+/// Answer in the background and forward it as SSE: role, content deltas as they are generated, then the finish
+/// reason with usage, or an error object on failure
+fn stream_completion(model: ServedModel, messages: Vec<IncomingMessage>, header: ChunkHeader) -> Response {
+    if let Err(report) = split_messages(&messages) {
+        return error_response(report.current_context().status(), &report.current_context().to_string());
+    }
+
+    let (sender, receiver) = mpsc::unbounded_channel::<serde_json::Value>();
+    let _ = sender.send(header.chunk(json!({"role": "assistant"}), None));
+    tokio::spawn(async move {
+        let deltas = sender.clone();
+        let delta_header = header.clone();
+        let answered = model
+            .answer_streaming(&messages, move |text| {
+                let _ = deltas.send(delta_header.chunk(json!({"content": text}), None));
+            })
+            .await;
+        let last = match answered {
+            Ok((_, usage)) => {
+                let mut last = header.chunk(json!({}), Some("stop"));
+                last["usage"] = usage_json(usage);
+                last
+            }
+            Err(report) => {
+                warn!("Failed to answer request for {}: {}", header.model, redact(&format!("{report:?}")));
+                json!({"error": {"message": report.current_context().to_string(), "type": "server_error"}})
+            }
+        };
+        let _ = sender.send(last);
+    });
+
+    let events = UnboundedReceiverStream::new(receiver)
+        .map(|chunk| Event::default().data(chunk.to_string()))
+        .chain(futures::stream::once(async { Event::default().data("[DONE]") }))
+        .map(Ok::<_, Infallible>);
+    Sse::new(events).into_response()
+}
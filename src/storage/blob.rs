@@ -0,0 +1,247 @@
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use error_stack::{Report, Result, ResultExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::schema::tool_schema::{register_tool, ChatToolSchemaError, ToolRegistryError};
+use crate::storage::StorageError;
+
+/// 被转存内容的引用在消息元数据中的键
+/// Key of the reference to offloaded content in message metadata
+pub const BLOB_METADATA_KEY: &str = "blob";
+
+/// 消息正文中引用的前缀，后接内容哈希
+/// Prefix of references in message content, followed by the content hash
+pub const BLOB_REFERENCE_PREFIX: &str = "blob:";
+
+/// 按需读取被转存内容的工具名称
+/// Name of the tool that reads offloaded content on demand
+pub const READ_BLOB_TOOL: &str = "read_blob";
+
+const DEFAULT_THRESHOLD_CHARS: usize = 8000;
+
+const DEFAULT_PREVIEW_CHARS: usize = 1000;
+
+const DEFAULT_READ_CHARS: usize = 8000;
+
+/// 内容寻址的大块内容存储，键为内容的 SHA-256 十六进制哈希
+/// Content-addressed store of large payloads keyed by the SHA-256 hex digest of the content
+///
+/// 工具函数是同步的，为了让工具直接读取，存储接口也是同步的
+/// Tool functions are synchronous, so the store is too, letting tools read from it directly
+pub trait BlobStore: Debug + Send + Sync {
+    /// 保存内容，相同哈希已存在时可以跳过写入
+    /// Save content; writing may be skipped when the hash already exists
+    fn put(&self, hash: &str, content: &str) -> Result<(), StorageError>;
+
+    fn get(&self, hash: &str) -> Result<Option<String>, StorageError>;
+}
+
+/// 内容的 SHA-256 十六进制哈希
+/// SHA-256 hex digest of the content
+pub fn blob_hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn is_blob_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// 进程内的内容存储
+/// In-process blob store
+#[derive(Debug, Default)]
+pub struct MemoryBlobStore {
+    blobs: DashMap<String, String>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn put(&self, hash: &str, content: &str) -> Result<(), StorageError> {
+        self.blobs.insert(hash.to_string(), content.to_string());
+        Ok(())
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.blobs.get(hash).map(|entry| entry.value().clone()))
+    }
+}
+
+/// 基于目录的内容存储，以哈希前两位分目录：`<dir>/ab/cdef…`
+/// Directory-backed blob store sharded by the first two hash digits: `<dir>/ab/cdef…`
+#[derive(Clone, Debug)]
+pub struct FileBlobStore {
+    dir: PathBuf,
+}
+
+impl FileBlobStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn blob_path(&self, hash: &str) -> Result<PathBuf, StorageError> {
+        if !is_blob_hash(hash) {
+            return Err(Report::new(StorageError::InvalidId(hash.to_string())));
+        }
+        Ok(self.dir.join(&hash[..2]).join(&hash[2..]))
+    }
+}
+
+impl BlobStore for FileBlobStore {
+    fn put(&self, hash: &str, content: &str) -> Result<(), StorageError> {
+        let path = self.blob_path(hash)?;
+        if path.exists() {
+            return Ok(());
+        }
+        let shard = path.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(shard)
+            .change_context_lazy(|| StorageError::OpenError(shard.display().to_string()))?;
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, content).change_context(StorageError::QueryError)?;
+        std::fs::rename(&temp, &path).change_context(StorageError::QueryError)
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<String>, StorageError> {
+        match std::fs::read_to_string(self.blob_path(hash)?) {
+            Ok(content) => Ok(Some(content)),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(Report::new(error).change_context(StorageError::QueryError)),
+        }
+    }
+}
+
+/// 消息中对被转存内容的引用
+/// Reference from a message to offloaded content
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobRef {
+    pub hash: String,
+
+    /// 完整内容的字符数
+    /// Character count of the full content
+    pub chars: usize,
+}
+
+/// 大块工具结果的转存策略：超过阈值的内容存入内容存储，消息中只保留开头的预览与引用
+/// Offload policy for large tool results: content over the threshold goes to the blob store and the message keeps
+/// only a leading preview plus a reference
+#[derive(Clone, Debug)]
+pub struct BlobOffload {
+    store: Arc<dyn BlobStore>,
+
+    threshold_chars: usize,
+
+    preview_chars: usize,
+}
+
+impl BlobOffload {
+    pub fn new(store: impl BlobStore + 'static) -> Self {
+        Self::with_shared_store(Arc::new(store))
+    }
+
+    /// 与其他对话或 [`register_read_blob_tool`] 共用同一个存储
+    /// Share one store with other chats or [`register_read_blob_tool`]
+    pub fn with_shared_store(store: Arc<dyn BlobStore>) -> Self {
+        Self {
+            store,
+            threshold_chars: DEFAULT_THRESHOLD_CHARS,
+            preview_chars: DEFAULT_PREVIEW_CHARS,
+        }
+    }
+
+    /// 超过多少字符的内容会被转存，默认 8000
+    /// Content longer than this many characters is offloaded; 8000 by default
+    pub fn with_threshold_chars(mut self, threshold_chars: usize) -> Self {
+        self.threshold_chars = threshold_chars;
+        self
+    }
+
+    /// 消息中保留的预览字符数，默认 1000
+    /// Characters of preview kept in the message; 1000 by default
+    pub fn with_preview_chars(mut self, preview_chars: usize) -> Self {
+        self.preview_chars = preview_chars;
+        self
+    }
+
+    pub fn store(&self) -> &Arc<dyn BlobStore> {
+        &self.store
+    }
+
+    /// 内容超过阈值时存入内容存储，返回替代消息正文与引用；未超过时返回 `None`
+    /// Store content over the threshold and return the replacement message content with its reference;
+    /// `None` when under the threshold
+    pub fn offload(&self, content: &str) -> Result<Option<(String, BlobRef)>, StorageError> {
+        let chars = content.chars().count();
+        if chars <= self.threshold_chars {
+            return Ok(None);
+        }
+
+        let hash = blob_hash(content);
+        self.store.put(&hash, content)?;
+        let preview: String = content.chars().take(self.preview_chars).collect();
+        let message = format!(
+            "{preview}\n……\n[完整内容共 {chars} 字符，已存为 {BLOB_REFERENCE_PREFIX}{hash}，可用 {READ_BLOB_TOOL} 工具按需读取]"
+        );
+        Ok(Some((message, BlobRef { hash, chars })))
+    }
+}
+
+/// 读取被转存内容的一段，`hash` 可带 `blob:` 前缀；内容不存在时返回 `None`
+/// Read a slice of offloaded content; `hash` may carry the `blob:` prefix. `None` when the content does not exist
+pub fn read_blob(
+    store: &dyn BlobStore,
+    hash: &str,
+    offset: usize,
+    length: usize,
+) -> Result<Option<String>, StorageError> {
+    let hash = hash.strip_prefix(BLOB_REFERENCE_PREFIX).unwrap_or(hash);
+    Ok(store
+        .get(hash)?
+        .map(|content| content.chars().skip(offset).take(length).collect()))
+}
+
+/// 注册 [`READ_BLOB_TOOL`] 工具，让模型或智能体按字符偏移分段读取被转存的内容
+/// Register the [`READ_BLOB_TOOL`] tool so models and agents can read offloaded content in slices by character offset
+pub fn register_read_blob_tool(store: Arc<dyn BlobStore>) -> Result<serde_json::Value, ToolRegistryError> {
+    let schema = json!({
+        "type": "function",
+        "function": {
+            "name": READ_BLOB_TOOL,
+            "description": "读取因过长而被转存的工具结果",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "hash": {"type": "string", "description": "消息中给出的 blob: 引用"},
+                    "offset": {"type": "integer", "description": "起始字符位置，默认 0"},
+                    "length": {"type": "integer", "description": "读取的字符数，默认 8000"},
+                },
+                "required": ["hash"],
+            },
+        },
+    });
+    register_tool(READ_BLOB_TOOL, schema, move |args| {
+        let hash = args["hash"].as_str().unwrap_or_default();
+        let offset = args["offset"].as_u64().unwrap_or(0) as usize;
+        let length = args["length"].as_u64().map_or(DEFAULT_READ_CHARS, |length| length as usize);
+        let content = read_blob(store.as_ref(), hash, offset, length)
+            .change_context(ChatToolSchemaError::FunctionCallError)?
+            .ok_or_else(|| {
+                Report::new(ChatToolSchemaError::FunctionCallError)
+                    .attach_printable(format!("No blob stored under {hash}"))
+            })?;
+        let read = content.chars().count();
+        Ok(json!({"content": content, "offset": offset, "read_chars": read}))
+    })
+}
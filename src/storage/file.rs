@@ -0,0 +1,104 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use error_stack::{Report, Result, ResultExt};
+use futures::future::BoxFuture;
+
+use crate::chat::message::Session;
+use crate::storage::{SessionStore, StorageError};
+
+const SESSION_EXTENSION: &str = "json";
+
+/// 基于目录的会话存储：每个会话一个 `<id>.json` 文件
+/// Directory-backed session store: one `<id>.json` file per session
+///
+/// 写入先落到临时文件再改名，进程中断不会留下半个会话
+/// Writes go to a temporary file that is then renamed, so an interrupted process never leaves half a session
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// 目录不存在时在第一次保存时创建
+    /// The directory is created on the first save when missing
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn session_path(&self, id: &str) -> Result<PathBuf, StorageError> {
+        let valid = !id.is_empty()
+            && id != "."
+            && id != ".."
+            && !id.contains(['/', '\\', '\0']);
+        if !valid {
+            return Err(Report::new(StorageError::InvalidId(id.to_string())));
+        }
+        Ok(self.dir.join(format!("{id}.{SESSION_EXTENSION}")))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn save<'a>(&'a self, id: &'a str, session: &'a Session) -> BoxFuture<'a, Result<(), StorageError>> {
+        Box::pin(async move {
+            let path = self.session_path(id)?;
+            let content = serde_json::to_vec_pretty(session).change_context(StorageError::SerializeError)?;
+            let dir = self.dir.display().to_string();
+            tokio::fs::create_dir_all(&self.dir)
+                .await
+                .change_context_lazy(|| StorageError::OpenError(dir.clone()))?;
+            let temp = path.with_extension(format!("{SESSION_EXTENSION}.tmp"));
+            tokio::fs::write(&temp, content)
+                .await
+                .change_context(StorageError::QueryError)?;
+            tokio::fs::rename(&temp, &path)
+                .await
+                .change_context(StorageError::QueryError)
+        })
+    }
+
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Session>, StorageError>> {
+        Box::pin(async move {
+            let content = match tokio::fs::read(self.session_path(id)?).await {
+                Ok(content) => content,
+                Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(error) => return Err(Report::new(error).change_context(StorageError::QueryError)),
+            };
+            serde_json::from_slice(&content)
+                .map(Some)
+                .change_context(StorageError::SerializeError)
+        })
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), StorageError>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.session_path(id)?).await {
+                Err(error) if error.kind() != ErrorKind::NotFound => {
+                    Err(Report::new(error).change_context(StorageError::QueryError))
+                }
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<String>, StorageError>> {
+        Box::pin(async move {
+            let mut entries = match tokio::fs::read_dir(&self.dir).await {
+                Ok(entries) => entries,
+                Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(error) => return Err(Report::new(error).change_context(StorageError::QueryError)),
+            };
+            let mut ids = Vec::new();
+            while let Some(entry) = entries.next_entry().await.change_context(StorageError::QueryError)? {
+                let path = entry.path();
+                if path.extension().is_some_and(|extension| extension == SESSION_EXTENSION)
+                    && let Some(id) = path.file_stem().and_then(|stem| stem.to_str())
+                {
+                    ids.push(id.to_string());
+                }
+            }
+            ids.sort();
+            Ok(ids)
+        })
+    }
+}
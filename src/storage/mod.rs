@@ -0,0 +1,66 @@
+use std::fmt::Debug;
+
+use error_stack::Result;
+use futures::future::BoxFuture;
+use thiserror::Error;
+
+use crate::chat::message::Session;
+use crate::storage::search::{scan_sessions, SessionHit};
+
+pub mod blob;
+pub mod file;
+pub mod search;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "redis")]
+pub mod redis;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("Failed to open database: {0}")]
+    OpenError(String),
+
+    #[error("Database query failed")]
+    QueryError,
+
+    #[error("Failed to (de)serialize stored data")]
+    SerializeError,
+
+    #[error("Database task failed")]
+    TaskError,
+
+    #[error("Invalid session id: {0}")]
+    InvalidId(String),
+
+    #[error("Failed to embed text for search")]
+    EmbeddingError,
+}
+
+/// 会话存储：文件、SQLite、Redis 等后端可互换，也可以接入自己的数据库
+/// Session store: file, SQLite, Redis and other backends are interchangeable, and custom databases can be plugged in
+pub trait SessionStore: Debug + Send + Sync {
+    /// 保存整个会话，覆盖同一 id 下已有的数据
+    /// Save a whole session, replacing whatever was stored under the same id
+    fn save<'a>(&'a self, id: &'a str, session: &'a Session) -> BoxFuture<'a, Result<(), StorageError>>;
+
+    /// 读取整个会话，不存在时返回 `None`
+    /// Load a whole session; `None` when it does not exist
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Session>, StorageError>>;
+
+    /// 删除会话，不存在时什么也不做
+    /// Delete a session; does nothing when it does not exist
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), StorageError>>;
+
+    /// 所有已保存会话的 id
+    /// Ids of every saved session
+    fn list(&self) -> BoxFuture<'_, Result<Vec<String>, StorageError>>;
+
+    /// 在所有会话的消息正文中查找 `query`（不区分大小写），最多返回 `limit` 条命中
+    /// Find `query` in the message content of every session (case-insensitive), returning at most `limit` hits
+    ///
+    /// 默认实现逐个读取会话并扫描，能在数据库里检索的后端应当覆盖它
+    /// The default implementation loads and scans every session; backends that can query their database should override it
+    fn search<'a>(&'a self, query: &'a str, limit: usize) -> BoxFuture<'a, Result<Vec<SessionHit>, StorageError>> {
+        Box::pin(scan_sessions(self, query, limit))
+    }
+}
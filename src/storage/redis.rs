@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use ::redis::aio::ConnectionManager;
+use ::redis::{AsyncCommands, Client, Script};
+use error_stack::{Report, Result, ResultExt};
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+
+use crate::chat::message::Session;
+use crate::chat::rate_limit::{RateLimit, RateLimitBackend, RateLimitError, RATE_LIMIT_WINDOW};
+use crate::storage::{SessionStore, StorageError};
+
+const DEFAULT_PREFIX: &str = "rhine";
+
+/// 原子地检查并登记一次请求，窗口按 Redis 服务器时间划分，各副本的时钟偏差不影响计数
+/// Atomically check and record one request; windows follow the Redis server clock so replica clock skew does not matter
+///
+/// 参数为窗口毫秒数、请求数限额、令牌数限额（-1 表示不限）与本次令牌数，返回需要等待的毫秒数
+/// Arguments are the window in millis, the request quota, the token quota (-1 for unlimited) and this request's
+/// tokens; returns the millis to wait
+static RESERVE_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+local now = redis.call('TIME')
+local now_ms = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+local window_ms = tonumber(ARGV[1])
+local rpm = tonumber(ARGV[2])
+local tpm = tonumber(ARGV[3])
+local tokens = tonumber(ARGV[4])
+local index = math.floor(now_ms / window_ms)
+local state = redis.call('HMGET', KEYS[1], 'window', 'requests', 'tokens')
+local requests, used = 0, 0
+if tonumber(state[1]) == index then
+    requests = tonumber(state[2]) or 0
+    used = tonumber(state[3]) or 0
+end
+if (rpm >= 0 and requests >= rpm) or (tpm >= 0 and used > 0 and used + tokens > tpm) then
+    return (index + 1) * window_ms - now_ms
+end
+redis.call('HSET', KEYS[1], 'window', index, 'requests', requests + 1, 'tokens', used + tokens)
+redis.call('PEXPIRE', KEYS[1], window_ms * 2)
+return 0
+",
+    )
+});
+
+/// 基于 Redis 的共享状态：会话以 JSON 保存，限额计数在所有服务副本间共享
+/// Redis-backed shared state: sessions are kept as JSON and quota counters are shared by every service replica
+///
+/// 同一前缀下的副本可以接着彼此的对话继续，也可以通过
+/// [`Config::set_rate_limit_backend`](crate::config::Config::set_rate_limit_backend) 协调同一来源的限额
+/// Replicas under the same prefix can resume each other's conversations and coordinate the quota of a source through
+/// [`Config::set_rate_limit_backend`](crate::config::Config::set_rate_limit_backend)
+#[derive(Clone)]
+pub struct RedisStore {
+    connection: ConnectionManager,
+
+    prefix: String,
+
+    session_ttl: Option<Duration>,
+}
+
+impl std::fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("prefix", &self.prefix)
+            .field("session_ttl", &self.session_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisStore {
+    /// 连接 Redis，例如 `redis://127.0.0.1:6379`
+    /// Connect to Redis, e.g. `redis://127.0.0.1:6379`
+    pub async fn connect(url: &str) -> Result<Self, StorageError> {
+        let client = Client::open(url).change_context_lazy(|| StorageError::OpenError(url.to_string()))?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .change_context_lazy(|| StorageError::OpenError(url.to_string()))?;
+        Ok(Self {
+            connection,
+            prefix: DEFAULT_PREFIX.to_string(),
+            session_ttl: None,
+        })
+    }
+
+    /// 所有键的前缀，默认为 `rhine`，同一 Redis 上的不同部署可用前缀隔开
+    /// Prefix of every key, `rhine` by default; deployments sharing one Redis can be separated by prefix
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// 会话在最后一次保存后的保留时间，默认永久保留
+    /// How long a session is kept after its last save; kept forever by default
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = Some(ttl);
+        self
+    }
+
+    fn session_key(&self, id: &str) -> String {
+        format!("{}:session:{id}", self.prefix)
+    }
+
+    fn index_key(&self) -> String {
+        format!("{}:sessions", self.prefix)
+    }
+
+    fn rate_limit_key(&self, key: &str) -> String {
+        format!("{}:rate:{key}", self.prefix)
+    }
+
+    /// 保存整个会话，覆盖同一 id 下已有的数据
+    /// Save a whole session, replacing whatever was stored under the same id
+    pub async fn save_session(&self, id: &str, session: &Session) -> Result<(), StorageError> {
+        let value = serde_json::to_string(session).change_context(StorageError::SerializeError)?;
+        let key = self.session_key(id);
+        let mut pipe = ::redis::pipe();
+        pipe.atomic().set(&key, value).ignore().sadd(self.index_key(), id).ignore();
+        if let Some(ttl) = self.session_ttl {
+            pipe.pexpire(&key, ttl.as_millis() as i64).ignore();
+        }
+        pipe.query_async::<()>(&mut self.connection.clone())
+            .await
+            .change_context(StorageError::QueryError)
+    }
+
+    /// 读取整个会话，不存在或已过期时返回 `None`
+    /// Load a whole session; `None` when it does not exist or has expired
+    pub async fn load_session(&self, id: &str) -> Result<Option<Session>, StorageError> {
+        let value: Option<String> = self
+            .connection
+            .clone()
+            .get(self.session_key(id))
+            .await
+            .change_context(StorageError::QueryError)?;
+        value
+            .map(|value| serde_json::from_str(&value).change_context(StorageError::SerializeError))
+            .transpose()
+    }
+
+    pub async fn delete_session(&self, id: &str) -> Result<(), StorageError> {
+        ::redis::pipe()
+            .atomic()
+            .del(self.session_key(id))
+            .ignore()
+            .srem(self.index_key(), id)
+            .ignore()
+            .query_async::<()>(&mut self.connection.clone())
+            .await
+            .change_context(StorageError::QueryError)
+    }
+
+    /// 按 id 排序列出保存过的会话，已过期的会话会顺带从索引中移除
+    /// List saved sessions sorted by id; expired sessions are dropped from the index along the way
+    pub async fn list_sessions(&self) -> Result<Vec<String>, StorageError> {
+        let mut connection = self.connection.clone();
+        let mut ids: Vec<String> = connection
+            .smembers(self.index_key())
+            .await
+            .change_context(StorageError::QueryError)?;
+        let mut live = Vec::with_capacity(ids.len());
+        for id in ids.drain(..) {
+            let exists: bool = connection
+                .exists(self.session_key(&id))
+                .await
+                .change_context(StorageError::QueryError)?;
+            if exists {
+                live.push(id);
+            } else {
+                let _: () = connection
+                    .srem(self.index_key(), &id)
+                    .await
+                    .change_context(StorageError::QueryError)?;
+            }
+        }
+        live.sort();
+        Ok(live)
+    }
+}
+
+impl RateLimitBackend for RedisStore {
+    fn reserve<'a>(&'a self, key: &'a str, limit: RateLimit, tokens: u64) -> BoxFuture<'a, Result<Duration, RateLimitError>> {
+        Box::pin(async move {
+            let unlimited = |quota: Option<u64>| quota.map_or(-1, |quota| quota as i64);
+            let wait_ms: u64 = RESERVE_SCRIPT
+                .key(self.rate_limit_key(key))
+                .arg(RATE_LIMIT_WINDOW.as_millis() as u64)
+                .arg(unlimited(limit.requests_per_minute))
+                .arg(unlimited(limit.tokens_per_minute))
+                .arg(tokens)
+                .invoke_async(&mut self.connection.clone())
+                .await
+                .map_err(|error| Report::new(RateLimitError::Backend(error.to_string())))?;
+            Ok(Duration::from_millis(wait_ms))
+        })
+    }
+}
+
+impl SessionStore for RedisStore {
+    fn save<'a>(&'a self, id: &'a str, session: &'a Session) -> BoxFuture<'a, Result<(), StorageError>> {
+        Box::pin(self.save_session(id, session))
+    }
+
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Session>, StorageError>> {
+        Box::pin(self.load_session(id))
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), StorageError>> {
+        Box::pin(self.delete_session(id))
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<String>, StorageError>> {
+        Box::pin(self.list_sessions())
+    }
+}
@@ -0,0 +1,166 @@
+use std::cmp::Ordering;
+
+use error_stack::{Result, ResultExt};
+
+use crate::chat::message::{Messages, Session};
+use crate::memory::{cosine_similarity, Embedder};
+use crate::storage::{SessionStore, StorageError};
+
+/// 片段在命中位置前后各保留的字符数
+/// Characters a snippet keeps on each side of the match
+const SNIPPET_CONTEXT_CHARS: usize = 40;
+
+/// 会话搜索的一条命中
+/// One hit of a session search
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionHit {
+    pub session_id: String,
+
+    /// 命中消息在会话中的路径
+    /// Path of the matching message in its session
+    pub path: Vec<usize>,
+
+    /// 命中位置附近的一段正文，被截断的一侧以 `…` 标出
+    /// Content around the match, with `…` marking a truncated side
+    pub snippet: String,
+}
+
+/// 截取 `content` 中 `query` 首次出现（不区分大小写）前后的片段；未出现时截取开头
+/// Cut the snippet around the first case-insensitive occurrence of `query` in `content`; the beginning when absent
+pub(crate) fn snippet(content: &str, query: &str) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    let start = find_ignore_case(&chars, query).unwrap_or(0);
+    let query_chars = query.chars().count();
+    let from = start.saturating_sub(SNIPPET_CONTEXT_CHARS);
+    let to = (start + query_chars + SNIPPET_CONTEXT_CHARS).min(chars.len());
+
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(&chars[from..to]);
+    if to < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// `query` 在 `chars` 中首次出现的字符位置，不区分大小写；空查询不匹配
+/// Char position of the first case-insensitive occurrence of `query` in `chars`; an empty query never matches
+fn find_ignore_case(chars: &[char], query: &str) -> Option<usize> {
+    let fold = |c: char| c.to_lowercase().next().unwrap_or(c);
+    let query: Vec<char> = query.chars().map(fold).collect();
+    if query.is_empty() || query.len() > chars.len() {
+        return None;
+    }
+    (0..=chars.len() - query.len())
+        .find(|&start| chars[start..start + query.len()].iter().zip(&query).all(|(&c, &q)| fold(c) == q))
+}
+
+/// 按先序列出会话中的全部消息及其路径
+/// List every message of a session with its path, in pre-order
+fn messages_with_paths(session: &Session) -> Vec<(Vec<usize>, &Messages)> {
+    let mut messages = Vec::new();
+    let mut stack: Vec<(Vec<usize>, &Messages)> = session
+        .message_roots
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(i, root)| (vec![i], root.as_ref()))
+        .collect();
+    while let Some((path, node)) = stack.pop() {
+        for (i, child) in node.child.iter().enumerate().rev() {
+            let mut child_path = path.clone();
+            child_path.push(i);
+            stack.push((child_path, child.as_ref()));
+        }
+        messages.push((path, node));
+    }
+    messages
+}
+
+/// 逐个读取会话并按子串匹配正文，是 [`SessionStore::search`] 的默认实现
+/// Load every session and match message content by substring; the default implementation of [`SessionStore::search`]
+///
+/// 与 SQLite 后端一致，结果按消息创建时间、会话 id 与路径排序
+/// Like the SQLite backend, hits are ordered by message creation time, session id and path
+pub(crate) async fn scan_sessions<S: SessionStore + ?Sized>(
+    store: &S,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<SessionHit>, StorageError> {
+    let mut hits = Vec::new();
+    for id in store.list().await? {
+        let Some(session) = store.load(&id).await? else {
+            continue;
+        };
+        for (path, node) in messages_with_paths(&session) {
+            let chars: Vec<char> = node.content.chars().collect();
+            if find_ignore_case(&chars, query).is_some() {
+                let hit = SessionHit {
+                    session_id: id.clone(),
+                    path,
+                    snippet: snippet(&node.content, query),
+                };
+                hits.push((node.created_ms, hit));
+            }
+        }
+    }
+    hits.sort_by(|(a_ms, a), (b_ms, b)| {
+        a_ms.cmp(b_ms)
+            .then_with(|| a.session_id.cmp(&b.session_id))
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    Ok(hits.into_iter().take(limit).map(|(_, hit)| hit).collect())
+}
+
+/// 按语义相似度搜索会话：向量化查询与每条非空消息，返回最相似的 `limit` 条命中及其相似度
+/// Search sessions by meaning: embed the query and every non-empty message and return the `limit` most similar hits
+/// with their similarity
+///
+/// 每次搜索都会向量化全部消息，适合会话不多的场景；量大时应把向量保存在专门的索引里
+/// Every search embeds all messages, which suits a modest number of sessions; larger volumes call for a dedicated
+/// vector index
+pub async fn semantic_search(
+    store: &dyn SessionStore,
+    embedder: &dyn Embedder,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<(SessionHit, f32)>, StorageError> {
+    let query_vector = embedder
+        .embed(&[query.to_string()])
+        .await
+        .change_context(StorageError::EmbeddingError)?
+        .pop()
+        .ok_or(StorageError::EmbeddingError)?;
+
+    let mut scored = Vec::new();
+    for id in store.list().await? {
+        let Some(session) = store.load(&id).await? else {
+            continue;
+        };
+        let messages: Vec<_> = messages_with_paths(&session)
+            .into_iter()
+            .filter(|(_, node)| !node.content.trim().is_empty())
+            .collect();
+        if messages.is_empty() {
+            continue;
+        }
+        let texts: Vec<String> = messages.iter().map(|(_, node)| node.content.clone()).collect();
+        let vectors = embedder
+            .embed(&texts)
+            .await
+            .change_context(StorageError::EmbeddingError)?;
+        for ((path, node), vector) in messages.into_iter().zip(vectors) {
+            let hit = SessionHit {
+                session_id: id.clone(),
+                path,
+                snippet: snippet(&node.content, query),
+            };
+            scored.push((hit, cosine_similarity(&query_vector, &vector)));
+        }
+    }
+    scored.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    scored.truncate(limit);
+    Ok(scored)
+}
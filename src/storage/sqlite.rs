@@ -0,0 +1,577 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use error_stack::{Report, Result, ResultExt};
+use futures::future::BoxFuture;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::chat::message::{Messages, Role, Session, SessionUsage, TITLE_METADATA_KEY};
+use crate::chat::pruning::now_ms;
+use crate::storage::search::{snippet, SessionHit};
+use crate::storage::{SessionStore, StorageError};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    title TEXT,
+    default_path TEXT NOT NULL,
+    metadata TEXT NOT NULL,
+    trees TEXT NOT NULL,
+    pruning TEXT,
+    updated_ms INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS messages (
+    session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    parent_path TEXT,
+    position INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    metadata TEXT NOT NULL,
+    private_to TEXT,
+    attachments TEXT NOT NULL,
+    created_ms INTEGER NOT NULL,
+    PRIMARY KEY (session_id, path)
+);
+CREATE INDEX IF NOT EXISTS messages_parent ON messages(session_id, parent_path, position);
+CREATE TABLE IF NOT EXISTS tool_calls (
+    session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    call_id TEXT,
+    name TEXT,
+    result TEXT NOT NULL,
+    PRIMARY KEY (session_id, path)
+);
+CREATE TABLE IF NOT EXISTS usage (
+    session_id TEXT PRIMARY KEY REFERENCES sessions(id) ON DELETE CASCADE,
+    requests INTEGER NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL
+);
+";
+
+const MESSAGE_COLUMNS: &str = "path, role, content, metadata, private_to, attachments, created_ms";
+
+/// 会话列表中的一项
+/// Entry of the session list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub id: String,
+
+    pub title: Option<String>,
+
+    pub message_count: usize,
+
+    pub usage: SessionUsage,
+
+    pub updated_ms: u64,
+}
+
+/// 内容搜索命中的消息
+/// Message matched by a content search
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageHit {
+    pub session_id: String,
+
+    pub path: Vec<usize>,
+
+    pub role: Role,
+
+    pub content: String,
+}
+
+/// 工具调用结果的记录
+/// Record of a tool call result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolCallRecord {
+    pub path: Vec<usize>,
+
+    /// `tool` 或 `function`
+    /// `tool` or `function`
+    pub kind: String,
+
+    pub call_id: Option<String>,
+
+    pub name: Option<String>,
+
+    pub result: String,
+}
+
+/// 基于 SQLite 的会话存储：会话、消息、工具调用与用量分表保存
+/// SQLite-backed session store keeping sessions, messages, tool calls and usage in separate tables
+///
+/// 数据库操作在阻塞线程池中执行，存储可以克隆并在任务间共享
+/// Database work runs on the blocking thread pool; the store can be cloned and shared between tasks
+#[derive(Clone, Debug)]
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let path = path.as_ref().to_path_buf();
+        let connection = tokio::task::spawn_blocking(move || {
+            Connection::open(&path).change_context_lazy(|| StorageError::OpenError(path.display().to_string()))
+        })
+        .await
+        .change_context(StorageError::TaskError)??;
+        Self::from_connection(connection).await
+    }
+
+    pub async fn open_in_memory() -> Result<Self, StorageError> {
+        let connection = Connection::open_in_memory()
+            .change_context_lazy(|| StorageError::OpenError(":memory:".to_string()))?;
+        Self::from_connection(connection).await
+    }
+
+    async fn from_connection(connection: Connection) -> Result<Self, StorageError> {
+        let store = Self {
+            connection: Arc::new(Mutex::new(connection)),
+        };
+        store
+            .with_connection(|connection| {
+                connection
+                    .execute_batch(&format!("PRAGMA foreign_keys = ON;{SCHEMA}"))
+                    .change_context(StorageError::QueryError)
+            })
+            .await?;
+        Ok(store)
+    }
+
+    async fn with_connection<R, F>(&self, operation: F) -> Result<R, StorageError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<R, StorageError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| Report::new(StorageError::TaskError))?;
+            operation(&mut connection)
+        })
+        .await
+        .change_context(StorageError::TaskError)?
+    }
+
+    /// 保存整个会话，覆盖同一 id 下已有的数据
+    /// Save a whole session, replacing whatever was stored under the same id
+    pub async fn save_session(&self, id: &str, session: &Session) -> Result<(), StorageError> {
+        let id = id.to_string();
+        let session = session.clone();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction().change_context(StorageError::QueryError)?;
+            transaction
+                .execute("DELETE FROM sessions WHERE id = ?1", params![id])
+                .change_context(StorageError::QueryError)?;
+            let title = session
+                .get_metadata(TITLE_METADATA_KEY)
+                .and_then(|title| title.as_str())
+                .map(str::to_string);
+            transaction
+                .execute(
+                    "INSERT INTO sessions (id, title, default_path, metadata, trees, pruning, updated_ms)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        id,
+                        title,
+                        to_json(&session.default_path)?,
+                        to_json(&session.metadata)?,
+                        to_json(&session.trees)?,
+                        session.pruning.as_ref().map(to_json).transpose()?,
+                        now_ms() as i64,
+                    ],
+                )
+                .change_context(StorageError::QueryError)?;
+            transaction
+                .execute(
+                    "INSERT INTO usage (session_id, requests, prompt_tokens, completion_tokens) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        id,
+                        session.usage.requests as i64,
+                        session.usage.prompt_tokens as i64,
+                        session.usage.completion_tokens as i64,
+                    ],
+                )
+                .change_context(StorageError::QueryError)?;
+
+            let mut stack: Vec<(Vec<usize>, &Messages)> = session
+                .message_roots
+                .iter()
+                .enumerate()
+                .map(|(i, root)| (vec![i], root.as_ref()))
+                .collect();
+            while let Some((path, node)) = stack.pop() {
+                insert_message(&transaction, &id, &path, node)?;
+                for (i, child) in node.child.iter().enumerate() {
+                    let mut child_path = path.clone();
+                    child_path.push(i);
+                    stack.push((child_path, child.as_ref()));
+                }
+            }
+            transaction.commit().change_context(StorageError::QueryError)
+        })
+        .await
+    }
+
+    /// 读取整个会话，不存在时返回 `None`
+    /// Load a whole session; `None` when it does not exist
+    pub async fn load_session(&self, id: &str) -> Result<Option<Session>, StorageError> {
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            let header = connection
+                .query_row(
+                    "SELECT s.default_path, s.metadata, s.trees, s.pruning,
+                            u.requests, u.prompt_tokens, u.completion_tokens
+                     FROM sessions s LEFT JOIN usage u ON u.session_id = s.id WHERE s.id = ?1",
+                    params![id],
+                    |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, Option<String>>(3)?,
+                            read_usage(row, 4)?,
+                        ))
+                    },
+                )
+                .optional()
+                .change_context(StorageError::QueryError)?;
+            let Some((default_path, metadata, trees, pruning, usage)) = header else {
+                return Ok(None);
+            };
+
+            let mut session = Session::new();
+            session.default_path = from_json(&default_path)?;
+            session.metadata = from_json(&metadata)?;
+            session.trees = from_json(&trees)?;
+            session.pruning = pruning.as_deref().map(from_json).transpose()?;
+            session.usage = usage;
+
+            let mut nodes = query_messages(
+                connection,
+                &format!("SELECT {MESSAGE_COLUMNS} FROM messages WHERE session_id = ?1"),
+                params![id],
+            )?;
+            // 按路径的字典序插入，父节点总在子节点之前，兄弟节点按位置先后
+            // Insert in lexicographic path order so parents precede children and siblings keep their order
+            nodes.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (path, node) in nodes {
+                let (&last, parent) = path.split_last().ok_or(StorageError::SerializeError)?;
+                if parent.is_empty() {
+                    if session.message_roots.len() != last {
+                        return Err(Report::new(StorageError::SerializeError));
+                    }
+                    session.message_roots.push(Arc::new(node));
+                    continue;
+                }
+                let siblings = &mut session
+                    .get_node_by_path(parent)
+                    .change_context(StorageError::SerializeError)?
+                    .child;
+                if siblings.len() != last {
+                    return Err(Report::new(StorageError::SerializeError));
+                }
+                siblings.push(Arc::new(node));
+            }
+            Ok(Some(session))
+        })
+        .await
+    }
+
+    pub async fn delete_session(&self, id: &str) -> Result<(), StorageError> {
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .execute("DELETE FROM sessions WHERE id = ?1", params![id])
+                .change_context(StorageError::QueryError)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// 按最近更新时间倒序列出所有会话
+    /// List every session, most recently updated first
+    pub async fn list_sessions(&self) -> Result<Vec<SessionSummary>, StorageError> {
+        self.with_connection(|connection| {
+            let mut statement = connection
+                .prepare(
+                    "SELECT s.id, s.title, s.updated_ms,
+                            u.requests, u.prompt_tokens, u.completion_tokens,
+                            (SELECT COUNT(*) FROM messages m WHERE m.session_id = s.id)
+                     FROM sessions s LEFT JOIN usage u ON u.session_id = s.id
+                     ORDER BY s.updated_ms DESC, s.id",
+                )
+                .change_context(StorageError::QueryError)?;
+            statement
+                .query_map([], |row| {
+                    Ok(SessionSummary {
+                        id: row.get(0)?,
+                        title: row.get(1)?,
+                        updated_ms: row.get::<_, i64>(2)? as u64,
+                        usage: read_usage(row, 3)?,
+                        message_count: row.get::<_, i64>(6)? as usize,
+                    })
+                })
+                .change_context(StorageError::QueryError)?
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .change_context(StorageError::QueryError)
+        })
+        .await
+    }
+
+    /// 在所有会话的消息正文中搜索子串（不区分 ASCII 大小写），最多返回 `limit` 条
+    /// Search every session's message content for a substring (ASCII case-insensitive), returning at most `limit` hits
+    pub async fn search(&self, query: &str, limit: usize) -> Result<Vec<MessageHit>, StorageError> {
+        let pattern = format!("%{}%", escape_like(query));
+        self.with_connection(move |connection| {
+            let mut statement = connection
+                .prepare(
+                    "SELECT session_id, path, role, content FROM messages
+                     WHERE content LIKE ?1 ESCAPE '\\'
+                     ORDER BY created_ms, session_id, path LIMIT ?2",
+                )
+                .change_context(StorageError::QueryError)?;
+            let rows = statement
+                .query_map(params![pattern, limit as i64], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                })
+                .change_context(StorageError::QueryError)?
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .change_context(StorageError::QueryError)?;
+            rows.into_iter()
+                .map(|(session_id, path, role, content)| {
+                    Ok(MessageHit {
+                        session_id,
+                        path: parse_path(&path)?,
+                        role: from_json(&role)?,
+                        content,
+                    })
+                })
+                .collect()
+        })
+        .await
+    }
+
+    /// 只读取一条路径上的消息，不加载其余分支；返回的节点不含子节点
+    /// Load only the messages along one path without touching other branches; returned nodes have no children
+    pub async fn load_branch(&self, id: &str, path: &[usize]) -> Result<Vec<Messages>, StorageError> {
+        let id = id.to_string();
+        let prefixes: Vec<String> = (1..=path.len()).map(|len| format_path(&path[..len])).collect();
+        self.with_connection(move |connection| {
+            let mut branch = Vec::with_capacity(prefixes.len());
+            for prefix in prefixes {
+                let mut nodes = query_messages(
+                    connection,
+                    &format!("SELECT {MESSAGE_COLUMNS} FROM messages WHERE session_id = ?1 AND path = ?2"),
+                    params![id, prefix],
+                )?;
+                let (_, node) = nodes.pop().ok_or(StorageError::QueryError)?;
+                branch.push(node);
+            }
+            Ok(branch)
+        })
+        .await
+    }
+
+    /// 读取某个节点的直接子节点（空路径为各个根），用于逐层展开消息树
+    /// Load the direct children of a node (the roots for an empty path), for expanding the tree level by level
+    pub async fn load_children(&self, id: &str, path: &[usize]) -> Result<Vec<Messages>, StorageError> {
+        let id = id.to_string();
+        let parent = (!path.is_empty()).then(|| format_path(path));
+        self.with_connection(move |connection| {
+            let nodes = query_messages(
+                connection,
+                &format!(
+                    "SELECT {MESSAGE_COLUMNS} FROM messages
+                     WHERE session_id = ?1 AND parent_path IS ?2 ORDER BY position"
+                ),
+                params![id, parent],
+            )?;
+            Ok(nodes.into_iter().map(|(_, node)| node).collect())
+        })
+        .await
+    }
+
+    /// 会话中保存的工具调用结果，按路径排序
+    /// Tool call results stored for a session, ordered by path
+    pub async fn tool_calls(&self, id: &str) -> Result<Vec<ToolCallRecord>, StorageError> {
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            let mut statement = connection
+                .prepare("SELECT path, kind, call_id, name, result FROM tool_calls WHERE session_id = ?1")
+                .change_context(StorageError::QueryError)?;
+            let rows = statement
+                .query_map(params![id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+                })
+                .change_context(StorageError::QueryError)?
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .change_context(StorageError::QueryError)?;
+            let mut records = rows
+                .into_iter()
+                .map(|(path, kind, call_id, name, result)| {
+                    Ok(ToolCallRecord {
+                        path: parse_path(&path)?,
+                        kind,
+                        call_id,
+                        name,
+                        result,
+                    })
+                })
+                .collect::<Result<Vec<_>, StorageError>>()?;
+            records.sort_by(|a, b| a.path.cmp(&b.path));
+            Ok(records)
+        })
+        .await
+    }
+}
+
+fn insert_message(
+    transaction: &rusqlite::Transaction,
+    id: &str,
+    path: &[usize],
+    node: &Messages,
+) -> Result<(), StorageError> {
+    let (&position, parent) = path.split_last().ok_or(StorageError::SerializeError)?;
+    let path_key = format_path(path);
+    transaction
+        .execute(
+            "INSERT INTO messages
+             (session_id, path, parent_path, position, role, content, metadata, private_to, attachments, created_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                id,
+                path_key,
+                (!parent.is_empty()).then(|| format_path(parent)),
+                position as i64,
+                to_json(&node.role)?,
+                node.content,
+                to_json(&node.metadata)?,
+                node.private_to,
+                to_json(&node.attachments)?,
+                node.created_ms as i64,
+            ],
+        )
+        .change_context(StorageError::QueryError)?;
+
+    let (kind, call_id, name) = match &node.role {
+        Role::Tool { call_id } => ("tool", Some(call_id.as_str()), None),
+        Role::Function { name } => ("function", None, Some(name.as_str())),
+        _ => return Ok(()),
+    };
+    transaction
+        .execute(
+            "INSERT INTO tool_calls (session_id, path, kind, call_id, name, result) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![id, path_key, kind, call_id, name, node.content],
+        )
+        .change_context(StorageError::QueryError)?;
+    Ok(())
+}
+
+fn query_messages(
+    connection: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<(Vec<usize>, Messages)>, StorageError> {
+    let mut statement = connection.prepare(sql).change_context(StorageError::QueryError)?;
+    let rows = statement
+        .query_map(params, |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, i64>(6)?,
+            ))
+        })
+        .change_context(StorageError::QueryError)?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .change_context(StorageError::QueryError)?;
+
+    rows.into_iter()
+        .map(|(path, role, content, metadata, private_to, attachments, created_ms)| {
+            let mut node = Messages::new(from_json(&role)?, content);
+            node.metadata = from_json(&metadata)?;
+            node.private_to = private_to;
+            node.attachments = from_json(&attachments)?;
+            node.created_ms = created_ms as u64;
+            Ok((parse_path(&path)?, node))
+        })
+        .collect()
+}
+
+fn read_usage(row: &Row, start: usize) -> rusqlite::Result<SessionUsage> {
+    Ok(SessionUsage {
+        requests: row.get::<_, Option<i64>>(start)?.unwrap_or_default() as u64,
+        prompt_tokens: row.get::<_, Option<i64>>(start + 1)?.unwrap_or_default() as u64,
+        completion_tokens: row.get::<_, Option<i64>>(start + 2)?.unwrap_or_default() as u64,
+    })
+}
+
+fn format_path(path: &[usize]) -> String {
+    path.iter().map(usize::to_string).collect::<Vec<_>>().join(".")
+}
+
+fn parse_path(path: &str) -> Result<Vec<usize>, StorageError> {
+    path.split('.')
+        .map(|index| index.parse::<usize>().change_context(StorageError::SerializeError))
+        .collect()
+}
+
+fn escape_like(query: &str) -> String {
+    query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String, StorageError> {
+    serde_json::to_string(value).change_context(StorageError::SerializeError)
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(text: &str) -> Result<T, StorageError> {
+    serde_json::from_str(text).change_context(StorageError::SerializeError)
+}
+
+impl SessionStore for SqliteStore {
+    fn save<'a>(&'a self, id: &'a str, session: &'a Session) -> BoxFuture<'a, Result<(), StorageError>> {
+        Box::pin(self.save_session(id, session))
+    }
+
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Session>, StorageError>> {
+        Box::pin(self.load_session(id))
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), StorageError>> {
+        Box::pin(self.delete_session(id))
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<String>, StorageError>> {
+        Box::pin(async move {
+            let sessions = self.list_sessions().await?;
+            let mut ids: Vec<String> = sessions.into_iter().map(|summary| summary.id).collect();
+            ids.sort();
+            Ok(ids)
+        })
+    }
+
+    fn search<'a>(&'a self, query: &'a str, limit: usize) -> BoxFuture<'a, Result<Vec<SessionHit>, StorageError>> {
+        Box::pin(async move {
+            let hits = SqliteStore::search(self, query, limit).await?;
+            Ok(hits
+                .into_iter()
+                .map(|hit| SessionHit {
+                    snippet: snippet(&hit.content, query),
+                    session_id: hit.session_id,
+                    path: hit.path,
+                })
+                .collect())
+        })
+    }
+}
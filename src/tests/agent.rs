@@ -0,0 +1,514 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::agent::planner::{PlannerAgent, TaskStatus};
+use crate::agent::react::ReActAgent;
+use crate::chat::ask_user::{user_channel, UserQuestion};
+use crate::chat::chat_base::ChatError;
+use crate::chat::chat_single::SingleChat;
+use crate::chat::mock::{MockApi, MockReply};
+use crate::chat::rate_limit::RateLimit;
+use crate::config::{Config, ConfigError, ModelCapability};
+use crate::runtime::{Runtime, TenantError};
+use crate::schema::coerce::coerce;
+use crate::schema::tool_schema::{
+    check_tool_arguments, create_tool, execute_tool_call, get_tool_function, get_tool_registry, list_tools,
+    register_namespaced_tool, register_tool, resolve_tool_name, unregister_tool, ToolCall, ToolError,
+    ToolErrorKind, ToolPermissions, ToolRegistryError,
+};
+use crate::tests::format_test_block;
+
+pub async fn test_agent() {
+    test_react_agent().await;
+    test_planner_agent().await;
+    test_invalid_tool_arguments().await;
+    test_argument_coercion();
+    test_runtime_isolation().await;
+    test_runtime_tool_calls().await;
+    test_tenant_runtimes();
+    test_tool_registry().await;
+    test_ask_user().await;
+}
+
+async fn test_react_agent() {
+    let (name, tool) = create_tool("add", |args| {
+        Ok(json!(args["a"].as_i64().unwrap_or(0) + args["b"].as_i64().unwrap_or(0)))
+    });
+    get_tool_registry().insert(name, tool);
+
+    Config::add_mock("mock-react", |body| {
+        let last = body["messages"].as_array().and_then(|m| m.last()).cloned();
+        let last = last.map(|m| m["content"].to_string()).unwrap_or_default();
+        if last.contains("Observation: 5") {
+            MockReply::from("Thought: 工具已经给出结果\nFinal Answer: 5")
+        } else {
+            MockReply::from("Thought: 需要计算\nAction: add\nAction Input: {\"a\": 2, \"b\": 3}")
+        }
+    });
+
+    let add_schema = json!({
+        "type": "function",
+        "function": {
+            "name": "add",
+            "description": "两数相加",
+            "parameters": {"type": "object", "properties": {"a": {"type": "integer"}, "b": {"type": "integer"}}},
+        }
+    });
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_steps = seen.clone();
+    let mut agent = ReActAgent::new(SingleChat::new_with_api_name("mock-react", "", false), vec![add_schema])
+        .with_max_steps(4)
+        .on_step(move |step| seen_steps.lock().unwrap().push(step.index));
+
+    let (answer, steps) = agent.run("2加3等于几?").await.unwrap();
+    assert_eq!(answer, "5");
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[0].observation.as_deref(), Some("5"));
+    assert_eq!(*seen.lock().unwrap(), vec![0, 1]);
+
+    format_test_block("react_agent", || format!("answer: {}\nsteps: {:?}", answer, steps));
+}
+
+async fn test_planner_agent() {
+    let planner_api = Config::add_mock("mock-planner", |body| {
+        if body.get("response_format").is_some() {
+            MockReply::from(json!({"tasks": ["计算2加3", "检查结果"]}).to_string())
+        } else {
+            MockReply::from("最终答案是5")
+        }
+    });
+
+    let executor = ReActAgent::new(SingleChat::new_with_api_name("mock-react", "", false), vec![]);
+    let mut agent = PlannerAgent::new(SingleChat::new_with_api_name("mock-planner", "", false), executor);
+
+    let (answer, state) = agent.run("2加3等于几?").await.unwrap();
+    assert_eq!(answer, "最终答案是5");
+    assert_eq!(state.tasks.len(), 2);
+    assert!(state.tasks.iter().all(|task| task.status == TaskStatus::Done));
+    assert_eq!(planner_api.calls(), 2);
+
+    // 计划状态保存在会话中，同一目标再次运行不会重复请求
+    // The plan state lives in the session, so rerunning the same goal makes no new requests
+    let (again, _) = agent.run("2加3等于几?").await.unwrap();
+    assert_eq!(again, answer);
+    assert_eq!(planner_api.calls(), 2);
+
+    format_test_block("planner_agent", || format!("answer: {}\nstate: {:?}", answer, state));
+}
+
+async fn test_invalid_tool_arguments() {
+    let schema = json!({
+        "type": "function",
+        "function": {
+            "name": "add",
+            "description": "两数相加",
+            "parameters": {
+                "type": "object",
+                "properties": {"a": {"type": "integer"}, "b": {"type": "integer"}},
+                "required": ["a", "b"],
+                "additionalProperties": false,
+            },
+        }
+    });
+    let tools = vec![schema];
+    let error = check_tool_arguments(&tools, "add", &json!({"a": "two"})).unwrap();
+    assert_eq!(error.kind, ToolErrorKind::InvalidArguments);
+    assert_eq!(error.violations.len(), 2);
+    assert_eq!(error.expected_schema.as_ref(), Some(&tools[0]["function"]["parameters"]));
+    assert_eq!(error.to_value()["error"]["type"], "invalid_arguments");
+    assert!(check_tool_arguments(&tools, "add", &json!({"a": 2, "b": 3})).is_none());
+
+    Config::add_mock("mock-react-invalid", |body| {
+        let last = body["messages"].as_array().and_then(|m| m.last()).cloned();
+        let last = last.map(|m| m["content"].to_string()).unwrap_or_default();
+        if last.contains("invalid_arguments") {
+            MockReply::from("Thought: 参数有误，放弃调用\nFinal Answer: 参数错误")
+        } else {
+            MockReply::from("Thought: 需要计算\nAction: add\nAction Input: {\"a\": \"two\", \"b\": 3}")
+        }
+    });
+    let mut agent = ReActAgent::new(SingleChat::new_with_api_name("mock-react-invalid", "", false), tools);
+    let (answer, steps) = agent.run("2加3等于几?").await.unwrap();
+    assert_eq!(answer, "参数错误");
+    assert!(steps[0].observation.as_deref().unwrap().contains("/a"));
+
+    format_test_block("invalid_tool_arguments", || format!("error: {}", error));
+}
+
+fn test_argument_coercion() {
+    let schema = json!({
+        "type": "function",
+        "function": {
+            "name": "coerce_search",
+            "description": "搜索",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "limit": {"type": "integer", "minimum": 1},
+                    "score": {"type": "number"},
+                    "exact": {"type": "boolean"},
+                    "order": {"type": "string", "enum": ["asc", "desc"]},
+                    "tags": {"type": "array", "items": {"type": "integer"}},
+                    "filter": {
+                        "type": "object",
+                        "properties": {"archived": {"type": "boolean"}},
+                    },
+                },
+                "required": ["limit", "order"],
+                "additionalProperties": false,
+            },
+        }
+    });
+
+    let mut arguments = json!({
+        "limit": " 10 ",
+        "score": "0.5",
+        "exact": "Yes",
+        "order": " DESC",
+        "tags": ["1", 2.0],
+        "filter": "{\"archived\": \"false\"}",
+    });
+    let mut changed = coerce(&schema, &mut arguments);
+    changed.sort();
+    assert_eq!(
+        arguments,
+        json!({
+            "limit": 10,
+            "score": 0.5,
+            "exact": true,
+            "order": "desc",
+            "tags": [1, 2],
+            "filter": {"archived": false},
+        })
+    );
+    assert_eq!(
+        changed,
+        ["/exact", "/filter", "/filter/archived", "/limit", "/order", "/score", "/tags/0", "/tags/1"]
+    );
+
+    // 已符合模式的参数不改动，无法无歧义转换的值留给校验报错
+    // Arguments already matching the schema are untouched; values without an unambiguous conversion are left to validation
+    let mut valid = json!({"limit": 3, "order": "asc"});
+    assert!(coerce(&schema, &mut valid).is_empty());
+    let mut invalid = json!({"limit": "ten", "order": "random", "exact": "maybe"});
+    assert!(coerce(&schema, &mut invalid).is_empty());
+    assert_eq!(invalid, json!({"limit": "ten", "order": "random", "exact": "maybe"}));
+
+    let tools = vec![register_tool("coerce_search", schema, Ok).unwrap()];
+    let call = ToolCall::new("coerce_search", json!({"limit": "5", "order": "Asc"}));
+    let result = execute_tool_call(&call, &tools);
+    assert!(!result.is_error, "{}", result.output);
+    let output: serde_json::Value = serde_json::from_str(&result.output).unwrap();
+    assert_eq!(output, json!({"limit": 5, "order": "asc"}));
+
+    let call = ToolCall::new("coerce_search", json!({"limit": "0", "order": "asc"}));
+    let rejected = execute_tool_call(&call, &tools);
+    assert!(rejected.is_error);
+    assert!(rejected.output.contains("/limit"));
+    unregister_tool("coerce_search");
+
+    format_test_block("argument_coercion", || format!("arguments: {}\nrejected: {}", arguments, rejected.output));
+}
+
+async fn test_runtime_isolation() {
+    let runtime = Arc::new(Runtime::new());
+    runtime.add_mock("mock-runtime", |body| {
+        let last = body["messages"].as_array().and_then(|m| m.last()).cloned();
+        let last = last.map(|m| m["content"].to_string()).unwrap_or_default();
+        if last.contains("Observation: 6") {
+            MockReply::from("Thought: 工具已经给出结果\nFinal Answer: 6")
+        } else {
+            MockReply::from("Thought: 需要计算\nAction: runtime_mul\nAction Input: {\"a\": 2, \"b\": 3}")
+        }
+    });
+    let schema = runtime
+        .tools
+        .register(
+            "runtime_mul",
+            json!({
+                "type": "function",
+                "function": {
+                    "name": "runtime_mul",
+                    "description": "两数相乘",
+                    "parameters": {"type": "object", "properties": {"a": {"type": "integer"}, "b": {"type": "integer"}}},
+                }
+            }),
+            |args| Ok(json!(args["a"].as_i64().unwrap_or(0) * args["b"].as_i64().unwrap_or(0))),
+        )
+        .unwrap();
+
+    // 运行时中的API与工具对全局配置不可见
+    // APIs and tools of the runtime are invisible to the global configuration
+    assert!(Config::get_api_info_with_name("mock-runtime".to_string()).is_err());
+    assert!(get_tool_function("runtime_mul").is_none());
+    assert!(Runtime::new().get_api_info_with_name("mock-runtime").is_err());
+
+    let chat = SingleChat::new_with_runtime(runtime.clone(), "mock-runtime", "", false).unwrap();
+    let mut agent = ReActAgent::new(chat, vec![schema]).with_max_steps(4);
+    let (answer, steps) = agent.run("2乘3等于几?").await.unwrap();
+    assert_eq!(answer, "6");
+    assert_eq!(steps[0].observation.as_deref(), Some("6"));
+    assert!(Arc::ptr_eq(&agent.chat.base.runtime, &runtime));
+
+    // 同一基础 URL 的模拟应答、优先级调度与暂停只在各自的运行时中生效
+    // Mock replies, priority scheduling and pauses on the same base URL only apply within their own runtime
+    let other = Arc::new(Runtime::new());
+    other.add_mock("mock-runtime", |_| MockReply::from("另一个运行时"));
+    other.enable_priority_scheduling("mock-runtime").unwrap();
+    other.pause_source("mock://mock-runtime", Duration::from_secs(60));
+    assert!(runtime.scheduler("mock://mock-runtime").is_none());
+    assert!(runtime.source_paused_until("mock://mock-runtime").is_none());
+    let mut chat = SingleChat::new_with_runtime(runtime.clone(), "mock-runtime", "", false).unwrap();
+    let resp = chat.get_req_body("2乘3等于几?").await.unwrap();
+    let reply = chat.get_content_from_req_body(resp).await.unwrap();
+    assert!(reply.contains("runtime_mul"));
+
+    // 运行时中没有该来源时返回错误而不是 panic
+    // A runtime without the source returns an error instead of panicking
+    let mut orphan = SingleChat::new_with_runtime(runtime.clone(), "mock-runtime", "", false).unwrap();
+    orphan.base.set_runtime(Arc::new(Runtime::new()));
+    let resp = orphan.get_req_body("2乘3等于几?").await.unwrap();
+    let error = orphan.get_content_from_req_body(resp).await.unwrap_err();
+    assert!(matches!(error.current_context(), ChatError::ApiSourceNotFound(url) if url == "mock://mock-runtime"));
+    let missing = SingleChat::new_with_runtime(Arc::new(Runtime::new()), "mock-runtime", "", false).unwrap_err();
+    assert!(matches!(missing.current_context(), ChatError::ApiNotFound(name) if name == "mock-runtime"));
+
+    format_test_block("runtime_isolation", || format!("answer: {}\nsteps: {:?}", answer, steps));
+}
+
+async fn test_runtime_tool_calls() {
+    let add_schema = json!({
+        "type": "function",
+        "function": {
+            "name": "runtime_add",
+            "description": "两数相加",
+            "parameters": {"type": "object", "properties": {"a": {"type": "integer"}, "b": {"type": "integer"}}},
+        }
+    });
+    let parse_requests = Arc::new(Mutex::new(0));
+    let seen = parse_requests.clone();
+    let runtime = Arc::new(Runtime::new());
+    runtime.add_mock("mock-runtime-tools", move |body| {
+        if body.get("tools").is_none() {
+            return MockReply::from("<ToolUse>runtime_add a=2 b=3</ToolUse>");
+        }
+        *seen.lock().unwrap() += 1;
+        MockReply::Raw(json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{"function": {"name": "runtime_add", "arguments": "{\"a\": 2, \"b\": 3}"}}],
+                },
+                "finish_reason": "tool_calls",
+            }]
+        }))
+    });
+    runtime
+        .tools
+        .register("runtime_add", add_schema.clone(), |args| {
+            Ok(json!(args["a"].as_i64().unwrap_or(0) + args["b"].as_i64().unwrap_or(0)))
+        })
+        .unwrap();
+
+    // 解析工具调用的辅助请求发往对话自己的运行时，而不是全局配置
+    // The auxiliary request parsing the tool call goes to the chat's own runtime instead of the global configuration
+    let mut chat = SingleChat::new_with_runtime(runtime.clone(), "mock-runtime-tools", "", false).unwrap();
+    chat.set_tools(vec![add_schema.clone()]).unwrap();
+    let (_, results) = chat.get_tool_answer("2加3等于几?").await.unwrap();
+    assert_eq!(*parse_requests.lock().unwrap(), 1);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0.name, "runtime_add");
+    assert_eq!(results[0].1.output, "5");
+    assert!(!results[0].1.is_error);
+
+    // 运行时中没有工具使用能力的模型时，该调用记为解析错误而不是 panic
+    // Without a tool-use model in the runtime the call is recorded as a parse error instead of panicking
+    let bare = Arc::new(Runtime::new());
+    bare.add_mock_api(
+        "mock-runtime-bare",
+        ModelCapability::Think,
+        MockApi::new(|_| MockReply::from("<ToolUse>runtime_add a=2 b=3</ToolUse>")),
+    );
+    let mut chat = SingleChat::new_with_runtime(bare, "mock-runtime-bare", "", false).unwrap();
+    chat.set_tools(vec![add_schema]).unwrap();
+    let (_, results) = chat.get_tool_answer("2加3等于几?").await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].1.is_error);
+    assert!(results[0].1.output.contains("Failed to parse function call"));
+
+    format_test_block("runtime_tool_calls", || format!("{:?}", results[0].1));
+}
+
+fn test_tenant_runtimes() {
+    let echo = |name: &str| {
+        json!({
+            "type": "function",
+            "function": {"name": name, "parameters": {"type": "object", "properties": {"text": {"type": "string"}}}},
+        })
+    };
+    let tools = vec![
+        register_tool("tenant_global", echo("tenant_global"), |args| Ok(args["text"].clone())).unwrap(),
+        register_tool("tenant_secret", echo("tenant_secret"), |args| Ok(args["text"].clone())).unwrap(),
+    ];
+
+    let acme = Runtime::new().with_tool_permissions(
+        ToolPermissions::new()
+            .with_allowed(&["tenant_global", "tenant_secret"])
+            .with_denied(&["tenant_secret"])
+            .with_inherit_global(true),
+    );
+    acme.add_api_source("tenant-source", "https://tenant.example/v1", 2);
+    acme.add_api_info("tenant-api", "tenant-model", ModelCapability::ToolUse, "tenant-source", "sk-acme")
+        .unwrap();
+    let acme = Runtime::create_tenant("acme", acme).unwrap();
+    let globex = Runtime::new();
+    globex.add_api_source("tenant-source", "https://tenant.example/v1", 2);
+    globex
+        .add_api_info("tenant-api", "tenant-model", ModelCapability::ToolUse, "tenant-source", "sk-globex")
+        .unwrap();
+    let globex = Runtime::create_tenant("globex", globex).unwrap();
+
+    let error = Runtime::create_tenant("acme", Runtime::new()).map(|_| ()).unwrap_err();
+    assert!(matches!(error.current_context(), TenantError::Exists(id) if id == "acme"));
+    assert!(Arc::ptr_eq(&Runtime::get_tenant("acme").unwrap(), &acme));
+    assert_eq!(acme.tenant(), Some("acme"));
+    let tenants = Runtime::list_tenants();
+    assert!(tenants.contains(&"acme".to_string()) && tenants.contains(&"globex".to_string()));
+
+    // 同名API在各租户中使用各自的密钥
+    // Equally named APIs use each tenant's own key
+    assert_eq!(acme.get_api_info_with_name("tenant-api").unwrap().api_key, "sk-acme");
+    assert_eq!(globex.get_api_info_with_name("tenant-api").unwrap().api_key, "sk-globex");
+
+    let limit = RateLimit::new().with_requests_per_minute(10);
+    acme.set_rate_limit("tenant-source", limit).unwrap();
+    assert_eq!(acme.get_rate_limit("https://tenant.example/v1"), Some(limit));
+    assert_eq!(globex.get_rate_limit("https://tenant.example/v1"), None);
+    let error = acme.set_rate_limit("missing-source", limit).unwrap_err();
+    assert!(matches!(error.current_context(), ConfigError::ApiSourceNotFound(_)));
+
+    let call = |name: &str| ToolCall::new(name, json!({"text": "hi"}));
+    let allowed = acme.execute_tool(&call("tenant_global"), &tools);
+    assert!(!allowed.is_error, "{}", allowed.output);
+    let denied = acme.execute_tool(&call("tenant_secret"), &tools);
+    assert!(denied.is_error);
+    let error = ToolError::from_output(&denied.output).unwrap();
+    assert_eq!(error.kind, ToolErrorKind::PermissionDenied);
+    let isolated = globex.execute_tool(&call("tenant_global"), &tools);
+    assert_eq!(ToolError::from_output(&isolated.output).unwrap().kind, ToolErrorKind::UnknownTool);
+
+    Runtime::remove_tenant("acme").unwrap();
+    Runtime::remove_tenant("globex").unwrap();
+    let error = Runtime::get_tenant("acme").map(|_| ()).unwrap_err();
+    assert!(matches!(error.current_context(), TenantError::NotFound(id) if id == "acme"));
+    unregister_tool("tenant_global");
+    unregister_tool("tenant_secret");
+
+    // 工具环境按运行时隔离
+    // Tool environments are scoped to their runtime
+    acme.add_env("tenant-env");
+    assert!(acme.envs.contains_key("tenant-env"));
+    assert!(!globex.envs.contains_key("tenant-env"));
+    assert!(!Runtime::global().envs.contains_key("tenant-env"));
+    assert!(acme.remove_env("tenant-env").is_some());
+    assert!(acme.remove_env("tenant-env").is_none());
+
+    format_test_block("tenant_runtimes", || format!("allowed: {allowed:?}\ndenied: {denied:?}"));
+}
+
+async fn test_tool_registry() {
+    let schema = |name: &str| {
+        json!({
+            "type": "function",
+            "function": {
+                "name": name,
+                "description": "原样返回输入",
+                "parameters": {"type": "object", "properties": {"text": {"type": "string"}}},
+            },
+        })
+    };
+
+    register_tool("echo_registry", schema("echo_registry"), |args| Ok(args["text"].clone())).unwrap();
+    let error = register_tool("echo_registry", schema("echo_registry"), |_| Ok(json!(null))).unwrap_err();
+    assert!(matches!(error.current_context(), ToolRegistryError::Collision(name) if name == "echo_registry"));
+    let echoed = get_tool_function("echo_registry").unwrap()(json!({"text": "hi"})).unwrap();
+    assert_eq!(echoed, "hi");
+
+    let alpha = register_namespaced_tool("crate::alpha", "lookup", schema("lookup"), |_| Ok(json!("alpha"))).unwrap();
+    assert_eq!(alpha["function"]["name"], "crate::alpha::lookup");
+    assert_eq!(resolve_tool_name("lookup").unwrap().as_deref(), Some("crate::alpha::lookup"));
+    assert_eq!(get_tool_function("lookup").unwrap()(json!({})).unwrap(), "alpha");
+
+    register_namespaced_tool("crate::beta", "lookup", schema("lookup"), |_| Ok(json!("beta"))).unwrap();
+    let error = resolve_tool_name("lookup").unwrap_err();
+    assert!(matches!(error.current_context(), ToolRegistryError::Ambiguous(_)));
+    assert!(get_tool_function("lookup").is_none());
+    assert_eq!(get_tool_function("crate::beta::lookup").unwrap()(json!({})).unwrap(), "beta");
+
+    let tools = list_tools();
+    let listed = tools.iter().find(|tool| tool.name == "crate::alpha::lookup").unwrap();
+    assert_eq!(listed.schema.as_ref(), Some(&alpha));
+    assert!(tools.windows(2).all(|pair| pair[0].name <= pair[1].name));
+
+    assert!(unregister_tool("crate::beta::lookup"));
+    assert!(!unregister_tool("crate::beta::lookup"));
+    assert_eq!(resolve_tool_name("lookup").unwrap().as_deref(), Some("crate::alpha::lookup"));
+
+    format_test_block("tool_registry", || {
+        tools.iter().map(|tool| tool.name.clone()).collect::<Vec<_>>().join("\n")
+    });
+}
+
+async fn test_ask_user() {
+    Config::add_mock("mock-ask-user", |body| {
+        let last = body["messages"].as_array().and_then(|m| m.last()).cloned();
+        let last = last.map(|m| m["content"].to_string()).unwrap_or_default();
+        if last.contains("Observation: 蓝色") {
+            MockReply::from("Thought: 用户喜欢蓝色\nFinal Answer: 选蓝色的主题")
+        } else if last.contains("Observation: 用户没有回答") {
+            MockReply::from("Thought: 无法确定\nFinal Answer: 使用默认主题")
+        } else {
+            MockReply::from(
+                "Thought: 不知道用户喜欢什么颜色\nAction: ask_user\nAction Input: {\"question\": \"你喜欢什么颜色?\", \"options\": [\"红色\", \"蓝色\"]}",
+            )
+        }
+    });
+
+    let (responder, mut questions) = user_channel();
+    let host = tokio::spawn(async move {
+        let pending = questions.recv().await.unwrap();
+        let question = pending.question.clone();
+        pending.answer("蓝色");
+        question
+    });
+    let mut agent = ReActAgent::new(SingleChat::new_with_api_name("mock-ask-user", "", false), vec![])
+        .with_max_steps(4)
+        .with_user(responder);
+    let (answer, steps) = agent.run("帮我挑一个主题").await.unwrap();
+    let question = host.await.unwrap();
+    assert_eq!(answer, "选蓝色的主题");
+    assert_eq!(steps[0].observation.as_deref(), Some("蓝色"));
+    assert_eq!(
+        question,
+        UserQuestion {
+            question: "你喜欢什么颜色?".to_string(),
+            options: vec!["红色".to_string(), "蓝色".to_string()],
+        }
+    );
+
+    // 宿主不回答就丢弃问题时，模型得知用户没有回答而不是一直等待
+    // When the host drops the question unanswered the model learns the user gave no answer instead of waiting forever
+    let (responder, mut questions) = user_channel();
+    let host = tokio::spawn(async move { drop(questions.recv().await) });
+    let mut agent = ReActAgent::new(SingleChat::new_with_api_name("mock-ask-user", "", false), vec![])
+        .with_max_steps(4)
+        .with_user(responder);
+    let (fallback, _) = agent.run("帮我挑一个主题").await.unwrap();
+    host.await.unwrap();
+    assert_eq!(fallback, "使用默认主题");
+
+    format_test_block("ask_user", || format!("question: {:?}\nanswer: {}\nsteps: {:?}", question, answer, steps));
+}
@@ -1,33 +1,118 @@
-use crate::chat::chat_single::SingleChat;
-use crate::config::Config;
-use crate::config::ModelCapability::{Think, ToolUse};
+use crate::chat::actor::{ChatHandle, UserMessage};
+use crate::chat::arena::ArenaSession;
+use crate::chat::attachment::{Attachment, AttachmentKind};
+use crate::chat::chat_base::{BaseChat, ChatError, FinishReason, CONTINUE_PROMPT, FINISH_REASON_METADATA_KEY};
+use crate::chat::chat_batch::{BatchJob, parse_results};
+use crate::chat::chat_multi::MultiChat;
+use crate::chat::chat_tool::{ChatTool, JsonRepair, STRUCTURED_OUTPUT_METADATA_KEY};
+use crate::chat::datetime::DateTimeContext;
+use crate::chat::extract::{extract_json, json_candidates};
+use crate::chat::events::{ChatEvent, ChatEvents};
+use crate::chat::context::{ContextMessage, ContextStrategy, LastTurns, MapReduce, ModelWindow, Salience, TokenWindow};
+use crate::chat::chat_single::{SingleChat, ToolCallError, STAGE_METADATA_KEY, TOOLS_METADATA_KEY};
+use crate::chat::files::{FileClient, FileProvider, UploadedFile, FILE_PARTS_KEY};
+use crate::chat::images::{ImageError, ImageGenerator, ImageOptions, ImageResponseFormat};
+use crate::chat::judge::Judge;
+use crate::chat::message::{
+    strip_secrets, Role, Session, TranscriptStyle, MODEL_METADATA_KEY, SUMMARY_METADATA_KEY, TITLE_METADATA_KEY,
+};
+use crate::chat::pruning::{load_archive, PruningPolicy};
+use crate::chat::openrouter::{
+    is_openrouter, list_models_from, DataCollection, ProviderPreferences, ProviderSort, OPENROUTER_BASE_URL,
+};
+use crate::chat::mock::{MockApi, MockReply, MOCK_FINGERPRINT};
+use crate::chat::repro::{ReproBundle, ReproInfo, REPRO_METADATA_KEY};
+use crate::chat::normalize::RoleRules;
+use crate::chat::transport::{ByteStream, ChatTransport, StallPolicy, TransportError, TransportRequest};
+use crate::chat::stream::{parse_partial_json, StreamEvent};
+use crate::chat::variables::render_template;
+use crate::chat::safety::{KeywordFilter, SafetyAction, SafetyPolicy};
+use crate::chat::usage::{count_tokens, AnswerTiming, UsageSource, UsageTracker, TIMING_METADATA_KEY};
+use crate::chat::recorder::{build_entry, Recorder, RecorderOptions, RecordEntry, RecordingStream, STREAM_DROPPED};
+use crate::chat::replay::ReplayStore;
+use crate::utils::common::redact::register_secret;
+use crate::config::{ApiInfo, Config};
+use crate::runtime::Runtime;
+use crate::memory::{Embedder, MemoryError};
+use crate::prompt::model::Prompt;
+use crate::config::ModelCapability::{Cheap, Embedding, ImageGeneration, Think, ToolUse};
 use crate::schema::json_schema::JsonSchema;
-use crate::tests::format_test_block;
+use crate::schema::tool_schema::{execute_tool_call, register_tool, ToolCall, ToolError, ToolErrorKind, ToolResult};
+use crate::tests::{format_test_block, LogCapture};
+use tracing_subscriber::fmt::format::FmtSpan;
 use rhine_schema_derive::{JsonSchema, tool_schema_derive};
 use serde::Deserialize;
+use bytes::Bytes;
+use error_stack::Report;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// 回放记录文件，测试不依赖真实 API
+/// Replay records, tests never reach a live API
+const CHAT_RECORDS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/fixtures/chat.jsonl");
 
 pub async fn test_chat() {
-    Config::add_api_source(
-        "pumpkin",
-        "https://api.pumpkinaigc.online/v1/chat/completions",
-        20,
-    );
-    Config::add_api_info(
-        "pumpkin-ds-r1",
-        "deepseek-r1",
-        Think,
-        "pumpkin",
-        "sk-cPdegaWl8YFcKZYs8a108b5f741844D9A1E0B90e724bBe23",
-    );
-    Config::add_api_info(
-        "pumpkin-gpt-4o",
-        "gpt-4o",
-        ToolUse,
-        "pumpkin",
-        "sk-cPdegaWl8YFcKZYs8a108b5f741844D9A1E0B90e724bBe23",
-    );
+    Config::add_replay_source("pumpkin", CHAT_RECORDS, 20).unwrap();
+    Config::add_api_info("pumpkin-ds-r1", "deepseek-r1", Think, "pumpkin", "replay");
+    Config::add_api_info("pumpkin-gpt-4o", "gpt-4o", ToolUse, "pumpkin", "replay");
 
     test_single_chat().await;
+    test_record_and_replay().await;
+    test_recorder_rotation().await;
+    test_mock_chat().await;
+    test_batch_files().await;
+    test_safety_filter().await;
+    test_judge().await;
+    test_refined_answer().await;
+    test_session_title().await;
+    test_answer_timing().await;
+    test_missing_usage().await;
+    test_stream_usage().await;
+    test_tool_use_stream().await;
+    test_multi_character_prompts().await;
+    test_private_memory().await;
+    test_transcript_style().await;
+    test_character_models().await;
+    test_pick_next_speaker().await;
+    test_secret_spans().await;
+    test_named_trees().await;
+    test_replay_on().await;
+    test_shared_session().await;
+    test_arena_session().await;
+    test_fork_at().await;
+    test_pruning().await;
+    test_tool_role().await;
+    test_attachments().await;
+    test_context_strategies().await;
+    test_role_rules().await;
+    test_custom_transport().await;
+    test_request_headers().await;
+    test_openrouter().await;
+    test_seed_and_repro_bundle().await;
+    test_finish_reason().await;
+    test_request_spans().await;
+    test_preflight_validation().await;
+    test_image_generation().await;
+    test_file_uploads().await;
+    test_stream_stall().await;
+    test_tool_prompt_caching().await;
+    test_selective_tools().await;
+    test_structured_tool_calls().await;
+    test_multi_chat_tools().await;
+    test_response_format_downgrade().await;
+    test_json_repair().await;
+    test_json_extraction();
+    test_structured_stream().await;
+    test_prompt_variables().await;
+    test_datetime_context().await;
+    test_chat_handle().await;
+    test_chat_events().await;
+    test_prompt_stages().await;
     // test_single_chat_get_json().await;
     // test_single_chat_get_tool().await;
 }
@@ -38,6 +123,7 @@ async fn test_single_chat() {
     let resp_1 = chat.get_req_body("深度思考strawberry有几个r").await.unwrap();
     let message_1 = chat.base.session.clone();
     let answer_1 = chat.get_content_from_req_body(resp_1).await.unwrap();
+    assert!(answer_1.contains("3 个 r"));
     format_test_block("chat_single_round", || {
         format!("message: {:?}\nanswer: {}", message_1, answer_1)
     });
@@ -65,11 +151,1910 @@ async fn test_single_chat() {
         .unwrap();
     let message_4 = chat.base.session.clone();
     let answer_4 = chat.get_content_from_req_body(resp_4).await.unwrap();
+    assert_eq!(chat.base.session.message_roots.len(), 2);
     format_test_block("chat_single_round", || {
         format!("message: {:?}\nanswer: {}", message_4, answer_4)
     });
 }
 
+async fn test_record_and_replay() {
+    let dir = std::env::temp_dir().join(format!("rhine-record-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    Recorder::new(&dir, RecorderOptions::default()).unwrap().install();
+
+    let mut chat = SingleChat::new_with_api_name("pumpkin-gpt-4o", "", true);
+    let resp = chat.get_req_body("深度思考strawberry有几个r").await.unwrap();
+    let answer = chat.get_content_from_req_body(resp).await.unwrap();
+    Recorder::uninstall();
+
+    let store = ReplayStore::load(&dir).unwrap();
+    let replayed = store
+        .stream_body(&json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "深度思考strawberry有几个r"}],
+            "stream": true,
+            "stream_options": {"include_usage": true},
+        }))
+        .unwrap();
+    assert_eq!(store.len(), 1);
+    assert!(replayed.contains("strawber"));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    // 哈希只去掉凭据字段，不受全局注册密钥影响
+    // The hash only strips credential fields and ignores globally registered secrets
+    let request = json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hash-probe-0123456789"}]});
+    let hash = ReplayStore::request_hash(&request);
+    register_secret("hash-probe-0123456789");
+    assert_eq!(ReplayStore::request_hash(&request), hash);
+    let mut with_key = request.clone();
+    with_key["api_key"] = json!("sk-replay-hash-key");
+    assert_eq!(ReplayStore::request_hash(&with_key), hash);
+    let mut other = request.clone();
+    other["messages"][0]["content"] = json!("hash-probe-9876543210");
+    assert_ne!(ReplayStore::request_hash(&other), hash);
+
+    format_test_block("record_and_replay", || format!("answer: {}", answer));
+}
+
+async fn test_recorder_rotation() {
+    let dir = std::env::temp_dir().join(format!("rhine-rotate-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let read_ns = |name: &str| -> Vec<u64> {
+        std::fs::read_to_string(dir.join(name))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<RecordEntry>(line).unwrap().request["n"].as_u64().unwrap())
+            .collect()
+    };
+
+    // 每条记录都超过上限，写入新记录前都会轮转
+    // Every record exceeds the limit, so each new record rotates first
+    let options = RecorderOptions {
+        file_prefix: "rot".to_string(),
+        max_file_bytes: 1,
+        max_files: 2,
+    };
+    let recorder = Recorder::new(&dir, options).unwrap();
+    for n in 0..4 {
+        let entry = build_entry("mock", "http://mock", false, &json!({"n": n}), std::time::Instant::now());
+        recorder.record(&entry).unwrap();
+    }
+    let rotated = [read_ns("rot.jsonl"), read_ns("rot.1.jsonl"), read_ns("rot.2.jsonl")];
+    assert_eq!(rotated, [vec![3], vec![2], vec![1]]);
+    assert!(!dir.join("rot.3.jsonl").exists());
+
+    // 提前丢弃的流也会写入已收到的部分
+    // A stream dropped early still records the part received so far
+    let recorder = Arc::new(Recorder::new(&dir, RecorderOptions::default()).unwrap());
+    let entry = build_entry("mock", "http://mock", true, &json!({"n": 4}), std::time::Instant::now());
+    let chunks = futures::stream::iter([Ok(Bytes::from("data: first\n\n")), Ok(Bytes::from("data: second\n\n"))]);
+    let mut stream = RecordingStream::new(chunks, Some((recorder.clone(), entry, std::time::Instant::now())));
+    stream.next().await.unwrap().unwrap();
+    drop(stream);
+
+    let dropped: RecordEntry =
+        serde_json::from_str(std::fs::read_to_string(recorder.current_path()).unwrap().trim()).unwrap();
+    assert_eq!(dropped.stream_body.as_deref(), Some("data: first\n\n"));
+    assert_eq!(dropped.error.as_deref(), Some(STREAM_DROPPED));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    format_test_block("recorder_rotation", || {
+        format!("rotated: {:?}\ndropped: {:?}", rotated, dropped.stream_body)
+    });
+}
+
+async fn test_mock_chat() {
+    let echo = Config::add_mock("mock-echo", |body| {
+        let question = body["messages"][0]["content"].as_str().unwrap_or_default();
+        MockReply::Text(format!("echo: {question}"))
+    });
+
+    let mut chat = SingleChat::new_with_api_name("mock-echo", "", true);
+    let resp = chat.get_req_body("strawberry").await.unwrap();
+    let answer = chat.get_content_from_req_body(resp).await.unwrap();
+    assert!(answer.contains("echo: strawberry"));
+    assert_eq!(echo.calls(), 1);
+
+    let flaky = MockApi::new(|_| "ok".into()).fail_first(1, 503);
+    Config::add_mock_api("mock-flaky", Cheap, flaky.clone());
+    let mut chat = SingleChat::new_with_api_name("mock-flaky", "", false);
+    let resp = chat.get_req_body("hello").await.unwrap();
+    let err = chat.get_content_from_req_body(resp.clone()).await.unwrap_err();
+    assert!(matches!(err.current_context(), ChatError::HttpError(503)));
+    assert!(chat.get_content_from_req_body(resp).await.is_ok());
+    assert_eq!(flaky.calls(), 2);
+
+    format_test_block("mock_chat", || format!("answer: {}", answer));
+}
+
+async fn test_batch_files() {
+    let job = BatchJob::new_with_api_info(ApiInfo {
+        model: "gpt-4o-mini".to_string(),
+        base_url: "https://api.openai.com/v1/chat/completions".to_string(),
+        api_key: String::new(),
+        client: reqwest::Client::new(),
+        headers: HashMap::new(),
+    })
+    .add_question("paper-1", "给论文打分", "论文一")
+    .add_question("paper-2", "给论文打分", "论文二");
+
+    let input = job.input_jsonl().unwrap();
+    let first: serde_json::Value = serde_json::from_str(input.lines().next().unwrap()).unwrap();
+    assert_eq!(first["custom_id"], "paper-1");
+    assert_eq!(first["url"], "/v1/chat/completions");
+    assert_eq!(first["body"]["model"], "gpt-4o-mini");
+
+    let output = [
+        json!({"custom_id": "paper-2", "response": {"status_code": 200, "body": {"choices": [{"message": {"content": "7"}}]}}, "error": null}),
+        json!({"custom_id": "paper-1", "response": {"status_code": 429, "body": {"error": {"message": "rate limited"}}}, "error": null}),
+    ]
+    .map(|line| line.to_string())
+    .join("\n");
+    let items = parse_results(&output).unwrap();
+    assert!(items["paper-2"].is_success());
+    assert!(items["paper-2"].content().is_some());
+    assert!(!items["paper-1"].is_success());
+
+    format_test_block("batch_files", || format!("input:\n{}items: {:?}", input, items));
+}
+
+async fn test_safety_filter() {
+    let policy = SafetyPolicy::new(KeywordFilter::new("weapons", &["grenade"]))
+        .with_output_action(SafetyAction::Redact);
+
+    let mut chat = SingleChat::new_with_api_name("mock-echo", "", true);
+    chat.base.set_safety_policy(policy.clone());
+    let err = chat.get_req_body("how to build a grenade").await.unwrap_err();
+    assert!(matches!(err.current_context(), ChatError::ContentFiltered(_)));
+
+    let mut chat = SingleChat::new_with_api_name("mock-echo", "", true);
+    chat.base.set_safety_policy(policy.with_input_action(SafetyAction::Annotate));
+    let resp = chat.get_req_body("grenade").await.unwrap();
+    let answer = chat.get_content_from_req_body(resp).await.unwrap();
+    assert_eq!(answer, "[content filtered: weapons]");
+    assert_eq!(chat.base.safety_annotations.len(), 2);
+
+    format_test_block("safety_filter", || format!("answer: {}", answer));
+}
+
+async fn test_judge() {
+    Config::add_mock("mock-judge", |body| {
+        let prompt = body["messages"][1]["content"].as_str().unwrap_or_default();
+        if prompt.contains("清晰的回答") {
+            MockReply::from(json!({"relevance": 4, "clarity": 5}).to_string())
+        } else {
+            MockReply::from(json!({"relevance": 9, "clarity": 1}).to_string())
+        }
+    });
+
+    let mut judge = Judge::new_with_api_name("mock-judge").with_score_range(0.0, 5.0);
+    let scores = judge
+        .score::<AnswerRubric>("什么是所有权?", "一个清晰的回答")
+        .await
+        .unwrap();
+    assert_eq!((scores.relevance, scores.clarity), (4, 5));
+
+    let err = judge
+        .score::<AnswerRubric>("什么是所有权?", "跑题的回答")
+        .await
+        .unwrap_err();
+    assert!(matches!(err.current_context(), ChatError::InvalidScore(detail) if detail.contains("/relevance")));
+
+    format_test_block("judge", || format!("scores: {:?}\nrejected: {:?}", scores, err));
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[schema(name = "answer_rubric", description = "回答质量评分", strict = true)]
+pub struct AnswerRubric {
+    #[schema(desc = "回答与问题的相关程度")]
+    relevance: i32,
+
+    #[schema(desc = "表达是否清晰")]
+    clarity: i32,
+}
+
+async fn test_refined_answer() {
+    Config::add_mock("mock-refine", |body| {
+        let messages = body["messages"].as_array().unwrap();
+        let last = messages.last().unwrap()["content"].as_str().unwrap_or_default();
+        if last.contains("评审") && !last.contains("修改") {
+            MockReply::from("缺少例子")
+        } else if last.contains("修改") {
+            MockReply::from(format!("修改稿{}", messages.len()))
+        } else {
+            MockReply::from("初稿")
+        }
+    });
+
+    let mut chat = SingleChat::new_with_api_name("mock-refine", "", false);
+    let (answer, rounds) = chat.get_refined_answer("解释所有权", 2).await.unwrap();
+    assert_eq!(rounds.len(), 2);
+    assert_eq!(rounds[0].draft, "初稿");
+    assert_eq!(rounds[0].critique, "缺少例子");
+    assert_eq!(rounds[1].draft, "修改稿5");
+
+    let question = &chat.base.session.message_roots[0];
+    assert_eq!(question.child.len(), 3);
+    assert_eq!(question.child[0].child[0].child[0].content, "缺少例子");
+    assert_eq!(chat.base.session.default_path, vec![0, 2]);
+    assert_eq!(question.child[2].content, answer);
+
+    format_test_block("refined_answer", || format!("answer: {}\nrounds: {:?}", answer, rounds));
+}
+
+async fn test_session_title() {
+    Config::add_mock("mock-summary", |body| {
+        let instruction = body["messages"][0]["content"].as_str().unwrap_or_default();
+        if instruction.contains("标题") {
+            MockReply::from(" 草莓里的r \n")
+        } else {
+            MockReply::from("用户询问strawberry中有几个r，回答是3个。")
+        }
+    });
+
+    let mut chat = SingleChat::new_with_api_name("pumpkin-gpt-4o", "", true);
+    let resp = chat.get_req_body("深度思考strawberry有几个r").await.unwrap();
+    chat.get_content_from_req_body(resp).await.unwrap();
+
+    let session = &mut chat.base.session;
+    let title = session
+        .generate_title_with(BaseChat::new_with_api_name("mock-summary", "", false))
+        .await
+        .unwrap();
+    let summary = session
+        .generate_summary_with(BaseChat::new_with_api_name("mock-summary", "", false))
+        .await
+        .unwrap();
+    assert_eq!(title, "草莓里的r");
+    assert_eq!(session.get_metadata(TITLE_METADATA_KEY), Some(&json!("草莓里的r")));
+    assert_eq!(session.get_metadata(SUMMARY_METADATA_KEY), Some(&json!(summary)));
+
+    format_test_block("session_title", || format!("title: {}\nsummary: {}", title, summary));
+}
+
+async fn test_answer_timing() {
+    let slow = MockApi::new(|_| "strawberry 有 3 个 r".into()).with_latency(Duration::from_millis(50));
+    Config::add_mock_api("mock-slow", Cheap, slow);
+
+    let mut chat = SingleChat::new_with_api_name("mock-slow", "", true);
+    let resp = chat.get_req_body("strawberry有几个r").await.unwrap();
+    chat.get_content_from_req_body(resp).await.unwrap();
+    let streamed = chat.base.last_timing().unwrap();
+    assert!(streamed.time_to_first_token_ms.unwrap() >= 50);
+    assert!(streamed.latency_ms >= streamed.time_to_first_token_ms.unwrap());
+
+    let path = chat.base.session.default_path.clone();
+    let stored = chat.base.session.get_node_by_path(&path).unwrap().metadata[TIMING_METADATA_KEY].clone();
+    assert_eq!(serde_json::from_value::<AnswerTiming>(stored).unwrap(), streamed);
+
+    let mut chat = SingleChat::new_with_api_name("mock-slow", "", false);
+    let resp = chat.get_req_body("strawberry有几个r").await.unwrap();
+    chat.get_content_from_req_body(resp).await.unwrap();
+    let timing = chat.base.last_timing().unwrap();
+    assert!(timing.latency_ms >= 50);
+    assert!(timing.time_to_first_token_ms.is_none());
+    assert!(timing.tokens_per_second.unwrap() > 0.0);
+
+    let stats = UsageTracker::stats("mock://mock-slow", &chat.base.model).unwrap();
+    assert_eq!(stats.requests, 2);
+    assert!(stats.mean_latency_ms().unwrap() >= 50.0);
+    assert!(stats.mean_time_to_first_token_ms().unwrap() >= 50.0);
+
+    format_test_block("answer_timing", || format!("{:#?}", stats));
+}
+
+async fn test_missing_usage() {
+    Config::add_mock("mock-no-usage", |_| {
+        MockReply::Raw(json!({
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "hello world"}}]
+        }))
+    });
+
+    let mut chat = SingleChat::new_with_api_name("mock-no-usage", "", false);
+    let resp = chat.get_req_body("hi").await.unwrap();
+    let answer = chat.get_content_from_req_body(resp).await.unwrap();
+    assert_eq!(answer, "hello world");
+
+    let timing = chat.base.last_timing().unwrap();
+    assert_eq!(timing.usage_source, UsageSource::Estimated);
+    assert_eq!(timing.completion_tokens, Some(count_tokens("gpt-4o", "hello world")));
+    assert!(chat.base.usage_snapshot().total_tokens > 0);
+    assert_eq!(
+        UsageTracker::stats("mock://mock-no-usage", &chat.base.model).unwrap().estimated_requests,
+        1
+    );
+
+    format_test_block("missing_usage", || format!("{:#?}", timing));
+}
+
+async fn test_stream_usage() {
+    Config::add_mock("mock-stream-usage", |body| {
+        // 辅助任务（如 JSON 修复）可能选中此模拟API，它们的非流式请求不受检查
+        // Auxiliary tasks (e.g. JSON repair) may pick this mock; their non-streaming requests are not checked
+        if body["stream"] == true {
+            assert_eq!(body["stream_options"]["include_usage"], true);
+        }
+        "strawberry 有 3 个 r".into()
+    });
+
+    let mut chat = SingleChat::new_with_api_name("mock-stream-usage", "", true);
+    let resp = chat.get_req_body("strawberry有几个r").await.unwrap();
+    chat.get_content_from_req_body(resp).await.unwrap();
+
+    let timing = chat.base.last_timing().unwrap();
+    assert_eq!(timing.usage_source, UsageSource::Reported);
+    let usage = chat.base.usage_snapshot();
+    assert_eq!(usage.prompt_tokens, timing.prompt_tokens.unwrap());
+    assert_eq!(usage.completion_tokens, timing.completion_tokens.unwrap());
+    assert_eq!(usage.total_tokens, usage.prompt_tokens + usage.completion_tokens);
+
+    // 派生任务中读完的流同样计入共享的计数器
+    // A stream drained in a spawned task is counted in the shared counters as well
+    let request_body = chat.get_req_body("再数一次").await.unwrap();
+    let (stream, semaphore_permit) = chat.base.get_stream_response(request_body).await.unwrap();
+    tokio::spawn(BaseChat::get_content_from_stream_resp(stream, semaphore_permit))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(chat.base.usage_snapshot().total_tokens > usage.total_tokens);
+
+    format_test_block("stream_usage", || format!("{:#?}", chat.base.usage_snapshot()));
+}
+
+async fn test_tool_use_stream() {
+    let mock = MockApi::new(|_| {
+        "我来算一下<ToolUse>{\"name\": \"add\", \"arguments\": \"{}\"}</ToolUse>稍等 a<b".into()
+    })
+    .with_chunk_chars(3);
+    Config::add_mock_api("mock-tool-stream", Cheap, mock);
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut chat = SingleChat::new_with_api_name("mock-tool-stream", "", true);
+    let sink = events.clone();
+    chat.base
+        .set_stream_callback(move |event| sink.lock().unwrap().push(event.clone()));
+    let resp = chat.get_req_body("1+1").await.unwrap();
+    let answer = chat.get_content_from_req_body(resp).await.unwrap();
+    assert!(answer.contains("<ToolUse>"));
+
+    let events = events.lock().unwrap().clone();
+    let text: String = events
+        .iter()
+        .filter_map(|event| match event {
+            StreamEvent::Text(text) => Some(text.as_str()),
+            StreamEvent::ToolCallDetected(_) | StreamEvent::PartialJson(_) => None,
+        })
+        .collect();
+    assert_eq!(text, "我来算一下稍等 a<b");
+    assert!(events.contains(&StreamEvent::ToolCallDetected(
+        "{\"name\": \"add\", \"arguments\": \"{}\"}".to_string()
+    )));
+
+    format_test_block("tool_use_stream", || format!("{:#?}", events));
+}
+
+async fn test_multi_character_prompts() {
+    Config::add_mock("mock-stage", |body| {
+        let prompt = body["messages"][0]["content"].as_str().unwrap_or_default();
+        MockReply::Text(format!("[{prompt}] 收到"))
+    });
+
+    let prompts = HashMap::from([
+        ("alice".to_string(), "你是爱丽丝".to_string()),
+        ("bob".to_string(), "你是鲍勃".to_string()),
+    ]);
+    let mut chat = MultiChat::new_with_api_name("mock-stage", prompts, false).unwrap();
+
+    let alice = chat.dialogue("alice", "开场").await.unwrap();
+    let bob = chat.dialogue("bob", "接话").await.unwrap();
+    assert_eq!(alice, "[你是爱丽丝] 收到");
+    assert_eq!(bob, "[你是鲍勃] 收到");
+
+    let body = chat.get_req_body_again(&chat.base.session.default_path.clone()).await.unwrap();
+    let messages = body["messages"].as_array().unwrap();
+    assert_eq!(messages[0], json!({"role": "system", "content": "你是鲍勃"}));
+    assert!(messages.iter().skip(1).all(|m| m["role"] != "system"));
+    assert!(messages.iter().any(|m| m["content"] == "alice said: [你是爱丽丝] 收到"));
+
+    format_test_block("multi_character_prompts", || format!("{:#?}", messages));
+}
+
+async fn test_private_memory() {
+    Config::add_mock("mock-negotiation", |body| {
+        let knows = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|m| m["content"].as_str().unwrap_or_default().contains("底价"));
+        MockReply::Text(if knows { "知道底价" } else { "不知道底价" }.to_string())
+    });
+
+    let prompts = HashMap::from([
+        ("seller".to_string(), "你是卖家".to_string()),
+        ("buyer".to_string(), "你是买家".to_string()),
+    ]);
+    let mut chat = MultiChat::new_with_api_name("mock-negotiation", prompts, false).unwrap();
+
+    let seller = chat.private_dialogue("seller", "你的底价是100").await.unwrap();
+    let buyer = chat.dialogue("buyer", "出个价吧").await.unwrap();
+    assert_eq!(seller, "知道底价");
+    assert_eq!(buyer, "不知道底价");
+
+    chat.set_character("seller").unwrap();
+    let body = chat.get_req_body_again(&chat.base.session.default_path.clone()).await.unwrap();
+    assert!(body.to_string().contains("你的底价是100"));
+
+    format_test_block("private_memory", || format!("seller: {}\nbuyer: {}", seller, buyer));
+}
+
+async fn test_transcript_style() {
+    let prompts = HashMap::from([
+        ("alice".to_string(), "你是爱丽丝".to_string()),
+        ("bob".to_string(), "你是鲍勃".to_string()),
+    ]);
+
+    let mut bodies = Vec::new();
+    for style in [TranscriptStyle::NamePrefix, TranscriptStyle::XmlTags, TranscriptStyle::NameField] {
+        let mut chat = MultiChat::new_with_api_name("mock-stage", prompts.clone(), false)
+            .unwrap()
+            .with_transcript_style(style);
+        chat.dialogue("alice", "开场").await.unwrap();
+        chat.set_character("bob").unwrap();
+        let body = chat.get_req_body_again(&chat.base.session.default_path.clone()).await.unwrap();
+        bodies.push(body["messages"].as_array().unwrap().last().unwrap().clone());
+    }
+
+    assert_eq!(bodies[0], json!({"role": "user", "content": "alice said: [你是爱丽丝] 收到"}));
+    assert_eq!(
+        bodies[1],
+        json!({"role": "user", "content": "<speaker name=\"alice\">[你是爱丽丝] 收到</speaker>"})
+    );
+    assert_eq!(bodies[2], json!({"role": "user", "name": "alice", "content": "[你是爱丽丝] 收到"}));
+
+    format_test_block("transcript_style", || format!("{:#?}", bodies));
+}
+
+async fn test_character_models() {
+    let critic = Config::add_mock("mock-critic", |body| {
+        MockReply::from(format!("critic via {}", body["model"].as_str().unwrap_or_default()))
+    });
+    Config::add_mock("mock-writer", |body| {
+        MockReply::from(format!("writer via {}", body["model"].as_str().unwrap_or_default()))
+    });
+    let prompts = HashMap::from([
+        ("writer".to_string(), "你是作者".to_string()),
+        ("critic".to_string(), "你是评论家".to_string()),
+    ]);
+    let mut chat = MultiChat::new_with_api_name("mock-writer", prompts, false).unwrap();
+    chat.set_character_api_name("critic", "mock-critic").unwrap();
+    let error = chat.set_character_api_name("critic", "mock-missing").unwrap_err();
+    assert!(matches!(error.current_context(), ChatError::ApiNotFound(name) if name == "mock-missing"));
+
+    // 切换发言角色时随之切换模型，未绑定的角色使用创建时的模型
+    // The model follows the speaker; characters without a binding use the model the chat was created with
+    let draft = chat.dialogue("writer", "写一句诗").await.unwrap();
+    let review = chat.dialogue("critic", "评价一下").await.unwrap();
+    let revision = chat.dialogue("writer", "改一改").await.unwrap();
+    assert_eq!(draft, "writer via mock-writer");
+    assert_eq!(review, "critic via mock-critic");
+    assert_eq!(revision, "writer via mock-writer");
+    assert_eq!(critic.calls(), 1);
+
+    chat.clear_character_api("critic").unwrap();
+    chat.set_character("critic").unwrap();
+    assert_eq!(chat.base.model, "mock-writer");
+
+    format_test_block("character_models", || format!("{draft}\n{review}\n{revision}"));
+}
+
+async fn test_pick_next_speaker() {
+    let runtime = Arc::new(Runtime::new());
+    runtime.add_mock_api("mock-speaker-stage", ToolUse, MockApi::new(|_| MockReply::from("收到")));
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    runtime.add_mock_api(
+        "mock-speaker",
+        Cheap,
+        MockApi::new(move |body| {
+            seen.lock().unwrap().push(body.clone());
+            MockReply::from("```json\n{\"speaker\": \"Bob\", \"reason\": \"爱丽丝向鲍勃提问\"}\n```")
+        }),
+    );
+
+    let prompts = HashMap::from([
+        ("alice".to_string(), "你是爱丽丝".to_string()),
+        ("bob".to_string(), "你是鲍勃".to_string()),
+    ]);
+    let api_info = runtime.get_api_info_with_name("mock-speaker-stage").unwrap();
+    let mut chat = MultiChat::new_with_api_info(api_info, prompts, false).unwrap();
+    chat.base.set_runtime(runtime);
+    chat.private_dialogue("alice", "只告诉你的秘密").await.unwrap();
+    chat.dialogue("alice", "鲍勃，你怎么看？").await.unwrap();
+
+    let speaker = chat.pick_next_speaker().await.unwrap();
+    assert_eq!(speaker, "bob");
+    assert_eq!(chat.current_character, "alice");
+
+    let body = requests.lock().unwrap()[0].clone();
+    let schema = &body["response_format"]["json_schema"]["schema"];
+    assert_eq!(schema["properties"]["speaker"]["enum"], json!(["alice", "bob"]));
+    let prompt = body.to_string();
+    assert!(prompt.contains("鲍勃，你怎么看？"));
+    assert!(!prompt.contains("只告诉你的秘密"));
+
+    format_test_block("pick_next_speaker", || format!("speaker: {speaker}"));
+}
+
+async fn test_replay_on() {
+    Config::add_mock("mock-replay", |body| {
+        let messages = body["messages"].as_array().cloned().unwrap_or_default();
+        let question = messages.last().map(|m| m["content"].to_string()).unwrap_or_default();
+        MockReply::Text(format!("replayed {question} after {} messages", messages.len()))
+    });
+
+    let mut chat = SingleChat::new_with_api_name("mock-echo", "", false);
+    for question in ["苹果", "香蕉"] {
+        let resp = chat.get_req_body(question).await.unwrap();
+        chat.get_content_from_req_body(resp).await.unwrap();
+    }
+    let session = &mut chat.base.session;
+    let original = session.default_path.clone();
+    let branch = session.replay_on("mock-replay").await.unwrap();
+
+    // 默认路径不变，新分支从第一个回答处分出并用新模型的回答续写
+    // The default path is kept; the new branch forks at the first answer and continues with the new model's answers
+    assert_eq!(session.default_path, original);
+    assert_eq!(branch.len(), original.len());
+    assert_eq!(branch[..1], original[..1]);
+    assert_ne!(branch[1], original[1]);
+    let replayed = session
+        .nodes_along_path(&branch)
+        .unwrap()
+        .iter()
+        .map(|node| node.content.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        replayed,
+        vec![
+            "苹果".to_string(),
+            "replayed \"苹果\" after 1 messages".to_string(),
+            "香蕉".to_string(),
+            "replayed \"香蕉\" after 3 messages".to_string(),
+        ]
+    );
+    let model = session.nodes_along_path(&branch).unwrap()[3].metadata[MODEL_METADATA_KEY].clone();
+    assert_eq!(model, "mock-replay");
+
+    let mut empty = Session::new();
+    assert!(empty.replay_on("mock-replay").await.is_err());
+
+    // 以助手消息开头的默认路径保留开头的问候，从之后的第一个回答处分出
+    // A default path starting with an assistant greeting keeps the greeting and forks at the first answer after it
+    let mut greeted = Session::new();
+    greeted.add_with_default_path(Role::Assistant, "你好，想聊什么?".to_string()).unwrap();
+    greeted.add_with_default_path(Role::User, "苹果".to_string()).unwrap();
+    greeted.add_with_default_path(Role::Assistant, "苹果很好吃".to_string()).unwrap();
+    let greeted_branch = greeted.replay_on("mock-replay").await.unwrap();
+    assert_eq!(greeted_branch, vec![0, 0, 1]);
+    let contents = greeted
+        .nodes_along_path(&greeted_branch)
+        .unwrap()
+        .iter()
+        .map(|node| node.content.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(contents, ["你好，想聊什么?", "苹果", "replayed \"苹果\" after 2 messages"]);
+
+    let mut greeting_only = Session::new();
+    greeting_only.add_with_default_path(Role::Assistant, "你好".to_string()).unwrap();
+    assert!(greeting_only.replay_on("mock-replay").await.is_err());
+
+    format_test_block("replay_on", || format!("{replayed:#?}"));
+}
+
+async fn test_secret_spans() {
+    let narration = "门开了。<secret to=\"bob\">钥匙藏在井里</secret><secret>凶手是管家</secret>";
+    Config::add_mock("mock-game-master", move |_| narration.into());
+    let prompts = HashMap::from([
+        ("gm".to_string(), "你是主持人".to_string()),
+        ("bob".to_string(), "你是鲍勃".to_string()),
+        ("carol".to_string(), "你是卡罗尔".to_string()),
+    ]);
+    let mut chat = MultiChat::new_with_api_name("mock-game-master", prompts, false).unwrap();
+    let answer = chat.dialogue("gm", "开始游戏").await.unwrap();
+    assert_eq!(answer, narration);
+
+    let mut seen = HashMap::new();
+    for character in ["gm", "bob", "carol"] {
+        chat.set_character(character).unwrap();
+        let body = chat.get_req_body_again(&chat.base.session.default_path.clone()).await.unwrap();
+        let last = body["messages"].as_array().unwrap().last().unwrap()["content"].clone();
+        seen.insert(character, last.as_str().unwrap().to_string());
+    }
+    assert_eq!(seen["gm"], narration);
+    assert_eq!(seen["bob"], "gm said: 门开了。<secret to=\"bob\">钥匙藏在井里</secret>");
+    assert_eq!(seen["carol"], "gm said: 门开了。");
+    assert_eq!(strip_secrets(narration, None), "门开了。");
+    assert_eq!(strip_secrets("<secret to=\"bob, carol\">线索</secret>", Some("carol")), "<secret to=\"bob, carol\">线索</secret>");
+
+    format_test_block("secret_spans", || format!("{:#?}", seen));
+}
+
+async fn test_named_trees() {
+    let mut chat = SingleChat::new_with_api_name("mock-echo", "", false);
+    chat.base
+        .session
+        .new_tree("fruit", Role::System, "只聊水果".to_string())
+        .unwrap();
+    let resp = chat.get_req_body("strawberry").await.unwrap();
+    chat.get_content_from_req_body(resp).await.unwrap();
+    let fruit_path = chat.base.session.default_path.clone();
+
+    let session = &mut chat.base.session;
+    session.new_tree("weather", Role::System, "只聊天气".to_string()).unwrap();
+    assert_eq!(session.default_path, vec![1]);
+    assert!(session.new_tree("weather", Role::System, String::new()).is_err());
+    session.set_tree_metadata("weather", "topic", json!("天气")).unwrap();
+
+    session.switch_tree("fruit").unwrap();
+    assert_eq!(session.default_path, fruit_path);
+    assert_eq!(session.current_tree().unwrap().name, "fruit");
+    assert_eq!(session.tree("weather").unwrap().metadata["topic"], "天气");
+    assert!(session.switch_tree("sports").is_err());
+
+    let usage = session.usage;
+    assert_eq!(usage.requests, 1);
+    assert!(usage.total_tokens() > 0);
+
+    let restored: Session = serde_json::from_str(&serde_json::to_string(session).unwrap()).unwrap();
+    assert_eq!(&restored, session);
+
+    format_test_block("named_trees", || format!("{:#?}", session.trees));
+}
+
+async fn test_shared_session() {
+    let shared = Session::new().into_shared();
+    let mut fast = SingleChat::new_with_api_name("mock-echo", "", false);
+    let mut slow = SingleChat::new_with_api_name("mock-slow", "", true);
+    fast.base.share_session(shared.clone()).unwrap();
+    slow.base.share_session(shared.clone()).unwrap();
+    fast.base.add_message(Role::System, "简短回答").unwrap();
+
+    let (fast_answer, slow_answer) = tokio::join!(
+        async {
+            let resp = fast.get_req_body_with_new_question(&[0], "apple").await.unwrap();
+            fast.get_content_from_req_body(resp).await.unwrap()
+        },
+        async {
+            let resp = slow.get_req_body_with_new_question(&[0], "banana").await.unwrap();
+            slow.get_content_from_req_body(resp).await.unwrap()
+        }
+    );
+    assert_ne!(fast.base.session.default_path, slow.base.session.default_path);
+
+    let session = shared.read().unwrap().clone();
+    let root = &session.message_roots[0];
+    let questions: Vec<_> = root.child.iter().map(|question| question.content.as_str()).collect();
+    assert_eq!(questions.len(), 2);
+    assert!(questions.contains(&"apple") && questions.contains(&"banana"));
+    assert!(root.child.iter().all(|question| question.child.len() == 1));
+    assert_eq!(session.usage.requests, 2);
+
+    let fast_path = fast.base.session.default_path.clone();
+    let end_path = {
+        let mut session = shared.write().unwrap();
+        session.add_with_parent_path(&fast_path, Role::User, "cherry".to_string()).unwrap();
+        session.default_path.clone()
+    };
+    let body = fast.base.build_request_body(&end_path, &Role::User).await.unwrap();
+    let messages = body["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[3]["content"], "cherry");
+
+    format_test_block("shared_session", || format!("fast: {}\nslow: {}", fast_answer, slow_answer));
+}
+
+async fn test_arena_session() {
+    let mut session = Session::new();
+    session.add_with_default_path(Role::System, "sys".to_string()).unwrap();
+    for question in ["q1", "q2"] {
+        session.add_with_parent_path(&[0], Role::User, question.to_string()).unwrap();
+        session.add_with_default_path(Role::Assistant, format!("a-{question}")).unwrap();
+    }
+    session.set_private(&[0, 1, 0], Some("alice")).unwrap();
+
+    let mut arena = ArenaSession::from(session.clone());
+    assert_eq!(arena.node_count(), 5);
+    assert_eq!(arena.default_path, session.default_path);
+    for path in [vec![0, 0, 0], vec![0, 1, 0]] {
+        assert_eq!(
+            arena.assemble_context(&path, &Role::User).unwrap(),
+            session.assemble_context(&path, &Role::User).unwrap()
+        );
+    }
+    assert_eq!(serde_json::to_value(&arena).unwrap(), serde_json::to_value(&session).unwrap());
+    assert_eq!(Session::from(arena.clone()), session);
+
+    let follow_up = arena.add_with_default_path(Role::User, "follow".to_string()).unwrap();
+    assert_eq!(arena.path_of(follow_up).unwrap(), vec![0, 1, 0, 0]);
+
+    let first_answer = arena.node_id(&[0, 0, 0]).unwrap();
+    let second_answer = arena.node_id(&[0, 1, 0]).unwrap();
+    let moved = arena.move_subtree(second_answer, first_answer).unwrap();
+    assert_eq!(moved, vec![0, 0, 0, 0]);
+    assert_eq!(arena.default_path, vec![0, 0, 0, 0, 0]);
+    assert_eq!(arena.node(follow_up).unwrap().content, "follow");
+    let contents: Vec<_> = arena
+        .nodes_along_path(&arena.default_path)
+        .unwrap()
+        .iter()
+        .map(|node| node.content.clone())
+        .collect();
+    assert_eq!(contents, ["sys", "q1", "a-q1", "a-q2", "follow"]);
+
+    let root = arena.roots()[0];
+    assert!(arena.move_subtree(root, first_answer).is_err());
+    assert!(arena.move_subtree(first_answer, follow_up).is_err());
+
+    assert_eq!(arena.remove_subtree(second_answer).unwrap(), 2);
+    assert!(arena.node(follow_up).is_none());
+    assert_eq!(arena.default_path, vec![0, 0, 0]);
+    assert!(arena.remove_subtree(root).is_err());
+
+    let restored: ArenaSession = serde_json::from_value(serde_json::to_value(&arena).unwrap()).unwrap();
+    assert_eq!(Session::from(restored), Session::from(arena));
+
+    // 修剪策略在添加消息时执行，规则与 Session 相同
+    // The pruning policy is enforced on add by the same rules as Session
+    let archive = std::env::temp_dir().join(format!("rhine-arena-prune-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&archive);
+    let mut pruned = ArenaSession::new();
+    pruned.set_pruning_policy(PruningPolicy::new(&archive).with_max_depth(2));
+    pruned.add_with_default_path(Role::System, "sys".to_string()).unwrap();
+    pruned.add_with_default_path(Role::User, "q1".to_string()).unwrap();
+    pruned.add_with_default_path(Role::Assistant, "a1".to_string()).unwrap();
+    pruned.add_with_parent_path(&[0], Role::User, "q2".to_string()).unwrap();
+    assert_eq!(pruned.node_count(), 3);
+    assert!(pruned.children(pruned.node_id(&[0, 0]).unwrap()).is_empty());
+    assert_eq!(pruned.default_path, vec![0, 1]);
+    assert_eq!(load_archive(&archive).unwrap().last().unwrap().branch.content, "a1");
+
+    pruned.set_pruning_policy(PruningPolicy::new(&archive).with_max_nodes(3));
+    pruned.add_with_default_path(Role::Assistant, "a2".to_string()).unwrap();
+    let root = pruned.roots()[0];
+    let remaining: Vec<_> = pruned
+        .children(root)
+        .iter()
+        .map(|&child| pruned.node(child).unwrap().content.clone())
+        .collect();
+    assert_eq!(remaining, ["q2"]);
+    assert_eq!(pruned.default_path, vec![0, 0, 0]);
+    let archived = load_archive(&archive).unwrap();
+    assert_eq!(archived.len(), 2);
+    assert_eq!(archived[1].branch.content, "q1");
+    assert_eq!(archived[1].parent_path, vec![0]);
+    let _ = std::fs::remove_file(&archive);
+
+    format_test_block("arena_session", || format!("{contents:?}"));
+}
+
+async fn test_fork_at() {
+    let mut session = Session::new();
+    session.add_with_default_path(Role::System, "sys".to_string()).unwrap();
+    for question in ["q1", "q2"] {
+        session.add_with_parent_path(&[0], Role::User, question.to_string()).unwrap();
+        session.add_with_default_path(Role::Assistant, format!("a-{question}")).unwrap();
+    }
+    session.add_with_parent_path(&[], Role::System, "other".to_string()).unwrap();
+    session.add_with_default_path(Role::User, "other-q".to_string()).unwrap();
+
+    let mut fork = session.fork_at(&[0, 0, 0]).unwrap();
+    assert_eq!(fork.default_path, vec![0, 0, 0]);
+    for (forked, original) in fork.message_roots.iter().zip(&session.message_roots) {
+        assert!(Arc::ptr_eq(forked, original));
+    }
+
+    fork.add_with_default_path(Role::User, "speculative".to_string()).unwrap();
+    fork.set_message_metadata(&[0, 0], "checked", true.into()).unwrap();
+    assert_eq!(fork.message_roots[0].node_count(), 6);
+    assert_eq!(session.message_roots[0].node_count(), 5);
+    assert!(session.message_roots[0].child[0].metadata.is_empty());
+    assert!(!Arc::ptr_eq(&fork.message_roots[0].child[0], &session.message_roots[0].child[0]));
+    assert!(!Arc::ptr_eq(&fork.message_roots[0], &session.message_roots[0]));
+    assert!(Arc::ptr_eq(&fork.message_roots[0].child[1], &session.message_roots[0].child[1]));
+    assert!(Arc::ptr_eq(&fork.message_roots[1], &session.message_roots[1]));
+
+    assert!(session.fork_at(&[0, 5]).is_err());
+
+    format_test_block("fork_at", || format!("{:#?}", fork.message_roots[0].child[0]));
+}
+
+async fn test_pruning() {
+    let archive = std::env::temp_dir().join(format!("rhine-prune-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&archive);
+
+    let mut session = Session::new();
+    session.add_with_default_path(Role::System, "sys".to_string()).unwrap();
+    for question in ["q1", "q2", "q3"] {
+        session.add_with_parent_path(&[0], Role::User, question.to_string()).unwrap();
+        session.add_with_default_path(Role::Assistant, format!("a-{question}")).unwrap();
+        session.add_with_default_path(Role::User, format!("follow-{question}")).unwrap();
+    }
+    assert_eq!(session.message_roots[0].node_count(), 10);
+
+    session.set_pruning_policy(PruningPolicy::new(&archive).with_max_depth(3));
+    assert_eq!(session.prune().unwrap(), 2);
+    assert_eq!(session.message_roots[0].node_count(), 8);
+    assert_eq!(session.default_path.len(), 4);
+
+    session.set_pruning_policy(PruningPolicy::new(&archive).with_max_nodes(4));
+    session.add_with_default_path(Role::Assistant, "a-follow".to_string()).unwrap();
+    let root = &session.message_roots[0];
+    assert_eq!(root.child.len(), 1);
+    assert_eq!(root.child[0].content, "q3");
+    assert_eq!(session.default_path, vec![0, 0, 0, 0, 0]);
+
+    let archived = load_archive(&archive).unwrap();
+    assert_eq!(archived.len(), 4);
+    assert_eq!(archived[2].branch.content, "q1");
+
+    // 增量检查：切换分支后，离开活动路径的旧分支在下次添加时被修剪
+    // Incremental check: after switching branches, the branch left behind is pruned on the next add
+    let mut switched = Session::new();
+    switched.set_pruning_policy(PruningPolicy::new(&archive).with_max_depth(2));
+    switched.add_with_default_path(Role::System, "sys".to_string()).unwrap();
+    switched.add_with_default_path(Role::User, "q1".to_string()).unwrap();
+    switched.add_with_default_path(Role::Assistant, "a1".to_string()).unwrap();
+    switched.add_with_parent_path(&[0], Role::User, "q2".to_string()).unwrap();
+    assert_eq!(switched.message_roots[0].node_count(), 3);
+    assert!(switched.message_roots[0].child[0].child.is_empty());
+    assert_eq!(switched.default_path, vec![0, 1]);
+    assert_eq!(load_archive(&archive).unwrap().last().unwrap().branch.content, "a1");
+
+    // 共享会话仍被多个对话持有时不修剪，另一对话的分支与路径保持不变；只剩一个持有者后恢复修剪
+    // No pruning while several chats hold a shared session, so the other chat's branch and path stay intact;
+    // pruning resumes once a single holder is left
+    let archived = load_archive(&archive).unwrap().len();
+    let shared = Session::new().into_shared();
+    shared
+        .write()
+        .unwrap()
+        .set_pruning_policy(PruningPolicy::new(&archive).with_max_nodes(3));
+    let mut first = SingleChat::new_with_api_name("mock-echo", "", false);
+    let mut second = SingleChat::new_with_api_name("mock-echo", "", false);
+    first.base.share_session(shared.clone()).unwrap();
+    second.base.share_session(shared.clone()).unwrap();
+    first.base.add_message(Role::System, "sys").unwrap();
+    for (chat, question) in [(&mut first, "apple"), (&mut second, "banana")] {
+        let resp = chat.get_req_body_with_new_question(&[0], question).await.unwrap();
+        chat.get_content_from_req_body(resp).await.unwrap();
+    }
+    assert_eq!(shared.read().unwrap().message_roots[0].node_count(), 5);
+    assert_eq!(load_archive(&archive).unwrap().len(), archived);
+    for (chat, question) in [(&first, "apple"), (&second, "banana")] {
+        let session = shared.read().unwrap();
+        let nodes = session.nodes_along_path(&chat.base.session.default_path).unwrap();
+        assert_eq!(nodes[1].content, question);
+        assert_eq!(nodes.last().unwrap().role, Role::Assistant);
+    }
+
+    drop(second);
+    drop(shared);
+    first.base.add_message(Role::User, "follow").unwrap();
+    let root = &first.base.session.message_roots[0];
+    assert_eq!(root.child.len(), 1);
+    assert_eq!(root.child[0].content, "apple");
+    assert_eq!(load_archive(&archive).unwrap().last().unwrap().branch.content, "banana");
+
+    format_test_block("pruning", || format!("{:#?}", session.message_roots[0]));
+    let _ = std::fs::remove_file(&archive);
+}
+
+async fn test_tool_role() {
+    let mut chat = SingleChat::new_with_api_name("mock-echo", "", false);
+    chat.base.add_message(Role::User, "1+1=?").unwrap();
+    chat.base.add_message(Role::Assistant, "<ToolUse>add</ToolUse>").unwrap();
+    chat.add_tool_result("call_1", "2").unwrap();
+    chat.base
+        .add_message(Role::Function { name: "add".to_string() }, "2")
+        .unwrap();
+
+    let body = chat
+        .base
+        .build_request_body(&chat.base.session.default_path.clone(), &Role::User)
+        .await
+        .unwrap();
+    // 提示词工具模式没有带 tool_calls 的助手消息，结果只能作为用户消息
+    // Prompt-based tool mode has no assistant message with tool_calls, so the result stays a user message
+    assert_eq!(body["messages"][2], json!({"role": "user", "content": "2"}));
+    assert_eq!(body["messages"][3], json!({"role": "function", "name": "add", "content": "2"}));
+
+    let mut native = body.clone();
+    native["tools"] = json!([{"type": "function", "function": {"name": "add", "parameters": {"type": "object"}}}]);
+    chat.base.get_response(native).await.unwrap();
+    chat.add_tool_result("call_2", "4").unwrap();
+    let body = chat
+        .base
+        .build_request_body(&chat.base.session.default_path.clone(), &Role::User)
+        .await
+        .unwrap();
+    assert_eq!(body["messages"][4], json!({"role": "tool", "tool_call_id": "call_2", "content": "4"}));
+
+    let restored: Session =
+        serde_json::from_str(&serde_json::to_string(&chat.base.session).unwrap()).unwrap();
+    assert_eq!(restored, chat.base.session);
+
+    format_test_block("tool_role", || format!("{:#?}", body["messages"]));
+}
+
+async fn test_attachments() {
+    let dir = std::env::temp_dir().join(format!("rhine-attach-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("scores.csv"), "name,score\nalice,90\n").unwrap();
+    std::fs::write(dir.join("report.txt"), "草莓".repeat(100)).unwrap();
+
+    Config::add_mock("mock-digest", |_| "报告反复提到草莓".into());
+    let mut chat = SingleChat::new_with_api_name("mock-digest", "", false);
+    let resp = chat
+        .get_req_body_with_attachments(
+            "总结附件",
+            vec![
+                Attachment::from_path(dir.join("scores.csv")),
+                Attachment::from_path(dir.join("report.txt")).with_max_chars(50),
+            ],
+        )
+        .await
+        .unwrap();
+    let content = resp["messages"][0]["content"].as_str().unwrap();
+    assert!(content.starts_with("总结附件"));
+    assert!(content.contains("<attachment name=\"scores.csv\" type=\"csv\">\n```csv\nname,score"));
+    assert!(content.contains("[truncated 150 chars]"));
+
+    let summarized = chat
+        .base
+        .session
+        .summarize_attachments_with(BaseChat::new_with_api_name("mock-digest", "", false))
+        .await
+        .unwrap();
+    assert_eq!(summarized, 1);
+    let resp = chat.get_req_body_again(&chat.base.session.default_path.clone()).await.unwrap();
+    let content = resp["messages"][0]["content"].as_str().unwrap();
+    assert!(content.contains("报告反复提到草莓"));
+    assert!(!content.contains("truncated"));
+
+    let pdf = Attachment::from_path(dir.join("paper.pdf"));
+    assert_eq!(pdf.kind, AttachmentKind::Pdf);
+    assert!(pdf.render().is_err());
+
+    format_test_block("attachments", || content.to_string());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// 以关键词是否出现作为向量的测试用向量化
+/// Test embedder using keyword presence as vectors
+#[derive(Debug)]
+struct KeywordEmbedder;
+
+impl Embedder for KeywordEmbedder {
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, error_stack::Result<Vec<Vec<f32>>, MemoryError>> {
+        let embeddings = texts
+            .iter()
+            .map(|text| ["猫", "狗", "鱼"].iter().map(|k| f32::from(u8::from(text.contains(k)))).collect())
+            .collect();
+        Box::pin(async move { Ok(embeddings) })
+    }
+}
+
+async fn test_context_strategies() {
+    let mut chat = BaseChat::new_with_api_name("mock-echo", "", false);
+    chat.add_message(Role::System, "sys").unwrap();
+    for (question, answer) in [("猫吃什么", "鱼"), ("狗叫什么", "汪"), ("今天几号", "周五")] {
+        chat.add_message(Role::User, question).unwrap();
+        chat.add_message(Role::Assistant, answer).unwrap();
+    }
+    chat.add_message(Role::User, "猫喜欢什么").unwrap();
+    let end_path = chat.session.default_path.clone();
+
+    async fn contents(chat: &mut BaseChat, end_path: &[usize]) -> Vec<String> {
+        let body = chat.build_request_body(end_path, &Role::User).await.unwrap();
+        body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    assert_eq!(contents(&mut chat, &end_path).await.len(), 8);
+
+    chat.set_context_strategy(LastTurns::new(2));
+    assert_eq!(contents(&mut chat, &end_path).await, ["sys", "今天几号", "周五", "猫喜欢什么"]);
+
+    chat.set_context_strategy(LastTurns::new(0));
+    assert_eq!(contents(&mut chat, &end_path).await, ["sys", "猫喜欢什么"]);
+
+    let window = count_tokens("mock-echo", "sys") + count_tokens("mock-echo", "猫喜欢什么")
+        + count_tokens("mock-echo", "周五")
+        + 9;
+    chat.set_context_strategy(TokenWindow::new(window));
+    assert_eq!(contents(&mut chat, &end_path).await, ["sys", "周五", "猫喜欢什么"]);
+
+    // 按登记的上限裁剪：上下文长度减去回答上限即为预算
+    // Trimming by registered limits: the budget is the context window minus the output cap
+    let full: Vec<ContextMessage> = [
+        ("system", "sys"),
+        ("user", "猫吃什么"),
+        ("assistant", "鱼"),
+        ("user", "狗叫什么"),
+        ("assistant", "汪"),
+        ("user", "今天几号"),
+        ("assistant", "周五"),
+        ("user", "猫喜欢什么"),
+    ]
+    .into_iter()
+    .map(|(role, content)| {
+        HashMap::from([
+            ("role".to_string(), role.to_string()),
+            ("content".to_string(), content.to_string()),
+        ])
+    })
+    .collect();
+    Config::set_context_limit("window-model", window + 100);
+    Config::set_max_output_tokens("window-model", 100);
+    let trimmed = ModelWindow.select("window-model", full.clone()).await.unwrap();
+    assert_eq!(trimmed, TokenWindow::new(window).select("window-model", full.clone()).await.unwrap());
+    assert_eq!(trimmed.len(), 3);
+    assert_eq!(ModelWindow.select("unknown-window-model", full.clone()).await.unwrap(), full);
+
+    chat.set_context_strategy(Salience::new(KeywordEmbedder, 1).with_keep_recent(1));
+    assert_eq!(contents(&mut chat, &end_path).await, ["sys", "猫吃什么", "周五", "猫喜欢什么"]);
+
+    Config::add_mock("mock-condense", |body| {
+        let input = body["messages"][1]["content"].as_str().unwrap_or_default();
+        MockReply::Text(format!("摘要({})", input.lines().count()))
+    });
+    let summarizer = BaseChat::new_with_api_name("mock-condense", "", false);
+    chat.set_context_strategy(MapReduce::new_with_chat(summarizer).with_chunk_size(2).with_keep_recent(2));
+    let condensed = contents(&mut chat, &end_path).await;
+    assert_eq!(condensed, ["sys", "此前对话的摘要：\n摘要(3)", "今天几号", "周五", "猫喜欢什么"]);
+
+    format_test_block("context_strategies", || format!("{:?}", condensed));
+}
+
+async fn test_role_rules() {
+    Config::add_mock("mock-strict", |_| "ok".into());
+    let mut chat = BaseChat::new_with_api_name("mock-strict", "", false);
+    chat.add_message(Role::System, "sys").unwrap();
+    chat.add_message(Role::User, "a").unwrap();
+    chat.add_message(Role::User, "b").unwrap();
+    chat.add_message(Role::Assistant, "<ToolUse>lookup</ToolUse>").unwrap();
+    chat.add_message(Role::Tool { call_id: "1".into() }, "r1").unwrap();
+    chat.add_message(Role::Tool { call_id: "2".into() }, "r2").unwrap();
+    chat.add_message(Role::System, "late").unwrap();
+    chat.add_message(Role::User, "c").unwrap();
+    let end_path = chat.session.default_path.clone();
+
+    let permissive = chat.build_request_body(&end_path, &Role::User).await.unwrap();
+    assert_eq!(permissive["messages"].as_array().unwrap().len(), 8);
+
+    Config::set_role_rules("mock-strict", RoleRules::MergeConsecutive).unwrap();
+    let merged = chat.build_request_body(&end_path, &Role::User).await.unwrap();
+    assert_eq!(merged["messages"][1], json!({"role": "user", "content": "a\n\nb"}));
+    assert_eq!(merged["messages"].as_array().unwrap().len(), 7);
+
+    Config::set_role_rules("mock-strict", RoleRules::StrictAlternation).unwrap();
+    let strict = chat.build_request_body(&end_path, &Role::User).await.unwrap();
+    let roles: Vec<_> = strict["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["role"].as_str().unwrap())
+        .collect();
+    assert_eq!(roles, ["system", "user", "assistant", "tool", "tool", "user"]);
+    assert_eq!(strict["messages"][5]["content"], "late\n\nc");
+
+    let prompts = HashMap::from([
+        ("alice".to_string(), "你是alice".to_string()),
+        ("bob".to_string(), "你是bob".to_string()),
+    ]);
+    let mut stage = MultiChat::new_with_api_name("mock-strict", prompts, false)
+        .unwrap()
+        .with_transcript_style(TranscriptStyle::NameField);
+    stage.add_user_message("开始").unwrap();
+    stage.base.add_message(Role::Character("bob".into()), "hi").unwrap();
+    stage.set_character("alice").unwrap();
+    let body = stage.get_req_body_again(&stage.base.session.default_path.clone()).await.unwrap();
+    assert_eq!(body["messages"][1], json!({"role": "user", "content": "开始\n\nbob said: hi"}));
+
+    format_test_block("role_rules", || format!("{:#?}", strict["messages"]));
+}
+
+/// 记录请求的测试替身传输层，状态码非 200 时返回 HTTP 错误
+/// Test double transport recording requests; fails with an HTTP error when the status is not 200
+#[derive(Debug, Clone, Default)]
+struct StubTransport {
+    status: u16,
+    requests: Arc<Mutex<Vec<TransportRequest>>>,
+}
+
+impl StubTransport {
+    fn check(&self, request: &TransportRequest) -> error_stack::Result<(), TransportError> {
+        self.requests.lock().unwrap().push(request.clone());
+        match self.status {
+            200 => Ok(()),
+            status => Err(Report::new(TransportError::Http(status))),
+        }
+    }
+}
+
+impl ChatTransport for StubTransport {
+    fn send<'a>(
+        &'a self,
+        request: &'a TransportRequest,
+    ) -> BoxFuture<'a, error_stack::Result<serde_json::Value, TransportError>> {
+        Box::pin(async move {
+            self.check(request)?;
+            Ok(json!({
+                "choices": [{"message": {"role": "assistant", "content": "stub answer"}}],
+                "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7},
+            }))
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: &'a TransportRequest,
+    ) -> BoxFuture<'a, error_stack::Result<ByteStream, TransportError>> {
+        Box::pin(async move {
+            self.check(request)?;
+            let chunks = ["stub ", "stream"].map(|text| {
+                let chunk = json!({"choices": [{"delta": {"content": text}}]});
+                Ok(Bytes::from(format!("data: {chunk}\n\n")))
+            });
+            let stream: ByteStream = Box::pin(futures::stream::iter(chunks));
+            Ok(stream)
+        })
+    }
+}
+
+async fn test_custom_transport() {
+    Config::add_api_source("stub", "stub://transport", 2);
+    Config::add_api_info("stub-model", "stub-model", Cheap, "stub", "sk-stub");
+    let transport = StubTransport {
+        status: 200,
+        ..Default::default()
+    };
+    Config::set_transport("stub", transport.clone()).unwrap();
+
+    let mut chat = SingleChat::new_with_api_name("stub-model", "", false);
+    let request_body = chat.get_req_body("你好").await.unwrap();
+    let answer = chat.get_content_from_req_body(request_body).await.unwrap();
+    assert_eq!(answer, "stub answer");
+    assert_eq!(chat.base.session.usage.total_tokens(), 7);
+
+    let mut streaming = SingleChat::new_with_api_name("stub-model", "", true);
+    let request_body = streaming.get_req_body("你好").await.unwrap();
+    let streamed = streaming.get_content_from_req_body(request_body).await.unwrap();
+    assert_eq!(streamed, "stub stream");
+
+    let requests = transport.requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].url, "stub://transport");
+    assert_eq!(requests[0].api_key, "sk-stub");
+    assert_eq!(requests[1].body["stream"], true);
+
+    let mut failing = BaseChat::new_with_api_name("stub-model", "", false);
+    failing.set_transport(StubTransport {
+        status: 429,
+        ..Default::default()
+    });
+    failing.add_message(Role::User, "你好").unwrap();
+    let request_body = failing
+        .build_request_body(&failing.session.default_path.clone(), &Role::User)
+        .await
+        .unwrap();
+    let error = failing.get_response(request_body).await.unwrap_err();
+    assert!(matches!(error.current_context(), ChatError::HttpError(429)));
+
+    format_test_block("custom_transport", || format!("answer: {answer}\nstreamed: {streamed}\nerror: {error:?}"));
+}
+
+async fn test_request_headers() {
+    Config::add_api_source("stub-headers", "stub://headers", 2);
+    Config::set_source_headers(
+        "stub-headers",
+        HashMap::from([
+            ("HTTP-Referer".to_string(), "https://example.com".to_string()),
+            ("X-Title".to_string(), "source".to_string()),
+        ]),
+    )
+    .unwrap();
+    Config::add_api_info("stub-headers-model", "stub-model", Cheap, "stub-headers", "sk-stub");
+    Config::set_api_headers("stub-headers-model", HashMap::from([("X-Title".to_string(), "api".to_string())])).unwrap();
+    assert!(Config::set_api_headers("missing-api", HashMap::new()).is_err());
+    let transport = StubTransport {
+        status: 200,
+        ..Default::default()
+    };
+    Config::set_transport("stub-headers", transport.clone()).unwrap();
+
+    let mut chat = SingleChat::new_with_api_name("stub-headers-model", "", false);
+    chat.base.set_header("OpenAI-Organization", "org-1");
+    chat.base
+        .set_request_headers(HashMap::from([("traceparent".to_string(), "00-abc-01".to_string())]));
+    for question in ["第一问", "第二问"] {
+        let request_body = chat.get_req_body(question).await.unwrap();
+        chat.get_content_from_req_body(request_body).await.unwrap();
+    }
+
+    let requests = transport.requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    let first = &requests[0].headers;
+    assert_eq!(first["HTTP-Referer"], "https://example.com");
+    assert_eq!(first["X-Title"], "api");
+    assert_eq!(first["OpenAI-Organization"], "org-1");
+    assert_eq!(first["traceparent"], "00-abc-01");
+    // 单次请求的头用过即清除
+    // Per-request headers are cleared once used
+    assert!(!requests[1].headers.contains_key("traceparent"));
+    assert_eq!(requests[1].headers["OpenAI-Organization"], "org-1");
+
+    format_test_block("request_headers", || {
+        let mut names: Vec<_> = first.keys().cloned().collect();
+        names.sort();
+        names.join("\n")
+    });
+}
+
+async fn test_openrouter() {
+    assert!(is_openrouter(OPENROUTER_BASE_URL));
+    assert!(!is_openrouter("https://api.openai.com/v1/chat/completions"));
+
+    Config::add_api_source("stub-openrouter", OPENROUTER_BASE_URL, 2);
+    let transport = StubTransport {
+        status: 200,
+        ..Default::default()
+    };
+    let mut chat = BaseChat::new_with_api_info(
+        ApiInfo {
+            model: "openai/gpt-4o".to_string(),
+            base_url: OPENROUTER_BASE_URL.to_string(),
+            api_key: "sk-or".to_string(),
+            client: reqwest::Client::new(),
+            headers: HashMap::new(),
+        },
+        "",
+        false,
+    );
+    chat.set_transport(transport.clone());
+    chat.set_provider_preferences(
+        ProviderPreferences::new()
+            .with_order(["anthropic", "openai"])
+            .with_allow_fallbacks(false)
+            .with_data_collection(DataCollection::Deny)
+            .with_sort(ProviderSort::Price),
+    );
+    chat.add_message(Role::User, "你好").unwrap();
+    let request_body = chat
+        .build_request_body(&chat.session.default_path.clone(), &Role::User)
+        .await
+        .unwrap();
+    chat.get_response(request_body).await.unwrap();
+    let sent = transport.requests.lock().unwrap()[0].body.clone();
+    assert_eq!(
+        sent["provider"],
+        json!({"order": ["anthropic", "openai"], "allow_fallbacks": false, "data_collection": "deny", "sort": "price"})
+    );
+    assert_eq!(sent["usage"], json!({"include": true}));
+
+    Config::add_mock("mock-openrouter-usage", |_| {
+        MockReply::Raw(json!({
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "hello"}}],
+            "usage": {
+                "prompt_tokens": 20,
+                "completion_tokens": 8,
+                "total_tokens": 28,
+                "cost": 0.0025,
+                "prompt_tokens_details": {"cached_tokens": 12},
+                "completion_tokens_details": {"reasoning_tokens": 5},
+            },
+        }))
+    });
+    let mut chat = SingleChat::new_with_api_name("mock-openrouter-usage", "", false);
+    for question in ["第一问", "第二问"] {
+        let request_body = chat.get_req_body(question).await.unwrap();
+        assert!(request_body.get("provider").is_none());
+        chat.get_content_from_req_body(request_body).await.unwrap();
+    }
+    let timing = chat.base.last_timing().unwrap();
+    assert_eq!(timing.cost, Some(0.0025));
+    assert_eq!(timing.cached_tokens, Some(12));
+    assert_eq!(timing.reasoning_tokens, Some(5));
+    let stats = UsageTracker::stats("mock://mock-openrouter-usage", &chat.base.model).unwrap();
+    assert!((stats.total_cost - 0.005).abs() < 1e-9);
+    assert_eq!(stats.cached_tokens, 24);
+    assert_eq!(stats.reasoning_tokens, 10);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/api/v1/models", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+        }
+        let body = json!({"data": [{
+            "id": "openai/gpt-4o",
+            "name": "OpenAI: GPT-4o",
+            "context_length": 128000,
+            "pricing": {"prompt": "0.0000025", "completion": "0.00001"},
+            "supported_parameters": ["tools", "response_format"],
+        }]})
+        .to_string();
+        let response = format!("HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}", body.len());
+        socket.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&request).to_ascii_lowercase()
+    });
+    let models = list_models_from(&url, &reqwest::Client::new(), Some("sk-or")).await.unwrap();
+    let request = server.await.unwrap();
+    assert!(request.contains("authorization: bearer sk-or"));
+    assert_eq!(models.len(), 1);
+    assert_eq!(models[0].context_length, Some(128000));
+    assert_eq!(models[0].prompt_price(), Some(0.0000025));
+    assert!(models[0].supports("tools"));
+
+    format_test_block("openrouter", || format!("{:#?}\n{:#?}", sent["provider"], models));
+}
+
+async fn test_seed_and_repro_bundle() {
+    Config::add_mock("mock-seed", |body| MockReply::Text(format!("seed {}", body["seed"])));
+    let expected = ReproInfo {
+        seed: Some(42),
+        system_fingerprint: Some(MOCK_FINGERPRINT.to_string()),
+    };
+
+    let mut unseeded = SingleChat::new_with_api_name("mock-seed", "", false);
+    let request_body = unseeded.get_req_body("你好").await.unwrap();
+    assert!(request_body.get("seed").is_none());
+    assert!(unseeded.base.repro_bundle().is_none());
+
+    for stream in [false, true] {
+        let mut chat = SingleChat::new_with_api_name("mock-seed", "", stream);
+        chat.base.set_seed(Some(42));
+        let request_body = chat.get_req_body("你好").await.unwrap();
+        assert_eq!(request_body["seed"], 42);
+        let answer = chat.get_content_from_req_body(request_body).await.unwrap();
+        assert_eq!(answer, "seed 42");
+        assert_eq!(chat.base.last_repro_info(), expected);
+
+        let nodes = chat.base.session.nodes_along_path(&chat.base.session.default_path).unwrap();
+        let metadata = nodes.last().unwrap().metadata[REPRO_METADATA_KEY].clone();
+        assert_eq!(serde_json::from_value::<ReproInfo>(metadata).unwrap(), expected);
+    }
+
+    let mut chat = SingleChat::new_with_api_name("mock-seed", "", false);
+    chat.base.add_message(Role::System, "你是助手").unwrap();
+    chat.base.set_seed(Some(42));
+    let request_body = chat.get_req_body("你好").await.unwrap();
+    chat.get_content_from_req_body(request_body).await.unwrap();
+    let bundle = chat.base.repro_bundle().unwrap();
+    assert_eq!(bundle.info, expected);
+    assert_eq!(bundle.request["messages"][0]["content"], "你是助手");
+
+    let path = std::env::temp_dir().join(format!("rhine-repro-{}.json", std::process::id()));
+    bundle.save(&path).unwrap();
+    let loaded = ReproBundle::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, bundle);
+
+    let mut rerun_chat = BaseChat::new_with_api_name("mock-seed", "", true);
+    let rerun = loaded.rerun(&mut rerun_chat).await.unwrap();
+    assert_eq!(rerun, "seed 42");
+    assert_eq!(rerun_chat.last_repro_info(), expected);
+
+    format_test_block("repro_bundle", || serde_json::to_string_pretty(&loaded).unwrap());
+}
+
+async fn test_finish_reason() {
+    Config::add_mock("mock-length", |body| {
+        let messages = body["messages"].as_array().unwrap();
+        let continued = messages.last().unwrap()["content"] == CONTINUE_PROMPT;
+        let (text, reason) = if continued { ("world", "stop") } else { ("hello ", "length") };
+        let mut reply = MockApi::completion_body(body, text);
+        reply["choices"][0]["finish_reason"] = json!(reason);
+        MockReply::Raw(reply)
+    });
+
+    let mut truncated = SingleChat::new_with_api_name("mock-length", "", false);
+    let request_body = truncated.get_req_body("写一段话").await.unwrap();
+    assert_eq!(truncated.get_content_from_req_body(request_body).await.unwrap(), "hello ");
+    assert_eq!(truncated.base.last_finish_reason(), Some(FinishReason::Length));
+
+    let mut chat = SingleChat::new_with_api_name("mock-length", "", false);
+    chat.base.set_auto_continue(true);
+    let request_body = chat.get_req_body("写一段话").await.unwrap();
+    let answer = chat.get_content_from_req_body(request_body).await.unwrap();
+    assert_eq!(answer, "hello world");
+    assert_eq!(chat.base.last_finish_reason(), Some(FinishReason::Stop));
+    assert_eq!(chat.base.session.usage.requests, 2);
+    let nodes = chat.base.session.nodes_along_path(&chat.base.session.default_path).unwrap();
+    assert_eq!(nodes.len(), 2);
+    assert_eq!(nodes[1].content, "hello world");
+    assert_eq!(nodes[1].metadata[FINISH_REASON_METADATA_KEY], "stop");
+
+    Config::add_mock("mock-stop", |_| "完整回答".into());
+    let mut streaming = SingleChat::new_with_api_name("mock-stop", "", true);
+    let request_body = streaming.get_req_body("你好").await.unwrap();
+    streaming.get_content_from_req_body(request_body).await.unwrap();
+    assert_eq!(streaming.base.last_finish_reason(), Some(FinishReason::Stop));
+
+    assert_eq!(FinishReason::parse("max_tokens"), FinishReason::Length);
+    assert_eq!(FinishReason::parse("eos").as_str(), "eos");
+
+    format_test_block("finish_reason", || format!("answer: {answer}\nnodes: {nodes:#?}"));
+}
+
+async fn test_request_spans() {
+    Config::add_mock("mock-span", |body| {
+        let messages = body["messages"].as_array().unwrap();
+        let continued = messages.last().unwrap()["content"] == CONTINUE_PROMPT;
+        let (text, reason) = if continued { ("world", "stop") } else { ("hello ", "length") };
+        let mut reply = MockApi::completion_body(body, text);
+        reply["choices"][0]["finish_reason"] = json!(reason);
+        MockReply::Raw(reply)
+    });
+    Config::add_mock("mock-span-stream", |_| "流式回答".into());
+
+    let capture = LogCapture::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(capture.clone())
+        .finish();
+    let default = tracing::subscriber::set_default(subscriber);
+
+    let mut chat = SingleChat::new_with_api_name("mock-span", "", false);
+    chat.base.set_auto_continue(true);
+    let request_body = chat.get_req_body("写一段话").await.unwrap();
+    chat.get_content_from_req_body(request_body).await.unwrap();
+    assert_eq!(chat.base.retry_count, 0);
+
+    let mut streaming = SingleChat::new_with_api_name("mock-span-stream", "", true);
+    let request_body = streaming.get_req_body("你好").await.unwrap();
+    streaming.get_content_from_req_body(request_body).await.unwrap();
+
+    drop(default);
+    let logs = capture.contents();
+    let spans: Vec<_> = logs.lines().filter(|line| line.contains("chat_request{") && line.contains("close")).collect();
+    assert_eq!(spans.len(), 3);
+    assert!(spans[0].contains("retry_count=0") && spans[1].contains("retry_count=1"));
+    for span in &spans {
+        assert!(span.contains("total_tokens=") && span.contains("latency_ms=") && span.contains("outcome=\"ok\""));
+    }
+    assert!(spans[2].contains("stream=true"));
+
+    format_test_block("request_spans", || spans.join("\n"));
+}
+
+async fn test_preflight_validation() {
+    let mock = Config::add_mock("mock-preflight", |_| "ok".into());
+    Config::set_context_limit("mock-preflight", 60);
+    let mut chat = BaseChat::new_with_api_name("mock-preflight", "", false);
+
+    let invalid_reason = |error: error_stack::Report<ChatError>| match error.current_context() {
+        ChatError::InvalidRequest(reason) => reason.clone(),
+        other => panic!("unexpected error: {other:?}"),
+    };
+    let body = |messages: serde_json::Value| json!({"model": "mock-preflight", "messages": messages});
+
+    let error = chat.get_response(body(json!([]))).await.unwrap_err();
+    assert_eq!(invalid_reason(error), "`messages` is empty");
+    let error = chat.get_response(body(json!([{"role": "bot", "content": "hi"}]))).await.unwrap_err();
+    assert!(invalid_reason(error).contains("unsupported role `bot`"));
+    let error = chat.get_response(body(json!([{"role": "tool", "content": "42"}]))).await.unwrap_err();
+    assert!(invalid_reason(error).contains("tool_call_id"));
+
+    let long_question = "token ".repeat(100);
+    let error = chat
+        .get_response(body(json!([{"role": "user", "content": long_question}])))
+        .await
+        .unwrap_err();
+    let reason = invalid_reason(error);
+    assert!(reason.contains("60 token context limit"));
+
+    let mut reserved = body(json!([{"role": "user", "content": "hi"}]));
+    reserved["max_tokens"] = json!(100);
+    assert!(chat.get_response(reserved).await.is_err());
+
+    Config::set_max_output_tokens("mock-preflight", 20);
+    let mut capped = body(json!([{"role": "user", "content": "hi"}]));
+    capped["max_tokens"] = json!(30);
+    let error = chat.get_response(capped).await.unwrap_err();
+    assert!(invalid_reason(error).contains("20 token output limit"));
+    assert_eq!(mock.calls(), 0);
+
+    chat.add_message(Role::User, "hi").unwrap();
+    let request_body = chat.build_request_body(&chat.session.default_path.clone(), &Role::User).await.unwrap();
+    assert!(chat.get_response(request_body).await.is_ok());
+    assert_eq!(mock.calls(), 1);
+
+    Config::add_mock("mock-preflight-strict", |_| "ok".into());
+    Config::set_role_rules("mock-preflight-strict", RoleRules::StrictAlternation).unwrap();
+    let mut strict = BaseChat::new_with_api_name("mock-preflight-strict", "", true);
+    let repeated = json!({
+        "model": "mock-preflight-strict",
+        "messages": [{"role": "user", "content": "a"}, {"role": "user", "content": "b"}],
+    });
+    let error = strict.get_stream_content(repeated).await.unwrap_err();
+    assert!(invalid_reason(error).contains("without alternation"));
+
+    format_test_block("preflight_validation", || reason);
+}
+
+async fn test_image_generation() {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+    Config::add_mock("mock-image", move |body| {
+        recorded.lock().unwrap().push(body.clone());
+        let n = body["n"].as_u64().unwrap_or(1);
+        let item = match body["response_format"].as_str() {
+            Some("b64_json") => json!({"b64_json": "UE5HREFUQQ==", "revised_prompt": "a red fox, watercolor"}),
+            _ => json!({"url": "https://images.example/fox.png"}),
+        };
+        MockReply::Raw(json!({"created": 0, "data": vec![item; n as usize]}))
+    });
+
+    let generator = ImageGenerator::new_with_api_name("mock-image").unwrap();
+    let images = generator
+        .generate("a red fox", &ImageOptions::new().with_n(2).with_size("512x512"))
+        .await
+        .unwrap();
+    assert_eq!(images.len(), 2);
+    assert_eq!(images[0].url(), Some("https://images.example/fox.png"));
+    assert_eq!(requests.lock().unwrap()[0], json!({"model": "mock-image", "prompt": "a red fox", "n": 2, "size": "512x512"}));
+
+    let options = ImageOptions::new().with_response_format(ImageResponseFormat::Bytes);
+    let images = generator.generate("a red fox", &options).await.unwrap();
+    assert_eq!(images[0].bytes(), Some(b"PNGDATA".as_slice()));
+    assert_eq!(images[0].revised_prompt.as_deref(), Some("a red fox, watercolor"));
+
+    Config::add_mock("mock-image-busy", |_| MockReply::HttpError(429));
+    let error = ImageGenerator::new_with_api_name("mock-image-busy")
+        .unwrap()
+        .generate("a red fox", &ImageOptions::new())
+        .await
+        .unwrap_err();
+    assert!(matches!(error.current_context(), ImageError::HttpError(429)));
+
+    Config::add_api_source("image-responses", "http://localhost/v1/responses", 1);
+    Config::add_api_info("image-responses", "image-model", ImageGeneration, "image-responses", "");
+    let error = ImageGenerator::new_with_api_name("image-responses").unwrap_err();
+    assert!(matches!(error.current_context(), ImageError::InvalidEndpoint(_)));
+
+    format_test_block("image_generation", || format!("{images:#?}"));
+}
+
+async fn test_file_uploads() {
+    let mock = Config::add_mock("mock-files", |body| {
+        MockReply::Raw(json!({"id": "file-abc", "object": "file", "bytes": body["bytes"], "filename": body["filename"]}))
+    });
+    let path = std::env::temp_dir().join(format!("rhine-upload-{}.md", std::process::id()));
+    std::fs::write(&path, "# 报告\n第三季度营收增长 12%").unwrap();
+
+    let files = FileClient::new_with_api_name("mock-files").unwrap();
+    let file = files.upload(&path).await.unwrap();
+    assert_eq!(file.id, "file-abc");
+    assert_eq!(file.mime_type, "text/markdown");
+    assert_eq!(file.bytes, std::fs::metadata(&path).unwrap().len());
+    assert_eq!(files.upload(&path).await.unwrap(), file);
+    assert_eq!(mock.calls(), 1);
+
+    let mut chat = BaseChat::new_with_api_name("mock-files", "", false);
+    chat.add_message(Role::User, "总结附件").unwrap();
+    chat.update_session(|session| {
+        session.add_attachment(&session.default_path.clone(), Attachment::from_uploaded(file.clone()))
+    })
+    .unwrap();
+    let body = chat.build_request_body(&chat.session.default_path.clone(), &Role::User).await.unwrap();
+    assert_eq!(
+        body["messages"][0],
+        json!({
+            "role": "user",
+            "content": [
+                {"type": "text", "text": "总结附件"},
+                {"type": "file", "file": {"file_id": "file-abc"}},
+            ],
+        })
+    );
+    assert!(!body.to_string().contains(FILE_PARTS_KEY));
+    assert!(chat.get_response(body.clone()).await.is_ok());
+
+    files.delete(&file).await.unwrap();
+    assert!(Config::get_uploaded_file("mock://mock-files", &path).is_none());
+    std::fs::remove_file(&path).unwrap();
+
+    Config::add_api_source("gemini-files", "https://generativelanguage.googleapis.com/v1beta/openai/chat/completions", 1);
+    Config::add_api_info("gemini-files", "gemini-2.5-flash", Cheap, "gemini-files", "");
+    assert_eq!(FileClient::new_with_api_name("gemini-files").unwrap().provider(), FileProvider::Gemini);
+    let gemini_file = UploadedFile {
+        id: "files/xyz".to_string(),
+        name: "report.pdf".to_string(),
+        provider: FileProvider::Gemini,
+        mime_type: "application/pdf".to_string(),
+        bytes: 10,
+        uri: Some("https://generativelanguage.googleapis.com/v1beta/files/xyz".to_string()),
+    };
+    assert_eq!(gemini_file.content_part()["file"]["file_uri"], gemini_file.uri.clone().unwrap());
+
+    format_test_block("file_uploads", || serde_json::to_string_pretty(&body).unwrap());
+}
+
+async fn test_response_format_downgrade() {
+    Config::add_mock("mock-json-native", |body| {
+        if body.get("response_format").is_some() {
+            r#"{"name": "李雷", "age": 15, "grade": "freshman", "had_exam": true}"#.into()
+        } else {
+            "李雷，15岁".into()
+        }
+    });
+    let mut chat = SingleChat::new_with_api_name("mock-json-native", "", false);
+    let student = chat.get_json_answer::<StudentInfo>("编造一个学生信息").await.unwrap();
+    assert_eq!((student.name.as_str(), student.age), ("李雷", 15));
+    let nodes = chat.base.session.nodes_along_path(&chat.base.session.default_path).unwrap();
+    assert_eq!(nodes.last().unwrap().metadata[STRUCTURED_OUTPUT_METADATA_KEY], "response_format");
+    assert!(!Config::response_format_ignored("mock://mock-json-native"));
+
+    // 忽略 response_format 的来源：第一次回答不是 JSON 后不再附带 response_format
+    // A source ignoring response_format: once the first answer is not JSON, response_format is left out
+    let carried = Arc::new(Mutex::new(Vec::new()));
+    let seen = carried.clone();
+    Config::add_mock("mock-json-ignored", move |body| {
+        if body["messages"][0]["content"] == "将输入内容整理为指定的json形式输出" {
+            return r#"{"name": "李雷", "age": 15, "grade": "freshman", "had_exam": false}"#.into();
+        }
+        seen.lock().unwrap().push(body.get("response_format").is_some());
+        "李雷，15岁，大一".into()
+    });
+    let mut chat = SingleChat::new_with_api_name("mock-json-ignored", "", false);
+    // 修复固定交给调用方自己的模型，整理结果只取决于本来源的应答
+    // Repair is pinned to the caller's own model, so the reformatted result only depends on this source's reply
+    chat.base.set_json_repair(JsonRepair::Caller);
+    for question in ["编造一个学生信息", "再编造一个"] {
+        let repaired = chat.get_json_answer::<StudentInfo>(question).await.unwrap();
+        assert_eq!(
+            (repaired.name.as_str(), repaired.age, repaired.grade.as_deref(), repaired.had_exam),
+            ("李雷", 15, Some("freshman"), false)
+        );
+        let nodes = chat.base.session.nodes_along_path(&chat.base.session.default_path).unwrap();
+        assert_eq!(nodes.last().unwrap().metadata[STRUCTURED_OUTPUT_METADATA_KEY], "prompt_repair");
+        assert!(Config::response_format_ignored("mock://mock-json-ignored"));
+    }
+    assert_eq!(*carried.lock().unwrap(), [true, false]);
+
+    Config::set_response_format_ignored("mock://mock-json-ignored", false);
+    assert!(!Config::response_format_ignored("mock://mock-json-ignored"));
+
+    format_test_block("response_format_downgrade", || format!("{student:?}"));
+}
+
+async fn test_json_repair() {
+    let schema = StudentInfo::json_schema();
+    let student = r#"{"name": "韩梅梅", "age": 16, "grade": "sophomore", "had_exam": false}"#;
+
+    // 本地修复：代码块围栏、前后文字、多余逗号与截断的结尾
+    // Local repair: code fences, surrounding text, trailing commas and a truncated end
+    let fenced = format!("```json\n{}\n```", student.replace("false}", "false,}"));
+    let wrapped = format!("好的，结果如下：{student} 希望有帮助");
+    let truncated = &student[..student.len() - 1];
+    for text in [fenced.as_str(), &wrapped, truncated] {
+        let parsed = ChatTool::parse_json::<StudentInfo>(text, &schema).unwrap();
+        assert_eq!((parsed.name.as_str(), parsed.age), ("韩梅梅", 16));
+    }
+    assert!(ChatTool::parse_json::<StudentInfo>(r#"{"name": "韩梅梅"}"#, &schema).is_err());
+    assert!(ChatTool::parse_json::<StudentInfo>("韩梅梅，16岁", &schema).is_err());
+
+    // 默认不请求模型；`Caller` 用调用方自己的模型整理
+    // No model is asked by default; `Caller` reformats with the caller's own model
+    let format_prompt = "将输入内容整理为指定的json形式输出";
+    let reformatter = MockApi::new(move |body| {
+        if body["messages"][0]["content"] == format_prompt {
+            student.into()
+        } else {
+            "韩梅梅，16岁，大二".into()
+        }
+    });
+    Config::add_mock_api("mock-json-caller", Embedding, reformatter.clone());
+    let base = BaseChat::new_with_api_name("mock-json-caller", "", false);
+    let err = ChatTool::get_json_with_repair::<StudentInfo>(&base, "韩梅梅，16岁", schema.clone(), JsonRepair::Local)
+        .await
+        .unwrap_err();
+    assert!(matches!(err.current_context(), ChatError::GetJsonError));
+    assert_eq!(reformatter.calls(), 0);
+    let parsed =
+        ChatTool::get_json_with_repair::<StudentInfo>(&base, "韩梅梅，16岁", schema.clone(), JsonRepair::Caller)
+            .await
+            .unwrap();
+    assert_eq!(parsed.grade.as_deref(), Some("sophomore"));
+    assert_eq!(reformatter.calls(), 1);
+
+    let mut chat = SingleChat::new_with_api_name("mock-json-caller", "", false);
+    chat.base.set_json_repair(JsonRepair::Caller);
+    let answer = chat.get_json_answer::<StudentInfo>("编造一个学生信息").await.unwrap();
+    assert_eq!(answer.name, "韩梅梅");
+    assert_eq!(reformatter.calls(), 3);
+    let nodes = chat.base.session.nodes_along_path(&chat.base.session.default_path).unwrap();
+    assert_eq!(nodes.last().unwrap().metadata[STRUCTURED_OUTPUT_METADATA_KEY], "prompt_repair");
+    // 整理请求不写入调用方的会话
+    // The reformatting request stays out of the caller's session
+    assert!(nodes.iter().all(|node| node.content != format_prompt));
+
+    chat.base.set_json_repair(JsonRepair::Local);
+    assert!(chat.get_json_answer::<StudentInfo>("再编造一个").await.is_err());
+    assert_eq!(reformatter.calls(), 4);
+
+    // 新对话默认只在本地修复，不会额外请求模型
+    // A new chat repairs locally only by default and never asks another model
+    let mut plain = SingleChat::new_with_api_name("mock-json-caller", "", false);
+    assert_eq!(plain.base.json_repair, JsonRepair::Local);
+    assert!(plain.get_json_answer::<StudentInfo>("编造一个学生信息").await.is_err());
+    assert_eq!(reformatter.calls(), 5);
+
+    let fenced_answers = MockApi::new(move |_| format!("```json\n{student}\n```").into());
+    Config::add_mock_api("mock-json-fenced", Embedding, fenced_answers);
+    Config::set_response_format_ignored("mock://mock-json-fenced", true);
+    let mut chat = SingleChat::new_with_api_name("mock-json-fenced", "", false);
+    chat.base.set_json_repair(JsonRepair::Local);
+    chat.get_json_answer::<StudentInfo>("编造一个学生信息").await.unwrap();
+    let nodes = chat.base.session.nodes_along_path(&chat.base.session.default_path).unwrap();
+    assert_eq!(nodes.last().unwrap().metadata[STRUCTURED_OUTPUT_METADATA_KEY], "local_repair");
+
+    format_test_block("json_repair", || format!("{parsed:?}\n{answer:?}"));
+}
+
+fn test_json_extraction() {
+    let fenced = "结果如下：\n```json\n{\"a\": 1}\n```\n以上。";
+    assert_eq!(extract_json(fenced), Some(json!({"a": 1})));
+
+    // 字符串内的括号、逗号与斜杠保持原样，字符串外的注释与多余逗号被容忍
+    // Brackets, commas and slashes inside strings are kept; comments and trailing commas outside them are tolerated
+    let lenient = "用 {name} 占位，结果：{\"a\": [1, 2,], // 注释\n \"b\": \"x, ] // y\", /* 块 */ \"c\": {},}";
+    assert_eq!(extract_json(lenient), Some(json!({"a": [1, 2], "b": "x, ] // y", "c": {}})));
+
+    let several = "先给草稿 {\"draft\": true}，再给定稿：\n```\n[1, 2]\n```";
+    assert_eq!(json_candidates(several), [json!([1, 2]), json!({"draft": true})]);
+
+    assert_eq!(extract_json("{\"a\": [1, 2"), Some(json!({"a": [1, 2]})));
+    assert_eq!(extract_json("没有任何 JSON，只有 a < b"), None);
+
+    // 取第一个符合模式的候选
+    // The first candidate matching the schema is taken
+    let answer = "备注 {\"note\": \"x\"}\n学生：{\"name\": \"李雷\", \"age\": 15, \"grade\": \"junior\", \"had_exam\": true,}";
+    let student = ChatTool::parse_json::<StudentInfo>(answer, &StudentInfo::json_schema()).unwrap();
+    assert_eq!(student.grade.as_deref(), Some("junior"));
+
+    format_test_block("json_extraction", || format!("{:?}", json_candidates(lenient)));
+}
+
+async fn test_structured_stream() {
+    assert_eq!(parse_partial_json("```json\n{\"name\": \"李"), Some(json!({"name": "李"})));
+    assert_eq!(parse_partial_json("{\"name\": \"李雷\", \"ag"), Some(json!({"name": "李雷"})));
+    assert_eq!(parse_partial_json("{\"age\": 1"), Some(json!({"age": 1})));
+    assert_eq!(parse_partial_json("{\"ok\": tr"), Some(json!({})));
+    assert_eq!(parse_partial_json("{\"tags\": [\"a\", \"b\\"), Some(json!({"tags": ["a", "b"]})));
+    assert_eq!(parse_partial_json("{\"a\": {\"b\": [1, 2,"), Some(json!({"a": {"b": [1, 2]}})));
+    assert_eq!(parse_partial_json("{\"a\": 1}\n```"), Some(json!({"a": 1})));
+    assert_eq!(parse_partial_json("还没有开始"), None);
+
+    let mock = MockApi::new(|_| r#"{"name": "李雷", "age": 15, "grade": "junior", "had_exam": false}"#.into())
+        .with_chunk_chars(4);
+    Config::add_mock_api("mock-json-stream", Cheap, mock);
+    let snapshots = Arc::new(Mutex::new(Vec::new()));
+    let sink = snapshots.clone();
+    let mut chat = SingleChat::new_with_api_name("mock-json-stream", "", true);
+    chat.base.set_stream_callback(move |event| {
+        if let StreamEvent::PartialJson(value) = event {
+            sink.lock().unwrap().push(value.clone());
+        }
+    });
+    let student = chat.get_json_answer::<StudentInfo>("编造一个学生信息").await.unwrap();
+    assert_eq!(student.grade.as_deref(), Some("junior"));
+
+    let received = snapshots.clone();
+    let snapshots = received.lock().unwrap().clone();
+    assert!(snapshots.len() > 3);
+    assert!(snapshots.iter().any(|value| value.get("name").is_some() && value.get("age").is_none()));
+    assert_eq!(snapshots.last().unwrap()["had_exam"], false);
+
+    // 普通的流式回答不会给出结构化事件
+    // Plain streaming answers report no structured events
+    let request_body = chat.get_req_body("再说一遍").await.unwrap();
+    chat.get_content_from_req_body(request_body).await.unwrap();
+    assert_eq!(received.lock().unwrap().len(), snapshots.len());
+
+    format_test_block("structured_stream", || format!("{snapshots:#?}"));
+}
+
+async fn test_prompt_variables() {
+    assert_eq!(render_template("{{ a }}-{{b}}-{{c", |name| (name == "a").then(|| "1".to_string())), "1-{{b}}-{{c");
+
+    Config::add_mock("mock-variables", |_| "好的".into());
+    let mut chat = SingleChat::new_with_api_name("mock-variables", "", false);
+    chat.base.add_message(Role::System, "用户是{{user_name}}，今天是{{date}}，{{unknown}}").unwrap();
+    chat.base.set_prompt_variable("user_name", "李雷");
+    let day = Arc::new(AtomicUsize::new(1));
+    let counter = day.clone();
+    chat.base
+        .set_dynamic_prompt_variable("date", move || format!("第{}天", counter.load(Ordering::SeqCst)));
+
+    let body = chat.get_req_body("你好").await.unwrap();
+    assert_eq!(body["messages"][0]["content"], "用户是李雷，今天是第1天，{{unknown}}");
+    chat.get_content_from_req_body(body).await.unwrap();
+
+    day.store(2, Ordering::SeqCst);
+    chat.base.set_prompt_variable("user_name", "韩梅梅");
+    let body = chat.get_req_body("再见").await.unwrap();
+    assert_eq!(body["messages"][0]["content"], "用户是韩梅梅，今天是第2天，{{unknown}}");
+    // 会话中保存的仍是模板，用户消息不做替换
+    // The session keeps the template and user messages are not rendered
+    assert!(chat.base.session.message_roots[0].content.contains("{{user_name}}"));
+
+    let prompts = HashMap::from([("guide".to_string(), "你是{{city}}的导游".to_string())]);
+    let mut multi = MultiChat::new_with_api_name("mock-variables", prompts, false).unwrap();
+    multi.base.set_prompt_variable("city", "科隆");
+    multi.set_character("guide").unwrap();
+    let body = multi.get_req_body("介绍一下{{city}}").await.unwrap();
+    assert_eq!(body["messages"][0]["content"], "你是科隆的导游");
+    assert!(body.to_string().contains("介绍一下{{city}}"));
+
+    format_test_block("prompt_variables", || serde_json::to_string_pretty(&body["messages"]).unwrap());
+}
+
+async fn test_datetime_context() {
+    let leap_night = UNIX_EPOCH + Duration::from_secs(1709249400);
+    let utc = DateTimeContext::new().with_locale(None);
+    assert_eq!(utc.render_at(leap_night), "当前时间：2024-02-29 23:30（星期四），时区：UTC+00:00");
+    let shanghai = DateTimeContext::new()
+        .with_utc_offset(480, Some("Asia/Shanghai"))
+        .with_locale(Some("zh-CN"));
+    assert_eq!(
+        shanghai.render_at(leap_night),
+        "当前时间：2024-03-01 07:30（星期五），时区：UTC+08:00 (Asia/Shanghai)，语言区域：zh-CN"
+    );
+    let before_epoch = DateTimeContext::new().with_utc_offset(-150, None).with_locale(None);
+    assert_eq!(before_epoch.render_at(UNIX_EPOCH), "当前时间：1969-12-31 21:30（星期三），时区：UTC-02:30");
+
+    let now = Arc::new(AtomicUsize::new(1709249400));
+    let clock = now.clone();
+    let context = shanghai
+        .with_refresh(Duration::from_secs(3600))
+        .with_clock(move || UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::SeqCst) as u64));
+
+    Config::add_mock("mock-datetime", |_| "好的".into());
+    let mut chat = SingleChat::new_with_api_name("mock-datetime", "", false);
+    chat.base.add_message(Role::System, "你是助手").unwrap();
+    chat.base.add_context_provider(context);
+    let body = chat.get_req_body("现在几点").await.unwrap();
+    assert_eq!(body["messages"][0]["content"], "你是助手");
+    assert!(body["messages"][1]["content"].as_str().unwrap().starts_with("当前时间：2024-03-01 07:30"));
+    assert_eq!(body["messages"][2]["role"], "user");
+    chat.get_content_from_req_body(body).await.unwrap();
+
+    // 刷新间隔内沿用同一条说明，过期后重新生成；说明不写入会话
+    // The same note is reused within the refresh interval and regenerated afterwards; notes never enter the session
+    now.fetch_add(1800, Ordering::SeqCst);
+    let body = chat.get_req_body("现在呢").await.unwrap();
+    assert!(body["messages"][1]["content"].as_str().unwrap().contains("07:30"));
+    chat.get_content_from_req_body(body).await.unwrap();
+    now.fetch_add(1800, Ordering::SeqCst);
+    let body = chat.get_req_body("那现在呢").await.unwrap();
+    assert!(body["messages"][1]["content"].as_str().unwrap().contains("08:30"));
+    assert!(chat.base.session.message_roots.iter().all(|root| !root.content.starts_with("当前时间")));
+
+    format_test_block("datetime_context", || serde_json::to_string_pretty(&body["messages"]).unwrap());
+}
+
 async fn test_single_chat_get_json() {
     let mut chat = SingleChat::new_with_api_name("pumpkin-ds-r1", "", true);
     let answer = chat
@@ -142,3 +2127,449 @@ pub fn send_email(params: SendEmailParameters) {
         params.to, params.subject, params.body
     );
 }
+
+/// 第一次流式请求发出一块后停滞的测试替身传输层，之后的请求夹带心跳注释正常返回
+/// Test double transport whose first stream stalls after one chunk; later streams answer normally with heartbeat comments in between
+#[derive(Debug, Clone, Default)]
+struct StallingTransport {
+    streams: Arc<AtomicUsize>,
+}
+
+impl ChatTransport for StallingTransport {
+    fn send<'a>(
+        &'a self,
+        _request: &'a TransportRequest,
+    ) -> BoxFuture<'a, error_stack::Result<serde_json::Value, TransportError>> {
+        Box::pin(async move { Err(Report::new(TransportError::Network("streaming only".to_string()))) })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        _request: &'a TransportRequest,
+    ) -> BoxFuture<'a, error_stack::Result<ByteStream, TransportError>> {
+        Box::pin(async move {
+            let delta = |text: &str| {
+                let chunk = json!({"choices": [{"delta": {"content": text}}]});
+                Ok(Bytes::from(format!("data: {chunk}\n\n")))
+            };
+            let stream: ByteStream = if self.streams.fetch_add(1, Ordering::SeqCst) == 0 {
+                Box::pin(futures::stream::iter([delta("partial ")]).chain(futures::stream::pending()))
+            } else {
+                let keep_alive = Ok(Bytes::from(": keep-alive\n\n"));
+                Box::pin(futures::stream::iter([delta("full "), keep_alive, delta("answer")]))
+            };
+            Ok(stream)
+        })
+    }
+}
+
+async fn test_stream_stall() {
+    Config::add_mock_api(
+        "mock-stall",
+        Cheap,
+        MockApi::new(|_| MockReply::Text("slow answer".to_string())).with_latency(Duration::from_millis(200)),
+    );
+    Config::set_stall_policy("mock-stall", StallPolicy::abort(Duration::from_millis(50))).unwrap();
+
+    let mut stalled = SingleChat::new_with_api_name("mock-stall", "", true);
+    let request_body = stalled.get_req_body("你好").await.unwrap();
+    let error = stalled.get_content_from_req_body(request_body).await.unwrap_err();
+    assert!(matches!(error.current_context(), ChatError::StreamStalled));
+
+    let mut unwatched = SingleChat::new_with_api_name("mock-stall", "", false);
+    let request_body = unwatched.get_req_body("你好").await.unwrap();
+    assert_eq!(unwatched.get_content_from_req_body(request_body).await.unwrap(), "slow answer");
+
+    Config::add_api_source("stall", "stub://stall", 2);
+    Config::add_api_info("stall-model", "stall-model", Cheap, "stall", "sk-stall");
+    let transport = StallingTransport::default();
+    Config::set_transport("stall", transport.clone()).unwrap();
+    Config::set_stall_policy("stall", StallPolicy::retry(Duration::from_millis(50), 1)).unwrap();
+
+    let mut retried = SingleChat::new_with_api_name("stall-model", "", true);
+    let request_body = retried.get_req_body("你好").await.unwrap();
+    let answer = retried.get_content_from_req_body(request_body).await.unwrap();
+    assert_eq!(answer, "full answer");
+    assert_eq!(transport.streams.load(Ordering::SeqCst), 2);
+
+    format_test_block("stream_stall", || format!("answer: {answer}\nerror: {error:?}"));
+}
+
+async fn test_tool_prompt_caching() {
+    Config::add_mock("mock-tools", |_| "好的".into());
+    let system_prompts = |body: &serde_json::Value| {
+        body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|m| m["role"] == "system")
+            .map(|m| m["content"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let mut chat = SingleChat::new_with_api_name("mock-tools", "", false);
+    chat.set_tools(vec![send_email_tool_schema()]).unwrap();
+    chat.set_tools(vec![send_email_tool_schema()]).unwrap();
+    let request_body = chat.get_req_body("发一封邮件").await.unwrap();
+    let first = system_prompts(&request_body);
+    assert_eq!(first.len(), 1);
+    assert!(first[0].contains("Send an email to a given recipient"));
+    chat.get_content_from_req_body(request_body).await.unwrap();
+
+    chat.set_tools(vec![send_email_tool_schema()]).unwrap();
+    let request_body = chat.get_req_body("再发一封").await.unwrap();
+    assert_eq!(system_prompts(&request_body), first);
+    chat.get_content_from_req_body(request_body).await.unwrap();
+
+    chat.set_tools(Vec::new()).unwrap();
+    let request_body = chat.get_req_body("不用工具了").await.unwrap();
+    let replaced = system_prompts(&request_body);
+    assert_eq!(replaced.len(), 1);
+    assert!(!replaced[0].contains("Send an email to a given recipient"));
+
+    let root = &chat.base.session.message_roots[0];
+    assert_eq!(root.role, Role::System);
+    assert!(root.metadata.contains_key(TOOLS_METADATA_KEY));
+    assert_eq!(chat.base.session.default_path.len(), 6);
+
+    format_test_block("tool_prompt_caching", || format!("{:#?}", request_body["messages"]));
+}
+
+async fn test_selective_tools() {
+    let prompts = Arc::new(Mutex::new(Vec::new()));
+    let seen = prompts.clone();
+    Config::add_mock("mock-selective-tools", move |body| {
+        let system = body["messages"][0]["content"].as_str().unwrap_or_default().to_string();
+        seen.lock().unwrap().push(system);
+        "不需要调用工具".into()
+    });
+    let weather_tool = json!({
+        "type": "function",
+        "function": {
+            "name": "get_weather",
+            "description": "Get the current weather of a city.",
+            "parameters": {
+                "type": "object",
+                "properties": {"city": {"type": "string", "description": "City name."}},
+                "required": ["city"],
+            },
+        },
+    });
+
+    let mut chat = SingleChat::new_with_api_name("mock-selective-tools", "", false);
+    chat.set_tools(vec![send_email_tool_schema(), weather_tool]).unwrap();
+    let (answer, results) = chat.get_tool_answer_with(&["get_weather"], "北京天气如何").await.unwrap();
+    assert_eq!(answer, "不需要调用工具");
+    assert!(results.is_empty());
+    chat.get_tool_answer("再问一次").await.unwrap();
+
+    let prompts = prompts.lock().unwrap().clone();
+    assert!(prompts[0].contains("get_weather"));
+    assert!(!prompts[0].contains("Send an email to a given recipient"));
+    assert!(prompts[1].contains("get_weather"));
+    assert!(prompts[1].contains("Send an email to a given recipient"));
+
+    let error = chat.get_tool_answer_with(&["delete_files"], "删除文件").await.unwrap_err();
+    assert!(matches!(error.current_context(), ToolCallError::FunctionNotFound(name) if name == "delete_files"));
+
+    format_test_block("selective_tools", || prompts[0].clone());
+}
+
+async fn test_structured_tool_calls() {
+    let schema = register_tool(
+        "word_count",
+        json!({
+            "type": "function",
+            "function": {
+                "name": "word_count",
+                "description": "统计单词数",
+                "parameters": {
+                    "type": "object",
+                    "properties": {"text": {"type": "string"}},
+                    "required": ["text"],
+                },
+            },
+        }),
+        |args| Ok(json!(args["text"].as_str().unwrap_or_default().split_whitespace().count())),
+    )
+    .unwrap();
+    let tools_schema = vec![schema];
+
+    let calls = [
+        ToolCall::new("word_count", json!({"text": "a b c"})),
+        ToolCall::new("word_count", json!({})),
+        ToolCall::new("no_such_tool", json!({})),
+    ];
+    let results: Vec<ToolResult> = calls.iter().map(|call| execute_tool_call(call, &tools_schema)).collect();
+    assert_ne!(calls[0].id, calls[1].id);
+    assert!(results.iter().zip(&calls).all(|(result, call)| result.id == call.id));
+    assert_eq!(results[0], ToolResult::ok(&calls[0], "3".to_string()));
+    assert!(results[1].is_error && results[1].output.contains("invalid_arguments"));
+    assert!(results[2].is_error && results[2].output.contains("no_such_tool"));
+
+    // 出错的结果是结构化载荷，模型可按其中的参数模式修正后重试
+    // Failed results are structured payloads the model can retry from, using the parameters schema in them
+    let invalid = ToolError::from_output(&results[1].output).unwrap();
+    assert_eq!(invalid.kind, ToolErrorKind::InvalidArguments);
+    assert_eq!(invalid.tool.as_deref(), Some("word_count"));
+    assert_eq!(invalid.violations[0].path, "/text");
+    assert_eq!(invalid.expected_schema.as_ref(), Some(&tools_schema[0]["function"]["parameters"]));
+    let unknown = ToolError::from_output(&results[2].output).unwrap();
+    assert_eq!(unknown.kind, ToolErrorKind::UnknownTool);
+    assert!(unknown.message.contains("word_count"));
+    assert!(ToolError::from_output(&results[0].output).is_none());
+
+    let mut chat = SingleChat::new_with_api_name("mock-echo", "", false);
+    chat.base.add_message(Role::User, "数一下 a b c").unwrap();
+    chat.base.add_message(Role::Assistant, "<ToolUse>word_count</ToolUse>").unwrap();
+    chat.add_tool_results(&results[..1]).unwrap();
+    let body = chat
+        .base
+        .build_request_body(&chat.base.session.default_path.clone(), &Role::User)
+        .await
+        .unwrap();
+    assert_eq!(body["messages"][2], json!({"role": "user", "content": "3"}));
+
+    let mut native = body.clone();
+    native["tools"] = json!(tools_schema);
+    chat.base.get_response(native).await.unwrap();
+    chat.add_tool_results(&results[..1]).unwrap();
+    let body = chat
+        .base
+        .build_request_body(&chat.base.session.default_path.clone(), &Role::User)
+        .await
+        .unwrap();
+    assert_eq!(body["messages"][3], json!({"role": "tool", "tool_call_id": calls[0].id, "content": "3"}));
+
+    format_test_block("structured_tool_calls", || format!("{:#?}", results));
+}
+
+async fn test_multi_chat_tools() {
+    Config::add_mock("mock-stage-tools", |body| {
+        let systems = body["messages"].as_array().unwrap().iter().filter(|m| m["role"] == "system").count();
+        MockReply::Text(format!("{systems} 条系统消息"))
+    });
+    let search_tool = json!({
+        "type": "function",
+        "function": {
+            "name": "web_search",
+            "description": "Search the web.",
+            "parameters": {
+                "type": "object",
+                "properties": {"query": {"type": "string", "description": "Search query."}},
+                "required": ["query"],
+            },
+        },
+    });
+
+    let prompts = HashMap::from([
+        ("researcher".to_string(), "你是研究员".to_string()),
+        ("critic".to_string(), "你是评论员".to_string()),
+    ]);
+    let mut chat = MultiChat::new_with_api_name("mock-stage-tools", prompts, false).unwrap();
+    chat.set_tools("researcher", vec![search_tool.clone()]).unwrap();
+    let error = chat.set_tools("narrator", vec![search_tool.clone()]).unwrap_err();
+    assert!(matches!(error.current_context(), ChatError::UndefinedCharacter(_)));
+    assert_eq!(chat.tools("researcher"), [search_tool]);
+    assert!(chat.tools("critic").is_empty());
+
+    let (answer, results) = chat.tool_dialogue("researcher", "查一下莱茵河").await.unwrap();
+    assert_eq!(answer, "2 条系统消息");
+    assert!(results.is_empty());
+    let body = chat.get_req_body_again(&chat.base.session.default_path.clone()).await.unwrap();
+    assert!(body["messages"][1]["content"].as_str().unwrap().contains("web_search"));
+
+    let (answer, _) = chat.tool_dialogue("critic", "评价一下").await.unwrap();
+    assert_eq!(answer, "1 条系统消息");
+
+    chat.set_tools("researcher", Vec::new()).unwrap();
+    let (answer, _) = chat.tool_dialogue("researcher", "再查一次").await.unwrap();
+    assert_eq!(answer, "1 条系统消息");
+    assert!(chat.base.session.message_roots.iter().all(|root| root.role != Role::System));
+
+    format_test_block("multi_chat_tools", || format!("{:#?}", body["messages"]));
+}
+
+async fn test_chat_handle() {
+    Config::add_mock("mock-actor", |body| {
+        let messages = body["messages"].as_array().unwrap();
+        format!("回答：{}", messages.last().unwrap()["content"].as_str().unwrap()).into()
+    });
+    let handle = ChatHandle::spawn(SingleChat::new_with_api_name("mock-actor", "", false));
+    let questions: Vec<String> = (0..6).map(|i| format!("问题{i}")).collect();
+    let answers = futures::future::join_all(questions.iter().map(|question| {
+        let handle = handle.clone();
+        async move { handle.ask(question).await.unwrap() }
+    }))
+    .await;
+    for (question, answer) in questions.iter().zip(&answers) {
+        assert_eq!(answer, &format!("回答：{question}"));
+    }
+
+    // 每轮问答都完整地落在默认路径上，问题与回答不会交错
+    // Every turn lands whole on the default path; questions and answers never interleave
+    let session = handle.snapshot().await.unwrap();
+    let nodes = session.nodes_along_path(&session.default_path).unwrap();
+    assert_eq!(nodes.len(), questions.len() * 2);
+    for turn in nodes.chunks(2) {
+        assert_eq!(turn[0].role, Role::User);
+        assert_eq!(turn[1].content, format!("回答：{}", turn[0].content));
+    }
+
+    let mock = MockApi::new(|_| "流式的回答".into()).with_chunk_chars(2);
+    Config::add_mock_api("mock-actor-stream", Cheap, mock);
+    let handle = ChatHandle::spawn(SingleChat::new_with_api_name("mock-actor-stream", "", true));
+    let mut events = handle.subscribe_stream();
+    let answer = handle.send(UserMessage::new("讲讲")).await.unwrap().unwrap();
+    assert_eq!(answer.content, "流式的回答");
+    assert_eq!(answer.path, handle.snapshot().await.unwrap().default_path);
+    let mut streamed = String::new();
+    while let Ok(event) = events.try_recv() {
+        if let StreamEvent::Text(text) = event {
+            streamed.push_str(&text);
+        }
+    }
+    assert_eq!(streamed, answer.content);
+
+    format_test_block("chat_handle", || format!("{answers:?}"));
+}
+
+async fn test_chat_events() {
+    Config::add_mock("mock-events", |_| "事件回答".into());
+    let events = ChatEvents::new();
+    let mut receiver = events.subscribe();
+    let mut chat = SingleChat::new_with_api_name("mock-events", "", false);
+    chat.base.set_events(events.clone());
+    let request_body = chat.get_req_body("你好").await.unwrap();
+    chat.get_content_from_req_body(request_body).await.unwrap();
+
+    let mut received = Vec::new();
+    while let Ok(event) = receiver.try_recv() {
+        received.push(event);
+    }
+    let model = chat.base.model.clone();
+    assert_eq!(
+        received,
+        vec![
+            ChatEvent::MessageAdded {
+                path: vec![0],
+                role: Role::User,
+                content: "你好".to_string(),
+            },
+            ChatEvent::AnswerStarted {
+                model: model.clone(),
+                stream: false,
+            },
+            ChatEvent::AnswerCompleted {
+                model: model.clone(),
+                content: "事件回答".to_string(),
+            },
+            ChatEvent::MessageAdded {
+                path: vec![0, 0],
+                role: Role::Assistant,
+                content: "事件回答".to_string(),
+            },
+        ]
+    );
+
+    // 流式回答逐段给出增量，失败的请求给出错误事件
+    // Streaming answers report each delta and failed requests report an error event
+    Config::add_mock_api("mock-events-stream", Cheap, MockApi::new(|_| "流式回答".into()).with_chunk_chars(2));
+    let mut chat = SingleChat::new_with_api_name("mock-events-stream", "", true);
+    chat.base.set_events(events.clone());
+    let request_body = chat.get_req_body("讲讲").await.unwrap();
+    chat.get_content_from_req_body(request_body).await.unwrap();
+    let deltas: String = std::iter::from_fn(|| receiver.try_recv().ok())
+        .filter_map(|event| match event {
+            ChatEvent::TokenDelta { delta } => Some(delta),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(deltas, "流式回答");
+
+    Config::add_mock("mock-events-error", |_| MockReply::HttpError(400));
+    let mut chat = SingleChat::new_with_api_name("mock-events-error", "", false);
+    chat.base.set_events(events.clone());
+    let request_body = chat.get_req_body("你好").await.unwrap();
+    assert!(chat.get_content_from_req_body(request_body).await.is_err());
+    let error = std::iter::from_fn(|| receiver.try_recv().ok()).last().unwrap();
+    assert!(matches!(error, ChatEvent::Error { .. }));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/events", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        let body = loop {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                continue;
+            };
+            let length = head
+                .lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|n| n.trim().to_string()))
+                .and_then(|length| length.parse::<usize>().ok())
+                .unwrap_or_default();
+            if body.len() >= length {
+                break body.to_string();
+            }
+        };
+        socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+        body
+    });
+    let webhook = events.add_webhook(&url);
+    events.emit(ChatEvent::TokenDelta {
+        delta: "钩子".to_string(),
+    });
+    let delivered: serde_json::Value =
+        serde_json::from_str(&tokio::time::timeout(std::time::Duration::from_secs(5), server).await.unwrap().unwrap())
+            .unwrap();
+    webhook.abort();
+    assert_eq!(delivered, json!({"type": "token_delta", "delta": "钩子"}));
+
+    format_test_block("chat_events", || {
+        received.iter().map(|event| serde_json::to_string(event).unwrap()).collect::<Vec<_>>().join("\n")
+    });
+}
+
+async fn test_prompt_stages() {
+    let prompt = Prompt {
+        character_prompts: HashMap::from([("assistant".to_string(), "你是面试官".to_string())]),
+        stage_prompts: HashMap::from([
+            ("warmup".to_string(), "先做自我介绍".to_string()),
+            ("coding".to_string(), "现在出一道编程题".to_string()),
+        ]),
+    };
+    let mut chat = SingleChat::new_with_api_name("mock-echo", "", false).with_prompt(&prompt).unwrap();
+    assert_eq!(chat.base.character_prompt, "你是面试官");
+    assert_eq!(chat.current_stage(), None);
+
+    chat.enter_stage("warmup").unwrap();
+    chat.enter_stage("coding").unwrap();
+    assert_eq!(chat.current_stage().as_deref(), Some("coding"));
+    assert_eq!(chat.base.session.get_metadata(STAGE_METADATA_KEY), Some(&json!("coding")));
+
+    let request_body = chat.get_req_body("开始吧").await.unwrap();
+    let messages = request_body["messages"].as_array().unwrap();
+    let contents: Vec<_> = messages.iter().map(|message| message["content"].as_str().unwrap()).collect();
+    assert_eq!(contents, ["你是面试官", "先做自我介绍", "现在出一道编程题", "开始吧"]);
+    assert!(messages[..3].iter().all(|message| message["role"] == "system"));
+
+    let err = chat.enter_stage("offer").unwrap_err();
+    assert!(matches!(err.current_context(), ChatError::UndefinedStage(stage) if stage == "offer"));
+    assert_eq!(chat.current_stage().as_deref(), Some("coding"));
+
+    let unbound = SingleChat::new_with_api_name("mock-echo", "", false).enter_stage("warmup");
+    assert!(unbound.is_err());
+    let missing = Prompt {
+        character_prompts: HashMap::new(),
+        stage_prompts: HashMap::new(),
+    };
+    assert!(SingleChat::new_with_api_name("mock-echo", "", false).with_prompt(&missing).is_err());
+
+    format_test_block("prompt_stages", || format!("{}", json!(messages)));
+}
@@ -0,0 +1,253 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::chat::limits::ModelLimits;
+use crate::config::{AuxiliaryTask, Config, ConfigError, ModelCapability};
+use crate::runtime::Runtime;
+use crate::tests::{format_test_block, LogCapture};
+use crate::utils::common::body_log::{
+    body_attachment, set_body_log_policy, text_attachment, truncate_middle, BodyLogPolicy,
+};
+use crate::utils::common::redact::{redact, redact_json, RedactingWriter};
+
+pub async fn test_config() {
+    test_auxiliary_capability();
+    test_redaction();
+    test_body_log();
+    test_config_file();
+    test_model_limits();
+    test_model_discovery().await;
+}
+
+fn test_auxiliary_capability() {
+    assert_eq!(
+        Config::get_auxiliary_capability(&AuxiliaryTask::Summarize),
+        ModelCapability::Cheap
+    );
+
+    Config::set_auxiliary_capability(AuxiliaryTask::Judge, ModelCapability::Fast);
+    assert_eq!(
+        Config::get_auxiliary_capability(&AuxiliaryTask::Judge),
+        ModelCapability::Fast
+    );
+    Config::set_auxiliary_capability(AuxiliaryTask::Judge, ModelCapability::Think);
+
+    format_test_block("auxiliary_capability", || {
+        format!(
+            "JsonFormat: {:?}\nSummarize: {:?}",
+            Config::get_auxiliary_capability(&AuxiliaryTask::JsonFormat),
+            Config::get_auxiliary_capability(&AuxiliaryTask::Summarize)
+        )
+    });
+}
+
+fn test_redaction() {
+    let api_key = "test-key-0123456789abcdef";
+    Config::add_api_source("redaction", "http://localhost/v1/chat/completions", 1);
+    Config::add_api_info("redaction-model", "model", ModelCapability::Fast, "redaction", api_key);
+
+    let api_info = Config::get_api_info_with_name("redaction-model".to_string()).unwrap();
+    assert!(!format!("{:?}", api_info).contains(api_key));
+
+    let text = redact(&format!("Authorization: Bearer abc.def-123 key {api_key}"));
+    assert!(!text.contains("abc.def-123"));
+    assert!(!text.contains(api_key));
+
+    let body = redact_json(&json!({"model": "m", "api_key": "plain", "usage": {"total_tokens": 3}}));
+    assert_eq!(body["api_key"], "***");
+    assert_eq!(body["usage"]["total_tokens"], 3);
+
+    // 过短的密钥不注册，密钥只在作为完整词元时遮盖
+    // Too-short secrets are not registered, and secrets are masked only as whole tokens
+    Config::add_api_info("redaction-short", "model", ModelCapability::Fast, "redaction", "replay");
+    assert_eq!(redact("replay the recorded answer"), "replay the recorded answer");
+    let embedded = format!("x{api_key}y and {api_key}.");
+    assert_eq!(redact(&embedded), format!("x{api_key}y and tes***."));
+
+    // 经过遮盖写入器的日志语句无需自行调用 redact
+    // Log statements going through the redacting writer need no redact call of their own
+    let capture = LogCapture::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(RedactingWriter::new(capture.clone()))
+        .finish();
+    tracing::subscriber::with_default(subscriber, || tracing::info!("calling with {api_key}"));
+    assert!(capture.contents().contains("calling with tes***"));
+    assert!(!capture.contents().contains(api_key));
+
+    format_test_block("redaction", || format!("{}\n{}", text, body));
+}
+
+fn test_body_log() {
+    assert_eq!(truncate_middle("short", 16), "short");
+    assert_eq!(truncate_middle("0123456789abcdef", 8), "0123…[8 bytes omitted]…cdef");
+    // 切分点不落在多字节字符中间
+    // Cuts never fall inside a multi-byte char
+    assert_eq!(truncate_middle("一二三四五六", 7), "一…[12 bytes omitted]…六");
+
+    set_body_log_policy(BodyLogPolicy::new().with_max_bytes(Some(64)));
+    let body = json!({"api_key": "plain", "messages": [{"role": "user", "content": "x".repeat(10_000)}]});
+    let truncated = body_attachment("Request body", &body);
+    assert!(truncated.starts_with("Request body: {\"api_key\":\"***\""));
+    assert!(truncated.contains("bytes omitted]…"));
+    assert!(truncated.len() < 128);
+
+    set_body_log_policy(BodyLogPolicy::new().with_sample_every(3));
+    let sampled = (0..6).map(|_| text_attachment("Answer", "abc")).collect::<Vec<_>>();
+    assert_eq!(sampled.iter().filter(|a| *a == "Answer: abc").count(), 2);
+    assert_eq!(sampled[1], "Answer: omitted by sampling (3 bytes)");
+
+    set_body_log_policy(BodyLogPolicy::new().with_min_interval(Some(Duration::from_secs(60))));
+    assert_eq!(text_attachment("Answer", "abc"), "Answer: abc");
+    assert_eq!(text_attachment("Answer", "abc"), "Answer: omitted by sampling (3 bytes)");
+
+    set_body_log_policy(BodyLogPolicy::default());
+
+    format_test_block("body_log", || format!("{}\n{:?}", truncated, sampled));
+}
+
+fn test_config_file() {
+    let path = std::env::temp_dir().join(format!("rhine-config-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"
+[[source]]
+name = "file-source"
+base_url = "http://localhost/v1/chat/completions"
+headers = { "HTTP-Referer" = "https://example.com", "X-Title" = "rhine" }
+
+[[api]]
+name = "file-fast"
+model = "small-model"
+capability = "fast"
+source = "file-source"
+api_key = "file-key-0123456789"
+headers = { "X-Title" = "fast" }
+
+[[api]]
+name = "file-long"
+model = "long-model"
+capability = "long_context"
+source = "file-source"
+context_limit = 200000
+max_output_tokens = 8000
+"#,
+    )
+    .unwrap();
+
+    let names = Config::load_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(names, ["file-fast", "file-long"]);
+    let api_info = Config::get_api_info_with_name("file-fast".to_string()).unwrap();
+    assert_eq!(api_info.model, "small-model");
+    assert_eq!(api_info.api_key, "file-key-0123456789");
+    assert_eq!(api_info.headers["HTTP-Referer"], "https://example.com");
+    assert_eq!(api_info.headers["X-Title"], "fast");
+    let long = Config::get_api_info_with_name("file-long".to_string()).unwrap();
+    assert_eq!(long.headers["X-Title"], "rhine");
+    assert_eq!(Config::get_context_limit("long-model"), Some(200000));
+    assert_eq!(Config::get_context_limit("small-model"), None);
+    assert_eq!(Config::get_model_limits("long-model").max_output_tokens, Some(8000));
+
+    std::fs::write(
+        &path,
+        "[[api]]\nname = \"orphan\"\nmodel = \"m\"\ncapability = \"fast\"\nsource = \"missing\"\n",
+    )
+    .unwrap();
+    let err = Config::load_file(path.to_str().unwrap()).unwrap_err();
+    assert!(matches!(err.current_context(), ConfigError::ApiSourceNotFound(source) if source == "missing"));
+
+    let _ = std::fs::remove_file(&path);
+    format_test_block("config_file", || format!("{:?}", names));
+}
+
+fn test_model_limits() {
+    let gpt = Config::get_model_limits("gpt-4o");
+    assert_eq!(gpt.context_window, Some(128_000));
+    assert_eq!(gpt.max_output_tokens, Some(16_384));
+    // 带服务商前缀与版本后缀的名称按最长前缀匹配
+    // Names with a provider prefix and a version suffix match the longest prefix
+    assert_eq!(Config::get_model_limits("openai/gpt-4o-mini-2024-07-18"), gpt);
+    assert_eq!(Config::get_context_limit("gpt-4.1-nano"), Some(1_047_576));
+    assert_eq!(Config::get_model_limits("unknown-model"), ModelLimits::default());
+
+    let model = "claude-3-5-sonnet-20241022";
+    Config::set_context_limit(model, 100_000);
+    let overridden = Config::get_model_limits(model);
+    assert_eq!(overridden.context_window, Some(100_000));
+    assert_eq!(overridden.max_output_tokens, Some(8_192));
+    assert_eq!(overridden.prompt_budget(), Some(91_808));
+
+    Config::set_max_output_tokens("output-only-model", 1_000);
+    assert_eq!(Config::get_model_limits("output-only-model").prompt_budget(), None);
+
+    format_test_block("model_limits", || format!("{:?}\n{:?}", gpt, overridden));
+}
+
+/// 在本地端口上应答一次模型列表请求，返回对应的基础 URL 与收到的请求
+/// Answer one model list request on a local port; returns the matching base URL and the received request
+async fn serve_model_list() -> (String, tokio::task::JoinHandle<String>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}/v1/chat/completions", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+        }
+        let body = json!({"object": "list", "data": [{"id": "vision-model"}, {"id": "other-model"}]}).to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&request).to_string()
+    });
+    (base_url, server)
+}
+
+async fn test_model_discovery() {
+    let (base_url, server) = serve_model_list().await;
+
+    Config::add_api_source("discovery", &base_url, 1);
+    Config::add_api_info("discovery-ghost", "ghost-model", ModelCapability::Vision, "discovery", "discovery-key");
+    // 发现之前不做校验
+    // Nothing is verified before discovery
+    assert_eq!(Config::get_api_info_with_capability(ModelCapability::Vision).unwrap().model, "ghost-model");
+    assert!(Config::get_discovered_models(&base_url).is_none());
+
+    let models = Config::discover_models("discovery").await.unwrap();
+    let request = server.await.unwrap();
+    assert!(request.starts_with("GET /v1/models "));
+    assert!(request.to_ascii_lowercase().contains("authorization: bearer discovery-key"));
+    assert_eq!(models, ["vision-model", "other-model"]);
+    assert_eq!(Config::get_discovered_models(&base_url), Some(models.clone()));
+
+    let err = Config::get_api_info_with_capability(ModelCapability::Vision).unwrap_err();
+    assert!(matches!(err.current_context(), ConfigError::ModelNotFound { model, .. } if model == "ghost-model"));
+
+    Config::add_api_info("discovery-vision", "vision-model", ModelCapability::Vision, "discovery", "discovery-key");
+    assert_eq!(Config::get_api_info_with_capability(ModelCapability::Vision).unwrap().model, "vision-model");
+
+    assert!(Config::discover_models("missing").await.is_err());
+
+    // 租户运行时的发现结果只缓存在自身，并用于自身的能力查找
+    // A tenant runtime caches its discovery results in itself only and uses them for its own capability lookups
+    let (tenant_url, tenant_server) = serve_model_list().await;
+    let tenant = Runtime::new();
+    tenant.add_api_source("tenant-discovery", &tenant_url, 1);
+    tenant
+        .add_api_info("tenant-ghost", "ghost-model", ModelCapability::Vision, "tenant-discovery", "tenant-key")
+        .unwrap();
+    assert_eq!(tenant.discover_models("tenant-discovery").await.unwrap(), models);
+    assert!(tenant_server.await.unwrap().to_ascii_lowercase().contains("authorization: bearer tenant-key"));
+    assert_eq!(tenant.get_discovered_models(&tenant_url), Some(models.clone()));
+    assert!(Config::get_discovered_models(&tenant_url).is_none());
+    let tenant_err = tenant.get_api_info_with_capability(ModelCapability::Vision).unwrap_err();
+    assert!(matches!(tenant_err.current_context(), ConfigError::ModelNotFound { model, .. } if model == "ghost-model"));
+
+    format_test_block("model_discovery", || format!("{:?}\n{}", models, err));
+}
@@ -0,0 +1,78 @@
+use serde_json::json;
+
+use crate::chat::chat_single::SingleChat;
+use crate::chat::judge::Judge;
+use crate::chat::mock::MockReply;
+use crate::config::Config;
+use crate::eval::{Experiment, Variant, Verdict};
+use crate::tests::format_test_block;
+
+pub async fn test_eval() {
+    test_experiment().await;
+}
+
+async fn test_experiment() {
+    let last_content = |body: &serde_json::Value| {
+        body["messages"].as_array().unwrap().last().unwrap()["content"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string()
+    };
+    Config::add_mock("mock-eval-terse", move |body| {
+        let question = last_content(body);
+        if question.contains("失败") {
+            MockReply::HttpError(400)
+        } else {
+            MockReply::Text(format!("简短：{question}"))
+        }
+    });
+    Config::add_mock("mock-eval-verbose", move |body| MockReply::Text(format!("详细：{}", last_content(body))));
+    Config::add_mock("mock-eval-judge", |body| {
+        let prompt = body["messages"][1]["content"].as_str().unwrap_or_default();
+        let quality = if prompt.contains("平局") {
+            3
+        } else if prompt.contains("详细") {
+            5
+        } else {
+            2
+        };
+        MockReply::from(json!({"quality": quality}).to_string())
+    });
+
+    let rubric = json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": "quality",
+            "strict": true,
+            "schema": {
+                "type": "object",
+                "properties": {"quality": {"type": "integer", "minimum": 0, "maximum": 5, "description": "回答质量"}},
+                "required": ["quality"],
+            },
+        },
+    });
+    let experiment = Experiment::new(
+        Variant::new("terse", SingleChat::new_with_api_name("mock-eval-terse", "", false), ""),
+        Variant::new("verbose", SingleChat::new_with_api_name("mock-eval-verbose", "", false), "请回答：{input}"),
+    )
+    .with_judge(Judge::new_with_api_name("mock-eval-judge"), rubric)
+    .with_concurrency(2);
+
+    let report = experiment.run(["什么是所有权?", "平局", "失败"]).await;
+    let verdicts = report.cases.iter().map(|case| case.verdict).collect::<Vec<_>>();
+    assert_eq!(verdicts, vec![Some(Verdict::B), Some(Verdict::Tie), None]);
+    assert_eq!(report.cases[0].b.answer.as_deref(), Some("详细：请回答：什么是所有权?"));
+    assert_eq!((report.a.wins, report.b.wins, report.ties), (0, 1, 1));
+    assert_eq!(report.a.win_rate, Some(0.25));
+    assert_eq!(report.b.win_rate, Some(0.75));
+    assert_eq!((report.a.failures, report.b.failures), (1, 0));
+    assert_eq!(report.b.mean_scores["quality"], 13.0 / 3.0);
+    assert!(report.b.prompt_tokens > 0);
+
+    let markdown = report.to_markdown();
+    assert!(markdown.contains("| Metric | terse | verbose |"));
+    assert!(markdown.contains("| Win rate | 25.0% | 75.0% |"));
+    assert!(markdown.contains("| Failures | 1/3 | 0/3 |"));
+
+    format_test_block("experiment", || markdown);
+}
@@ -0,0 +1,79 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_json::json;
+
+use crate::chat::chat_base::ChatError;
+use crate::chat::chat_single::SingleChat;
+use crate::chat::mock::MockReply;
+use crate::config::Config;
+use crate::guard::{
+    DenyList, FnGuard, GuardChain, GuardOutcome, GuardVerdict, JsonSchemaGuard, MaxLength,
+    OnViolation,
+};
+use crate::tests::format_test_block;
+
+pub async fn test_guard() {
+    test_guard_chain();
+    test_guard_retry().await;
+}
+
+fn test_guard_chain() {
+    let chain = GuardChain::new()
+        .add(DenyList::new(&[r"(?i)as an ai"]).unwrap(), OnViolation::Truncate)
+        .add(MaxLength::new(5), OnViolation::Truncate);
+    assert_eq!(
+        chain.apply("Sure. As an AI model", 0).unwrap(),
+        GuardOutcome::Accept("Sure.".to_string())
+    );
+    assert_eq!(
+        chain.apply("hello world", 0).unwrap(),
+        GuardOutcome::Accept("hello".to_string())
+    );
+
+    let chain = GuardChain::new().add(
+        FnGuard::new("no_empty", |text| {
+            if text.trim().is_empty() {
+                GuardVerdict::Violation { message: "empty".to_string(), valid_prefix: None }
+            } else {
+                GuardVerdict::Pass
+            }
+        }),
+        OnViolation::Error,
+    );
+    assert!(chain.apply("  ", 0).is_err());
+
+    format_test_block("guard_chain", || "deny-list, max length and closure guards passed".to_string());
+}
+
+async fn test_guard_retry() {
+    let replies = Arc::new(AtomicUsize::new(0));
+    let counter = replies.clone();
+    Config::add_mock("mock-guard", move |_| {
+        match counter.fetch_add(1, Ordering::SeqCst) {
+            0 => MockReply::from("not json"),
+            _ => MockReply::from("```json\n{\"score\": 3}\n```"),
+        }
+    });
+
+    let schema = json!({
+        "type": "object",
+        "properties": {"score": {"type": "integer", "minimum": 0, "maximum": 5}},
+        "required": ["score"],
+    });
+    let mut chat = SingleChat::new_with_api_name("mock-guard", "", false);
+    chat.base.set_guards(GuardChain::new().add(JsonSchemaGuard::new(schema.clone()), OnViolation::Retry(1)));
+    let resp = chat.get_req_body("打分").await.unwrap();
+    let answer = chat.get_content_from_req_body(resp).await.unwrap();
+    assert_eq!(answer, "{\"score\": 3}");
+    assert_eq!(replies.load(Ordering::SeqCst), 2);
+
+    let mut chat = SingleChat::new_with_api_name("mock-guard", "", false);
+    chat.base.set_guards(GuardChain::new().add(JsonSchemaGuard::new(schema), OnViolation::Error));
+    replies.store(0, Ordering::SeqCst);
+    let resp = chat.get_req_body("打分").await.unwrap();
+    let err = chat.get_content_from_req_body(resp).await.unwrap_err();
+    assert!(matches!(err.current_context(), ChatError::GuardViolation));
+
+    format_test_block("guard_retry", || format!("answer: {}", answer));
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::agent::react::ReActAgent;
+use crate::chat::chat_single::SingleChat;
+use crate::chat::mock::MockReply;
+use crate::config::Config;
+use crate::memory::{Embedder, MemoryError, MemoryStore};
+use crate::tests::format_test_block;
+
+pub async fn test_memory() {
+    let dir = std::env::temp_dir().join(format!("rhine-memory-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    test_memory_store(&dir).await;
+    test_memory_in_agent(&dir).await;
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// 以字符出现次数作为向量的测试用向量化
+/// Test embedder using character counts as vectors
+#[derive(Debug)]
+struct CharEmbedder;
+
+impl Embedder for CharEmbedder {
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, error_stack::Result<Vec<Vec<f32>>, MemoryError>> {
+        let embeddings = texts
+            .iter()
+            .map(|text| {
+                let mut vector = vec![0.0; 64];
+                text.chars().for_each(|c| vector[c as usize % 64] += 1.0);
+                vector
+            })
+            .collect();
+        Box::pin(async move { Ok(embeddings) })
+    }
+}
+
+async fn test_memory_store(dir: &std::path::Path) {
+    let path = dir.join("memory.json");
+    let store = MemoryStore::open(&path).unwrap();
+    store.set("user_name", "Amiya").unwrap();
+    store.add_note("用户喜欢简短的回答").unwrap();
+    store.add_note("the project deadline is friday").unwrap();
+
+    let reopened = MemoryStore::open(&path).unwrap().with_embedder(CharEmbedder);
+    assert_eq!(reopened.get("user_name").as_deref(), Some("Amiya"));
+    assert_eq!(reopened.notes().len(), 2);
+
+    let keyword = reopened.keyword_search("deadline friday", 1);
+    assert_eq!(keyword[0].1.text, "the project deadline is friday");
+
+    let semantic = reopened.search("when is the deadline", 1).await.unwrap();
+    assert_eq!(semantic[0].1.text, "the project deadline is friday");
+    assert!(reopened.notes().iter().all(|note| note.embedding.is_some()));
+
+    format_test_block("memory_store", || format!("keyword: {:?}\nsemantic: {:?}", keyword, semantic));
+}
+
+async fn test_memory_in_agent(dir: &std::path::Path) {
+    let store = Arc::new(MemoryStore::open(dir.join("agent.json")).unwrap());
+    store.add_note("用户的猫叫做 Kal'tsit").unwrap();
+
+    Config::add_mock("mock-memory", |body| {
+        let system = body["messages"][0]["content"].as_str().unwrap_or_default();
+        if system.contains("Kal'tsit") {
+            MockReply::from("Thought: 记忆中有答案\nFinal Answer: Kal'tsit")
+        } else {
+            MockReply::from("Thought: 不知道\nFinal Answer: 不知道")
+        }
+    });
+
+    let mut agent = ReActAgent::new(SingleChat::new_with_api_name("mock-memory", "", false), vec![])
+        .with_memory(store.clone());
+    let (answer, _) = agent.run("我的猫叫什么?").await.unwrap();
+    assert_eq!(answer, "Kal'tsit");
+
+    format_test_block("memory_in_agent", || format!("answer: {}", answer));
+}
@@ -1,6 +1,188 @@
+use proptest::prelude::*;
+use proptest::test_runner::TestRunner;
+
+use crate::chat::finetune::{FinetuneBranch, FinetuneOptions};
+use crate::chat::message::{MessageError, Role, Session};
+use crate::tests::format_test_block;
+
+pub async fn test_context_assembly() {
+    test_context_inherits_root_system();
+    test_context_properties();
+}
+
+/// 随机树中的一次插入：父节点序号（对已有节点数取模，超出则新建根）、角色、是否私有（根不会设为私有）
+/// One insertion into a random tree: parent index (modulo the existing nodes, past them a new root), role, privacy (roots stay public)
+type Insertion = (usize, u8, bool);
+
+fn role_of(kind: u8) -> Role {
+    match kind % 3 {
+        0 => Role::System,
+        1 => Role::User,
+        _ => Role::Assistant,
+    }
+}
+
+/// 依次插入消息，返回会话与每条消息的路径（按插入顺序，内容为 `m{序号}`）
+/// Insert messages in turn; returns the session and the path of every message (in insertion order, content `m{index}`)
+fn build_session(insertions: &[Insertion]) -> (Session, Vec<Vec<usize>>) {
+    let mut session = Session::new();
+    let mut paths: Vec<Vec<usize>> = Vec::new();
+    for (i, &(parent, kind, private)) in insertions.iter().enumerate() {
+        let parent_path = match parent % (paths.len() + 2) {
+            idx if idx < paths.len() => paths[idx].clone(),
+            _ => Vec::new(),
+        };
+        session
+            .add_with_parent_path(&parent_path, role_of(kind), format!("m{i}"))
+            .unwrap();
+        let path = session.default_path.clone();
+        if private && path.len() > 1 {
+            session.set_private(&path, Some("alice")).unwrap();
+        }
+        paths.push(path);
+    }
+    (session, paths)
+}
+
+fn test_context_inherits_root_system() {
+    let mut session = Session::new();
+    session.add_with_default_path(Role::System, "sys".into()).unwrap();
+    session.add_with_default_path(Role::User, "main".into()).unwrap();
+    session.new_tree("side", Role::User, "side".into()).unwrap();
+    session.add_with_default_path(Role::Assistant, "reply".into()).unwrap();
+
+    let context = session.assemble_context(&session.default_path, &Role::Assistant).unwrap();
+    let contents: Vec<_> = context.iter().map(|m| m["content"].as_str()).collect();
+    assert_eq!(contents, ["sys", "side", "reply"]);
+    assert_eq!(context[0]["role"], "system");
+
+    let context = session.assemble_context(&[0, 0], &Role::Assistant).unwrap();
+    let contents: Vec<_> = context.iter().map(|m| m["content"].as_str()).collect();
+    assert_eq!(contents, ["sys", "main"]);
+
+    assert!(matches!(session.assemble_context(&[], &Role::Assistant), Err(MessageError::InvalidPath)));
+    assert!(matches!(session.assemble_context(&[0, 5], &Role::Assistant), Err(MessageError::InvalidPath)));
+    assert!(matches!(session.assemble_context(&[9], &Role::Assistant), Err(MessageError::InvalidPath)));
+    assert!(matches!(
+        session.add_with_parent_path(&[9], Role::User, "lost".into()),
+        Err(MessageError::InvalidPath)
+    ));
+    assert!(matches!(
+        session.add_with_parent_path(&[9, 0], Role::User, "lost".into()),
+        Err(MessageError::InvalidPath)
+    ));
+
+    format_test_block("context_inherits_root_system", || format!("{:?}", context));
+}
+
+fn test_context_properties() {
+    let insertions = prop::collection::vec((any::<usize>(), any::<u8>(), any::<bool>()), 1..40);
+    let mut runner = TestRunner::default();
+    runner
+        .run(&insertions, |insertions| {
+            let (session, paths) = build_session(&insertions);
+            let root_system = session.root_system_message().map(|m| m.content.clone());
+
+            for end in &paths {
+                let nodes = session.nodes_along_path(end).unwrap();
+                let context = session.assemble_context(end, &Role::Assistant).unwrap();
+                let contents: Vec<_> = context.iter().map(|m| m["content"].clone()).collect();
+
+                // 根 → 终点顺序，跳过私有消息，必要时以会话级系统消息开头
+                // Root → end order without private messages, led by the session-wide system message when inherited
+                let mut expected: Vec<_> = nodes
+                    .iter()
+                    .filter(|node| node.private_to.is_none())
+                    .map(|node| node.content.clone())
+                    .collect();
+                if nodes[0].role != Role::System
+                    && let Some(system) = &root_system
+                {
+                    expected.insert(0, system.clone());
+                }
+                prop_assert_eq!(&contents, &expected);
+
+                // 同一路径的组装结果是确定的
+                // Assembling the same path is deterministic
+                prop_assert_eq!(&context, &session.assemble_context(end, &Role::Assistant).unwrap());
+
+                // 会话有系统消息时上下文总以系统消息开头，且会话级系统消息至多出现一次
+                // With a session system message the context always leads with a system message,
+                // and the session-wide one appears at most once
+                if let Some(system) = &root_system {
+                    prop_assert_eq!(&context[0]["role"], "system");
+                    prop_assert!(contents.iter().filter(|c| *c == system).count() <= 1);
+                }
+
+                // 私有消息对其所属角色可见
+                // Private messages are visible to their owner
+                let owner_view = session.assemble_context(end, &Role::Character("alice".into())).unwrap();
+                let inherited = usize::from(nodes[0].role != Role::System && root_system.is_some());
+                prop_assert_eq!(owner_view.len(), nodes.len() + inherited);
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    format_test_block("context_properties", || "random trees passed".to_string());
+}
+
 // use crate::tests::format_test_block;
 // use crate::chat::message::{Messages, Role};
 //
+pub async fn test_finetune_export() {
+    let mut session = Session::new();
+    session.add_with_default_path(Role::System, "sys".into()).unwrap();
+    session.add_with_default_path(Role::User, "q1".into()).unwrap();
+    session.add_with_default_path(Role::Assistant, "a1".into()).unwrap();
+    session.add_with_default_path(Role::User, "dangling".into()).unwrap();
+    session.add_with_parent_path(&[0, 0], Role::Assistant, "a2".into()).unwrap();
+    session.add_with_parent_path(&[0], Role::User, "unanswered".into()).unwrap();
+
+    let records = session.finetune_records(&FinetuneBranch::All, &FinetuneOptions::default()).unwrap();
+    let transcripts: Vec<Vec<&str>> = records
+        .iter()
+        .map(|r| r["messages"].as_array().unwrap().iter().map(|m| m["content"].as_str().unwrap()).collect())
+        .collect();
+    assert_eq!(transcripts, [vec!["sys", "q1", "a1"], vec!["sys", "q1", "a2"]]);
+
+    let options = FinetuneOptions::new()
+        .with_system(false)
+        .with_trim_trailing(false)
+        .with_min_assistant_messages(0)
+        .with_role_name("user", "human");
+    let records = session.finetune_records(&FinetuneBranch::Path(vec![0, 1]), &options).unwrap();
+    assert_eq!(records[0]["messages"], serde_json::json!([{"role": "human", "content": "unanswered"}]));
+
+    let mut multi = Session::new();
+    multi.add_with_default_path(Role::User, "topic".into()).unwrap();
+    multi.add_with_default_path(Role::Character("alice".into()), "hi".into()).unwrap();
+    multi.add_with_default_path(Role::Character("bob".into()), "secret".into()).unwrap();
+    multi.set_private(&multi.default_path.clone(), Some("bob")).unwrap();
+    multi.add_with_default_path(Role::Character("bob".into()), "hello".into()).unwrap();
+    let alice = FinetuneOptions::new().with_speaker(Role::Character("alice".into()));
+    let records = multi.finetune_records(&FinetuneBranch::Default, &alice).unwrap();
+    assert_eq!(records[0]["messages"].as_array().unwrap().len(), 2);
+    let bob = FinetuneOptions::new().with_speaker(Role::Character("bob".into()));
+    let records = multi.finetune_records(&FinetuneBranch::Default, &bob).unwrap();
+    assert_eq!(records[0]["messages"][1]["content"], "alice said: hi");
+    assert_eq!(records[0]["messages"][3]["role"], "assistant");
+
+    assert!(matches!(
+        session.finetune_records(&FinetuneBranch::Tree("missing".into()), &FinetuneOptions::default()),
+        Err(MessageError::UndefinedTree(_))
+    ));
+
+    let path = std::env::temp_dir().join(format!("rhine-finetune-{}.jsonl", std::process::id()));
+    let written = session.export_finetune_jsonl(&path, &FinetuneBranch::All).unwrap();
+    let jsonl = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(written, 2);
+    assert_eq!(jsonl.lines().count(), 2);
+
+    format_test_block("finetune_export", || jsonl);
+}
+
 // pub async fn test_message() {
 //     test_message_creation();
 //     test_add_message();
@@ -1,10 +1,37 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
 use tracing::log::info;
-use crate::tests::prompt::test_prompt;
+use tracing_subscriber::fmt::MakeWriter;
+use crate::tests::prompt::{test_hidden_fields, test_injection_detection, test_nested_properties, test_prompt_compression, test_prompt_format, test_prompt_snapshot, test_sanitize, test_schema_cache, test_schema_diff, test_schema_enum, test_section_order, test_template_overrides, test_union_properties};
 use crate::tests::chat::test_chat;
+use crate::tests::config::test_config;
+use crate::tests::scheduler::test_scheduler;
+use crate::tests::agent::test_agent;
+use crate::tests::memory::test_memory;
+use crate::tests::guard::test_guard;
+use crate::tests::message::{test_context_assembly, test_finetune_export};
+use crate::tests::tool_use::test_tool_use_extraction;
+use crate::tests::pipeline::test_pipeline;
+use crate::tests::eval::test_eval;
+#[cfg(feature = "server")]
+use crate::tests::server::test_server;
+use crate::tests::storage::test_storage;
 
 mod prompt;
 mod message;
 mod chat;
+mod config;
+mod scheduler;
+mod agent;
+mod memory;
+mod guard;
+mod tool_use;
+mod pipeline;
+mod eval;
+mod storage;
+#[cfg(feature = "server")]
+mod server;
 
 
 #[tokio::test]
@@ -19,7 +46,63 @@ pub async fn test() {
         .init();
     println!("log level: {}", "info");
     // test_prompt().await;
+    test_config().await;
+    test_scheduler().await;
     test_chat().await;
+    test_agent().await;
+    test_memory().await;
+    test_guard().await;
+    test_context_assembly().await;
+    test_finetune_export().await;
+    test_tool_use_extraction().await;
+    test_pipeline().await;
+    test_eval().await;
+    test_prompt_compression().await;
+    test_schema_enum().await;
+    test_schema_cache().await;
+    test_schema_diff().await;
+    test_nested_properties().await;
+    test_union_properties().await;
+    test_hidden_fields().await;
+    test_template_overrides().await;
+    test_prompt_snapshot().await;
+    test_prompt_format().await;
+    test_sanitize().await;
+    test_injection_detection().await;
+    test_section_order().await;
+    test_storage().await;
+    #[cfg(feature = "server")]
+    test_server().await;
+}
+
+/// 收集日志输出的写入器，用于检查订阅器写出的内容
+/// Writer collecting log output, for checking what a subscriber writes
+#[derive(Clone, Default)]
+pub struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+impl LogCapture {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogCapture {
+    type Writer = LogCapture;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
 }
 
 pub fn format_test_block<F>(title: &str, content_fn: F)
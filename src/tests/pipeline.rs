@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rhine_schema_derive::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::chat::chat_single::SingleChat;
+use crate::chat::mock::MockReply;
+use crate::config::Config;
+use crate::pipeline::batch::BatchRunner;
+use crate::pipeline::{input_id, load_dataset, DatasetBuilder, DatasetFormat};
+use crate::schema::json_schema::JsonSchema;
+use crate::tests::format_test_block;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schema(name = "word_info", description = "单词信息", strict = true)]
+pub struct WordInfo {
+    #[schema(desc = "单词本身")]
+    word: String,
+
+    #[schema(desc = "字母数")]
+    length: i32,
+}
+
+pub async fn test_pipeline() {
+    test_dataset_builder().await;
+    test_batch_runner().await;
+}
+
+async fn test_dataset_builder() {
+    let attempts: Arc<Mutex<HashMap<String, usize>>> = Arc::default();
+    let counter = attempts.clone();
+    let mock = Config::add_mock("mock-dataset", move |body| {
+        let question = body["messages"].as_array().unwrap().last().unwrap()["content"]
+            .as_str()
+            .unwrap_or_default()
+            .trim_start_matches("单词：")
+            .to_string();
+        let mut attempts = counter.lock().unwrap();
+        let attempt = attempts.entry(question.clone()).or_default();
+        *attempt += 1;
+        match question.as_str() {
+            "flaky" if *attempt == 1 => MockReply::from("not json"),
+            "broken" => MockReply::from("{\"word\": 1}"),
+            word => MockReply::from(format!(
+                "```json\n{{\"word\": \"{word}\", \"length\": {}}}\n```",
+                word.len()
+            )),
+        }
+    });
+
+    let chat = SingleChat::new_with_api_name("mock-dataset", "", false);
+    let builder = DatasetBuilder::<WordInfo>::new(chat, "单词：{input}")
+        .with_concurrency(2)
+        .with_max_retries(1)
+        .with_retry_delay(Duration::ZERO);
+
+    let dir = std::env::temp_dir().join(format!("rhine-dataset-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let jsonl = dir.join("words.jsonl");
+
+    let inputs = ["apple", "flaky", "broken", "pear", "apple"];
+    let summary = builder.run(inputs, &jsonl).await.unwrap();
+    assert_eq!(summary.written, 3);
+    assert_eq!(summary.skipped, 1);
+    assert_eq!(summary.failed.len(), 1);
+    assert_eq!(summary.failed[0].input, "broken");
+    assert_eq!(summary.failed[0].attempts, 2);
+    assert_eq!(attempts.lock().unwrap()["flaky"], 2);
+
+    let records = load_dataset::<WordInfo>(&jsonl).unwrap();
+    assert_eq!(records.len(), 3);
+    let pear = records.iter().find(|r| r.input == "pear").unwrap();
+    assert_eq!(pear.id, input_id("pear"));
+    assert_eq!(pear.output, WordInfo { word: "pear".into(), length: 4 });
+
+    // 续跑只会重新尝试失败的和新的输入
+    // A resumed run only tries the failed and the new inputs
+    let calls = mock.calls();
+    let summary = builder.run(["apple", "pear", "broken", "fig"], &jsonl).await.unwrap();
+    assert_eq!((summary.written, summary.skipped, summary.failed.len()), (1, 2, 1));
+    assert_eq!(mock.calls() - calls, 3);
+    assert_eq!(load_dataset::<WordInfo>(&jsonl).unwrap().len(), 4);
+
+    let csv = dir.join("words.csv");
+    let builder = builder.with_format(DatasetFormat::Csv);
+    builder.run(["kiwi", "a,b"], &csv).await.unwrap();
+    let summary = builder.run(["kiwi", "a,b", "plum"], &csv).await.unwrap();
+    assert_eq!((summary.written, summary.skipped), (1, 2));
+    let content = std::fs::read_to_string(&csv).unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut lines = content.lines();
+    assert_eq!(lines.next(), Some("id,input,length,word"));
+    assert_eq!(content.lines().count(), 4);
+    assert!(content.contains(&format!("{},\"a,b\",3,\"a,b\"", input_id("a,b"))));
+
+    format_test_block("dataset_builder", || content);
+}
+
+async fn test_batch_runner() {
+    let attempts: Arc<Mutex<HashMap<String, usize>>> = Arc::default();
+    let counter = attempts.clone();
+    let mock = Config::add_mock("mock-batch-runner", move |body| {
+        let question = body["messages"].as_array().unwrap().last().unwrap()["content"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let mut attempts = counter.lock().unwrap();
+        let attempt = attempts.entry(question.clone()).or_default();
+        *attempt += 1;
+        match question.as_str() {
+            "flaky" if *attempt == 1 => MockReply::HttpError(500),
+            "broken" => MockReply::HttpError(500),
+            other => MockReply::from(format!("answer to {other}")),
+        }
+    });
+
+    let dir = std::env::temp_dir().join(format!("rhine-batch-runner-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let output = dir.join("answers.jsonl");
+    let chat = SingleChat::new_with_api_name("mock-batch-runner", "", false);
+    let runner = BatchRunner::new(chat, &output)
+        .with_concurrency(2)
+        .with_retry_budget(2)
+        .with_retry_delay(Duration::ZERO);
+
+    let items = [("a", "apple"), ("f", "flaky"), ("b", "broken")];
+    let report = runner.run(items).await.unwrap();
+    assert_eq!((report.completed, report.completed_this_run), (2, 2));
+    assert_eq!(report.exhausted["b"].attempts, 2);
+    assert!(report.pending.is_empty());
+    assert!(report.usage.total_tokens > 0);
+
+    let state = runner.load_state().unwrap();
+    assert_eq!(state.completed.iter().map(String::as_str).collect::<Vec<_>>(), ["a", "f"]);
+    assert_eq!(state.usage, report.usage);
+
+    // 模拟两次保存之间的崩溃：输出中已有 `d`，状态文件中没有
+    // Simulate a crash between two checkpoints: `d` is in the output but not in the state file
+    let mut content = std::fs::read_to_string(&output).unwrap();
+    content.push_str(r#"{"id":"d","input":"date","output":"answer to date"}"#);
+    std::fs::write(&output, content).unwrap();
+
+    let calls = mock.calls();
+    let runner = runner.with_retry_budget(3);
+    let items = [("a", "apple"), ("f", "flaky"), ("b", "broken"), ("d", "date"), ("e", "elder")];
+    let report = runner.run(items).await.unwrap();
+    assert_eq!(mock.calls() - calls, 2);
+    assert_eq!((report.completed, report.completed_this_run), (4, 1));
+    assert_eq!(report.exhausted["b"].attempts, 3);
+    assert!(report.usage.total_tokens > state.usage.total_tokens);
+
+    let records = load_dataset::<String>(&output).unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(records.len(), 4);
+    assert_eq!(records.last().unwrap().output, "answer to elder");
+
+    format_test_block("batch_runner", || format!("{:?}", report));
+}
@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use error_stack::Result;
+use futures::future::BoxFuture;
+use tokio::sync::Semaphore;
+
+use crate::chat::chat_base::{BaseChat, ChatError};
+use crate::chat::chat_single::SingleChat;
+use crate::chat::mock::MockReply;
+use crate::chat::rate_limit::{estimate_request_tokens, LocalRateLimiter, RateLimit, RateLimitBackend, RateLimitError};
+use crate::chat::scheduler::{source_paused_until, PriorityScheduler, RequestPriority};
+use crate::chat::transport::parse_retry_after;
+use crate::config::{AuxiliaryTask, Config};
+use crate::tests::format_test_block;
+
+pub async fn test_scheduler() {
+    test_priority_order().await;
+    test_retry_after().await;
+    test_rate_limit().await;
+}
+
+async fn test_priority_order() {
+    let scheduler = Arc::new(PriorityScheduler::new(Arc::new(Semaphore::new(1))));
+    let order = Arc::new(Mutex::new(Vec::new()));
+
+    let holding = scheduler.acquire(RequestPriority::Normal).await;
+
+    let mut tasks = Vec::new();
+    for (queued, (name, priority)) in [
+        ("background", RequestPriority::Background),
+        ("normal", RequestPriority::Normal),
+        ("high", RequestPriority::High),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let task_scheduler = scheduler.clone();
+        let order = order.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = task_scheduler.acquire(priority).await;
+            order.lock().unwrap().push(name);
+        }));
+        // 上一个请求入队后才放出下一个，入队顺序与生成顺序一致
+        // Release the next request only once the previous one is queued, so they queue in spawn order
+        while scheduler.queued() <= queued {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    assert_eq!(scheduler.queued(), 3);
+    drop(holding);
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let order = order.lock().unwrap().clone();
+    assert_eq!(order, vec!["high", "normal", "background"]);
+
+    Config::add_mock("mock-priority", |_| "ok".into());
+    let chat = SingleChat::new_with_api_name("mock-priority", "", false);
+    let summarize = BaseChat::new_with_auxiliary_task(AuxiliaryTask::Summarize, "", false);
+    assert_eq!(summarize.priority, RequestPriority::Background);
+    assert_eq!(chat.base.new_auxiliary_chat(AuxiliaryTask::JsonFormat, "").unwrap().priority, RequestPriority::Background);
+    assert_eq!(chat.base.new_task_chat("").priority, RequestPriority::Background);
+    assert_eq!(chat.base.priority, RequestPriority::Normal);
+    format_test_block("priority_order", || format!("{:?}", order));
+}
+
+async fn test_retry_after() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784111767);
+    assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+    assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now), Some(Duration::from_secs(10)));
+    assert_eq!(parse_retry_after("Sat, 05 Nov 1994 08:49:37 GMT", now), Some(Duration::ZERO));
+    assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:49:37 PST", now), None);
+    assert_eq!(parse_retry_after("soon", now), None);
+
+    let delay = Duration::from_millis(300);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    Config::add_mock("mock-rate-limited", move |_| match counter.fetch_add(1, Ordering::SeqCst) {
+        0 => MockReply::RateLimited(delay),
+        _ => MockReply::from("ok"),
+    });
+
+    let mut chat = SingleChat::new_with_api_name("mock-rate-limited", "", false);
+    let request_body = chat.get_req_body("busy").await.unwrap();
+    let error = chat.get_content_from_req_body(request_body).await.unwrap_err();
+    assert!(matches!(error.current_context(), ChatError::HttpError(429)));
+    let paused_until = source_paused_until("mock://mock-rate-limited").unwrap();
+
+    // 暂停期间的请求等到暂停结束才发出
+    // A request during the pause is sent only after it ends
+    let started = Instant::now();
+    let request_body = chat.get_req_body("hello").await.unwrap();
+    let answer = chat.get_content_from_req_body(request_body).await.unwrap();
+    assert_eq!(answer, "ok");
+    assert!(Instant::now() >= paused_until);
+    assert!(started.elapsed() >= delay / 2);
+    assert!(source_paused_until("mock://mock-rate-limited").is_none());
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    format_test_block("retry_after", || format!("{error:?}"));
+}
+
+/// 先要求等待一次再放行的计数后端，记录每次登记的令牌数
+/// Counter backend that asks for one wait before admitting; records the tokens of every reservation
+#[derive(Debug, Default)]
+struct ScriptedBackend {
+    reservations: Arc<Mutex<Vec<u64>>>,
+}
+
+impl RateLimitBackend for ScriptedBackend {
+    fn reserve<'a>(&'a self, _key: &'a str, _limit: RateLimit, tokens: u64) -> BoxFuture<'a, Result<Duration, RateLimitError>> {
+        let mut reservations = self.reservations.lock().unwrap();
+        reservations.push(tokens);
+        let delay = if reservations.len() == 1 { Duration::from_millis(50) } else { Duration::ZERO };
+        Box::pin(async move { Ok(delay) })
+    }
+}
+
+async fn test_rate_limit() {
+    let limiter = LocalRateLimiter::new();
+    let limit = RateLimit::new().with_requests_per_minute(2).with_tokens_per_minute(100);
+    let start = Duration::from_secs(600);
+    assert_eq!(limiter.reserve_at("a", limit, 40, start), Duration::ZERO);
+    assert_eq!(limiter.reserve_at("a", limit, 70, start), Duration::from_secs(60));
+    assert_eq!(limiter.reserve_at("a", limit, 60, start + Duration::from_secs(15)), Duration::ZERO);
+    assert_eq!(limiter.reserve_at("a", limit, 1, start + Duration::from_secs(20)), Duration::from_secs(40));
+    // 新窗口重新计数，超过令牌限额的单次请求在空窗口中放行
+    // A new window starts from zero and a single request over the token quota gets into an empty window
+    assert_eq!(limiter.reserve_at("a", limit, 500, start + Duration::from_secs(60)), Duration::ZERO);
+    assert_eq!(limiter.reserve_at("b", limit, 1, start), Duration::ZERO);
+
+    Config::add_mock("mock-rate-limited", |_| "好的".into());
+    let mut chat = SingleChat::new_with_api_name("mock-rate-limited", "", false);
+    let body = chat.get_req_body("你好").await.unwrap();
+    let tokens = estimate_request_tokens(&body);
+    assert!(tokens > 0);
+
+    let backend = ScriptedBackend::default();
+    let reservations = backend.reservations.clone();
+    Config::set_rate_limit("mock-rate-limited", RateLimit::new().with_tokens_per_minute(1000)).unwrap();
+    Config::set_rate_limit_backend("mock-rate-limited", backend).unwrap();
+    let started = Instant::now();
+    chat.get_content_from_req_body(body).await.unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert_eq!(*reservations.lock().unwrap(), [tokens, tokens]);
+    assert!(Config::set_rate_limit("missing-source", RateLimit::new()).is_err());
+
+    format_test_block("rate_limit", || format!("{limit:?} -> {tokens} tokens per request"));
+}
@@ -0,0 +1,131 @@
+use serde_json::json;
+
+use crate::chat::chat_single::SingleChat;
+use crate::chat::message::Role;
+use crate::chat::mock::MockApi;
+use crate::config::Config;
+use crate::config::ModelCapability::Cheap;
+use crate::server::ChatServer;
+use crate::tests::format_test_block;
+
+pub async fn test_server() {
+    let mock = MockApi::new(|body| {
+        let messages = body["messages"].as_array().cloned().unwrap_or_default();
+        format!("收到{}条消息", messages.len()).into()
+    })
+    .with_chunk_chars(2);
+    Config::add_mock_api("mock-served", Cheap, mock);
+    let mut chat = SingleChat::new_with_api_name("mock-served", "", false);
+    chat.base.add_message(Role::System, "你是rhine").unwrap();
+    let router = ChatServer::new()
+        .with_chat("rhine-chat", chat)
+        .with_api_key("sk-test")
+        .router();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}/v1", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let client = reqwest::Client::new();
+    let request = json!({
+        "model": "rhine-chat",
+        "messages": [
+            {"role": "user", "content": "你好"},
+            {"role": "assistant", "content": "你好！"},
+            {"role": "user", "content": [{"type": "text", "text": "介绍一下你自己"}]},
+        ],
+    });
+
+    let unauthorized = client
+        .post(format!("{base}/chat/completions"))
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unauthorized.status(), 401);
+    let wrong_key = client
+        .post(format!("{base}/chat/completions"))
+        .bearer_auth("sk-tes")
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(wrong_key.status(), 401);
+
+    let models: serde_json::Value = client
+        .get(format!("{base}/models"))
+        .bearer_auth("sk-test")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(models["data"][0]["id"], "rhine-chat");
+
+    let completion: serde_json::Value = client
+        .post(format!("{base}/chat/completions"))
+        .bearer_auth("sk-test")
+        .json(&request)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(completion["choices"][0]["message"]["content"], "收到4条消息");
+    assert_eq!(completion["usage"]["total_tokens"].as_u64().map(|t| t > 0), Some(true));
+
+    let mut streaming = request.clone();
+    streaming["stream"] = json!(true);
+    let events = client
+        .post(format!("{base}/chat/completions"))
+        .bearer_auth("sk-test")
+        .json(&streaming)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    // 回答按模型的增量逐段转发，而不是生成完毕后的单个分块
+    // The answer is forwarded delta by delta as the model produces it, not as one chunk once finished
+    let chunks: Vec<serde_json::Value> = events
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter_map(|data| serde_json::from_str(data).ok())
+        .collect();
+    let deltas: Vec<&str> = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(deltas, ["收到", "4条", "消息"]);
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "stop");
+    assert!(chunks.last().unwrap()["usage"]["total_tokens"].as_u64().is_some());
+    assert!(events.trim_end().ends_with("data: [DONE]"));
+
+    let mut invalid = streaming.clone();
+    invalid["messages"] = json!([{"role": "assistant", "content": "你好！"}]);
+    let response = client
+        .post(format!("{base}/chat/completions"))
+        .bearer_auth("sk-test")
+        .json(&invalid)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let mut unknown = request.clone();
+    unknown["model"] = json!("gpt-4o");
+    let response = client
+        .post(format!("{base}/chat/completions"))
+        .bearer_auth("sk-test")
+        .json(&unknown)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    format_test_block("server", || events);
+}
@@ -0,0 +1,232 @@
+use std::sync::Arc;
+
+use error_stack::Result;
+use futures::future::BoxFuture;
+use serde_json::json;
+
+use crate::chat::chat_single::SingleChat;
+use crate::chat::message::{Role, Session, TITLE_METADATA_KEY};
+use crate::config::Config;
+use crate::memory::{Embedder, MemoryError};
+use crate::schema::tool_schema::get_tool_function;
+use crate::storage::blob::{
+    blob_hash, register_read_blob_tool, BlobOffload, BlobRef, BlobStore, FileBlobStore, MemoryBlobStore,
+    BLOB_METADATA_KEY, BLOB_REFERENCE_PREFIX,
+};
+use crate::storage::file::FileStore;
+use crate::storage::search::{semantic_search, SessionHit};
+#[cfg(feature = "sqlite")]
+use crate::storage::sqlite::SqliteStore;
+use crate::storage::{SessionStore, StorageError};
+use crate::tests::format_test_block;
+
+pub async fn test_storage() {
+    test_session_store().await;
+    test_blob_offload().await;
+    test_session_search().await;
+    #[cfg(feature = "sqlite")]
+    test_sqlite_store().await;
+}
+
+/// 任意会话存储都应满足的行为
+/// Behaviour every session store must have
+async fn check_session_store(store: &dyn SessionStore) {
+    let mut session = Session::new();
+    session.add_with_default_path(Role::System, "你是助手".into()).unwrap();
+    session.add_with_default_path(Role::User, "你好".into()).unwrap();
+    session.set_metadata(TITLE_METADATA_KEY, json!("问候"));
+
+    assert_eq!(store.load("greeting").await.unwrap(), None);
+    store.save("greeting", &session).await.unwrap();
+    store.save("other", &Session::new()).await.unwrap();
+    assert_eq!(store.load("greeting").await.unwrap(), Some(session.clone()));
+    assert_eq!(store.list().await.unwrap(), ["greeting", "other"]);
+
+    session.add_with_default_path(Role::Assistant, "你好！".into()).unwrap();
+    store.save("greeting", &session).await.unwrap();
+    assert_eq!(store.load("greeting").await.unwrap(), Some(session));
+    store.delete("other").await.unwrap();
+    store.delete("other").await.unwrap();
+    assert_eq!(store.list().await.unwrap(), ["greeting"]);
+
+    let hits = store.search("你好", 10).await.unwrap();
+    assert_eq!(hits.iter().map(|hit| hit.path.clone()).collect::<Vec<_>>(), [vec![0, 0], vec![0, 0, 0]]);
+    assert!(hits.iter().all(|hit| hit.session_id == "greeting"));
+    assert_eq!(hits[1].snippet, "你好！");
+    assert_eq!(store.search("你好", 1).await.unwrap().len(), 1);
+    assert!(store.search("再见", 10).await.unwrap().is_empty());
+}
+
+async fn test_session_store() {
+    let dir = std::env::temp_dir().join(format!("rhine-session-store-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let store = FileStore::new(&dir);
+    assert!(store.list().await.unwrap().is_empty());
+    check_session_store(&store).await;
+    let error = store.save("../escape", &Session::new()).await.unwrap_err();
+    assert!(matches!(error.current_context(), StorageError::InvalidId(_)));
+
+    #[cfg(feature = "sqlite")]
+    check_session_store(&SqliteStore::open_in_memory().await.unwrap()).await;
+
+    let files = std::fs::read_dir(&dir).unwrap().count();
+    std::fs::remove_dir_all(&dir).unwrap();
+    format_test_block("session_store", || format!("{} file(s) in {}", files, dir.display()));
+}
+
+/// 按话题关键词给出向量的测试用向量化
+/// Test embedder giving vectors by topic keywords
+#[derive(Debug)]
+struct TopicEmbedder;
+
+impl Embedder for TopicEmbedder {
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, Result<Vec<Vec<f32>>, MemoryError>> {
+        let topics = [["天气", "下雨"], ["代码", "编译"]];
+        let embeddings = texts
+            .iter()
+            .map(|text| {
+                topics
+                    .iter()
+                    .map(|words| words.iter().filter(|word| text.contains(*word)).count() as f32 + 0.1)
+                    .collect()
+            })
+            .collect();
+        Box::pin(async move { Ok(embeddings) })
+    }
+}
+
+async fn test_session_search() {
+    let dir = std::env::temp_dir().join(format!("rhine-session-search-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let store = FileStore::new(&dir);
+    let mut weather = Session::new();
+    weather.add_with_default_path(Role::User, "明天科隆的天气怎么样".into()).unwrap();
+    weather.add_with_default_path(Role::Assistant, "明天多云，傍晚可能下雨".into()).unwrap();
+    store.save("weather", &weather).await.unwrap();
+    let mut code = Session::new();
+    let long_question = format!("{}为什么 Rust 代码编译报错{}", "前文".repeat(30), "后文".repeat(30));
+    code.add_with_default_path(Role::User, long_question).unwrap();
+    store.save("code", &code).await.unwrap();
+
+    // 片段只保留命中位置附近的正文，匹配不区分大小写
+    // Snippets keep only the text around the match, which is case-insensitive
+    let hits = store.search("rust", 10).await.unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].session_id, "code");
+    assert!(hits[0].snippet.starts_with('…') && hits[0].snippet.ends_with('…'));
+    assert!(hits[0].snippet.contains("Rust 代码编译"));
+
+    let ranked = semantic_search(&store, &TopicEmbedder, "会不会下雨", 2).await.unwrap();
+    let ranked: Vec<&SessionHit> = ranked.iter().map(|(hit, _)| hit).collect();
+    assert_eq!(ranked.len(), 2);
+    assert!(ranked.iter().all(|hit| hit.session_id == "weather"));
+    assert_eq!(ranked[0].path, [0]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    format_test_block("session_search", || format!("{:?}", hits));
+}
+
+async fn test_blob_offload() {
+    assert_eq!(blob_hash("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+    let dir = std::env::temp_dir().join(format!("rhine-blobs-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let files = FileBlobStore::new(&dir);
+    let hash = blob_hash("网页正文");
+    files.put(&hash, "网页正文").unwrap();
+    files.put(&hash, "网页正文").unwrap();
+    assert_eq!(files.get(&hash).unwrap().as_deref(), Some("网页正文"));
+    assert_eq!(files.get(&blob_hash("别的")).unwrap(), None);
+    assert!(files.get("../escape").is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let store: Arc<dyn BlobStore> = Arc::new(MemoryBlobStore::new());
+    Config::add_mock("mock-blob", |_| "好的".into());
+    let mut chat = SingleChat::new_with_api_name("mock-blob", "", false);
+    chat.base
+        .set_blob_offload(BlobOffload::with_shared_store(store.clone()).with_threshold_chars(20).with_preview_chars(5));
+    chat.add_tool_result("call_short", "短结果").unwrap();
+    let page = "第一段内容。".repeat(10);
+    chat.add_tool_result("call_page", &page).unwrap();
+
+    let nodes = chat.base.session.nodes_along_path(&chat.base.session.default_path).unwrap();
+    assert_eq!(nodes[0].content, "短结果");
+    assert!(!nodes[0].metadata.contains_key(BLOB_METADATA_KEY));
+    let stored = nodes[1];
+    let blob: BlobRef = serde_json::from_value(stored.metadata[BLOB_METADATA_KEY].clone()).unwrap();
+    assert_eq!(blob, BlobRef { hash: blob_hash(&page), chars: 60 });
+    assert!(stored.content.starts_with("第一段内容\n……"));
+    let reference = format!("{BLOB_REFERENCE_PREFIX}{}", blob.hash);
+    assert!(stored.content.contains(&reference));
+    assert_eq!(chat.base.resolve_blob(&reference).unwrap().as_deref(), Some(page.as_str()));
+
+    register_read_blob_tool(store).unwrap();
+    let read = get_tool_function("read_blob").unwrap();
+    let slice = read(json!({"hash": reference, "offset": 6, "length": 6})).unwrap();
+    assert_eq!(slice["content"], "第一段内容。");
+    assert!(read(json!({"hash": blob_hash("不存在")})).is_err());
+
+    format_test_block("blob_offload", || stored.content.clone());
+}
+
+#[cfg(feature = "sqlite")]
+async fn test_sqlite_store() {
+    let mut session = Session::new();
+    session.add_with_default_path(Role::System, "你是助手".into()).unwrap();
+    session.add_with_default_path(Role::User, "查一下科隆的天气".into()).unwrap();
+    session.add_with_default_path(Role::Assistant, "<ToolUse>weather</ToolUse>".into()).unwrap();
+    session
+        .add_with_default_path(Role::Tool { call_id: "call_1".into() }, "晴，18℃".into())
+        .unwrap();
+    session.add_with_default_path(Role::Assistant, "科隆今天晴，18℃".into()).unwrap();
+    // 在第一个问题下另开一个分支
+    // Open a second branch under the first question
+    session.add_with_parent_path(&[0, 0], Role::Assistant, "100% 会下雨_吗".into()).unwrap();
+    session.set_metadata(TITLE_METADATA_KEY, json!("科隆天气"));
+    session.usage.add(120, 30);
+
+    let store = SqliteStore::open_in_memory().await.unwrap();
+    store.save_session("weather", &session).await.unwrap();
+    store.save_session("empty", &Session::new()).await.unwrap();
+    assert_eq!(store.load_session("weather").await.unwrap(), Some(session.clone()));
+    assert_eq!(store.load_session("missing").await.unwrap(), None);
+
+    let sessions = store.list_sessions().await.unwrap();
+    let weather = sessions.iter().find(|summary| summary.id == "weather").unwrap();
+    assert_eq!(weather.title.as_deref(), Some("科隆天气"));
+    assert_eq!(weather.message_count, 6);
+    assert_eq!(weather.usage.total_tokens(), 150);
+
+    let hits = store.search("18℃", 10).await.unwrap();
+    assert_eq!(hits.iter().map(|hit| hit.path.clone()).collect::<Vec<_>>(), [vec![0, 0, 0, 0], vec![0, 0, 0, 0, 0]]);
+    // LIKE 通配符按字面匹配
+    // LIKE wildcards match literally
+    let hits = store.search("100%", 10).await.unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].path, [0, 0, 1]);
+    assert!(store.search("下雨%吗", 10).await.unwrap().is_empty());
+
+    let branch = store.load_branch("weather", &[0, 0, 0, 0]).await.unwrap();
+    assert_eq!(branch.len(), 4);
+    assert_eq!(branch[3].role, Role::Tool { call_id: "call_1".into() });
+    assert!(branch.iter().all(|node| node.child.is_empty()));
+    let children = store.load_children("weather", &[0, 0]).await.unwrap();
+    assert_eq!(children.iter().map(|node| node.content.as_str()).collect::<Vec<_>>(), ["<ToolUse>weather</ToolUse>", "100% 会下雨_吗"]);
+    assert_eq!(store.load_children("weather", &[]).await.unwrap().len(), 1);
+
+    let tool_calls = store.tool_calls("weather").await.unwrap();
+    assert_eq!(tool_calls.len(), 1);
+    assert_eq!(tool_calls[0].call_id.as_deref(), Some("call_1"));
+    assert_eq!(tool_calls[0].result, "晴，18℃");
+
+    // 再次保存会覆盖旧数据
+    // Saving again replaces the old data
+    session.add_with_default_path(Role::User, "谢谢".into()).unwrap();
+    store.save_session("weather", &session).await.unwrap();
+    assert_eq!(store.load_session("weather").await.unwrap(), Some(session));
+    store.delete_session("weather").await.unwrap();
+    assert!(store.tool_calls("weather").await.unwrap().is_empty());
+    assert_eq!(store.list_sessions().await.unwrap().len(), 1);
+
+    format_test_block("sqlite_store", || format!("{sessions:#?}"));
+}
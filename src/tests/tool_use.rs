@@ -0,0 +1,113 @@
+use proptest::prelude::*;
+use proptest::test_runner::TestRunner;
+use serde_json::json;
+
+use crate::schema::tool_schema::{extract_tool_uses, find_tool_uses, strip_tool_uses};
+use crate::tests::format_test_block;
+
+pub async fn test_tool_use_extraction() {
+    test_tool_use_cases();
+    test_tool_use_properties();
+}
+
+fn test_tool_use_cases() {
+    let cases = [
+        ("<ToolUse> send_email </ToolUse>", vec!["send_email"]),
+        ("a<ToolUse>x</ToolUse>b<ToolUse>\ny\n</ToolUse>c", vec!["x", "y"]),
+        (
+            r#"<ToolUse>{"name": "echo", "arguments": {"text": "</ToolUse> inside"}}</ToolUse>"#,
+            vec![r#"{"name": "echo", "arguments": {"text": "</ToolUse> inside"}}"#],
+        ),
+        (
+            r#"<ToolUse>{"text": "quote \" then </ToolUse>"}</ToolUse>"#,
+            vec![r#"{"text": "quote \" then </ToolUse>"}"#],
+        ),
+        (
+            "<ToolUse>outer <ToolUse>inner</ToolUse> rest</ToolUse>",
+            vec!["outer <ToolUse>inner</ToolUse> rest"],
+        ),
+        ("<ToolUse><![CDATA[a </ToolUse> b]]></ToolUse>", vec!["a </ToolUse> b"]),
+        ("<ToolUse>first <ToolUse>second</ToolUse>", vec!["second"]),
+        (r#"<ToolUse>say "hi</ToolUse>"#, vec![r#"say "hi"#]),
+        ("<ToolUse>never closed", vec![]),
+        ("no calls at all", vec![]),
+        (r#"<ToolUse>x<ToolUse>{"a": 1}</ToolUse><ToolUse>x"#, vec![r#"{"a": 1}"#]),
+        (r#"<ToolUse>{"text": "a </ToolUse> <ToolUse>b</ToolUse>"#, vec!["b"]),
+    ];
+    for (input, expected) in &cases {
+        assert_eq!(&extract_tool_uses(input), expected, "input: {input}");
+    }
+
+    assert_eq!(strip_tool_uses("前<ToolUse>a</ToolUse>中<ToolUse>b</ToolUse>后"), "前中后");
+    let spans = find_tool_uses("ab<ToolUse>c</ToolUse>");
+    assert_eq!(spans[0].range, 2..22);
+
+    format_test_block("tool_use_cases", || format!("{:#?}", spans));
+}
+
+/// 由容易迷惑解析器的片段拼出的文本
+/// Text assembled from fragments that tend to confuse the parser
+fn adversarial_text() -> impl Strategy<Value = String> {
+    let fragment = prop_oneof![
+        Just("<ToolUse>".to_string()),
+        Just("</ToolUse>".to_string()),
+        Just("<![CDATA[".to_string()),
+        Just("]]>".to_string()),
+        Just("\"".to_string()),
+        Just("\\".to_string()),
+        Just("{".to_string()),
+        Just("}".to_string()),
+        Just("[".to_string()),
+        Just("<".to_string()),
+        "[a-z 中文\n]{0,4}",
+    ];
+    prop::collection::vec(fragment, 0..24).prop_map(|fragments| fragments.concat())
+}
+
+fn test_tool_use_properties() {
+    let mut runner = TestRunner::default();
+
+    // 任意输入都不会崩溃，找到的范围有序、互不重叠且落在字符边界上
+    // Any input is handled without panicking; found ranges are ordered, disjoint and on char boundaries
+    runner
+        .run(&adversarial_text(), |text| {
+            let spans = find_tool_uses(&text);
+            let mut last = 0;
+            for span in &spans {
+                prop_assert!(span.range.start >= last && span.range.end <= text.len());
+                prop_assert!(text[span.range.clone()].starts_with("<ToolUse>"));
+                prop_assert!(text[span.range.clone()].ends_with("</ToolUse>"));
+                last = span.range.end;
+            }
+            let stripped = strip_tool_uses(&text);
+            prop_assert_eq!(stripped.len() + spans.iter().map(|s| s.range.len()).sum::<usize>(), text.len());
+            Ok(())
+        })
+        .unwrap();
+
+    // JSON 参数中的任意字符串都原样保留
+    // Arbitrary strings inside JSON arguments survive intact
+    runner
+        .run(&(adversarial_text(), adversarial_text()), |(text, prose)| {
+            let arguments = json!({"name": "echo", "arguments": {"text": text}}).to_string();
+            let prose = strip_tool_uses(&prose);
+            let answer = format!("{prose}<ToolUse>{arguments}</ToolUse>{prose}");
+            let calls = extract_tool_uses(&answer);
+            prop_assert_eq!(calls.len(), 1);
+            prop_assert_eq!(&calls[0], &arguments);
+            prop_assert_eq!(strip_tool_uses(&answer), format!("{prose}{prose}"));
+            Ok(())
+        })
+        .unwrap();
+
+    // CDATA 内的任意文本都原样保留
+    // Arbitrary text inside CDATA survives intact
+    runner
+        .run(&adversarial_text(), |text| {
+            prop_assume!(!text.contains("]]>"));
+            let calls = extract_tool_uses(&format!("<ToolUse><![CDATA[{text}]]></ToolUse>"));
+            prop_assert_eq!(calls, vec![text.trim().to_string()]);
+            Ok(())
+        })
+        .unwrap();
+}
@@ -1,6 +1,4 @@
-use std::collections::HashMap;
 use dashmap::DashMap;
-use once_cell::sync::Lazy;
 
 pub mod text;
 pub mod search;
@@ -9,22 +7,8 @@ pub mod cmd;
 pub mod code;
 
 
+#[derive(Default)]
 pub struct Environment {
     text: DashMap<String, String>,
     note: DashMap<String, String>,
 }
-
-pub fn add_env(key: &str) {
-    ENV_POOL.insert(key.to_string(), Environment {
-        text: DashMap::new(),
-        note: DashMap::new(),
-    });
-}
-
-
-pub fn remove_env(key: &str) {
-    let env = ENV_POOL.get(key).unwrap();
-    ENV_POOL.remove(key);
-}
-
-pub static ENV_POOL: Lazy<DashMap<String, Environment>> = Lazy::new(|| DashMap::new());
@@ -0,0 +1,158 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::utils::common::redact::{redact, redact_json};
+
+/// 默认附带的请求体、响应体字节上限
+/// Default byte cap of attached request and response bodies
+pub const DEFAULT_MAX_BODY_BYTES: usize = 8 * 1024;
+
+/// 错误报告附带原始请求体、响应体的方式：超过上限时保留开头与结尾，并可按次数采样、按时间限流，
+/// 避免巨大的上下文撑爆日志
+/// How raw request and response bodies are attached to error reports: bodies over the cap keep their head and
+/// tail, and attachments can be sampled by count and rate limited by time, so giant contexts don't blow up logs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BodyLogPolicy {
+    /// 字节上限，为 `None` 时不截断
+    /// Byte cap, no truncation when `None`
+    pub max_bytes: Option<usize>,
+
+    /// 每 N 次附带一次完整内容，其余只记录大小；为 1 时每次都附带
+    /// Attach the content once every N times and only the size otherwise; every time when 1
+    pub sample_every: u64,
+
+    /// 两次附带完整内容的最短间隔
+    /// Minimum interval between two attachments with content
+    pub min_interval: Option<Duration>,
+}
+
+impl Default for BodyLogPolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: Some(DEFAULT_MAX_BODY_BYTES),
+            sample_every: 1,
+            min_interval: None,
+        }
+    }
+}
+
+impl BodyLogPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_sample_every(mut self, sample_every: u64) -> Self {
+        self.sample_every = sample_every.max(1);
+        self
+    }
+
+    pub fn with_min_interval(mut self, min_interval: Option<Duration>) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+}
+
+static POLICY: Lazy<RwLock<BodyLogPolicy>> = Lazy::new(|| RwLock::new(BodyLogPolicy::default()));
+
+/// 已请求附带的次数
+/// Number of attachments requested so far
+static ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+
+/// 上次附带完整内容的时间
+/// When content was last attached
+static LAST_ATTACHED: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
+/// 设置全局策略并重置采样计数
+/// Set the global policy and reset the sampling state
+pub fn set_body_log_policy(policy: BodyLogPolicy) {
+    *POLICY.write().unwrap() = policy;
+    ATTEMPTS.store(0, Ordering::Relaxed);
+    *LAST_ATTACHED.lock().unwrap() = None;
+}
+
+pub fn body_log_policy() -> BodyLogPolicy {
+    POLICY.read().unwrap().clone()
+}
+
+/// 本次是否附带完整内容
+/// Whether content is attached this time
+fn sampled(policy: &BodyLogPolicy) -> bool {
+    let attempt = ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    if !attempt.is_multiple_of(policy.sample_every.max(1)) {
+        return false;
+    }
+    let Some(min_interval) = policy.min_interval else {
+        return true;
+    };
+    let mut last = LAST_ATTACHED.lock().unwrap();
+    let now = Instant::now();
+    if last.is_some_and(|last| now.duration_since(last) < min_interval) {
+        return false;
+    }
+    *last = Some(now);
+    true
+}
+
+/// 超过字节上限时保留开头与结尾各一半，中间以省略的字节数代替；切分点落在字符边界上
+/// Keep half the cap from the head and half from the tail when over the byte cap, replacing the middle with the
+/// number of bytes omitted; cuts fall on char boundaries
+pub fn truncate_middle(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_string();
+    }
+    let mut head = max_bytes / 2;
+    while !text.is_char_boundary(head) {
+        head -= 1;
+    }
+    let mut tail = text.len() - (max_bytes - max_bytes / 2);
+    while !text.is_char_boundary(tail) {
+        tail += 1;
+    }
+    format!("{}…[{} bytes omitted]…{}", &text[..head], tail - head, &text[tail..])
+}
+
+/// 按全局策略生成附带到错误报告的请求体或响应体，内容先遮盖敏感信息
+/// Build a request or response body attachment for an error report under the global policy; sensitive content is
+/// masked first
+///
+/// # 参数 (Parameters)
+/// * `label` - 附带内容的说明，例如 `Request body`
+///   - Description of the attachment, e.g. `Request body`
+/// * `body` - 原始 JSON
+///   - Raw JSON
+pub fn body_attachment(label: &str, body: &serde_json::Value) -> String {
+    let policy = body_log_policy();
+    if !sampled(&policy) {
+        return omitted(label, body.to_string().len());
+    }
+    attachment(label, &redact_json(body).to_string(), &policy)
+}
+
+/// 与 [`body_attachment`] 相同，用于文本内容
+/// Same as [`body_attachment`] for text content
+pub fn text_attachment(label: &str, text: &str) -> String {
+    let policy = body_log_policy();
+    if !sampled(&policy) {
+        return omitted(label, text.len());
+    }
+    attachment(label, &redact(text), &policy)
+}
+
+fn attachment(label: &str, content: &str, policy: &BodyLogPolicy) -> String {
+    match policy.max_bytes {
+        Some(max_bytes) => format!("{label}: {}", truncate_middle(content, max_bytes)),
+        None => format!("{label}: {content}"),
+    }
+}
+
+fn omitted(label: &str, bytes: usize) -> String {
+    format!("{label}: omitted by sampling ({bytes} bytes)")
+}
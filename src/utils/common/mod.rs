@@ -1 +1,3 @@
-pub mod load_toml;
\ No newline at end of file
+pub mod body_log;
+pub mod load_toml;
+pub mod redact;
\ No newline at end of file
@@ -0,0 +1,232 @@
+use std::io::Write;
+use std::sync::RwLock;
+
+use dashmap::DashSet;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// 替换敏感内容使用的掩码
+/// Mask used in place of sensitive content
+pub const REDACTED: &str = "***";
+
+/// 默认敏感字段（大小写不敏感）
+/// Default sensitive fields (case-insensitive)
+const DEFAULT_SENSITIVE_FIELDS: [&str; 6] = [
+    "api_key",
+    "apikey",
+    "authorization",
+    "password",
+    "secret",
+    "token",
+];
+
+/// 注册密钥的最短长度，更短的字符串太容易出现在普通文本中
+/// Minimum length of a registered secret; shorter strings show up in ordinary text too easily
+pub const MIN_SECRET_LEN: usize = 8;
+
+/// 已注册的密钥原文，作为完整词元出现在任意文本中都会被遮盖
+/// Registered raw secrets, masked wherever they appear in text
+static KNOWN_SECRETS: Lazy<DashSet<String>> = Lazy::new(DashSet::new);
+
+/// 需要遮盖取值的字段名及其匹配模式
+/// Field names whose values are masked, with their compiled pattern
+static SENSITIVE_FIELDS: Lazy<RwLock<SensitiveFields>> = Lazy::new(|| {
+    RwLock::new(SensitiveFields::new(
+        DEFAULT_SENSITIVE_FIELDS.iter().map(|s| s.to_string()).collect(),
+    ))
+});
+
+struct SensitiveFields {
+    names: Vec<String>,
+    pattern: Regex,
+}
+
+impl SensitiveFields {
+    fn new(names: Vec<String>) -> Self {
+        let alternatives = names
+            .iter()
+            .map(|name| regex::escape(name))
+            .collect::<Vec<_>>()
+            .join("|");
+        let pattern =
+            Regex::new(&format!(r#"(?i)("[^"]*(?:{alternatives})"\s*:\s*)"[^"]*""#)).unwrap();
+        Self { names, pattern }
+    }
+}
+
+/// Bearer 令牌、常见密钥前缀与 URL 查询参数中的 key
+/// Bearer tokens, common key prefixes and `key` query parameters
+static SECRET_PATTERNS: Lazy<Vec<(Regex, &'static str)>> = Lazy::new(|| {
+    vec![
+        (Regex::new(r"(?i)(bearer\s+)[A-Za-z0-9._~+/=\-]+").unwrap(), "${1}***"),
+        (Regex::new(r"\b(sk|pk|rk)-[A-Za-z0-9_\-]{8,}").unwrap(), "${1}-***"),
+        (Regex::new(r"(?i)([?&](?:api_?)?key=)[^&\s]+").unwrap(), "${1}***"),
+    ]
+});
+
+/// 注册一个密钥原文，短于 [`MIN_SECRET_LEN`] 的字符串会被忽略
+/// Register a raw secret; strings shorter than [`MIN_SECRET_LEN`] are ignored
+pub fn register_secret(secret: &str) {
+    if secret.chars().count() >= MIN_SECRET_LEN {
+        KNOWN_SECRETS.insert(secret.to_string());
+    }
+}
+
+/// 添加需要遮盖的字段名
+/// Add a field name whose values should be masked
+pub fn add_sensitive_field(field: &str) {
+    let mut fields = SENSITIVE_FIELDS.write().unwrap();
+    if !fields.names.iter().any(|f| f.eq_ignore_ascii_case(field)) {
+        let mut names = fields.names.clone();
+        names.push(field.to_lowercase());
+        *fields = SensitiveFields::new(names);
+    }
+}
+
+/// 判断字段名是否敏感
+/// Whether a field name is sensitive
+pub fn is_sensitive_field(field: &str) -> bool {
+    let field = field.to_lowercase();
+    SENSITIVE_FIELDS
+        .read()
+        .unwrap()
+        .names
+        .iter()
+        .any(|f| field == *f || field.ends_with(&format!("_{f}")))
+}
+
+/// 判断字段名是否为内置凭据字段，不受运行时注册的字段和密钥影响
+/// Whether a field name is a built-in credential field, independent of fields and secrets registered at runtime
+pub fn is_credential_field(field: &str) -> bool {
+    let field = field.to_lowercase();
+    DEFAULT_SENSITIVE_FIELDS
+        .iter()
+        .any(|f| field == *f || field.ends_with(&format!("_{f}")))
+}
+
+/// 遮盖单个密钥，仅保留前缀便于辨认
+/// Mask a single secret, keeping a short prefix for recognition
+pub fn mask_secret(secret: &str) -> String {
+    let prefix: String = secret.chars().take(3).collect();
+    if secret.chars().count() > 8 {
+        format!("{prefix}{REDACTED}")
+    } else {
+        REDACTED.to_string()
+    }
+}
+
+/// 遮盖文本中的密钥和敏感字段
+/// Mask secrets and sensitive fields in text
+pub fn redact(text: &str) -> String {
+    let mut result = text.to_string();
+
+    for secret in KNOWN_SECRETS.iter() {
+        if result.contains(secret.as_str()) {
+            result = replace_token(&result, &secret, &mask_secret(&secret));
+        }
+    }
+
+    for (pattern, replacement) in SECRET_PATTERNS.iter() {
+        result = pattern.replace_all(&result, *replacement).into_owned();
+    }
+
+    SENSITIVE_FIELDS
+        .read()
+        .unwrap()
+        .pattern
+        .replace_all(&result, format!(r#"${{1}}"{REDACTED}""#))
+        .into_owned()
+}
+
+/// 只替换作为完整词元出现的密钥，前后紧邻字母、数字、`_` 或 `-` 的出现保持原样
+/// Replace only occurrences of the secret that form a whole token; occurrences next to a letter, digit, `_` or `-`
+/// are kept as they are
+fn replace_token(text: &str, secret: &str, mask: &str) -> String {
+    let is_token_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let mut result = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (start, _) in text.match_indices(secret) {
+        let end = start + secret.len();
+        if start < copied
+            || text[..start].chars().next_back().is_some_and(is_token_char)
+            || text[end..].chars().next().is_some_and(is_token_char)
+        {
+            continue;
+        }
+        result.push_str(&text[copied..start]);
+        result.push_str(mask);
+        copied = end;
+    }
+    result.push_str(&text[copied..]);
+    result
+}
+
+/// 写入前遮盖日志的写入器，包装 `tracing_subscriber` 的任意写入器，使新增的日志语句默认也会被遮盖
+/// Writer that redacts logs before writing them, wrapping any `tracing_subscriber` writer so that new log
+/// statements are redacted by default as well
+///
+/// `fmt` 层每条事件只调用一次写入，因此每次写入都按完整文本遮盖
+/// The `fmt` layer writes every event in one call, so each write is redacted as a whole
+///
+/// ```ignore
+/// tracing_subscriber::fmt().with_writer(RedactingWriter::new(std::io::stdout)).init();
+/// ```
+#[derive(Debug, Clone)]
+pub struct RedactingWriter<M> {
+    inner: M,
+}
+
+impl<M> RedactingWriter<M> {
+    pub fn new(inner: M) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingWriter<M> {
+    type Writer = Redacted<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Redacted(self.inner.make_writer())
+    }
+}
+
+/// [`RedactingWriter`] 为每条事件创建的写入器
+/// Writer created by [`RedactingWriter`] for each event
+#[derive(Debug)]
+pub struct Redacted<W>(W);
+
+impl<W: Write> Write for Redacted<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write_all(redact(&String::from_utf8_lossy(buf)).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// 遮盖JSON值中的敏感字段与密钥
+/// Mask sensitive fields and secrets in a JSON value
+pub fn redact_json(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.iter()
+                .map(|(key, value)| {
+                    let value = if is_sensitive_field(key) && !value.is_object() && !value.is_array() {
+                        serde_json::Value::String(REDACTED.to_string())
+                    } else {
+                        redact_json(value)
+                    };
+                    (key.clone(), value)
+                })
+                .collect(),
+        ),
+        serde_json::Value::Array(items) => {
+            serde_json::Value::Array(items.iter().map(redact_json).collect())
+        }
+        serde_json::Value::String(s) => serde_json::Value::String(redact(s)),
+        other => other.clone(),
+    }
+}
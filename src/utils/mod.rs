@@ -1 +1,3 @@
-pub mod common;
\ No newline at end of file
+pub mod common;
+#[cfg(feature = "otel")]
+pub mod telemetry;
\ No newline at end of file
@@ -0,0 +1,65 @@
+use error_stack::{Result, ResultExt};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use thiserror::Error;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::utils::common::redact::RedactingWriter;
+
+#[derive(Debug, Error)]
+pub enum TelemetryError {
+    #[error("Failed to build OTLP exporter")]
+    ExporterBuildError,
+
+    #[error("Failed to install tracing subscriber")]
+    SubscriberInitError,
+}
+
+/// 初始化 OTLP 追踪导出，每个 `chat_request` span 都会被发送到收集器
+/// Initialize OTLP trace export so every `chat_request` span reaches the collector
+///
+/// # 参数 (Parameters)
+/// * `endpoint` - OTLP/HTTP 收集器地址，例如 `http://localhost:4318/v1/traces`
+///   - OTLP/HTTP collector endpoint, e.g. `http://localhost:4318/v1/traces`
+/// * `service_name` - 上报的服务名称
+///   - Reported service name
+/// * `filter` - 日志过滤表达式，例如 `info`
+///   - Log filter directive, e.g. `info`
+///
+/// # 返回 (Returns)
+/// * `SdkTracerProvider` - 退出前调用 `shutdown` 以刷新剩余的 span
+///   - Call `shutdown` before exit to flush remaining spans
+pub fn init_otlp_tracing(
+    endpoint: &str,
+    service_name: &str,
+    filter: &str,
+) -> Result<SdkTracerProvider, TelemetryError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .change_context(TelemetryError::ExporterBuildError)
+        .attach_printable_lazy(|| format!("OTLP endpoint: {endpoint}"))?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(service_name.to_string())
+                .build(),
+        )
+        .build();
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::new(filter))
+        .with(tracing_subscriber::fmt::layer().with_writer(RedactingWriter::new(std::io::stdout)))
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("rhine")))
+        .try_init()
+        .change_context(TelemetryError::SubscriberInitError)?;
+
+    Ok(provider)
+}